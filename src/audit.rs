@@ -0,0 +1,405 @@
+use crate::{
+    config::BucketConfig,
+    s3::get_bytes_and_etag,
+    store::{ObjectStore, Store},
+};
+use color_eyre::eyre::bail;
+use comfy_table::Table;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    mem,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    oneshot,
+};
+
+pub const AUDIT_PREFIX: &str = "audit/";
+pub const DEFAULT_TAIL_EVENTS: usize = 50;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+///how many times to redo a read-modify-write that lost a race before waiting for the next flush
+const APPEND_ATTEMPTS: usize = 5;
+///if the bucket's unreachable for long enough, stop holding onto events rather than running out of memory
+const MAX_BUFFERED: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditAction {
+    Login {
+        username: String,
+        realm: String,
+        ip: IpAddr,
+        success: bool,
+    },
+    UserAdded {
+        username: String,
+    },
+    UserRemoved {
+        username: String,
+    },
+    PasswordChanged {
+        username: String,
+    },
+    CredentialAdded {
+        username: String,
+        label: String,
+    },
+    CredentialRemoved {
+        username: String,
+        label: String,
+    },
+    ///also used when the users for a realm get changed
+    RealmProtected {
+        realm: String,
+        usernames: Vec<String>,
+    },
+    RealmRemoved {
+        realm: String,
+    },
+    ///anyone can get in, even if a lower priority realm would've been protected
+    RealmMadePublic {
+        realm: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub at_ms: u128,
+    #[serde(flatten)]
+    pub action: AuditAction,
+}
+
+impl AuditEvent {
+    pub fn now(action: AuditAction) -> Self {
+        Self {
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            action,
+        }
+    }
+
+    ///the day's file this goes in, eg. `audit/2025-01-15.jsonl`
+    fn location(&self) -> String {
+        let (year, month, day, ..) = utc_datetime(self.at_ms);
+        format!("{AUDIT_PREFIX}{year:04}-{month:02}-{day:02}.jsonl")
+    }
+}
+
+///`(year, month, day, hour, minute, second)` from milliseconds since the epoch
+pub fn utc_datetime(at_ms: u128) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = (at_ms / 1000) as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86_400), secs.rem_euclid(86_400) as u32);
+
+    //thx http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
+}
+
+///appends `events` as JSON lines to the file at `location`. S3 can't append, so this re-reads and
+///re-writes the file, only writing if nobody else has since
+async fn append_events(
+    bucket: &dyn ObjectStore,
+    location: &str,
+    events: &[AuditEvent],
+) -> color_eyre::Result<()> {
+    let mut lines = vec![];
+    for event in events {
+        serde_json::to_writer(&mut lines, event)?;
+        lines.push(b'\n');
+    }
+
+    for _ in 0..APPEND_ATTEMPTS {
+        let (mut bytes, etag) = get_bytes_and_etag(bucket, location).await?;
+        if !bytes.is_empty() && !bytes.ends_with(b"\n") {
+            bytes.push(b'\n');
+        }
+        bytes.extend_from_slice(&lines);
+
+        if bucket
+            .put_if_unchanged(location, &bytes, "application/x-ndjson", etag.as_deref())
+            .await?
+        {
+            return Ok(());
+        }
+        debug!(?location, "Audit log changed underneath us, trying again");
+    }
+
+    bail!("audit log at {location:?} kept changing whilst appending")
+}
+
+///writes `events` to their days' files, returning whichever couldn't be written
+async fn write_events(bucket: &dyn ObjectStore, events: Vec<AuditEvent>) -> Vec<AuditEvent> {
+    let mut by_location: BTreeMap<String, Vec<AuditEvent>> = BTreeMap::new();
+    for event in events {
+        by_location.entry(event.location()).or_default().push(event);
+    }
+
+    let mut failed = vec![];
+    for (location, events) in by_location {
+        if let Err(e) = append_events(bucket, &location, &events).await {
+            warn!(?e, ?location, count=%events.len(), "Error writing audit events, will retry");
+            failed.extend(events);
+        }
+    }
+    failed
+}
+
+///for use outside of `serve`, where there's nothing to batch up
+pub async fn record_now(
+    bucket: &dyn ObjectStore,
+    actions: Vec<AuditAction>,
+) -> color_eyre::Result<()> {
+    let failed = write_events(bucket, actions.into_iter().map(AuditEvent::now).collect()).await;
+    if !failed.is_empty() {
+        bail!("unable to write {} audit event(s)", failed.len());
+    }
+    Ok(())
+}
+
+///puts events that couldn't be written back in front of any newer ones, dropping the oldest past
+///[`MAX_BUFFERED`]
+fn requeue(buffer: &Mutex<Vec<AuditEvent>>, mut failed: Vec<AuditEvent>) {
+    if failed.is_empty() {
+        return;
+    }
+
+    let mut buffer = buffer.lock().unwrap_or_else(|e| e.into_inner());
+    failed.append(&mut buffer);
+    if failed.len() > MAX_BUFFERED {
+        let dropped = failed.len() - MAX_BUFFERED;
+        error!(%dropped, "Too many unwritten audit events, dropping the oldest");
+        failed.drain(..dropped);
+    }
+    *buffer = failed;
+}
+
+///buffers audit events in memory, appending them to the bucket every so often
+#[derive(Clone)]
+pub struct AuditLog {
+    buffer: Arc<Mutex<Vec<AuditEvent>>>,
+    stop_tx: Sender<oneshot::Sender<()>>,
+}
+
+impl AuditLog {
+    pub fn new(bucket: Store) -> Self {
+        let buffer = Arc::new(Mutex::new(vec![]));
+        let (stop_tx, stop_rx) = channel(1);
+
+        tokio::task::spawn(Self::write_events(stop_rx, bucket, buffer.clone()));
+
+        Self { buffer, stop_tx }
+    }
+
+    async fn flush(bucket: &dyn ObjectStore, buffer: &Mutex<Vec<AuditEvent>>) {
+        let events = mem::take(&mut *buffer.lock().unwrap_or_else(|e| e.into_inner()));
+        if events.is_empty() {
+            return;
+        }
+
+        let count = events.len();
+        let failed = write_events(bucket, events).await;
+        trace!(written=%(count - failed.len()), "Flushed audit log");
+        requeue(buffer, failed);
+    }
+
+    async fn write_events(
+        mut stop_rx: Receiver<oneshot::Sender<()>>,
+        bucket: Store,
+        buffer: Arc<Mutex<Vec<AuditEvent>>>,
+    ) {
+        let mut flush_interval = tokio::time::interval(FLUSH_INTERVAL);
+        let stopped = loop {
+            tokio::select! {
+                stopped = stop_rx.recv() => {
+                    info!("Stop signal received for audit log");
+                    break stopped;
+                }
+                _ = flush_interval.tick() => Self::flush(&bucket, &buffer).await,
+            }
+        };
+
+        //a few goes, as there's no next flush to catch anything that fails
+        for _ in 0..3 {
+            Self::flush(&bucket, &buffer).await;
+            if buffer.lock().unwrap_or_else(|e| e.into_inner()).is_empty() {
+                break;
+            }
+        }
+        let lost = buffer.lock().unwrap_or_else(|e| e.into_inner()).len();
+        if lost > 0 {
+            error!(%lost, "Unable to write audit events on stop");
+        }
+
+        if let Some(stopped) = stopped {
+            let _ = stopped.send(());
+        }
+    }
+
+    ///never waits on the bucket
+    pub fn record(&self, action: AuditAction) {
+        self.buffer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(AuditEvent::now(action));
+    }
+
+    ///waits for everything buffered to be written
+    pub async fn send_stop(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.stop_tx.send(tx).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+fn describe(action: &AuditAction) -> (&'static str, String) {
+    match action {
+        AuditAction::Login {
+            username,
+            realm,
+            ip,
+            success,
+        } => (
+            if *success { "Login" } else { "Failed Login" },
+            format!("{username:?} from {ip} for {realm}"),
+        ),
+        AuditAction::UserAdded { username } => ("User Added", format!("{username:?}")),
+        AuditAction::UserRemoved { username } => ("User Removed", format!("{username:?}")),
+        AuditAction::PasswordChanged { username } => ("Password Changed", format!("{username:?}")),
+        AuditAction::CredentialAdded { username, label } => {
+            ("Credential Added", format!("{label:?} for {username:?}"))
+        }
+        AuditAction::CredentialRemoved { username, label } => {
+            ("Credential Removed", format!("{label:?} for {username:?}"))
+        }
+        AuditAction::RealmProtected { realm, usernames } => (
+            "Realm Protected",
+            format!("{realm} for {}", usernames.join(", ")),
+        ),
+        AuditAction::RealmRemoved { realm } => ("Realm Removed", realm.clone()),
+        AuditAction::RealmMadePublic { realm } => ("Realm Made Public", realm.clone()),
+    }
+}
+
+pub async fn audit_tail(config: &BucketConfig, count: usize) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+
+    let mut locations = ObjectStore::list(&bucket, AUDIT_PREFIX).await?;
+    //the dates sort the same as the names
+    locations.sort_unstable();
+
+    let mut events = vec![];
+    for location in locations.iter().rev() {
+        let (bytes, _) = get_bytes_and_etag(&bucket, location).await?;
+        let mut day: Vec<AuditEvent> = bytes
+            .split(|b| *b == b'\n')
+            .filter(|x| !x.is_empty())
+            .filter_map(|line| match serde_json::from_slice(line) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    warn!(?e, ?location, "Skipping unreadable audit event");
+                    None
+                }
+            })
+            .collect();
+        day.append(&mut events);
+        events = day;
+
+        if events.len() >= count {
+            break;
+        }
+    }
+
+    if events.is_empty() {
+        println!("No audit events yet.");
+        return Ok(());
+    }
+
+    events.sort_by_key(|x| x.at_ms);
+    let skip = events.len().saturating_sub(count);
+
+    let mut table = Table::new();
+    table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+    table.set_header(vec!["Time (UTC)", "Event", "Details"]);
+    for event in &events[skip..] {
+        let (year, month, day, hour, minute, second) = utc_datetime(event.at_ms);
+        let (kind, details) = describe(&event.action);
+        table.add_row(vec![
+            format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}"),
+            kind.to_string(),
+            details,
+        ]);
+    }
+    println!("{table}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_are_partitioned_by_day() {
+        assert_eq!(utc_datetime(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(utc_datetime(951_782_400_000), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(utc_datetime(1_736_984_096_789), (2025, 1, 15, 23, 34, 56));
+
+        let event = AuditEvent {
+            at_ms: 1_736_984_096_789,
+            action: AuditAction::UserAdded {
+                username: "alice".to_string(),
+            },
+        };
+        assert_eq!(event.location(), "audit/2025-01-15.jsonl");
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"at_ms":1736984096789,"event":"user_added","username":"alice"}"#
+        );
+    }
+
+    #[test]
+    fn test_failed_writes_are_kept_in_order() {
+        let event = |at_ms| AuditEvent {
+            at_ms,
+            action: AuditAction::RealmRemoved {
+                realm: String::new(),
+            },
+        };
+
+        let buffer = Mutex::new(vec![event(3)]);
+        requeue(&buffer, vec![event(1), event(2)]);
+        let order: Vec<u128> = buffer.lock().unwrap().iter().map(|x| x.at_ms).collect();
+        assert_eq!(order, vec![1, 2, 3]);
+
+        let buffer = Mutex::new((0..MAX_BUFFERED as u128).map(event).collect());
+        requeue(&buffer, vec![event(u128::MAX)]);
+        let buffer = buffer.lock().unwrap();
+        //the requeued event was the oldest, so it's the one to go
+        assert_eq!(buffer.len(), MAX_BUFFERED);
+        assert_eq!(buffer[0].at_ms, 0);
+    }
+}
@@ -1,32 +1,38 @@
 use crate::{
     cache_control::manager::{Caching, Directive},
+    config::BucketConfig,
     non_empty_list::NonEmptyList,
-    s3::get_bucket,
     Realm,
 };
 use comfy_table::Table;
 use dialoguer::{
     theme::{ColorfulTheme, Theme},
-    Confirm, FuzzySelect, Input,
+    Confirm, FuzzySelect, Input, MultiSelect,
 };
 use std::num::NonZeroUsize;
 
 pub mod manager;
 
-pub async fn cache() -> color_eyre::Result<()> {
-    let bucket = get_bucket();
-    let (mut caching, _) = Caching::new(&bucket).await?;
+pub async fn cache(config: &BucketConfig, site: Option<&str>) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let (mut caching, _) = Caching::new(&bucket, site).await?;
 
     let theme = ColorfulTheme::default();
     let choice = FuzzySelect::with_theme(&theme)
         .with_prompt("What do you want to do?")
-        .items(&["View Caching Rules", "Set Default", "Add New Rule"])
+        .items(&[
+            "View Caching Rules",
+            "Set Default",
+            "Add New Rule",
+            "Edit Existing Rule",
+            "Remove Existing Rule",
+        ])
         .interact()?;
 
     match choice {
         0 => {
             match caching.default.clone() {
-                Some(x) => println!("Default Caching: {x:?}"),
+                Some(x) => println!("Default Caching: {}", Directive::directives_to_header(x)),
                 None => println!("Default Caching: Nothing specified"),
             };
 
@@ -34,25 +40,63 @@ pub async fn cache() -> color_eyre::Result<()> {
             table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
             table.set_header(vec!["Pattern", "Rules"]);
 
-            for (pat, rules) in caching.get_all_caching_rules() {
-                table.add_row(vec![
-                    format!("{pat:?}"),
-                    Directive::directives_to_header(rules),
-                ]);
+            for pat in caching.get_sorted_realms() {
+                if let Some(rules) = caching.get_directives_for_realm(&pat) {
+                    table.add_row(vec![
+                        format!("{pat:?}"),
+                        Directive::directives_to_header(rules.clone()),
+                    ]);
+                }
             }
 
             println!("{table}");
         }
         1 => {
-            caching.default = get_any_number_of_directives(&theme)?;
-            caching.save(&bucket).await?;
+            let default = match caching.default.clone() {
+                Some(existing) => edit_directives(&theme, existing)?,
+                None => get_any_number_of_directives(&theme)?,
+            };
+            caching.set_default(default);
+            caching.save(&bucket, site).await?;
         }
         2 => {
             let pat = Realm::get_from_stdin(&theme)?;
             let directives = get_nonempty_directives(&theme)?;
 
             caching.set_directives(pat, directives);
-            caching.save(&bucket).await?;
+            caching.save(&bucket, site).await?;
+        }
+        3 => {
+            let Some(pat) = realm_from_stdin(&theme, &caching, "Which rule to edit?")? else {
+                println!("No caching rules in place.");
+                return Ok(());
+            };
+            let Some(existing) = caching.get_directives_for_realm(&pat).cloned() else {
+                unreachable!("realm came from the existing rules")
+            };
+
+            match edit_directives(&theme, existing)? {
+                Some(directives) => caching.set_directives(pat, directives),
+                None => {
+                    println!("No directives left, so removing the rule for {pat:?}");
+                    caching.rm_directives(&pat);
+                }
+            }
+            caching.save(&bucket, site).await?;
+        }
+        4 => {
+            let Some(pat) = realm_from_stdin(&theme, &caching, "Which rule to remove?")? else {
+                println!("No caching rules in place.");
+                return Ok(());
+            };
+
+            if Confirm::with_theme(&theme)
+                .with_prompt(format!("Confirm removal of {pat:?}"))
+                .interact()?
+            {
+                caching.rm_directives(&pat);
+                caching.save(&bucket, site).await?;
+            }
         }
         _ => unreachable!(),
     }
@@ -60,12 +104,63 @@ pub async fn cache() -> color_eyre::Result<()> {
     Ok(())
 }
 
+///`None` if there aren't any rules to pick from
+fn realm_from_stdin(
+    theme: &dyn Theme,
+    caching: &Caching,
+    prompt: &str,
+) -> color_eyre::Result<Option<Realm>> {
+    let mut realms = caching.get_sorted_realms();
+    if realms.is_empty() {
+        return Ok(None);
+    }
+
+    let items: Vec<String> = realms
+        .iter()
+        .map(|pat| match caching.get_directives_for_realm(pat) {
+            Some(rules) => format!(
+                "{pat:?}: {}",
+                Directive::directives_to_header(rules.clone())
+            ),
+            None => format!("{pat:?}"),
+        })
+        .collect();
+    let choice = FuzzySelect::with_theme(theme)
+        .with_prompt(prompt)
+        .items(&items)
+        .interact()?;
+
+    Ok(Some(realms.swap_remove(choice)))
+}
+
+///keeps whichever of the `existing` directives are still wanted, then adds any new ones. `None` if
+///that leaves nothing
+fn edit_directives(
+    theme: &dyn Theme,
+    existing: NonEmptyList<Directive>,
+) -> color_eyre::Result<Option<NonEmptyList<Directive>>> {
+    let existing: Vec<Directive> = existing.into();
+    let kept = MultiSelect::with_theme(theme)
+        .with_prompt("Which directives should stay?")
+        .items(&existing)
+        .defaults(&vec![true; existing.len()])
+        .interact()?;
+    let mut directives: Vec<Directive> = kept.into_iter().map(|i| existing[i]).collect();
+
+    if let Some(new) = get_any_number_of_directives(theme)? {
+        directives.extend(new);
+    }
+    Directive::validate(&directives)?;
+
+    Ok(NonEmptyList::new(directives))
+}
+
 fn get_any_number_of_directives(
     theme: &dyn Theme,
 ) -> color_eyre::Result<Option<NonEmptyList<Directive>>> {
     Ok(
         if Confirm::with_theme(theme)
-            .with_prompt("Would you like any directives?")
+            .with_prompt("Would you like any new directives?")
             .interact()?
         {
             Some(get_nonempty_directives(theme)?)
@@ -81,9 +176,10 @@ fn get_nonempty_directives(theme: &dyn Theme) -> color_eyre::Result<NonEmptyList
         .interact()?;
     let number_of_directives: usize = number_of_directives.into();
 
-    let directives = (0..number_of_directives)
+    let directives: Vec<Directive> = (0..number_of_directives)
         .map(|_| Directive::get_from_stdin(theme))
         .collect::<Result<_, _>>()?;
 
+    Directive::validate(&directives)?;
     Ok(NonEmptyList::new(directives).expect("number of directives should be > 0"))
 }
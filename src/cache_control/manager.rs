@@ -1,24 +1,46 @@
-use crate::{hash_raw_bytes, non_empty_list::NonEmptyList, s3::get_bytes_or_default, Realm};
-use color_eyre::eyre::bail;
+use crate::{
+    envelope::{self, Legacy},
+    error::ShoveError,
+    merge::{merge_map, merge_value, save_merged, Mergeable},
+    non_empty_list::NonEmptyList,
+    s3::{
+        get_bytes_or_default, get_if_changed, site_location, Changed, ConfigHealth, LastFetched,
+        StaleConfig,
+    },
+    store::ObjectStore,
+    versioned::{from_versioned, stored_version, to_versioned, Migration, Versioned},
+    Realm,
+};
+use color_eyre::eyre::{bail, eyre};
 use dialoguer::{theme::Theme, FuzzySelect, Input};
-use s3::Bucket;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     collections::HashMap,
     fmt::{Display, Formatter},
+    mem::discriminant,
     sync::Arc,
 };
 use tokio::sync::{Mutex, RwLock};
 
-const CC_LOCATION: &str = "cache_control.json";
+pub const CC_LOCATION: &str = "cache_control.json";
+const ONE_YEAR_SECS: usize = 365 * 24 * 60 * 60;
+const ONE_DAY_SECS: usize = 24 * 60 * 60;
+const SHORT_SECS: usize = 60;
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Directive {
     MaxAge(usize),
     NoCache,
     MustRevalidate,
     NoStore,
     StaleWhileRevalidate,
+    ///like `MaxAge`, but only for shared caches like CDNs
+    SMaxAge(usize),
+    ///for content that'll never change at that path, like hashed asset filenames
+    Immutable,
+    Public,
+    Private,
+    NoTransform,
 }
 
 impl Display for Directive {
@@ -29,12 +51,29 @@ impl Display for Directive {
             Directive::MustRevalidate => write!(f, "must-revalidate"),
             Directive::NoStore => write!(f, "no-store"),
             Directive::StaleWhileRevalidate => write!(f, "stale-while-revalidate"),
+            Directive::SMaxAge(secs) => write!(f, "s-maxage={secs}"),
+            Directive::Immutable => write!(f, "immutable"),
+            Directive::Public => write!(f, "public"),
+            Directive::Private => write!(f, "private"),
+            Directive::NoTransform => write!(f, "no-transform"),
         }
     }
 }
 
 impl Directive {
+    ///any contradictions get sorted out first - see [`Directive::resolve_conflicts`]
     pub fn directives_to_header(directives: NonEmptyList<Directive>) -> String {
+        let directives: Vec<Directive> = directives.into();
+        let directives = if Self::validate(&directives).is_ok() {
+            directives
+        } else {
+            debug!(
+                ?directives,
+                "Resolving conflicting cache-control directives"
+            );
+            Self::resolve_conflicts(&directives)
+        };
+
         directives
             .into_iter()
             .map(|x| x.to_string())
@@ -42,6 +81,66 @@ impl Directive {
             .join(", ")
     }
 
+    ///whether this says to keep the response around for a while, which makes no sense with `NoStore`
+    fn is_lifetime(&self) -> bool {
+        matches!(
+            self,
+            Self::MaxAge(_) | Self::SMaxAge(_) | Self::StaleWhileRevalidate | Self::Immutable
+        )
+    }
+
+    ///rejects directives that contradict each other, or that are there more than once
+    pub fn validate(directives: &[Directive]) -> color_eyre::Result<()> {
+        for (i, a) in directives.iter().enumerate() {
+            for b in &directives[(i + 1)..] {
+                if discriminant(a) == discriminant(b) {
+                    bail!("{a} and {b} can't both be used");
+                }
+                let contradicts = |x: &Directive, y: &Directive| {
+                    (*x == Self::NoStore && y.is_lifetime())
+                        || (*x == Self::NoCache && *y == Self::Immutable)
+                        || (*x == Self::Public && *y == Self::Private)
+                };
+                if contradicts(a, b) || contradicts(b, a) {
+                    bail!("{a} contradicts {b}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///keeps the most cautious reading - `NoStore` and `Private` win, the shortest ages win, and anything
+    ///else repeated is only kept once. used where directives from overlapping realms get combined
+    pub fn resolve_conflicts(directives: &[Directive]) -> Vec<Directive> {
+        let no_store = directives.contains(&Self::NoStore);
+        let no_cache = directives.contains(&Self::NoCache);
+        let private = directives.contains(&Self::Private);
+
+        let mut resolved: Vec<Directive> = vec![];
+        for directive in directives {
+            if (no_store && directive.is_lifetime())
+                || (no_cache && *directive == Self::Immutable)
+                || (private && *directive == Self::Public)
+            {
+                continue;
+            }
+
+            match resolved
+                .iter_mut()
+                .find(|x| discriminant(*x) == discriminant(directive))
+            {
+                Some(Self::MaxAge(existing)) | Some(Self::SMaxAge(existing)) => {
+                    if let Self::MaxAge(secs) | Self::SMaxAge(secs) = directive {
+                        *existing = (*existing).min(*secs);
+                    }
+                }
+                Some(_) => {}
+                None => resolved.push(*directive),
+            }
+        }
+        resolved
+    }
+
     pub fn get_from_stdin(theme: &dyn Theme) -> color_eyre::Result<Self> {
         let choice = FuzzySelect::with_theme(theme)
             .with_prompt("Which directive?")
@@ -51,6 +150,11 @@ impl Directive {
                 "Must Revalidate",
                 "No Store",
                 "Stale While Revalidate",
+                "Shared Max Age (s-maxage)",
+                "Immutable",
+                "Public",
+                "Private",
+                "No Transform",
             ])
             .interact()?;
 
@@ -65,122 +169,285 @@ impl Directive {
             2 => Self::MustRevalidate,
             3 => Self::NoStore,
             4 => Self::StaleWhileRevalidate,
+            5 => {
+                let max_age = Input::with_theme(theme)
+                    .with_prompt("What should the max age (seconds) be for shared caches?")
+                    .interact()?;
+                Self::SMaxAge(max_age)
+            }
+            6 => Self::Immutable,
+            7 => Self::Public,
+            8 => Self::Private,
+            9 => Self::NoTransform,
             _ => unreachable!(),
         })
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CacheControlManager {
-    last_hash: Arc<Mutex<Vec<u8>>>,
+    site: Option<String>,
+    last_fetched: Arc<Mutex<LastFetched>>,
     current: Arc<RwLock<Caching>>,
+    config_health: ConfigHealth,
+    ///from `SMART_CACHE_DEFAULTS`
+    smart_defaults: bool,
 }
 
 impl CacheControlManager {
-    pub async fn new(bucket: &Bucket) -> color_eyre::Result<Self> {
-        let (caching, raw_bytes) = Caching::new(bucket).await?;
-        let hashed_bytes = hash_raw_bytes(&raw_bytes);
-
+    pub async fn new(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+        smart_defaults: bool,
+    ) -> color_eyre::Result<Self> {
+        let (caching, raw_bytes) = Caching::new(bucket, site).await?;
         Ok(Self {
-            last_hash: Arc::new(Mutex::new(hashed_bytes)),
+            site: site.map(|x| x.to_string()),
+            last_fetched: Arc::new(Mutex::new(LastFetched::new(&raw_bytes))),
             current: Arc::new(RwLock::new(caching)),
+            config_health: ConfigHealth::default(),
+            smart_defaults,
         })
     }
 
-    pub async fn check_and_reload(&self, bucket: &Bucket) -> color_eyre::Result<()> {
-        let Ok(mut last_hash) = self.last_hash.try_lock() else {
+    ///new rules that can't be read are never swapped in - only them not being there at all means
+    ///there aren't any
+    pub async fn check_and_reload(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<()> {
+        let Ok(mut last_fetched) = self.last_fetched.try_lock() else {
             bail!("already reloading cache control")
         };
 
-        let raw_bytes = Caching::get_raw_bytes(bucket).await?;
-        if raw_bytes.is_empty() {
-            return Ok(());
+        let location = site_location(self.site.as_deref(), CC_LOCATION);
+        let new_version = match get_if_changed(bucket, &location, &mut last_fetched).await? {
+            None => return Ok(()),
+            Some(Changed::Missing) => Ok(Caching::default()),
+            Some(Changed::Found(raw_bytes)) if raw_bytes.is_empty() => {
+                Err(eyre!("cache control is there, but empty"))
+            }
+            Some(Changed::Found(raw_bytes)) => {
+                Caching::construct_from_bytes(&raw_bytes).map_err(Into::into)
+            }
+        };
+
+        match new_version {
+            Ok(new_version) => {
+                *self.current.write().await = new_version;
+                self.config_health.applied();
+                Ok(())
+            }
+            Err(e) => {
+                self.config_health.rejected(&location, &e);
+                Err(e.wrap_err("unable to read new cache control, keeping the current version"))
+            }
         }
+    }
 
-        let new_hash = hash_raw_bytes(&raw_bytes);
+    ///why the cache control in the bucket isn't what's being used, if it isn't
+    pub fn stale(&self) -> Option<StaleConfig> {
+        self.config_health.stale()
+    }
 
-        if *last_hash == new_hash {
-            return Ok(());
+    ///anything stored always wins over guessing from the content type
+    pub async fn get_directives(&self, path: &str, content_type: &str) -> Vec<Directive> {
+        let directives = self.current.read().await.get_cache_control_directives(path);
+        if directives.is_empty() && self.smart_defaults {
+            smart_default_directives(path, content_type)
+        } else {
+            directives
         }
-        *last_hash = new_hash;
+    }
+}
 
-        let new_version = Caching::construct_from_bytes(&raw_bytes)?;
-        *self.current.write().await = new_version;
+///a guess at sensible caching for when nothing's been configured. HTML always gets revalidated so new
+///deploys show up, and static assets get kept for a while - forever if the filename has a hash in it,
+///as a new version would get a new name
+pub fn smart_default_directives(path: &str, content_type: &str) -> Vec<Directive> {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
 
-        Ok(())
+    let is_asset = essence.starts_with("image/")
+        || essence.starts_with("font/")
+        || matches!(
+            essence.as_str(),
+            "text/css"
+                | "text/javascript"
+                | "application/javascript"
+                | "application/wasm"
+                | "application/font-woff"
+                | "application/vnd.ms-fontobject"
+        );
+
+    if essence == "text/html" {
+        vec![Directive::NoCache]
+    } else if essence == "application/json" || essence.ends_with("+json") {
+        vec![Directive::MaxAge(SHORT_SECS)]
+    } else if is_asset && is_fingerprinted(path) {
+        vec![Directive::MaxAge(ONE_YEAR_SECS), Directive::Immutable]
+    } else if is_asset {
+        vec![Directive::MaxAge(ONE_DAY_SECS)]
+    } else {
+        vec![]
     }
+}
 
-    pub async fn get_directives(&self, path: &str) -> Vec<Directive> {
-        self.current.read().await.get_cache_control_directives(path)
+///whether the filename has a content hash in it, like `app.3f9a2c.js` or `logo-8d2b41e0.png`
+fn is_fingerprinted(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let parts: Vec<&str> = file_name.split(['.', '-', '_']).collect();
+    if parts.len() < 3 {
+        return false;
     }
+
+    //not the first part, which is the name, or the last, which is the extension
+    parts[1..(parts.len() - 1)].iter().any(|part| {
+        part.len() >= 6
+            && part.chars().all(|c| c.is_ascii_hexdigit())
+            && part.chars().any(|c| c.is_ascii_digit())
+    })
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Caching {
+    ///`None` for no default
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub default: Option<NonEmptyList<Directive>>,
+    ///stored as a list of pairs, as realms can't be JSON keys
+    #[serde(
+        serialize_with = "rules_as_list",
+        deserialize_with = "without_empty_rules"
+    )]
     overrides: HashMap<Realm, NonEmptyList<Directive>>,
+    ///what was loaded, so saving can tell our changes from anyone else's. `None` for nothing
+    #[serde(skip)]
+    base: Option<Arc<Caching>>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct StoredCaching {
-    default: Vec<Directive>,
-    overrides: Vec<(Realm, Vec<Directive>)>,
+impl Mergeable for Caching {
+    fn merge(base: &Self, ours: Self, theirs: Self) -> Result<Self, String> {
+        Ok(Self {
+            default: merge_value(&base.default, ours.default, theirs.default)
+                .ok_or_else(|| "the default".to_string())?,
+            overrides: merge_map(&base.overrides, ours.overrides, theirs.overrides)
+                .map_err(|realm| format!("the rule for {realm}"))?,
+            base: None,
+        })
+    }
 }
 
-impl From<Caching> for StoredCaching {
-    fn from(value: Caching) -> Self {
-        Self {
-            default: value.default.map(|x| x.into()).unwrap_or_default(),
-            overrides: value
-                .overrides
-                .into_iter()
-                .map(|(r, l)| (r, l.into()))
-                .collect(),
-        }
-    }
+///older versions wrote an empty list for no default, which still works
+fn empty_as_none<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<NonEmptyList<Directive>>, D::Error> {
+    Ok(Option::<Vec<Directive>>::deserialize(deserializer)?.and_then(NonEmptyList::new))
 }
-impl From<StoredCaching> for Caching {
-    fn from(value: StoredCaching) -> Self {
-        Self {
-            default: NonEmptyList::new(value.default),
-            overrides: value
-                .overrides
-                .into_iter()
-                .flat_map(|(realm, dirs)| NonEmptyList::new(dirs).map(|nel| (realm, nel)))
-                .collect(),
-        }
-    }
+
+///older versions could also write rules with nothing in them, which never did anything
+fn without_empty_rules<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<Realm, NonEmptyList<Directive>>, D::Error> {
+    let rules = Vec::<(Realm, Vec<Directive>)>::deserialize(deserializer)?;
+    Ok(rules
+        .into_iter()
+        .flat_map(|(realm, dirs)| NonEmptyList::new(dirs).map(|nel| (realm, nel)))
+        .collect())
+}
+
+fn rules_as_list<S: Serializer>(
+    overrides: &HashMap<Realm, NonEmptyList<Directive>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(overrides)
+}
+
+impl Versioned for Caching {
+    const VERSION: u32 = 2;
+    const MIGRATIONS: &'static [Migration] = &[
+        //1 was the same, just not wrapped up with a version
+        Ok,
+    ];
 }
 
 impl Caching {
-    pub async fn new(bucket: &Bucket) -> color_eyre::Result<(Self, Vec<u8>)> {
-        let bytes = Self::get_raw_bytes(bucket).await?;
+    pub async fn new(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<(Self, Vec<u8>)> {
+        let bytes = Self::get_raw_bytes(bucket, site).await?;
         let s = Self::construct_from_bytes(&bytes)?;
         Ok((s, bytes))
     }
 
-    pub async fn save(&self, bucket: &Bucket) -> color_eyre::Result<()> {
-        let stored: StoredCaching = self.clone().into(); //can't do ref stuff because we have to do in-memory stuff for the hashmap :(
-        let bytes = serde_json::to_vec(&stored)?;
-
-        bucket
-            .put_object_with_content_type(CC_LOCATION, &bytes, "application/json")
-            .await?;
+    ///merges in anything saved since this was loaded, failing with
+    ///[`ChangedSinceLoaded`](crate::merge::ChangedSinceLoaded) if that changed the same rules
+    pub async fn save(
+        &mut self,
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> Result<(), ShoveError> {
+        let base = self.base.clone().unwrap_or_default();
+        let mut merged = save_merged(
+            bucket,
+            &site_location(site, CC_LOCATION),
+            &*base,
+            self,
+            Self::construct_from_bytes,
+            |caching| {
+                //not secret, and read by uploads too, which don't have the key
+                envelope::seal(&to_versioned(caching)?, None)
+            },
+        )
+        .await?;
 
+        merged.base = Some(Arc::new(merged.clone()));
+        *self = merged;
         Ok(())
     }
 
-    async fn get_raw_bytes(bucket: &Bucket) -> color_eyre::Result<Vec<u8>> {
-        get_bytes_or_default(bucket, CC_LOCATION).await
+    ///whether `bytes` are already how they'd be [saved](Self::save), so there's nothing to migrate
+    pub fn is_latest(bytes: &[u8]) -> color_eyre::Result<bool> {
+        if bytes.is_empty() {
+            return Ok(true);
+        }
+        if envelope::is_encrypted(bytes).is_none() {
+            return Ok(false);
+        }
+        let json = envelope::open(bytes, None, Legacy::Plain)?;
+        Ok(stored_version(&json)? == Self::VERSION)
+    }
+
+    async fn get_raw_bytes(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<Vec<u8>> {
+        Ok(get_bytes_or_default(bucket, site_location(site, CC_LOCATION)).await?)
     }
 
     //not very necessary rn, but good for API footprint stuff later
-    fn construct_from_bytes(bytes: &[u8]) -> color_eyre::Result<Self> {
+    fn construct_from_bytes(bytes: &[u8]) -> Result<Self, ShoveError> {
         if bytes.is_empty() {
             return Ok(Self::default());
         }
-        let stored: StoredCaching = serde_json::from_slice(bytes)?;
-        Ok(stored.into())
+        let json = envelope::open(bytes, None, Legacy::Plain)?;
+        let mut caching: Self = from_versioned(&json)?;
+
+        //still used, as the header gets sorted out when it's built, but worth knowing about
+        for (realm, directives) in caching
+            .default
+            .iter()
+            .map(|x| ("default".to_string(), x))
+            .chain(caching.overrides.iter().map(|(r, x)| (r.to_string(), x)))
+        {
+            if let Err(e) = Directive::validate(directives.as_ref()) {
+                warn!(?realm, ?e, "Invalid cache-control directives");
+            }
+        }
+
+        caching.base = Some(Arc::new(caching.clone()));
+        Ok(caching)
     }
 
     pub fn get_cache_control_directives(&self, path: &str) -> Vec<Directive> {
@@ -191,22 +458,367 @@ impl Caching {
             .flat_map(|(_, dirs)| dirs.clone())
             .collect();
 
-        if from_map.is_empty() {
-            if let Some(default) = &self.default {
-                from_map.extend(default.as_ref())
-            }
+        if from_map.is_empty()
+            && let Some(default) = &self.default
+        {
+            from_map.extend(default.as_ref())
         }
 
         from_map
     }
 
-    #[allow(clippy::mutable_key_type)]
-    //the hash impl uses `as_str`, and the mutable part isn't involved with that - iirc it's a cache
     pub fn get_all_caching_rules(&self) -> HashMap<Realm, NonEmptyList<Directive>> {
         self.overrides.clone()
     }
 
+    ///every realm with its own rule, sorted so they show up in the same order each time
+    pub fn get_sorted_realms(&self) -> Vec<Realm> {
+        let mut realms: Vec<Realm> = self.overrides.keys().cloned().collect();
+        realms.sort_by_cached_key(ToString::to_string);
+        realms
+    }
+
+    pub fn get_directives_for_realm(&self, realm: &Realm) -> Option<&NonEmptyList<Directive>> {
+        self.overrides.get(realm)
+    }
+
     pub fn set_directives(&mut self, realm: Realm, directives: NonEmptyList<Directive>) {
         self.overrides.insert(realm, directives);
     }
+
+    ///returns the directives that were there, if any
+    pub fn rm_directives(&mut self, realm: &Realm) -> Option<NonEmptyList<Directive>> {
+        self.overrides.remove(realm)
+    }
+
+    pub fn set_default(&mut self, default: Option<NonEmptyList<Directive>>) {
+        self.default = default;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(caching: &Caching) -> Caching {
+        let bytes = to_versioned(caching).unwrap();
+        Caching::construct_from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_every_stored_format_loads() {
+        let overrides = r#"[[{"EndsWith":".pdf"},["NoStore"]]]"#;
+        let formats = [
+            //before envelopes, with an empty list for no default
+            format!(r#"{{"default":[],"overrides":{overrides}}}"#).into_bytes(),
+            format!(r#"{{"default":null,"overrides":{overrides}}}"#).into_bytes(),
+            //in an envelope, but not versioned
+            envelope::seal(
+                format!(r#"{{"default":null,"overrides":{overrides}}}"#).as_bytes(),
+                None,
+            )
+            .unwrap(),
+            envelope::seal(
+                format!(r#"{{"version":2,"data":{{"default":null,"overrides":{overrides}}}}}"#)
+                    .as_bytes(),
+                None,
+            )
+            .unwrap(),
+        ];
+
+        for (i, bytes) in formats.iter().enumerate() {
+            let caching = Caching::construct_from_bytes(bytes).unwrap();
+            assert!(caching.default.is_none(), "{i}");
+            assert_eq!(
+                caching.get_cache_control_directives("/a.pdf"),
+                vec![Directive::NoStore],
+                "{i}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_rules_survive_saving() {
+        let mut caching = Caching::default();
+        let docs = Realm::StartsWith("/docs".to_string());
+        let pdfs = Realm::EndsWith(".pdf".to_string());
+
+        caching.set_directives(
+            docs.clone(),
+            NonEmptyList::new(vec![Directive::MaxAge(60), Directive::MustRevalidate]).unwrap(),
+        );
+        caching.set_directives(
+            pdfs.clone(),
+            NonEmptyList::single_element(Directive::NoStore),
+        );
+        caching.set_default(Some(NonEmptyList::single_element(Directive::NoCache)));
+
+        let reloaded = round_trip(&caching);
+        assert_eq!(
+            reloaded.get_sorted_realms(),
+            vec![pdfs.clone(), docs.clone()]
+        );
+        assert_eq!(
+            reloaded.get_cache_control_directives("/docs/a.html"),
+            vec![Directive::MaxAge(60), Directive::MustRevalidate]
+        );
+        assert_eq!(
+            reloaded.get_cache_control_directives("/index.html"),
+            vec![Directive::NoCache]
+        );
+
+        assert!(caching.rm_directives(&docs).is_some());
+        assert!(caching.rm_directives(&docs).is_none());
+        caching.set_default(None);
+
+        let reloaded = round_trip(&caching);
+        assert_eq!(reloaded.get_sorted_realms(), vec![pdfs]);
+        assert!(reloaded.default.is_none());
+        assert!(reloaded
+            .get_cache_control_directives("/docs/a.html")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_new_directives_round_trip() {
+        let directives = vec![
+            Directive::MaxAge(60),
+            Directive::SMaxAge(3600),
+            Directive::Immutable,
+            Directive::Public,
+            Directive::NoTransform,
+        ];
+        assert!(Directive::validate(&directives).is_ok());
+        assert_eq!(
+            Directive::directives_to_header(NonEmptyList::new(directives.clone()).unwrap()),
+            "max-age=60, s-maxage=3600, immutable, public, no-transform"
+        );
+
+        let mut caching = Caching::default();
+        caching.set_default(NonEmptyList::new(directives.clone()));
+        caching.set_directives(
+            Realm::StartsWith("/me".to_string()),
+            NonEmptyList::new(vec![Directive::Private, Directive::NoCache]).unwrap(),
+        );
+        let reloaded = round_trip(&caching);
+        assert_eq!(reloaded.get_cache_control_directives("/"), directives);
+        assert_eq!(
+            reloaded.get_cache_control_directives("/me/a.html"),
+            vec![Directive::Private, Directive::NoCache]
+        );
+
+        //unknown variants fail loudly rather than getting dropped
+        let future = br#"{"default":[{"MaxAge":10},"SomethingNew"],"overrides":[]}"#;
+        assert!(Caching::construct_from_bytes(future).is_err());
+    }
+
+    #[test]
+    fn test_contradictions_are_caught_and_resolved() {
+        for bad in [
+            vec![Directive::NoStore, Directive::MaxAge(60)],
+            vec![Directive::SMaxAge(60), Directive::NoStore],
+            vec![Directive::Public, Directive::Private],
+            vec![Directive::Immutable, Directive::NoCache],
+            vec![Directive::MaxAge(60), Directive::MaxAge(10)],
+        ] {
+            assert!(Directive::validate(&bad).is_err(), "{bad:?}");
+        }
+
+        //like when overlapping realms get combined
+        let combined = vec![
+            Directive::MaxAge(600),
+            Directive::Public,
+            Directive::Immutable,
+            Directive::MaxAge(60),
+            Directive::Private,
+            Directive::MustRevalidate,
+        ];
+        assert_eq!(
+            Directive::directives_to_header(NonEmptyList::new(combined).unwrap()),
+            "max-age=60, immutable, private, must-revalidate"
+        );
+        assert_eq!(
+            Directive::resolve_conflicts(&[
+                Directive::MaxAge(60),
+                Directive::NoStore,
+                Directive::StaleWhileRevalidate
+            ]),
+            vec![Directive::NoStore]
+        );
+    }
+
+    #[test]
+    fn test_smart_defaults_per_content_type() {
+        let long = vec![Directive::MaxAge(ONE_YEAR_SECS), Directive::Immutable];
+        let day = vec![Directive::MaxAge(ONE_DAY_SECS)];
+
+        assert_eq!(
+            smart_default_directives("/index.html", "text/html; charset=utf-8"),
+            vec![Directive::NoCache]
+        );
+        assert_eq!(
+            smart_default_directives("/api/data.json", "application/json"),
+            vec![Directive::MaxAge(SHORT_SECS)]
+        );
+        assert_eq!(
+            smart_default_directives("/manifest.webmanifest", "application/manifest+json"),
+            vec![Directive::MaxAge(SHORT_SECS)]
+        );
+
+        assert_eq!(
+            smart_default_directives("/assets/app.3f9a2c.js", "text/javascript"),
+            long
+        );
+        assert_eq!(
+            smart_default_directives("/assets/style-8d2b41e0.css", "text/css"),
+            long
+        );
+        assert_eq!(
+            smart_default_directives("/fonts/inter.a1b2c3d4.woff2", "font/woff2"),
+            long
+        );
+        assert_eq!(
+            smart_default_directives("/img/logo_20240101.png", "image/png"),
+            long
+        );
+        assert_eq!(
+            smart_default_directives("/assets/app.js", "text/javascript"),
+            day
+        );
+        assert_eq!(
+            smart_default_directives("/img/cafe.beef.png", "image/png"),
+            day
+        );
+        assert_eq!(smart_default_directives("/img/logo.png", "IMAGE/PNG"), day);
+
+        assert!(smart_default_directives("/file.bin", "application/octet-stream").is_empty());
+        assert!(smart_default_directives("/notes.txt", "text/plain").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stored_rules_beat_smart_defaults() {
+        let mut caching = Caching::default();
+        caching.set_directives(
+            Realm::StartsWith("/assets".to_string()),
+            NonEmptyList::single_element(Directive::NoStore),
+        );
+        let mut manager = CacheControlManager {
+            current: Arc::new(RwLock::new(caching)),
+            smart_defaults: true,
+            ..CacheControlManager::default()
+        };
+
+        assert_eq!(
+            manager
+                .get_directives("/assets/app.3f9a2c.js", "text/javascript")
+                .await,
+            vec![Directive::NoStore]
+        );
+        assert_eq!(
+            manager.get_directives("/index.html", "text/html").await,
+            vec![Directive::NoCache]
+        );
+
+        manager
+            .current
+            .write()
+            .await
+            .set_default(Some(NonEmptyList::single_element(Directive::MaxAge(5))));
+        assert_eq!(
+            manager.get_directives("/index.html", "text/html").await,
+            vec![Directive::MaxAge(5)]
+        );
+
+        manager.smart_defaults = false;
+        manager.current.write().await.set_default(None);
+        assert!(manager
+            .get_directives("/index.html", "text/html")
+            .await
+            .is_empty());
+    }
+
+    #[test]
+    fn test_old_empty_default_means_none() {
+        let old = br#"{"default":[],"overrides":[[{"StartsWith":"/docs"},[]]]}"#;
+        let caching = Caching::construct_from_bytes(old).unwrap();
+        assert!(caching.default.is_none());
+        assert!(caching.get_sorted_realms().is_empty());
+
+        let old = br#"{"default":[{"MaxAge":10}],"overrides":[]}"#;
+        let caching = Caching::construct_from_bytes(old).unwrap();
+        assert_eq!(
+            caching.get_cache_control_directives("/"),
+            vec![Directive::MaxAge(10)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_rules_keep_the_old_ones() {
+        use crate::store::memory::InMemoryBucket;
+
+        let bucket = InMemoryBucket::new();
+        let mut caching = Caching::default();
+        caching.set_default(Some(NonEmptyList::single_element(Directive::MaxAge(5))));
+        caching.save(&bucket, None).await.unwrap();
+        let manager = CacheControlManager::new(&bucket, None, false).await.unwrap();
+        let directives = || manager.get_directives("/index.html", "text/html");
+        assert_eq!(directives().await, vec![Directive::MaxAge(5)]);
+
+        for broken in [b"{not json".to_vec(), vec![]] {
+            bucket
+                .put(CC_LOCATION, &broken, "application/octet-stream")
+                .await
+                .unwrap();
+            assert!(manager.check_and_reload(&bucket).await.is_err());
+            assert_eq!(directives().await, vec![Directive::MaxAge(5)]);
+            assert_eq!(manager.stale().unwrap().location, CC_LOCATION);
+        }
+
+        bucket.delete(CC_LOCATION).await.unwrap();
+        manager.check_and_reload(&bucket).await.unwrap();
+        assert!(directives().await.is_empty());
+        assert!(manager.stale().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_saves_merge() {
+        use crate::store::memory::InMemoryBucket;
+
+        let bucket = InMemoryBucket::new();
+        let site = Some("example.com");
+        let docs = Realm::StartsWith("/docs".to_string());
+        let pdfs = Realm::EndsWith(".pdf".to_string());
+
+        let (mut first, _) = Caching::new(&bucket, site).await.unwrap();
+        let (mut second, _) = Caching::new(&bucket, site).await.unwrap();
+        first.set_directives(docs.clone(), NonEmptyList::single_element(Directive::NoCache));
+        first.save(&bucket, site).await.unwrap();
+        second.set_directives(pdfs.clone(), NonEmptyList::single_element(Directive::NoStore));
+        second.set_default(Some(NonEmptyList::single_element(Directive::MaxAge(5))));
+        second.save(&bucket, site).await.unwrap();
+
+        let (saved, _) = Caching::new(&bucket, site).await.unwrap();
+        assert_eq!(saved.get_sorted_realms(), vec![pdfs.clone(), docs.clone()]);
+        assert_eq!(
+            saved.get_cache_control_directives("/"),
+            vec![Directive::MaxAge(5)]
+        );
+        //and the first picks up the second's changes next time it saves
+        first.save(&bucket, site).await.unwrap();
+        assert_eq!(first.get_sorted_realms(), vec![pdfs, docs.clone()]);
+
+        //both changing the same rule can't be merged, and leaves what's saved alone
+        let (mut third, _) = Caching::new(&bucket, site).await.unwrap();
+        first.set_directives(docs.clone(), NonEmptyList::single_element(Directive::Private));
+        first.save(&bucket, site).await.unwrap();
+        third.set_directives(docs.clone(), NonEmptyList::single_element(Directive::Public));
+        let e = third.save(&bucket, site).await.unwrap_err();
+        assert!(matches!(e, ShoveError::ChangedSinceLoaded(_)), "{e}");
+
+        let (saved, _) = Caching::new(&bucket, site).await.unwrap();
+        assert_eq!(
+            saved.get_cache_control_directives("/docs/a.html"),
+            vec![Directive::Private]
+        );
+    }
 }
@@ -0,0 +1,346 @@
+use crate::{
+    protect::auth_storer::{AuthKey, PasswordPolicy},
+    store::{prefixed::Prefixed, ObjectStore},
+};
+use s3::{creds::Credentials, Bucket, Region};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+///every env var [`BucketConfig`] needs
+pub const BUCKET_ENV_VARS: [&str; 4] = [
+    "AWS_ACCESS_KEY_ID",
+    "AWS_SECRET_ACCESS_KEY",
+    "BUCKET_NAME",
+    "AWS_ENDPOINT_URL_S3",
+];
+
+///everything that was wrong with the environment, rather than just the first thing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unable to start, as the environment isn't set up right:")?;
+        for problem in &self.problems {
+            write!(f, "\n - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+///looks up one env var
+type Lookup = Box<dyn Fn(&str) -> Option<String>>;
+
+///reads env vars, noting down anything missing or invalid so it can all be reported at once
+pub struct EnvReader {
+    get: Lookup,
+    problems: Vec<String>,
+}
+
+impl EnvReader {
+    ///`get` looks up an env var
+    pub fn new(get: impl Fn(&str) -> Option<String> + 'static) -> Self {
+        Self {
+            get: Box::new(get),
+            problems: vec![],
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(|name| std::env::var(name).ok())
+    }
+
+    ///like [`Self::from_env`], with the `.env` file read again over the top. A running process's own
+    ///environment never changes, so that's the only place anything new can come from
+    pub fn from_env_reread() -> Self {
+        let dotenv: HashMap<String, String> = dotenvy::dotenv_iter()
+            .map(|vars| vars.filter_map(Result::ok).collect())
+            .unwrap_or_default();
+        Self::new(move |name| {
+            dotenv
+                .get(name)
+                .cloned()
+                .or_else(|| std::env::var(name).ok())
+        })
+    }
+
+    pub fn problem(&mut self, problem: impl Into<String>) {
+        self.problems.push(problem.into());
+    }
+
+    ///empty counts as unset
+    pub fn optional(&self, name: &str) -> Option<String> {
+        (self.get)(name).filter(|x| !x.is_empty())
+    }
+
+    ///`what` says what it is, for the error if it's missing
+    pub fn required(&mut self, name: &str, what: &str) -> String {
+        match self.optional(name) {
+            Some(x) => x,
+            None => {
+                self.problem(format!("{name} must be set to {what}"));
+                String::new()
+            }
+        }
+    }
+
+    ///`None` if it isn't set, or can't be parsed
+    pub fn parsed<T: FromStr>(&mut self, name: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        let raw = self.optional(name)?;
+        match raw.parse() {
+            Ok(x) => Some(x),
+            Err(e) => {
+                self.problem(format!("{name} is {raw:?}, which isn't valid: {e}"));
+                None
+            }
+        }
+    }
+
+    ///unset is `false`
+    pub fn flag(&mut self, name: &str) -> bool {
+        match self.optional(name).as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => {
+                self.problem(format!(
+                    "{name} is {other:?}, but should be `true` or `false`"
+                ));
+                false
+            }
+        }
+    }
+
+    pub fn finish<T>(self, value: T) -> Result<T, ConfigError> {
+        if self.problems.is_empty() {
+            Ok(value)
+        } else {
+            Err(ConfigError {
+                problems: self.problems,
+            })
+        }
+    }
+}
+
+///where the bucket is, and how to get into it
+#[derive(Clone)]
+pub struct BucketConfig {
+    pub name: String,
+    pub endpoint: String,
+    ///everything shove reads & writes goes under this, so the bucket can be shared. Empty for the
+    ///top of the bucket
+    pub prefix: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl std::fmt::Debug for BucketConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BucketConfig")
+            .field("name", &self.name)
+            .field("endpoint", &self.endpoint)
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BucketConfig {
+    pub fn new(
+        name: impl Into<String>,
+        endpoint: impl Into<String>,
+        access_key_id: impl Into<String>,
+        secret_access_key: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            endpoint: endpoint.into(),
+            prefix: String::new(),
+            access_key_id: access_key_id.into(),
+            secret_access_key: secret_access_key.into(),
+        }
+    }
+
+    pub fn read(env: &mut EnvReader) -> Self {
+        Self {
+            access_key_id: env.required("AWS_ACCESS_KEY_ID", "the key ID for the bucket"),
+            secret_access_key: env.required(
+                "AWS_SECRET_ACCESS_KEY",
+                "the secret access key for the bucket",
+            ),
+            name: env.required("BUCKET_NAME", "the name of the bucket"),
+            endpoint: env.required("AWS_ENDPOINT_URL_S3", "the endpoint of the bucket"),
+            prefix: env.optional("S3_PREFIX").unwrap_or_default(),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut env = EnvReader::from_env();
+        let config = Self::read(&mut env);
+        env.finish(config)
+    }
+
+    ///only under [`Self::prefix`]
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    ///the bucket, with every key under the prefix
+    pub fn bucket(&self) -> color_eyre::Result<Prefixed<Box<Bucket>>> {
+        Ok(self.under_prefix(self.s3_bucket()?))
+    }
+
+    pub fn under_prefix<S: ObjectStore>(&self, store: S) -> Prefixed<S> {
+        Prefixed::new(store, &self.prefix)
+    }
+
+    ///the whole bucket, ignoring the prefix
+    pub fn s3_bucket(&self) -> color_eyre::Result<Box<Bucket>> {
+        let credentials = Credentials::new(
+            Some(&self.access_key_id),
+            Some(&self.secret_access_key),
+            None,
+            None,
+            None,
+        )?;
+        let region = Region::Custom {
+            region: "auto".to_owned(),
+            endpoint: self.endpoint.clone(),
+        };
+        Ok(Bucket::new(&self.name, region, credentials)?)
+    }
+}
+
+///what's needed to read & write the auth data
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    pub key: AuthKey,
+    pub password_policy: PasswordPolicy,
+}
+
+impl AuthConfig {
+    ///the key is salted with the bucket's name
+    pub fn read(env: &mut EnvReader, bucket: &BucketConfig) -> Self {
+        let password = env.required(
+            "AUTH_ENCRYPTION_KEY",
+            "the key the authentication data is encrypted with",
+        );
+        Self {
+            key: AuthKey::derive(&password, &bucket.name),
+            password_policy: PasswordPolicy::read(env),
+        }
+    }
+
+    pub fn from_env() -> Result<(BucketConfig, Self), ConfigError> {
+        let mut env = EnvReader::from_env();
+        let bucket = BucketConfig::read(&mut env);
+        let auth = Self::read(&mut env, &bucket);
+        env.finish((bucket, auth))
+    }
+}
+
+///the key the auth data is encrypted with now, and the one it's moving to
+#[derive(Clone, Debug)]
+pub struct RekeyConfig {
+    pub old: AuthKey,
+    pub new: AuthKey,
+}
+
+impl RekeyConfig {
+    pub fn read(env: &mut EnvReader, bucket: &BucketConfig) -> Self {
+        let old = env.required(
+            "AUTH_ENCRYPTION_KEY_OLD",
+            "the key the authentication data is encrypted with now",
+        );
+        let new = env.required(
+            "AUTH_ENCRYPTION_KEY",
+            "the key to encrypt the authentication data with from now on",
+        );
+        if !old.is_empty() && old == new {
+            env.problem("AUTH_ENCRYPTION_KEY_OLD is the same as AUTH_ENCRYPTION_KEY");
+        }
+        Self {
+            old: AuthKey::derive(&old, &bucket.name),
+            new: AuthKey::derive(&new, &bucket.name),
+        }
+    }
+
+    pub fn from_env() -> Result<(BucketConfig, Self), ConfigError> {
+        let mut env = EnvReader::from_env();
+        let bucket = BucketConfig::read(&mut env);
+        let rekey = Self::read(&mut env, &bucket);
+        env.finish((bucket, rekey))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protect::auth_storer::DEFAULT_MIN_PASSWORD_LENGTH;
+    use std::collections::HashMap;
+
+    fn reader(vars: &[(&str, &str)]) -> EnvReader {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        EnvReader::new(move |name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_every_problem_is_reported() {
+        let mut env = reader(&[
+            ("AWS_ACCESS_KEY_ID", "key"),
+            ("BUCKET_NAME", ""),
+            ("MIN_PASSWORD_LENGTH", "eight"),
+            ("SOME_FLAG", "yes"),
+        ]);
+        let bucket = BucketConfig::read(&mut env);
+        let auth = AuthConfig::read(&mut env, &bucket);
+        assert!(!env.flag("SOME_FLAG"));
+        assert_eq!(auth.password_policy.min_length, DEFAULT_MIN_PASSWORD_LENGTH);
+
+        let problems = env.finish(()).unwrap_err().problems;
+        let names: Vec<&str> = problems
+            .iter()
+            .map(|x| x.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "AWS_SECRET_ACCESS_KEY",
+                "BUCKET_NAME",
+                "AWS_ENDPOINT_URL_S3",
+                "AUTH_ENCRYPTION_KEY",
+                "MIN_PASSWORD_LENGTH",
+                "SOME_FLAG"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_uploading_doesnt_need_auth() {
+        let mut env = reader(&[
+            ("AWS_ACCESS_KEY_ID", "key"),
+            ("AWS_SECRET_ACCESS_KEY", "secret"),
+            ("BUCKET_NAME", "bucket"),
+            ("AWS_ENDPOINT_URL_S3", "http://127.0.0.1:1"),
+            ("TRUE_FLAG", "true"),
+        ]);
+        let bucket = BucketConfig::read(&mut env);
+        assert!(env.flag("TRUE_FLAG"));
+        assert!(!env.flag("UNSET_FLAG"));
+        let bucket = env.finish(bucket).unwrap();
+        assert_eq!(bucket.name, "bucket");
+        assert!(!format!("{bucket:?}").contains("secret"));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{
+    config::BucketConfig,
+    cors::manager::{default_methods, AllowedOrigins, Cors, CorsRule},
+    Realm,
+};
+use comfy_table::Table;
+use dialoguer::{
+    theme::{ColorfulTheme, Theme},
+    Confirm, FuzzySelect, Input,
+};
+
+pub mod manager;
+
+pub async fn cors(config: &BucketConfig, site: Option<&str>) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let (mut cors, _) = Cors::new(&bucket, site).await?;
+
+    let theme = ColorfulTheme::default();
+    let choice = FuzzySelect::with_theme(&theme)
+        .with_prompt("What do you want to do?")
+        .items(&["View CORS Rules", "Add New Rule"])
+        .interact()?;
+
+    match choice {
+        0 => {
+            let mut table = Table::new();
+            table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+            table.set_header(vec![
+                "Pattern",
+                "Origins",
+                "Methods",
+                "Max Age",
+                "Credentials",
+            ]);
+
+            for (pat, rule) in cors.rules() {
+                table.add_row(vec![
+                    format!("{pat:?}"),
+                    match &rule.origins {
+                        AllowedOrigins::Any => "*".to_string(),
+                        AllowedOrigins::List(list) => list.join(", "),
+                    },
+                    rule.methods.join(", "),
+                    rule.max_age.map(|x| x.to_string()).unwrap_or_default(),
+                    rule.allow_credentials.to_string(),
+                ]);
+            }
+
+            println!("{table}");
+        }
+        1 => {
+            let pat = Realm::get_from_stdin(&theme)?;
+            let rule = get_rule_from_stdin(&theme)?;
+
+            cors.set_rule(pat, rule);
+            cors.save(&bucket, site).await?;
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn comma_separated(input: &str) -> Vec<String> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn get_rule_from_stdin(theme: &dyn Theme) -> color_eyre::Result<CorsRule> {
+    let origins: String = Input::with_theme(theme)
+        .with_prompt("Which origins (comma-separated, or `*` for any)?")
+        .interact()?;
+    let origins = if origins.trim() == "*" {
+        AllowedOrigins::Any
+    } else {
+        AllowedOrigins::List(comma_separated(&origins))
+    };
+
+    let methods: String = Input::with_theme(theme)
+        .with_prompt("Which methods (comma-separated)?")
+        .default(default_methods().join(", "))
+        .interact()?;
+
+    let max_age: String = Input::with_theme(theme)
+        .with_prompt("How long can preflights be cached (seconds, blank for the browser default)?")
+        .allow_empty(true)
+        .validate_with(|x: &String| {
+            if x.is_empty() || x.parse::<u64>().is_ok() {
+                Ok(())
+            } else {
+                Err("must be a number of seconds")
+            }
+        })
+        .interact()?;
+
+    let allow_credentials = Confirm::with_theme(theme)
+        .with_prompt("Allow credentials (cookies, auth)?")
+        .default(false)
+        .interact()?;
+
+    Ok(CorsRule {
+        origins,
+        methods: comma_separated(&methods),
+        max_age: max_age.parse().ok(),
+        allow_credentials,
+    })
+}
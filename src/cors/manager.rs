@@ -0,0 +1,365 @@
+use crate::{
+    s3::{get_bytes_if_changed, get_bytes_or_default, site_location, LastFetched},
+    store::ObjectStore,
+    Realm,
+};
+use color_eyre::eyre::bail;
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    Method, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+pub const CORS_LOCATION: &str = "cors.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AllowedOrigins {
+    Any,
+    List(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CorsRule {
+    pub origins: AllowedOrigins,
+    pub methods: Vec<String>,
+    pub max_age: Option<u64>,
+    ///if set, the specific origin is always echoed back rather than `*`
+    pub allow_credentials: bool,
+}
+
+pub type CorsHeaders = Vec<(HeaderName, HeaderValue)>;
+
+impl CorsRule {
+    fn allows_origin(&self, origin: &str) -> bool {
+        match &self.origins {
+            AllowedOrigins::Any => true,
+            AllowedOrigins::List(list) => list.iter().any(|x| x.eq_ignore_ascii_case(origin)),
+        }
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|x| x.eq_ignore_ascii_case(method))
+    }
+
+    ///the headers that say `origin` can read the response
+    fn allow_origin_headers(&self, origin: &str) -> Option<CorsHeaders> {
+        let allow_origin = if self.origins == AllowedOrigins::Any && !self.allow_credentials {
+            HeaderValue::from_static("*")
+        } else {
+            HeaderValue::from_str(origin).ok()?
+        };
+
+        let mut headers = vec![(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)];
+        if self.allow_credentials {
+            headers.push((
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            ));
+        }
+        Some(headers)
+    }
+}
+
+fn vary_origin() -> (HeaderName, HeaderValue) {
+    (header::VARY, HeaderValue::from_static("origin"))
+}
+
+///headers for a normal (non-preflight) response
+pub fn response_headers(rule: Option<&CorsRule>, origin: Option<&str>) -> CorsHeaders {
+    let Some(rule) = rule else {
+        return vec![];
+    };
+
+    //whether or not this origin gets let in, the response differs by origin
+    let mut headers = vec![vary_origin()];
+    if let Some(origin) = origin
+        && rule.allows_origin(origin)
+        && let Some(allow) = rule.allow_origin_headers(origin)
+    {
+        headers.extend(allow);
+    }
+    headers
+}
+
+///checks a preflight for `requested_method` from `origin`, returning the headers to allow it or the
+///headers to send with a rejection
+pub fn preflight_headers(
+    rule: Option<&CorsRule>,
+    origin: &str,
+    requested_method: &str,
+    requested_headers: Option<&HeaderValue>,
+) -> Result<CorsHeaders, (StatusCode, CorsHeaders)> {
+    let rejection = || (StatusCode::FORBIDDEN, vec![vary_origin()]);
+
+    let Some(rule) = rule else {
+        return Err(rejection());
+    };
+    if !rule.allows_origin(origin) || !rule.allows_method(requested_method) {
+        return Err(rejection());
+    }
+    let Some(allow) = rule.allow_origin_headers(origin) else {
+        return Err(rejection());
+    };
+
+    let mut headers = vec![vary_origin()];
+    headers.extend(allow);
+    if let Ok(methods) = HeaderValue::from_str(&rule.methods.join(", ")) {
+        headers.push((header::ACCESS_CONTROL_ALLOW_METHODS, methods));
+    }
+    if let Some(requested_headers) = requested_headers {
+        headers.push((
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            requested_headers.clone(),
+        ));
+    }
+    if let Some(max_age) = rule.max_age {
+        headers.push((header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age)));
+    }
+
+    Ok(headers)
+}
+
+pub fn default_methods() -> Vec<String> {
+    [Method::GET, Method::HEAD]
+        .iter()
+        .map(|x| x.to_string())
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CorsManager {
+    site: Option<String>,
+    last_fetched: Arc<Mutex<LastFetched>>,
+    current: Arc<RwLock<Cors>>,
+}
+
+impl CorsManager {
+    pub async fn new(bucket: &dyn ObjectStore, site: Option<&str>) -> color_eyre::Result<Self> {
+        let (cors, raw_bytes) = Cors::new(bucket, site).await?;
+        Ok(Self {
+            site: site.map(|x| x.to_string()),
+            last_fetched: Arc::new(Mutex::new(LastFetched::new(&raw_bytes))),
+            current: Arc::new(RwLock::new(cors)),
+        })
+    }
+
+    pub async fn check_and_reload(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<()> {
+        let Ok(mut last_fetched) = self.last_fetched.try_lock() else {
+            bail!("already reloading cors")
+        };
+
+        let location = site_location(self.site.as_deref(), CORS_LOCATION);
+        let Some(raw_bytes) = get_bytes_if_changed(bucket, location, &mut last_fetched).await?
+        else {
+            return Ok(());
+        };
+        if raw_bytes.is_empty() {
+            return Ok(());
+        }
+
+        let new_version = Cors::construct_from_bytes(&raw_bytes)?;
+        *self.current.write().await = new_version;
+
+        Ok(())
+    }
+
+    pub async fn get_rule(&self, path: &str) -> Option<CorsRule> {
+        self.current.read().await.get_rule(path).cloned()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Cors {
+    ///the first matching realm wins
+    rules: Vec<(Realm, CorsRule)>,
+}
+
+impl Cors {
+    pub async fn new(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<(Self, Vec<u8>)> {
+        let bytes = Self::get_raw_bytes(bucket, site).await?;
+        let s = Self::construct_from_bytes(&bytes)?;
+        Ok((s, bytes))
+    }
+
+    pub async fn save(
+        &self,
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        let bytes = serde_json::to_vec(&self)?;
+
+        bucket
+            .put(
+                &site_location(site, CORS_LOCATION),
+                &bytes,
+                "application/json",
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_raw_bytes(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<Vec<u8>> {
+        Ok(get_bytes_or_default(bucket, site_location(site, CORS_LOCATION)).await?)
+    }
+
+    fn construct_from_bytes(bytes: &[u8]) -> color_eyre::Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    pub fn get_rule(&self, path: &str) -> Option<&CorsRule> {
+        self.rules
+            .iter()
+            .find(|(realm, _)| realm.matches(path))
+            .map(|(_, rule)| rule)
+    }
+
+    pub fn rules(&self) -> &[(Realm, CorsRule)] {
+        &self.rules
+    }
+
+    ///replaces the rule for `realm` if there is one, otherwise adds it last
+    pub fn set_rule(&mut self, realm: Realm, rule: CorsRule) {
+        match self.rules.iter_mut().find(|(r, _)| *r == realm) {
+            Some((_, existing)) => *existing = rule,
+            None => self.rules.push((realm, rule)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(headers: &CorsHeaders, name: HeaderName) -> Option<&str> {
+        headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .and_then(|(_, v)| v.to_str().ok())
+    }
+
+    fn fonts() -> CorsRule {
+        CorsRule {
+            origins: AllowedOrigins::List(vec!["https://a.example".to_string()]),
+            methods: default_methods(),
+            max_age: Some(600),
+            allow_credentials: false,
+        }
+    }
+
+    #[test]
+    fn test_preflight_allowed() {
+        let requested = HeaderValue::from_static("x-custom");
+        let headers =
+            preflight_headers(Some(&fonts()), "https://a.example", "GET", Some(&requested))
+                .unwrap();
+
+        assert_eq!(
+            get(&headers, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://a.example")
+        );
+        assert_eq!(
+            get(&headers, header::ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET, HEAD")
+        );
+        assert_eq!(
+            get(&headers, header::ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("x-custom")
+        );
+        assert_eq!(get(&headers, header::ACCESS_CONTROL_MAX_AGE), Some("600"));
+        assert_eq!(get(&headers, header::VARY), Some("origin"));
+    }
+
+    #[test]
+    fn test_preflight_disallowed_origin() {
+        let (status, headers) =
+            preflight_headers(Some(&fonts()), "https://evil.example", "GET", None).unwrap_err();
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(get(&headers, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        assert_eq!(get(&headers, header::VARY), Some("origin"));
+    }
+
+    #[test]
+    fn test_preflight_disallowed_method() {
+        let (status, headers) =
+            preflight_headers(Some(&fonts()), "https://a.example", "DELETE", None).unwrap_err();
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(get(&headers, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+    }
+
+    #[test]
+    fn test_preflight_without_rule() {
+        let (status, _) = preflight_headers(None, "https://a.example", "GET", None).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_wildcard_unless_credentials() {
+        let mut rule = CorsRule {
+            origins: AllowedOrigins::Any,
+            ..fonts()
+        };
+
+        let headers = response_headers(Some(&rule), Some("https://b.example"));
+        assert_eq!(
+            get(&headers, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("*")
+        );
+        assert_eq!(
+            get(&headers, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            None
+        );
+
+        rule.allow_credentials = true;
+        let headers = response_headers(Some(&rule), Some("https://b.example"));
+        assert_eq!(
+            get(&headers, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://b.example")
+        );
+        assert_eq!(
+            get(&headers, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some("true")
+        );
+        assert_eq!(get(&headers, header::VARY), Some("origin"));
+    }
+
+    #[test]
+    fn test_response_for_disallowed_origin_only_varies() {
+        let headers = response_headers(Some(&fonts()), Some("https://evil.example"));
+        assert_eq!(headers, vec![vary_origin()]);
+
+        assert!(response_headers(None, Some("https://a.example")).is_empty());
+    }
+
+    #[test]
+    fn test_first_matching_realm_wins() {
+        let mut cors = Cors::default();
+        cors.set_rule(Realm::StartsWith("/fonts".to_string()), fonts());
+        cors.set_rule(
+            Realm::StartsWith("/".to_string()),
+            CorsRule {
+                origins: AllowedOrigins::Any,
+                ..fonts()
+            },
+        );
+
+        assert_eq!(cors.get_rule("/fonts/a.woff2"), Some(&fonts()));
+        assert_eq!(
+            cors.get_rule("/api/a.json").map(|x| &x.origins),
+            Some(&AllowedOrigins::Any)
+        );
+    }
+}
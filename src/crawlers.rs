@@ -0,0 +1,67 @@
+use crate::{config::BucketConfig, crawlers::manager::Crawlers};
+use comfy_table::Table;
+use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, Input};
+use regex::Regex;
+
+pub mod manager;
+
+pub async fn crawlers(config: &BucketConfig, site: Option<&str>) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let (mut crawlers, _) = Crawlers::new(&bucket, site).await?;
+
+    let theme = ColorfulTheme::default();
+    let choice = FuzzySelect::with_theme(&theme)
+        .with_prompt("What do you want to do?")
+        .items(&[
+            "View Crawlers",
+            "Add New Crawler",
+            "Remove Existing Crawler",
+        ])
+        .interact()?;
+
+    match choice {
+        0 => {
+            let mut table = Table::new();
+            table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+            table.set_header(vec!["User-Agent Pattern"]);
+
+            for user_agent in crawlers.user_agents() {
+                table.add_row(vec![user_agent.as_str()]);
+            }
+
+            println!("{table}");
+        }
+        1 => {
+            let user_agent: String = Input::with_theme(&theme)
+                .with_prompt("Which User-Agents are crawlers (regex, eg. `(?i)bot\\b`)?")
+                .validate_with(|x: &String| Regex::new(x).map(|_| ()).map_err(|e| e.to_string()))
+                .interact()?;
+
+            crawlers.add(Regex::new(&user_agent)?);
+            crawlers.save(&bucket, site).await?;
+        }
+        2 => {
+            if crawlers.user_agents().is_empty() {
+                println!("No crawlers set.");
+                return Ok(());
+            }
+
+            let items: Vec<&str> = crawlers.user_agents().iter().map(Regex::as_str).collect();
+            let choice = FuzzySelect::with_theme(&theme)
+                .with_prompt("Which crawler to remove?")
+                .items(&items)
+                .interact()?;
+
+            if Confirm::with_theme(&theme)
+                .with_prompt(format!("Confirm removal of {:?}", items[choice]))
+                .interact()?
+            {
+                crawlers.remove(choice);
+                crawlers.save(&bucket, site).await?;
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
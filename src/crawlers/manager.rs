@@ -0,0 +1,170 @@
+use crate::{
+    s3::{get_bytes_if_changed, get_bytes_or_default, site_location, LastFetched},
+    store::ObjectStore,
+};
+use color_eyre::eyre::bail;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+pub const CRAWLERS_LOCATION: &str = "crawlers.json";
+
+///`User-Agent`s that request every page once, like search engines, so whatever they get isn't worth
+///pushing anything else out of the cache for
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Crawlers {
+    #[serde(with = "serde_regex")]
+    user_agents: Vec<Regex>,
+}
+
+impl Crawlers {
+    pub async fn new(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<(Self, Vec<u8>)> {
+        let bytes = Self::get_raw_bytes(bucket, site).await?;
+        let s = Self::construct_from_bytes(&bytes)?;
+        Ok((s, bytes))
+    }
+
+    pub async fn save(
+        &self,
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        let bytes = serde_json::to_vec(&self)?;
+
+        bucket
+            .put(
+                &site_location(site, CRAWLERS_LOCATION),
+                &bytes,
+                "application/json",
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_raw_bytes(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<Vec<u8>> {
+        Ok(get_bytes_or_default(bucket, site_location(site, CRAWLERS_LOCATION)).await?)
+    }
+
+    fn construct_from_bytes(bytes: &[u8]) -> color_eyre::Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    pub fn user_agents(&self) -> &[Regex] {
+        &self.user_agents
+    }
+
+    ///does nothing if there's already the same pattern
+    pub fn add(&mut self, user_agent: Regex) {
+        if !self
+            .user_agents
+            .iter()
+            .any(|x| x.as_str() == user_agent.as_str())
+        {
+            self.user_agents.push(user_agent);
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if index < self.user_agents.len() {
+            self.user_agents.remove(index);
+        }
+    }
+
+    fn matches(&self, user_agent: &str) -> bool {
+        self.user_agents.iter().any(|x| x.is_match(user_agent))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CrawlerManager {
+    site: Option<String>,
+    last_fetched: Arc<Mutex<LastFetched>>,
+    current: Arc<RwLock<Crawlers>>,
+}
+
+impl CrawlerManager {
+    pub async fn new(bucket: &dyn ObjectStore, site: Option<&str>) -> color_eyre::Result<Self> {
+        let (crawlers, raw_bytes) = Crawlers::new(bucket, site).await?;
+        Ok(Self {
+            site: site.map(|x| x.to_string()),
+            last_fetched: Arc::new(Mutex::new(LastFetched::new(&raw_bytes))),
+            current: Arc::new(RwLock::new(crawlers)),
+        })
+    }
+
+    pub async fn check_and_reload(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<()> {
+        let Ok(mut last_fetched) = self.last_fetched.try_lock() else {
+            bail!("already reloading crawlers")
+        };
+
+        let location = site_location(self.site.as_deref(), CRAWLERS_LOCATION);
+        let Some(raw_bytes) = get_bytes_if_changed(bucket, location, &mut last_fetched).await?
+        else {
+            return Ok(());
+        };
+
+        let new_version = Crawlers::construct_from_bytes(&raw_bytes)?;
+        *self.current.write().await = new_version;
+
+        Ok(())
+    }
+
+    ///`false` without a `User-Agent`, as there's nothing to say it's one
+    pub async fn is_crawler(&self, user_agent: Option<&str>) -> bool {
+        let Some(user_agent) = user_agent else {
+            return false;
+        };
+        self.current.read().await.matches(user_agent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::InMemoryBucket;
+
+    #[tokio::test]
+    async fn test_crawlers_are_matched_and_reloaded() {
+        let bucket = InMemoryBucket::new();
+        let site = Some("example.com");
+        let manager = CrawlerManager::new(&bucket, site).await.unwrap();
+        assert!(!manager.is_crawler(Some("Googlebot/2.1")).await);
+
+        let mut crawlers = Crawlers::default();
+        crawlers.add(Regex::new("(?i)bot\\b").unwrap());
+        crawlers.add(Regex::new("(?i)bot\\b").unwrap());
+        crawlers.add(Regex::new("^curl/").unwrap());
+        assert_eq!(crawlers.user_agents().len(), 2);
+        crawlers.save(&bucket, site).await.unwrap();
+        manager.check_and_reload(&bucket).await.unwrap();
+
+        assert!(
+            manager
+                .is_crawler(Some("Mozilla/5.0 (compatible; Googlebot/2.1)"))
+                .await
+        );
+        assert!(manager.is_crawler(Some("curl/8.5.0")).await);
+        assert!(
+            !manager
+                .is_crawler(Some("Mozilla/5.0 (X11; Linux x86_64) Firefox/128.0"))
+                .await
+        );
+        assert!(!manager.is_crawler(None).await);
+
+        crawlers.remove(0);
+        crawlers.save(&bucket, site).await.unwrap();
+        manager.check_and_reload(&bucket).await.unwrap();
+        assert!(!manager.is_crawler(Some("Googlebot/2.1")).await);
+    }
+}
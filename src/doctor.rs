@@ -0,0 +1,404 @@
+use crate::{
+    cache_control::manager::{Caching, CC_LOCATION},
+    config::{BucketConfig, BUCKET_ENV_VARS},
+    cors::manager::CORS_LOCATION,
+    crawlers::manager::CRAWLERS_LOCATION,
+    entry_path,
+    headers::manager::HEADERS_LOCATION,
+    ip_filter::manager::IP_FILTER_LOCATION,
+    protect::auth_storer::{AuthKey, AuthStorer},
+    rate_limit::manager::RATE_LIMIT_LOCATION,
+    s3::{get_bytes_or_default, site_location, SITES_LOCATION, UPLOAD_DATA_LOCATION},
+    store::ObjectStore,
+    SitesManifest, UploadData,
+};
+use color_eyre::owo_colors::OwoColorize;
+use comfy_table::Table;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+///so a wrong endpoint fails rather than hanging
+const DOCTOR_TIMEOUT: Duration = Duration::from_secs(10);
+///how many missing/orphaned keys to list before just giving a count
+const MAX_LISTED_KEYS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    ///the name of the check, how it went, and what to do about it
+    checks: Vec<(String, Status, String)>,
+}
+
+impl Report {
+    fn add(&mut self, check: impl Into<String>, status: Status, details: impl Into<String>) {
+        self.checks.push((check.into(), status, details.into()));
+    }
+
+    fn pass(&mut self, check: impl Into<String>, details: impl Into<String>) {
+        self.add(check, Status::Pass, details);
+    }
+
+    fn warn(&mut self, check: impl Into<String>, details: impl Into<String>) {
+        self.add(check, Status::Warn, details);
+    }
+
+    fn fail(&mut self, check: impl Into<String>, details: impl Into<String>) {
+        self.add(check, Status::Fail, details);
+    }
+
+    fn failed(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|(_, status, _)| *status == Status::Fail)
+    }
+
+    fn print(&self) {
+        let mut table = Table::new();
+        table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+        table.set_header(vec!["Check", "Status", "Details"]);
+        for (check, status, details) in &self.checks {
+            let status = match status {
+                Status::Pass => "PASS".green().to_string(),
+                Status::Warn => "WARN".yellow().to_string(),
+                Status::Fail => "FAIL".red().to_string(),
+            };
+            table.add_row(vec![check.clone(), status, details.clone()]);
+        }
+        println!("{table}");
+    }
+}
+
+///`get` is how to look up an env var
+fn check_env(report: &mut Report, get: impl Fn(&str) -> Option<String>) -> bool {
+    let mut all_there = true;
+    for name in BUCKET_ENV_VARS {
+        if get(name).is_some_and(|x| !x.is_empty()) {
+            report.pass(name, "set");
+        } else {
+            report.fail(
+                name,
+                format!("not set - {name} is needed for anything to do with the bucket"),
+            );
+            all_there = false;
+        }
+    }
+
+    if get("AUTH_ENCRYPTION_KEY").is_some_and(|x| !x.is_empty()) {
+        report.pass("AUTH_ENCRYPTION_KEY", "set");
+    } else {
+        report.warn(
+            "AUTH_ENCRYPTION_KEY",
+            "not set - it's needed to serve or protect, but not to upload",
+        );
+    }
+
+    all_there
+}
+
+fn list_some(keys: &[&String]) -> String {
+    let mut listed: Vec<&str> = keys
+        .iter()
+        .take(MAX_LISTED_KEYS)
+        .map(|x| x.as_str())
+        .collect();
+    if keys.len() > MAX_LISTED_KEYS {
+        listed.push("...");
+    }
+    listed.join(", ")
+}
+
+///the keys `entries` has that aren't `present`, and what's `present` under the root that `entries`
+///doesn't know about (leaving out anything under `not_content`). sorted
+fn compare_entries<'a>(
+    entries: &'a HashMap<String, String>,
+    root: &str,
+    present: &'a HashSet<String>,
+    not_content: &[String],
+) -> (Vec<&'a String>, Vec<&'a String>) {
+    let mut missing: Vec<&String> = entries.keys().filter(|x| !present.contains(*x)).collect();
+    missing.sort();
+
+    let mut orphans: Vec<&String> = present
+        .iter()
+        .filter(|key| entry_path(root, key).is_some() && !entries.contains_key(*key))
+        .filter(|key| !not_content.iter().any(|x| key.starts_with(x.as_str())))
+        .collect();
+    orphans.sort();
+
+    (missing, orphans)
+}
+
+async fn list_keys(bucket: &dyn ObjectStore, prefix: &str) -> color_eyre::Result<HashSet<String>> {
+    Ok(bucket.list(prefix).await?.into_iter().collect())
+}
+
+async fn check_site(report: &mut Report, bucket: &dyn ObjectStore, site: Option<&str>) {
+    let name = site.unwrap_or("top of bucket");
+    let location = site_location(site, UPLOAD_DATA_LOCATION);
+
+    let upload_data: UploadData = match get_bytes_or_default(bucket, &location).await {
+        Ok(bytes) if bytes.is_empty() => {
+            report.warn(
+                format!("{name}: upload data"),
+                format!("nothing at {location} - run `shove upload` first"),
+            );
+            return;
+        }
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(x) => x,
+            Err(e) => {
+                report.fail(
+                    format!("{name}: upload data"),
+                    format!("{location} doesn't parse ({e}) - upload again to rewrite it"),
+                );
+                return;
+            }
+        },
+        Err(e) => {
+            report.fail(
+                format!("{name}: upload data"),
+                format!("unable to read {location}: {e}"),
+            );
+            return;
+        }
+    };
+    report.pass(
+        format!("{name}: upload data"),
+        format!(
+            "{} files under {:?}",
+            upload_data.entries.len(),
+            upload_data.root
+        ),
+    );
+
+    //with the root at the top of the bucket, every other file in the bucket would look orphaned
+    let prefix = if upload_data.root.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", upload_data.root.trim_end_matches('/'))
+    };
+    match list_keys(bucket, &prefix).await {
+        Ok(present) => {
+            let not_content: Vec<String> = [
+                UPLOAD_DATA_LOCATION,
+                CC_LOCATION,
+                HEADERS_LOCATION,
+                CORS_LOCATION,
+                IP_FILTER_LOCATION,
+                RATE_LIMIT_LOCATION,
+                CRAWLERS_LOCATION,
+                "snapshots/",
+                "releases/",
+            ]
+            .iter()
+            .map(|x| site_location(site, x))
+            .collect();
+            let (missing, orphans) = compare_entries(
+                &upload_data.entries,
+                &upload_data.root,
+                &present,
+                &not_content,
+            );
+
+            if missing.is_empty() {
+                report.pass(
+                    format!("{name}: files"),
+                    "every file in the upload data is there",
+                );
+            } else {
+                report.fail(
+                    format!("{name}: files"),
+                    format!(
+                        "{} files in the upload data are missing from the bucket ({}) - upload again",
+                        missing.len(),
+                        list_some(&missing)
+                    ),
+                );
+            }
+
+            if prefix.is_empty() {
+                report.pass(
+                    format!("{name}: orphans"),
+                    "not checked, as the site is at the top of the bucket",
+                );
+            } else if orphans.is_empty() {
+                report.pass(format!("{name}: orphans"), "nothing extra under the root");
+            } else {
+                report.warn(
+                    format!("{name}: orphans"),
+                    format!(
+                        "{} files under the root aren't in the upload data ({}) - some may be kept for `shove rollback`",
+                        orphans.len(),
+                        list_some(&orphans)
+                    ),
+                );
+            }
+        }
+        Err(e) => report.fail(
+            format!("{name}: files"),
+            format!("unable to list {prefix:?}: {e}"),
+        ),
+    }
+
+    match Caching::new(bucket, site).await {
+        Ok(_) => report.pass(format!("{name}: cache control"), "parses"),
+        Err(e) => report.fail(
+            format!("{name}: cache control"),
+            format!(
+                "{} doesn't parse ({e}) - fix it with `shove cache`",
+                site_location(site, CC_LOCATION)
+            ),
+        ),
+    }
+}
+
+///checks everything's configured right, exiting with 1 if anything isn't
+pub async fn doctor() -> color_eyre::Result<()> {
+    let mut report = Report::default();
+
+    if !check_env(&mut report, |name| env::var(name).ok()) {
+        report.print();
+        std::process::exit(1);
+    }
+
+    let config = BucketConfig::from_env()?;
+    let bucket = config.under_prefix(config.s3_bucket()?.with_request_timeout(DOCTOR_TIMEOUT)?);
+    if let Err(e) = bucket
+        .inner()
+        .list_page(config.prefix.clone(), None, None, None, Some(1))
+        .await
+    {
+        report.fail(
+            "bucket",
+            format!("unable to list the bucket ({e}) - check BUCKET_NAME, AWS_ENDPOINT_URL_S3 and the keys"),
+        );
+        report.print();
+        std::process::exit(1);
+    }
+    report.pass("bucket", "reachable");
+
+    let probe = format!(
+        "doctor-probe-{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_millis())
+    );
+    match bucket.put(&probe, b"probe", "text/plain").await {
+        Ok(()) => match bucket.delete(&probe).await {
+            Ok(()) => report.pass("credentials", "can write & delete"),
+            Err(e) => report.fail(
+                "credentials",
+                format!("can write but not delete ({e}) - {probe} needs deleting by hand, and the keys need delete permission"),
+            ),
+        },
+        Err(e) => report.fail(
+            "credentials",
+            format!("unable to write ({e}) - the keys need write permission to upload"),
+        ),
+    }
+
+    if let Ok(password) = env::var("AUTH_ENCRYPTION_KEY")
+        && !password.is_empty()
+    {
+        let key = AuthKey::derive(&password, &config.name);
+        match AuthStorer::new(&bucket, &key).await {
+            Ok((_, bytes)) if bytes.is_empty() => report.pass("auth data", "nothing protected yet"),
+            Ok((auth, _)) => report.pass(
+                "auth data",
+                format!(
+                    "decrypts, with {} users & {} realms",
+                    auth.get_users().len(),
+                    auth.get_all_realms().len()
+                ),
+            ),
+            Err(e) => report.fail(
+                "auth data",
+                format!("doesn't decrypt ({e}) - AUTH_ENCRYPTION_KEY or BUCKET_NAME has changed since it was written"),
+            ),
+        }
+    }
+
+    let mut sites = vec![None];
+    match get_bytes_or_default(&bucket, SITES_LOCATION).await {
+        Ok(bytes) if bytes.is_empty() => {}
+        Ok(bytes) => match serde_json::from_slice::<SitesManifest>(&bytes) {
+            Ok(manifest) => sites.extend(manifest.hosts.into_iter().map(Some)),
+            Err(e) => report.fail(
+                "sites",
+                format!("{SITES_LOCATION} doesn't parse ({e}) - upload a site with `--site` to rewrite it"),
+            ),
+        },
+        Err(e) => report.fail("sites", format!("unable to read {SITES_LOCATION}: {e}")),
+    }
+    for site in &sites {
+        check_site(&mut report, &bucket, site.as_deref()).await;
+    }
+
+    report.print();
+    if report.failed() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_env_vars_fail() {
+        let mut report = Report::default();
+        assert!(!check_env(&mut report, |name| {
+            (name != "BUCKET_NAME").then(|| "x".to_string())
+        }));
+        assert!(report.failed());
+        assert_eq!(
+            report
+                .checks
+                .iter()
+                .filter(|(_, status, _)| *status == Status::Fail)
+                .map(|(name, _, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["BUCKET_NAME"]
+        );
+
+        let mut report = Report::default();
+        assert!(check_env(&mut report, |name| {
+            (name != "AUTH_ENCRYPTION_KEY").then(|| "x".to_string())
+        }));
+        assert!(!report.failed());
+    }
+
+    #[test]
+    fn test_orphans_both_ways() {
+        let entries: HashMap<String, String> = ["public/index.html", "public/gone.css"]
+            .map(|x| (x.to_string(), "hash".to_string()))
+            .into();
+        let present: HashSet<String> = [
+            "public/index.html",
+            "public/stray.js",
+            "publicity/other.html",
+            "authdata",
+        ]
+        .map(ToString::to_string)
+        .into();
+
+        let (missing, orphans) = compare_entries(&entries, "public", &present, &[]);
+        assert_eq!(missing, vec!["public/gone.css"]);
+        assert_eq!(orphans, vec!["public/stray.js"]);
+
+        let (_, orphans) =
+            compare_entries(&entries, "public", &present, &["public/stray".to_string()]);
+        assert!(orphans.is_empty());
+    }
+}
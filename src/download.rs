@@ -0,0 +1,433 @@
+use crate::{
+    config::BucketConfig,
+    entry_path,
+    s3::{get_bytes_or_default, site_location, UPLOAD_DATA_LOCATION},
+    store::{Fetched, ObjectStore},
+    upload::{
+        ignore::IgnoreRules,
+        machinery::entry_hash,
+        progress::{fancy, Phase},
+    },
+    UploadData,
+};
+use color_eyre::{eyre::bail, owo_colors::OwoColorize};
+use comfy_table::Table;
+use futures::{stream, StreamExt};
+use indicatif::{HumanBytes, HumanDuration};
+use std::{
+    fmt::{Display, Formatter},
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+///how many objects get downloaded at once
+const MAX_CONCURRENT_DOWNLOADS: usize = 16;
+
+///which files to download, from `--include` & `--exclude` globs. These work like a `.shoveignore`,
+///so `blog/` or `*.html` match at any depth and `/blog` only at the root
+#[derive(Debug, Clone, Default)]
+pub struct DownloadFilter {
+    ///`None` for everything
+    includes: Option<IgnoreRules>,
+    excludes: IgnoreRules,
+}
+
+impl DownloadFilter {
+    pub fn new(includes: &[String], excludes: &[String]) -> color_eyre::Result<Self> {
+        let includes = if includes.is_empty() {
+            None
+        } else {
+            Some(IgnoreRules::new([], includes)?)
+        };
+        Ok(Self {
+            includes,
+            excludes: IgnoreRules::new([], excludes)?,
+        })
+    }
+
+    ///`relative` is `/`-separated from the site root, eg. `blog/index.html`
+    pub fn wants(&self, relative: &str) -> bool {
+        //an include "ignoring" something is it matching
+        self.includes
+            .as_ref()
+            .is_none_or(|x| x.is_ignored(relative, false))
+            && !self.excludes.is_ignored(relative, false)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    ///the key would end up outside the directory, or isn't under the upload's root
+    Unsafe,
+    Missing,
+    ///still written, as it's what's being served
+    WrongHash {
+        expected: String,
+        found: String,
+    },
+    Unreadable(String),
+    Unwritable(String),
+}
+
+impl Problem {
+    ///whether the file didn't get written
+    pub fn failed(&self) -> bool {
+        !matches!(self, Self::WrongHash { .. })
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsafe => write!(f, "not a path inside the directory, so skipped"),
+            Self::Missing => write!(f, "missing from the bucket"),
+            Self::WrongHash { expected, found } => {
+                write!(f, "hashes to {found}, but {expected} was uploaded")
+            }
+            Self::Unreadable(e) => write!(f, "unable to download ({e})"),
+            Self::Unwritable(e) => write!(f, "unable to write ({e})"),
+        }
+    }
+}
+
+///what a download did, for once it's all over
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadSummary {
+    pub downloaded: usize,
+    ///already there with the right hash
+    pub unchanged: usize,
+    ///left out by `--include` or `--exclude`
+    pub filtered: usize,
+    ///didn't get written - see [`Problem::failed`]
+    pub failed: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl DownloadSummary {
+    pub fn throughput(&self) -> u64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            (self.bytes as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+}
+
+///how one entry went
+enum Outcome {
+    Downloaded { bytes: u64 },
+    Unchanged { bytes: u64 },
+}
+
+///where `relative` goes in `dir`, or `None` if it'd end up anywhere else. Every part has to be a
+///plain name, so no `..`, absolute paths, or drive letters
+pub fn local_path(dir: &Path, relative: &str) -> Option<PathBuf> {
+    let relative = Path::new(relative.strip_prefix('/').unwrap_or(relative));
+    let mut components = relative.components().peekable();
+    components.peek()?;
+    if !components.all(|x| matches!(x, Component::Normal(_))) {
+        return None;
+    }
+    Some(dir.join(relative))
+}
+
+///writes `bytes` to `path`, as long as that's really inside `dir` - a symlink already in `dir` could
+///point anywhere
+async fn write_inside(dir: &Path, path: &Path, bytes: &[u8]) -> Result<(), Problem> {
+    let unwritable = |e: std::io::Error| Problem::Unwritable(e.to_string());
+
+    let Some(parent) = path.parent() else {
+        return Err(Problem::Unsafe);
+    };
+    tokio::fs::create_dir_all(parent)
+        .await
+        .map_err(unwritable)?;
+    let parent = tokio::fs::canonicalize(parent).await.map_err(unwritable)?;
+    if !parent.starts_with(dir) {
+        return Err(Problem::Unsafe);
+    }
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.file_type().is_symlink() => return Err(Problem::Unsafe),
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(unwritable(e)),
+    }
+
+    tokio::fs::write(path, bytes).await.map_err(unwritable)
+}
+
+///gets one entry into `dir` (which must be canonical), unless it's already there. A hash that
+///doesn't match is still written, but comes back as a problem too
+async fn download_one(
+    bucket: &dyn ObjectStore,
+    dir: &Path,
+    key: &str,
+    hash: &str,
+    path: &Path,
+) -> (Option<Outcome>, Option<Problem>) {
+    match tokio::fs::read(path).await {
+        Ok(existing) if entry_hash(&existing) == hash => {
+            return (
+                Some(Outcome::Unchanged {
+                    bytes: existing.len() as u64,
+                }),
+                None,
+            );
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return (None, Some(Problem::Unwritable(e.to_string()))),
+    }
+
+    let object = match bucket.get(key, None).await {
+        Ok(Fetched::Found(object)) => object,
+        Ok(Fetched::Missing | Fetched::NotModified) => return (None, Some(Problem::Missing)),
+        Err(e) => return (None, Some(Problem::Unreadable(e.to_string()))),
+    };
+    if let Err(problem) = write_inside(dir, path, &object.bytes).await {
+        return (None, Some(problem));
+    }
+
+    let found = entry_hash(&object.bytes);
+    let outcome = Outcome::Downloaded {
+        bytes: object.bytes.len() as u64,
+    };
+    if found == hash {
+        (Some(outcome), None)
+    } else {
+        warn!(?key, "Downloaded file doesn't match the uploaded hash");
+        (
+            Some(outcome),
+            Some(Problem::WrongHash {
+                expected: hash.to_string(),
+                found,
+            }),
+        )
+    }
+}
+
+///writes everything in `upload_data` that `filter` wants to `dir`, laid out like the site. Problems
+///are sorted by key
+pub async fn download_to_dir(
+    bucket: &dyn ObjectStore,
+    upload_data: &UploadData,
+    dir: &Path,
+    filter: &DownloadFilter,
+    quiet: bool,
+) -> color_eyre::Result<(DownloadSummary, Vec<(String, Problem)>)> {
+    let started = Instant::now();
+    tokio::fs::create_dir_all(dir).await?;
+    let dir = tokio::fs::canonicalize(dir).await?;
+
+    let mut summary = DownloadSummary::default();
+    let mut problems = vec![];
+    let mut wanted = vec![];
+    for (key, hash) in &upload_data.entries {
+        let Some(relative) = entry_path(&upload_data.root, key) else {
+            problems.push((key.clone(), Problem::Unsafe));
+            continue;
+        };
+        if !filter.wants(&relative) {
+            summary.filtered += 1;
+            continue;
+        }
+        match local_path(&dir, &relative) {
+            Some(path) => wanted.push((key, hash, path)),
+            None => problems.push((key.clone(), Problem::Unsafe)),
+        }
+    }
+
+    let fetching = Phase::downloading(wanted.len() as u64, fancy(quiet));
+    let dir = &dir;
+    let fetching_ref = &fetching;
+    let mut downloads = stream::iter(wanted)
+        .map(|(key, hash, path)| async move {
+            let (outcome, problem) = download_one(bucket, dir, key, hash, &path).await;
+            if let Some(Outcome::Downloaded { bytes } | Outcome::Unchanged { bytes }) = &outcome {
+                fetching_ref.downloaded(*bytes);
+            }
+            (key, outcome, problem)
+        })
+        .buffer_unordered(MAX_CONCURRENT_DOWNLOADS);
+
+    while let Some((key, outcome, problem)) = downloads.next().await {
+        match outcome {
+            Some(Outcome::Downloaded { bytes }) => {
+                summary.downloaded += 1;
+                summary.bytes += bytes;
+            }
+            Some(Outcome::Unchanged { .. }) => summary.unchanged += 1,
+            None => {}
+        }
+        if let Some(problem) = problem {
+            problems.push((key.clone(), problem));
+        }
+    }
+    fetching.finish();
+
+    summary.failed = problems.iter().filter(|(_, x)| x.failed()).count();
+    summary.elapsed = started.elapsed();
+    problems.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok((summary, problems))
+}
+
+///downloads the last upload into `dir`, returning whether every file made it
+pub async fn download(
+    config: &BucketConfig,
+    dir: &Path,
+    site: Option<&str>,
+    filter: &DownloadFilter,
+) -> color_eyre::Result<bool> {
+    let bucket = config.bucket()?;
+    let upload_data =
+        get_bytes_or_default(&bucket, site_location(site, UPLOAD_DATA_LOCATION)).await?;
+    if upload_data.is_empty() {
+        bail!("nothing has been uploaded yet");
+    }
+    let upload_data: UploadData = serde_json::from_slice(&upload_data)?;
+
+    let (summary, problems) = download_to_dir(&bucket, &upload_data, dir, filter, false).await?;
+
+    if !problems.is_empty() {
+        let mut table = Table::new();
+        table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+        table.set_header(vec!["Key", "Problem"]);
+        for (key, problem) in &problems {
+            table.add_row(vec![key.clone(), problem.to_string()]);
+        }
+        println!("{table}");
+    }
+    println!(
+        "{} downloaded, {} unchanged, {} filtered out, {} {}. {} transferred in {} ({}/s)",
+        summary.downloaded,
+        summary.unchanged,
+        summary.filtered,
+        summary.failed,
+        if summary.failed == 0 {
+            "failed".green().to_string()
+        } else {
+            "failed".red().to_string()
+        },
+        HumanBytes(summary.bytes),
+        HumanDuration(summary.elapsed),
+        HumanBytes(summary.throughput())
+    );
+
+    Ok(summary.failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::InMemoryBucket;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_local_paths_stay_inside() {
+        let dir = Path::new("/tmp/site");
+        assert_eq!(
+            local_path(dir, "/blog/index.html"),
+            Some(PathBuf::from("/tmp/site/blog/index.html"))
+        );
+        assert_eq!(
+            local_path(dir, "index.html"),
+            Some(PathBuf::from("/tmp/site/index.html"))
+        );
+
+        assert_eq!(local_path(dir, "/../etc/passwd"), None);
+        assert_eq!(local_path(dir, "/blog/../../etc/passwd"), None);
+        assert_eq!(local_path(dir, "//etc/passwd"), None);
+        assert_eq!(local_path(dir, "/"), None);
+        assert_eq!(local_path(dir, ""), None);
+    }
+
+    #[test]
+    fn test_filters() {
+        let everything = DownloadFilter::default();
+        assert!(everything.wants("blog/index.html"));
+
+        let filter = DownloadFilter::new(
+            &["blog/".to_string(), "*.css".to_string()],
+            &["*.map".to_string()],
+        )
+        .unwrap();
+        assert!(filter.wants("blog/index.html"));
+        assert!(filter.wants("assets/app.css"));
+        assert!(!filter.wants("index.html"));
+        assert!(!filter.wants("blog/app.js.map"));
+    }
+
+    #[tokio::test]
+    async fn test_downloads_resume_and_report_problems() {
+        let bucket = InMemoryBucket::new();
+        for (key, contents) in [
+            ("public/index.html", "<h1>hi</h1>"),
+            ("public/blog/post.html", "<p>post</p>"),
+            ("public/changed.js", "let x = 2;"),
+            ("public/app.js.map", "{}"),
+        ] {
+            bucket
+                .put(key, contents.as_bytes(), "text/plain")
+                .await
+                .unwrap();
+        }
+        let upload_data = UploadData {
+            entries: [
+                ("public/index.html", entry_hash(b"<h1>hi</h1>")),
+                ("public/blog/post.html", entry_hash(b"<p>post</p>")),
+                ("public/changed.js", entry_hash(b"let x = 1;")),
+                ("public/app.js.map", entry_hash(b"{}")),
+                ("public/gone.png", entry_hash(b"png")),
+                ("public/../escaped.html", entry_hash(b"")),
+            ]
+            .into_iter()
+            .map(|(key, hash)| (key.to_string(), hash))
+            .collect(),
+            root: "public".to_string(),
+            ..Default::default()
+        };
+
+        let dir = std::env::temp_dir().join(format!(
+            "shove-download-test-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let filter = DownloadFilter::new(&[], &["*.map".to_string()]).unwrap();
+
+        let (summary, problems) = download_to_dir(&bucket, &upload_data, &dir, &filter, true)
+            .await
+            .unwrap();
+        assert_eq!(summary.downloaded, 3);
+        assert_eq!(summary.filtered, 1);
+        assert_eq!(summary.failed, 2);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("blog/post.html")).unwrap(),
+            "<p>post</p>"
+        );
+        assert!(!dir.join("app.js.map").exists());
+        assert!(!dir.parent().unwrap().join("escaped.html").exists());
+
+        let problems: Vec<_> = problems.iter().map(|(key, x)| (key.as_str(), x)).collect();
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0], ("public/../escaped.html", &Problem::Unsafe));
+        assert!(matches!(
+            problems[1],
+            ("public/changed.js", Problem::WrongHash { .. })
+        ));
+        assert_eq!(problems[2], ("public/gone.png", &Problem::Missing));
+
+        //only the file that didn't match gets fetched again
+        let (summary, _) = download_to_dir(&bucket, &upload_data, &dir, &filter, true)
+            .await
+            .unwrap();
+        assert_eq!(summary.downloaded, 1);
+        assert_eq!(summary.unchanged, 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{error::ShoveError, protect::auth_storer::AuthKey};
+
+///what every enveloped blob starts with
+const MAGIC: &[u8; 3] = b"shv";
+const VERSION: u8 = 1;
+const PLAIN: u8 = 0;
+const ENCRYPTED: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+///how to read a blob from before there were envelopes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Legacy {
+    ///as it is, like the cache control config
+    Plain,
+    ///a nonce & the ciphertext, like the auth data
+    Encrypted,
+}
+
+///wraps `contents` up for the bucket, encrypting them if there's a `key`
+pub fn seal(contents: &[u8], key: Option<&AuthKey>) -> Result<Vec<u8>, ShoveError> {
+    let mut sealed = MAGIC.to_vec();
+    sealed.push(VERSION);
+    match key {
+        Some(key) => {
+            sealed.push(ENCRYPTED);
+            sealed.extend(key.encrypt(contents)?);
+        }
+        None => {
+            sealed.push(PLAIN);
+            sealed.extend_from_slice(contents);
+        }
+    }
+    Ok(sealed)
+}
+
+///whether `bytes` were sealed with a key. `None` if they aren't in an envelope
+pub fn is_encrypted(bytes: &[u8]) -> Option<bool> {
+    if bytes.len() < HEADER_LEN || !bytes.starts_with(MAGIC) {
+        return None;
+    }
+    Some(bytes[MAGIC.len() + 1] == ENCRYPTED)
+}
+
+///what was [sealed](seal), or anything from before then read as `legacy`
+pub fn open(bytes: &[u8], key: Option<&AuthKey>, legacy: Legacy) -> Result<Vec<u8>, ShoveError> {
+    let Some(encrypted) = is_encrypted(bytes) else {
+        return open_legacy(bytes, key, legacy);
+    };
+    let version = bytes[MAGIC.len()];
+    if version != VERSION {
+        return Err(ShoveError::TooNew {
+            version: version.into(),
+            latest: VERSION.into(),
+        });
+    }
+
+    let payload = &bytes[HEADER_LEN..];
+    if !encrypted {
+        return Ok(payload.to_vec());
+    }
+    let Some(key) = key else {
+        return Err(needs_key());
+    };
+    match key.decrypt(payload) {
+        Ok(contents) => Ok(contents),
+        //an old nonce can start with the magic bytes too
+        Err(e) if legacy == Legacy::Encrypted => key.decrypt(bytes).map_err(|_| e),
+        Err(e) => Err(e),
+    }
+}
+
+fn open_legacy(bytes: &[u8], key: Option<&AuthKey>, legacy: Legacy) -> Result<Vec<u8>, ShoveError> {
+    match legacy {
+        Legacy::Plain => Ok(bytes.to_vec()),
+        Legacy::Encrypted => key.ok_or_else(needs_key)?.decrypt(bytes),
+    }
+}
+
+fn needs_key() -> ShoveError {
+    ShoveError::Config("encrypted, so needs AUTH_ENCRYPTION_KEY".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_and_legacy_blobs_open() {
+        let key = AuthKey::derive("hunter2", "bucket");
+        let other = AuthKey::derive("hunter3", "bucket");
+
+        let plain = seal(b"{}", None).unwrap();
+        assert_eq!(is_encrypted(&plain), Some(false));
+        assert_eq!(open(&plain, None, Legacy::Plain).unwrap(), b"{}");
+
+        let encrypted = seal(b"{}", Some(&key)).unwrap();
+        assert_eq!(is_encrypted(&encrypted), Some(true));
+        assert_eq!(open(&encrypted, Some(&key), Legacy::Plain).unwrap(), b"{}");
+        assert!(matches!(
+            open(&encrypted, Some(&other), Legacy::Plain),
+            Err(ShoveError::Crypto(_))
+        ));
+        assert!(matches!(
+            open(&encrypted, None, Legacy::Plain),
+            Err(ShoveError::Config(_))
+        ));
+
+        assert_eq!(open(b"{}", None, Legacy::Plain).unwrap(), b"{}");
+        let old = key.encrypt(b"{}").unwrap();
+        assert_eq!(is_encrypted(&old), None);
+        assert_eq!(open(&old, Some(&key), Legacy::Encrypted).unwrap(), b"{}");
+
+        let mut future = plain.clone();
+        future[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(
+            open(&future, None, Legacy::Plain),
+            Err(ShoveError::TooNew { .. })
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::merge::ChangedSinceLoaded;
+use s3::error::S3Error;
+use serde::de::DeserializeOwned;
+use std::{io, path::PathBuf, time::Duration};
+
+///what the store, the stored data & the uploads can fail with, so anything using them can tell
+///something not being there from it not decrypting from the store being down without matching on
+///messages. Turns into a [`color_eyre::Report`] with `?` like anything else
+#[derive(Debug, thiserror::Error)]
+pub enum ShoveError {
+    ///there's nothing at a key that was expected to have something
+    #[error("{key:?} is missing")]
+    Missing { key: String },
+    ///the store said no, or couldn't be reached at all
+    #[error("store error for {key:?}: {source}")]
+    Store {
+        key: String,
+        #[source]
+        source: S3Error,
+    },
+    ///the store didn't answer about `key` in time
+    #[error("{key:?} took longer than {after:?}")]
+    TimedOut { key: String, after: Duration },
+    ///the store is only ever read from, like a local directory
+    #[error("unable to change {key:?}, as the store is only ever read from")]
+    ReadOnly { key: String },
+    #[error("{path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    ///`what` couldn't be turned into or out of JSON
+    #[error("unable to handle {what} as JSON: {source}")]
+    Serde {
+        what: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    ///encrypting, decrypting or hashing went wrong - usually the wrong `AUTH_ENCRYPTION_KEY`
+    #[error("{0}")]
+    Crypto(String),
+    ///something that was asked for doesn't make sense, like overlapping mappings or a missing key
+    #[error("{0}")]
+    Config(String),
+    ///something was stored in a newer shape than this version of shove can read
+    #[error("written as version {version}, but this only reads up to {latest} - update shove")]
+    TooNew { version: u32, latest: u32 },
+    ///something read from or written to the store isn't what it should be
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    ChangedSinceLoaded(#[from] ChangedSinceLoaded),
+    ///something else kept writing to `key` while we were trying to
+    #[error("{key:?} kept changing whilst saving")]
+    Busy { key: String },
+}
+
+impl ShoveError {
+    pub fn store(key: &str, source: S3Error) -> Self {
+        Self::Store {
+            key: key.to_string(),
+            source,
+        }
+    }
+
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Self::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub fn serde(what: impl ToString, source: serde_json::Error) -> Self {
+        Self::Serde {
+            what: what.to_string(),
+            source,
+        }
+    }
+
+    ///the HTTP status the store answered with, if it got as far as answering
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Store {
+                source: S3Error::HttpFailWithBody(status, _),
+                ..
+            } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+///`bytes` as JSON, saying it was `what` if they aren't
+pub fn parse_json<T: DeserializeOwned>(what: &str, bytes: &[u8]) -> Result<T, ShoveError> {
+    serde_json::from_slice(bytes).map_err(|source| ShoveError::serde(what, source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_keeps_the_key() {
+        let missing = ShoveError::Missing {
+            key: "public/index.html".to_string(),
+        };
+        assert_eq!(missing.to_string(), "\"public/index.html\" is missing");
+        assert_eq!(missing.status(), None);
+
+        let forbidden = ShoveError::store(
+            "upload_data.json",
+            S3Error::HttpFailWithBody(403, "AccessDenied".to_string()),
+        );
+        assert!(
+            forbidden.to_string().contains("\"upload_data.json\""),
+            "{forbidden}"
+        );
+        assert_eq!(forbidden.status(), Some(403));
+
+        let e = parse_json::<Vec<u8>>("sites.json", b"{").unwrap_err();
+        assert!(matches!(&e, ShoveError::Serde { what, .. } if what == "sites.json"));
+        assert!(e.to_string().contains("sites.json"), "{e}");
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{
+    config::BucketConfig,
+    headers::manager::{ExtraHeader, Headers},
+    non_empty_list::NonEmptyList,
+    Realm,
+};
+use comfy_table::Table;
+use dialoguer::{
+    theme::{ColorfulTheme, Theme},
+    Confirm, FuzzySelect, Input,
+};
+use std::num::NonZeroUsize;
+
+pub mod manager;
+
+pub async fn headers(config: &BucketConfig, site: Option<&str>) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let (mut headers, _) = Headers::new(&bucket, site).await?;
+
+    let theme = ColorfulTheme::default();
+    let choice = FuzzySelect::with_theme(&theme)
+        .with_prompt("What do you want to do?")
+        .items(&[
+            "View Header Rules",
+            "Set Default",
+            "Add New Rule",
+            "Set Content Type",
+        ])
+        .interact()?;
+
+    match choice {
+        0 => {
+            let mut table = Table::new();
+            table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+            table.set_header(vec!["Pattern", "Name", "Value"]);
+
+            for header in headers.default.iter().flat_map(|x| x.iter()) {
+                table.add_row(vec![
+                    "Default".to_string(),
+                    header.name.clone(),
+                    header.value.clone(),
+                ]);
+            }
+            for (pat, rules) in headers.get_all_header_rules() {
+                for header in rules {
+                    table.add_row(vec![format!("{pat:?}"), header.name, header.value]);
+                }
+            }
+
+            println!("{table}");
+        }
+        1 => {
+            headers.default = get_any_number_of_headers(&theme)?;
+            headers.save(&bucket, site).await?;
+        }
+        2 => {
+            let pat = Realm::get_from_stdin(&theme)?;
+            let rules = get_nonempty_headers(&theme)?;
+
+            headers.set_headers(pat, rules);
+            headers.save(&bucket, site).await?;
+        }
+        3 => {
+            let pat = Realm::get_from_stdin(&theme)?;
+            let content_type: String = Input::with_theme(&theme)
+                .with_prompt("What should the content type be?")
+                .validate_with(|ct: &String| {
+                    ct.parse::<mime::Mime>()
+                        .map(|_| ())
+                        .map_err(|_| "not a valid content type")
+                })
+                .interact()?;
+
+            headers.set_header(
+                pat,
+                ExtraHeader {
+                    name: "Content-Type".to_string(),
+                    value: content_type,
+                },
+            );
+            headers.save(&bucket, site).await?;
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn get_any_number_of_headers(
+    theme: &dyn Theme,
+) -> color_eyre::Result<Option<NonEmptyList<ExtraHeader>>> {
+    Ok(
+        if Confirm::with_theme(theme)
+            .with_prompt("Would you like any headers?")
+            .interact()?
+        {
+            Some(get_nonempty_headers(theme)?)
+        } else {
+            None
+        },
+    )
+}
+
+fn get_nonempty_headers(theme: &dyn Theme) -> color_eyre::Result<NonEmptyList<ExtraHeader>> {
+    let number_of_headers: NonZeroUsize = Input::with_theme(theme)
+        .with_prompt("How many headers (must be >0)?")
+        .interact()?;
+    let number_of_headers: usize = number_of_headers.into();
+
+    let headers = (0..number_of_headers)
+        .map(|_| ExtraHeader::get_from_stdin(theme))
+        .collect::<Result<_, _>>()?;
+
+    Ok(NonEmptyList::new(headers).expect("number of headers should be > 0"))
+}
@@ -0,0 +1,403 @@
+use crate::{
+    non_empty_list::NonEmptyList,
+    s3::{get_bytes_if_changed, get_bytes_or_default, site_location, LastFetched},
+    store::ObjectStore,
+    Realm,
+};
+use color_eyre::eyre::bail;
+use dialoguer::{theme::Theme, Input};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{Mutex, RwLock};
+
+pub const HEADERS_LOCATION: &str = "headers.json";
+
+///only mean something to the connection they're sent on, so can't be set for a file
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExtraHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl ExtraHeader {
+    ///why a header called `name` can't be set, if it can't
+    fn check_name(name: &str) -> Result<HeaderName, String> {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("{name:?} isn't a valid header name"))?;
+        if header_name == CONTENT_LENGTH {
+            return Err(format!("{name} is set by shove, from what's sent"));
+        }
+        if HOP_BY_HOP.contains(&header_name.as_str()) {
+            return Err(format!(
+                "{name} is hop-by-hop, so only means something to one connection"
+            ));
+        }
+        Ok(header_name)
+    }
+
+    ///why it can't be set, if it can't
+    pub fn check(&self) -> Result<(HeaderName, HeaderValue), String> {
+        let name = Self::check_name(&self.name)?;
+        let value = HeaderValue::from_str(&self.value)
+            .map_err(|_| format!("{:?} isn't a valid header value", self.value))?;
+        if name == CONTENT_TYPE && self.value.parse::<mime::Mime>().is_err() {
+            return Err(format!("{:?} isn't a valid content type", self.value));
+        }
+        Ok((name, value))
+    }
+
+    ///`None` if it [can't be set](Self::check)
+    pub fn to_header(&self) -> Option<(HeaderName, HeaderValue)> {
+        self.check().ok()
+    }
+
+    pub fn get_from_stdin(theme: &dyn Theme) -> color_eyre::Result<Self> {
+        let name: String = Input::with_theme(theme)
+            .with_prompt("What's the header name?")
+            .validate_with(|name: &String| Self::check_name(name).map(|_| ()))
+            .interact()?;
+        let value: String = Input::with_theme(theme)
+            .with_prompt("What should the value be?")
+            .validate_with(|value: &String| {
+                Self {
+                    name: name.clone(),
+                    value: value.clone(),
+                }
+                .check()
+                .map(|_| ())
+            })
+            .interact()?;
+
+        Ok(Self { name, value })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HeaderManager {
+    site: Option<String>,
+    last_fetched: Arc<Mutex<LastFetched>>,
+    current: Arc<RwLock<Headers>>,
+}
+
+impl HeaderManager {
+    pub async fn new(bucket: &dyn ObjectStore, site: Option<&str>) -> color_eyre::Result<Self> {
+        let (headers, raw_bytes) = Headers::new(bucket, site).await?;
+        Ok(Self {
+            site: site.map(|x| x.to_string()),
+            last_fetched: Arc::new(Mutex::new(LastFetched::new(&raw_bytes))),
+            current: Arc::new(RwLock::new(headers)),
+        })
+    }
+
+    pub async fn check_and_reload(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<()> {
+        let Ok(mut last_fetched) = self.last_fetched.try_lock() else {
+            bail!("already reloading headers")
+        };
+
+        let location = site_location(self.site.as_deref(), HEADERS_LOCATION);
+        let Some(raw_bytes) = get_bytes_if_changed(bucket, location, &mut last_fetched).await?
+        else {
+            return Ok(());
+        };
+        if raw_bytes.is_empty() {
+            return Ok(());
+        }
+
+        let new_version = Headers::construct_from_bytes(&raw_bytes)?;
+        *self.current.write().await = new_version;
+
+        Ok(())
+    }
+
+    pub async fn get_headers(&self, path: &str) -> Vec<ExtraHeader> {
+        self.current.read().await.get_headers(path)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    pub default: Option<NonEmptyList<ExtraHeader>>,
+    overrides: HashMap<Realm, NonEmptyList<ExtraHeader>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredHeaders {
+    default: Vec<ExtraHeader>,
+    overrides: Vec<(Realm, Vec<ExtraHeader>)>,
+}
+
+impl From<Headers> for StoredHeaders {
+    fn from(value: Headers) -> Self {
+        Self {
+            default: value.default.map(|x| x.into()).unwrap_or_default(),
+            overrides: value
+                .overrides
+                .into_iter()
+                .map(|(r, l)| (r, l.into()))
+                .collect(),
+        }
+    }
+}
+impl From<StoredHeaders> for Headers {
+    fn from(value: StoredHeaders) -> Self {
+        Self {
+            default: NonEmptyList::new(value.default),
+            overrides: value
+                .overrides
+                .into_iter()
+                .flat_map(|(realm, headers)| NonEmptyList::new(headers).map(|nel| (realm, nel)))
+                .collect(),
+        }
+    }
+}
+
+impl Headers {
+    pub async fn new(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<(Self, Vec<u8>)> {
+        let bytes = Self::get_raw_bytes(bucket, site).await?;
+        let s = Self::construct_from_bytes(&bytes)?;
+        Ok((s, bytes))
+    }
+
+    pub async fn save(
+        &self,
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        let stored: StoredHeaders = self.clone().into();
+        let bytes = serde_json::to_vec(&stored)?;
+
+        bucket
+            .put(
+                &site_location(site, HEADERS_LOCATION),
+                &bytes,
+                "application/json",
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_raw_bytes(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<Vec<u8>> {
+        Ok(get_bytes_or_default(bucket, site_location(site, HEADERS_LOCATION)).await?)
+    }
+
+    fn construct_from_bytes(bytes: &[u8]) -> color_eyre::Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        let stored: StoredHeaders = serde_json::from_slice(bytes)?;
+        let headers: Self = stored.into();
+
+        //skipped when serving, but worth knowing about
+        for (realm, header) in headers
+            .default
+            .iter()
+            .flat_map(|x| x.iter().map(|header| ("default".to_string(), header)))
+            .chain(
+                headers
+                    .overrides
+                    .iter()
+                    .flat_map(|(r, x)| x.iter().map(move |header| (r.to_string(), header))),
+            )
+        {
+            if let Err(e) = header.check() {
+                warn!(?realm, %e, "Invalid header");
+            }
+        }
+
+        Ok(headers)
+    }
+
+    ///the defaults, with any matching realms' headers replacing defaults of the same name
+    pub fn get_headers(&self, path: &str) -> Vec<ExtraHeader> {
+        let mut headers: Vec<ExtraHeader> = self
+            .default
+            .as_ref()
+            .map(|x| x.as_ref().to_vec())
+            .unwrap_or_default();
+
+        for header in self
+            .overrides
+            .iter()
+            .filter(|(realm, _)| realm.matches(path))
+            .flat_map(|(_, headers)| headers.iter())
+        {
+            match headers
+                .iter_mut()
+                .find(|x| x.name.eq_ignore_ascii_case(&header.name))
+            {
+                Some(existing) => existing.value.clone_from(&header.value),
+                None => headers.push(header.clone()),
+            }
+        }
+
+        headers
+    }
+
+    pub fn get_all_header_rules(&self) -> HashMap<Realm, NonEmptyList<ExtraHeader>> {
+        self.overrides.clone()
+    }
+
+    pub fn set_headers(&mut self, realm: Realm, headers: NonEmptyList<ExtraHeader>) {
+        self.overrides.insert(realm, headers);
+    }
+
+    ///adds `header` to the realm's rule, replacing one of the same name and leaving the rest
+    pub fn set_header(&mut self, realm: Realm, header: ExtraHeader) {
+        match self.overrides.get_mut(&realm) {
+            Some(headers) => match headers
+                .iter_mut()
+                .find(|x| x.name.eq_ignore_ascii_case(&header.name))
+            {
+                Some(existing) => *existing = header,
+                None => headers.push(header),
+            },
+            None => {
+                self.overrides
+                    .insert(realm, NonEmptyList::single_element(header));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> ExtraHeader {
+        ExtraHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_realm_overrides_default_by_name() {
+        let mut headers = Headers {
+            default: NonEmptyList::new(vec![
+                header("X-Frame-Options", "DENY"),
+                header("Referrer-Policy", "no-referrer"),
+            ]),
+            overrides: HashMap::new(),
+        };
+        headers.set_headers(
+            Realm::StartsWith("/embed".to_string()),
+            NonEmptyList::new(vec![
+                header("x-frame-options", "SAMEORIGIN"),
+                header("Content-Security-Policy", "frame-ancestors 'self'"),
+            ])
+            .unwrap(),
+        );
+
+        assert_eq!(
+            headers.get_headers("/index.html"),
+            vec![
+                header("X-Frame-Options", "DENY"),
+                header("Referrer-Policy", "no-referrer"),
+            ]
+        );
+        assert_eq!(
+            headers.get_headers("/embed/index.html"),
+            vec![
+                header("X-Frame-Options", "SAMEORIGIN"),
+                header("Referrer-Policy", "no-referrer"),
+                header("Content-Security-Policy", "frame-ancestors 'self'"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_headers_round_trip_through_storage() {
+        let mut headers = Headers {
+            default: NonEmptyList::new(vec![header("X-Content-Type-Options", "nosniff")]),
+            overrides: HashMap::new(),
+        };
+        headers.set_headers(
+            Realm::EndsWith(".html".to_string()),
+            NonEmptyList::new(vec![header(
+                "Strict-Transport-Security",
+                "max-age=63072000",
+            )])
+            .unwrap(),
+        );
+
+        let bytes = serde_json::to_vec(&StoredHeaders::from(headers.clone())).unwrap();
+        let read = Headers::construct_from_bytes(&bytes).unwrap();
+
+        assert_eq!(read.get_headers("/a.html"), headers.get_headers("/a.html"));
+        assert_eq!(read.get_headers("/a.css"), headers.get_headers("/a.css"));
+    }
+
+    #[test]
+    fn test_reserved_headers_are_skipped() {
+        assert!(header("Content-Length", "5").to_header().is_none());
+        assert!(header("not a name", "x").to_header().is_none());
+        assert!(header("X-Frame-Options", "DENY").to_header().is_some());
+    }
+
+    #[test]
+    fn test_headers_that_would_break_responses_are_rejected() {
+        for (name, value) in [
+            ("Content-Length", "5"),
+            ("Transfer-Encoding", "chunked"),
+            ("connection", "close"),
+            ("Keep-Alive", "timeout=5"),
+            ("Upgrade", "websocket"),
+            ("TE", "trailers"),
+            ("Trailer", "Expires"),
+            ("Proxy-Authorization", "Basic abc"),
+            ("Content-Type", "not a type"),
+            ("X-Broken", "new\nline"),
+        ] {
+            assert!(header(name, value).check().is_err(), "{name}: {value}");
+        }
+
+        for (name, value) in [
+            ("Content-Type", "application/wasm"),
+            ("content-type", "text/plain; charset=iso-8859-1"),
+            ("Content-Disposition", "attachment; filename=\"report.pdf\""),
+        ] {
+            assert!(header(name, value).check().is_ok(), "{name}: {value}");
+        }
+    }
+
+    #[test]
+    fn test_setting_one_header_keeps_the_rest() {
+        let mut headers = Headers::default();
+        let wasm = Realm::EndsWith(".wasm".to_string());
+        headers.set_header(
+            wasm.clone(),
+            header("Content-Type", "application/octet-stream"),
+        );
+        headers.set_header(wasm.clone(), header("X-Robots-Tag", "noindex"));
+        headers.set_header(wasm, header("content-type", "application/wasm"));
+
+        assert_eq!(
+            headers.get_headers("/app.wasm"),
+            vec![
+                header("content-type", "application/wasm"),
+                header("X-Robots-Tag", "noindex"),
+            ]
+        );
+        assert!(headers.get_headers("/app.js").is_empty());
+    }
+}
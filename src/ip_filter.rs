@@ -0,0 +1,113 @@
+use crate::{
+    config::BucketConfig,
+    ip_filter::manager::{IpFilter, IpRule},
+    Realm,
+};
+use comfy_table::Table;
+use dialoguer::{
+    theme::{ColorfulTheme, Theme},
+    FuzzySelect, Input,
+};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+pub mod manager;
+
+pub async fn ip_filter(config: &BucketConfig, site: Option<&str>) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let (mut ip_filter, _) = IpFilter::new(&bucket, site).await?;
+
+    let theme = ColorfulTheme::default();
+    let choice = FuzzySelect::with_theme(&theme)
+        .with_prompt("What do you want to do?")
+        .items(&["View IP Rules", "Set Default", "Add New Rule"])
+        .interact()?;
+
+    match choice {
+        0 => {
+            let mut table = Table::new();
+            table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+            table.set_header(vec!["Pattern", "Allow", "Deny"]);
+
+            table.add_row(rule_row("Default".to_string(), &ip_filter.default));
+            for (pat, rule) in ip_filter.rules() {
+                table.add_row(rule_row(format!("{pat:?}"), rule));
+            }
+
+            println!("{table}");
+        }
+        1 => {
+            ip_filter.default = get_rule_from_stdin(&theme)?;
+            ip_filter.save(&bucket, site).await?;
+        }
+        2 => {
+            let pat = Realm::get_from_stdin(&theme)?;
+            let rule = get_rule_from_stdin(&theme)?;
+
+            ip_filter.set_rule(pat, rule);
+            ip_filter.save(&bucket, site).await?;
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn rule_row(pattern: String, rule: &IpRule) -> Vec<String> {
+    let list = |nets: &[IpNet]| {
+        if nets.is_empty() {
+            "-".to_string()
+        } else {
+            nets.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    };
+    vec![pattern, list(&rule.allow), list(&rule.deny)]
+}
+
+///a bare address is taken as a network with just that address in it
+pub fn parse_net(input: &str) -> Option<IpNet> {
+    input
+        .parse()
+        .ok()
+        .or_else(|| input.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+fn nets_from_stdin(theme: &dyn Theme, prompt: &str) -> color_eyre::Result<Vec<IpNet>> {
+    let input: String = Input::with_theme(theme)
+        .with_prompt(prompt)
+        .allow_empty(true)
+        .validate_with(|x: &String| {
+            if x.split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .all(|x| parse_net(x).is_some())
+            {
+                Ok(())
+            } else {
+                Err("must be comma-separated addresses or CIDRs, eg. `192.0.2.0/24`")
+            }
+        })
+        .interact()?;
+
+    Ok(input
+        .split(',')
+        .map(str::trim)
+        .filter_map(parse_net)
+        .collect())
+}
+
+fn get_rule_from_stdin(theme: &dyn Theme) -> color_eyre::Result<IpRule> {
+    let allow = nets_from_stdin(
+        theme,
+        "Which networks are allowed (comma-separated, blank for any)?",
+    )?;
+    let deny = nets_from_stdin(
+        theme,
+        "Which networks are denied (comma-separated, blank for none)?",
+    )?;
+
+    Ok(IpRule { allow, deny })
+}
@@ -0,0 +1,217 @@
+use crate::{
+    s3::{get_bytes_if_changed, get_bytes_or_default, site_location, LastFetched},
+    store::ObjectStore,
+    Realm,
+};
+use color_eyre::eyre::bail;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, sync::Arc};
+use tokio::sync::{Mutex, RwLock};
+
+pub const IP_FILTER_LOCATION: &str = "ip_filter.json";
+
+///which networks can get at a realm. anything in `deny` is turned away, and if `allow` has anything in
+///it then only those networks get in
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpRule {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl IpRule {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|x| x.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|x| x.contains(&ip))
+    }
+
+    ///merges overlapping & adjacent networks so there's less to check per request
+    fn aggregated(&self) -> Self {
+        Self {
+            allow: IpNet::aggregate(&self.allow),
+            deny: IpNet::aggregate(&self.deny),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IpFilterManager {
+    site: Option<String>,
+    last_fetched: Arc<Mutex<LastFetched>>,
+    current: Arc<RwLock<IpFilter>>,
+}
+
+impl IpFilterManager {
+    pub async fn new(bucket: &dyn ObjectStore, site: Option<&str>) -> color_eyre::Result<Self> {
+        let (ip_filter, raw_bytes) = IpFilter::new(bucket, site).await?;
+        Ok(Self {
+            site: site.map(|x| x.to_string()),
+            last_fetched: Arc::new(Mutex::new(LastFetched::new(&raw_bytes))),
+            current: Arc::new(RwLock::new(ip_filter.aggregated())),
+        })
+    }
+
+    pub async fn check_and_reload(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<()> {
+        let Ok(mut last_fetched) = self.last_fetched.try_lock() else {
+            bail!("already reloading ip filter")
+        };
+
+        let location = site_location(self.site.as_deref(), IP_FILTER_LOCATION);
+        let Some(raw_bytes) = get_bytes_if_changed(bucket, location, &mut last_fetched).await?
+        else {
+            return Ok(());
+        };
+        if raw_bytes.is_empty() {
+            return Ok(());
+        }
+
+        let new_version = IpFilter::construct_from_bytes(&raw_bytes)?.aggregated();
+        *self.current.write().await = new_version;
+
+        Ok(())
+    }
+
+    pub async fn allows(&self, path: &str, ip: IpAddr) -> bool {
+        self.current.read().await.allows(path, ip)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IpFilter {
+    ///used for any path that doesn't match one of the `rules`
+    pub default: IpRule,
+    ///the first matching realm wins
+    rules: Vec<(Realm, IpRule)>,
+}
+
+impl IpFilter {
+    pub async fn new(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<(Self, Vec<u8>)> {
+        let bytes = Self::get_raw_bytes(bucket, site).await?;
+        let s = Self::construct_from_bytes(&bytes)?;
+        Ok((s, bytes))
+    }
+
+    pub async fn save(
+        &self,
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        let bytes = serde_json::to_vec(&self)?;
+
+        bucket
+            .put(
+                &site_location(site, IP_FILTER_LOCATION),
+                &bytes,
+                "application/json",
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_raw_bytes(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<Vec<u8>> {
+        Ok(get_bytes_or_default(bucket, site_location(site, IP_FILTER_LOCATION)).await?)
+    }
+
+    fn construct_from_bytes(bytes: &[u8]) -> color_eyre::Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    fn aggregated(&self) -> Self {
+        Self {
+            default: self.default.aggregated(),
+            rules: self
+                .rules
+                .iter()
+                .map(|(realm, rule)| (realm.clone(), rule.aggregated()))
+                .collect(),
+        }
+    }
+
+    pub fn get_rule(&self, path: &str) -> &IpRule {
+        self.rules
+            .iter()
+            .find(|(realm, _)| realm.matches(path))
+            .map_or(&self.default, |(_, rule)| rule)
+    }
+
+    pub fn allows(&self, path: &str, ip: IpAddr) -> bool {
+        self.get_rule(path).allows(ip)
+    }
+
+    pub fn rules(&self) -> &[(Realm, IpRule)] {
+        &self.rules
+    }
+
+    ///replaces the rule for `realm` if there is one, otherwise adds it last
+    pub fn set_rule(&mut self, realm: Realm, rule: IpRule) {
+        match self.rules.iter_mut().find(|(r, _)| *r == realm) {
+            Some((_, existing)) => *existing = rule,
+            None => self.rules.push((realm, rule)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(list: &[&str]) -> Vec<IpNet> {
+        list.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_realm_rule_beats_default() {
+        let mut filter = IpFilter {
+            default: IpRule {
+                allow: vec![],
+                deny: nets(&["203.0.113.0/24"]),
+            },
+            rules: vec![],
+        };
+        filter.set_rule(
+            Realm::StartsWith("/admin".to_string()),
+            IpRule {
+                allow: nets(&["198.51.100.7/32"]),
+                deny: vec![],
+            },
+        );
+
+        let office = "198.51.100.7".parse().unwrap();
+        let abusive = "203.0.113.40".parse().unwrap();
+        let anyone = "192.0.2.1".parse().unwrap();
+
+        assert!(filter.allows("/index.html", anyone));
+        assert!(filter.allows("/index.html", office));
+        assert!(!filter.allows("/index.html", abusive));
+
+        assert!(filter.allows("/admin/index.html", office));
+        assert!(!filter.allows("/admin/index.html", anyone));
+    }
+
+    #[test]
+    fn test_deny_beats_allow_after_aggregating() {
+        let rule = IpRule {
+            allow: nets(&["10.0.0.0/25", "10.0.0.128/25", "2001:db8::/32"]),
+            deny: nets(&["10.0.0.5/32"]),
+        }
+        .aggregated();
+
+        assert_eq!(rule.allow, nets(&["10.0.0.0/24", "2001:db8::/32"]));
+        assert!(rule.allows("10.0.0.200".parse().unwrap()));
+        assert!(rule.allows("2001:db8::1".parse().unwrap()));
+        assert!(!rule.allows("10.0.0.5".parse().unwrap()));
+        assert!(!rule.allows("10.0.1.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,463 @@
+use dialoguer::{theme::Theme, FuzzySelect, Input};
+use dotenvy::var;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::{Display, Formatter, Write},
+    hash::{Hash, Hasher},
+    sync::OnceLock,
+};
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+///set by [`setup`], so `RUST_LOG` can be changed while serving
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+pub fn hash_raw_bytes(bytes: impl AsRef<[u8]>) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(&bytes);
+    hasher.finalize().to_vec()
+}
+
+///lowercase, two characters a byte
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut acc, x| {
+        let _ = write!(acc, "{x:02x}");
+        acc
+    })
+}
+
+pub mod audit;
+pub mod cache_control;
+pub mod config;
+pub mod cors;
+pub mod crawlers;
+pub mod doctor;
+pub mod download;
+pub mod envelope;
+pub mod error;
+pub mod headers;
+pub mod ip_filter;
+pub mod merge;
+pub mod migrate;
+pub mod non_empty_list;
+pub mod protect;
+pub mod rate_limit;
+pub mod releases;
+pub mod s3;
+pub mod serve;
+pub mod store;
+pub mod upload;
+pub mod verify;
+pub mod versioned;
+
+#[macro_use]
+extern crate tracing;
+
+extern crate serde_regex;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Realm {
+    StartsWith(String),
+    #[serde(with = "serde_regex")]
+    Regex(Regex),
+    EndsWith(String),
+    Contains(String),
+    ///the whole path, where `*` is any run of characters and `?` is any one, neither crossing a `/`
+    Glob(String),
+}
+
+impl Display for Realm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Realm::StartsWith(sw) => write!(f, "Starts with: {sw:?}"),
+            Realm::EndsWith(ew) => write!(f, "Ends with: {ew:?}"),
+            Realm::Regex(regex) => write!(f, "Matches Regex: {regex}"),
+            Realm::Contains(cont) => write!(f, "Contains: {cont:?}"),
+            Realm::Glob(glob) => write!(f, "Matches Glob: {glob:?}"),
+        }
+    }
+}
+
+///whether `glob` matches all of `path`, see [`Realm::Glob`]
+fn glob_matches(glob: &str, path: &str) -> bool {
+    let path: Vec<char> = path.chars().collect();
+    //which lengths of `path` the glob so far can match
+    let mut matched = vec![false; path.len() + 1];
+    matched[0] = true;
+
+    for g in glob.chars() {
+        let mut next = vec![false; path.len() + 1];
+        for i in 0..=path.len() {
+            next[i] = match g {
+                '*' => matched[i] || (i > 0 && next[i - 1] && path[i - 1] != '/'),
+                '?' => i > 0 && matched[i - 1] && path[i - 1] != '/',
+                c => i > 0 && matched[i - 1] && path[i - 1] == c,
+            };
+        }
+        matched = next;
+    }
+
+    matched[path.len()]
+}
+
+impl Realm {
+    ///what the login prompt calls the realm when it hasn't been given a name - close to the pattern,
+    ///without the `Starts with:` & quoting of [`Display`]
+    pub fn default_display_name(&self) -> String {
+        match self {
+            Realm::StartsWith(sw) => sw.clone(),
+            Realm::EndsWith(ew) => format!("*{ew}"),
+            Realm::Regex(regex) => regex.as_str().to_string(),
+            Realm::Contains(cont) => format!("*{cont}*"),
+            Realm::Glob(glob) => glob.clone(),
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            Self::StartsWith(pattern) => path.starts_with(pattern),
+            Self::EndsWith(ew) => path.ends_with(ew),
+            Self::Regex(regex) => regex.is_match(path),
+            Self::Contains(cont) => path.contains(cont),
+            Self::Glob(glob) => glob_matches(glob, path),
+        }
+    }
+
+    pub fn get_from_stdin(theme: &dyn Theme) -> color_eyre::Result<Self> {
+        let ty = FuzzySelect::with_theme(theme)
+            .items(&["Starts With", "Ends With", "Regex", "Contains", "Glob"])
+            .with_prompt("What kind of realm matcher?")
+            .interact()?;
+
+        match ty {
+            0 => {
+                let sw = Input::with_theme(theme)
+                    .with_prompt("What should the path start with?")
+                    .interact()?;
+                Ok(Self::StartsWith(sw))
+            }
+            1 => {
+                let ew = Input::with_theme(theme)
+                    .with_prompt("What should the path end with?")
+                    .interact()?;
+                Ok(Self::EndsWith(ew))
+            }
+            2 => {
+                let regex = Input::with_theme(theme)
+                    .with_prompt("What should the regular expression match on?")
+                    .interact()?;
+                Ok(Self::Regex(regex))
+            }
+            3 => {
+                let ew = Input::with_theme(theme)
+                    .with_prompt("What should the path contain?")
+                    .interact()?;
+                Ok(Self::Contains(ew))
+            }
+            4 => {
+                let glob = Input::with_theme(theme)
+                    .with_prompt(
+                        "What should the whole path look like (`*` for any run of characters, `?` for any one, neither crossing a `/`)?",
+                    )
+                    .interact()?;
+                Ok(Self::Glob(glob))
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Hash for Realm {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Realm::StartsWith(sw) => sw.hash(state),
+            Realm::EndsWith(ew) => ew.hash(state),
+            Realm::Regex(reg) => reg.as_str().hash(state),
+            Realm::Contains(cont) => cont.hash(state),
+            Realm::Glob(glob) => glob.hash(state),
+        }
+    }
+}
+
+impl PartialEq for Realm {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Realm::StartsWith(s), Realm::StartsWith(o)) => s.eq(o),
+            (Realm::EndsWith(s), Realm::EndsWith(o)) => s.eq(o),
+            //technically not comprehensive but i'm not dealing with that mess lolll
+            //also that would break the hash/partialeq invariant if we dealt with output-identical regexes
+            (Realm::Regex(s), Realm::Regex(o)) => s.as_str().eq(o.as_str()),
+            (Realm::Contains(s), Realm::Contains(o)) => s.eq(o),
+            (Realm::Glob(s), Realm::Glob(o)) => s.eq(o),
+            //could technically turn the sw/ew into a regex, but no :)
+            (_, _) => false,
+        }
+    }
+}
+
+impl Eq for Realm {}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Eq, PartialEq)]
+pub struct UploadData {
+    ///key to hash, where each key is already [`entry_key`]'d with the `root`
+    pub entries: HashMap<String, String>,
+    ///the prefix every key is stored under. Empty when the site was uploaded from several
+    ///directories, in which case the keys are rooted at the site root
+    pub root: String,
+    ///key to the `Cache-Control` set on the object itself, for CDNs that sit right in front of the bucket
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub cache_control: HashMap<String, String>,
+    ///key to how many bytes were uploaded, for `shove verify`. Missing for anything uploaded before
+    ///sizes were recorded
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sizes: HashMap<String, u64>,
+}
+
+///every site uploaded with `--site`, stored at [`s3::SITES_LOCATION`]
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Eq, PartialEq)]
+pub struct SitesManifest {
+    pub hosts: BTreeSet<String>,
+    ///which host to serve when the `Host` header doesn't match any. If unset, the site at the top of
+    ///the bucket is used
+    pub default: Option<String>,
+}
+
+///lowercases, and strips any port or trailing dot so `Example.com.:8080` is just `example.com`
+pub fn normalise_host(host: &str) -> String {
+    let host = if let Some(rest) = host.strip_prefix('[') {
+        //ipv6 literal, which has colons of its own
+        rest.split_once(']').map_or(rest, |(addr, _)| addr)
+    } else {
+        host.split_once(':').map_or(host, |(host, _)| host)
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+///NFC, so a name typed on one OS matches a file named on another
+pub fn normalise_unicode(s: &str) -> String {
+    icu_normalizer::ComposingNormalizerBorrowed::new_nfc()
+        .normalize(s)
+        .into_owned()
+}
+
+///the key used both in the manifest and in the cache for a resolved request path
+pub fn entry_key(root: &str, resolved_path: &str) -> String {
+    format!(
+        "{}/{}",
+        root.trim_end_matches('/'),
+        resolved_path.trim_start_matches('/')
+    )
+}
+
+///the inverse of [`entry_key`], `None` if `key` isn't under `root`
+pub fn entry_path(root: &str, key: &str) -> Option<String> {
+    let path = key.strip_prefix(root.trim_end_matches('/'))?;
+    if path.starts_with('/') {
+        Some(path.to_string())
+    } else {
+        None
+    }
+}
+
+///swaps what gets logged for `directives`, written like `RUST_LOG`. Returns the old filter if it
+///changed, and `None` if it didn't or [`setup`] was never called
+pub fn set_log_filter(directives: &str) -> color_eyre::Result<Option<String>> {
+    let Some(handle) = LOG_FILTER.get() else {
+        return Ok(None);
+    };
+    let new = EnvFilter::try_new(directives)?;
+    let old = handle.with_current(ToString::to_string)?;
+    if old == new.to_string() {
+        return Ok(None);
+    }
+    handle.reload(new)?;
+    Ok(Some(old))
+}
+
+/// # Safety
+/// Must only be called in a single-threaded environment
+pub unsafe fn setup() {
+    if cfg!(debug_assertions) {
+        for (key, value) in &[
+            ("RUST_SPANTRACE", "full"),
+            ("RUST_LIB_BACKTRACE", "full"),
+            ("RUST_BACKTRACE", "full"),
+            ("RUST_LOG", "info"),
+        ] {
+            match std::env::var(key) {
+                Err(_) => {
+                    trace!(%key, %value, "Setting env var");
+                    unsafe {
+                        //safety: method safety notes ensure only single threaded
+                        std::env::set_var(key, value);
+                    }
+                }
+                Ok(found) => {
+                    trace!(%key, %found, "Found existing env var");
+                }
+            }
+        }
+    }
+
+    if let Err(e) = dotenvy::dotenv() {
+        eprintln!("Error finding env vars: {e:?}")
+    }
+
+    let (filter, handle) = reload::Layer::new(EnvFilter::from_default_env());
+    let _ = LOG_FILTER.set(handle);
+    let sub = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+
+    if var("SENTRY_DSN").is_ok() {
+        sub.with(sentry::integrations::tracing::layer()).init();
+    } else {
+        sub.init();
+    }
+
+    color_eyre::install().expect("unable to install color-eyre");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_old_upload_data_still_parses() {
+        //from when `root` was a `PathBuf`, which serde wrote out as a plain string, and before
+        //cache control or sizes were recorded
+        let old = br#"{"entries":{"/home/me/blog/public/index.html":"abc"},"root":"/home/me/blog/public"}"#;
+        let upload_data: UploadData = serde_json::from_slice(old).unwrap();
+        assert_eq!(upload_data.root, "/home/me/blog/public");
+        assert_eq!(
+            entry_path(&upload_data.root, "/home/me/blog/public/index.html").as_deref(),
+            Some("/index.html")
+        );
+        assert!(upload_data.cache_control.is_empty());
+        assert!(upload_data.sizes.is_empty());
+
+        //and what's written now reads back the same
+        let written = serde_json::to_vec(&upload_data).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<UploadData>(&written).unwrap(),
+            upload_data
+        );
+    }
+
+    fn realms() -> [Realm; 5] {
+        [
+            Realm::StartsWith("/blog/".to_string()),
+            Realm::EndsWith(".pdf".to_string()),
+            Realm::Regex(Regex::new("^/[0-9]+$").unwrap()),
+            Realm::Contains("draft".to_string()),
+            Realm::Glob("/blog/*/draft-*".to_string()),
+        ]
+    }
+
+    #[test]
+    fn test_realms_against_paths() {
+        //whether each of `realms()` matches, in order
+        let table = [
+            ("/", [false, false, false, false, false]),
+            ("/blog", [false, false, false, false, false]),
+            ("/blog/", [true, false, false, false, false]),
+            ("/blog/2024/post.html", [true, false, false, false, false]),
+            ("/blog/24/draft-1.html", [true, false, false, true, true]),
+            ("/blog/2024/draft-", [true, false, false, true, true]),
+            ("/blog/2/5/draft-1.htm", [true, false, false, true, false]),
+            ("/blog//draft-one", [true, false, false, true, true]),
+            ("/blog/24/draft-1/extra", [true, false, false, true, false]),
+            ("/blog/report.pdf", [true, true, false, false, false]),
+            ("/drafts/report.pdf", [false, true, false, true, false]),
+            ("/report.PDF", [false, false, false, false, false]),
+            ("/123", [false, false, true, false, false]),
+            ("/123/", [false, false, false, false, false]),
+            ("/a/blog/2024/draft-one", [false, false, false, true, false]),
+        ];
+
+        for (path, expected) in table {
+            let matched = realms().map(|realm| realm.matches(path));
+            assert_eq!(matched, expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_globs() {
+        for (glob, path, expected) in [
+            ("/*.html", "/index.html", true),
+            ("/*.html", "/a/index.html", false),
+            ("/*/*.html", "/a/index.html", true),
+            ("/page-?.html", "/page-1.html", true),
+            ("/page-?.html", "/page-10.html", false),
+            ("/page-?.html", "/page-/.html", false),
+            ("/*", "/", true),
+            ("*", "", true),
+            ("/*a*b*", "/xaybz", true),
+            ("/*a*b*", "/xbya", false),
+            ("/exact", "/exact", true),
+            ("/exact", "/exactly", false),
+            ("", "", true),
+            ("", "/", false),
+            ("/caf\u{e9}/*", "/caf\u{e9}/menu", true),
+        ] {
+            assert_eq!(
+                Realm::Glob(glob.to_string()).matches(path),
+                expected,
+                "{glob} {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_earlier_realms_take_precedence() {
+        //rate limits, IP filters & CORS all go with the first rule that matches
+        let first = |path: &str| realms().iter().position(|realm| realm.matches(path));
+        assert_eq!(first("/blog/2024/draft-one.html"), Some(0));
+        assert_eq!(first("/drafts/report.pdf"), Some(1));
+        assert_eq!(first("/a/draft"), Some(3));
+        assert_eq!(first("/index.html"), None);
+
+        let reversed = |path: &str| realms().iter().rev().position(|realm| realm.matches(path));
+        assert_eq!(reversed("/blog/2024/draft-one.html"), Some(0));
+        assert_eq!(reversed("/blog/2024/post.html"), Some(4));
+    }
+
+    #[test]
+    fn test_realms_round_trip_and_compare_by_variant() {
+        let mut all = realms().to_vec();
+        all.push(Realm::StartsWith("/blog/*/draft-*".to_string()));
+        all.push(Realm::Contains("/blog/*/draft-*".to_string()));
+
+        for (i, realm) in all.iter().enumerate() {
+            let written = serde_json::to_string(realm).unwrap();
+            let read: Realm = serde_json::from_str(&written).unwrap();
+            assert_eq!(&read, realm, "{written}");
+            assert_eq!(read.to_string(), realm.to_string());
+
+            let hash = |realm: &Realm| {
+                let mut hasher = std::hash::DefaultHasher::new();
+                realm.hash(&mut hasher);
+                hasher.finish()
+            };
+            assert_eq!(hash(&read), hash(realm), "{written}");
+
+            //the same pattern in another variant is a different realm
+            for other in &all[i + 1..] {
+                assert_ne!(realm, other);
+            }
+        }
+
+        //stored before globs existed
+        let old: Realm = serde_json::from_str(r#"{"EndsWith":".pdf"}"#).unwrap();
+        assert_eq!(old, Realm::EndsWith(".pdf".to_string()));
+        assert_eq!(
+            serde_json::to_string(&Realm::Glob("/*".to_string())).unwrap(),
+            r#"{"Glob":"/*"}"#
+        );
+    }
+}
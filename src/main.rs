@@ -1,209 +1,450 @@
-use crate::{cache_control::cache, protect::protect, serve::serve, upload::upload};
 use color_eyre::owo_colors::OwoColorize;
-use dialoguer::{theme::Theme, FuzzySelect, Input};
-use dotenvy::var;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::{
-    collections::HashMap,
-    env::args,
-    fmt::{Display, Formatter},
-    hash::{Hash, Hasher},
+use shove::{
+    audit::{audit_tail, DEFAULT_TAIL_EVENTS},
+    cache_control::cache,
+    config::{AuthConfig, BucketConfig, ConfigError, RekeyConfig},
+    cors::cors,
+    crawlers::crawlers,
+    doctor::doctor,
+    download::{download, DownloadFilter},
+    headers::headers,
+    ip_filter::ip_filter,
+    migrate::migrate,
+    normalise_host,
+    protect::{protect, rekey::rekey},
+    rate_limit::rate_limit,
+    releases::{releases, rollback},
+    serve::{
+        bandwidth::{report_bandwidth, DEFAULT_REPORT_DAYS},
+        config::Config,
+        journal::journal,
+        serve,
+        stats::stats,
+    },
+    setup,
+    upload::{
+        diff::diff,
+        pending::{gc, parse_delete_after, DEFAULT_DELETE_AFTER},
+        upload,
+        watch::Notify,
+        UploadOptions,
+    },
+    verify::verify,
 };
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
-
-pub fn hash_raw_bytes(bytes: impl AsRef<[u8]>) -> Vec<u8> {
-    let mut hasher = Sha256::new();
-    hasher.update(&bytes);
-    hasher.finalize().to_vec()
-}
-
-pub mod cache_control;
-mod non_empty_list;
-pub mod protect;
-pub mod s3;
-pub mod serve;
-mod upload;
+use std::{env::args, path::PathBuf};
 
 #[macro_use]
 extern crate tracing;
 
-extern crate serde_regex;
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub enum Realm {
-    StartsWith(String),
-    #[serde(with = "serde_regex")]
-    Regex(Regex),
-    EndsWith(String),
-    Contains(String),
-}
-
-impl Display for Realm {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Realm::StartsWith(sw) => write!(f, "Starts with: {sw:?}"),
-            Realm::EndsWith(ew) => write!(f, "Ends with: {ew:?}"),
-            Realm::Regex(regex) => write!(f, "Matches Regex: {regex}"),
-            Realm::Contains(cont) => write!(f, "Contains: {cont:?}"),
-        }
-    }
-}
-
-impl Realm {
-    pub fn matches(&self, path: &str) -> bool {
-        match self {
-            Self::StartsWith(pattern) => path.starts_with(pattern),
-            Self::EndsWith(ew) => path.ends_with(ew),
-            Self::Regex(regex) => regex.is_match(path),
-            Self::Contains(cont) => path.contains(cont),
-        }
-    }
-
-    pub fn get_from_stdin(theme: &dyn Theme) -> color_eyre::Result<Self> {
-        let ty = FuzzySelect::with_theme(theme)
-            .items(&["Starts With", "Ends With", "Regex", "Contains"])
-            .with_prompt("What kind of realm matcher?")
-            .interact()?;
-
-        match ty {
-            0 => {
-                let sw = Input::with_theme(theme)
-                    .with_prompt("What should the path start with?")
-                    .interact()?;
-                Ok(Self::StartsWith(sw))
-            }
-            1 => {
-                let ew = Input::with_theme(theme)
-                    .with_prompt("What should the path end with?")
-                    .interact()?;
-                Ok(Self::StartsWith(ew))
-            }
-            2 => {
-                let regex = Input::with_theme(theme)
-                    .with_prompt("What should the regular expression match on?")
-                    .interact()?;
-                Ok(Self::Regex(regex))
-            }
-            3 => {
-                let ew = Input::with_theme(theme)
-                    .with_prompt("What should the path contain?")
-                    .interact()?;
-                Ok(Self::Contains(ew))
-            }
-            _ => unreachable!(),
-        }
-    }
-}
-
-impl Hash for Realm {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        std::mem::discriminant(self).hash(state);
-        match self {
-            Realm::StartsWith(sw) => sw.hash(state),
-            Realm::EndsWith(ew) => ew.hash(state),
-            Realm::Regex(reg) => reg.as_str().hash(state),
-            Realm::Contains(cont) => cont.hash(state),
-        }
-    }
-}
-
-impl PartialEq for Realm {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Realm::StartsWith(s), Realm::StartsWith(o)) => s.eq(o),
-            (Realm::EndsWith(s), Realm::EndsWith(o)) => s.eq(o),
-            //technically not comprehensive but i'm not dealing with that mess lolll
-            //also that would break the hash/partialeq invariant if we dealt with output-identical regexes
-            (Realm::Regex(s), Realm::Regex(o)) => s.as_str().eq(o.as_str()),
-            (Realm::Contains(s), Realm::Contains(o)) => s.eq(o),
-            //could technically turn the sw/ew into a regex, but no :)
-            (_, _) => false,
-        }
-    }
-}
-
-impl Eq for Realm {}
-
-#[derive(Serialize, Deserialize, Default, Debug, Clone, Eq, PartialEq)]
-pub struct UploadData {
-    ///path to hash
-    pub entries: HashMap<String, String>,
-    pub root: String,
-}
-
-/// # Safety
-/// Must only be called in a single-threaded environment
-pub unsafe fn setup() {
-    if cfg!(debug_assertions) {
-        for (key, value) in &[
-            ("RUST_SPANTRACE", "full"),
-            ("RUST_LIB_BACKTRACE", "full"),
-            ("RUST_BACKTRACE", "full"),
-            ("RUST_LOG", "info"),
-        ] {
-            match std::env::var(key) {
-                Err(_) => {
-                    trace!(%key, %value, "Setting env var");
-                    unsafe {
-                        //safety: method safety notes ensure only single threaded
-                        std::env::set_var(key, value);
-                    }
-                }
-                Ok(found) => {
-                    trace!(%key, %found, "Found existing env var");
-                }
-            }
-        }
-    }
-
-    if let Err(e) = dotenvy::dotenv() {
-        eprintln!("Error finding env vars: {e:?}")
-    }
-
-    let sub = tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(EnvFilter::from_default_env());
-
-    if var("SENTRY_DSN").is_ok() {
-        sub.with(sentry::integrations::tracing::layer()).init();
-    } else {
-        sub.init();
-    }
-
-    color_eyre::install().expect("unable to install color-eyre");
-}
-
 pub enum Args {
-    Serve,
-    Upload(String),
+    Serve {
+        local: Option<PathBuf>,
+    },
+    Upload {
+        mappings: Vec<String>,
+        site: Option<String>,
+        options: UploadOptions,
+        watch: bool,
+        notify: Option<Notify>,
+    },
+    Diff {
+        mappings: Vec<String>,
+        site: Option<String>,
+        options: UploadOptions,
+        json: bool,
+    },
     Protect,
-    Cache,
+    Rekey,
+    Migrate,
+    Cache {
+        site: Option<String>,
+    },
+    Headers {
+        site: Option<String>,
+    },
+    Cors {
+        site: Option<String>,
+    },
+    IpFilter {
+        site: Option<String>,
+    },
+    RateLimit {
+        site: Option<String>,
+    },
+    Crawlers {
+        site: Option<String>,
+    },
+    Journal,
+    Stats,
+    ReportBandwidth {
+        days: u64,
+    },
+    AuditTail {
+        count: usize,
+    },
+    Releases {
+        site: Option<String>,
+    },
+    Doctor,
+    Rollback {
+        site: Option<String>,
+        release: String,
+    },
+    Verify {
+        site: Option<String>,
+        deep: bool,
+    },
+    Download {
+        dir: PathBuf,
+        site: Option<String>,
+        includes: Vec<String>,
+        excludes: Vec<String>,
+    },
+    Gc {
+        site: Option<String>,
+        now: bool,
+    },
 }
 
 impl Args {
     pub fn parse() -> Self {
         let mut args = args().skip(1);
 
+        fn site_arg(args: &mut impl Iterator<Item = String>) -> String {
+            match args.next() {
+                Some(site) if !site.is_empty() && !site.contains('/') => normalise_host(&site),
+                _ => {
+                    eprintln!("{} needs a host, eg. `example.com`", "--site".blue());
+                    std::process::exit(1);
+                }
+            }
+        }
+
         if let Some(command) = args.next() {
             match command.as_str() {
                 "serve" => {
-                    return Self::Serve;
+                    let local = match args.next().as_deref() {
+                        Some("--local") => match args.next() {
+                            Some(dir) => Some(PathBuf::from(dir)),
+                            None => {
+                                eprintln!("{} needs a directory", "--local".blue());
+                                std::process::exit(1);
+                            }
+                        },
+                        _ => None,
+                    };
+                    return Self::Serve { local };
                 }
                 "upload" => {
-                    if let Some(dir) = args.next() {
-                        return Self::Upload(dir);
+                    let mut mappings = vec![];
+                    let mut site = None;
+                    let mut options = UploadOptions::default();
+                    options.delete_after = Some(DEFAULT_DELETE_AFTER);
+                    let mut watch = false;
+                    let mut notify_url = None;
+                    let mut notify_token = None;
+                    while let Some(arg) = args.next() {
+                        if arg == "--dry-run" {
+                            options.dry_run = true;
+                        } else if arg == "--watch" {
+                            watch = true;
+                        } else if arg == "--notify-url" {
+                            match args.next() {
+                                Some(url) => notify_url = Some(url),
+                                None => {
+                                    eprintln!(
+                                        "{} needs the server's URL, eg. `http://localhost:8080`",
+                                        "--notify-url".blue()
+                                    );
+                                    std::process::exit(1);
+                                }
+                            }
+                        } else if arg == "--notify-token" {
+                            match args.next() {
+                                Some(token) => notify_token = Some(token),
+                                None => {
+                                    eprintln!(
+                                        "{} needs one of the server's {}s",
+                                        "--notify-token".blue(),
+                                        "TIGRIS_TOKEN".green()
+                                    );
+                                    std::process::exit(1);
+                                }
+                            }
+                        } else if arg == "--skip-metadata" {
+                            options.skip_metadata = true;
+                        } else if arg == "--keep-ignored" {
+                            options.keep_ignored = true;
+                        } else if arg == "--no-manifest" {
+                            options.no_manifest = true;
+                        } else if arg == "--atomic" {
+                            options.atomic = true;
+                        } else if arg == "--quiet" {
+                            options.quiet = true;
+                        } else if arg == "--delete-now" {
+                            options.delete_after = None;
+                        } else if arg == "--delete-after" {
+                            match args.next().as_deref().and_then(parse_delete_after) {
+                                Some(after) => options.delete_after = Some(after),
+                                None => {
+                                    eprintln!(
+                                        "{} needs a duration, eg. `10m`",
+                                        "--delete-after".blue()
+                                    );
+                                    std::process::exit(1);
+                                }
+                            }
+                        } else if arg == "--exclude" {
+                            match args.next() {
+                                Some(glob) => options.excludes.push(glob),
+                                None => {
+                                    eprintln!("{} needs a glob, eg. `*.map`", "--exclude".blue());
+                                    std::process::exit(1);
+                                }
+                            }
+                        } else if arg == "--site" {
+                            site = Some(site_arg(&mut args));
+                        } else {
+                            mappings.push(arg);
+                        }
+                    }
+
+                    let notify = match (notify_url, notify_token) {
+                        (Some(url), Some(token)) => Some(Notify { url, token }),
+                        (None, None) => None,
+                        _ => {
+                            eprintln!(
+                                "{} and {} need to be given together",
+                                "--notify-url".blue(),
+                                "--notify-token".blue()
+                            );
+                            std::process::exit(1);
+                        }
+                    };
+
+                    if !mappings.is_empty() {
+                        return Self::Upload {
+                            mappings,
+                            site,
+                            options,
+                            watch,
+                            notify,
+                        };
                     } else {
-                        eprintln!("missing argument {}", "[DIR]".blue());
+                        eprintln!("missing argument {}", "[DIR[:/PREFIX]]".blue());
+                        std::process::exit(1);
+                    }
+                }
+                "diff" => {
+                    let mut mappings = vec![];
+                    let mut site = None;
+                    let mut options = UploadOptions::default();
+                    let mut json = false;
+                    while let Some(arg) = args.next() {
+                        if arg == "--json" {
+                            json = true;
+                        } else if arg == "--no-manifest" {
+                            options.no_manifest = true;
+                        } else if arg == "--exclude" {
+                            match args.next() {
+                                Some(glob) => options.excludes.push(glob),
+                                None => {
+                                    eprintln!("{} needs a glob, eg. `*.map`", "--exclude".blue());
+                                    std::process::exit(1);
+                                }
+                            }
+                        } else if arg == "--site" {
+                            site = Some(site_arg(&mut args));
+                        } else {
+                            mappings.push(arg);
+                        }
+                    }
+
+                    if mappings.is_empty() {
+                        eprintln!("missing argument {}", "[DIR[:/PREFIX]]".blue());
                         std::process::exit(1);
                     }
+                    //the bars would only get in the way of the output
+                    options.quiet = json;
+                    return Self::Diff {
+                        mappings,
+                        site,
+                        options,
+                        json,
+                    };
                 }
                 "protect" => {
                     return Self::Protect;
                 }
+                "rekey" => {
+                    return Self::Rekey;
+                }
+                "migrate" => {
+                    return Self::Migrate;
+                }
                 "cache" => {
-                    return Self::Cache;
+                    let site = match args.next().as_deref() {
+                        Some("--site") => Some(site_arg(&mut args)),
+                        _ => None,
+                    };
+                    return Self::Cache { site };
+                }
+                "headers" => {
+                    let site = match args.next().as_deref() {
+                        Some("--site") => Some(site_arg(&mut args)),
+                        _ => None,
+                    };
+                    return Self::Headers { site };
+                }
+                "cors" => {
+                    let site = match args.next().as_deref() {
+                        Some("--site") => Some(site_arg(&mut args)),
+                        _ => None,
+                    };
+                    return Self::Cors { site };
+                }
+                "ip-filter" => {
+                    let site = match args.next().as_deref() {
+                        Some("--site") => Some(site_arg(&mut args)),
+                        _ => None,
+                    };
+                    return Self::IpFilter { site };
+                }
+                "rate-limit" => {
+                    let site = match args.next().as_deref() {
+                        Some("--site") => Some(site_arg(&mut args)),
+                        _ => None,
+                    };
+                    return Self::RateLimit { site };
+                }
+                "crawlers" => {
+                    let site = match args.next().as_deref() {
+                        Some("--site") => Some(site_arg(&mut args)),
+                        _ => None,
+                    };
+                    return Self::Crawlers { site };
+                }
+                "journal" => {
+                    return Self::Journal;
+                }
+                "stats" => {
+                    return Self::Stats;
+                }
+                "report" if args.next().as_deref() == Some("bandwidth") => {
+                    let days = match args.next().as_deref() {
+                        Some("--days") => match args.next().map(|x| x.parse()) {
+                            Some(Ok(days)) => days,
+                            _ => {
+                                eprintln!("{} needs a number of days", "--days".blue());
+                                std::process::exit(1);
+                            }
+                        },
+                        _ => DEFAULT_REPORT_DAYS,
+                    };
+                    return Self::ReportBandwidth { days };
+                }
+                "audit" if args.next().as_deref() == Some("tail") => {
+                    let count = match args.next() {
+                        Some(count) => match count.parse() {
+                            Ok(count) => count,
+                            Err(_) => {
+                                eprintln!("{} needs to be a number", "COUNT".blue());
+                                std::process::exit(1);
+                            }
+                        },
+                        None => DEFAULT_TAIL_EVENTS,
+                    };
+                    return Self::AuditTail { count };
+                }
+                "doctor" => {
+                    return Self::Doctor;
+                }
+                "releases" => {
+                    let site = match args.next().as_deref() {
+                        Some("--site") => Some(site_arg(&mut args)),
+                        _ => None,
+                    };
+                    return Self::Releases { site };
+                }
+                "rollback" => {
+                    let mut site = None;
+                    let mut release = None;
+                    while let Some(arg) = args.next() {
+                        if arg == "--site" {
+                            site = Some(site_arg(&mut args));
+                        } else {
+                            release = Some(arg);
+                        }
+                    }
+
+                    match release {
+                        Some(release) => return Self::Rollback { site, release },
+                        None => {
+                            eprintln!("missing argument {}", "[TIMESTAMP|INDEX]".blue());
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                "verify" => {
+                    let mut site = None;
+                    let mut deep = false;
+                    while let Some(arg) = args.next() {
+                        if arg == "--site" {
+                            site = Some(site_arg(&mut args));
+                        } else if arg == "--deep" {
+                            deep = true;
+                        }
+                    }
+                    return Self::Verify { site, deep };
+                }
+                "download" => {
+                    let mut dir = None;
+                    let mut site = None;
+                    let mut includes = vec![];
+                    let mut excludes = vec![];
+                    while let Some(arg) = args.next() {
+                        if arg == "--include" || arg == "--exclude" {
+                            match args.next() {
+                                Some(glob) if arg == "--include" => includes.push(glob),
+                                Some(glob) => excludes.push(glob),
+                                None => {
+                                    eprintln!("{} needs a glob, eg. `*.map`", arg.blue());
+                                    std::process::exit(1);
+                                }
+                            }
+                        } else if arg == "--site" {
+                            site = Some(site_arg(&mut args));
+                        } else {
+                            dir = Some(PathBuf::from(arg));
+                        }
+                    }
+
+                    match dir {
+                        Some(dir) => {
+                            return Self::Download {
+                                dir,
+                                site,
+                                includes,
+                                excludes,
+                            }
+                        }
+                        None => {
+                            eprintln!("missing argument {}", "[DIR]".blue());
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                "gc" => {
+                    let mut site = None;
+                    let mut now = false;
+                    while let Some(arg) = args.next() {
+                        if arg == "--site" {
+                            site = Some(site_arg(&mut args));
+                        } else if arg == "--now" {
+                            now = true;
+                        }
+                    }
+                    return Self::Gc { site, now };
                 }
                 _ => {}
             }
@@ -222,10 +463,58 @@ impl Args {
         eprintln!("Usage: {} [command]", "shove".bold());
         eprintln!();
         eprintln!("{}", "Available Commands:".underline());
-        eprintln!("- {}", "serve".italic());
-        eprintln!("- {} {}", "upload".italic(), "[DIR]".blue());
+        eprintln!("- {} {}", "serve".italic(), "[--local DIR]".blue());
+        eprintln!(
+            "- {} {} {}",
+            "upload".italic(),
+            "[DIR[:/PREFIX]]...".blue(),
+            "[--site HOST] [--dry-run] [--watch]".blue()
+        );
+        eprintln!(
+            "- {} {} {}",
+            "diff".italic(),
+            "[DIR[:/PREFIX]]...".blue(),
+            "[--site HOST] [--json]".blue()
+        );
         eprintln!("- {}", "protect".italic());
-        eprintln!("- {}", "cache".italic());
+        eprintln!("- {}", "rekey".italic());
+        eprintln!("- {}", "migrate".italic());
+        eprintln!("- {} {}", "cache".italic(), "[--site HOST]".blue());
+        eprintln!("- {} {}", "headers".italic(), "[--site HOST]".blue());
+        eprintln!("- {} {}", "cors".italic(), "[--site HOST]".blue());
+        eprintln!("- {} {}", "ip-filter".italic(), "[--site HOST]".blue());
+        eprintln!("- {} {}", "rate-limit".italic(), "[--site HOST]".blue());
+        eprintln!("- {} {}", "crawlers".italic(), "[--site HOST]".blue());
+        eprintln!("- {}", "journal".italic());
+        eprintln!("- {}", "stats".italic());
+        eprintln!("- {} {}", "report bandwidth".italic(), "[--days N]".blue());
+        eprintln!("- {} {}", "audit tail".italic(), "[COUNT]".blue());
+        eprintln!("- {} {}", "releases".italic(), "[--site HOST]".blue());
+        eprintln!("- {}", "doctor".italic());
+        eprintln!(
+            "- {} {} {}",
+            "rollback".italic(),
+            "[TIMESTAMP|INDEX]".blue(),
+            "[--site HOST]".blue()
+        );
+        eprintln!(
+            "- {} {} {}",
+            "verify".italic(),
+            "[--site HOST]".blue(),
+            "[--deep]".blue()
+        );
+        eprintln!(
+            "- {} {} {}",
+            "download".italic(),
+            "[DIR]".blue(),
+            "[--site HOST] [--include GLOB] [--exclude GLOB]".blue()
+        );
+        eprintln!(
+            "- {} {} {}",
+            "gc".italic(),
+            "[--site HOST]".blue(),
+            "[--now]".blue()
+        );
         eprintln!();
         eprintln!("`{}` command", "serve".italic());
         eprintln!(
@@ -233,6 +522,21 @@ impl Args {
             "S3_BUCKET".green(),
             "PORT".green()
         );
+        eprintln!(
+            "  Each site uploaded with {} is served to requests for its host, falling back to the default",
+            "--site".blue()
+        );
+        eprintln!(
+            "  With {}, serves that directory instead without needing a bucket, reloading whenever a file in it changes",
+            "--local DIR".blue()
+        );
+        eprintln!(
+            "  On SIGHUP, reloads everything and re-reads {}, {} & {} from the environment and {}",
+            "RUST_LOG".green(),
+            "MAX_CONCURRENT_REQUESTS".green(),
+            "CACHE_MAX_BYTES".green(),
+            ".env".blue()
+        );
         eprintln!("  eg. `{}`", "shove serve".cyan());
         eprintln!();
         eprintln!("`{}` command", "upload".italic());
@@ -245,7 +549,92 @@ impl Args {
             "DIR".blue(),
             "S3_BUCKET".green()
         );
+        eprintln!(
+            "  Several {}s can be given, each mounted at its {} (defaulting to the root). No two can provide the same path",
+            "DIR".blue(),
+            "PREFIX".blue()
+        );
+        eprintln!(
+            "  With {}, prints what would change without touching the bucket, exiting with 2 if anything would",
+            "--dry-run".blue()
+        );
+        eprintln!(
+            "  With {}, uploads to that host's site rather than the one at the top of the bucket",
+            "--site".blue()
+        );
+        eprintln!(
+            "  Each file gets the Cache-Control its {} rules give it set on the object too, re-uploading any where that's changed. {} leaves that off",
+            "cache".italic(),
+            "--skip-metadata".blue()
+        );
+        eprintln!(
+            "  Anything matched by the gitignore-style patterns in a {}'s {} or any {} is left out. Previously uploaded files that are now ignored get deleted, unless {} is given",
+            "DIR".blue(),
+            ".shoveignore".italic(),
+            "--exclude GLOB".blue(),
+            "--keep-ignored".blue()
+        );
+        eprintln!(
+            "  Files that haven't changed size or modification time since the last upload aren't hashed again, using a {} in each {}. {} hashes everything",
+            ".shove-manifest.json".italic(),
+            "DIR".blue(),
+            "--no-manifest".blue()
+        );
+        eprintln!(
+            "  With {}, everything goes under a new {} prefix and the site switches over once it's all there, so visitors never see half an upload. Files that are already in the bucket get copied rather than uploaded, and old prefixes go once their release is older than the last {}",
+            "--atomic".blue(),
+            "releases/".italic(),
+            "RELEASES_KEPT".green()
+        );
+        eprintln!(
+            "  Shows progress bars and a summary at the end when run in a terminal. {} leaves both off, only logging",
+            "--quiet".blue()
+        );
+        eprintln!(
+            "  Files that are no longer part of the site stay in the bucket for 10 minutes, or {}, so servers still on the last upload can serve them. They're deleted by the next upload or {} once that's up, or straight away with {}",
+            "--delete-after DURATION".blue(),
+            "gc".italic(),
+            "--delete-now".blue()
+        );
+        eprintln!(
+            "  With {}, keeps running after uploading, uploading again whenever something in a {} changes until Ctrl+C. Editors' swap & temp files are left out while watching",
+            "--watch".blue(),
+            "DIR".blue()
+        );
+        eprintln!(
+            "  With {} and {}, asks the server at that URL to reload after each upload that changed anything",
+            "--notify-url URL".blue(),
+            "--notify-token TOKEN".blue()
+        );
         eprintln!("  eg. `{}`", "shove upload public".cyan());
+        eprintln!(
+            "  eg. `{}`",
+            "shove upload site:/ docs/build:/docs storybook-static:/components".cyan()
+        );
+        eprintln!(
+            "  eg. `{}`",
+            "shove upload public --watch --notify-url http://localhost:8080 --notify-token token"
+                .cyan()
+        );
+        eprintln!();
+        eprintln!("`{}` command", "diff".italic());
+        eprintln!(
+            "  Compares the {}s to what was last uploaded, listing what's been added, changed and removed with their sizes",
+            "DIR".blue()
+        );
+        eprintln!(
+            "  Takes the same {}s, {}, {} and {} as {}, and goes by contents alone",
+            "DIR[:/PREFIX]".blue(),
+            "--site".blue(),
+            "--exclude GLOB".blue(),
+            "--no-manifest".blue(),
+            "upload".italic()
+        );
+        eprintln!(
+            "  Exits with 0 if nothing's different, 2 if anything is, and 1 if it couldn't tell. {} prints it as JSON",
+            "--json".blue()
+        );
+        eprintln!("  eg. `{}`", "shove diff public --json".cyan());
         eprintln!();
         eprintln!("`{}` command", "protect".italic());
         eprintln!(
@@ -253,10 +642,182 @@ impl Args {
         );
         eprintln!("  eg. `{}`", "shove protect".cyan());
         eprintln!();
+        eprintln!("`{}` command", "rekey".italic());
+        eprintln!(
+            "  Re-encrypts the authentication data (and anything else encrypted) from {} to {}, keeping every user",
+            "AUTH_ENCRYPTION_KEY_OLD".green(),
+            "AUTH_ENCRYPTION_KEY".green()
+        );
+        eprintln!("  Restart the server with the new key afterwards");
+        eprintln!(
+            "  eg. `{}`",
+            "AUTH_ENCRYPTION_KEY_OLD=old AUTH_ENCRYPTION_KEY=new shove rekey".cyan()
+        );
+        eprintln!();
+        eprintln!("`{}` command", "migrate".italic());
+        eprintln!(
+            "  Rewrites the authentication data and every site's cache control in the latest format"
+        );
+        eprintln!("  Older formats are still read, so this is only needed to stop older versions of shove reading them");
+        eprintln!("  eg. `{}`", "shove migrate".cyan());
+        eprintln!();
         eprintln!("`{}` command", "cache".italic());
         eprintln!("  Modifies the cache control headers on files",);
+        eprintln!(
+            "  With {}, modifies that host's site rather than the one at the top of the bucket",
+            "--site".blue()
+        );
         eprintln!("  eg. `{}`", "shove cache".cyan());
         eprintln!();
+        eprintln!("`{}` command", "headers".italic());
+        eprintln!("  Modifies the extra headers (eg. security headers) sent with files");
+        eprintln!(
+            "  With {}, modifies that host's site rather than the one at the top of the bucket",
+            "--site".blue()
+        );
+        eprintln!("  eg. `{}`", "shove headers".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "cors".italic());
+        eprintln!("  Modifies which other origins can fetch files");
+        eprintln!(
+            "  With {}, modifies that host's site rather than the one at the top of the bucket",
+            "--site".blue()
+        );
+        eprintln!("  eg. `{}`", "shove cors".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "ip-filter".italic());
+        eprintln!("  Modifies which networks can request files");
+        eprintln!(
+            "  With {}, modifies that host's site rather than the one at the top of the bucket",
+            "--site".blue()
+        );
+        eprintln!("  eg. `{}`", "shove ip-filter".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "rate-limit".italic());
+        eprintln!(
+            "  Modifies how often each client can request files, for paths that match a rule"
+        );
+        eprintln!(
+            "  With {}, modifies that host's site rather than the one at the top of the bucket",
+            "--site".blue()
+        );
+        eprintln!("  eg. `{}`", "shove rate-limit".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "crawlers".italic());
+        eprintln!(
+            "  Modifies which User-Agents are crawlers, whose requests are served without being added to the cache"
+        );
+        eprintln!(
+            "  With {}, modifies that host's site rather than the one at the top of the bucket",
+            "--site".blue()
+        );
+        eprintln!("  eg. `{}`", "shove crawlers".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "journal".italic());
+        eprintln!("  Prints the requests that most recently failed with a server error");
+        eprintln!("  eg. `{}`", "shove journal".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "stats".italic());
+        eprintln!("  Prints how often the most requested paths were hit or missed in the cache, as of the last time a server saved them");
+        eprintln!("  eg. `{}`", "shove stats".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "report bandwidth".italic());
+        eprintln!(
+            "  Prints how many bytes each path served over the last {} days (defaulting to {}), heaviest first",
+            "N".blue(),
+            DEFAULT_REPORT_DAYS
+        );
+        eprintln!("  eg. `{}`", "shove report bandwidth --days 30".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "audit tail".italic());
+        eprintln!(
+            "  Prints the most recent {} (defaulting to {}) logins and changes to users/realms",
+            "COUNT".blue(),
+            DEFAULT_TAIL_EVENTS
+        );
+        eprintln!("  eg. `{}`", "shove audit tail 100".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "releases".italic());
+        eprintln!(
+            "  Lists the last {} uploads, newest first, which can be rolled back to",
+            "RELEASES_KEPT".green()
+        );
+        eprintln!(
+            "  With {}, lists that host's site rather than the one at the top of the bucket",
+            "--site".blue()
+        );
+        eprintln!("  eg. `{}`", "shove releases".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "rollback".italic());
+        eprintln!(
+            "  Makes the release with that {} (or at that {} in `{}`) live again, putting back any files that have changed since",
+            "TIMESTAMP".blue(),
+            "INDEX".blue(),
+            "releases".italic()
+        );
+        eprintln!(
+            "  If {} and {} are set, asks the server to reload afterwards",
+            "TIGRIS_TOKEN".green(),
+            "SHOVE_URL".green()
+        );
+        eprintln!(
+            "  With {}, rolls back that host's site rather than the one at the top of the bucket",
+            "--site".blue()
+        );
+        eprintln!("  eg. `{}`", "shove rollback 1".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "doctor".italic());
+        eprintln!(
+            "  Checks the environment variables, that the bucket can be reached & written to, and that everything in it reads properly"
+        );
+        eprintln!("  Exits with 1 if anything's wrong, so it can be run before deploying");
+        eprintln!("  eg. `{}`", "shove doctor".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "verify".italic());
+        eprintln!(
+            "  Checks everything in the last upload is in the bucket, and the size it was uploaded at"
+        );
+        eprintln!(
+            "  With {}, downloads & re-hashes every object too. With {}, checks that host's site",
+            "--deep".blue(),
+            "--site".blue()
+        );
+        eprintln!("  Exits with 1 if anything's missing or different");
+        eprintln!("  eg. `{}`", "shove verify --deep".cyan());
+        eprintln!();
+        eprintln!("`{}` command", "download".italic());
+        eprintln!(
+            "  Downloads the last upload into {}, laid out like the site, checking each file against the hash it was uploaded with",
+            "DIR".blue()
+        );
+        eprintln!(
+            "  Files already in {} with the right hash are left alone, so it can be run again to pick up where it left off",
+            "DIR".blue()
+        );
+        eprintln!(
+            "  Only files matching an {} are downloaded if any are given, and none matching an {}. With {}, downloads that host's site",
+            "--include GLOB".blue(),
+            "--exclude GLOB".blue(),
+            "--site".blue()
+        );
+        eprintln!("  Exits with 1 if anything couldn't be downloaded");
+        eprintln!(
+            "  eg. `{}`",
+            "shove download backup --exclude '*.map'".cyan()
+        );
+        eprintln!();
+        eprintln!("`{}` command", "gc".italic());
+        eprintln!(
+            "  Deletes the files earlier uploads left in the bucket once they're past their {}, unless an upload or rollback needs them again",
+            "--delete-after".blue()
+        );
+        eprintln!(
+            "  With {}, deletes them all without waiting. With {}, cleans up that host's site",
+            "--now".blue(),
+            "--site".blue()
+        );
+        eprintln!("  eg. `{}`", "shove gc --site example.com".cyan());
+        eprintln!();
         eprintln!("{}", "Environment Variables".underline());
         eprintln!(
             "{} - the secret key ID for the S3 bucket",
@@ -271,10 +832,18 @@ impl Args {
             "{} - the endpoint of the S3 bucket",
             "AWS_ENDPOINT_URL_S3".green()
         );
+        eprintln!(
+            "{} - a prefix in the bucket to keep everything under, so it can be shared. Optional",
+            "S3_PREFIX".green()
+        );
         eprintln!(
             "{} - the port used for serving the bucket. Not needed if uploading/protecting. Defaults to 8080",
             "PORT".green()
         );
+        eprintln!(
+            "{} - a comma-separated list of addresses and unix:paths to listen on instead of PORT, each optionally followed by =public to hide /reload, /metrics & /__shove/ there or =internal to serve only those. Optional",
+            "LISTEN".green()
+        );
         eprintln!(
             "{} - the sentry DSN for use with analytics. Not needed if uploading/protecting. Optional",
             "SENTRY_DSN".green()
@@ -283,20 +852,74 @@ impl Args {
             "{} - the key used to encrypt the authentication data. Not needed if uploading.",
             "AUTH_ENCRYPTION_KEY".green(),
         );
-        eprintln!("{} - the authentication token for use with Tigris Webhooks. Not needed if uploading/protecting. Optional", "TIGRIS_TOKEN".green());
+        eprintln!(
+            "{} - the key the authentication data was encrypted with before. Only needed if rekeying",
+            "AUTH_ENCRYPTION_KEY_OLD".green(),
+        );
+        eprintln!(
+            "{} - the fewest characters a new password can have. Defaults to 8. Only needed if protecting. Optional",
+            "MIN_PASSWORD_LENGTH".green()
+        );
+        eprintln!("{} - the authentication token for use with Tigris Webhooks, or a comma-separated list of them so old & new can overlap while rotating. Not needed if uploading/protecting. Optional", "TIGRIS_TOKEN".green());
+        eprintln!("{} - the bearer token for the `/__shove/*` admin endpoints. Not needed if uploading/protecting. Optional", "ADMIN_TOKEN".green());
+        eprintln!("{} - set to `true` to need the {} for `/__shove/version` too. Not needed if uploading/protecting. Optional", "PROTECT_VERSION".green(), "ADMIN_TOKEN".green());
+        eprintln!("{} - set to `true` to record requests that end in a server error to the bucket. Not needed if uploading/protecting. Optional", "REQUEST_JOURNAL".green());
+        eprintln!("{} - set to `true` to record logins to `audit/` in the bucket. Not needed if uploading/protecting. Optional", "AUDIT_LOG".green());
+        eprintln!(
+            "{} - how many requests the journal keeps. Defaults to 200",
+            "REQUEST_JOURNAL_SIZE".green()
+        );
+        eprintln!("{} - how long to wait after a drain request before shutting down if no signal arrives. Not needed if uploading/protecting. Optional", "DRAIN_EXIT_AFTER_SECS".green());
+        eprintln!("{} - files bigger than this many bytes get streamed from S3 rather than cached. Not needed if uploading/protecting. Optional", "MAX_CACHEABLE_BYTES".green());
+        eprintln!("{} - files bigger than this many bytes get read in & served, but not kept in the cache. Not needed if uploading/protecting. Optional", "CACHE_ADMIT_MAX_BYTES".green());
+        eprintln!("{} - `redirect` (the default) to 308 directories to end in a `/` and files to not, or `ignore` to serve either. Not needed if uploading/protecting. Optional", "TRAILING_SLASH".green());
+        eprintln!("{} - `true` (or `redirect`) to 301 requests that only match an uploaded file ignoring case to how it was uploaded, or `serve` to serve it as-is. Not needed if uploading/protecting. Optional", "CASE_INSENSITIVE_PATHS".green());
+        eprintln!("{} - how much of the cache (from 0 to 1) needs reading in before `/readycheck` passes, rather than waiting for all of it. Not needed if uploading/protecting. Optional", "READY_FRACTION".green());
+        eprintln!("{} - set to `true` to have `/healthcheck` check the bucket can be reached, at most every 30s. Not needed if uploading/protecting. Optional", "HEALTHCHECK_PROBE".green());
+        eprintln!("{} - how many bucket requests in a row need to fail before `/healthcheck` does. Defaults to 3. Not needed if uploading/protecting. Optional", "HEALTHCHECK_FAILURES".green());
+        eprintln!("{} - how many seconds a bucket request gets before it's given up on, with a 504 if a page needed it. Defaults to 10. Not needed if uploading/protecting. Optional", "S3_TIMEOUT_SECS".green());
+        eprintln!("{} - how many paths get their own count in each day's bandwidth report, before the rest are lumped together. Defaults to 1000. Not needed if uploading/protecting. Optional", "BANDWIDTH_MAX_PATHS".green());
+        eprintln!("{} - roughly how many bytes of files to keep in memory. Defaults to 256MB. Not needed if uploading/protecting. Optional", "CACHE_MAX_BYTES".green());
+        eprintln!("{} - how long clients get to send request headers. Defaults to 10. Not needed if uploading/protecting. Optional", "HEADER_READ_TIMEOUT_SECS".green());
+        eprintln!("{} - how long a request can take before a 408. Defaults to 30. Not needed if uploading/protecting. Optional", "REQUEST_TIMEOUT_SECS".green());
+        eprintln!("{} - how long a connection can sit idle before it gets closed. Defaults to 120. Not needed if uploading/protecting. Optional", "IDLE_TIMEOUT_SECS".green());
+        eprintln!("{} - set to `true` to pick cache-control from the content type when no rule or default matches. Not needed if uploading/protecting. Optional", "SMART_CACHE_DEFAULTS".green());
+        eprintln!("{} - set to `true` to add a script to HTML pages that reloads them when the site changes. Not needed if uploading/protecting. Optional", "LIVERELOAD_INJECT".green());
+        eprintln!("{} - set to `true` to stop `; charset=utf-8` being added to text, JSON & JavaScript content types that don't say. Not needed if uploading/protecting. Optional", "NO_DEFAULT_CHARSET".green());
+        eprintln!("{} - set to `true` to make up `/sitemap.xml` & `/robots.txt` for sites that didn't upload their own, leaving out protected pages. Not needed if uploading/protecting. Optional", "GENERATE_SITEMAP".green());
+        eprintln!("{} - the path a proxy forwards requests under, eg. `/docs`, which is taken off before serving & put back on redirects. Anything outside it is a 404. Not needed if uploading/protecting. Optional", "BASE_PATH".green());
+        eprintln!("{} - the host (or URL) that generated sitemaps point to, instead of the host in the request. Not needed if uploading/protecting. Optional", "CANONICAL_HOST".green());
+        eprintln!("{} - how long live-reload sockets get to acknowledge a shutdown. Defaults to 5. Not needed if uploading/protecting. Optional", "WS_CLOSE_TIMEOUT_SECS".green());
+        eprintln!("{} - how many live-reload sockets can be open at once, with any more getting a 503. Defaults to 1024. Not needed if uploading/protecting. Optional", "MAX_LIVERELOAD_CLIENTS".green());
+        eprintln!("{} - comma-separated addresses/CIDRs of the proxies in front of shove, whose `X-Forwarded-For`/`Forwarded` headers are trusted. Not needed if uploading/protecting. Optional", "TRUSTED_PROXIES".green());
+        eprintln!("{} - how many failed logins in a row before each new attempt has to wait longer. Defaults to 5. Not needed if uploading/protecting. Optional", "AUTH_BACKOFF_AFTER".green());
+        eprintln!("{} - how many failed logins in a row before an IP gets banned. Defaults to 20. Not needed if uploading/protecting. Optional", "AUTH_BAN_AFTER".green());
+        eprintln!("{} - how long an IP stays banned for. Defaults to 900. Not needed if uploading/protecting. Optional", "AUTH_BAN_SECS".green());
+        eprintln!("{} - the biggest POST body accepted. Defaults to 64KiB. Not needed if uploading/protecting. Optional", "MAX_BODY_BYTES".green());
+        eprintln!("{} - how many requests get handled at once, with any more getting a 429. Defaults to 512. Not needed if uploading/protecting. Optional", "MAX_CONCURRENT_REQUESTS".green());
+        eprintln!("{} - how many milliseconds a request past MAX_CONCURRENT_REQUESTS waits for a turn, 0 to turn it away straight away. Defaults to 100. Not needed if uploading/protecting. Optional", "REQUEST_QUEUE_MS".green());
+        eprintln!("{} - how many uploads to keep around to roll back to, with `0` turning it off. Defaults to 5. Only needed if uploading/rolling back. Optional", "RELEASES_KEPT".green());
+        eprintln!("{} - where the server is, eg. `https://example.com`, so `rollback` can ask it to reload. Only needed if rolling back. Optional", "SHOVE_URL".green());
 
         std::process::exit(1);
     }
 }
 
+///what a command needs from the environment, or exits listing everything that's wrong with it
+fn config_or_exit<T>(config: Result<T, ConfigError>) -> T {
+    config.unwrap_or_else(|e| {
+        eprintln!("{}", e.red());
+        std::process::exit(1);
+    })
+}
+
 fn main() {
     //SAFETY: only one thread r/w at this point
     unsafe {
         setup();
     }
-    
+
     let args = Args::parse();
-    
 
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -304,49 +927,229 @@ fn main() {
         .expect("unable to build runtime");
 
     match args {
-        Args::Serve => {
-            let dsn = match var("SENTRY_DSN") {
-                Ok(x) => match x.parse() {
-                    Ok(x) => Some(x),
-                    Err(e) => {
-                        warn!(?e, "Error parsing sentry DSN");
-                        None
-                    }
-                },
-                Err(_) => {
-                    warn!("No Sentry DSN detected");
-                    None
-                }
-            };
+        Args::Serve { local } => {
+            let config = config_or_exit(Config::from_env(local));
+            if config.sentry_dsn.is_none() {
+                warn!("No Sentry DSN detected");
+            }
 
             let _sentry = sentry::init(sentry::ClientOptions {
-                dsn,
+                dsn: config.sentry_dsn.clone(),
                 release: sentry::release_name!(),
                 traces_sample_rate: 0.1,
                 ..Default::default()
             });
             runtime.block_on(async move {
-                if let Err(e) = serve().await {
+                if let Err(e) = serve(config).await {
                     error!(?e, "Error serving");
                 }
             });
         }
-        Args::Upload(dir) => runtime.block_on(async move {
-            if let Err(e) = upload(&dir).await {
-                error!(?e, "Error uploading");
-            }
-        }),
+        Args::Upload {
+            mappings,
+            site,
+            options,
+            watch,
+            notify,
+        } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) =
+                    upload(&config, &mappings, site.as_deref(), options, watch, notify).await
+                {
+                    error!(?e, "Error uploading");
+                }
+            })
+        }
+        Args::Diff {
+            mappings,
+            site,
+            options,
+            json,
+        } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                match diff(&config, &mappings, site.as_deref(), options, json).await {
+                    Ok(false) => {}
+                    Ok(true) => std::process::exit(2),
+                    Err(e) => {
+                        error!(?e, "Error diffing");
+                        std::process::exit(1);
+                    }
+                }
+            })
+        }
         Args::Protect => {
+            let (config, auth) = config_or_exit(AuthConfig::from_env());
             runtime.block_on(async move {
-                if let Err(e) = protect().await {
+                if let Err(e) = protect(&config, &auth).await {
                     error!(?e, "Error protecting");
                 }
             });
         }
-        Args::Cache => runtime.block_on(async move {
-            if let Err(e) = cache().await {
-                error!(?e, "Error caching");
+        Args::Rekey => {
+            let (config, keys) = config_or_exit(RekeyConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = rekey(&config, &keys).await {
+                    error!(?e, "Error re-encrypting");
+                    std::process::exit(1);
+                }
+            });
+        }
+        Args::Migrate => {
+            let (config, auth) = config_or_exit(AuthConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = migrate(&config, &auth).await {
+                    error!(?e, "Error migrating");
+                    std::process::exit(1);
+                }
+            });
+        }
+        Args::Cache { site } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = cache(&config, site.as_deref()).await {
+                    error!(?e, "Error caching");
+                }
+            })
+        }
+        Args::Headers { site } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = headers(&config, site.as_deref()).await {
+                    error!(?e, "Error setting headers");
+                }
+            })
+        }
+        Args::Cors { site } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = cors(&config, site.as_deref()).await {
+                    error!(?e, "Error setting CORS rules");
+                }
+            })
+        }
+        Args::IpFilter { site } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = ip_filter(&config, site.as_deref()).await {
+                    error!(?e, "Error setting IP rules");
+                }
+            })
+        }
+        Args::RateLimit { site } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = rate_limit(&config, site.as_deref()).await {
+                    error!(?e, "Error setting rate limits");
+                }
+            })
+        }
+        Args::Crawlers { site } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = crawlers(&config, site.as_deref()).await {
+                    error!(?e, "Error setting crawlers");
+                }
+            })
+        }
+        Args::Journal => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = journal(&config).await {
+                    error!(?e, "Error reading journal");
+                }
+            })
+        }
+        Args::Stats => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = stats(&config).await {
+                    error!(?e, "Error reading hit stats");
+                }
+            })
+        }
+        Args::ReportBandwidth { days } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = report_bandwidth(&config, days).await {
+                    error!(?e, "Error reading bandwidth reports");
+                }
+            })
+        }
+        Args::AuditTail { count } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = audit_tail(&config, count).await {
+                    error!(?e, "Error reading audit log");
+                }
+            })
+        }
+        Args::Doctor => runtime.block_on(async move {
+            if let Err(e) = doctor().await {
+                error!(?e, "Error checking configuration");
+                std::process::exit(1);
             }
         }),
+        Args::Releases { site } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = releases(&config, site.as_deref()).await {
+                    error!(?e, "Error listing releases");
+                }
+            })
+        }
+        Args::Rollback { site, release } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = rollback(&config, site.as_deref(), &release).await {
+                    error!(?e, "Error rolling back");
+                }
+            })
+        }
+        Args::Verify { site, deep } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                match verify(&config, site.as_deref(), deep).await {
+                    Ok(true) => {}
+                    Ok(false) => std::process::exit(1),
+                    Err(e) => {
+                        error!(?e, "Error verifying");
+                        std::process::exit(1);
+                    }
+                }
+            })
+        }
+        Args::Download {
+            dir,
+            site,
+            includes,
+            excludes,
+        } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            let filter = DownloadFilter::new(&includes, &excludes).unwrap_or_else(|e| {
+                eprintln!("{}", e.red());
+                std::process::exit(1);
+            });
+            runtime.block_on(async move {
+                match download(&config, &dir, site.as_deref(), &filter).await {
+                    Ok(true) => {}
+                    Ok(false) => std::process::exit(1),
+                    Err(e) => {
+                        error!(?e, "Error downloading");
+                        std::process::exit(1);
+                    }
+                }
+            })
+        }
+        Args::Gc { site, now } => {
+            let config = config_or_exit(BucketConfig::from_env());
+            runtime.block_on(async move {
+                if let Err(e) = gc(&config, site.as_deref(), now).await {
+                    error!(?e, "Error deleting old files");
+                    std::process::exit(1);
+                }
+            })
+        }
     }
 }
@@ -0,0 +1,165 @@
+use crate::{error::ShoveError, s3::get_bytes_and_etag, store::ObjectStore};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter},
+    hash::Hash,
+};
+
+///how many times a save re-reads and merges before giving up on something that keeps changing
+const SAVE_ATTEMPTS: usize = 5;
+
+///something else changed the same thing we did since we loaded it, so neither can just win
+#[derive(Debug)]
+pub struct ChangedSinceLoaded {
+    pub location: String,
+    ///what both changed
+    pub what: String,
+}
+
+impl Display for ChangedSinceLoaded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} in {:?} changed since it was loaded - re-run to start from the new version",
+            self.what, self.location
+        )
+    }
+}
+
+impl std::error::Error for ChangedSinceLoaded {}
+
+///data that's read, changed and written back whole, so anything written in between needs merging
+///in rather than writing over
+pub trait Mergeable: Clone + Default {
+    ///`ours` and `theirs` both started out as `base`. `Err` says what they both changed
+    fn merge(base: &Self, ours: Self, theirs: Self) -> Result<Self, String>;
+}
+
+///whichever changed from `base`, or `None` if both did, differently
+pub fn merge_value<T: PartialEq>(base: &T, ours: T, theirs: T) -> Option<T> {
+    if &ours == base {
+        Some(theirs)
+    } else if &theirs == base || ours == theirs {
+        Some(ours)
+    } else {
+        None
+    }
+}
+
+///[`merge_value`] for every key, with being added or removed counting as a change. `Err` has the
+///first key both changed
+pub fn merge_map<K: Hash + Eq + Clone, V: PartialEq>(
+    base: &HashMap<K, V>,
+    mut ours: HashMap<K, V>,
+    mut theirs: HashMap<K, V>,
+) -> Result<HashMap<K, V>, K> {
+    let keys: HashSet<K> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .cloned()
+        .collect();
+
+    let mut merged = HashMap::new();
+    for key in keys {
+        let base = base.get(&key);
+        let (ours, theirs) = (ours.remove(&key), theirs.remove(&key));
+        let value = if ours.as_ref() == base {
+            theirs
+        } else if theirs.as_ref() == base || ours == theirs {
+            ours
+        } else {
+            return Err(key);
+        };
+        if let Some(value) = value {
+            merged.insert(key, value);
+        }
+    }
+    Ok(merged)
+}
+
+///writes `ours` to `location`, merging in whatever's been written there since `base` was loaded,
+///and only writing if nothing else has by the time it's done. Returns what was written, to be the
+///next `base`
+pub async fn save_merged<T: Mergeable>(
+    bucket: &dyn ObjectStore,
+    location: &str,
+    base: &T,
+    ours: &T,
+    read: impl Fn(&[u8]) -> Result<T, ShoveError>,
+    write: impl Fn(&T) -> Result<Vec<u8>, ShoveError>,
+) -> Result<T, ShoveError> {
+    for _ in 0..SAVE_ATTEMPTS {
+        let (bytes, etag) = get_bytes_and_etag(bucket, location).await?;
+        let theirs = read(&bytes)?;
+        let merged = T::merge(base, ours.clone(), theirs).map_err(|what| ChangedSinceLoaded {
+            location: location.to_string(),
+            what,
+        })?;
+
+        if bucket
+            .put_if_unchanged(
+                location,
+                &write(&merged)?,
+                "application/octet-stream",
+                etag.as_deref(),
+            )
+            .await?
+        {
+            return Ok(merged);
+        }
+        debug!(
+            ?location,
+            "Changed underneath us whilst saving, trying again"
+        );
+    }
+
+    Err(ShoveError::Busy {
+        key: location.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_value() {
+        assert_eq!(merge_value(&1, 1, 1), Some(1));
+        assert_eq!(merge_value(&1, 2, 1), Some(2));
+        assert_eq!(merge_value(&1, 1, 3), Some(3));
+        assert_eq!(merge_value(&1, 2, 2), Some(2));
+        assert_eq!(merge_value(&1, 2, 3), None);
+    }
+
+    #[test]
+    fn test_merge_map() {
+        let base = HashMap::from([("kept", 1), ("ours", 1), ("theirs", 1), ("gone", 1)]);
+        let mut ours = base.clone();
+        ours.insert("ours", 2);
+        ours.insert("added", 1);
+        ours.remove("gone");
+        let mut theirs = base.clone();
+        theirs.insert("theirs", 3);
+        theirs.insert("also added", 1);
+
+        assert_eq!(
+            merge_map(&base, ours.clone(), theirs.clone()),
+            Ok(HashMap::from([
+                ("kept", 1),
+                ("ours", 2),
+                ("theirs", 3),
+                ("added", 1),
+                ("also added", 1)
+            ]))
+        );
+
+        //changed by one, removed by the other
+        theirs.insert("gone", 2);
+        assert_eq!(merge_map(&base, ours.clone(), theirs.clone()), Err("gone"));
+        theirs.insert("gone", 1);
+        //added by both, differently
+        theirs.insert("added", 2);
+        assert_eq!(merge_map(&base, ours, theirs), Err("added"));
+    }
+}
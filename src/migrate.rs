@@ -0,0 +1,110 @@
+use crate::{
+    cache_control::manager::{Caching, CC_LOCATION},
+    config::{AuthConfig, BucketConfig},
+    protect::{
+        auth::AUTH_DATA_LOCATION,
+        auth_storer::{AuthKey, AuthStorer},
+    },
+    s3::{get_bytes_or_default, site_location, SITES_LOCATION},
+    store::ObjectStore,
+    SitesManifest,
+};
+use color_eyre::eyre::WrapErr;
+
+///rewrites the auth data & every site's cache control that isn't the latest version, returning
+///where it did
+pub async fn migrate_store(
+    bucket: &dyn ObjectStore,
+    key: &AuthKey,
+) -> color_eyre::Result<Vec<String>> {
+    let mut migrated = vec![];
+
+    let (mut auth, bytes) = AuthStorer::new(bucket, key)
+        .await
+        .wrap_err("couldn't read the auth data")?;
+    if !AuthStorer::is_latest(&bytes, key)? {
+        auth.save(bucket, key).await?;
+        info!(location=?AUTH_DATA_LOCATION, "Migrated");
+        migrated.push(AUTH_DATA_LOCATION.to_string());
+    }
+
+    let sites = get_bytes_or_default(bucket, SITES_LOCATION).await?;
+    let manifest: SitesManifest = if sites.is_empty() {
+        SitesManifest::default()
+    } else {
+        serde_json::from_slice(&sites)?
+    };
+    let sites = std::iter::once(None).chain(manifest.hosts.iter().map(|x| Some(x.as_str())));
+    for site in sites {
+        let location = site_location(site, CC_LOCATION);
+        let (mut caching, bytes) = Caching::new(bucket, site)
+            .await
+            .wrap_err_with(|| format!("couldn't read {location}"))?;
+        if !Caching::is_latest(&bytes)? {
+            caching.save(bucket, site).await?;
+            info!(?location, "Migrated");
+            migrated.push(location);
+        }
+    }
+
+    Ok(migrated)
+}
+
+pub async fn migrate(config: &BucketConfig, auth: &AuthConfig) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let migrated = migrate_store(&bucket, &auth.key).await?;
+    if migrated.is_empty() {
+        println!("Everything is already the latest version.");
+    } else {
+        println!("Migrated {}.", migrated.join(", "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{envelope, store::memory::InMemoryBucket};
+
+    #[tokio::test]
+    async fn test_old_formats_are_rewritten() {
+        let key = AuthKey::derive("hunter2", "bucket");
+        let bucket = InMemoryBucket::new();
+
+        //from before envelopes & versions
+        let auth = key.encrypt(br#"{"realms":[],"users":[]}"#).unwrap();
+        bucket
+            .put(AUTH_DATA_LOCATION, &auth, "application/octet-stream")
+            .await
+            .unwrap();
+        let unversioned = envelope::seal(br#"{"default":null,"overrides":[]}"#, None).unwrap();
+        bucket
+            .put(CC_LOCATION, &unversioned, "application/octet-stream")
+            .await
+            .unwrap();
+        let site_cc = site_location(Some("example.com"), CC_LOCATION);
+        Caching::default()
+            .save(&bucket, Some("example.com"))
+            .await
+            .unwrap();
+        let latest = bucket.bytes(&site_cc).await.unwrap();
+        bucket
+            .put(
+                SITES_LOCATION,
+                br#"{"hosts":["example.com"],"default":null}"#,
+                "application/json",
+            )
+            .await
+            .unwrap();
+
+        let migrated = migrate_store(&bucket, &key).await.unwrap();
+        assert_eq!(migrated, vec![AUTH_DATA_LOCATION, CC_LOCATION]);
+        assert!(
+            AuthStorer::is_latest(&bucket.bytes(AUTH_DATA_LOCATION).await.unwrap(), &key).unwrap()
+        );
+        assert!(Caching::is_latest(&bucket.bytes(CC_LOCATION).await.unwrap()).unwrap());
+        assert_eq!(bucket.bytes(&site_cc).await.unwrap(), latest);
+
+        assert!(migrate_store(&bucket, &key).await.unwrap().is_empty());
+    }
+}
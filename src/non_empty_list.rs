@@ -1,6 +1,8 @@
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     alloc::{alloc, dealloc, realloc, Layout},
     fmt::{Debug, Formatter},
+    hash::{Hash, Hasher},
     marker::PhantomData,
     mem::{needs_drop, ManuallyDrop},
     num::NonZeroUsize,
@@ -30,6 +32,11 @@ impl<T> NonEmptyList<T> {
         }
     }
 
+    ///`None` if `iter` doesn't give anything
+    pub fn try_from_iter(iter: impl IntoIterator<Item = T>) -> Option<Self> {
+        Self::new(iter.into_iter().collect())
+    }
+
     pub fn single_element(el: T) -> Self {
         if size_of::<T>() == 0 {
             return Self {
@@ -169,6 +176,8 @@ impl<T> NonEmptyList<T> {
         self.len = self.len.checked_add(1).unwrap();
     }
 
+    ///never empty, so there's no `is_empty`
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         self.len.get()
     }
@@ -210,6 +219,81 @@ impl<T> NonEmptyList<T> {
         currently_at_that_position
     }
 
+    ///`None` when there's only one element left, as taking it would make the list empty
+    pub fn pop(&mut self) -> Option<T> {
+        let new_len = NonZeroUsize::new(self.len.get() - 1)?;
+        self.len = new_len;
+
+        //safety: new_len was the last valid index, and isn't counted any more so won't be read again
+        let last = unsafe { self.ptr.add(new_len.get()) };
+        debug_assert!(last.is_aligned());
+        //safety: valid & aligned from above
+        Some(unsafe { last.read() })
+    }
+
+    ///drops everything from `new_len` onwards. Does nothing if the list is no longer than that
+    pub fn truncate(&mut self, new_len: NonZeroUsize) {
+        let current_len = self.len.get();
+        if new_len.get() >= current_len {
+            return;
+        }
+
+        //before dropping, so a panicking drop leaks the rest rather than them being dropped twice
+        self.len = new_len;
+
+        unsafe {
+            //safety: new_len < current_len, so everything between them is init
+            //safety: and no longer counted, so won't be read again
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
+                self.ptr.add(new_len.get()).as_ptr(),
+                current_len - new_len.get(),
+            ));
+        }
+    }
+
+    ///shifts everything from `index` onwards back one to make room for `el`
+    ///
+    ///# Panics
+    /// If `index > len`.
+    pub fn insert(&mut self, index: usize, el: T) {
+        let current_len = self.len.get();
+        if index > current_len {
+            panic!("tried to insert index out of bounds");
+        }
+
+        if size_of::<T>() == 0 {
+            //nothing to write, but it's now owned by the list
+            std::mem::forget(el);
+            self.len = self.len.checked_add(1).unwrap();
+            return;
+        }
+
+        if self.len >= self.cap {
+            self.grow_at_least(NonZeroUsize::new(1).unwrap());
+        }
+
+        debug_assert!(self.len < self.cap);
+
+        //safety: index <= current_len < cap, so it's within the allocation
+        let dst = unsafe { self.ptr.add(index) };
+        debug_assert!(dst.is_aligned());
+
+        if index != current_len {
+            //safety: there's room for one more, so index + 1 + count <= cap
+            //safety: copy_to allows overlapping
+            unsafe {
+                dst.copy_to(dst.add(1), current_len - index);
+            }
+        }
+
+        //safety: valid & aligned, and whatever was there has been moved up
+        unsafe {
+            dst.write(el);
+        }
+
+        self.len = self.len.checked_add(1).unwrap();
+    }
+
     pub fn swap_remove(&mut self, index: usize) -> T {
         let current_len = self.len.get();
         if index >= current_len {
@@ -289,7 +373,7 @@ impl<T> NonEmptyList<T> {
         }
     }
 
-    pub fn iter(&self) -> slice::Iter<T> {
+    pub fn iter(&self) -> slice::Iter<'_, T> {
         self.as_ref().iter()
     }
 
@@ -398,6 +482,20 @@ impl<T> AsMut<[T]> for NonEmptyList<T> {
     }
 }
 
+impl<T: PartialEq> PartialEq for NonEmptyList<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_ref() == other.as_ref()
+    }
+}
+impl<T: Eq> Eq for NonEmptyList<T> {}
+
+///the same as the slice's, and so a `Vec`'s
+impl<T: Hash> Hash for NonEmptyList<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_ref().hash(state);
+    }
+}
+
 impl<T: Clone> Clone for NonEmptyList<T> {
     fn clone(&self) -> Self {
         if size_of::<T>() == 0 {
@@ -460,6 +558,38 @@ impl<T> IntoIterator for NonEmptyList<T> {
     }
 }
 
+impl<'a, T> IntoIterator for &'a NonEmptyList<T> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut NonEmptyList<T> {
+    type Item = &'a mut T;
+    type IntoIter = slice::IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+///as a plain sequence, so it's stored the same as a `Vec` would be
+impl<T: Serialize> Serialize for NonEmptyList<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.as_ref().serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for NonEmptyList<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let list = Vec::deserialize(deserializer)?;
+        Self::new(list).ok_or_else(|| D::Error::invalid_length(0, &"at least one element"))
+    }
+}
+
 impl<T> Index<usize> for NonEmptyList<T> {
     type Output = T;
 
@@ -500,6 +630,12 @@ impl<T> TryFrom<NonEmptyListBuilder<T>> for NonEmptyList<T> {
     }
 }
 
+impl<T> From<NonEmptyList<T>> for NonEmptyListBuilder<T> {
+    fn from(value: NonEmptyList<T>) -> Self {
+        Self(value.into())
+    }
+}
+
 impl<T> Deref for NonEmptyListBuilder<T> {
     type Target = Vec<T>;
 
@@ -1029,6 +1165,7 @@ mod tests {
     }
 
     #[derive(Debug, PartialEq, Eq, Clone)]
+    #[allow(clippy::upper_case_acronyms)]
     struct ZST;
 
     #[test]
@@ -1135,4 +1272,300 @@ mod tests {
         assert_eq!(list[0], ZST);
         assert_eq!(list[1], ZST);
     }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let list = NonEmptyList::new(vec![1, 2, 3]).unwrap();
+        let json = serde_json::to_string(&list).unwrap();
+        assert_eq!(json, "[1,2,3]");
+        assert_eq!(
+            serde_json::from_str::<NonEmptyList<i32>>(&json).unwrap(),
+            list
+        );
+
+        //the same as a `Vec`, so either can read what the other wrote
+        let from_vec = serde_json::to_string(&vec!["a".to_string(), "b".to_string()]).unwrap();
+        let list: NonEmptyList<String> = serde_json::from_str(&from_vec).unwrap();
+        assert_eq!(serde_json::to_string(&list).unwrap(), from_vec);
+
+        let zsts: NonEmptyList<()> = serde_json::from_str("[null,null]").unwrap();
+        assert_eq!(zsts.len(), 2);
+        assert_eq!(serde_json::to_string(&zsts).unwrap(), "[null,null]");
+    }
+
+    #[test]
+    fn test_deserializing_nothing_fails() {
+        let e = serde_json::from_str::<NonEmptyList<i32>>("[]").unwrap_err();
+        assert!(
+            e.to_string().contains("expected at least one element"),
+            "{e}"
+        );
+        assert!(serde_json::from_str::<NonEmptyList<i32>>("null").is_err());
+        assert!(serde_json::from_str::<NonEmptyList<i32>>("[1,\"2\"]").is_err());
+    }
+
+    #[test]
+    fn test_deserializing_drops_everything_on_failure() {
+        #[derive(Deserialize)]
+        #[serde(from = "i32")]
+        struct Counted(i32);
+        thread_local! {
+            static DROPPED: RefCell<usize> = const { RefCell::new(0) };
+        }
+        impl From<i32> for Counted {
+            fn from(value: i32) -> Self {
+                Self(value)
+            }
+        }
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPPED.with(|x| *x.borrow_mut() += 1);
+            }
+        }
+
+        assert!(serde_json::from_str::<NonEmptyList<Counted>>("[1,2,\"3\"]").is_err());
+        assert_eq!(DROPPED.with(|x| *x.borrow()), 2);
+
+        let list: NonEmptyList<Counted> = serde_json::from_str("[1,2,3]").unwrap();
+        assert_eq!(list.iter().map(|x| x.0).sum::<i32>(), 6);
+        drop(list);
+        assert_eq!(DROPPED.with(|x| *x.borrow()), 5);
+    }
+
+    #[test]
+    fn test_hash_matches_eq() {
+        use std::{collections::HashSet, hash::BuildHasher};
+
+        let hasher = std::hash::RandomState::new();
+        let list = NonEmptyList::new(vec![1, 2, 3]).unwrap();
+        assert_eq!(hasher.hash_one(&list), hasher.hash_one(list.clone()));
+        assert_eq!(hasher.hash_one(&list), hasher.hash_one(vec![1, 2, 3]));
+        assert_ne!(
+            hasher.hash_one(&list),
+            hasher.hash_one(NonEmptyList::new(vec![1, 2]).unwrap())
+        );
+
+        let set: HashSet<_> = [list.clone(), list.clone(), NonEmptyList::single_element(1)].into();
+        assert_eq!(set.len(), 2);
+
+        let zsts = NonEmptyList::new(vec![ZST, ZST]).unwrap();
+        assert_eq!(zsts, zsts.clone());
+        assert_ne!(zsts, NonEmptyList::single_element(ZST));
+    }
+
+    #[test]
+    fn test_iterating_by_reference() {
+        let mut list = NonEmptyList::new(vec![1, 2, 3]).unwrap();
+        for x in &mut list {
+            *x *= 10;
+        }
+        let mut seen = vec![];
+        for x in &list {
+            seen.push(*x);
+        }
+        assert_eq!(seen, vec![10, 20, 30]);
+
+        let mut zsts = NonEmptyList::new(vec![ZST, ZST, ZST]).unwrap();
+        assert_eq!((&mut zsts).into_iter().count(), 3);
+        assert_eq!((&zsts).into_iter().count(), 3);
+    }
+
+    #[test]
+    fn test_try_from_iter() {
+        assert!(NonEmptyList::try_from_iter(std::iter::empty::<i32>()).is_none());
+        assert_eq!(
+            NonEmptyList::try_from_iter(1..=3).unwrap().as_ref(),
+            &[1, 2, 3]
+        );
+        assert_eq!(
+            NonEmptyList::try_from_iter(std::iter::repeat_n(ZST, 4))
+                .unwrap()
+                .len(),
+            4
+        );
+
+        let drop_count = Rc::new(RefCell::new(0));
+        let list =
+            NonEmptyList::try_from_iter((0..3).map(|_| DropNotifier(drop_count.clone()))).unwrap();
+        drop(list);
+        assert_eq!(*drop_count.borrow(), 3);
+    }
+
+    #[test]
+    fn test_back_to_a_builder() {
+        let drop_count = Rc::new(RefCell::new(0));
+        let list = NonEmptyList::new(vec![
+            DropNotifier(drop_count.clone()),
+            DropNotifier(drop_count.clone()),
+        ])
+        .unwrap();
+
+        let mut builder = NonEmptyListBuilder::from(list);
+        assert_eq!(builder.len(), 2);
+        builder.clear();
+        assert_eq!(*drop_count.borrow(), 2);
+        assert!(NonEmptyList::try_from(builder).is_err());
+
+        let mut builder = NonEmptyListBuilder::from(NonEmptyList::single_element(ZST));
+        builder.push(ZST);
+        assert_eq!(NonEmptyList::try_from(builder).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut list = NonEmptyList::new(vec![1, 2, 3]).unwrap();
+
+        assert_eq!(list.pop(), Some(3));
+        assert_eq!(list.pop(), Some(2));
+        assert_eq!(list.pop(), None);
+        assert_eq!(list.as_ref(), &[1]);
+
+        //still fine to grow again afterwards
+        list.push(4);
+        assert_eq!(list.as_ref(), &[1, 4]);
+    }
+
+    #[test]
+    fn test_pop_with_zst() {
+        let mut list = NonEmptyList::new(vec![ZST, ZST]).unwrap();
+
+        assert_eq!(list.pop(), Some(ZST));
+        assert_eq!(list.pop(), None);
+        assert_eq!(list.len(), 1);
+    }
+
+    #[test]
+    fn test_pop_hands_over_ownership() {
+        let drop_count = Rc::new(RefCell::new(0));
+        let mut list = NonEmptyList::new(vec![
+            DropNotifier(drop_count.clone()),
+            DropNotifier(drop_count.clone()),
+        ])
+        .unwrap();
+
+        let popped = list.pop().unwrap();
+        assert!(list.pop().is_none());
+        assert_eq!(*drop_count.borrow(), 0);
+
+        drop(popped);
+        assert_eq!(*drop_count.borrow(), 1);
+        drop(list);
+        assert_eq!(*drop_count.borrow(), 2);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut list = NonEmptyList::new(vec![1, 2, 3, 4]).unwrap();
+
+        list.truncate(NonZeroUsize::new(10).unwrap());
+        assert_eq!(list.as_ref(), &[1, 2, 3, 4]);
+        list.truncate(NonZeroUsize::new(4).unwrap());
+        assert_eq!(list.as_ref(), &[1, 2, 3, 4]);
+        list.truncate(NonZeroUsize::new(2).unwrap());
+        assert_eq!(list.as_ref(), &[1, 2]);
+        list.truncate(NonZeroUsize::MIN);
+        assert_eq!(list.as_ref(), &[1]);
+        assert_eq!(list.capacity(), 4);
+
+        list.push(5);
+        assert_eq!(list.as_ref(), &[1, 5]);
+    }
+
+    #[test]
+    fn test_truncate_drops_the_rest() {
+        let drop_count = Rc::new(RefCell::new(0));
+        let mut list =
+            NonEmptyList::try_from_iter((0..5).map(|_| DropNotifier(drop_count.clone()))).unwrap();
+
+        list.truncate(NonZeroUsize::new(5).unwrap());
+        assert_eq!(*drop_count.borrow(), 0);
+        list.truncate(NonZeroUsize::new(3).unwrap());
+        assert_eq!(*drop_count.borrow(), 2);
+        list.truncate(NonZeroUsize::MIN);
+        assert_eq!(*drop_count.borrow(), 4);
+
+        drop(list);
+        assert_eq!(*drop_count.borrow(), 5);
+    }
+
+    #[test]
+    fn test_truncate_with_zst() {
+        let mut list = NonEmptyList::new(vec![ZST, ZST, ZST]).unwrap();
+
+        list.truncate(NonZeroUsize::new(2).unwrap());
+        assert_eq!(list.len(), 2);
+        list.truncate(NonZeroUsize::MIN);
+        assert_eq!(list.as_ref(), &[ZST]);
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut list = NonEmptyList::single_element(2);
+        assert_eq!(list.capacity(), 1);
+
+        list.insert(0, 0);
+        assert_eq!(list.as_ref(), &[0, 2]);
+        list.insert(1, 1);
+        assert_eq!(list.as_ref(), &[0, 1, 2]);
+        list.insert(3, 3);
+        assert_eq!(list.as_ref(), &[0, 1, 2, 3]);
+        assert!(list.capacity() >= 4);
+
+        for i in 4..100 {
+            list.insert(i / 2, i);
+        }
+        assert_eq!(list.len(), 100);
+        assert_eq!(list[0], 0);
+        assert_eq!(*list.last(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "tried to insert index out of bounds")]
+    fn test_insert_out_of_bounds() {
+        let mut list = NonEmptyList::new(vec![1, 2]).unwrap();
+
+        list.insert(3, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "tried to insert index out of bounds")]
+    fn test_insert_out_of_bounds_with_zst() {
+        let mut list = NonEmptyList::single_element(ZST);
+
+        list.insert(2, ZST);
+    }
+
+    #[test]
+    fn test_insert_with_zst() {
+        let mut list = NonEmptyList::single_element(ZST);
+
+        list.insert(0, ZST);
+        list.insert(2, ZST);
+        list.insert(1, ZST);
+        assert_eq!(list.len(), 4);
+        assert_eq!(list.as_ref(), &[ZST, ZST, ZST, ZST]);
+    }
+
+    #[test]
+    fn test_insert_keeps_everything_owned() {
+        let drop_count = Rc::new(RefCell::new(0));
+        let mut list = NonEmptyList::single_element(Rc::new(1));
+
+        list.insert(0, Rc::new(0));
+        list.insert(2, Rc::new(2));
+        list.insert(1, Rc::new(10));
+        assert_eq!(
+            list.iter().map(|x| **x).collect::<Vec<_>>(),
+            vec![0, 10, 1, 2]
+        );
+        assert!(list.iter().all(|x| Rc::strong_count(x) == 1));
+
+        let mut notifiers = NonEmptyList::single_element(DropNotifier(drop_count.clone()));
+        for i in 0..10 {
+            notifiers.insert(i % 2, DropNotifier(drop_count.clone()));
+        }
+        assert_eq!(*drop_count.borrow(), 0);
+        drop(notifiers);
+        assert_eq!(*drop_count.borrow(), 11);
+    }
 }
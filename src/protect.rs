@@ -1,15 +1,28 @@
 use crate::{
-    non_empty_list::NonEmptyList, protect::auth_storer::AuthStorer, s3::get_bucket, Realm,
+    audit::{record_now, AuditAction},
+    config::{AuthConfig, BucketConfig},
+    non_empty_list::NonEmptyList,
+    protect::auth_storer::{AccessRule, PasswordPolicy, RealmRule, RealmSummary},
+    Realm,
 };
 use comfy_table::Table;
-use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, Input, MultiSelect, Password, Select};
+use dialoguer::{
+    theme::{ColorfulTheme, Theme},
+    Confirm, FuzzySelect, Input, MultiSelect, Password, Select,
+};
+use uuid::Uuid;
 
 pub mod auth;
 pub mod auth_storer;
+pub mod backoff;
+pub mod rekey;
+
+pub use auth_storer::AuthStorer;
 
-pub async fn protect() -> color_eyre::Result<()> {
-    let bucket = get_bucket();
-    let (mut existing_auth, _) = AuthStorer::new(&bucket).await?;
+pub async fn protect(config: &BucketConfig, auth: &AuthConfig) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let (mut existing_auth, _) = AuthStorer::new(&bucket, &auth.key).await?;
+    let policy = auth.password_policy;
 
     let theme = ColorfulTheme::default();
     let choice = FuzzySelect::with_theme(&theme)
@@ -21,7 +34,10 @@ pub async fn protect() -> color_eyre::Result<()> {
             "Remove Existing User",
             "Add New User",
             "Add New Realm",
-            "Set Users with access to Realm",
+            "Change who can access a Realm",
+            "Change User's Password",
+            "Add App Password",
+            "Revoke App Password",
         ])
         .interact()?;
 
@@ -29,48 +45,63 @@ pub async fn protect() -> color_eyre::Result<()> {
         0 => {
             let mut table = Table::new();
             table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
-            table.set_header(vec!["Pattern", "Usernames"]);
+            table.set_header(vec!["Pattern", "Name", "Priority", "Access"]);
 
-            for (pat, usernames) in existing_auth.get_patterns_and_usernames() {
-                table.add_row(vec![format!("{pat:?}"), usernames.join(", ")]);
+            for summary in existing_auth.get_realm_summaries() {
+                table.add_row(vec![
+                    format!("{:?}", summary.realm),
+                    summary
+                        .display_name
+                        .clone()
+                        .unwrap_or_else(|| summary.realm.default_display_name()),
+                    summary.priority.to_string(),
+                    access_description(&summary),
+                ]);
             }
 
             println!("{table}");
         }
         1 => {
-            let mut patterns_and_usernames = existing_auth.get_patterns_and_usernames();
-            if patterns_and_usernames.is_empty() {
+            let mut summaries = existing_auth.get_realm_summaries();
+            if summaries.is_empty() {
                 println!("No realms in place.");
                 return Ok(());
             }
 
-            let items: Vec<String> = patterns_and_usernames
-                .clone()
-                .into_iter()
-                .map(|(pattern, username)| format!("{pattern:?}: {}", username.join(", ")))
+            let items: Vec<String> = summaries
+                .iter()
+                .map(|summary| format!("{:?}: {}", summary.realm, access_description(summary)))
                 .collect();
             let choice = FuzzySelect::with_theme(&theme)
                 .with_prompt("Which realm to remove?")
                 .items(&items)
                 .interact()?;
 
-            let pattern_to_remove = patterns_and_usernames.swap_remove(choice).0;
+            let pattern_to_remove = summaries.swap_remove(choice).realm;
 
             if Confirm::with_theme(&theme)
                 .with_prompt(format!("Confirm removal of {pattern_to_remove:?}"))
                 .interact()?
             {
                 existing_auth.rm_realm(&pattern_to_remove);
-                existing_auth.save(&bucket).await?;
+                existing_auth.save(&bucket, &auth.key).await?;
+                record_now(
+                    &bucket,
+                    vec![AuditAction::RealmRemoved {
+                        realm: pattern_to_remove.to_string(),
+                    }],
+                )
+                .await?;
             }
         }
         2 => {
             let mut table = Table::new();
             table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
-            table.set_header(vec!["UUID", "Username"]);
+            table.set_header(vec!["UUID", "Username", "Credentials"]);
 
             for (uuid, username) in existing_auth.get_users() {
-                table.add_row(vec![uuid.to_string(), username]);
+                let labels = existing_auth.get_credential_labels(&uuid).join(", ");
+                table.add_row(vec![uuid.to_string(), username, labels]);
             }
 
             println!("{table}");
@@ -99,21 +130,32 @@ pub async fn protect() -> color_eyre::Result<()> {
                 .interact()?
             {
                 existing_auth.rm_user(&uuid);
-                existing_auth.save(&bucket).await?;
+                existing_auth.save(&bucket, &auth.key).await?;
+                record_now(&bucket, vec![AuditAction::UserRemoved { username }]).await?;
             }
         }
         4 => {
             let username: String = Input::with_theme(&theme)
                 .with_prompt("Username?")
+                .validate_with(|x: &String| {
+                    if existing_auth.username_taken(x) {
+                        Err("there's already a user with that name")
+                    } else {
+                        Ok(())
+                    }
+                })
                 .interact()?;
-            let password: String = Password::with_theme(&theme)
-                .with_prompt("Password?")
-                .with_confirmation("Confirm Password?", "Passwords didn't match.")
-                .interact()?;
+            let password = password_from_stdin(&theme, &policy)?;
 
-            let uuid = existing_auth.add_user(username.clone(), password)?;
+            let uuid = existing_auth.add_user(username.clone(), &password, &policy)?;
 
-            let realms = existing_auth.get_all_realms();
+            //public realms don't have users to add to
+            let realms: Vec<Realm> = existing_auth
+                .get_realm_summaries()
+                .into_iter()
+                .filter(|summary| summary.usernames.is_some())
+                .map(|summary| summary.realm)
+                .collect();
             let should_have_access_to = if !realms.is_empty() {
                 MultiSelect::with_theme(&theme)
                     .with_prompt(format!("Which realms should {username:?} have access to?"))
@@ -123,48 +165,31 @@ pub async fn protect() -> color_eyre::Result<()> {
                 vec![]
             };
 
+            let mut audit_actions = vec![AuditAction::UserAdded {
+                username: username.clone(),
+            }];
             for i in should_have_access_to {
                 let pat = realms[i].clone();
-                existing_auth.protect_additional(pat, NonEmptyList::single_element(uuid));
+                existing_auth.protect_additional(pat.clone(), NonEmptyList::single_element(uuid));
+                audit_actions.push(realm_audit_action(&existing_auth, &pat));
             }
 
-            existing_auth.save(&bucket).await?;
+            existing_auth.save(&bucket, &auth.key).await?;
+            record_now(&bucket, audit_actions).await?;
         }
         5 => {
             let pat = Realm::get_from_stdin(&theme)?;
 
-            let uuids = {
-                let users = existing_auth.get_users();
-                if users.is_empty() {
-                    vec![]
-                } else {
-                    MultiSelect::with_theme(&theme)
-                        .with_prompt("Which users should have access to this? NB: No users selected will mean anyone can access this page")
-                        .items(&users.iter().map(|(_, un)| un).collect::<Vec<_>>())
-                        .interact()?
-                        .into_iter()
-                        .flat_map(|x| users.get(x).map(|(uuid, _)| uuid))
-                        .copied()
-                        .collect()
-                }
-            };
-
-            match NonEmptyList::new(uuids) {
-                None => {
-                    existing_auth.remove_protection(pat);
-                }
-                Some(uuids) => {
-                    existing_auth.protect(pat, uuids);
-                }
-            }
+            set_realm_rule_from_stdin(&theme, &mut existing_auth, pat.clone())?;
 
-            existing_auth.save(&bucket).await?;
+            existing_auth.save(&bucket, &auth.key).await?;
+            record_now(&bucket, vec![realm_audit_action(&existing_auth, &pat)]).await?;
         }
         6 => {
             let mut patterns: Vec<Realm> = existing_auth
-                .get_patterns_and_usernames()
+                .get_realm_summaries()
                 .into_iter()
-                .map(|(pat, _)| pat)
+                .map(|summary| summary.realm)
                 .collect();
             if patterns.is_empty() {
                 println!("No existing realms.");
@@ -178,42 +203,255 @@ pub async fn protect() -> color_eyre::Result<()> {
 
             let pat = patterns.swap_remove(pat);
 
-            let uuids = {
-                let users = existing_auth.get_users();
-                if users.is_empty() {
-                    vec![]
-                } else {
-                    let currently_has_access = existing_auth.get_users_with_access_to_realm(&pat);
-                    let highlighted: Vec<bool> = users
-                        .iter()
-                        .map(|(uuid, _)| currently_has_access.contains(uuid))
-                        .collect();
-
-                    MultiSelect::with_theme(&theme)
-                        .with_prompt("Which users should have access to this? NB: No users will mean anyone can access this.")
-                        .items(&users.iter().map(|(_, un)| un).collect::<Vec<_>>())
-                        .defaults(&highlighted)
-                        .interact()?
-                        .into_iter()
-                        .flat_map(|x| users.get(x).map(|(uuid, _)| uuid))
-                        .copied()
-                        .collect()
-                }
+            set_realm_rule_from_stdin(&theme, &mut existing_auth, pat.clone())?;
+
+            existing_auth.save(&bucket, &auth.key).await?;
+            record_now(&bucket, vec![realm_audit_action(&existing_auth, &pat)]).await?;
+        }
+        7 => {
+            let mut uuids_and_users = existing_auth.get_users();
+            if uuids_and_users.is_empty() {
+                println!("No users yet.");
+                return Ok(());
+            }
+
+            let items: Vec<String> = uuids_and_users
+                .iter()
+                .map(|(_, username)| username.clone())
+                .collect();
+            let choice = FuzzySelect::with_theme(&theme)
+                .with_prompt("Whose password?")
+                .items(&items)
+                .interact()?;
+
+            let (uuid, username) = uuids_and_users.swap_remove(choice);
+            let password = password_from_stdin(&theme, &policy)?;
+
+            existing_auth.change_password(&uuid, &password, &policy)?;
+            existing_auth.save(&bucket, &auth.key).await?;
+            record_now(&bucket, vec![AuditAction::PasswordChanged { username }]).await?;
+        }
+        8 => {
+            let Some((uuid, username)) =
+                user_from_stdin(&theme, &existing_auth, "Whose app password?")?
+            else {
+                println!("No users yet.");
+                return Ok(());
+            };
+
+            let labels = existing_auth.get_credential_labels(&uuid);
+            let label: String = Input::with_theme(&theme)
+                .with_prompt("What's it for? eg. \"phone\"")
+                .validate_with(|x: &String| {
+                    if labels.contains(x) {
+                        Err("there's already a credential with that label")
+                    } else {
+                        Ok(())
+                    }
+                })
+                .interact()?;
+            let password = password_from_stdin(&theme, &policy)?;
+
+            existing_auth.add_credential(&uuid, label.clone(), &password, &policy)?;
+            existing_auth.save(&bucket, &auth.key).await?;
+            record_now(
+                &bucket,
+                vec![AuditAction::CredentialAdded { username, label }],
+            )
+            .await?;
+        }
+        9 => {
+            let Some((uuid, username)) =
+                user_from_stdin(&theme, &existing_auth, "Whose app password?")?
+            else {
+                println!("No users yet.");
+                return Ok(());
             };
 
-            match NonEmptyList::new(uuids) {
-                None => {
-                    existing_auth.remove_protection(pat);
-                }
-                Some(uuids) => {
-                    existing_auth.protect(pat, uuids);
-                }
+            let mut labels = existing_auth.get_credential_labels(&uuid);
+            if labels.len() < 2 {
+                println!("{username:?} only has one way to log in - remove the user instead.");
+                return Ok(());
             }
+            let choice = FuzzySelect::with_theme(&theme)
+                .with_prompt("Which one to revoke?")
+                .items(&labels)
+                .interact()?;
+            let label = labels.swap_remove(choice);
 
-            existing_auth.save(&bucket).await?;
+            if Confirm::with_theme(&theme)
+                .with_prompt(format!("Confirm revoking {label:?} for {username}"))
+                .interact()?
+            {
+                existing_auth.rm_credential(&uuid, &label)?;
+                existing_auth.save(&bucket, &auth.key).await?;
+                record_now(
+                    &bucket,
+                    vec![AuditAction::CredentialRemoved { username, label }],
+                )
+                .await?;
+            }
         }
         _ => unreachable!(),
     }
 
     Ok(())
 }
+
+///`None` if there aren't any users to pick from
+fn user_from_stdin(
+    theme: &dyn Theme,
+    auth: &AuthStorer,
+    prompt: &str,
+) -> color_eyre::Result<Option<(Uuid, String)>> {
+    let mut uuids_and_users = auth.get_users();
+    if uuids_and_users.is_empty() {
+        return Ok(None);
+    }
+
+    let items: Vec<&String> = uuids_and_users
+        .iter()
+        .map(|(_, username)| username)
+        .collect();
+    let choice = FuzzySelect::with_theme(theme)
+        .with_prompt(prompt)
+        .items(&items)
+        .interact()?;
+
+    Ok(Some(uuids_and_users.swap_remove(choice)))
+}
+
+fn access_description(summary: &RealmSummary) -> String {
+    match &summary.usernames {
+        Some(usernames) => usernames.join(", "),
+        None => "Anyone (public)".to_string(),
+    }
+}
+
+///asks for the priority & who can get in, keeping what's there as the defaults
+fn set_realm_rule_from_stdin(
+    theme: &dyn Theme,
+    auth: &mut AuthStorer,
+    pat: Realm,
+) -> color_eyre::Result<()> {
+    let existing = auth.get_rule(&pat).cloned();
+
+    let priority: i32 = Input::with_theme(theme)
+        .with_prompt("Priority? Where realms overlap, only the highest priority ones count")
+        .default(existing.as_ref().map_or(0, |x| x.priority))
+        .interact()?;
+
+    let display_name: String = Input::with_theme(theme)
+        .with_prompt(format!(
+            "Name shown when logging in? Leave empty for {:?}",
+            pat.default_display_name()
+        ))
+        .with_initial_text(
+            existing
+                .as_ref()
+                .and_then(|x| x.display_name.clone())
+                .unwrap_or_default(),
+        )
+        .allow_empty(true)
+        .validate_with(|x: &String| {
+            if x.chars().any(char::is_control) {
+                Err("can't have control characters")
+            } else {
+                Ok(())
+            }
+        })
+        .interact()?;
+    let display_name = Some(display_name.trim().to_string()).filter(|x| !x.is_empty());
+
+    let is_public = matches!(
+        existing.as_ref().map(|x| &x.access),
+        Some(AccessRule::Public)
+    );
+    let public = Select::with_theme(theme)
+        .with_prompt("Who should have access to this?")
+        .items(&["Only some users", "Anyone (public)"])
+        .default(usize::from(is_public))
+        .interact()?
+        == 1;
+    if public {
+        auth.set_rule(
+            pat,
+            RealmRule {
+                priority,
+                access: AccessRule::Public,
+                display_name,
+            },
+        );
+        return Ok(());
+    }
+
+    let uuids = {
+        let users = auth.get_users();
+        if users.is_empty() {
+            vec![]
+        } else {
+            let currently_has_access = auth.get_users_with_access_to_realm(&pat);
+            let highlighted: Vec<bool> = users
+                .iter()
+                .map(|(uuid, _)| currently_has_access.contains(uuid))
+                .collect();
+
+            MultiSelect::with_theme(theme)
+                .with_prompt("Which users should have access to this? NB: No users will remove this realm, so it's up to any others that match.")
+                .items(&users.iter().map(|(_, un)| un).collect::<Vec<_>>())
+                .defaults(&highlighted)
+                .interact()?
+                .into_iter()
+                .flat_map(|x| users.get(x).map(|(uuid, _)| uuid))
+                .copied()
+                .collect()
+        }
+    };
+
+    match NonEmptyList::new(uuids) {
+        None => {
+            auth.remove_protection(pat);
+        }
+        Some(uuids) => {
+            auth.set_rule(
+                pat,
+                RealmRule {
+                    priority,
+                    access: AccessRule::Users(uuids),
+                    display_name,
+                },
+            );
+        }
+    }
+
+    Ok(())
+}
+
+///what happened to `realm`, going by who can get at it now
+fn realm_audit_action(auth: &AuthStorer, realm: &Realm) -> AuditAction {
+    match auth
+        .get_realm_summaries()
+        .into_iter()
+        .find(|summary| &summary.realm == realm)
+        .map(|summary| summary.usernames)
+    {
+        Some(Some(usernames)) => AuditAction::RealmProtected {
+            realm: realm.to_string(),
+            usernames,
+        },
+        Some(None) => AuditAction::RealmMadePublic {
+            realm: realm.to_string(),
+        },
+        None => AuditAction::RealmRemoved {
+            realm: realm.to_string(),
+        },
+    }
+}
+
+fn password_from_stdin(theme: &dyn Theme, policy: &PasswordPolicy) -> color_eyre::Result<String> {
+    Ok(Password::with_theme(theme)
+        .with_prompt("Password?")
+        .with_confirmation("Confirm Password?", "Passwords didn't match.")
+        .validate_with(|x: &String| policy.check(x).map_err(|e| e.to_string()))
+        .interact()?)
+}
@@ -1,30 +1,36 @@
 use crate::{
-    hash_raw_bytes, non_empty_list::NonEmptyList, protect::auth_storer::AuthStorer,
-    s3::get_bytes_or_default, serve::empty_with_code, Realm,
+    audit::{AuditAction, AuditLog},
+    non_empty_list::NonEmptyList,
+    protect::{
+        auth_storer::{AuthKey, AuthStorer, PasswordPolicy, RealmSummary, StoredCredential},
+        backoff::{AuthBackoff, BackoffConfig},
+    },
+    s3::{get_if_changed, Changed, ConfigHealth, LastFetched, StaleConfig},
+    serve::{empty_with_code, empty_with_headers, journal::MatchedRealm, ServeBody},
+    store::{ObjectStore, StoreFailure},
+    Realm,
 };
 use argon2::{
     password_hash::{Error, SaltString},
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
 use base64::{prelude::BASE64_STANDARD, Engine};
-use color_eyre::eyre::bail;
+use color_eyre::eyre::{bail, eyre};
 use getrandom::getrandom;
 use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
-use http_body_util::Full;
-use hyper::{
-    body::{Bytes, Incoming},
-    http, Request, Response, StatusCode,
-};
-use s3::Bucket;
+use hyper::{body::Incoming, header, http, Request, Response, StatusCode};
 use std::{
-    net::{IpAddr, SocketAddr},
+    net::IpAddr,
     num::NonZeroU32,
     sync::{Arc, LazyLock},
+    time::Duration,
 };
 use tokio::sync::{Mutex, RwLock};
 use uuid::Uuid;
 
 pub const AUTH_DATA_LOCATION: &str = "authdata";
+///lets a client say which of a user's credentials it's using, so only that one gets checked
+pub const CREDENTIAL_HINT_HEADER: &str = "x-shove-credential";
 static FAKE_PASSWORD: LazyLock<String> = LazyLock::new(|| {
     const FAKE_PASSWORD_ACTUAL: &str = "thisismyfakepasswordtoreducesidechannelattackswhereyoumightbeabletoworkoutwhetheryourusernamewasanactualusernameforthisrealm";
     let mut salt = [0; 32];
@@ -41,18 +47,22 @@ static FAKE_PASSWORD: LazyLock<String> = LazyLock::new(|| {
 #[derive(Clone)]
 pub struct AuthChecker {
     auth: Arc<RwLock<AuthStorer>>,
-    last_hash: Arc<Mutex<Vec<u8>>>,
+    key: AuthKey,
+    last_fetched: Arc<Mutex<LastFetched>>,
+    config_health: ConfigHealth,
     rate_limiter: Arc<DefaultKeyedRateLimiter<IpAddr>>,
+    backoff: AuthBackoff,
+    audit: Option<AuditLog>,
 }
 
 pub enum AuthReturn {
     AuthConfirmed(Request<Incoming>),
-    ResponseFromAuth(Response<Full<Bytes>>),
+    ResponseFromAuth(Response<ServeBody>),
     Error(http::Error),
 }
 
-impl From<Result<Response<Full<Bytes>>, http::Error>> for AuthReturn {
-    fn from(value: Result<Response<Full<Bytes>>, http::Error>) -> Self {
+impl From<Result<Response<ServeBody>, http::Error>> for AuthReturn {
+    fn from(value: Result<Response<ServeBody>, http::Error>) -> Self {
         match value {
             Ok(x) => Self::ResponseFromAuth(x),
             Err(e) => Self::Error(e),
@@ -61,52 +71,98 @@ impl From<Result<Response<Full<Bytes>>, http::Error>> for AuthReturn {
 }
 
 impl AuthChecker {
-    pub async fn new(bucket: &Bucket) -> color_eyre::Result<Self> {
-        let (auth_storer, raw_bytes) = AuthStorer::new(bucket).await?;
-        let hashed_bytes = hash_raw_bytes(&raw_bytes);
+    pub async fn new(
+        bucket: &dyn ObjectStore,
+        key: AuthKey,
+        backoff: BackoffConfig,
+        audit: Option<AuditLog>,
+    ) -> color_eyre::Result<Self> {
+        let (auth_storer, raw_bytes) = AuthStorer::new(bucket, &key).await?;
 
         let rate_limiter = Arc::new(RateLimiter::keyed(Quota::per_minute(
             NonZeroU32::new(10).unwrap(),
         )));
-        
+
         tokio::task::spawn_blocking(|| {
             LazyLock::force(&FAKE_PASSWORD);
         });
 
         Ok(Self {
             auth: Arc::new(RwLock::new(auth_storer)),
-            last_hash: Arc::new(Mutex::new(hashed_bytes)),
+            key,
+            last_fetched: Arc::new(Mutex::new(LastFetched::new(&raw_bytes))),
+            config_health: ConfigHealth::default(),
             rate_limiter,
+            backoff: AuthBackoff::new(backoff),
+            audit,
         })
     }
 
-    pub async fn check_and_reload(&self, bucket: &Bucket) -> color_eyre::Result<()> {
-        let Ok(mut last_hash) = self.last_hash.try_lock() else {
+    ///whether anything changed. new auth data that can't be read is never swapped in, so whatever
+    ///was protected stays protected - only it not being there at all means there's nothing to protect
+    pub async fn check_and_reload(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<bool> {
+        let Ok(mut last_fetched) = self.last_fetched.try_lock() else {
             bail!("already reloading auth")
         };
 
-        let current_enc_bytes = get_bytes_or_default(bucket, AUTH_DATA_LOCATION).await?;
-        let hashed = hash_raw_bytes(&current_enc_bytes);
+        //whatever's loaded is kept if the store can't be read, rather than dropping all protection
+        let fetched = get_if_changed(bucket, AUTH_DATA_LOCATION, &mut last_fetched)
+            .await
+            .map_err(|e| {
+                let failure = StoreFailure::of(&e);
+                color_eyre::Report::from(e).wrap_err(format!(
+                    "auth data is {failure}, keeping the current version"
+                ))
+            })?;
+        let new_version = match fetched {
+            None => return Ok(false),
+            Some(Changed::Missing) => Ok(AuthStorer::default()),
+            Some(Changed::Found(enc_bytes)) if enc_bytes.is_empty() => {
+                Err(eyre!("auth data is there, but empty"))
+            }
+            Some(Changed::Found(enc_bytes)) => {
+                AuthStorer::construct_from_enc_bytes(&enc_bytes, &self.key).map_err(Into::into)
+            }
+        };
 
-        if *last_hash == hashed {
-            return Ok(());
+        match new_version {
+            Ok(new_version) => {
+                *self.auth.write().await = new_version;
+                self.config_health.applied();
+                Ok(true)
+            }
+            Err(e) => {
+                self.config_health.rejected(AUTH_DATA_LOCATION, &e);
+                Err(e.wrap_err("unable to read new auth data, keeping the current version"))
+            }
         }
+    }
 
-        *last_hash = hashed;
+    ///why the auth data in the bucket isn't what's being used, if it isn't
+    pub fn stale(&self) -> Option<StaleConfig> {
+        self.config_health.stale()
+    }
 
-        let new_version = AuthStorer::construct_from_enc_bytes(&current_enc_bytes)?;
-        *self.auth.write().await = new_version;
+    ///whichever of `paths` anyone can see without logging in
+    pub async fn public_paths(&self, paths: Vec<String>) -> Vec<String> {
+        let auth = self.auth.read().await;
+        paths
+            .into_iter()
+            .filter(|path| auth.find_users_with_access(path).is_none())
+            .collect()
+    }
 
-        Ok(())
+    pub async fn protected_prefixes(&self) -> Vec<String> {
+        self.auth.read().await.protected_prefixes()
     }
 
     //technically unused, but maybe?
-    pub async fn save_to_s3(&self, bucket: &Bucket) -> color_eyre::Result<()> {
-        self.auth.read().await.save(bucket).await
+    pub async fn save_to_s3(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<()> {
+        Ok(self.auth.write().await.save(bucket, &self.key).await?)
     }
 
-    pub async fn get_patterns_and_usernames(&self) -> Vec<(Realm, Vec<String>)> {
-        self.auth.read().await.get_patterns_and_usernames()
+    pub async fn get_realm_summaries(&self) -> Vec<RealmSummary> {
+        self.auth.read().await.get_realm_summaries()
     }
 
     pub async fn get_users(&self) -> Vec<(Uuid, String)> {
@@ -128,9 +184,14 @@ impl AuthChecker {
     pub async fn add_user(
         &self,
         username: String,
-        password: impl AsRef<[u8]>,
+        password: &str,
+        policy: &PasswordPolicy,
     ) -> color_eyre::Result<Uuid> {
-        self.auth.write().await.add_user(username, password)
+        Ok(self
+            .auth
+            .write()
+            .await
+            .add_user(username, password, policy)?)
     }
 
     pub async fn protect(&self, pattern: Realm, uuids: NonEmptyList<Uuid>) {
@@ -145,30 +206,57 @@ impl AuthChecker {
         self.auth.read().await.get_users_with_access_to_realm(pat)
     }
 
+    fn record_login(&self, username: &str, realm: &str, ip: IpAddr, success: bool) {
+        if let Some(audit) = &self.audit {
+            audit.record(AuditAction::Login {
+                username: username.to_string(),
+                realm: realm.to_string(),
+                ip,
+                success,
+            });
+        }
+    }
+
     pub async fn check_auth(
         &self,
         path: &str,
         req: Request<Incoming>,
-        remote_addr: SocketAddr,
+        client_ip: IpAddr,
     ) -> AuthReturn {
+        let (realms, challenge_name, users) = {
+            let auth = self.auth.read().await;
+            let Some((realms, users)) = auth.find_users_with_access(path) else {
+                return AuthReturn::AuthConfirmed(req);
+            };
+            let challenge_name = auth.challenge_name(&realms);
+            (realms, challenge_name, users)
+        };
         //closure so it isn't generated for the happy paths
-        let failed_auth_rsp = || Response::builder()
-            .header(
-                "WWW-Authenticate",
-                format!("Basic realm=\"{path:?}\" charset=\"UTF-8\""),
+        let failed_auth_rsp = || {
+            empty_with_headers(
+                StatusCode::UNAUTHORIZED,
+                [(
+                    header::WWW_AUTHENTICATE,
+                    format!("Basic realm={challenge_name:?}, charset=\"UTF-8\"").as_str(),
+                )],
             )
-            .status(StatusCode::UNAUTHORIZED)
-            .body(Full::default())
-            .into();
-
-        let Some(users) = self.auth.read().await.find_users_with_access(path) else {
-            return AuthReturn::AuthConfirmed(req);
+            .into()
         };
-
-        let ip = remote_addr.ip();
-        if self.rate_limiter.check_key(&ip).is_err() {
+        let realm = realms
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" & ");
+
+        //keyed by the real client, otherwise everyone behind the same proxy shares one limit
+        if self.rate_limiter.check_key(&client_ip).is_err() {
+            warn!(?client_ip, "Rate limited auth attempt");
             return empty_with_code(StatusCode::TOO_MANY_REQUESTS).into();
         }
+        if let Err(retry_after) = self.backoff.check_ip(client_ip) {
+            debug!(?client_ip, ?retry_after, "Auth attempt during backoff");
+            return too_many_attempts(retry_after);
+        }
 
         let headers = req.headers();
         let provided_auth_b64 = match headers.get("Authorization").cloned() {
@@ -213,43 +301,201 @@ impl AuthChecker {
         let (provided_username, provided_password) = decoded.split_at(colon_index);
         let provided_password = &provided_password[1..];
 
-        let Some(stored_key) = users.get(provided_username) else {
-            debug!("Usernames didn't match for auth");
-            let fake_password_hash = match PasswordHash::new(&FAKE_PASSWORD) {
-                Ok(x) => x,
-                Err(e) => {
-                    error!(?e, "Unable to decode stored fake password");
-                    return empty_with_code(StatusCode::INTERNAL_SERVER_ERROR).into();
-                }
-            };
-            let _ = Argon2::default()
-                .verify_password(provided_password.as_bytes(), &fake_password_hash);
-            return failed_auth_rsp();
+        if let Err(retry_after) = self.backoff.check_username(provided_username) {
+            debug!(?retry_after, "Auth attempt for username during backoff");
+            return too_many_attempts(retry_after);
+        }
+
+        //an unknown user still gets the fake password checked, so it takes as long as a wrong one
+        let credentials = match users.get(provided_username) {
+            Some(credentials) => credentials.as_slice(),
+            None => {
+                debug!("Usernames didn't match for auth");
+                &[]
+            }
         };
 
-        let password_hash = match PasswordHash::new(stored_key) {
+        let hint = req
+            .headers()
+            .get(CREDENTIAL_HINT_HEADER)
+            .and_then(|x| x.to_str().ok());
+        let matched_label = match verify_credentials(credentials, hint, provided_password) {
             Ok(x) => x,
             Err(e) => {
-                error!(?e, "Unable to decode stored password key");
+                error!(?e, "Error verifying password");
                 return empty_with_code(StatusCode::INTERNAL_SERVER_ERROR).into();
             }
         };
 
-        let password_matches =
-            match Argon2::default().verify_password(provided_password.as_bytes(), &password_hash) {
-                Ok(()) => true,
-                Err(Error::Password) => false,
-                Err(e) => {
-                    debug!(?e, "Error verifying password");
-                    return empty_with_code(StatusCode::INTERNAL_SERVER_ERROR).into();
-                }
-            };
-
-        if password_matches {
+        if let Some(label) = matched_label {
+            debug!(?label, "Credential matched for auth");
+            self.backoff.record_success(client_ip, provided_username);
+            self.record_login(provided_username, &realm, client_ip, true);
+            let mut req = req;
+            req.extensions_mut().insert(MatchedRealm(realm));
             AuthReturn::AuthConfirmed(req)
         } else {
             debug!("Passwords didn't match for auth");
+            self.backoff.record_failure(client_ip, provided_username);
+            self.record_login(provided_username, &realm, client_ip, false);
             failed_auth_rsp()
         }
     }
 }
+
+///which of `credentials` the password is for, if any. with a hint only the credential with that label
+///is tried, otherwise each gets its own argon2 run until one matches
+fn verify_credentials<'a>(
+    credentials: &'a [StoredCredential],
+    hint: Option<&str>,
+    provided_password: &str,
+) -> Result<Option<&'a str>, Error> {
+    matching_credential(credentials, hint, |stored_key| {
+        let password_hash = PasswordHash::new(stored_key)?;
+        match Argon2::default().verify_password(provided_password.as_bytes(), &password_hash) {
+            Ok(()) => Ok(true),
+            Err(Error::Password) => Ok(false),
+            Err(e) => Err(e),
+        }
+    })
+}
+
+///`verify`s each credential that could match. if none could (an unknown user, or a hint that isn't
+///any of theirs) the fake password gets verified instead, so how long it takes doesn't say which
+fn matching_credential<'a>(
+    credentials: &'a [StoredCredential],
+    hint: Option<&str>,
+    mut verify: impl FnMut(&str) -> Result<bool, Error>,
+) -> Result<Option<&'a str>, Error> {
+    let mut verified_any = false;
+    for credential in credentials
+        .iter()
+        .filter(|x| hint.is_none_or(|hint| x.label == hint))
+    {
+        verified_any = true;
+        if verify(&credential.stored_key)? {
+            return Ok(Some(&credential.label));
+        }
+    }
+
+    if !verified_any {
+        verify(&FAKE_PASSWORD)?;
+    }
+    Ok(None)
+}
+
+///a 429 saying how long until it's worth trying again
+fn too_many_attempts(retry_after: Duration) -> AuthReturn {
+    //round up so clients don't come back just too early
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    empty_with_headers(
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.to_string().as_str())],
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{envelope, store::memory::InMemoryBucket};
+
+    async fn protected_bucket(key: &AuthKey) -> (InMemoryBucket, Vec<u8>) {
+        let bucket = InMemoryBucket::new();
+        let mut auth = AuthStorer::default();
+        let uuid = auth
+            .add_user("jack".to_string(), "hunter22", &PasswordPolicy::default())
+            .unwrap();
+        auth.protect(
+            Realm::StartsWith("/private".to_string()),
+            NonEmptyList::single_element(uuid),
+        );
+        auth.save(&bucket, key).await.unwrap();
+
+        let saved = get_if_changed(&bucket, AUTH_DATA_LOCATION, &mut LastFetched::default())
+            .await
+            .unwrap();
+        let Some(Changed::Found(saved)) = saved else {
+            panic!("auth data wasn't saved");
+        };
+        (bucket, saved)
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_auth_data_keeps_realms_protected() {
+        let key = AuthKey::derive("hunter2", "bucket");
+        let (bucket, saved) = protected_bucket(&key).await;
+        let checker = AuthChecker::new(&bucket, key.clone(), BackoffConfig::default(), None)
+            .await
+            .unwrap();
+        let protected = vec!["/private".to_string()];
+        assert_eq!(checker.protected_prefixes().await, protected);
+        assert!(checker.stale().is_none());
+
+        let corrupted = envelope::seal(b"{\"version\":", Some(&key)).unwrap();
+        //as if AUTH_ENCRYPTION_KEY were different to what uploaded it
+        let wrong_key =
+            envelope::seal(&saved[5..], Some(&AuthKey::derive("hunter3", "bucket"))).unwrap();
+        let truncated = saved[..saved.len() / 2].to_vec();
+
+        let mut since_ms = None;
+        for broken in [corrupted, wrong_key, truncated, vec![]] {
+            bucket
+                .put(AUTH_DATA_LOCATION, &broken, "application/octet-stream")
+                .await
+                .unwrap();
+            assert!(checker.check_and_reload(&bucket).await.is_err());
+            assert_eq!(checker.protected_prefixes().await, protected);
+
+            let stale = checker.stale().unwrap();
+            assert_eq!(stale.location, AUTH_DATA_LOCATION);
+            //still since the first thing that went wrong
+            assert_eq!(*since_ms.get_or_insert(stale.since_ms), stale.since_ms);
+        }
+
+        bucket
+            .put(AUTH_DATA_LOCATION, &saved, "application/octet-stream")
+            .await
+            .unwrap();
+        assert!(checker.check_and_reload(&bucket).await.unwrap());
+        assert!(checker.stale().is_none());
+
+        //only it being gone means there's nothing to protect
+        bucket.delete(AUTH_DATA_LOCATION).await.unwrap();
+        assert!(checker.check_and_reload(&bucket).await.unwrap());
+        assert!(checker.protected_prefixes().await.is_empty());
+    }
+
+    #[test]
+    fn test_failing_takes_one_verify_whatever_the_reason() {
+        let credential = |label: &str| StoredCredential {
+            label: label.to_string(),
+            stored_key: format!("key for {label}"),
+            created_at_ms: 0,
+        };
+        let credentials = [credential("laptop"), credential("phone")];
+        let verifies = |credentials: &[StoredCredential], hint: Option<&str>| {
+            let mut tried = vec![];
+            let matched = matching_credential(credentials, hint, |stored_key| {
+                tried.push(stored_key.to_string());
+                Ok(false)
+            })
+            .unwrap();
+            assert_eq!(matched, None);
+            tried
+        };
+
+        let fake = vec![FAKE_PASSWORD.clone()];
+        //an unknown user
+        assert_eq!(verifies(&[], None), fake);
+        assert_eq!(verifies(&[], Some("phone")), fake);
+        //a known user, with a hint for none of their credentials
+        assert_eq!(verifies(&credentials, Some("tablet")), fake);
+
+        assert_eq!(verifies(&credentials, Some("phone")), vec!["key for phone"]);
+        assert_eq!(
+            verifies(&credentials, None),
+            vec!["key for laptop", "key for phone"]
+        );
+    }
+}
@@ -1,5 +1,13 @@
 use crate::{
-    non_empty_list::NonEmptyList, protect::auth::AUTH_DATA_LOCATION, s3::get_bytes_or_default,
+    config::EnvReader,
+    envelope::{self, Legacy},
+    error::ShoveError,
+    merge::{merge_map, save_merged, Mergeable},
+    non_empty_list::NonEmptyList,
+    protect::auth::AUTH_DATA_LOCATION,
+    s3::get_bytes_or_default,
+    store::ObjectStore,
+    versioned::{from_versioned, stored_version, to_versioned, Migration, Versioned},
     Realm,
 };
 use aes_gcm::{
@@ -9,66 +17,354 @@ use aes_gcm::{
 use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
 use getrandom::getrandom;
 use hkdf::Hkdf;
-use s3::Bucket;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_slice, to_vec};
 use sha2::Sha256;
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    env::var,
-    sync::LazyLock,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 use uuid::Uuid;
 
-static AUTH_KEY: LazyLock<Key<Aes256Gcm>> = LazyLock::new(|| {
-    let password = var("AUTH_ENCRYPTION_KEY").expect("unable to find env var AUTH_ENCRYPTION_KEY");
-    let salt = var("BUCKET_NAME").expect("unable to find env var BUCKET_NAME");
+///what the auth data is encrypted with
+#[derive(Clone)]
+pub struct AuthKey(Key<Aes256Gcm>);
 
-    let hk = Hkdf::<Sha256>::new(Some(salt.as_bytes()), password.as_bytes());
-    let mut key_output = [0; 32];
-    hk.expand(b"Auth Encryption Key", &mut key_output)
-        .expect("unable to expand key");
+impl std::fmt::Debug for AuthKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AuthKey(..)")
+    }
+}
+
+impl AuthKey {
+    ///from `AUTH_ENCRYPTION_KEY`, salted with the bucket's name
+    pub fn derive(password: &str, bucket_name: &str) -> Self {
+        let hk = Hkdf::<Sha256>::new(Some(bucket_name.as_bytes()), password.as_bytes());
+        let mut key_output = [0; 32];
+        hk.expand(b"Auth Encryption Key", &mut key_output)
+            .expect("unable to expand key");
+
+        Self(Key::<Aes256Gcm>::from_slice(&key_output).to_owned())
+    }
+
+    ///a random nonce, followed by the ciphertext
+    pub fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>, ShoveError> {
+        let mut nonce_data = [0; 12];
+        getrandom(&mut nonce_data).map_err(|e| ShoveError::Crypto(format!("no nonce: {e}")))?;
+        let nonce = Nonce::<Aes256Gcm>::from_slice(&nonce_data);
+
+        let cipher = Aes256Gcm::new(&self.0);
+        let ciphered_data = cipher
+            .encrypt(nonce, plain)
+            .map_err(|_| ShoveError::Crypto("unable to encrypt".to_string()))?;
+
+        let mut encrypted_data = nonce_data.to_vec();
+        encrypted_data.extend(ciphered_data);
+        Ok(encrypted_data)
+    }
+
+    ///the inverse of [`Self::encrypt`]
+    pub fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, ShoveError> {
+        if encrypted.len() < 12 {
+            return Err(ShoveError::Crypto(
+                "too short to have been encrypted".to_string(),
+            ));
+        }
+        let (nonce, ciphered_data) = encrypted.split_at(12);
+        let nonce = Nonce::<Aes256Gcm>::from_slice(nonce);
+        let cipher = Aes256Gcm::new(&self.0);
+        cipher.decrypt(nonce, ciphered_data).map_err(|_| {
+            ShoveError::Crypto("unable to decrypt - is AUTH_ENCRYPTION_KEY right?".to_string())
+        })
+    }
+}
+
+pub const DEFAULT_MIN_PASSWORD_LENGTH: usize = 8;
+///what the password a user is created with is called, alongside any app passwords
+pub const PRIMARY_CREDENTIAL_LABEL: &str = "password";
+///every failed login checks all of a user's credentials, so this keeps that from getting too slow
+pub const MAX_CREDENTIALS: usize = 8;
+
+///what a new password has to look like
+#[derive(Clone, Copy, Debug)]
+pub struct PasswordPolicy {
+    ///in characters, not bytes
+    pub min_length: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: DEFAULT_MIN_PASSWORD_LENGTH,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn read(env: &mut EnvReader) -> Self {
+        Self {
+            min_length: env
+                .parsed("MIN_PASSWORD_LENGTH")
+                .unwrap_or(DEFAULT_MIN_PASSWORD_LENGTH),
+        }
+    }
+
+    pub fn check(&self, password: &str) -> Result<(), ShoveError> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(ShoveError::Invalid(format!(
+                "password must be at least {} characters long, but is only {length}",
+                self.min_length
+            )));
+        }
+        Ok(())
+    }
+}
 
-    Key::<Aes256Gcm>::from_slice(&key_output).to_owned()
-});
+fn hash_password(password: &str) -> Result<String, ShoveError> {
+    let mut salt = [0; 32];
+    getrandom(&mut salt).map_err(|e| ShoveError::Crypto(format!("no salt: {e}")))?;
+    let saltstring = SaltString::encode_b64(&salt)
+        .map_err(|e| ShoveError::Crypto(format!("unable to encode salt: {e}")))?;
 
-#[derive(Serialize, Deserialize, Clone)]
+    let argon2 = Argon2::default();
+    let password_hash = argon2
+        .hash_password(password.as_bytes(), &saltstring)
+        .map_err(|e| ShoveError::Crypto(format!("unable to hash password: {e}")))?;
+    Ok(password_hash.serialize().to_string())
+}
+
+///one password a user can log in with, eg. a per-device app password that can be revoked on its own
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StoredCredential {
+    pub label: String,
+    pub stored_key: String,
+    pub created_at_ms: u64,
+}
+
+impl StoredCredential {
+    fn new(label: String, password: &str) -> Result<Self, ShoveError> {
+        Ok(Self {
+            label,
+            stored_key: hash_password(password)?,
+            created_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |x| x.as_millis() as u64),
+        })
+    }
+}
+
+///every credential for each username
+pub type UserCredentials = HashMap<String, Vec<StoredCredential>>;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(from = "StoredUser")]
 struct UsernameAndPassword {
     pub username: String,
-    pub stored_key: String,
+    pub credentials: Vec<StoredCredential>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredUser {
+    Credentials {
+        username: String,
+        credentials: Vec<StoredCredential>,
+    },
+    ///from before app passwords, when everyone had exactly one
+    Single {
+        username: String,
+        stored_key: String,
+    },
+}
+
+impl From<StoredUser> for UsernameAndPassword {
+    fn from(value: StoredUser) -> Self {
+        match value {
+            StoredUser::Credentials {
+                username,
+                credentials,
+            } => Self {
+                username,
+                credentials,
+            },
+            StoredUser::Single {
+                username,
+                stored_key,
+            } => Self {
+                username,
+                credentials: vec![StoredCredential {
+                    label: PRIMARY_CREDENTIAL_LABEL.to_string(),
+                    stored_key,
+                    created_at_ms: 0,
+                }],
+            },
+        }
+    }
+}
+
+///who can get into a realm
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccessRule {
+    Users(NonEmptyList<Uuid>),
+    ///anyone, even without logging in. useful for carving an exception out of a wider realm
+    Public,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RealmRule {
+    ///when realms overlap, the highest priority ones decide
+    pub priority: i32,
+    pub access: AccessRule,
+    ///what the browser's login prompt calls it, eg. `Staff area`. `None` goes by the pattern
+    pub display_name: Option<String>,
+}
+
+impl RealmRule {
+    pub fn users(uuids: NonEmptyList<Uuid>) -> Self {
+        Self {
+            priority: 0,
+            access: AccessRule::Users(uuids),
+            display_name: None,
+        }
+    }
+}
+
+///a realm as shown in the CLI, with usernames rather than ids
+pub struct RealmSummary {
+    pub realm: Realm,
+    pub priority: i32,
+    pub display_name: Option<String>,
+    ///`None` for public realms
+    pub usernames: Option<Vec<String>>,
 }
 
 #[derive(Clone, Default)]
 pub struct AuthStorer {
-    realms: HashMap<Realm, NonEmptyList<Uuid>>,
+    realms: HashMap<Realm, RealmRule>,
     users: HashMap<Uuid, UsernameAndPassword>,
+    ///what was loaded, so saving can tell our changes from anyone else's. `None` for nothing
+    base: Option<Arc<AuthStorer>>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum StoredAccess {
+    Users(Vec<Uuid>),
+    Public,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredRealmRule {
+    Rule {
+        #[serde(default)]
+        priority: i32,
+        access: StoredAccess,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        display_name: Option<String>,
+    },
+    ///from before priorities, when every realm was just a list of users
+    Users(Vec<Uuid>),
 }
 
 #[derive(Serialize, Deserialize)]
 struct StoredAuthStorer {
-    pub realms: Vec<(Realm, Vec<Uuid>)>,
+    pub realms: Vec<(Realm, StoredRealmRule)>,
     pub users: Vec<(Uuid, UsernameAndPassword)>,
 }
 
+impl Versioned for StoredAuthStorer {
+    const VERSION: u32 = 3;
+    const MIGRATIONS: &'static [Migration] = &[
+        //1 was the same, just not wrapped up with a version. 2 was the same but without display
+        //names, which are a new version so anything older refuses rather than dropping them
+        Ok, Ok,
+    ];
+}
+
 impl From<StoredAuthStorer> for AuthStorer {
     fn from(value: StoredAuthStorer) -> Self {
         Self {
             realms: value
                 .realms
                 .into_iter()
-                .flat_map(|(realm, vec)| NonEmptyList::new(vec).map(|nel| (realm, nel)))
+                .flat_map(|(realm, rule)| {
+                    let (priority, access, display_name) = match rule {
+                        StoredRealmRule::Rule {
+                            priority,
+                            access,
+                            display_name,
+                        } => (priority, access, display_name),
+                        StoredRealmRule::Users(uuids) => (0, StoredAccess::Users(uuids), None),
+                    };
+                    let access = match access {
+                        StoredAccess::Users(uuids) => AccessRule::Users(NonEmptyList::new(uuids)?),
+                        StoredAccess::Public => AccessRule::Public,
+                    };
+                    Some((
+                        realm,
+                        RealmRule {
+                            priority,
+                            access,
+                            display_name,
+                        },
+                    ))
+                })
                 .collect(),
             users: HashMap::from_iter(value.users),
+            base: None,
         }
     }
 }
+
+impl Mergeable for AuthStorer {
+    fn merge(base: &Self, ours: Self, theirs: Self) -> Result<Self, String> {
+        let merged = Self {
+            realms: merge_map(&base.realms, ours.realms, theirs.realms)
+                .map_err(|realm| format!("the realm {realm}"))?,
+            users: merge_map(&base.users, ours.users, theirs.users)
+                .map_err(|uuid| format!("the user {uuid}"))?,
+            base: None,
+        };
+
+        //each side's fine on its own, but they can still clash together
+        let mut usernames = HashSet::new();
+        for user in merged.users.values() {
+            if !usernames.insert(user.username.to_lowercase()) {
+                return Err(format!("the user {:?}", user.username));
+            }
+        }
+        for (realm, rule) in &merged.realms {
+            if let AccessRule::Users(uuids) = &rule.access
+                && uuids.iter().any(|uuid| !merged.users.contains_key(uuid))
+            {
+                return Err(format!("the realm {realm}, which lets in a removed user"));
+            }
+        }
+
+        Ok(merged)
+    }
+}
 impl From<AuthStorer> for StoredAuthStorer {
     fn from(value: AuthStorer) -> Self {
         Self {
             realms: value
                 .realms
                 .into_iter()
-                .map(|(realm, nel)| (realm, nel.into()))
+                .map(|(realm, rule)| {
+                    let access = match rule.access {
+                        AccessRule::Users(uuids) => StoredAccess::Users(uuids.into()),
+                        AccessRule::Public => StoredAccess::Public,
+                    };
+                    (
+                        realm,
+                        StoredRealmRule::Rule {
+                            priority: rule.priority,
+                            access,
+                            display_name: rule.display_name,
+                        },
+                    )
+                })
                 .collect(),
             users: Vec::from_iter(value.users),
         }
@@ -77,67 +373,99 @@ impl From<AuthStorer> for StoredAuthStorer {
 
 impl AuthStorer {
     ///returns raw bytes from S3 as well
-    pub async fn new(bucket: &Bucket) -> color_eyre::Result<(Self, Vec<u8>)> {
+    pub async fn new(
+        bucket: &dyn ObjectStore,
+        key: &AuthKey,
+    ) -> Result<(Self, Vec<u8>), ShoveError> {
         let enc_bytes = get_bytes_or_default(bucket, AUTH_DATA_LOCATION).await?;
-        let obj = Self::construct_from_enc_bytes(&enc_bytes)?;
+        let obj = Self::construct_from_enc_bytes(&enc_bytes, key)?;
 
         Ok((obj, enc_bytes))
     }
 
-    pub(super) fn construct_from_enc_bytes(enc_bytes: &[u8]) -> color_eyre::Result<Self> {
+    pub(super) fn construct_from_enc_bytes(
+        enc_bytes: &[u8],
+        key: &AuthKey,
+    ) -> Result<Self, ShoveError> {
         if enc_bytes.is_empty() {
             return Ok(Self::default());
         }
 
-        let (nonce, ciphered_data) = enc_bytes.split_at(12);
-        let nonce = Nonce::<Aes256Gcm>::from_slice(nonce);
-        let cipher = Aes256Gcm::new(&*AUTH_KEY);
-        let json = cipher.decrypt(nonce, ciphered_data)?;
-
-        let stored: StoredAuthStorer = from_slice(&json)?;
+        let json = envelope::open(enc_bytes, Some(key), Legacy::Encrypted)?;
+        let stored: StoredAuthStorer = from_versioned(&json)?;
 
-        Ok(stored.into())
+        let mut auth: Self = stored.into();
+        auth.base = Some(Arc::new(auth.clone()));
+        Ok(auth)
     }
 
-    pub async fn save(&self, bucket: &Bucket) -> color_eyre::Result<()> {
-        let mut nonce_data = [0; 12];
-        getrandom(&mut nonce_data)?;
-        let nonce = Nonce::<Aes256Gcm>::from_slice(&nonce_data);
-
-        let stored: StoredAuthStorer = self.clone().into();
-        let json = to_vec(&stored)?;
-
-        let cipher = Aes256Gcm::new(&*AUTH_KEY);
-        let ciphered_data = cipher.encrypt(nonce, json.as_slice())?;
-
-        let mut encrypted_data = nonce_data.to_vec();
-        encrypted_data.extend(ciphered_data);
+    ///whether `enc_bytes` are already how they'd be [saved](Self::save), so there's nothing to
+    ///migrate
+    pub fn is_latest(enc_bytes: &[u8], key: &AuthKey) -> Result<bool, ShoveError> {
+        if enc_bytes.is_empty() {
+            return Ok(true);
+        }
+        if envelope::is_encrypted(enc_bytes).is_none() {
+            return Ok(false);
+        }
+        let json = envelope::open(enc_bytes, Some(key), Legacy::Encrypted)?;
+        Ok(stored_version(&json)? == StoredAuthStorer::VERSION)
+    }
 
-        bucket
-            .put_object_with_content_type(
-                AUTH_DATA_LOCATION,
-                &encrypted_data,
-                "application/octet-stream",
-            )
-            .await?;
+    ///merges in anything saved since this was loaded, failing with
+    ///[`ChangedSinceLoaded`](crate::merge::ChangedSinceLoaded) if that changed the same users or
+    ///realms
+    pub async fn save(
+        &mut self,
+        bucket: &dyn ObjectStore,
+        key: &AuthKey,
+    ) -> Result<(), ShoveError> {
+        let base = self.base.clone().unwrap_or_default();
+        let mut merged = save_merged(
+            bucket,
+            AUTH_DATA_LOCATION,
+            &*base,
+            self,
+            |enc_bytes| Self::construct_from_enc_bytes(enc_bytes, key),
+            |auth| {
+                let stored: StoredAuthStorer = auth.clone().into();
+                envelope::seal(&to_versioned(&stored)?, Some(key))
+            },
+        )
+        .await?;
 
+        merged.base = Some(Arc::new(merged.clone()));
+        *self = merged;
         Ok(())
     }
 
-    pub fn get_patterns_and_usernames(&self) -> Vec<(Realm, Vec<String>)> {
-        self.realms
+    ///highest priority first
+    pub fn get_realm_summaries(&self) -> Vec<RealmSummary> {
+        let mut summaries: Vec<RealmSummary> = self
+            .realms
             .iter()
-            .map(|(pat, uuids)| {
-                (
-                    pat.clone(),
-                    uuids
-                        .iter()
-                        .flat_map(|uuid| self.users.get(uuid))
-                        .map(|x| x.username.clone())
-                        .collect(),
-                )
+            .map(|(realm, rule)| RealmSummary {
+                realm: realm.clone(),
+                priority: rule.priority,
+                display_name: rule.display_name.clone(),
+                usernames: match &rule.access {
+                    AccessRule::Users(uuids) => Some(
+                        uuids
+                            .iter()
+                            .flat_map(|uuid| self.users.get(uuid))
+                            .map(|x| x.username.clone())
+                            .collect(),
+                    ),
+                    AccessRule::Public => None,
+                },
             })
-            .collect()
+            .collect();
+        summaries.sort_by_cached_key(|x| (-i64::from(x.priority), x.realm.to_string()));
+        summaries
+    }
+
+    pub fn get_rule(&self, realm: &Realm) -> Option<&RealmRule> {
+        self.realms.get(realm)
     }
 
     pub fn get_users(&self) -> Vec<(Uuid, String)> {
@@ -148,13 +476,24 @@ impl AuthStorer {
             .collect()
     }
 
+    ///the labels of every credential `uuid` can log in with
+    pub fn get_credential_labels(&self, uuid: &Uuid) -> Vec<String> {
+        self.users
+            .get(uuid)
+            .map(|uap| uap.credentials.iter().map(|x| x.label.clone()).collect())
+            .unwrap_or_default()
+    }
+
     pub fn rm_realm(&mut self, realm: &Realm) {
         self.realms.remove(realm);
     }
 
     pub fn rm_user(&mut self, user: &Uuid) {
         let mut realms_to_remove = vec![];
-        for (realm, list) in self.realms.iter_mut() {
+        for (realm, rule) in self.realms.iter_mut() {
+            let AccessRule::Users(list) = &mut rule.access else {
+                continue;
+            };
             match list.clone().retain(|uuid| uuid != user) {
                 Some(x) => {
                     *list = x;
@@ -173,18 +512,26 @@ impl AuthStorer {
         self.realms.keys().cloned().collect()
     }
 
+    ///usernames are compared case-insensitively, so `Alice` and `alice` can't both exist
+    pub fn username_taken(&self, username: &str) -> bool {
+        self.users
+            .values()
+            .any(|x| x.username.to_lowercase() == username.to_lowercase())
+    }
+
     pub fn add_user(
         &mut self,
         username: String,
-        password: impl AsRef<[u8]>,
-    ) -> color_eyre::Result<Uuid> {
-        let mut salt = [0; 32];
-        getrandom(&mut salt)?;
-        let saltstring = SaltString::encode_b64(&salt)?;
-
-        let argon2 = Argon2::default();
-        let password_hash = argon2.hash_password(password.as_ref(), &saltstring)?;
-        let stored_key = password_hash.serialize().to_string();
+        password: &str,
+        policy: &PasswordPolicy,
+    ) -> Result<Uuid, ShoveError> {
+        if self.username_taken(&username) {
+            return Err(ShoveError::Invalid(format!(
+                "there's already a user called {username:?}"
+            )));
+        }
+        policy.check(password)?;
+        let credential = StoredCredential::new(PRIMARY_CREDENTIAL_LABEL.to_string(), password)?;
 
         let uuid = Uuid::now_v7();
 
@@ -192,24 +539,127 @@ impl AuthStorer {
             uuid,
             UsernameAndPassword {
                 username: username.to_string(),
-                stored_key,
+                credentials: vec![credential],
             },
         );
 
         Ok(uuid)
     }
 
+    ///keeps the user's realms, unlike removing and re-adding them. only changes the primary
+    ///password, adding it back if it had been removed, and leaves any app passwords alone
+    pub fn change_password(
+        &mut self,
+        uuid: &Uuid,
+        new_password: &str,
+        policy: &PasswordPolicy,
+    ) -> Result<(), ShoveError> {
+        policy.check(new_password)?;
+        let Some(user) = self.users.get_mut(uuid) else {
+            return Err(ShoveError::Invalid(format!("no user with id {uuid}")));
+        };
+        let credential = StoredCredential::new(PRIMARY_CREDENTIAL_LABEL.to_string(), new_password)?;
+
+        match user
+            .credentials
+            .iter_mut()
+            .find(|x| x.label == PRIMARY_CREDENTIAL_LABEL)
+        {
+            Some(existing) => *existing = credential,
+            None => {
+                if user.credentials.len() >= MAX_CREDENTIALS {
+                    return Err(ShoveError::Invalid(format!(
+                        "{:?} already has {MAX_CREDENTIALS} credentials",
+                        user.username
+                    )));
+                }
+                user.credentials.push(credential);
+            }
+        }
+        Ok(())
+    }
+
+    ///adds another password `uuid` can log in with, eg. for one device
+    pub fn add_credential(
+        &mut self,
+        uuid: &Uuid,
+        label: String,
+        password: &str,
+        policy: &PasswordPolicy,
+    ) -> Result<(), ShoveError> {
+        policy.check(password)?;
+        let Some(user) = self.users.get_mut(uuid) else {
+            return Err(ShoveError::Invalid(format!("no user with id {uuid}")));
+        };
+        if label.is_empty() {
+            return Err(ShoveError::Invalid("credentials need a label".to_string()));
+        }
+        if user.credentials.iter().any(|x| x.label == label) {
+            return Err(ShoveError::Invalid(format!(
+                "{:?} already has a credential called {label:?}",
+                user.username
+            )));
+        }
+        if user.credentials.len() >= MAX_CREDENTIALS {
+            return Err(ShoveError::Invalid(format!(
+                "{:?} already has {MAX_CREDENTIALS} credentials",
+                user.username
+            )));
+        }
+
+        user.credentials
+            .push(StoredCredential::new(label, password)?);
+        Ok(())
+    }
+
+    ///revokes one credential. the last one can't go, as that'd lock the user out - remove the user instead
+    pub fn rm_credential(&mut self, uuid: &Uuid, label: &str) -> Result<(), ShoveError> {
+        let Some(user) = self.users.get_mut(uuid) else {
+            return Err(ShoveError::Invalid(format!("no user with id {uuid}")));
+        };
+        let Some(index) = user.credentials.iter().position(|x| x.label == label) else {
+            return Err(ShoveError::Invalid(format!(
+                "{:?} has no credential called {label:?}",
+                user.username
+            )));
+        };
+        if user.credentials.len() == 1 {
+            return Err(ShoveError::Invalid(format!(
+                "can't remove the only credential {:?} has",
+                user.username
+            )));
+        }
+
+        user.credentials.remove(index);
+        Ok(())
+    }
+
+    ///keeps the realm's priority if it already exists
     pub fn protect(&mut self, pattern: Realm, uuids: NonEmptyList<Uuid>) {
-        self.realms.insert(pattern, uuids);
+        match self.realms.entry(pattern) {
+            Entry::Occupied(mut occ) => {
+                occ.get_mut().access = AccessRule::Users(uuids);
+            }
+            Entry::Vacant(vac) => {
+                vac.insert(RealmRule::users(uuids));
+            }
+        }
+    }
+
+    pub fn set_rule(&mut self, pattern: Realm, rule: RealmRule) {
+        self.realms.insert(pattern, rule);
     }
 
+    ///public realms are left public
     pub fn protect_additional(&mut self, pattern: Realm, uuids: NonEmptyList<Uuid>) {
         match self.realms.entry(pattern) {
             Entry::Occupied(mut occ) => {
-                occ.get_mut().extend(uuids);
+                if let AccessRule::Users(existing) = &mut occ.get_mut().access {
+                    existing.extend(uuids);
+                }
             }
             Entry::Vacant(vac) => {
-                vac.insert(uuids);
+                vac.insert(RealmRule::users(uuids));
             }
         }
     }
@@ -219,27 +669,538 @@ impl AuthStorer {
     }
 
     pub fn get_users_with_access_to_realm(&self, pat: &Realm) -> Vec<Uuid> {
-        self.realms
-            .get(pat)
-            .map(|uuids| Vec::from(uuids.clone()))
-            .unwrap_or_default()
+        match self.realms.get(pat).map(|x| &x.access) {
+            Some(AccessRule::Users(uuids)) => Vec::from(uuids.clone()),
+            Some(AccessRule::Public) | None => vec![],
+        }
     }
 
-    ///None signifies everyone (even unauth) has access
-    pub fn find_users_with_access(&self, path: &str) -> Option<HashMap<String, String>> {
-        let uuids = self
+    ///the prefixes of every realm that needs logging in to, sorted. Only [`Realm::StartsWith`] ones,
+    ///as the others can't be written as a prefix
+    pub fn protected_prefixes(&self) -> Vec<String> {
+        let mut prefixes: Vec<String> = self
             .realms
             .iter()
-            .find(|(pattern, _)| pattern.matches(path))
-            .map(|(_, uap)| uap.clone())?;
+            .filter(|(_, rule)| matches!(rule.access, AccessRule::Users(_)))
+            .filter_map(|(realm, _)| match realm {
+                Realm::StartsWith(prefix) => Some(prefix.clone()),
+                _ => None,
+            })
+            .collect();
+        prefixes.sort_unstable();
+        prefixes
+    }
 
-        Some(
-            uuids
-                .into_iter()
-                .filter_map(|uuid| self.users.get(&uuid))
-                .cloned()
-                .map(|uap| (uap.username, uap.stored_key))
-                .collect(),
-        )
+    ///what the `WWW-Authenticate` challenge calls `realms`, as found by
+    ///[`Self::find_users_with_access`]. The same for every path they match, so browsers keep using
+    ///the same credentials throughout
+    pub fn challenge_name(&self, realms: &[Realm]) -> String {
+        let mut names: Vec<String> = vec![];
+        for realm in realms {
+            let name = self
+                .realms
+                .get(realm)
+                .and_then(|rule| rule.display_name.clone())
+                .unwrap_or_else(|| realm.default_display_name());
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names.join(" & ")
+    }
+
+    ///None signifies everyone (even unauth) has access. Only the highest priority realms that match
+    ///count - if any of those have users, the users from all of them get in (sorted, so it doesn't
+    ///depend on the map's order), so a public realm has to outrank any it's an exception to
+    pub fn find_users_with_access(&self, path: &str) -> Option<(Vec<Realm>, UserCredentials)> {
+        let matching = || {
+            self.realms
+                .iter()
+                .filter(|(pattern, _)| pattern.matches(path))
+        };
+        let top_priority = matching().map(|(_, rule)| rule.priority).max()?;
+
+        let mut matched: Vec<(&Realm, &NonEmptyList<Uuid>)> = matching()
+            .filter(|(_, rule)| rule.priority == top_priority)
+            .filter_map(|(realm, rule)| match &rule.access {
+                AccessRule::Users(uuids) => Some((realm, uuids)),
+                AccessRule::Public => None,
+            })
+            .collect();
+        if matched.is_empty() {
+            return None;
+        }
+        matched.sort_by_cached_key(|(realm, _)| realm.to_string());
+
+        let users = matched
+            .iter()
+            .flat_map(|(_, uuids)| uuids.iter())
+            .filter_map(|uuid| self.users.get(uuid))
+            .map(|uap| (uap.username.clone(), uap.credentials.clone()))
+            .collect();
+        let realms = matched
+            .into_iter()
+            .map(|(realm, _)| realm.clone())
+            .collect();
+
+        Some((realms, users))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{PasswordHash, PasswordVerifier};
+
+    fn verifies(auth: &AuthStorer, uuid: &Uuid, password: &str) -> bool {
+        auth.users[uuid].credentials.iter().any(|credential| {
+            let hash = PasswordHash::new(&credential.stored_key).unwrap();
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+
+    #[test]
+    fn test_app_passwords() {
+        let policy = PasswordPolicy::default();
+        let mut auth = AuthStorer::default();
+        let alice = auth
+            .add_user("alice".to_string(), "correct horse", &policy)
+            .unwrap();
+
+        auth.add_credential(&alice, "phone".to_string(), "battery staple", &policy)
+            .unwrap();
+        assert!(auth
+            .add_credential(&alice, "phone".to_string(), "another one", &policy)
+            .is_err());
+        assert!(auth
+            .add_credential(&alice, "laptop".to_string(), "short", &policy)
+            .is_err());
+        assert_eq!(
+            auth.get_credential_labels(&alice),
+            vec![PRIMARY_CREDENTIAL_LABEL.to_string(), "phone".to_string()]
+        );
+        assert!(verifies(&auth, &alice, "correct horse"));
+        assert!(verifies(&auth, &alice, "battery staple"));
+
+        //changing the password leaves app passwords be
+        auth.change_password(&alice, "new password", &policy)
+            .unwrap();
+        assert!(!verifies(&auth, &alice, "correct horse"));
+        assert!(verifies(&auth, &alice, "battery staple"));
+
+        auth.rm_credential(&alice, "phone").unwrap();
+        assert!(!verifies(&auth, &alice, "battery staple"));
+        assert!(auth.rm_credential(&alice, "phone").is_err());
+        assert!(auth
+            .rm_credential(&alice, PRIMARY_CREDENTIAL_LABEL)
+            .is_err());
+
+        for i in 1..MAX_CREDENTIALS {
+            auth.add_credential(&alice, format!("device {i}"), "battery staple", &policy)
+                .unwrap();
+        }
+        assert!(auth
+            .add_credential(
+                &alice,
+                "one too many".to_string(),
+                "battery staple",
+                &policy
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_old_users_still_load() {
+        let uuid = Uuid::now_v7();
+        let stored_key = hash_password("correct horse").unwrap();
+        let old = serde_json::json!({
+            "realms": [],
+            "users": [[uuid, {"username": "alice", "stored_key": stored_key}]],
+        });
+        let auth: AuthStorer =
+            from_versioned::<StoredAuthStorer>(&serde_json::to_vec(&old).unwrap())
+                .unwrap()
+                .into();
+
+        assert_eq!(
+            auth.get_credential_labels(&uuid),
+            vec![PRIMARY_CREDENTIAL_LABEL.to_string()]
+        );
+        assert!(verifies(&auth, &uuid, "correct horse"));
+
+        //and survive a round trip in the new format
+        let stored: StoredAuthStorer = auth.into();
+        let auth: AuthStorer = from_versioned::<StoredAuthStorer>(&to_versioned(&stored).unwrap())
+            .unwrap()
+            .into();
+        assert!(verifies(&auth, &uuid, "correct horse"));
+    }
+
+    #[test]
+    fn test_every_kind_of_realm_is_enforced() {
+        let policy = PasswordPolicy::default();
+        let mut auth = AuthStorer::default();
+        let alice = auth
+            .add_user("alice".to_string(), "correct horse", &policy)
+            .unwrap();
+        let bob = auth
+            .add_user("bob".to_string(), "battery staple", &policy)
+            .unwrap();
+
+        auth.protect(
+            Realm::StartsWith("/docs".to_string()),
+            NonEmptyList::single_element(alice),
+        );
+        auth.protect(
+            Realm::EndsWith(".pdf".to_string()),
+            NonEmptyList::single_element(bob),
+        );
+        auth.protect(
+            Realm::Regex(regex::Regex::new("^/private/[0-9]+$").unwrap()),
+            NonEmptyList::single_element(bob),
+        );
+        auth.protect(
+            Realm::Contains("secret".to_string()),
+            NonEmptyList::single_element(alice),
+        );
+
+        let usernames = |path| {
+            auth.find_users_with_access(path).map(|(realms, users)| {
+                let mut users: Vec<String> = users.into_keys().collect();
+                users.sort();
+                (realms.len(), users)
+            })
+        };
+
+        assert_eq!(usernames("/index.html"), None);
+        assert_eq!(
+            usernames("/docs/a.html"),
+            Some((1, vec!["alice".to_string()]))
+        );
+        assert_eq!(usernames("/b.pdf"), Some((1, vec!["bob".to_string()])));
+        assert_eq!(
+            usernames("/private/123"),
+            Some((1, vec!["bob".to_string()]))
+        );
+        assert_eq!(usernames("/private/abc"), None);
+        assert_eq!(
+            usernames("/a/secret/b"),
+            Some((1, vec!["alice".to_string()]))
+        );
+        //overlapping realms let in anyone from either
+        assert_eq!(
+            usernames("/docs/a.pdf"),
+            Some((2, vec!["alice".to_string(), "bob".to_string()]))
+        );
+    }
+
+    fn users_for(auth: &AuthStorer, path: &str) -> Option<Vec<String>> {
+        auth.find_users_with_access(path).map(|(_, users)| {
+            let mut users: Vec<String> = users.into_keys().collect();
+            users.sort();
+            users
+        })
+    }
+
+    #[test]
+    fn test_priority_and_public_exceptions() {
+        let policy = PasswordPolicy::default();
+        let mut auth = AuthStorer::default();
+        let alice = auth
+            .add_user("alice".to_string(), "correct horse", &policy)
+            .unwrap();
+        let bob = auth
+            .add_user("bob".to_string(), "battery staple", &policy)
+            .unwrap();
+
+        auth.protect(
+            Realm::StartsWith("/docs".to_string()),
+            NonEmptyList::single_element(alice),
+        );
+        auth.set_rule(
+            Realm::StartsWith("/docs/public".to_string()),
+            RealmRule {
+                priority: 1,
+                access: AccessRule::Public,
+                display_name: None,
+            },
+        );
+        auth.set_rule(
+            Realm::StartsWith("/docs/public/drafts".to_string()),
+            RealmRule {
+                priority: 2,
+                access: AccessRule::Users(NonEmptyList::single_element(bob)),
+                display_name: None,
+            },
+        );
+
+        assert_eq!(
+            users_for(&auth, "/docs/a.html"),
+            Some(vec!["alice".to_string()])
+        );
+        assert_eq!(users_for(&auth, "/docs/public/a.html"), None);
+        assert_eq!(
+            users_for(&auth, "/docs/public/drafts/a.html"),
+            Some(vec!["bob".to_string()])
+        );
+
+        //regexes overlap just the same, and a tie with a public realm stays protected
+        auth.set_rule(
+            Realm::Regex(regex::Regex::new(r"\.pdf$").unwrap()),
+            RealmRule {
+                priority: 1,
+                access: AccessRule::Users(NonEmptyList::single_element(bob)),
+                display_name: None,
+            },
+        );
+        assert_eq!(
+            users_for(&auth, "/docs/public/a.pdf"),
+            Some(vec!["bob".to_string()])
+        );
+        assert_eq!(
+            users_for(&auth, "/docs/a.pdf"),
+            Some(vec!["bob".to_string()])
+        );
+        assert_eq!(users_for(&auth, "/a.pdf"), Some(vec!["bob".to_string()]));
+
+        //removing the only user from a realm removes it, but public ones stay
+        auth.rm_user(&bob);
+        assert_eq!(users_for(&auth, "/docs/public/drafts/a.pdf"), None);
+        assert_eq!(
+            users_for(&auth, "/docs/a.pdf"),
+            Some(vec!["alice".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_challenge_names_cover_the_whole_realm() {
+        let policy = PasswordPolicy::default();
+        let mut auth = AuthStorer::default();
+        let alice = auth
+            .add_user("alice".to_string(), "correct horse", &policy)
+            .unwrap();
+        let name_for = |auth: &AuthStorer, path: &str| {
+            let (realms, _) = auth.find_users_with_access(path).unwrap();
+            auth.challenge_name(&realms)
+        };
+
+        let staff = Realm::StartsWith("/staff".to_string());
+        auth.protect(staff.clone(), NonEmptyList::single_element(alice));
+        auth.protect(
+            Realm::EndsWith(".pdf".to_string()),
+            NonEmptyList::single_element(alice),
+        );
+        for path in ["/staff", "/staff/", "/staff/rota/index.html"] {
+            assert_eq!(name_for(&auth, path), "/staff", "{path}");
+        }
+        assert_eq!(name_for(&auth, "/a.pdf"), "*.pdf");
+        assert_eq!(name_for(&auth, "/staff/a.pdf"), "*.pdf & /staff");
+
+        auth.set_rule(
+            staff.clone(),
+            RealmRule {
+                display_name: Some("Staff area".to_string()),
+                ..RealmRule::users(NonEmptyList::single_element(alice))
+            },
+        );
+        for path in ["/staff", "/staff/", "/staff/rota/index.html"] {
+            assert_eq!(name_for(&auth, path), "Staff area", "{path}");
+        }
+        assert_eq!(name_for(&auth, "/staff/a.pdf"), "*.pdf & Staff area");
+
+        //kept through saving, and left out for realms without one
+        let stored: StoredAuthStorer = auth.clone().into();
+        let written = to_versioned(&stored).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&written)
+                .matches("display_name")
+                .count(),
+            1
+        );
+        let auth: AuthStorer = from_versioned::<StoredAuthStorer>(&written).unwrap().into();
+        assert_eq!(
+            auth.get_rule(&staff)
+                .and_then(|x| x.display_name.as_deref()),
+            Some("Staff area")
+        );
+    }
+
+    #[test]
+    fn test_old_realms_still_load() {
+        let uuid = Uuid::now_v7();
+        let old = format!(
+            r#"{{"realms":[[{{"StartsWith":"/docs"}},["{uuid}"]],[{{"EndsWith":".txt"}},[]]],"users":[]}}"#
+        );
+        let auth: AuthStorer = from_versioned::<StoredAuthStorer>(old.as_bytes())
+            .unwrap()
+            .into();
+
+        assert_eq!(auth.realms.len(), 1);
+        assert_eq!(
+            auth.get_rule(&Realm::StartsWith("/docs".to_string())),
+            Some(&RealmRule::users(NonEmptyList::single_element(uuid)))
+        );
+
+        //and survive a round trip in the new format
+        let stored: StoredAuthStorer = auth.clone().into();
+        let auth: AuthStorer = from_versioned::<StoredAuthStorer>(&to_versioned(&stored).unwrap())
+            .unwrap()
+            .into();
+        assert_eq!(
+            auth.get_rule(&Realm::StartsWith("/docs".to_string()))
+                .map(|x| x.priority),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_every_stored_format_loads() {
+        let key = AuthKey::derive("hunter2", "bucket");
+        let uuid = Uuid::now_v7();
+        let stored_key = hash_password("correct horse").unwrap();
+        let users = format!(r#"[["{uuid}",{{"username":"alice","stored_key":"{stored_key}"}}]]"#);
+        let before_priorities =
+            format!(r#"{{"realms":[[{{"StartsWith":"/docs"}},["{uuid}"]]],"users":{users}}}"#);
+        let unversioned = format!(
+            r#"{{"realms":[[{{"StartsWith":"/docs"}},{{"priority":0,"access":{{"Users":["{uuid}"]}}}}]],"users":{users}}}"#
+        );
+        let versioned = format!(r#"{{"version":2,"data":{unversioned}}}"#);
+
+        let formats = [
+            //before envelopes, just a nonce & the ciphertext
+            key.encrypt(before_priorities.as_bytes()).unwrap(),
+            key.encrypt(unversioned.as_bytes()).unwrap(),
+            envelope::seal(before_priorities.as_bytes(), Some(&key)).unwrap(),
+            envelope::seal(unversioned.as_bytes(), Some(&key)).unwrap(),
+            envelope::seal(versioned.as_bytes(), Some(&key)).unwrap(),
+        ];
+        for (i, bytes) in formats.iter().enumerate() {
+            let auth = AuthStorer::construct_from_enc_bytes(bytes, &key).unwrap();
+            assert_eq!(
+                users_for(&auth, "/docs/a.pdf"),
+                Some(vec!["alice".to_string()]),
+                "{i}"
+            );
+            assert!(verifies(&auth, &uuid, "correct horse"), "{i}");
+        }
+
+        let other = AuthKey::derive("hunter3", "bucket");
+        for bytes in &formats {
+            assert!(matches!(
+                AuthStorer::construct_from_enc_bytes(bytes, &other),
+                Err(ShoveError::Crypto(_))
+            ));
+        }
+        assert!(matches!(
+            AuthStorer::construct_from_enc_bytes(&envelope::seal(b"[]", Some(&key)).unwrap(), &key),
+            Err(ShoveError::Serde { .. })
+        ));
+    }
+
+    #[test]
+    fn test_usernames_are_unique_ignoring_case() {
+        let policy = PasswordPolicy::default();
+        let mut auth = AuthStorer::default();
+
+        auth.add_user("Alice".to_string(), "correct horse", &policy)
+            .unwrap();
+        assert!(auth.username_taken("alice"));
+        assert!(auth
+            .add_user("aLiCe".to_string(), "battery staple", &policy)
+            .is_err());
+        assert_eq!(auth.get_users().len(), 1);
+    }
+
+    #[test]
+    fn test_password_policy() {
+        let policy = PasswordPolicy { min_length: 4 };
+        let mut auth = AuthStorer::default();
+
+        assert!(auth.add_user("bob".to_string(), "abc", &policy).is_err());
+        //characters, not bytes
+        assert!(matches!(policy.check("ééé"), Err(ShoveError::Invalid(_))));
+        assert!(policy.check("éééé").is_ok());
+
+        let uuid = auth.add_user("bob".to_string(), "abcd", &policy).unwrap();
+        assert!(auth.change_password(&uuid, "xyz", &policy).is_err());
+        assert!(verifies(&auth, &uuid, "abcd"));
+
+        auth.change_password(&uuid, "wxyz", &policy).unwrap();
+        assert!(verifies(&auth, &uuid, "wxyz"));
+        assert!(!verifies(&auth, &uuid, "abcd"));
+        assert!(auth
+            .change_password(&Uuid::now_v7(), "wxyz", &policy)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_saves_merge() {
+        use crate::store::memory::InMemoryBucket;
+
+        let bucket = InMemoryBucket::new();
+        let key = AuthKey::derive("hunter2", "bucket");
+        let policy = PasswordPolicy::default();
+        let docs = Realm::StartsWith("/docs".to_string());
+        let admin = Realm::StartsWith("/admin".to_string());
+
+        let mut setup = AuthStorer::default();
+        let alice = setup
+            .add_user("alice".to_string(), "correct horse", &policy)
+            .unwrap();
+        setup.save(&bucket, &key).await.unwrap();
+
+        //someone adds a user whilst someone else protects a realm
+        let (mut first, _) = AuthStorer::new(&bucket, &key).await.unwrap();
+        let (mut second, _) = AuthStorer::new(&bucket, &key).await.unwrap();
+        let bob = first
+            .add_user("bob".to_string(), "battery staple", &policy)
+            .unwrap();
+        first.save(&bucket, &key).await.unwrap();
+        second.protect(docs.clone(), NonEmptyList::single_element(alice));
+        second.save(&bucket, &key).await.unwrap();
+
+        let (saved, _) = AuthStorer::new(&bucket, &key).await.unwrap();
+        assert_eq!(saved.users.len(), 2);
+        assert_eq!(saved.get_users_with_access_to_realm(&docs), vec![alice]);
+        assert_eq!(second.users.len(), 2);
+
+        //both changing the same realm
+        let (mut third, _) = AuthStorer::new(&bucket, &key).await.unwrap();
+        second.protect(docs.clone(), NonEmptyList::single_element(bob));
+        second.save(&bucket, &key).await.unwrap();
+        third.remove_protection(docs.clone());
+        let e = third.save(&bucket, &key).await.unwrap_err();
+        assert!(matches!(e, ShoveError::ChangedSinceLoaded(_)), "{e}");
+
+        //both adding the same username, which are different users
+        let (mut third, _) = AuthStorer::new(&bucket, &key).await.unwrap();
+        second
+            .add_user("carol".to_string(), "correct horse", &policy)
+            .unwrap();
+        second.save(&bucket, &key).await.unwrap();
+        third
+            .add_user("Carol".to_string(), "battery staple", &policy)
+            .unwrap();
+        assert!(matches!(
+            third.save(&bucket, &key).await,
+            Err(ShoveError::ChangedSinceLoaded(_))
+        ));
+
+        //protecting a realm with a user someone else removed
+        let (mut third, _) = AuthStorer::new(&bucket, &key).await.unwrap();
+        second.rm_user(&bob);
+        second.save(&bucket, &key).await.unwrap();
+        third.protect(admin.clone(), NonEmptyList::single_element(bob));
+        assert!(matches!(
+            third.save(&bucket, &key).await,
+            Err(ShoveError::ChangedSinceLoaded(_))
+        ));
+
+        let (saved, _) = AuthStorer::new(&bucket, &key).await.unwrap();
+        assert!(saved.get_rule(&docs).is_none());
+        assert!(saved.get_rule(&admin).is_none());
+        assert_eq!(saved.users.len(), 2);
     }
 }
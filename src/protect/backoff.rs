@@ -0,0 +1,257 @@
+use crate::config::EnvReader;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const DEFAULT_BACKOFF_AFTER: u32 = 5;
+const DEFAULT_BAN_AFTER: u32 = 20;
+const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(15 * 60);
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+///when to start clearing out old failures rather than letting the maps grow forever
+const PRUNE_AT: usize = 10_000;
+
+///how harshly repeated failed logins get treated
+#[derive(Clone, Copy, Debug)]
+pub struct BackoffConfig {
+    ///consecutive failures before each new attempt has to wait
+    pub backoff_after: u32,
+    ///consecutive failures from one IP before it gets banned
+    pub ban_after: u32,
+    pub ban_duration: Duration,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            backoff_after: DEFAULT_BACKOFF_AFTER,
+            ban_after: DEFAULT_BAN_AFTER,
+            ban_duration: DEFAULT_BAN_DURATION,
+            base_delay: BASE_DELAY,
+            max_delay: MAX_DELAY,
+        }
+    }
+}
+
+impl BackoffConfig {
+    pub fn read(env: &mut EnvReader) -> Self {
+        Self {
+            backoff_after: env
+                .parsed("AUTH_BACKOFF_AFTER")
+                .unwrap_or(DEFAULT_BACKOFF_AFTER),
+            ban_after: env.parsed("AUTH_BAN_AFTER").unwrap_or(DEFAULT_BAN_AFTER),
+            ban_duration: env
+                .parsed("AUTH_BAN_SECS")
+                .map_or(DEFAULT_BAN_DURATION, Duration::from_secs),
+            ..Self::default()
+        }
+    }
+
+    ///how long to wait after the latest of `count` failures
+    fn delay(&self, count: u32) -> Duration {
+        if count < self.backoff_after {
+            return Duration::ZERO;
+        }
+        let doublings = (count - self.backoff_after).min(31);
+        self.base_delay
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay)
+    }
+
+    ///how long until a failure no longer counts for anything
+    fn forget_after(&self) -> Duration {
+        self.ban_duration.max(self.max_delay)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    banned_until: Option<Instant>,
+}
+
+///consecutive failures per key, forgetting any that have gone quiet
+#[derive(Debug)]
+struct FailureMap<K>(Mutex<HashMap<K, Failures>>);
+
+impl<K> Default for FailureMap<K> {
+    fn default() -> Self {
+        Self(Mutex::new(HashMap::new()))
+    }
+}
+
+impl<K: Hash + Eq> FailureMap<K> {
+    fn get(&self, key: &K, config: &BackoffConfig) -> Option<Failures> {
+        let map = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        map.get(key)
+            .copied()
+            .filter(|x| x.last.elapsed() < config.forget_after())
+    }
+
+    ///adds a failure, returning the new total
+    fn fail(&self, key: K, config: &BackoffConfig) -> Failures {
+        let mut map = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if map.len() >= PRUNE_AT {
+            map.retain(|_, x| x.last.elapsed() < config.forget_after());
+        }
+
+        let now = Instant::now();
+        let failures = map.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            banned_until: None,
+        });
+        if failures.last.elapsed() >= config.forget_after() {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        *failures
+    }
+
+    fn ban(&self, key: &K, until: Instant) {
+        let mut map = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(failures) = map.get_mut(key) {
+            failures.banned_until = Some(until);
+        }
+    }
+
+    fn reset(&self, key: &K) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+    }
+}
+
+///tracks failed logins per IP & per username, making each further attempt wait longer and banning
+///IPs that keep going. in-memory only, and kept separately from the auth data so reloads don't reset it
+#[derive(Clone, Debug, Default)]
+pub struct AuthBackoff {
+    config: BackoffConfig,
+    ips: Arc<FailureMap<IpAddr>>,
+    usernames: Arc<FailureMap<String>>,
+    bans: Arc<AtomicU64>,
+}
+
+impl AuthBackoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    fn wait(&self, failures: Option<Failures>) -> Result<(), Duration> {
+        let Some(failures) = failures else {
+            return Ok(());
+        };
+        let now = Instant::now();
+
+        let until = match failures.banned_until {
+            Some(banned_until) if banned_until > now => banned_until,
+            _ => failures.last + self.config.delay(failures.count),
+        };
+        if until > now {
+            Err(until - now)
+        } else {
+            Ok(())
+        }
+    }
+
+    ///`Err` with how long `ip` has to wait before trying again
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.wait(self.ips.get(&ip, &self.config))
+    }
+
+    ///`Err` with how long anyone has to wait before trying `username` again
+    pub fn check_username(&self, username: &str) -> Result<(), Duration> {
+        self.wait(self.usernames.get(&username.to_string(), &self.config))
+    }
+
+    pub fn record_failure(&self, ip: IpAddr, username: &str) {
+        self.usernames.fail(username.to_string(), &self.config);
+
+        let failures = self.ips.fail(ip, &self.config);
+        if failures.count == self.config.ban_after {
+            self.ips.ban(&ip, Instant::now() + self.config.ban_duration);
+            let total = self.bans.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(?ip, ban_duration=?self.config.ban_duration, %total, "Banning IP after repeated auth failures");
+        }
+    }
+
+    pub fn record_success(&self, ip: IpAddr, username: &str) {
+        self.ips.reset(&ip);
+        self.usernames.reset(&username.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> AuthBackoff {
+        AuthBackoff::new(BackoffConfig {
+            backoff_after: 2,
+            ban_after: 4,
+            ban_duration: Duration::from_secs(60),
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(80),
+        })
+    }
+
+    #[test]
+    fn test_delay_doubles_up_to_max() {
+        let config = BackoffConfig::default();
+        assert_eq!(config.delay(4), Duration::ZERO);
+        assert_eq!(config.delay(5), Duration::from_secs(1));
+        assert_eq!(config.delay(7), Duration::from_secs(4));
+        assert_eq!(config.delay(19), MAX_DELAY);
+        assert_eq!(config.delay(u32::MAX), MAX_DELAY);
+    }
+
+    #[test]
+    fn test_backoff_then_ban() {
+        let backoff = backoff();
+        let ip = "192.0.2.1".parse().unwrap();
+
+        backoff.record_failure(ip, "alice");
+        assert!(backoff.check_ip(ip).is_ok());
+        backoff.record_failure(ip, "alice");
+        assert!(backoff.check_ip(ip).is_err());
+        assert!(backoff.check_username("alice").is_err());
+        assert!(backoff.check_username("bob").is_ok());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(backoff.check_ip(ip).is_ok());
+
+        backoff.record_failure(ip, "bob");
+        backoff.record_failure(ip, "bob");
+        std::thread::sleep(Duration::from_millis(100));
+        //well past any backoff, but banned
+        let retry_after = backoff.check_ip(ip).unwrap_err();
+        assert!(retry_after > Duration::from_secs(50));
+        assert_eq!(backoff.bans.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_success_resets() {
+        let backoff = backoff();
+        let ip = "192.0.2.1".parse().unwrap();
+
+        backoff.record_failure(ip, "alice");
+        backoff.record_failure(ip, "alice");
+        assert!(backoff.check_ip(ip).is_err());
+
+        backoff.record_success(ip, "alice");
+        assert!(backoff.check_ip(ip).is_ok());
+        assert!(backoff.check_username("alice").is_ok());
+    }
+}
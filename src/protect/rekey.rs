@@ -0,0 +1,146 @@
+use crate::{
+    cache_control::manager::CC_LOCATION,
+    config::{BucketConfig, RekeyConfig},
+    envelope::{self, Legacy},
+    protect::{auth::AUTH_DATA_LOCATION, auth_storer::AuthKey},
+    s3::{get_bytes_or_default, site_location, SITES_LOCATION},
+    store::ObjectStore,
+    SitesManifest,
+};
+use color_eyre::eyre::WrapErr;
+
+///added to a location for where it's written before being copied over the real thing
+const REKEY_SUFFIX: &str = ".rekey";
+
+///the auth data, and any cache control that's been sealed with the key
+async fn encrypted_locations(bucket: &dyn ObjectStore) -> color_eyre::Result<Vec<String>> {
+    let mut locations = vec![AUTH_DATA_LOCATION.to_string()];
+
+    let sites = get_bytes_or_default(bucket, SITES_LOCATION).await?;
+    let manifest: SitesManifest = if sites.is_empty() {
+        SitesManifest::default()
+    } else {
+        serde_json::from_slice(&sites)?
+    };
+    let sites = std::iter::once(None).chain(manifest.hosts.iter().map(|x| Some(x.as_str())));
+    for site in sites {
+        let location = site_location(site, CC_LOCATION);
+        let bytes = get_bytes_or_default(bucket, &location).await?;
+        if envelope::is_encrypted(&bytes) == Some(true) {
+            locations.push(location);
+        }
+    }
+
+    Ok(locations)
+}
+
+///re-encrypts everything encrypted with `old` with `new`, returning where it did
+pub async fn rekey_store(
+    bucket: &dyn ObjectStore,
+    old: &AuthKey,
+    new: &AuthKey,
+) -> color_eyre::Result<Vec<String>> {
+    //everything's decrypted before anything's written, so the wrong old key doesn't leave some
+    //blobs under one key and some under the other
+    let mut resealed = vec![];
+    for location in encrypted_locations(bucket).await? {
+        let bytes = get_bytes_or_default(bucket, &location).await?;
+        if bytes.is_empty() {
+            continue;
+        }
+        let legacy = if location == AUTH_DATA_LOCATION {
+            Legacy::Encrypted
+        } else {
+            Legacy::Plain
+        };
+        let contents = envelope::open(&bytes, Some(old), legacy)
+            .wrap_err_with(|| format!("{location} doesn't decrypt with AUTH_ENCRYPTION_KEY_OLD"))?;
+        resealed.push((location, envelope::seal(&contents, Some(new))?));
+    }
+
+    for (location, sealed) in &resealed {
+        let temporary = format!("{location}{REKEY_SUFFIX}");
+        bucket
+            .put(&temporary, sealed, "application/octet-stream")
+            .await?;
+        bucket.copy(&temporary, location).await?;
+        bucket.delete(&temporary).await?;
+        info!(?location, "Re-encrypted");
+    }
+
+    Ok(resealed.into_iter().map(|(location, _)| location).collect())
+}
+
+pub async fn rekey(config: &BucketConfig, rekey: &RekeyConfig) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let locations = rekey_store(&bucket, &rekey.old, &rekey.new).await?;
+    if locations.is_empty() {
+        println!("Nothing is encrypted yet.");
+    } else {
+        println!(
+            "Re-encrypted {}. AUTH_ENCRYPTION_KEY_OLD can be unset, and the server needs restarting with the new AUTH_ENCRYPTION_KEY.",
+            locations.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::InMemoryBucket;
+
+    #[tokio::test]
+    async fn test_rekey() {
+        let old = AuthKey::derive("hunter2", "bucket");
+        let new = AuthKey::derive("correct horse", "bucket");
+        let bucket = InMemoryBucket::new();
+
+        //from before envelopes
+        let auth = old.encrypt(b"{}").unwrap();
+        bucket
+            .put(AUTH_DATA_LOCATION, &auth, "application/octet-stream")
+            .await
+            .unwrap();
+        let sealed = envelope::seal(b"[]", Some(&old)).unwrap();
+        bucket
+            .put(CC_LOCATION, &sealed, "application/octet-stream")
+            .await
+            .unwrap();
+        let plain = envelope::seal(b"[]", None).unwrap();
+        let site_cc = site_location(Some("example.com"), CC_LOCATION);
+        bucket
+            .put(&site_cc, &plain, "application/octet-stream")
+            .await
+            .unwrap();
+        bucket
+            .put(
+                SITES_LOCATION,
+                br#"{"hosts":["example.com"],"default":null}"#,
+                "application/json",
+            )
+            .await
+            .unwrap();
+
+        //the wrong key doesn't touch anything
+        assert!(rekey_store(&bucket, &new, &old).await.is_err());
+        assert_eq!(
+            bucket.bytes(AUTH_DATA_LOCATION).await.unwrap(),
+            auth.to_vec()
+        );
+
+        let locations = rekey_store(&bucket, &old, &new).await.unwrap();
+        assert_eq!(locations, vec![AUTH_DATA_LOCATION, CC_LOCATION]);
+        for location in [AUTH_DATA_LOCATION, CC_LOCATION] {
+            let bytes = bucket.bytes(location).await.unwrap();
+            assert!(envelope::open(&bytes, Some(&new), Legacy::Plain).is_ok());
+            assert!(envelope::open(&bytes, Some(&old), Legacy::Plain).is_err());
+        }
+        assert_eq!(bucket.bytes(&site_cc).await.unwrap(), plain);
+        assert!(!bucket
+            .keys()
+            .await
+            .iter()
+            .any(|x| x.ends_with(REKEY_SUFFIX)));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{
+    config::BucketConfig,
+    rate_limit::manager::{RateLimits, RateQuota},
+    Realm,
+};
+use comfy_table::Table;
+use dialoguer::{
+    theme::{ColorfulTheme, Theme},
+    Confirm, FuzzySelect, Input,
+};
+use std::num::NonZeroU32;
+
+pub mod manager;
+
+pub async fn rate_limit(config: &BucketConfig, site: Option<&str>) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let (mut limits, _) = RateLimits::new(&bucket, site).await?;
+
+    let theme = ColorfulTheme::default();
+    let choice = FuzzySelect::with_theme(&theme)
+        .with_prompt("What do you want to do?")
+        .items(&["View Rate Limits", "Add New Rule", "Remove Existing Rule"])
+        .interact()?;
+
+    match choice {
+        0 => {
+            let mut table = Table::new();
+            table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+            table.set_header(vec!["Pattern", "Per Minute", "Burst"]);
+
+            for (pat, quota) in limits.rules() {
+                table.add_row(vec![
+                    format!("{pat:?}"),
+                    quota.per_minute.to_string(),
+                    quota.burst.to_string(),
+                ]);
+            }
+
+            println!("{table}");
+        }
+        1 => {
+            let pat = Realm::get_from_stdin(&theme)?;
+            let quota = get_quota_from_stdin(&theme)?;
+
+            limits.set_rule(pat, quota);
+            limits.save(&bucket, site).await?;
+        }
+        2 => {
+            if limits.rules().is_empty() {
+                println!("No rate limits in place.");
+                return Ok(());
+            }
+
+            let items: Vec<String> = limits
+                .rules()
+                .iter()
+                .map(|(pat, quota)| {
+                    format!(
+                        "{pat:?}: {}/min, bursts of {}",
+                        quota.per_minute, quota.burst
+                    )
+                })
+                .collect();
+            let choice = FuzzySelect::with_theme(&theme)
+                .with_prompt("Which rule to remove?")
+                .items(&items)
+                .interact()?;
+            let pat = limits.rules()[choice].0.clone();
+
+            if Confirm::with_theme(&theme)
+                .with_prompt(format!("Confirm removal of {pat:?}"))
+                .interact()?
+            {
+                limits.remove_rule(&pat);
+                limits.save(&bucket, site).await?;
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn get_quota_from_stdin(theme: &dyn Theme) -> color_eyre::Result<RateQuota> {
+    let per_minute: NonZeroU32 = Input::with_theme(theme)
+        .with_prompt("How many requests a minute can each client make (must be >0)?")
+        .interact()?;
+    let burst: NonZeroU32 = Input::with_theme(theme)
+        .with_prompt("How many can they make all at once (must be >0)?")
+        .default(per_minute)
+        .interact()?;
+
+    Ok(RateQuota { per_minute, burst })
+}
@@ -0,0 +1,243 @@
+use crate::{
+    s3::{get_bytes_if_changed, get_bytes_or_default, site_location, LastFetched},
+    store::ObjectStore,
+    Realm,
+};
+use color_eyre::eyre::bail;
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultKeyedRateLimiter, Quota, RateLimiter,
+};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, num::NonZeroU32, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, RwLock};
+
+pub const RATE_LIMIT_LOCATION: &str = "rate_limit.json";
+
+///how many requests each client gets to a realm
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateQuota {
+    pub per_minute: NonZeroU32,
+    ///how many can come in all at once before they start getting spread out
+    pub burst: NonZeroU32,
+}
+
+impl RateQuota {
+    fn quota(self) -> Quota {
+        Quota::per_minute(self.per_minute).allow_burst(self.burst)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RateLimits {
+    ///the first matching realm wins, and paths that don't match any aren't limited
+    rules: Vec<(Realm, RateQuota)>,
+}
+
+impl RateLimits {
+    pub async fn new(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<(Self, Vec<u8>)> {
+        let bytes = Self::get_raw_bytes(bucket, site).await?;
+        let s = Self::construct_from_bytes(&bytes)?;
+        Ok((s, bytes))
+    }
+
+    pub async fn save(
+        &self,
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        let bytes = serde_json::to_vec(&self)?;
+
+        bucket
+            .put(
+                &site_location(site, RATE_LIMIT_LOCATION),
+                &bytes,
+                "application/json",
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_raw_bytes(
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<Vec<u8>> {
+        Ok(get_bytes_or_default(bucket, site_location(site, RATE_LIMIT_LOCATION)).await?)
+    }
+
+    fn construct_from_bytes(bytes: &[u8]) -> color_eyre::Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    ///the index of the rule for `path`, so it can be matched up with its limiter
+    fn rule_index(&self, path: &str) -> Option<usize> {
+        self.rules.iter().position(|(realm, _)| realm.matches(path))
+    }
+
+    pub fn rules(&self) -> &[(Realm, RateQuota)] {
+        &self.rules
+    }
+
+    ///replaces the quota for `realm` if there is one, otherwise adds it last
+    pub fn set_rule(&mut self, realm: Realm, quota: RateQuota) {
+        match self.rules.iter_mut().find(|(r, _)| *r == realm) {
+            Some((_, existing)) => *existing = quota,
+            None => self.rules.push((realm, quota)),
+        }
+    }
+
+    pub fn remove_rule(&mut self, realm: &Realm) {
+        self.rules.retain(|(r, _)| r != realm);
+    }
+}
+
+///the rules, with a limiter keyed by client for each one
+struct Limiters {
+    limits: RateLimits,
+    limiters: Vec<DefaultKeyedRateLimiter<IpAddr>>,
+}
+
+impl From<RateLimits> for Limiters {
+    fn from(limits: RateLimits) -> Self {
+        let limiters = limits
+            .rules
+            .iter()
+            .map(|(_, quota)| RateLimiter::keyed(quota.quota()))
+            .collect();
+        Self { limits, limiters }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitManager {
+    site: Option<String>,
+    last_fetched: Arc<Mutex<LastFetched>>,
+    current: Arc<RwLock<Limiters>>,
+}
+
+impl Default for RateLimitManager {
+    fn default() -> Self {
+        Self {
+            site: None,
+            last_fetched: Arc::default(),
+            current: Arc::new(RwLock::new(RateLimits::default().into())),
+        }
+    }
+}
+
+impl RateLimitManager {
+    pub async fn new(bucket: &dyn ObjectStore, site: Option<&str>) -> color_eyre::Result<Self> {
+        let (limits, raw_bytes) = RateLimits::new(bucket, site).await?;
+        Ok(Self {
+            site: site.map(|x| x.to_string()),
+            last_fetched: Arc::new(Mutex::new(LastFetched::new(&raw_bytes))),
+            current: Arc::new(RwLock::new(limits.into())),
+        })
+    }
+
+    ///changing the rules starts every client's count again
+    pub async fn check_and_reload(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<()> {
+        let Ok(mut last_fetched) = self.last_fetched.try_lock() else {
+            bail!("already reloading rate limits")
+        };
+
+        let location = site_location(self.site.as_deref(), RATE_LIMIT_LOCATION);
+        let Some(raw_bytes) = get_bytes_if_changed(bucket, location, &mut last_fetched).await?
+        else {
+            return Ok(());
+        };
+
+        let new_version = RateLimits::construct_from_bytes(&raw_bytes)?;
+        *self.current.write().await = new_version.into();
+
+        Ok(())
+    }
+
+    ///`Err` with how long until `ip` can try again if it's over the limit for `path`
+    pub async fn check(&self, path: &str, ip: IpAddr) -> Result<(), Duration> {
+        let current = self.current.read().await;
+        let Some(index) = current.limits.rule_index(path) else {
+            return Ok(());
+        };
+        current.limiters[index]
+            .check_key(&ip)
+            .map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now()))
+    }
+
+    ///forgets clients that are back to a full allowance, so scanning from lots of addresses can't
+    ///fill up memory
+    pub async fn sweep(&self) -> usize {
+        let current = self.current.read().await;
+        current
+            .limiters
+            .iter()
+            .map(|limiter| {
+                limiter.retain_recent();
+                limiter.shrink_to_fit();
+                limiter.len()
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(per_minute: u32, burst: u32) -> RateQuota {
+        RateQuota {
+            per_minute: NonZeroU32::new(per_minute).unwrap(),
+            burst: NonZeroU32::new(burst).unwrap(),
+        }
+    }
+
+    fn manager(limits: RateLimits) -> RateLimitManager {
+        RateLimitManager {
+            current: Arc::new(RwLock::new(limits.into())),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_matching_paths_are_limited() {
+        let mut limits = RateLimits::default();
+        limits.set_rule(Realm::StartsWith("/search".to_string()), quota(1, 2));
+        let manager = manager(limits);
+        let ip = "192.0.2.1".parse().unwrap();
+        let other = "192.0.2.2".parse().unwrap();
+
+        assert!(manager.check("/search/index.html", ip).await.is_ok());
+        assert!(manager.check("/search/index.html", ip).await.is_ok());
+        let retry_after = manager.check("/search/index.html", ip).await.unwrap_err();
+        assert!(retry_after > Duration::from_secs(50), "{retry_after:?}");
+
+        assert!(manager.check("/search/index.html", other).await.is_ok());
+        for _ in 0..10 {
+            assert!(manager.check("/index.html", ip).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rules_can_be_replaced_and_removed() {
+        let realm = Realm::StartsWith("/api".to_string());
+        let mut limits = RateLimits::default();
+        limits.set_rule(realm.clone(), quota(1, 1));
+        limits.set_rule(realm.clone(), quota(60, 5));
+        assert_eq!(limits.rules(), &[(realm.clone(), quota(60, 5))]);
+
+        let round_tripped: RateLimits =
+            serde_json::from_slice(&serde_json::to_vec(&limits).unwrap()).unwrap();
+        assert_eq!(round_tripped.rules(), limits.rules());
+
+        limits.remove_rule(&realm);
+        assert!(limits.rules().is_empty());
+        assert_eq!(manager(limits).sweep().await, 0);
+    }
+}
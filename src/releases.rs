@@ -0,0 +1,583 @@
+use crate::{
+    audit::utc_datetime,
+    config::BucketConfig,
+    s3::{get_bytes_or_default, site_location, UPLOAD_DATA_LOCATION},
+    store::ObjectStore,
+    UploadData,
+};
+use color_eyre::eyre::bail;
+use comfy_table::Table;
+use std::{
+    collections::HashSet,
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+///where each `--atomic` upload's files go, under a directory named for when it happened
+const RELEASES_PREFIX: &str = "releases/";
+
+///where old upload data, and any files only old releases still need, are kept
+pub const SNAPSHOTS_PREFIX: &str = "snapshots/";
+
+///how many releases are kept to roll back to if `RELEASES_KEPT` isn't set
+const DEFAULT_RELEASES_KEPT: usize = 5;
+
+///how many snapshots of the upload data to keep, including the live one. `0` turns them off
+pub fn releases_kept() -> usize {
+    env::var("RELEASES_KEPT")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_RELEASES_KEPT)
+}
+
+fn snapshot_prefix(site: Option<&str>) -> String {
+    site_location(site, &format!("{SNAPSHOTS_PREFIX}upload_data-"))
+}
+
+fn snapshot_location(site: Option<&str>, timestamp: u64) -> String {
+    format!("{}{timestamp}.json", snapshot_prefix(site))
+}
+
+fn preserved_prefix(site: Option<&str>) -> String {
+    site_location(site, &format!("{SNAPSHOTS_PREFIX}objects/"))
+}
+
+///where a file that got overwritten while an old release still needed it is kept, by its hash
+fn preserved_location(site: Option<&str>, hash: &str) -> String {
+    format!("{}{hash}", preserved_prefix(site))
+}
+
+///where an `--atomic` upload puts its files
+pub fn release_root(site: Option<&str>, timestamp: u64) -> String {
+    site_location(site, &format!("{RELEASES_PREFIX}{timestamp}"))
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_millis() as u64)
+}
+
+///the upload data as it was after one upload
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    ///milliseconds since the epoch
+    pub timestamp: u64,
+    pub data: UploadData,
+}
+
+///every snapshot of the site, newest first
+async fn get_snapshots(
+    bucket: &dyn ObjectStore,
+    site: Option<&str>,
+) -> color_eyre::Result<Vec<Snapshot>> {
+    let prefix = snapshot_prefix(site);
+    let mut timestamps: Vec<u64> = bucket
+        .list(&prefix)
+        .await?
+        .iter()
+        .filter_map(|key| {
+            key.strip_prefix(&prefix)?
+                .strip_suffix(".json")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    timestamps.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut snapshots = vec![];
+    for timestamp in timestamps {
+        let bytes = get_bytes_or_default(bucket, snapshot_location(site, timestamp)).await?;
+        match serde_json::from_slice(&bytes) {
+            Ok(data) => snapshots.push(Snapshot { timestamp, data }),
+            Err(e) => warn!(?timestamp, ?e, "Skipping unreadable snapshot"),
+        }
+    }
+    Ok(snapshots)
+}
+
+///the hashes of every file that's been copied aside
+async fn get_preserved(
+    bucket: &dyn ObjectStore,
+    site: Option<&str>,
+) -> color_eyre::Result<HashSet<String>> {
+    let prefix = preserved_prefix(site);
+    Ok(bucket
+        .list(&prefix)
+        .await?
+        .into_iter()
+        .filter_map(|key| key.strip_prefix(&prefix).map(ToString::to_string))
+        .collect())
+}
+
+///what's actually at `key` right now - whatever the newest of `live` and then the `snapshots` put there
+fn current_hash<'a>(live: &'a UploadData, snapshots: &'a [Snapshot], key: &str) -> Option<&'a str> {
+    std::iter::once(live)
+        .chain(snapshots.iter().map(|x| &x.data))
+        .find_map(|data| data.entries.get(key))
+        .map(String::as_str)
+}
+
+///the releases that can still be rolled back to, and what they need keeping in the bucket
+#[derive(Debug, Default)]
+pub struct Releases {
+    site: Option<String>,
+    ///newest first, and not including the upload that's happening now
+    kept: Vec<Snapshot>,
+    ///too old to keep once the upload that's happening now has its own snapshot
+    pruned: Vec<Snapshot>,
+    preserved: HashSet<String>,
+}
+
+impl Releases {
+    pub async fn load(bucket: &dyn ObjectStore, site: Option<&str>) -> color_eyre::Result<Self> {
+        let mut kept = get_snapshots(bucket, site).await?;
+        let pruned = kept.split_off(kept.len().min(releases_kept().saturating_sub(1)));
+        Ok(Self {
+            site: site.map(ToString::to_string),
+            kept,
+            pruned,
+            preserved: get_preserved(bucket, site).await?,
+        })
+    }
+
+    ///keys about to be `written` with new hashes whose current contents a kept release still needs.
+    ///`live` is the upload data before this upload
+    fn to_preserve<'a>(
+        &self,
+        live: &UploadData,
+        written: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Vec<(String, String)> {
+        let mut to_preserve = vec![];
+        let mut seen = HashSet::new();
+        for (key, hash) in written {
+            let Some(current) = current_hash(live, &self.kept, key) else {
+                continue;
+            };
+            if current == hash || self.preserved.contains(current) || !seen.insert(current) {
+                continue;
+            }
+            let needed = self
+                .kept
+                .iter()
+                .any(|x| x.data.entries.get(key).is_some_and(|x| x == current));
+            if needed {
+                to_preserve.push((key.to_string(), current.to_string()));
+            }
+        }
+        to_preserve
+    }
+
+    ///copies aside anything a kept release needs that's about to get overwritten
+    pub async fn preserve<'a>(
+        &mut self,
+        bucket: &dyn ObjectStore,
+        live: &UploadData,
+        written: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> color_eyre::Result<()> {
+        for (key, hash) in self.to_preserve(live, written) {
+            info!(?key, "Keeping a copy for older releases");
+            bucket
+                .copy(&key, &preserved_location(self.site.as_deref(), &hash))
+                .await?;
+            self.preserved.insert(hash);
+        }
+        Ok(())
+    }
+
+    ///every key `new` or a kept release uses
+    pub fn needed<'a>(&'a self, new: &'a UploadData) -> HashSet<&'a String> {
+        new.entries
+            .keys()
+            .chain(self.kept.iter().flat_map(|x| x.data.entries.keys()))
+            .collect()
+    }
+
+    ///what can go once `new` is live - the keys out of `removed`, the pruned releases & `released`
+    ///(everything under the `--atomic` prefixes) that nothing kept uses, and the hashes of the copied
+    ///aside files nothing kept needs
+    fn garbage(
+        &self,
+        new: &UploadData,
+        removed: Vec<String>,
+        released: Vec<String>,
+    ) -> (Vec<String>, Vec<String>) {
+        let needed = self.needed(new);
+        let mut keys: Vec<String> = removed
+            .into_iter()
+            .chain(
+                self.pruned
+                    .iter()
+                    .flat_map(|x| x.data.entries.keys().cloned()),
+            )
+            .chain(released)
+            .filter(|key| !needed.contains(key))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        keys.sort();
+
+        //only what's actually been replaced needs the copy
+        let needed_preserved: HashSet<&str> = self
+            .kept
+            .iter()
+            .flat_map(|x| &x.data.entries)
+            .filter(|(key, hash)| {
+                current_hash(new, &self.kept, key).is_some_and(|current| current != *hash)
+            })
+            .map(|(_, hash)| hash.as_str())
+            .collect();
+        let mut preserved: Vec<String> = self
+            .preserved
+            .iter()
+            .filter(|x| !needed_preserved.contains(x.as_str()))
+            .cloned()
+            .collect();
+        preserved.sort();
+
+        (keys, preserved)
+    }
+
+    ///snapshots `new` (which is now live), and deletes the copied aside files no kept release needs
+    ///any more. Returns the keys out of `removed`, the pruned releases & old `--atomic` uploads that
+    ///can go too, which servers still on the old upload data might be serving
+    pub async fn record(
+        self,
+        bucket: &dyn ObjectStore,
+        new: &UploadData,
+        removed: Vec<String>,
+    ) -> color_eyre::Result<Vec<String>> {
+        let site = self.site.as_deref();
+        if releases_kept() > 0 {
+            bucket
+                .put(
+                    &snapshot_location(site, now_ms()),
+                    &serde_json::to_vec(new)?,
+                    mime::JSON.as_str(),
+                )
+                .await?;
+            info!("Snapshotted upload data");
+        }
+
+        //includes any `--atomic` upload that never got as far as going live
+        let released = bucket.list(&site_location(site, RELEASES_PREFIX)).await?;
+        let (keys, preserved) = self.garbage(new, removed, released);
+        for hash in preserved {
+            trace!(?hash, "Deleting copy no release needs");
+            bucket.delete(&preserved_location(site, &hash)).await?;
+        }
+        for Snapshot { timestamp, .. } in &self.pruned {
+            trace!(?timestamp, "Deleting old snapshot");
+            bucket.delete(&snapshot_location(site, *timestamp)).await?;
+        }
+
+        Ok(keys)
+    }
+}
+
+///every key the live upload data or any release uses
+pub async fn still_needed(
+    bucket: &dyn ObjectStore,
+    site: Option<&str>,
+) -> color_eyre::Result<HashSet<String>> {
+    let live = get_bytes_or_default(bucket, site_location(site, UPLOAD_DATA_LOCATION)).await?;
+    let live: UploadData = if live.is_empty() {
+        UploadData::default()
+    } else {
+        serde_json::from_slice(&live)?
+    };
+    let snapshots = get_snapshots(bucket, site).await?;
+    Ok(live
+        .entries
+        .into_keys()
+        .chain(
+            snapshots
+                .into_iter()
+                .flat_map(|x| x.data.entries.into_keys()),
+        )
+        .collect())
+}
+
+fn format_timestamp(timestamp: u64) -> String {
+    let (year, month, day, hour, minute, second) = utc_datetime(u128::from(timestamp));
+    format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}")
+}
+
+pub async fn releases(config: &BucketConfig, site: Option<&str>) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let snapshots = get_snapshots(&bucket, site).await?;
+    if snapshots.is_empty() {
+        println!("No releases yet.");
+        return Ok(());
+    }
+
+    let live = get_bytes_or_default(&bucket, site_location(site, UPLOAD_DATA_LOCATION)).await?;
+    let live: Option<UploadData> = serde_json::from_slice(&live).ok();
+
+    let mut table = Table::new();
+    table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+    table.set_header(vec!["Index", "Timestamp", "Time (UTC)", "Files", ""]);
+    for (i, Snapshot { timestamp, data }) in snapshots.iter().enumerate() {
+        table.add_row(vec![
+            i.to_string(),
+            timestamp.to_string(),
+            format_timestamp(*timestamp),
+            data.entries.len().to_string(),
+            if live.as_ref() == Some(data) {
+                "live".to_string()
+            } else {
+                String::new()
+            },
+        ]);
+    }
+    println!("{table}");
+
+    Ok(())
+}
+
+///which snapshot `which` means - either its timestamp, or where it is in `shove releases`
+fn choose_snapshot(snapshots: Vec<Snapshot>, which: &str) -> Option<Snapshot> {
+    let n: u64 = which.parse().ok()?;
+    let index = usize::try_from(n).ok().filter(|x| *x < snapshots.len());
+    let mut snapshots = snapshots;
+    match snapshots.iter().position(|x| x.timestamp == n) {
+        Some(position) => Some(snapshots.swap_remove(position)),
+        None => index.map(|i| snapshots.swap_remove(i)),
+    }
+}
+
+///the keys `target` needs whose contents have since been replaced, and the hash to put back. errors
+///if any of them can't be put back
+fn to_restore<'a>(
+    target: &'a UploadData,
+    live: &UploadData,
+    snapshots: &[Snapshot],
+    preserved: &HashSet<String>,
+) -> color_eyre::Result<Vec<(&'a str, &'a str)>> {
+    let mut to_restore = vec![];
+    let mut missing = vec![];
+    for (key, hash) in &target.entries {
+        if current_hash(live, snapshots, key) == Some(hash.as_str()) {
+            continue;
+        }
+        if preserved.contains(hash) {
+            to_restore.push((key.as_str(), hash.as_str()));
+        } else {
+            missing.push(key.as_str());
+        }
+    }
+
+    if !missing.is_empty() {
+        missing.sort_unstable();
+        bail!(
+            "these files have been replaced since, and there isn't a copy to roll back to:\n{}",
+            missing.join("\n")
+        );
+    }
+    to_restore.sort_unstable();
+    Ok(to_restore)
+}
+
+///asks the server to pick up the new upload data, if there's a token & somewhere to send it
+async fn reload_server(site: Option<&str>) -> color_eyre::Result<()> {
+    let Some(token) = env::var("TIGRIS_TOKEN").ok().and_then(|x| {
+        x.split(',')
+            .map(str::trim)
+            .find(|x| !x.is_empty())
+            .map(ToString::to_string)
+    }) else {
+        return Ok(());
+    };
+    let Ok(url) = env::var("SHOVE_URL") else {
+        warn!("TIGRIS_TOKEN is set but SHOVE_URL isn't, so not asking the server to reload");
+        return Ok(());
+    };
+    request_reload(&url, &token, site).await
+}
+
+///asks the server at `url` to pick up `site`'s new upload data, with one of its `TIGRIS_TOKEN`s
+pub async fn request_reload(url: &str, token: &str, site: Option<&str>) -> color_eyre::Result<()> {
+    //shaped like a Tigris event, so only this site gets reloaded
+    let body = serde_json::json!({
+        "events": [{ "object": { "key": site_location(site, UPLOAD_DATA_LOCATION) } }]
+    });
+    let rsp = reqwest::Client::new()
+        .post(format!("{}/reload", url.trim_end_matches('/')))
+        .bearer_auth(token)
+        .header(reqwest::header::CONTENT_TYPE, mime::JSON.as_str())
+        .body(serde_json::to_vec(&body)?)
+        .send()
+        .await?;
+    if !rsp.status().is_success() {
+        bail!("server responded to reload with {}", rsp.status());
+    }
+    info!("Server reloaded");
+
+    Ok(())
+}
+
+pub async fn rollback(
+    config: &BucketConfig,
+    site: Option<&str>,
+    which: &str,
+) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let snapshots = get_snapshots(&bucket, site).await?;
+    let Some(target) = choose_snapshot(snapshots.clone(), which) else {
+        bail!("no release {which:?}, see `shove releases`");
+    };
+
+    let upload_data_location = site_location(site, UPLOAD_DATA_LOCATION);
+    let live = get_bytes_or_default(&bucket, &upload_data_location).await?;
+    let live: UploadData = if live.is_empty() {
+        UploadData::default()
+    } else {
+        serde_json::from_slice(&live)?
+    };
+    if live == target.data {
+        println!("{} is already live.", target.timestamp);
+        return Ok(());
+    }
+
+    let preserved = get_preserved(&bucket, site).await?;
+    for (key, hash) in to_restore(&target.data, &live, &snapshots, &preserved)? {
+        info!(?key, "Restoring file");
+        bucket.copy(&preserved_location(site, hash), key).await?;
+    }
+
+    let json = serde_json::to_vec(&target.data)?;
+    bucket
+        .put(&upload_data_location, &json, mime::JSON.as_str())
+        .await?;
+    //snapshotted again so it's the newest, which is what uploads go by for what's in the bucket
+    bucket
+        .put(
+            &snapshot_location(site, now_ms()),
+            &json,
+            mime::JSON.as_str(),
+        )
+        .await?;
+    println!(
+        "Rolled back to {} ({}).",
+        target.timestamp,
+        format_timestamp(target.timestamp)
+    );
+
+    reload_server(site).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn data(entries: &[(&str, &str)]) -> UploadData {
+        UploadData {
+            entries: entries
+                .iter()
+                .map(|(key, hash)| (key.to_string(), hash.to_string()))
+                .collect(),
+            root: String::new(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        }
+    }
+
+    fn snapshot(timestamp: u64, entries: &[(&str, &str)]) -> Snapshot {
+        Snapshot {
+            timestamp,
+            data: data(entries),
+        }
+    }
+
+    #[test]
+    fn test_old_releases_keep_what_they_need() {
+        let releases = Releases {
+            site: None,
+            kept: vec![
+                snapshot(2, &[("/index.html", "b"), ("/old.css", "c")]),
+                snapshot(1, &[("/index.html", "a"), ("/older.css", "d")]),
+            ],
+            pruned: vec![snapshot(0, &[("/index.html", "z"), ("/oldest.css", "e")])],
+            preserved: HashSet::from(["a".to_string(), "z".to_string()]),
+        };
+        let live = data(&[("/index.html", "b"), ("/old.css", "c")]);
+
+        //`b` is only needed by the newest release, which is where it's getting overwritten
+        assert_eq!(
+            releases.to_preserve(&live, [("/index.html", "x"), ("/new.css", "y")]),
+            vec![("/index.html".to_string(), "b".to_string())]
+        );
+        assert!(releases
+            .to_preserve(&live, [("/index.html", "b"), ("/old.css", "c")])
+            .is_empty());
+
+        let new = data(&[("/index.html", "x")]);
+        let (keys, preserved) = releases.garbage(&new, vec!["/old.css".to_string()], vec![]);
+        assert_eq!(keys, vec!["/oldest.css".to_string()]);
+        //`a` is still needed by the oldest kept release
+        assert_eq!(preserved, vec!["z".to_string()]);
+    }
+
+    #[test]
+    fn test_old_atomic_uploads_get_collected() {
+        let releases = Releases {
+            site: None,
+            kept: vec![snapshot(1, &[("releases/1/index.html", "a")])],
+            pruned: vec![snapshot(0, &[("releases/0/index.html", "a")])],
+            preserved: HashSet::new(),
+        };
+        let new = data(&[("releases/3/index.html", "b")]);
+        let released = [
+            "releases/0/index.html",
+            "releases/1/index.html",
+            //never went live
+            "releases/2/index.html",
+            "releases/3/index.html",
+        ]
+        .map(ToString::to_string)
+        .to_vec();
+
+        let (keys, _) = releases.garbage(&new, vec![], released);
+        assert_eq!(
+            keys,
+            vec![
+                "releases/0/index.html".to_string(),
+                "releases/2/index.html".to_string()
+            ]
+        );
+        assert_eq!(release_root(None, 3), "releases/3");
+        assert_eq!(
+            release_root(Some("example.com"), 3),
+            "sites/example.com/releases/3"
+        );
+    }
+
+    #[test]
+    fn test_rolling_back() {
+        let snapshots = vec![
+            snapshot(2000, &[("/index.html", "b")]),
+            snapshot(1000, &[("/index.html", "a"), ("/gone.html", "c")]),
+        ];
+
+        assert_eq!(
+            choose_snapshot(snapshots.clone(), "1000").map(|x| x.timestamp),
+            Some(1000)
+        );
+        assert_eq!(
+            choose_snapshot(snapshots.clone(), "1").map(|x| x.timestamp),
+            Some(1000)
+        );
+        assert!(choose_snapshot(snapshots.clone(), "5").is_none());
+        assert!(choose_snapshot(snapshots.clone(), "latest").is_none());
+
+        let live = snapshots[0].data.clone();
+        let target = &snapshots[1].data;
+        //`/gone.html` wasn't deleted as the older release needed it, so it's still there
+        assert_eq!(
+            to_restore(target, &live, &snapshots, &HashSet::from(["a".to_string()])).unwrap(),
+            vec![("/index.html", "a")]
+        );
+        assert!(to_restore(target, &live, &snapshots, &HashSet::new()).is_err());
+    }
+}
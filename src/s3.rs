@@ -1,35 +1,492 @@
-use s3::{creds::Credentials, error::S3Error, Bucket, Region};
-use std::env;
+use crate::{
+    audit::AUDIT_PREFIX,
+    cache_control::manager::CC_LOCATION,
+    cors::manager::CORS_LOCATION,
+    crawlers::manager::CRAWLERS_LOCATION,
+    error::ShoveError,
+    hash_raw_bytes,
+    headers::manager::HEADERS_LOCATION,
+    ip_filter::manager::IP_FILTER_LOCATION,
+    protect::auth::AUTH_DATA_LOCATION,
+    rate_limit::manager::RATE_LIMIT_LOCATION,
+    releases::{now_ms, SNAPSHOTS_PREFIX},
+    serve::{bandwidth::REPORTS_PREFIX, journal::JOURNAL_LOCATION, stats::STATS_LOCATION},
+    store::{Fetched, ObjectStore},
+    upload::pending::PENDING_DELETES_LOCATION,
+};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
 
 pub const UPLOAD_DATA_LOCATION: &str = "upload_data.json";
+pub const SITES_LOCATION: &str = "sites.json";
+///where the ACME accounts & certificates for `TLS_DOMAIN` are kept
+pub const ACME_CACHE_PREFIX: &str = "acme/";
 
-pub fn get_bucket() -> Box<Bucket> {
-    let aws_creds = get_aws_creds();
-    let bucket_name = env::var("BUCKET_NAME").expect("expected env var BUCKET_NAME");
-    let endpoint = env::var("AWS_ENDPOINT_URL_S3").expect("expected env var AWS_ENDPOINT_URL_S3");
-    let region = Region::Custom {
-        region: "auto".to_owned(),
-        endpoint,
-    };
-    Bucket::new(&bucket_name, region, aws_creds).unwrap()
-}
+///what shove keeps in the bucket for itself, which no uploaded file can be stored as or served from.
+///Anything ending in a `/` is everything under it
+pub const RESERVED_KEYS: &[&str] = &[
+    AUTH_DATA_LOCATION,
+    UPLOAD_DATA_LOCATION,
+    SITES_LOCATION,
+    CC_LOCATION,
+    HEADERS_LOCATION,
+    CORS_LOCATION,
+    IP_FILTER_LOCATION,
+    RATE_LIMIT_LOCATION,
+    CRAWLERS_LOCATION,
+    STATS_LOCATION,
+    JOURNAL_LOCATION,
+    AUDIT_PREFIX,
+    REPORTS_PREFIX,
+    SNAPSHOTS_PREFIX,
+    ACME_CACHE_PREFIX,
+    PENDING_DELETES_LOCATION,
+];
 
-pub fn get_aws_creds() -> Credentials {
-    let access_key = env::var("AWS_ACCESS_KEY_ID").expect("expected env var AWS_ACCESS_KEY_ID");
-    let secret_key =
-        env::var("AWS_SECRET_ACCESS_KEY").expect("expected env var AWS_SECRET_ACCESS_KEY");
+///whether `key` is one of the [`RESERVED_KEYS`], either at the top of the bucket or in a site's
+///directory. A leading `/` is the same object to S3, so doesn't get around it
+pub fn is_reserved_key(key: &str) -> bool {
+    let key = key.trim_start_matches('/');
+    let in_site = key
+        .strip_prefix("sites/")
+        .and_then(|x| x.split_once('/'))
+        .map(|(_, file)| file);
 
-    Credentials::new(Some(&access_key), Some(&secret_key), None, None, None).unwrap()
+    [Some(key), in_site].into_iter().flatten().any(|key| {
+        RESERVED_KEYS.iter().any(|reserved| {
+            if reserved.ends_with('/') {
+                key.starts_with(reserved)
+            } else {
+                key == *reserved
+            }
+        })
+    })
+}
+
+///where a site's copy of `location` lives. `None` is the site at the top of the bucket
+pub fn site_location(site: Option<&str>, location: &str) -> String {
+    match site {
+        Some(site) => format!("sites/{site}/{location}"),
+        None => location.to_string(),
+    }
 }
 
 ///if the file doesn't exist, get the default Vec<u8>
 pub async fn get_bytes_or_default(
-    bucket: &Bucket,
+    bucket: &dyn ObjectStore,
+    location: impl AsRef<str>,
+) -> Result<Vec<u8>, ShoveError> {
+    Ok(get_bytes_and_etag(bucket, location).await?.0)
+}
+
+///what was last seen at a location, so reloads can skip anything that hasn't changed
+#[derive(Debug, Default)]
+pub struct LastFetched {
+    hash: Vec<u8>,
+    ///`None` if the endpoint doesn't do them, or we haven't been given one yet
+    etag: Option<String>,
+    ///so an empty file turning up, or going away, still counts as a change
+    missing: bool,
+}
+
+impl LastFetched {
+    ///nothing being there is read as no bytes, so that's what they're taken to mean here too
+    pub fn new(bytes: &[u8]) -> Self {
+        Self {
+            hash: hash_raw_bytes(bytes),
+            etag: None,
+            missing: bytes.is_empty(),
+        }
+    }
+}
+
+///what's at a location, when it's changed since it was last fetched
+#[derive(Debug, PartialEq, Eq)]
+pub enum Changed {
+    ///a 404, which is different to there being an empty file
+    Missing,
+    Found(Vec<u8>),
+}
+
+///the bytes at `location` (empty if it doesn't exist), or `None` if they're the same as `last`
+pub async fn get_bytes_if_changed(
+    bucket: &dyn ObjectStore,
+    location: impl AsRef<str>,
+    last: &mut LastFetched,
+) -> Result<Option<Vec<u8>>, ShoveError> {
+    Ok(get_if_changed(bucket, location, last)
+        .await?
+        .map(|changed| match changed {
+            Changed::Missing => vec![],
+            Changed::Found(bytes) => bytes,
+        }))
+}
+
+///what's at `location`, or `None` if it's the same as `last`.
+///
+///asks with `If-None-Match` so unchanged files don't get downloaded at all, but still compares
+///hashes for endpoints that don't do ETags
+pub async fn get_if_changed(
+    bucket: &dyn ObjectStore,
+    location: impl AsRef<str>,
+    last: &mut LastFetched,
+) -> Result<Option<Changed>, ShoveError> {
+    let (bytes, etag, missing) = match bucket.get(location.as_ref(), last.etag.as_deref()).await? {
+        Fetched::Found(object) => (object.bytes, object.etag, false),
+        Fetched::NotModified => return Ok(None),
+        Fetched::Missing => (vec![], None, true),
+    };
+    //some endpoints, and proxies in front of them, send a full response with the same (or a
+    //weakened) ETag rather than a 304
+    let unchanged = !missing
+        && !last.missing
+        && last
+            .etag
+            .as_deref()
+            .zip(etag.as_deref())
+            .is_some_and(|(old, new)| same_etag(old, new));
+    last.etag = etag;
+    if unchanged {
+        return Ok(None);
+    }
+
+    let hash = hash_raw_bytes(&bytes);
+    if last.hash == hash && last.missing == missing {
+        return Ok(None);
+    }
+    last.hash = hash;
+    last.missing = missing;
+
+    Ok(Some(if missing {
+        Changed::Missing
+    } else {
+        Changed::Found(bytes)
+    }))
+}
+
+///the weak comparison from RFC 9110, which ignores whether either is marked `W/`
+fn same_etag(a: &str, b: &str) -> bool {
+    fn opaque(x: &str) -> &str {
+        x.trim().trim_start_matches("W/")
+    }
+    opaque(a) == opaque(b)
+}
+
+///why the newest version of some config isn't being used, so an older one still is
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StaleConfig {
+    pub location: String,
+    pub error: String,
+    ///when it first couldn't be used
+    pub since_ms: u64,
+}
+
+///whether the newest version of some config got used. Clones share the same state
+#[derive(Debug, Clone, Default)]
+pub struct ConfigHealth(Arc<Mutex<Option<StaleConfig>>>);
+
+impl ConfigHealth {
+    pub fn applied(&self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    ///keeps when it first went wrong, if it's still going wrong
+    pub fn rejected(&self, location: &str, error: &color_eyre::Report) {
+        let mut stale = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let since_ms = stale.as_ref().map_or_else(now_ms, |x| x.since_ms);
+        *stale = Some(StaleConfig {
+            location: location.to_string(),
+            error: format!("{error:#}"),
+            since_ms,
+        });
+    }
+
+    pub fn stale(&self) -> Option<StaleConfig> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+///what's at `location` and its ETag. nothing being there is no bytes and no ETag
+pub async fn get_bytes_and_etag(
+    bucket: &dyn ObjectStore,
     location: impl AsRef<str>,
-) -> color_eyre::Result<Vec<u8>> {
-    match bucket.get_object(location.as_ref()).await {
-        Ok(x) => Ok(x.to_vec()),
-        Err(S3Error::HttpFailWithBody(404, _)) => Ok(vec![]),
-        Err(e) => Err(e.into()),
+) -> Result<(Vec<u8>, Option<String>), ShoveError> {
+    match bucket.get(location.as_ref(), None).await? {
+        Fetched::Found(object) => Ok((object.bytes, object.etag)),
+        Fetched::NotModified | Fetched::Missing => Ok((vec![], None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serve::{empty_with_code, full_body, ServeBody};
+    use hyper::{
+        body::Incoming, header, server::conn::http1, service::service_fn, Request, Response,
+        StatusCode,
+    };
+    use hyper_util::rt::TokioIo;
+    use s3::{creds::Credentials, Bucket, Region};
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+    use tokio::net::TcpListener;
+
+    const ETAG: &str = "\"v1\"";
+
+    ///an S3 endpoint that counts how often it sent the whole file, rather than a 304
+    #[derive(Clone, Default)]
+    struct MockS3 {
+        full_gets: Arc<AtomicUsize>,
+        not_modified: Arc<AtomicUsize>,
+    }
+
+    impl MockS3 {
+        async fn handle(
+            &self,
+            req: Request<Incoming>,
+            send_etag: bool,
+        ) -> Result<Response<ServeBody>, Infallible> {
+            let matches = req
+                .headers()
+                .get(header::IF_NONE_MATCH)
+                .is_some_and(|x| x == ETAG);
+            if send_etag && matches {
+                self.not_modified.fetch_add(1, Ordering::SeqCst);
+                return Ok(empty_with_code(StatusCode::NOT_MODIFIED).unwrap());
+            }
+
+            self.full_gets.fetch_add(1, Ordering::SeqCst);
+            let mut rsp = Response::builder();
+            if send_etag {
+                rsp = rsp.header(header::ETAG, ETAG);
+            }
+            Ok(rsp.body(full_body("{}")).unwrap())
+        }
+
+        async fn start(&self, send_etag: bool) -> Box<Bucket> {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mock = self.clone();
+            tokio::task::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mock = mock.clone();
+                    tokio::task::spawn(async move {
+                        let svc = service_fn(|req| mock.handle(req, send_etag));
+                        let _ = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), svc)
+                            .await;
+                    });
+                }
+            });
+
+            let region = Region::Custom {
+                region: "auto".to_owned(),
+                endpoint: format!("http://{addr}"),
+            };
+            let creds = Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap();
+            Bucket::new("test", region, creds)
+                .unwrap()
+                .with_path_style()
+        }
+    }
+
+    ///the bytes and how many times they've been written
+    type Versioned = (Vec<u8>, usize);
+
+    ///one object that can be conditionally written, with the ETag being how many writes there have been
+    #[derive(Clone, Default)]
+    struct MockObject {
+        contents: Arc<std::sync::Mutex<Option<Versioned>>>,
+    }
+
+    impl MockObject {
+        async fn handle(&self, req: Request<Incoming>) -> Result<Response<ServeBody>, Infallible> {
+            use http_body_util::BodyExt;
+
+            let etag = |version: usize| format!("\"{version}\"");
+            let (parts, body) = req.into_parts();
+            let header = |name| parts.headers.get(name).and_then(|x| x.to_str().ok());
+
+            if parts.method == hyper::Method::GET {
+                let contents = self.contents.lock().unwrap().clone();
+                return Ok(match contents {
+                    Some((bytes, version)) => Response::builder()
+                        .header(header::ETAG, etag(version))
+                        .body(full_body(bytes))
+                        .unwrap(),
+                    None => empty_with_code(StatusCode::NOT_FOUND).unwrap(),
+                });
+            }
+
+            let bytes = body.collect().await.unwrap().to_bytes().to_vec();
+            let mut contents = self.contents.lock().unwrap();
+            let current = contents.as_ref().map(|(_, version)| etag(*version));
+            let allowed = match (header(header::IF_MATCH), header(header::IF_NONE_MATCH)) {
+                (Some(wanted), _) => current.as_deref() == Some(wanted),
+                (_, Some("*")) => current.is_none(),
+                _ => true,
+            };
+            if !allowed {
+                return Ok(empty_with_code(StatusCode::PRECONDITION_FAILED).unwrap());
+            }
+
+            let version = contents.as_ref().map_or(1, |(_, version)| version + 1);
+            *contents = Some((bytes, version));
+            Ok(Response::builder()
+                .header(header::ETAG, etag(version))
+                .body(full_body(""))
+                .unwrap())
+        }
+
+        async fn start(&self) -> Box<Bucket> {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mock = self.clone();
+            tokio::task::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mock = mock.clone();
+                    tokio::task::spawn(async move {
+                        let svc = service_fn(|req| mock.handle(req));
+                        let _ = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), svc)
+                            .await;
+                    });
+                }
+            });
+
+            let region = Region::Custom {
+                region: "auto".to_owned(),
+                endpoint: format!("http://{addr}"),
+            };
+            let creds = Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap();
+            Bucket::new("test", region, creds)
+                .unwrap()
+                .with_path_style()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conditional_puts_dont_clobber() {
+        let bucket = MockObject::default().start().await;
+
+        let (bytes, etag) = get_bytes_and_etag(&bucket, "log").await.unwrap();
+        assert!(bytes.is_empty() && etag.is_none());
+        assert!(bucket
+            .put_if_unchanged("log", b"a", "text/plain", None)
+            .await
+            .unwrap());
+        //someone else raced us to create it
+        assert!(!bucket
+            .put_if_unchanged("log", b"b", "text/plain", None)
+            .await
+            .unwrap());
+
+        let (bytes, etag) = get_bytes_and_etag(&bucket, "log").await.unwrap();
+        assert_eq!(bytes, b"a");
+        assert!(bucket
+            .put_if_unchanged("log", b"ab", "text/plain", etag.as_deref())
+            .await
+            .unwrap());
+        //stale now
+        assert!(!bucket
+            .put_if_unchanged("log", b"ac", "text/plain", etag.as_deref())
+            .await
+            .unwrap());
+        assert_eq!(get_bytes_and_etag(&bucket, "log").await.unwrap().0, b"ab");
+    }
+
+    #[test]
+    fn test_site_location() {
+        assert_eq!(
+            site_location(None, UPLOAD_DATA_LOCATION),
+            "upload_data.json"
+        );
+        assert_eq!(
+            site_location(Some("blog.example.com"), UPLOAD_DATA_LOCATION),
+            "sites/blog.example.com/upload_data.json"
+        );
+    }
+
+    #[test]
+    fn test_reserved_keys() {
+        for key in [
+            "authdata",
+            "/authdata",
+            "upload_data.json",
+            "sites.json",
+            "audit/1700000000000-0.json",
+            "snapshots/objects/abc",
+            "acme/cert-abc",
+            "sites/example.com/cache_control.json",
+            "sites/example.com/upload_data.json",
+            "sites/example.com/pending_deletes.json",
+        ] {
+            assert!(is_reserved_key(key), "{key}");
+        }
+
+        for key in [
+            "public/authdata",
+            "/index.html",
+            "/blog/upload_data.json",
+            "sites/example.com/index.html",
+            "releases/1700000000000/index.html",
+            "/auditing.html",
+        ] {
+            assert!(!is_reserved_key(key), "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_files_are_not_downloaded_again() {
+        let mock = MockS3::default();
+        let bucket = mock.start(true).await;
+        let mut last = LastFetched::default();
+
+        assert_eq!(
+            get_bytes_if_changed(&bucket, "cors.json", &mut last)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some(b"{}".as_slice())
+        );
+        for _ in 0..5 {
+            assert!(get_bytes_if_changed(&bucket, "cors.json", &mut last)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        assert_eq!(mock.full_gets.load(Ordering::SeqCst), 1);
+        assert_eq!(mock.not_modified.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_etags_are_compared_weakly() {
+        assert!(same_etag("\"abc\"", "\"abc\""));
+        assert!(same_etag("W/\"abc\"", "\"abc\""));
+        assert!(same_etag(" \"abc\"", "W/\"abc\""));
+        assert!(!same_etag("\"abc\"", "\"abd\""));
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_hashes_without_etags() {
+        let mock = MockS3::default();
+        let bucket = mock.start(false).await;
+        let mut last = LastFetched::new(b"{}");
+
+        for _ in 0..3 {
+            assert!(get_bytes_if_changed(&bucket, "cors.json", &mut last)
+                .await
+                .unwrap()
+                .is_none());
+        }
+        assert_eq!(mock.full_gets.load(Ordering::SeqCst), 3);
     }
 }
@@ -1,36 +1,143 @@
+mod background;
+pub mod bandwidth;
+mod body;
+mod concurrency;
+pub mod config;
+mod drain;
+mod encoding;
+mod flush;
+#[cfg(unix)]
+mod hangup;
+mod health;
+pub mod journal;
+mod limits;
+mod listen;
 mod livereload;
+mod markdown;
+mod negotiate;
 mod pages;
+mod proxy;
+mod range;
+mod redirects;
+mod resize;
 mod service;
+mod sitemap;
+mod sites;
 mod state;
+pub mod stats;
+mod telemetry;
+mod tls;
+mod watch;
+mod webhook;
 
-use crate::serve::{livereload::LiveReloader, service::ServeService, state::State};
-use http_body_util::Full;
-use hyper::{body::Bytes, http, server::conn::http1, Response, StatusCode};
-use hyper_util::rt::TokioIo;
-use std::{env::var, net::SocketAddr, sync::Arc, time::Duration};
+pub use crate::serve::webhook::WebhookTokens;
+
+use crate::{
+    config::{AuthConfig, BucketConfig},
+    serve::{
+        background::BACKGROUND_SHUTDOWN_WAIT,
+        config::{Config, Storage},
+        drain::Drainer,
+        limits::{is_idle_timeout, IdleTimeout, Limits},
+        listen::{Accepted, BindTarget, Listener, Routes, UNIX_PEER},
+        service::ServeService,
+        state::State,
+        telemetry::spawn_tagged,
+        tls::{is_challenge, start_acme, BucketCache},
+        watch::watch_local,
+    },
+};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Full};
+use hyper::{body::Bytes, header, http, Response, StatusCode};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use serde::Serialize;
+use std::{future::Future, net::SocketAddr, path::PathBuf, time::Duration};
 use tokio::{
-    net::TcpListener,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
     signal,
-    sync::{
-        mpsc::{channel, Sender as MPSCSender},
-        Semaphore,
-    },
+    sync::mpsc::{channel, Sender as MPSCSender},
     task::{JoinHandle, JoinSet},
 };
-
-const MAX_EXTERNAL_CONNS: usize = 512;
+use tokio_rustls::TlsAcceptor;
 
 enum Reloader {
+    ///on a timer, or watching the local directory
     Interval(JoinHandle<()>, MPSCSender<()>),
     Waiting,
 }
 
-pub fn empty_with_code(code: StatusCode) -> Result<Response<Full<Bytes>>, http::Error> {
-    Response::builder().status(code).body(Full::default())
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+///the body of every response - usually a buffered [`Full`], but large files get streamed
+pub type ServeBody = UnsyncBoxBody<Bytes, BoxError>;
+
+pub fn full_body(bytes: impl Into<Bytes>) -> ServeBody {
+    Full::new(bytes.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+pub fn empty_body() -> ServeBody {
+    full_body(Bytes::new())
+}
+
+///with a `Content-Length` of 0, other than for statuses that can't have a body at all
+fn empty_builder(code: StatusCode) -> http::response::Builder {
+    let builder = Response::builder().status(code);
+    if code.is_informational() || code == StatusCode::NO_CONTENT || code == StatusCode::NOT_MODIFIED
+    {
+        builder
+    } else {
+        builder.header(header::CONTENT_LENGTH, 0)
+    }
+}
+
+pub fn empty_with_code(code: StatusCode) -> Result<Response<ServeBody>, http::Error> {
+    empty_builder(code).body(empty_body())
+}
+
+pub fn empty_with_headers<'a>(
+    code: StatusCode,
+    headers: impl IntoIterator<Item = (header::HeaderName, &'a str)>,
+) -> Result<Response<ServeBody>, http::Error> {
+    headers
+        .into_iter()
+        .fold(empty_builder(code), |builder, (name, value)| {
+            builder.header(name, value)
+        })
+        .body(empty_body())
+}
+
+///the first value given for `name` in `query`, still percent-encoded
+pub fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name).then_some(value)
+    })
+}
+
+pub fn json_with_code(
+    code: StatusCode,
+    body: &impl Serialize,
+) -> Result<Response<ServeBody>, http::Error> {
+    match serde_json::to_vec(body) {
+        Ok(bytes) => Response::builder()
+            .status(code)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::CONTENT_LENGTH, bytes.len())
+            .body(full_body(bytes)),
+        Err(e) => {
+            error!(?e, "Error serialising JSON response");
+            empty_with_code(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
 
 //from https://github.com/tokio-rs/axum/blob/main/examples/graceful-shutdown/src/main.rs
-async fn shutdown_signal(reload_stop: Reloader, live_reloader: LiveReloader) {
+async fn shutdown_signal(drainer: Drainer) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -51,7 +158,15 @@ async fn shutdown_signal(reload_stop: Reloader, live_reloader: LiveReloader) {
     tokio::select! {
         () = ctrl_c => {},
         () = terminate => {},
+        () = drainer.exit_requested() => {},
     }
+}
+
+///everything but the connections, which are left to finish what they're doing after this. Anything
+///still going in the background gets a while to finish, so a reload isn't cut off half way
+async fn shut_down(reload_stop: Reloader, state: &State) {
+    //stop advertising ourselves as healthy first, same as an admin-requested drain
+    state.drainer().start_drain();
 
     match reload_stop {
         Reloader::Interval(handle, send) => {
@@ -63,24 +178,240 @@ async fn shutdown_signal(reload_stop: Reloader, live_reloader: LiveReloader) {
         Reloader::Waiting => {}
     }
 
-    if let Err(e) = live_reloader.send_stop().await {
+    state.background().finish(BACKGROUND_SHUTDOWN_WAIT).await;
+
+    if let Err(e) = state.live_reloader().send_stop().await {
         error!(?e, "Error stopping live reloader");
     }
+
+    if let Some(journal) = state.journal() {
+        journal.send_stop().await;
+    }
+
+    if let Some(audit) = state.audit() {
+        audit.send_stop().await;
+    }
 }
 
-pub async fn serve() -> color_eyre::Result<()> {
-    let port = var("PORT").unwrap_or_else(|_| "8080".into());
-    let addr: SocketAddr = format!("0.0.0.0:{port}")
-        .parse()
-        .expect("expected valid socket address to result from env var PORT");
+///serves both HTTP/1.1 and HTTP/2 (picked by the connection preface) on the same listener
+fn connection_builder(limits: Limits) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(limits.header_read_timeout);
+    builder
+}
+
+///shove's server, for running inside another binary. Anything not set here is at its default, as
+///if none of the optional environment variables were set
+#[must_use]
+pub struct Server {
+    config: Config,
+    listener: Option<TcpListener>,
+}
+
+impl Server {
+    ///everything read from the environment, the same as `shove serve`
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            listener: None,
+        }
+    }
+
+    ///serves what's been uploaded to `bucket`, protected with `auth`
+    pub fn bucket(bucket: BucketConfig, auth: AuthConfig) -> Self {
+        Self::from_config(Config::with_storage(Storage::Bucket { bucket, auth }))
+    }
 
-    let state = State::new().await?.expect("empty bucket");
+    ///serves `dir` with no auth, reloading whenever a file in it changes
+    pub fn local(dir: PathBuf) -> Self {
+        Self::from_config(Config::with_storage(Storage::Local(dir)))
+    }
+
+    ///binds to `0.0.0.0` on `port`, unless there's a [`listener`](Self::listener) or `LISTEN` said
+    ///where instead
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    ///serves on an already bound listener rather than a port
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    ///reloads when a Tigris webhook with one of `tokens` comes in, rather than every 60s
+    pub fn webhook_tokens(mut self, tokens: WebhookTokens) -> Self {
+        self.config.tigris_tokens = Some(tokens);
+        self
+    }
 
-    let reload = if state.tigris_token.is_none() {
+    ///serves until Ctrl+C, SIGTERM or an admin-requested drain, then shuts down gracefully
+    pub async fn run(self) -> color_eyre::Result<()> {
+        let mut listeners = vec![];
+        if let Some(listener) = self.listener {
+            listeners.push((Listener::Tcp(listener), Routes::All));
+        } else if self.config.listen.iter().next().is_some() {
+            for listen in self.config.listen.iter() {
+                let listener = Listener::bind(&listen.target).await.map_err(|e| {
+                    color_eyre::eyre::eyre!("Unable to listen on {}: {e}", listen.target)
+                })?;
+                listeners.push((listener, listen.routes));
+            }
+        } else {
+            let addr = SocketAddr::from(([0, 0, 0, 0], self.config.port));
+            listeners.push((Listener::bind(&BindTarget::Tcp(addr)).await?, Routes::All));
+        }
+
+        let tls_listener = match &self.config.tls {
+            Some(tls) => {
+                let target = BindTarget::Tcp(SocketAddr::from(([0, 0, 0, 0], tls.port)));
+                let listener = Listener::bind(&target).await.map_err(|e| {
+                    color_eyre::eyre::eyre!("Unable to listen for TLS on {target}: {e}")
+                })?;
+                //if the internal routes have a listener of their own, they stay there
+                let routes = if listeners.iter().any(|(_, x)| *x == Routes::Internal) {
+                    Routes::Public
+                } else {
+                    Routes::All
+                };
+                Some((listener, routes))
+            }
+            None => None,
+        };
+        serve_on(self.config, listeners, tls_listener).await
+    }
+}
+
+pub async fn serve(config: Config) -> color_eyre::Result<()> {
+    Server::from_config(config).run().await
+}
+
+///hands a connection to hyper, to be waited on in `futures` when shutting down
+fn serve_connection<S>(
+    stream: S,
+    remote_addr: SocketAddr,
+    routes: Routes,
+    state: &State,
+    http: &auto::Builder<TokioExecutor>,
+    futures: &mut JoinSet<()>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    futures.spawn(connection(stream, remote_addr, routes, false, state, http));
+}
+
+///[`serve_connection`], once the TLS handshake's done. That happens in `futures` too, so a slow
+///client can't hold up the accept loop
+fn serve_tls_connection(
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    routes: Routes,
+    acceptor: &TlsAcceptor,
+    state: &State,
+    http: &auto::Builder<TokioExecutor>,
+    futures: &mut JoinSet<()>,
+) {
+    let handshake = tokio::time::timeout(state.limits.header_read_timeout, acceptor.accept(stream));
+    let state = state.clone();
+    let http = http.clone();
+
+    futures.spawn(async move {
+        let stream = match handshake.await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                debug!(?e, "Error during TLS handshake");
+                return;
+            }
+            Err(_) => {
+                state.timeouts.header_read();
+                return;
+            }
+        };
+        if is_challenge(&stream) {
+            debug!("Answered TLS-ALPN-01 challenge");
+            return;
+        }
+        connection(stream, remote_addr, routes, true, &state, &http).await;
+    });
+}
+
+///serves `stream` until it closes, keeping count of how it timed out if it did
+fn connection<S>(
+    stream: S,
+    remote_addr: SocketAddr,
+    routes: Routes,
+    over_tls: bool,
+    state: &State,
+    http: &auto::Builder<TokioExecutor>,
+) -> impl Future<Output = ()> + Send + 'static
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(IdleTimeout::new(
+        stream,
+        state.limits.idle_timeout,
+        state.timeouts.clone(),
+    ));
+    let svc = ServeService::new(state.clone(), remote_addr, routes, over_tls);
+
+    let conn = http.serve_connection_with_upgrades(io, svc).into_owned();
+    let timeouts = state.timeouts.clone();
+
+    async move {
+        if let Err(e) = conn.await {
+            let hyper_error = e.downcast_ref::<hyper::Error>();
+            if hyper_error.is_some_and(hyper::Error::is_timeout) {
+                timeouts.header_read();
+            } else if hyper_error.is_some_and(is_idle_timeout) {
+                //already counted when it happened
+                debug!("Closed idle connection");
+            } else {
+                error!(?e, "Error serving request");
+            }
+        }
+    }
+}
+
+///`tls_listener` is for [`Config::tls`], if it's set
+async fn serve_on(
+    config: Config,
+    listeners: Vec<(Listener, Routes)>,
+    tls_listener: Option<(Listener, Routes)>,
+) -> color_eyre::Result<()> {
+    let auth_key = match &config.storage {
+        Storage::Bucket { auth, .. } => Some(auth.key.clone()),
+        Storage::Local(_) => None,
+    };
+    let state = State::new(config).await?;
+
+    //the config makes sure there's a bucket to keep the certificates in
+    let acceptor = state
+        .tls
+        .as_deref()
+        .zip(auth_key)
+        .map(|(tls, key)| start_acme(tls, BucketCache::new(state.store(), key)));
+    let listeners: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, routes)| (listener, routes, None))
+        .chain(
+            tls_listener
+                .zip(acceptor)
+                .map(|((listener, routes), acceptor)| (listener, routes, Some(acceptor))),
+        )
+        .collect();
+
+    let reload = if state.local().is_some() {
+        let (send_stop, recv_stop) = channel(1);
+        Reloader::Interval(watch_local(state.clone(), recv_stop)?, send_stop)
+    } else if state.tigris_tokens.is_none() {
         let (send_stop, mut recv_stop) = channel(1);
         let reload_state = state.clone();
         Reloader::Interval(
-            tokio::task::spawn(async move {
+            spawn_tagged("reload", async move {
                 loop {
                     tokio::select! {
                         _ = recv_stop.recv() => {
@@ -102,36 +433,50 @@ pub async fn serve() -> color_eyre::Result<()> {
         Reloader::Waiting
     };
 
-    let http = http1::Builder::new();
-    let mut signal = std::pin::pin!(shutdown_signal(reload, state.live_reloader()));
-    let semaphore = Arc::new(Semaphore::new(MAX_EXTERNAL_CONNS));
+    #[cfg(unix)]
+    hangup::listen_for_hangups(state.clone())?;
 
-    let listener = TcpListener::bind(&addr).await?;
-    info!(?addr, "Serving");
+    let http = connection_builder(state.limits);
+    let mut signal = std::pin::pin!(shutdown_signal(state.drainer()));
+
+    for (listener, routes, acceptor) in &listeners {
+        info!(addr=%listener.target()?, ?routes, tls=%acceptor.is_some(), "Serving");
+    }
 
     let mut futures = JoinSet::new();
 
     loop {
+        let accepts = listeners.iter().map(|(listener, routes, acceptor)| {
+            Box::pin(async move { (listener.accept().await, *routes, acceptor.as_ref()) })
+        });
         tokio::select! {
-            Ok((stream, remote_addr)) = listener.accept() => {
-                let io = TokioIo::new(stream);
-                let svc = ServeService::new(state.clone(), remote_addr, semaphore.clone());
-
-                let conn = http.serve_connection(io, svc).with_upgrades();
-
-                futures.spawn(async move {
-                    if let Err(e) = conn
-                        .await {
-                        error!(?e, "Error serving request");
+            ((accepted, routes, acceptor), _, _) = futures::future::select_all(accepts) => {
+                match accepted {
+                    Ok(Accepted::Tcp(stream, remote_addr)) => match acceptor {
+                        Some(acceptor) => serve_tls_connection(
+                            stream, remote_addr, routes, acceptor, &state, &http, &mut futures,
+                        ),
+                        None => {
+                            serve_connection(stream, remote_addr, routes, &state, &http, &mut futures);
+                        }
+                    },
+                    #[cfg(unix)]
+                    Ok(Accepted::Unix(stream)) => {
+                        serve_connection(stream, UNIX_PEER, routes, &state, &http, &mut futures);
                     }
-                });
+                    Err(e) => warn!(?e, "Error accepting connection"),
+                }
             },
-            _ = &mut signal => {
-                warn!("Graceful shutdown received");
+            () = &mut signal => {
+                warn!("Graceful shutdown received, no longer accepting connections");
                 break;
             }
         }
     }
+    //so nothing else can connect while the last requests finish
+    drop(listeners);
+
+    shut_down(reload, &state).await;
 
     tokio::select! {
         _ = futures.shutdown() => {
@@ -144,3 +489,178 @@ pub async fn serve() -> color_eyre::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        body::Incoming,
+        client::conn::{http1 as client_http1, http2 as client_http2},
+        service::service_fn,
+        Request, Version,
+    };
+    use std::{
+        convert::Infallible,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use tokio::net::TcpStream;
+
+    async fn handler(req: Request<Incoming>) -> Result<Response<ServeBody>, Infallible> {
+        Ok(Response::new(full_body(format!("{:?}", req.version()))))
+    }
+
+    async fn start_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            let http = connection_builder(Limits::default());
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let conn = http
+                    .serve_connection_with_upgrades(TokioIo::new(stream), service_fn(handler))
+                    .into_owned();
+                tokio::task::spawn(async move {
+                    let _ = conn.await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn request() -> Request<ServeBody> {
+        Request::builder()
+            .uri("http://test/index.html")
+            .body(empty_body())
+            .unwrap()
+    }
+
+    async fn body_string(rsp: Response<Incoming>) -> String {
+        let bytes = rsp.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serves_http2() {
+        let addr = start_server().await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            client_http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::task::spawn(conn);
+
+        //several requests multiplexed over the one connection
+        let rsps = futures::future::join_all((0..3).map(|_| sender.send_request(request()))).await;
+        for rsp in rsps {
+            let rsp = rsp.unwrap();
+            assert_eq!(rsp.status(), StatusCode::OK);
+            assert_eq!(rsp.version(), Version::HTTP_2);
+            assert_eq!(body_string(rsp).await, "HTTP/2.0");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_still_serves_http1() {
+        let addr = start_server().await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = client_http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::task::spawn(conn);
+
+        let rsp = sender.send_request(request()).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert_eq!(body_string(rsp).await, "HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn test_embedded_server_uses_given_listener() {
+        let dir = std::env::temp_dir().join(format!(
+            "shove-embedded-test-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<p>embedded</p>").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::local(dir.clone()).listener(listener);
+        let running = tokio::task::spawn(server.run());
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = client_http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::task::spawn(conn);
+        let rsp = sender.send_request(request()).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert_eq!(body_string(rsp).await, "<p>embedded</p>");
+
+        running.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listens_on_unix_sockets_with_their_own_routes() {
+        use crate::serve::listen::Listeners;
+        use tokio::net::UnixStream;
+
+        let dir = std::env::temp_dir().join(format!(
+            "shove-listen-test-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(dir.join("public")).unwrap();
+        std::fs::write(dir.join("public/index.html"), "<p>sockets</p>").unwrap();
+        let public = dir.join("public.sock");
+        let internal = dir.join("internal.sock");
+
+        let mut server = Server::local(dir.join("public"));
+        server.config.listen = Listeners::new(&format!(
+            "unix:{}=public,unix:{}=internal",
+            public.display(),
+            internal.display()
+        ))
+        .unwrap();
+        server.config.admin_token = Some("admin".to_string());
+        let running = tokio::task::spawn(server.run());
+
+        let get = |socket: PathBuf, path: &'static str| async move {
+            let stream = loop {
+                match UnixStream::connect(&socket).await {
+                    Ok(stream) => break stream,
+                    //not bound yet
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            let (mut sender, conn) = client_http1::handshake(TokioIo::new(stream)).await.unwrap();
+            tokio::task::spawn(conn);
+            let req = Request::builder()
+                .uri(format!("http://test{path}"))
+                .header(header::AUTHORIZATION, "Bearer admin")
+                .body(empty_body())
+                .unwrap();
+            sender.send_request(req).await.unwrap().status()
+        };
+
+        assert_eq!(get(public.clone(), "/index.html").await, StatusCode::OK);
+        assert_eq!(
+            get(public.clone(), "/__shove/status").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(get(public.clone(), "/healthcheck").await, StatusCode::OK);
+        assert_eq!(
+            get(internal.clone(), "/__shove/status").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get(internal.clone(), "/index.html").await,
+            StatusCode::NOT_FOUND
+        );
+
+        running.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use crate::serve::telemetry::spawn_tagged;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tokio_util::{
+    sync::{CancellationToken, WaitForCancellationFutureOwned},
+    task::TaskTracker,
+};
+
+///how long shutting down waits for reloads & the last flushes before carrying on without them
+pub const BACKGROUND_SHUTDOWN_WAIT: Duration = Duration::from_secs(30);
+
+///work that carries on after whatever started it, like reloads, warming the cache & flushing the
+///stats, so shutting down can let it finish rather than cutting it off part way through
+#[derive(Clone, Debug, Default)]
+pub struct Background {
+    tracker: TaskTracker,
+    stopping: CancellationToken,
+    ///how many of each task are going, to say what was waited on
+    running: Arc<Mutex<BTreeMap<&'static str, usize>>>,
+}
+
+///takes its task back out of `running` however it ends, including by panicking
+struct Running {
+    task: &'static str,
+    running: Arc<Mutex<BTreeMap<&'static str, usize>>>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = running.get_mut(self.task) {
+            *count -= 1;
+            if *count == 0 {
+                running.remove(self.task);
+            }
+        }
+    }
+}
+
+impl Background {
+    ///[`spawn_tagged`], but waited for when shutting down. `None` once that's started, as nothing
+    ///new gets going then
+    pub fn spawn<F>(&self, task: &'static str, fut: F) -> Option<JoinHandle<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        if self.is_stopping() {
+            debug!(%task, "Not starting, as we're shutting down");
+            return None;
+        }
+
+        *self
+            .running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(task)
+            .or_default() += 1;
+        let running = Running {
+            task,
+            running: self.running.clone(),
+        };
+        Some(spawn_tagged(
+            task,
+            self.tracker.track_future(async move {
+                let _running = running;
+                fut.await
+            }),
+        ))
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.is_cancelled()
+    }
+
+    ///resolves once shutting down starts, for anything that's better cut short than waited for
+    pub fn stopping(&self) -> WaitForCancellationFutureOwned {
+        self.stopping.clone().cancelled_owned()
+    }
+
+    fn running(&self) -> BTreeMap<&'static str, usize> {
+        self.running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    ///stops anything new from starting, then waits up to `bound` for whatever's still going. `true`
+    ///if it all finished in time
+    pub async fn finish(&self, bound: Duration) -> bool {
+        self.stopping.cancel();
+        self.tracker.close();
+
+        let waiting_on = self.running();
+        if waiting_on.is_empty() {
+            info!("No background tasks to wait for");
+            return true;
+        }
+        info!(
+            ?waiting_on,
+            ?bound,
+            "Waiting for background tasks to finish"
+        );
+
+        match tokio::time::timeout(bound, self.tracker.wait()).await {
+            Ok(()) => {
+                info!(?waiting_on, "Background tasks finished");
+                true
+            }
+            Err(_) => {
+                error!(still_running=?self.running(), ?bound, "Timed out waiting for background tasks, shutting down without them");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::Semaphore;
+
+    #[tokio::test]
+    async fn test_finishing_waits_for_whats_running() {
+        let background = Background::default();
+        let release = Arc::new(Semaphore::new(0));
+        let task_release = release.clone();
+        let reload = background
+            .spawn("reload", async move {
+                task_release.acquire().await.unwrap().forget();
+                "reloaded"
+            })
+            .unwrap();
+        let stopping = background.stopping();
+        let warm = background.spawn("warm", stopping).unwrap();
+        assert_eq!(
+            background.running(),
+            BTreeMap::from([("reload", 1), ("warm", 1)])
+        );
+
+        //the reload is still going, and is left to it
+        assert!(!background.finish(Duration::from_millis(50)).await);
+        assert!(warm.is_finished());
+        assert_eq!(background.running(), BTreeMap::from([("reload", 1)]));
+        assert!(background.spawn("late", async {}).is_none());
+
+        release.add_permits(1);
+        assert!(background.finish(Duration::from_secs(5)).await);
+        assert_eq!(reload.await.unwrap(), "reloaded");
+        assert!(background.running().is_empty());
+    }
+}
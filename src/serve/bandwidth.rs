@@ -0,0 +1,393 @@
+use crate::{
+    audit::utc_datetime,
+    config::BucketConfig,
+    s3::get_bytes_or_default,
+    serve::{background::Background, flush::start_flushing},
+    store::{ObjectStore, Store},
+};
+use comfy_table::Table;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const REPORTS_PREFIX: &str = "reports/";
+pub const DEFAULT_BANDWIDTH_MAX_PATHS: usize = 1000;
+pub const DEFAULT_REPORT_DAYS: u64 = 7;
+///where everything past `BANDWIDTH_MAX_PATHS` gets counted
+pub const OTHER_PATHS: &str = "(other)";
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DAY_MS: u128 = 24 * 60 * 60 * 1000;
+
+///days since the epoch, in UTC
+fn today() -> u64 {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    (now_ms / DAY_MS) as u64
+}
+
+///`YYYY-MM-DD`
+fn date(day: u64) -> String {
+    let (year, month, day, ..) = utc_datetime(u128::from(day) * DAY_MS);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+pub fn report_location(day: u64) -> String {
+    format!("{REPORTS_PREFIX}bandwidth-{}.json", date(day))
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Transferred {
+    pub bytes: u64,
+    pub requests: u64,
+}
+
+impl Transferred {
+    fn add(&mut self, other: Self) {
+        self.bytes += other.bytes;
+        self.requests += other.requests;
+    }
+}
+
+///everything served in one UTC day
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthReport {
+    pub paths: HashMap<String, Transferred>,
+}
+
+impl BandwidthReport {
+    pub fn total(&self) -> Transferred {
+        let mut total = Transferred::default();
+        for transferred in self.paths.values() {
+            total.add(*transferred);
+        }
+        total
+    }
+}
+
+///days since the epoch, and what was served then
+type DayReport = (u64, BandwidthReport);
+
+#[derive(Debug, Default)]
+struct Counter {
+    bytes: AtomicU64,
+    requests: AtomicU64,
+}
+
+impl Counter {
+    fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> Transferred {
+        Transferred {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl From<Transferred> for Counter {
+    fn from(transferred: Transferred) -> Self {
+        Self {
+            bytes: AtomicU64::new(transferred.bytes),
+            requests: AtomicU64::new(transferred.requests),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Day {
+    day: u64,
+    paths: HashMap<String, Counter>,
+}
+
+impl Day {
+    fn report(&self) -> BandwidthReport {
+        BandwidthReport {
+            paths: self
+                .paths
+                .iter()
+                .map(|(path, counter)| (path.clone(), counter.load()))
+                .collect(),
+        }
+    }
+}
+
+///bytes served for each path today. Clones share the same counts
+#[derive(Clone)]
+pub struct Bandwidth {
+    current: Arc<RwLock<Day>>,
+    ///days that rolled over but haven't been written yet
+    finished: Arc<Mutex<Vec<DayReport>>>,
+    max_paths: usize,
+}
+
+impl Bandwidth {
+    ///carries on from today's report if there is one, so restarts don't lose the day so far
+    pub async fn load(bucket: &dyn ObjectStore, max_paths: usize) -> Self {
+        let day = today();
+        let existing = match get_bytes_or_default(bucket, report_location(day)).await {
+            Ok(bytes) if bytes.is_empty() => BandwidthReport::default(),
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!(
+                    ?e,
+                    "Error parsing today's bandwidth report, starting afresh"
+                );
+                BandwidthReport::default()
+            }),
+            Err(e) => {
+                warn!(
+                    ?e,
+                    "Error reading today's bandwidth report, starting afresh"
+                );
+                BandwidthReport::default()
+            }
+        };
+
+        Self::starting_from(day, existing, max_paths)
+    }
+
+    fn starting_from(day: u64, existing: BandwidthReport, max_paths: usize) -> Self {
+        let paths = existing
+            .paths
+            .into_iter()
+            .map(|(path, transferred)| (path, transferred.into()))
+            .collect();
+        Self {
+            current: Arc::new(RwLock::new(Day { day, paths })),
+            finished: Arc::default(),
+            max_paths,
+        }
+    }
+
+    pub fn record(&self, key: &str, bytes: u64) {
+        self.record_on(today(), key, bytes);
+    }
+
+    fn record_on(&self, day: u64, key: &str, bytes: u64) {
+        //the usual case only needs the read lock
+        if let Ok(current) = self.current.read()
+            && current.day == day
+            && let Some(counter) = current.paths.get(key)
+        {
+            counter.add(bytes);
+            return;
+        }
+
+        let Ok(mut current) = self.current.write() else {
+            return;
+        };
+        if current.day != day {
+            let finished = mem::replace(
+                &mut *current,
+                Day {
+                    day,
+                    paths: HashMap::new(),
+                },
+            );
+            if let Ok(mut pending) = self.finished.lock() {
+                pending.push((finished.day, finished.report()));
+            }
+        }
+
+        let key = if current.paths.contains_key(key) || current.paths.len() < self.max_paths {
+            key
+        } else {
+            OTHER_PATHS
+        };
+        current.paths.entry(key.to_string()).or_default().add(bytes);
+    }
+
+    ///days that finished since the last flush, then today so far
+    fn to_write(&self) -> (Vec<DayReport>, Option<DayReport>) {
+        let finished = self
+            .finished
+            .lock()
+            .map(|mut x| mem::take(&mut *x))
+            .unwrap_or_default();
+        let current = self
+            .current
+            .read()
+            .ok()
+            .map(|current| (current.day, current.report()));
+        (finished, current)
+    }
+
+    async fn flush(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<()> {
+        let (finished, current) = self.to_write();
+        let mut finished = finished.into_iter();
+        while let Some((day, report)) = finished.next() {
+            if let Err(e) = write_report(bucket, day, &report).await {
+                //they won't get written again with today, so keep them for next time
+                if let Ok(mut pending) = self.finished.lock() {
+                    pending.extend(std::iter::once((day, report)).chain(finished));
+                }
+                return Err(e);
+            }
+        }
+        if let Some((day, report)) = current {
+            write_report(bucket, day, &report).await?;
+        }
+
+        trace!("Flushed bandwidth report");
+        Ok(())
+    }
+
+    ///writes to the bucket every so often, and once more when shutting down
+    pub fn start_flushing(&self, bucket: Store, background: &Background) {
+        let bandwidth = self.clone();
+        start_flushing("bandwidth", FLUSH_INTERVAL, background, move || {
+            let bandwidth = bandwidth.clone();
+            let bucket = bucket.clone();
+            async move { bandwidth.flush(&*bucket).await }
+        })
+    }
+}
+
+async fn write_report(
+    bucket: &dyn ObjectStore,
+    day: u64,
+    report: &BandwidthReport,
+) -> color_eyre::Result<()> {
+    let bytes = serde_json::to_vec(report)?;
+    bucket
+        .put(&report_location(day), &bytes, mime::JSON.as_str())
+        .await?;
+    Ok(())
+}
+
+pub async fn report_bandwidth(config: &BucketConfig, days: u64) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+
+    let today = today();
+    let mut by_day = Table::new();
+    by_day.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+    by_day.set_header(vec!["Day (UTC)", "Requests", "Bytes"]);
+
+    let mut paths: HashMap<String, Transferred> = HashMap::new();
+    let mut total = Transferred::default();
+    for day in (today.saturating_sub(days.saturating_sub(1))..=today).rev() {
+        let bytes = get_bytes_or_default(&bucket, report_location(day)).await?;
+        if bytes.is_empty() {
+            continue;
+        }
+        let report: BandwidthReport = serde_json::from_slice(&bytes)?;
+
+        let day_total = report.total();
+        by_day.add_row(vec![
+            date(day),
+            day_total.requests.to_string(),
+            day_total.bytes.to_string(),
+        ]);
+        total.add(day_total);
+        for (path, transferred) in report.paths {
+            paths.entry(path).or_default().add(transferred);
+        }
+    }
+
+    if paths.is_empty() {
+        println!("No bandwidth reports in the last {days} days.");
+        return Ok(());
+    }
+
+    let mut paths: Vec<(String, Transferred)> = paths.into_iter().collect();
+    paths.sort_unstable_by(|(a_path, a), (b_path, b)| {
+        b.bytes.cmp(&a.bytes).then_with(|| a_path.cmp(b_path))
+    });
+
+    let mut by_path = Table::new();
+    by_path.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+    by_path.set_header(vec!["Path", "Requests", "Bytes"]);
+    for (path, transferred) in paths {
+        by_path.add_row(vec![
+            path,
+            transferred.requests.to_string(),
+            transferred.bytes.to_string(),
+        ]);
+    }
+    by_path.add_row(vec![
+        "Total".to_string(),
+        total.requests.to_string(),
+        total.bytes.to_string(),
+    ]);
+
+    println!("{by_day}");
+    println!("{by_path}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::InMemoryBucket;
+
+    fn transferred(bytes: u64, requests: u64) -> Transferred {
+        Transferred { bytes, requests }
+    }
+
+    #[test]
+    fn test_report_location() {
+        assert_eq!(report_location(0), "reports/bandwidth-1970-01-01.json");
+        assert_eq!(report_location(20_089), "reports/bandwidth-2025-01-01.json");
+    }
+
+    #[test]
+    fn test_long_tail_goes_to_other() {
+        let bandwidth = Bandwidth::starting_from(1, BandwidthReport::default(), 2);
+        bandwidth.record_on(1, "/a.html", 10);
+        bandwidth.record_on(1, "/b.html", 20);
+        bandwidth.record_on(1, "/c.html", 30);
+        bandwidth.record_on(1, "/d.html", 40);
+        bandwidth.record_on(1, "/a.html", 10);
+
+        let (finished, current) = bandwidth.to_write();
+        assert!(finished.is_empty());
+        let (day, report) = current.unwrap();
+        assert_eq!(day, 1);
+        assert_eq!(
+            report.paths,
+            HashMap::from([
+                ("/a.html".to_string(), transferred(20, 2)),
+                ("/b.html".to_string(), transferred(20, 1)),
+                (OTHER_PATHS.to_string(), transferred(70, 2)),
+            ])
+        );
+        assert_eq!(report.total(), transferred(110, 5));
+    }
+
+    #[tokio::test]
+    async fn test_days_roll_over_and_carry_on_after_restarts() {
+        let bucket = InMemoryBucket::new();
+        let bandwidth = Bandwidth::starting_from(1, BandwidthReport::default(), 10);
+        bandwidth.record_on(1, "/a.html", 10);
+        bandwidth.record_on(2, "/a.html", 5);
+        bandwidth.flush(&bucket).await.unwrap();
+
+        let read = |day| {
+            let bucket = bucket.clone();
+            async move {
+                let bytes = bucket.bytes(&report_location(day)).await.unwrap();
+                serde_json::from_slice::<BandwidthReport>(&bytes).unwrap()
+            }
+        };
+        assert_eq!(read(1).await.total(), transferred(10, 1));
+        assert_eq!(read(2).await.total(), transferred(5, 1));
+
+        let restarted = Bandwidth::starting_from(2, read(2).await, 10);
+        restarted.record_on(2, "/a.html", 5);
+        let (_, report) = restarted.to_write().1.unwrap();
+        assert_eq!(report.total(), transferred(10, 2));
+    }
+}
@@ -0,0 +1,305 @@
+use crate::serve::{empty_body, BoxError, ServeBody};
+use http_body_util::{BodyExt, Limited};
+use hyper::{
+    body::{Body, Bytes, Frame, Incoming, SizeHint},
+    header, HeaderMap, StatusCode,
+};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+pub const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+///how long a client that was waiting on a `100 Continue` gets to send its body anyway, once it's
+///been turned down without one
+const REFUSED_BODY_WAIT: Duration = Duration::from_secs(5);
+
+///`None` if the request doesn't use `Expect`, otherwise whether we know how to meet the expectation
+pub fn expectation_is_supported(headers: &HeaderMap) -> Option<bool> {
+    let expect = headers.get(header::EXPECT)?;
+    Some(
+        expect
+            .to_str()
+            .is_ok_and(|x| x.eq_ignore_ascii_case("100-continue")),
+    )
+}
+
+///reads the whole body, refusing anything over `max_bytes`.
+///
+///Content-Length gets checked before the body is touched - hyper only sends the interim
+///`100 Continue` once the body is first polled, so a client that's waiting on one gets told no
+///without ever sending the body. Anything that needs checking (auth, paths etc) should therefore
+///happen before this is called.
+pub async fn read_capped_body(
+    body: Incoming,
+    headers: &HeaderMap,
+    max_bytes: usize,
+) -> Result<Bytes, StatusCode> {
+    check_content_length(headers, max_bytes)?;
+
+    //still needed for chunked bodies, which don't say how long they are up-front
+    match Limited::new(body, max_bytes).collect().await {
+        Ok(collected) => Ok(collected.to_bytes()),
+        Err(e) => {
+            if e.is::<http_body_util::LengthLimitError>() {
+                debug!(%max_bytes, "Rejecting body that went over the limit");
+                Err(StatusCode::PAYLOAD_TOO_LARGE)
+            } else {
+                debug!(?e, "Error reading body");
+                Err(StatusCode::BAD_REQUEST)
+            }
+        }
+    }
+}
+
+///whether the `Content-Length`, if there is one, is readable and no more than `max_bytes`
+fn check_content_length(headers: &HeaderMap, max_bytes: usize) -> Result<(), StatusCode> {
+    let Some(content_length) = headers.get(header::CONTENT_LENGTH) else {
+        return Ok(());
+    };
+    let Some(content_length) = content_length
+        .to_str()
+        .ok()
+        .and_then(|x| x.parse::<usize>().ok())
+    else {
+        debug!(?content_length, "Unable to parse Content-Length");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+
+    if content_length > max_bytes {
+        debug!(%content_length, %max_bytes, "Rejecting body by Content-Length");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    Ok(())
+}
+
+///reads & throws away a body that's been turned down, so the connection can carry on to the next
+///request. Gives back what to send as the refusal's body, or `None` if the body's been left, which
+///means the connection has to close, as anything over `max_bytes` isn't worth reading.
+///
+///A client waiting on a `100 Continue` shouldn't be made to send its body, so that's only read once
+///the refusal's gone out. It can still send it anyway, or end a chunked one early, and carry on
+pub async fn discard_body(
+    body: Incoming,
+    headers: &HeaderMap,
+    max_bytes: usize,
+) -> Option<ServeBody> {
+    if expectation_is_supported(headers).is_none() {
+        return read_capped_body(body, headers, max_bytes)
+            .await
+            .ok()
+            .map(|_| empty_body());
+    }
+    check_content_length(headers, max_bytes).ok()?;
+
+    let (sent, refusal_sent) = oneshot::channel();
+    let headers = headers.clone();
+    tokio::task::spawn(async move {
+        //asking for the body any earlier would send the `100 Continue`
+        let _ = refusal_sent.await;
+        let read = tokio::time::timeout(
+            REFUSED_BODY_WAIT,
+            read_capped_body(body, &headers, max_bytes),
+        );
+        if !matches!(read.await, Ok(Ok(_))) {
+            debug!("Turned down body never came, closing the connection");
+        }
+    });
+    Some(Refusal { _sent: sent }.boxed_unsync())
+}
+
+///an empty body. hyper drops it just as it writes the response head, so `_sent` going says that has
+struct Refusal {
+    _sent: oneshot::Sender<()>,
+}
+
+impl Body for Refusal {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Poll::Ready(None)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        true
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serve::{empty_with_code, full_body, ServeBody};
+    use hyper::{server::conn::http1, service::service_fn, Request, Response};
+    use hyper_util::rt::TokioIo;
+    use std::{convert::Infallible, net::SocketAddr, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    const MAX: usize = 1024;
+
+    async fn handler(req: Request<Incoming>) -> Result<Response<ServeBody>, Infallible> {
+        if expectation_is_supported(req.headers()) == Some(false) {
+            return Ok(empty_with_code(StatusCode::EXPECTATION_FAILED).unwrap());
+        }
+        if req.headers().get(header::AUTHORIZATION).is_none() {
+            return Ok(empty_with_code(StatusCode::UNAUTHORIZED).unwrap());
+        }
+
+        let (parts, body) = req.into_parts();
+        Ok(match read_capped_body(body, &parts.headers, MAX).await {
+            Ok(bytes) => Response::new(full_body(bytes)),
+            Err(code) => empty_with_code(code).unwrap(),
+        })
+    }
+
+    async fn start_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::task::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service_fn(handler))
+                        .await;
+                });
+            }
+        });
+        addr
+    }
+
+    ///reads until the end of the headers of the next response
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = vec![];
+        let mut byte = [0_u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut byte))
+                .await
+                .expect("timed out waiting for response")
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_oversized_expect_is_rejected_before_body() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream
+            .write_all(
+                b"POST /reload HTTP/1.1\r\nHost: test\r\nAuthorization: Bearer x\r\nExpect: 100-continue\r\nContent-Length: 10485760\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        //we never send any of the body, so the only way to get a response is an early rejection
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 413"), "{head}");
+        assert!(!head.contains("100 Continue"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_unauthorised_expect_is_rejected_before_body() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream
+            .write_all(
+                b"POST /reload HTTP/1.1\r\nHost: test\r\nExpect: 100-continue\r\nContent-Length: 10\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 401"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_expectation_fails() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream
+            .write_all(
+                b"POST /reload HTTP/1.1\r\nHost: test\r\nAuthorization: Bearer x\r\nExpect: something-else\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 417"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_accepted_expect_gets_continue() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream
+            .write_all(
+                b"POST /reload HTTP/1.1\r\nHost: test\r\nAuthorization: Bearer x\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 100"), "{head}");
+
+        stream.write_all(b"hello").await.unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_body_without_expect_still_works() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream
+            .write_all(
+                b"POST /reload HTTP/1.1\r\nHost: test\r\nAuthorization: Bearer x\r\nContent-Length: 5\r\n\r\nhello",
+            )
+            .await
+            .unwrap();
+
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+        assert!(head.contains("content-length: 5"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_chunked_body_over_limit_is_rejected() {
+        let addr = start_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let chunk = vec![b'a'; MAX + 1];
+        let mut request = format!(
+            "POST /reload HTTP/1.1\r\nHost: test\r\nAuthorization: Bearer x\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+            chunk.len()
+        )
+        .into_bytes();
+        request.extend(&chunk);
+        request.extend(b"\r\n0\r\n\r\n");
+        stream.write_all(&request).await.unwrap();
+
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 413"), "{head}");
+    }
+}
@@ -0,0 +1,189 @@
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 512;
+pub const DEFAULT_QUEUE_WAIT: Duration = Duration::from_millis(100);
+
+///why a request couldn't get a permit
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rejected {
+    ///every permit was still taken after waiting
+    Full,
+    ///we're shutting down
+    Closed,
+}
+
+///how many requests can be handled at once, with counts of how that's gone
+#[derive(Clone, Debug)]
+pub struct Concurrency {
+    semaphore: Arc<Semaphore>,
+    ///can change with [`Self::resize`]
+    max: Arc<AtomicUsize>,
+    ///how long a request waits for a permit before being turned away, to smooth out bursts
+    queue_wait: Duration,
+    acquired: Arc<AtomicU64>,
+    rejected: Arc<AtomicU64>,
+}
+
+///what `/__shove/status` says about [`Concurrency`]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConcurrencyReport {
+    pub max: usize,
+    pub available: usize,
+    pub acquired: u64,
+    pub rejected: u64,
+}
+
+impl Concurrency {
+    pub fn new(max: usize, queue_wait: Duration) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            max: Arc::new(AtomicUsize::new(max)),
+            queue_wait,
+            acquired: Arc::default(),
+            rejected: Arc::default(),
+        }
+    }
+
+    ///a permit to hold for as long as the request's being handled
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, Rejected> {
+        let res = match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Ok(permit),
+            Err(TryAcquireError::Closed) => Err(Rejected::Closed),
+            Err(TryAcquireError::NoPermits) if self.queue_wait.is_zero() => Err(Rejected::Full),
+            Err(TryAcquireError::NoPermits) => {
+                match tokio::time::timeout(self.queue_wait, self.semaphore.clone().acquire_owned())
+                    .await
+                {
+                    Ok(Ok(permit)) => Ok(permit),
+                    Ok(Err(_)) => Err(Rejected::Closed),
+                    Err(_) => Err(Rejected::Full),
+                }
+            }
+        };
+
+        match &res {
+            Ok(_) => {
+                self.acquired.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(Rejected::Full) => {
+                let total = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+                let max = self.max.load(Ordering::Relaxed);
+                warn!(%total, %max, queue_wait=?self.queue_wait, "Too many concurrent requests, turning one away");
+            }
+            Err(Rejected::Closed) => {}
+        }
+        res
+    }
+
+    ///changes how many requests can be handled at once, returning what it was before. Shrinking
+    ///takes back whatever permits are free now, and the rest as the requests holding them finish
+    pub fn resize(&self, max: usize) -> usize {
+        let old = self.max.swap(max, Ordering::SeqCst);
+        if max > old {
+            self.semaphore.add_permits(max - old);
+        } else if max < old {
+            let owed = old - max;
+            let forgotten = self.semaphore.forget_permits(owed);
+            if forgotten < owed {
+                let semaphore = self.semaphore.clone();
+                tokio::task::spawn(async move {
+                    //only fails once we're shutting down, when it doesn't matter
+                    let owed = (owed - forgotten).try_into().unwrap_or(u32::MAX);
+                    if let Ok(permits) = semaphore.acquire_many_owned(owed).await {
+                        permits.forget();
+                    }
+                });
+            }
+        }
+        old
+    }
+
+    pub fn report(&self) -> ConcurrencyReport {
+        ConcurrencyReport {
+            max: self.max.load(Ordering::Relaxed),
+            available: self.semaphore.available_permits(),
+            acquired: self.acquired.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rejects_straight_away_without_a_wait() {
+        let concurrency = Concurrency::new(1, Duration::ZERO);
+        let held = concurrency.acquire().await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        assert_eq!(concurrency.acquire().await.unwrap_err(), Rejected::Full);
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        drop(held);
+        drop(concurrency.acquire().await.unwrap());
+        assert_eq!(
+            concurrency.report(),
+            ConcurrencyReport {
+                max: 1,
+                available: 1,
+                acquired: 2,
+                rejected: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_waits_briefly_for_a_permit() {
+        let concurrency = Concurrency::new(1, Duration::from_millis(500));
+        let held = concurrency.acquire().await.unwrap();
+        tokio::task::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        });
+        let permit = concurrency.acquire().await.unwrap();
+
+        let impatient = Concurrency {
+            queue_wait: Duration::from_millis(20),
+            ..concurrency.clone()
+        };
+        assert_eq!(impatient.acquire().await.unwrap_err(), Rejected::Full);
+        assert_eq!(concurrency.report().rejected, 1);
+
+        drop(permit);
+        assert_eq!(concurrency.report().available, 1);
+    }
+
+    #[tokio::test]
+    async fn test_resizing_keeps_requests_in_flight() {
+        let concurrency = Concurrency::new(2, Duration::ZERO);
+        let first = concurrency.acquire().await.unwrap();
+        let second = concurrency.acquire().await.unwrap();
+
+        assert_eq!(concurrency.resize(3), 2);
+        let third = concurrency.acquire().await.unwrap();
+        assert_eq!(concurrency.acquire().await.unwrap_err(), Rejected::Full);
+
+        //all three are still being handled, so the permits come back as they finish
+        assert_eq!(concurrency.resize(1), 3);
+        assert_eq!(concurrency.report().max, 1);
+        drop(first);
+        drop(second);
+        tokio::task::yield_now().await;
+        assert_eq!(concurrency.report().available, 0);
+        assert_eq!(concurrency.acquire().await.unwrap_err(), Rejected::Full);
+
+        drop(third);
+        drop(concurrency.acquire().await.unwrap());
+        assert_eq!(concurrency.report().available, 1);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::{
+    config::{AuthConfig, BucketConfig, ConfigError, EnvReader},
+    protect::backoff::BackoffConfig,
+    serve::{
+        bandwidth::DEFAULT_BANDWIDTH_MAX_PATHS,
+        health::DEFAULT_HEALTHCHECK_FAILURES,
+        journal::DEFAULT_JOURNAL_SIZE,
+        limits::Limits,
+        listen::Listeners,
+        livereload::{DEFAULT_MAX_LIVERELOAD_CLIENTS, DEFAULT_WS_CLOSE_TIMEOUT},
+        markdown::RenderMarkdown,
+        pages::{CaseInsensitivePaths, IndexFiles, TrailingSlash, DEFAULT_CACHE_MAX_BYTES},
+        proxy::TrustedProxies,
+        resize::Resizing,
+        sites::SiteSettings,
+        tls::TlsConfig,
+        webhook::WebhookTokens,
+    },
+    store::timeout::DEFAULT_STORE_TIMEOUT,
+};
+use std::{num::NonZeroU32, path::PathBuf, time::Duration};
+
+const DEFAULT_PORT: u16 = 8080;
+
+///where pages are served from
+#[derive(Clone, Debug)]
+pub enum Storage {
+    Bucket {
+        bucket: BucketConfig,
+        auth: AuthConfig,
+    },
+    ///a directory on disk, with `serve --local` - no auth, and reloaded whenever a file changes
+    Local(PathBuf),
+}
+
+///everything `serve` reads from the environment, all checked before anything starts
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    pub storage: Storage,
+    pub port: u16,
+    ///where to listen instead of `port`, if anywhere
+    pub listen: Listeners,
+    ///`None` unless shove's terminating TLS itself, in which case plain HTTP gets sent to it
+    pub tls: Option<TlsConfig>,
+    ///`RUST_LOG`, which [`setup`](crate::setup) has already read - it's only here for SIGHUP
+    pub log_filter: Option<String>,
+    pub sentry_dsn: Option<sentry::types::Dsn>,
+    pub tigris_tokens: Option<WebhookTokens>,
+    pub admin_token: Option<String>,
+    ///whether `/__shove/version` needs the admin token
+    pub protect_version: bool,
+    pub drain_exit_after: Option<Duration>,
+    pub ws_close_timeout: Duration,
+    ///past this, live-reload upgrades get a 503
+    pub max_livereload_clients: usize,
+    pub audit_log: bool,
+    pub livereload_inject: bool,
+    ///add `; charset=utf-8` to text-like content types without a charset
+    pub default_charset: bool,
+    ///`None` unless `RESIZE_IMAGES` is on
+    pub resizing: Option<Resizing>,
+    ///make up `/sitemap.xml` & `/robots.txt` for sites without them
+    pub generate_sitemap: bool,
+    ///where URLs in generated sitemaps point, rather than whichever host asked first
+    pub canonical_host: Option<String>,
+    ///the prefix a proxy forwards every request under, like `/docs`. Never ends with a `/`
+    pub base_path: Option<String>,
+    pub trailing_slash: TrailingSlash,
+    ///how many paths get their own bandwidth count each day, before the rest are lumped together
+    pub bandwidth_max_paths: usize,
+    ///how much of the cache needs to be warm before `/readycheck` passes, `None` to wait for all of it
+    pub ready_fraction: Option<f64>,
+    ///HEAD the bucket from `/healthcheck` if it hasn't been recently
+    pub healthcheck_probe: bool,
+    ///how many store operations in a row need to fail before `/healthcheck` does
+    pub healthcheck_failures: NonZeroU32,
+    ///how long anything asked of the store gets before it's given up on
+    pub store_timeout: Duration,
+    ///`None` if the journal is off
+    pub journal_size: Option<usize>,
+    pub limits: Limits,
+    pub trusted_proxies: TrustedProxies,
+    pub backoff: BackoffConfig,
+    pub site_settings: SiteSettings,
+}
+
+impl Config {
+    ///with `local`, none of the bucket's variables are needed
+    pub fn read(env: &mut EnvReader, local: Option<PathBuf>) -> Self {
+        let storage = match local {
+            Some(dir) => Storage::Local(dir),
+            None => {
+                let bucket = BucketConfig::read(env);
+                let auth = AuthConfig::read(env, &bucket);
+                Storage::Bucket { bucket, auth }
+            }
+        };
+
+        let tigris_tokens = env.optional("TIGRIS_TOKEN").and_then(|x| {
+            let tokens = WebhookTokens::new(&x);
+            if tokens.is_none() {
+                env.problem("TIGRIS_TOKEN is set, but doesn't have any tokens in it");
+            }
+            tokens
+        });
+        let ready_fraction = env.parsed("READY_FRACTION").filter(|x: &f64| {
+            let in_range = (0.0..=1.0).contains(x);
+            if !in_range {
+                env.problem(format!(
+                    "READY_FRACTION is {x}, but should be between 0 and 1"
+                ));
+            }
+            in_range
+        });
+        let base_path = env.optional("BASE_PATH").and_then(|x| {
+            let trimmed = x.trim_end_matches('/');
+            let valid = x.starts_with('/')
+                && trimmed
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"/-._~".contains(&b));
+            if !valid {
+                env.problem(format!(
+                    "BASE_PATH is {x:?}, but should be a path like /docs"
+                ));
+                return None;
+            }
+            (!trimmed.is_empty()).then(|| trimmed.to_string())
+        });
+        let tls = TlsConfig::read(env);
+        if tls.is_some() && matches!(storage, Storage::Local(_)) {
+            env.problem("TLS_DOMAIN is set, but certificates are kept in the bucket, so it can't be used with --local");
+        }
+        let admin_token = env.optional("ADMIN_TOKEN");
+        let protect_version = env.flag("PROTECT_VERSION");
+        if protect_version && admin_token.is_none() {
+            env.problem("PROTECT_VERSION is set, but there's no ADMIN_TOKEN to protect it with");
+        }
+        let journal = env.flag("REQUEST_JOURNAL");
+        let journal_size = env
+            .parsed("REQUEST_JOURNAL_SIZE")
+            .unwrap_or(DEFAULT_JOURNAL_SIZE);
+
+        Self {
+            port: env.parsed("PORT").unwrap_or(DEFAULT_PORT),
+            listen: Listeners::read(env),
+            tls,
+            log_filter: env.optional("RUST_LOG"),
+            sentry_dsn: env.parsed("SENTRY_DSN"),
+            tigris_tokens,
+            admin_token,
+            protect_version,
+            drain_exit_after: env.parsed("DRAIN_EXIT_AFTER_SECS").map(Duration::from_secs),
+            ws_close_timeout: env
+                .parsed("WS_CLOSE_TIMEOUT_SECS")
+                .map_or(DEFAULT_WS_CLOSE_TIMEOUT, Duration::from_secs),
+            max_livereload_clients: env
+                .parsed("MAX_LIVERELOAD_CLIENTS")
+                .unwrap_or(DEFAULT_MAX_LIVERELOAD_CLIENTS),
+            audit_log: env.flag("AUDIT_LOG"),
+            livereload_inject: env.flag("LIVERELOAD_INJECT"),
+            default_charset: !env.flag("NO_DEFAULT_CHARSET"),
+            resizing: Resizing::read(env),
+            generate_sitemap: env.flag("GENERATE_SITEMAP"),
+            canonical_host: env.optional("CANONICAL_HOST"),
+            base_path,
+            trailing_slash: TrailingSlash::read(env),
+            bandwidth_max_paths: env
+                .parsed("BANDWIDTH_MAX_PATHS")
+                .unwrap_or(DEFAULT_BANDWIDTH_MAX_PATHS),
+            ready_fraction,
+            healthcheck_probe: env.flag("HEALTHCHECK_PROBE"),
+            healthcheck_failures: env
+                .parsed("HEALTHCHECK_FAILURES")
+                .unwrap_or(DEFAULT_HEALTHCHECK_FAILURES),
+            store_timeout: env
+                .parsed("S3_TIMEOUT_SECS")
+                .map_or(DEFAULT_STORE_TIMEOUT, Duration::from_secs),
+            journal_size: journal.then_some(journal_size),
+            limits: Limits::read(env),
+            trusted_proxies: TrustedProxies::read(env),
+            backoff: BackoffConfig::read(env),
+            site_settings: SiteSettings {
+                cache_max_bytes: env
+                    .parsed("CACHE_MAX_BYTES")
+                    .unwrap_or(DEFAULT_CACHE_MAX_BYTES),
+                max_cacheable_bytes: env.parsed("MAX_CACHEABLE_BYTES"),
+                smart_cache_defaults: env.flag("SMART_CACHE_DEFAULTS"),
+                case_insensitive_paths: CaseInsensitivePaths::read(env),
+                index_files: IndexFiles::read(env),
+                cache_admit_max_bytes: env.parsed("CACHE_ADMIT_MAX_BYTES"),
+                render_markdown: RenderMarkdown::read(env),
+            },
+            storage,
+        }
+    }
+
+    ///everything else at its default
+    pub fn with_storage(storage: Storage) -> Self {
+        //a local directory means none of the bucket's variables are looked for
+        let mut env = EnvReader::new(|_| None);
+        let config = Self::read(&mut env, Some(PathBuf::new()));
+        Self { storage, ..config }
+    }
+
+    pub fn from_env(local: Option<PathBuf>) -> Result<Self, ConfigError> {
+        let mut env = EnvReader::from_env();
+        let config = Self::read(&mut env, local);
+        env.finish(config)
+    }
+
+    ///with anything changed in the `.env` file since starting
+    pub fn reread(local: Option<PathBuf>) -> Result<Self, ConfigError> {
+        let mut env = EnvReader::from_env_reread();
+        let config = Self::read(&mut env, local);
+        env.finish(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, path::Path};
+
+    fn read(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        read_with_local(vars, None)
+    }
+
+    fn read_with_local(
+        vars: &[(&str, &str)],
+        local: Option<PathBuf>,
+    ) -> Result<Config, ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut env = EnvReader::new(move |name| vars.get(name).cloned());
+        let config = Config::read(&mut env, local);
+        env.finish(config)
+    }
+
+    const BUCKET: [(&str, &str); 5] = [
+        ("AWS_ACCESS_KEY_ID", "key"),
+        ("AWS_SECRET_ACCESS_KEY", "secret"),
+        ("BUCKET_NAME", "bucket"),
+        ("AWS_ENDPOINT_URL_S3", "http://127.0.0.1:1"),
+        ("AUTH_ENCRYPTION_KEY", "hunter2"),
+    ];
+
+    #[test]
+    fn test_serving_needs_the_auth_key() {
+        let config = read(&BUCKET).unwrap();
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.journal_size, None);
+        assert!(config.tigris_tokens.is_none());
+
+        let problems = read(&BUCKET[..4]).unwrap_err().problems;
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("AUTH_ENCRYPTION_KEY"));
+    }
+
+    #[test]
+    fn test_local_needs_no_bucket() {
+        let config = read_with_local(&[("PORT", "3000")], Some(PathBuf::from("public"))).unwrap();
+        assert_eq!(config.port, 3000);
+        assert!(matches!(config.storage, Storage::Local(dir) if dir == Path::new("public")));
+    }
+
+    #[test]
+    fn test_tls_needs_a_bucket() {
+        let tls = [
+            ("TLS_DOMAIN", "example.com"),
+            ("ACME_EMAIL", "admin@example.com"),
+        ];
+        let mut vars = BUCKET.to_vec();
+        vars.extend(tls);
+        assert!(read(&vars).unwrap().tls.is_some());
+
+        let problems = read_with_local(&tls, Some(PathBuf::from("public")))
+            .unwrap_err()
+            .problems;
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("TLS_DOMAIN"));
+    }
+
+    #[test]
+    fn test_invalid_values_are_all_reported() {
+        let mut vars = BUCKET.to_vec();
+        vars.extend([
+            ("PORT", "http"),
+            ("IDLE_TIMEOUT_SECS", "-1"),
+            ("TRAILING_SLASH", "sometimes"),
+            ("REQUEST_JOURNAL", "1"),
+            ("SENTRY_DSN", "not a dsn"),
+            ("READY_FRACTION", "2"),
+            ("PROTECT_VERSION", "true"),
+            ("HEALTHCHECK_FAILURES", "0"),
+            ("BASE_PATH", "docs"),
+            ("CASE_INSENSITIVE_PATHS", "yes"),
+            ("INDEX_FILES", "index.html,../index.html"),
+            ("LISTEN", "0.0.0.0:80=everyone"),
+        ]);
+        let problems = read(&vars).unwrap_err().problems;
+        let mut names: Vec<&str> = problems
+            .iter()
+            .map(|x| x.split_whitespace().next().unwrap())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            vec![
+                "BASE_PATH",
+                "CASE_INSENSITIVE_PATHS",
+                "HEALTHCHECK_FAILURES",
+                "IDLE_TIMEOUT_SECS",
+                "INDEX_FILES",
+                "LISTEN",
+                "PORT",
+                "PROTECT_VERSION",
+                "READY_FRACTION",
+                "REQUEST_JOURNAL",
+                "SENTRY_DSN",
+                "TRAILING_SLASH"
+            ]
+        );
+    }
+}
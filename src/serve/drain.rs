@@ -0,0 +1,77 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+///tracks whether we've been told to stop taking on new work, ready for a load balancer to take us out of rotation
+#[derive(Clone, Debug, Default)]
+pub struct Drainer {
+    draining: Arc<AtomicBool>,
+    exit: Arc<Notify>,
+}
+
+impl Drainer {
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    ///returns whether this call was the one that started the drain
+    pub fn start_drain(&self) -> bool {
+        !self.draining.swap(true, Ordering::SeqCst)
+    }
+
+    ///requests a graceful shutdown after `after`, in case no SIGTERM ever turns up
+    pub fn schedule_exit(&self, after: Duration) {
+        let exit = self.exit.clone();
+        tokio::task::spawn(async move {
+            tokio::time::sleep(after).await;
+            warn!(?after, "Drain exit delay elapsed, shutting down");
+            //notify_one stores a permit, so this still works if nothing is waiting yet
+            exit.notify_one();
+        });
+    }
+
+    pub async fn exit_requested(&self) {
+        self.exit.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_is_idempotent() {
+        let drainer = Drainer::default();
+        assert!(!drainer.is_draining());
+
+        assert!(drainer.start_drain());
+        assert!(drainer.is_draining());
+
+        assert!(!drainer.start_drain());
+        assert!(drainer.is_draining());
+    }
+
+    #[test]
+    fn test_drain_is_shared_between_clones() {
+        let drainer = Drainer::default();
+        let cloned = drainer.clone();
+
+        cloned.start_drain();
+        assert!(drainer.is_draining());
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_exit_fires() {
+        let drainer = Drainer::default();
+        drainer.schedule_exit(Duration::from_millis(10));
+
+        tokio::time::timeout(Duration::from_secs(5), drainer.exit_requested())
+            .await
+            .expect("exit should have been requested");
+    }
+}
@@ -0,0 +1,143 @@
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use hyper::{header, HeaderMap};
+use std::io::Read;
+
+///the most a page gets decompressed to for a client that can't take it compressed. Anything that
+///would go past this is treated like it can't be decoded at all
+pub const MAX_DECODED_BYTES: usize = 256 * 1024 * 1024;
+
+///`gzip` for `x-gzip`, which some older clients still send, and lowercase otherwise
+fn canonical(coding: &str) -> String {
+    let coding = coding.trim().to_ascii_lowercase();
+    match coding.as_str() {
+        "x-gzip" => "gzip".to_string(),
+        "x-compress" => "compress".to_string(),
+        _ => coding,
+    }
+}
+
+///whether the client's `Accept-Encoding` lets it have bytes in `encoding`. Without one only identity
+///counts, as plenty of simple clients leave it off and then can't read anything compressed
+pub fn accepts(headers: &HeaderMap, encoding: &str) -> bool {
+    let encoding = canonical(encoding);
+    if encoding == "identity" {
+        return true;
+    }
+
+    let mut wildcard = None;
+    for accept_encoding in headers.get_all(header::ACCEPT_ENCODING) {
+        let Ok(accept_encoding) = accept_encoding.to_str() else {
+            continue;
+        };
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';');
+            let coding = canonical(parts.next().unwrap_or_default());
+            let q = parts
+                .filter_map(|x| x.trim().strip_prefix("q="))
+                .find_map(|x| x.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if coding == encoding {
+                return q > 0.0;
+            }
+            if coding == "*" {
+                wildcard = Some(q > 0.0);
+            }
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+///undoes a `Content-Encoding` of `gzip`, `deflate` or `br`. `None` for anything else, and for bytes
+///that turn out not to be what they say. This can be a lot of work, so isn't for async threads
+pub fn decode(encoding: &str, bytes: &[u8]) -> Option<Vec<u8>> {
+    match canonical(encoding).as_str() {
+        //which checks the CRC & length in the trailer
+        "gzip" => read_capped(MultiGzDecoder::new(bytes)),
+        "br" => read_capped(brotli_decompressor::Decompressor::new(bytes, 4096)),
+        //meant to be zlib, but some servers have always sent it raw
+        "deflate" => read_capped(ZlibDecoder::new(bytes))
+            .or_else(|| read_capped(DeflateDecoder::new(bytes))),
+        _ => None,
+    }
+}
+
+///everything `decoder` gives, unless that'd be more than [`MAX_DECODED_BYTES`]
+fn read_capped(decoder: impl Read) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    //one past the limit, to tell going over it from landing exactly on it
+    decoder
+        .take(MAX_DECODED_BYTES as u64 + 1)
+        .read_to_end(&mut decoded)
+        .ok()?;
+    (decoded.len() <= MAX_DECODED_BYTES).then_some(decoded)
+}
+
+///`bytes` as a gzip file, for testing pages that were uploaded compressed
+#[cfg(test)]
+pub fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut gzipped = vec![];
+    flate2::read::GzEncoder::new(bytes, flate2::Compression::default())
+        .read_to_end(&mut gzipped)
+        .unwrap();
+    gzipped
+}
+
+///`bytes` as brotli, for the same
+#[cfg(test)]
+pub fn brotli(bytes: &[u8]) -> Vec<u8> {
+    let mut compressed = vec![];
+    brotli::BrotliCompress(&mut &bytes[..], &mut compressed, &Default::default()).unwrap();
+    compressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts() {
+        let with = |accept_encoding: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, accept_encoding.parse().unwrap());
+            headers
+        };
+
+        assert!(!accepts(&HeaderMap::new(), "gzip"));
+        assert!(accepts(&HeaderMap::new(), "identity"));
+        assert!(accepts(&with("gzip, deflate"), "gzip"));
+        assert!(accepts(&with("x-gzip"), "GZIP"));
+        assert!(!accepts(&with("gzip, deflate"), "br"));
+        assert!(!accepts(&with("br;q=0, *"), "br"));
+        assert!(accepts(&with("br;q=0.5"), "br"));
+        assert!(accepts(&with("*"), "br"));
+        assert!(!accepts(&with("*;q=0"), "gzip"));
+    }
+
+    #[test]
+    fn test_decode() {
+        let page = b"<html>hello hello hello</html>".repeat(50);
+        assert_eq!(decode("gzip", &gzip(&page)), Some(page.clone()));
+        assert_eq!(decode("x-gzip", &gzip(&page)), Some(page.clone()));
+        let mut zlib = vec![];
+        flate2::read::ZlibEncoder::new(&page[..], flate2::Compression::default())
+            .read_to_end(&mut zlib)
+            .unwrap();
+        assert_eq!(decode("deflate", &zlib), Some(page.clone()));
+        let mut raw = vec![];
+        flate2::read::DeflateEncoder::new(&page[..], flate2::Compression::default())
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_eq!(decode("deflate", &raw), Some(page.clone()));
+
+        let mut corrupted = gzip(&page);
+        let crc = corrupted.len() - 8;
+        corrupted[crc] ^= 1;
+        assert_eq!(decode("gzip", &corrupted), None);
+        assert_eq!(decode("gzip", &page), None);
+
+        assert_eq!(decode("br", &brotli(&page)), Some(page.clone()));
+        let compressed = brotli(&page);
+        assert_eq!(decode("br", &compressed[..compressed.len() / 2]), None);
+        assert_eq!(decode("br", b"not really brotli"), None);
+    }
+}
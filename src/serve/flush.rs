@@ -0,0 +1,39 @@
+use crate::serve::background::Background;
+use std::{future::Future, time::Duration};
+
+///runs `flush` every `interval`, and once more when `background` starts shutting down, which waits
+///for it. Failures are only logged, as none of this is worth stopping serving for
+pub fn start_flushing<F, Fut>(
+    what: &'static str,
+    interval: Duration,
+    background: &Background,
+    flush: F,
+) where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = color_eyre::Result<()>> + Send,
+{
+    let stopping = background.stopping();
+
+    background.spawn(what, async move {
+        let mut flush_interval = tokio::time::interval(interval);
+        //the first tick is straight away, and there's nothing new to write yet
+        flush_interval.tick().await;
+
+        let mut stopping = std::pin::pin!(stopping);
+        loop {
+            tokio::select! {
+                () = &mut stopping => break,
+                _ = flush_interval.tick() => {
+                    if let Err(e) = flush().await {
+                        warn!(?e, %what, "Error flushing");
+                    }
+                }
+            }
+        }
+
+        info!(%what, "Stop signal received for flushing");
+        if let Err(e) = flush().await {
+            error!(?e, %what, "Error flushing on stop");
+        }
+    });
+}
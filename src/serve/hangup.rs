@@ -0,0 +1,128 @@
+use crate::serve::{background::Background, state::State, telemetry::spawn_tagged};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+///one reload at a time, with however many get asked for while it's going folded into one more
+///after it
+#[derive(Clone, Default)]
+struct Coalescer {
+    running: Arc<AtomicBool>,
+    again: Arc<AtomicBool>,
+    ///what the reloads run in, so shutting down waits for them
+    background: Background,
+}
+
+impl Coalescer {
+    ///`false` if one was already going, which will now go again once it's done, or if we're shutting
+    ///down
+    fn run<F, Fut>(&self, reload: F) -> bool
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        self.again.store(true, Ordering::SeqCst);
+        if self.running.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        let this = self.clone();
+        let started = self.background.spawn("hangup", async move {
+            loop {
+                this.again.store(false, Ordering::SeqCst);
+                reload().await;
+                this.running.store(false, Ordering::SeqCst);
+                //anything asked for after this point starts its own
+                if !this.again.load(Ordering::SeqCst) || this.running.swap(true, Ordering::SeqCst) {
+                    break;
+                }
+            }
+        });
+        if started.is_none() {
+            self.running.store(false, Ordering::SeqCst);
+        }
+        started.is_some()
+    }
+}
+
+///reloads the settings & everything in the store on every SIGHUP. Unlike SIGTERM, it doesn't stop
+///anything, and it's only listened for on unix
+pub fn listen_for_hangups(state: State) -> std::io::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    let coalescer = Coalescer {
+        background: state.background(),
+        ..Coalescer::default()
+    };
+    spawn_tagged("hangup", async move {
+        while hangups.recv().await.is_some() {
+            if coalescer.background.is_stopping() {
+                info!("SIGHUP received while shutting down, so not reloading");
+                continue;
+            }
+            let state = state.clone();
+            let started = coalescer.run(move || {
+                let state = state.clone();
+                async move {
+                    info!("Reloading from SIGHUP");
+                    state.reload_everything().await;
+                    info!("Finished reloading from SIGHUP");
+                }
+            });
+            if !started {
+                info!("SIGHUP received while already reloading, so going again once that's done");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+    use tokio::sync::Semaphore;
+
+    #[tokio::test]
+    async fn test_overlapping_reloads_coalesce() {
+        let coalescer = Coalescer::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Semaphore::new(0));
+
+        let reload = {
+            let runs = runs.clone();
+            let release = release.clone();
+            move || {
+                let runs = runs.clone();
+                let release = release.clone();
+                async move {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    release.acquire().await.unwrap().forget();
+                }
+            }
+        };
+
+        assert!(coalescer.run(reload.clone()));
+        while runs.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        for _ in 0..3 {
+            assert!(!coalescer.run(reload.clone()));
+        }
+
+        //the first, then one more for all three that came in during it
+        release.add_permits(2);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert!(!coalescer.running.load(Ordering::SeqCst));
+
+        assert!(coalescer.run(reload));
+        release.add_permits(1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::{
+    error::ShoveError,
+    releases::now_ms,
+    s3::{StaleConfig, UPLOAD_DATA_LOCATION},
+    store::{ByteStream, Fetched, Head, ObjectStore, Store},
+};
+use async_trait::async_trait;
+use hyper::StatusCode;
+use serde::Serialize;
+use std::{
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+pub const DEFAULT_HEALTHCHECK_FAILURES: NonZeroU32 = NonZeroU32::new(3).unwrap();
+
+///with nothing asked of the store for this long, every request has been a cache hit, so we can't
+///tell whether it's still reachable
+const QUIET_AFTER: Duration = Duration::from_secs(5 * 60);
+///how often `HEALTHCHECK_PROBE` actually asks the store
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+///0 for never
+#[derive(Default)]
+struct Observed {
+    last_success_ms: AtomicU64,
+    last_failure_ms: AtomicU64,
+    consecutive_failures: AtomicU32,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StoreStatus {
+    Ok,
+    ///nothing has been asked of the store recently
+    Quiet,
+    Failing,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub status: StoreStatus,
+    ///`None` if nothing has worked since starting
+    pub last_success_ms: Option<u64>,
+    pub consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<&'static str>,
+    ///config that couldn't be read, so older versions are still being used
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stale_config: Vec<StaleConfig>,
+}
+
+impl HealthReport {
+    ///only failing takes us out of rotation - a quiet store is still worth serving from
+    pub fn status_code(&self) -> StatusCode {
+        match self.status {
+            StoreStatus::Ok | StoreStatus::Quiet => StatusCode::OK,
+            StoreStatus::Failing => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+///how the store's been doing lately. Clones share the same counts
+#[derive(Clone)]
+pub struct StoreHealth {
+    observed: Arc<Observed>,
+    ///how many failures in a row before the healthcheck fails
+    unhealthy_after: u32,
+    last_probe: Arc<Mutex<Option<Instant>>>,
+}
+
+impl StoreHealth {
+    pub fn new(unhealthy_after: NonZeroU32) -> Self {
+        Self {
+            observed: Arc::default(),
+            unhealthy_after: unhealthy_after.get(),
+            last_probe: Arc::default(),
+        }
+    }
+
+    ///`store`, but with every operation counted towards the health
+    pub fn monitor(&self, store: Store) -> Store {
+        Arc::new(MonitoredStore {
+            inner: store,
+            health: self.clone(),
+        })
+    }
+
+    fn observe<T, E>(&self, res: &Result<T, E>) {
+        let now = now_ms();
+        if res.is_ok() {
+            self.observed.last_success_ms.store(now, Ordering::Relaxed);
+            self.observed
+                .consecutive_failures
+                .store(0, Ordering::Relaxed);
+        } else {
+            self.observed.last_failure_ms.store(now, Ordering::Relaxed);
+            self.observed
+                .consecutive_failures
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    ///a cheap HEAD, at most once every [`PROBE_INTERVAL`] however often it's asked for. Only worth it
+    ///if `store` is [monitored](Self::monitor), as that's where the result gets counted
+    pub async fn probe(&self, store: &dyn ObjectStore) {
+        let mut last_probe = self.last_probe.lock().await;
+        if last_probe.is_some_and(|x| x.elapsed() < PROBE_INTERVAL) {
+            return;
+        }
+        *last_probe = Some(Instant::now());
+
+        if let Err(e) = store.head(UPLOAD_DATA_LOCATION).await {
+            warn!(?e, "Healthcheck probe failed");
+        }
+    }
+
+    pub fn report(&self) -> HealthReport {
+        self.report_at(now_ms())
+    }
+
+    fn report_at(&self, now_ms: u64) -> HealthReport {
+        let last_success_ms = self.observed.last_success_ms.load(Ordering::Relaxed);
+        let last_failure_ms = self.observed.last_failure_ms.load(Ordering::Relaxed);
+        let consecutive_failures = self.observed.consecutive_failures.load(Ordering::Relaxed);
+
+        let last_operation_ms = last_success_ms.max(last_failure_ms);
+        let quiet = last_operation_ms == 0
+            || now_ms.saturating_sub(last_operation_ms) > QUIET_AFTER.as_millis() as u64;
+
+        let (status, warning) = if consecutive_failures >= self.unhealthy_after {
+            (StoreStatus::Failing, None)
+        } else if quiet {
+            (
+                StoreStatus::Quiet,
+                Some("nothing has been asked of the store recently"),
+            )
+        } else {
+            (StoreStatus::Ok, None)
+        };
+
+        HealthReport {
+            status,
+            last_success_ms: (last_success_ms != 0).then_some(last_success_ms),
+            consecutive_failures,
+            warning,
+            stale_config: vec![],
+        }
+    }
+}
+
+///passes everything on to `inner`, keeping track of whether it worked
+struct MonitoredStore {
+    inner: Store,
+    health: StoreHealth,
+}
+
+impl MonitoredStore {
+    fn observed<T>(&self, res: Result<T, ShoveError>) -> Result<T, ShoveError> {
+        self.health.observe(&res);
+        res
+    }
+}
+
+#[async_trait]
+impl ObjectStore for MonitoredStore {
+    async fn get(&self, key: &str, if_none_match: Option<&str>) -> Result<Fetched, ShoveError> {
+        self.observed(self.inner.get(key, if_none_match).await)
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<Head>, ShoveError> {
+        self.observed(self.inner.head(key).await)
+    }
+
+    async fn stream(&self, key: &str) -> Result<ByteStream, ShoveError> {
+        self.observed(self.inner.stream(key).await)
+    }
+
+    async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<(), ShoveError> {
+        self.observed(self.inner.put(key, bytes, content_type).await)
+    }
+
+    async fn put_with_cache_control(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        cache_control: Option<&str>,
+    ) -> Result<(), ShoveError> {
+        self.observed(
+            self.inner
+                .put_with_cache_control(key, bytes, content_type, cache_control)
+                .await,
+        )
+    }
+
+    async fn put_if_unchanged(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        etag: Option<&str>,
+    ) -> Result<bool, ShoveError> {
+        self.observed(
+            self.inner
+                .put_if_unchanged(key, bytes, content_type, etag)
+                .await,
+        )
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ShoveError> {
+        self.observed(self.inner.delete(key).await)
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ShoveError> {
+        self.observed(self.inner.copy(from, to).await)
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ShoveError> {
+        self.observed(self.inner.list(prefix).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::InMemoryBucket;
+    use color_eyre::eyre::eyre;
+
+    #[test]
+    fn test_failing_after_enough_failures_in_a_row() {
+        let health = StoreHealth::new(NonZeroU32::new(2).unwrap());
+        let report = health.report();
+        assert_eq!(report.status, StoreStatus::Quiet);
+        assert_eq!(report.last_success_ms, None);
+
+        health.observe::<(), ()>(&Ok(()));
+        assert_eq!(health.report().status, StoreStatus::Ok);
+
+        health.observe::<(), _>(&Err(eyre!("expired")));
+        assert_eq!(health.report().status, StoreStatus::Ok);
+        health.observe::<(), _>(&Err(eyre!("expired")));
+        let report = health.report();
+        assert_eq!(report.status, StoreStatus::Failing);
+        assert_eq!(report.consecutive_failures, 2);
+        assert_eq!(report.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+
+        health.observe::<(), ()>(&Ok(()));
+        assert_eq!(health.report().consecutive_failures, 0);
+        let later = now_ms() + QUIET_AFTER.as_millis() as u64 + 1;
+        let report = health.report_at(later);
+        assert_eq!(report.status, StoreStatus::Quiet);
+        assert_eq!(report.status_code(), StatusCode::OK);
+        assert!(report.warning.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_probes_are_counted_but_not_repeated() {
+        let health = StoreHealth::new(DEFAULT_HEALTHCHECK_FAILURES);
+        let store = health.monitor(Arc::new(InMemoryBucket::new()));
+
+        health.probe(&*store).await;
+        let first = health.report();
+        assert_eq!(first.status, StoreStatus::Ok);
+
+        //a second probe straight away doesn't ask again, so the last success doesn't move
+        health.observed.last_success_ms.store(1, Ordering::Relaxed);
+        health.probe(&*store).await;
+        assert_eq!(health.report().last_success_ms, Some(1));
+    }
+}
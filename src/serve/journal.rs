@@ -0,0 +1,402 @@
+use crate::{
+    config::BucketConfig,
+    s3::get_bytes_or_default,
+    store::{ObjectStore, Store},
+};
+use comfy_table::Table;
+use hyper::{HeaderMap, Method, Uri, Version};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{
+    mpsc::{channel, error::TrySendError, Receiver, Sender},
+    RwLock,
+};
+use uuid::Uuid;
+
+pub const JOURNAL_LOCATION: &str = "journal.json";
+pub const DEFAULT_JOURNAL_SIZE: usize = 200;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-auth-token",
+];
+
+///the matched auth realm, stashed in request/response extensions so it can make it into the journal
+#[derive(Clone, Debug)]
+pub struct MatchedRealm(pub String);
+
+///whether a page came out of the cache, stashed in response extensions
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    ///not kept in the cache - streamed straight from S3 for being too big, or read in but too big to
+    ///admit or asked for by a crawler
+    Bypass,
+    NotFound,
+    ///the store couldn't be read, so an error was served instead
+    Unavailable,
+}
+
+///an error description for a response, stashed in response extensions
+#[derive(Clone, Debug)]
+pub struct ResponseError(pub String);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub request_id: Uuid,
+    pub started_at_ms: u128,
+    pub finished_at_ms: u128,
+    pub remote_addr: String,
+    ///who the request was for, which differs from `remote_addr` behind a trusted proxy
+    #[serde(default)]
+    pub client_ip: String,
+    pub request_line: String,
+    pub headers: Vec<(String, String)>,
+    pub status: Option<u16>,
+    pub matched_realm: Option<String>,
+    pub cache_status: Option<CacheStatus>,
+    pub error: Option<String>,
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+///headers with the values of anything credential-like swapped out
+fn scrub_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                "[scrubbed]".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+///everything we know about a request before it gets handled
+pub struct RequestContext {
+    request_id: Uuid,
+    started_at_ms: u128,
+    remote_addr: SocketAddr,
+    client_ip: IpAddr,
+    request_line: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestContext {
+    pub fn new(
+        method: &Method,
+        uri: &Uri,
+        version: Version,
+        headers: &HeaderMap,
+        remote_addr: SocketAddr,
+        client_ip: IpAddr,
+    ) -> Self {
+        Self {
+            request_id: Uuid::now_v7(),
+            started_at_ms: now_ms(),
+            remote_addr,
+            client_ip,
+            request_line: format!("{method} {uri} {version:?}"),
+            headers: scrub_headers(headers),
+        }
+    }
+
+    pub fn finish(
+        self,
+        status: Option<u16>,
+        matched_realm: Option<String>,
+        cache_status: Option<CacheStatus>,
+        error: Option<String>,
+    ) -> JournalEntry {
+        JournalEntry {
+            request_id: self.request_id,
+            started_at_ms: self.started_at_ms,
+            finished_at_ms: now_ms(),
+            remote_addr: self.remote_addr.to_string(),
+            client_ip: self.client_ip.to_string(),
+            request_line: self.request_line,
+            headers: self.headers,
+            status,
+            matched_realm,
+            cache_status,
+            error,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct StoredJournal {
+    pub dropped: u64,
+    pub entries: VecDeque<JournalEntry>,
+}
+
+enum JournalMessage {
+    Entry(Box<JournalEntry>),
+    Stop,
+}
+
+///a ring buffer of the last few requests that went wrong, periodically written to the bucket
+#[derive(Clone)]
+pub struct Journal {
+    tx: Sender<JournalMessage>,
+    entries: Arc<RwLock<VecDeque<JournalEntry>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Journal {
+    pub async fn new(bucket: Store, capacity: usize) -> color_eyre::Result<Self> {
+        let bytes = get_bytes_or_default(&bucket, JOURNAL_LOCATION).await?;
+        let stored: StoredJournal = if bytes.is_empty() {
+            StoredJournal::default()
+        } else {
+            serde_json::from_slice(&bytes)?
+        };
+
+        let mut existing = stored.entries;
+        while existing.len() > capacity {
+            existing.pop_front();
+        }
+
+        let entries = Arc::new(RwLock::new(existing));
+        let dropped = Arc::new(AtomicU64::new(stored.dropped));
+        let (tx, rx) = channel(capacity.max(1));
+
+        tokio::task::spawn(Self::write_entries(
+            rx,
+            bucket,
+            capacity,
+            entries.clone(),
+            dropped.clone(),
+        ));
+
+        Ok(Self {
+            tx,
+            entries,
+            dropped,
+        })
+    }
+
+    async fn write_entries(
+        mut rx: Receiver<JournalMessage>,
+        bucket: Store,
+        capacity: usize,
+        entries: Arc<RwLock<VecDeque<JournalEntry>>>,
+        dropped: Arc<AtomicU64>,
+    ) {
+        let mut dirty = false;
+        let mut flush_interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = rx.recv() => match msg {
+                    Some(JournalMessage::Entry(entry)) => {
+                        let mut entries = entries.write().await;
+                        if entries.len() >= capacity {
+                            entries.pop_front();
+                        }
+                        entries.push_back(*entry);
+                        dirty = true;
+                    }
+                    Some(JournalMessage::Stop) | None => {
+                        info!("Stop signal received for journal");
+                        break;
+                    }
+                },
+                _ = flush_interval.tick() => {
+                    if dirty {
+                        match Self::flush(&bucket, &entries, &dropped).await {
+                            Ok(()) => dirty = false,
+                            Err(e) => warn!(?e, "Error flushing request journal"),
+                        }
+                    }
+                }
+            }
+        }
+
+        if dirty && let Err(e) = Self::flush(&bucket, &entries, &dropped).await {
+            error!(?e, "Error flushing request journal on stop");
+        }
+    }
+
+    async fn flush(
+        bucket: &dyn ObjectStore,
+        entries: &RwLock<VecDeque<JournalEntry>>,
+        dropped: &AtomicU64,
+    ) -> color_eyre::Result<()> {
+        let stored = StoredJournal {
+            dropped: dropped.load(Ordering::Relaxed),
+            entries: entries.read().await.clone(),
+        };
+        let bytes = serde_json::to_vec(&stored)?;
+
+        bucket
+            .put(JOURNAL_LOCATION, &bytes, mime::JSON.as_str())
+            .await?;
+
+        trace!(len=%stored.entries.len(), "Flushed request journal");
+
+        Ok(())
+    }
+
+    ///never waits - if the writer has fallen behind, the entry is dropped and counted
+    pub fn record(&self, entry: JournalEntry) {
+        match self.tx.try_send(JournalMessage::Entry(Box::new(entry))) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(%dropped, "Request journal full, dropping entry");
+            }
+            Err(TrySendError::Closed(_)) => {
+                warn!("Request journal closed, dropping entry");
+            }
+        }
+    }
+
+    pub async fn snapshot(&self) -> StoredJournal {
+        StoredJournal {
+            dropped: self.dropped.load(Ordering::Relaxed),
+            entries: self.entries.read().await.clone(),
+        }
+    }
+
+    pub async fn send_stop(&self) {
+        let _ = self.tx.send(JournalMessage::Stop).await;
+    }
+}
+
+pub async fn journal(config: &BucketConfig) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let bytes = get_bytes_or_default(&bucket, JOURNAL_LOCATION).await?;
+    if bytes.is_empty() {
+        println!("No journal entries yet.");
+        return Ok(());
+    }
+
+    let stored: StoredJournal = serde_json::from_slice(&bytes)?;
+
+    for entry in &stored.entries {
+        let mut table = Table::new();
+        table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+        table.set_header(vec![
+            entry.request_id.to_string(),
+            entry.request_line.clone(),
+        ]);
+
+        table.add_row(vec!["Remote".to_string(), entry.remote_addr.clone()]);
+        if !entry.client_ip.is_empty() {
+            table.add_row(vec!["Client".to_string(), entry.client_ip.clone()]);
+        }
+        table.add_row(vec![
+            "Status".to_string(),
+            entry
+                .status
+                .map_or_else(|| "panicked".to_string(), |x| x.to_string()),
+        ]);
+        table.add_row(vec![
+            "Timing".to_string(),
+            format!(
+                "started {}ms, took {}ms",
+                entry.started_at_ms,
+                entry.finished_at_ms.saturating_sub(entry.started_at_ms)
+            ),
+        ]);
+        if let Some(realm) = &entry.matched_realm {
+            table.add_row(vec!["Realm".to_string(), realm.clone()]);
+        }
+        if let Some(cache_status) = &entry.cache_status {
+            table.add_row(vec!["Cache".to_string(), format!("{cache_status:?}")]);
+        }
+        if let Some(error) = &entry.error {
+            table.add_row(vec!["Error".to_string(), error.clone()]);
+        }
+        for (name, value) in &entry.headers {
+            table.add_row(vec![name.clone(), value.clone()]);
+        }
+
+        println!("{table}");
+    }
+
+    println!(
+        "{} entries, {} dropped",
+        stored.entries.len(),
+        stored.dropped
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{self, HeaderValue};
+
+    #[test]
+    fn test_sensitive_headers_are_scrubbed() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic c2VjcmV0"),
+        );
+        headers.insert(header::COOKIE, HeaderValue::from_static("session=abc"));
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("curl/8"));
+
+        let scrubbed = scrub_headers(&headers);
+
+        let get = |name: &str| {
+            scrubbed
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(get("authorization"), Some("[scrubbed]"));
+        assert_eq!(get("cookie"), Some("[scrubbed]"));
+        assert_eq!(get("user-agent"), Some("curl/8"));
+    }
+
+    #[test]
+    fn test_context_records_request_line() {
+        let context = RequestContext::new(
+            &Method::GET,
+            &"/a/b.html?x=1".parse().unwrap(),
+            Version::HTTP_11,
+            &HeaderMap::new(),
+            "127.0.0.1:1234".parse().unwrap(),
+            "192.0.2.1".parse().unwrap(),
+        );
+
+        let entry = context.finish(
+            Some(500),
+            Some("Starts with: \"/a\"".to_string()),
+            Some(CacheStatus::Miss),
+            Some("oh no".to_string()),
+        );
+
+        assert_eq!(entry.request_line, "GET /a/b.html?x=1 HTTP/1.1");
+        assert_eq!(entry.status, Some(500));
+        assert_eq!(entry.cache_status, Some(CacheStatus::Miss));
+        assert_eq!(entry.remote_addr, "127.0.0.1:1234");
+        assert_eq!(entry.client_ip, "192.0.2.1");
+        assert!(entry.finished_at_ms >= entry.started_at_ms);
+    }
+}
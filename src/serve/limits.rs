@@ -0,0 +1,255 @@
+use crate::{
+    config::EnvReader,
+    serve::{
+        body::DEFAULT_MAX_BODY_BYTES,
+        concurrency::{DEFAULT_MAX_CONCURRENT_REQUESTS, DEFAULT_QUEUE_WAIT},
+    },
+};
+use std::{
+    future::Future,
+    io,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+fn duration_from_env(env: &mut EnvReader, name: &str, default: Duration) -> Duration {
+    env.parsed(name).map_or(default, Duration::from_secs)
+}
+
+///how long and how much we'll put up with from one client
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    ///how long a HTTP/1.1 client gets to send all of a request's headers
+    pub header_read_timeout: Duration,
+    ///how long a request gets from the headers arriving to the response starting. WebSockets are exempt
+    pub request_timeout: Duration,
+    ///how long a connection can go without reading or writing anything before it gets closed
+    pub idle_timeout: Duration,
+    ///the most we'll buffer of a POST body
+    pub max_body_bytes: usize,
+    ///how many requests get handled at once, across every client
+    pub max_concurrent_requests: usize,
+    ///how long a request past that waits for a turn before getting a 429
+    pub queue_wait: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            header_read_timeout: DEFAULT_HEADER_READ_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            queue_wait: DEFAULT_QUEUE_WAIT,
+        }
+    }
+}
+
+impl Limits {
+    pub fn read(env: &mut EnvReader) -> Self {
+        Self {
+            header_read_timeout: duration_from_env(
+                env,
+                "HEADER_READ_TIMEOUT_SECS",
+                DEFAULT_HEADER_READ_TIMEOUT,
+            ),
+            request_timeout: duration_from_env(
+                env,
+                "REQUEST_TIMEOUT_SECS",
+                DEFAULT_REQUEST_TIMEOUT,
+            ),
+            idle_timeout: duration_from_env(env, "IDLE_TIMEOUT_SECS", DEFAULT_IDLE_TIMEOUT),
+            max_body_bytes: env
+                .parsed("MAX_BODY_BYTES")
+                .unwrap_or(DEFAULT_MAX_BODY_BYTES),
+            max_concurrent_requests: env
+                .parsed("MAX_CONCURRENT_REQUESTS")
+                .map_or(DEFAULT_MAX_CONCURRENT_REQUESTS, NonZeroUsize::get),
+            queue_wait: env
+                .parsed("REQUEST_QUEUE_MS")
+                .map_or(DEFAULT_QUEUE_WAIT, Duration::from_millis),
+        }
+    }
+}
+
+///how many of each kind of timeout there have been, logged whenever one goes up
+#[derive(Clone, Debug, Default)]
+pub struct TimeoutCounts {
+    header_read: Arc<AtomicU64>,
+    request: Arc<AtomicU64>,
+    idle: Arc<AtomicU64>,
+}
+
+impl TimeoutCounts {
+    pub fn header_read(&self) {
+        let total = self.header_read.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(%total, "Timed out reading request headers");
+    }
+
+    pub fn request(&self) {
+        let total = self.request.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(%total, "Request timed out");
+    }
+
+    pub fn idle(&self) {
+        let total = self.idle.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(%total, "Closing idle connection");
+    }
+}
+
+///whether a connection closed because of an [`IdleTimeout`]
+pub fn is_idle_timeout(e: &hyper::Error) -> bool {
+    std::error::Error::source(e)
+        .and_then(|x| x.downcast_ref::<io::Error>())
+        .is_some_and(|x| x.kind() == io::ErrorKind::TimedOut)
+}
+
+///errors out a connection once nothing has been read or written for `timeout`
+pub struct IdleTimeout<T> {
+    inner: T,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    timed_out: bool,
+    counts: TimeoutCounts,
+}
+
+impl<T> IdleTimeout<T> {
+    pub fn new(inner: T, timeout: Duration, counts: TimeoutCounts) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            timed_out: false,
+            counts,
+        }
+    }
+
+    fn reset(&mut self) {
+        let deadline = Instant::now() + self.timeout;
+        self.sleep.as_mut().reset(deadline);
+    }
+
+    ///to be called when the inner IO is pending, so we get woken up when the deadline passes
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        if !self.timed_out {
+            self.timed_out = true;
+            self.counts.idle();
+        }
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connection was idle for too long",
+        )))
+    }
+
+    ///resets the deadline on any progress, otherwise checks it
+    fn on_poll<R>(
+        &mut self,
+        cx: &mut Context<'_>,
+        res: Poll<io::Result<R>>,
+    ) -> Poll<io::Result<R>> {
+        match res {
+            Poll::Ready(res) => {
+                self.reset();
+                Poll::Ready(res)
+            }
+            Poll::Pending => match self.poll_idle(cx) {
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                _ => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.on_poll(cx, res)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.on_poll(cx, res)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_idle_connection_times_out() {
+        let (client, server) = duplex(64);
+        let counts = TimeoutCounts::default();
+        let mut server = IdleTimeout::new(server, Duration::from_millis(50), counts.clone());
+
+        let mut buf = [0_u8; 8];
+        let e = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(counts.idle.load(Ordering::Relaxed), 1);
+
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn test_activity_resets_idle_timeout() {
+        let (mut client, server) = duplex(64);
+        let mut server =
+            IdleTimeout::new(server, Duration::from_millis(200), TimeoutCounts::default());
+
+        let reader = tokio::task::spawn(async move {
+            let mut buf = [0_u8; 4];
+            for _ in 0..4 {
+                server.read_exact(&mut buf).await?;
+            }
+            io::Result::Ok(())
+        });
+
+        //well past the timeout in total, but never idle for long enough
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.write_all(b"ping").await.unwrap();
+        }
+
+        reader.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+use crate::config::EnvReader;
+use std::{
+    fmt::{Display, Formatter},
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+///who connections over a Unix socket look like they're from, as there's no address to go on. Being
+///loopback, a local proxy in front of it can still be trusted to say who the client is
+pub const UNIX_PEER: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+///somewhere to listen, from `LISTEN`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindTarget {
+    Tcp(SocketAddr),
+    ///`unix:/run/shove.sock`
+    Unix(PathBuf),
+}
+
+impl Display for BindTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+///which requests a listener answers. Health & ready checks are answered by all of them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Routes {
+    #[default]
+    All,
+    ///everything but the internal routes, which look like they don't exist
+    Public,
+    ///only the internal routes, for a port that isn't exposed
+    Internal,
+}
+
+impl Routes {
+    ///`/reload`, `/metrics` & everything under `/__shove/`
+    fn is_internal(path: &str) -> bool {
+        path.starts_with("/__shove/") || matches!(path, "/reload" | "/metrics")
+    }
+
+    pub fn serves(self, path: &str) -> bool {
+        if matches!(path, "/healthcheck" | "/readycheck") {
+            return true;
+        }
+        match self {
+            Self::All => true,
+            Self::Public => !Self::is_internal(path),
+            Self::Internal => Self::is_internal(path),
+        }
+    }
+}
+
+///a [`BindTarget`], and which requests get answered there
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listen {
+    pub target: BindTarget,
+    pub routes: Routes,
+}
+
+impl Listen {
+    ///`target` or `target=tag`, where the tag is `public` or `internal` (or `metrics`, which is the
+    ///same as `internal`)
+    fn parse(x: &str) -> Option<Self> {
+        let (target, routes) = match x.rsplit_once('=') {
+            Some((target, tag)) => {
+                let routes = match tag.trim() {
+                    "public" => Routes::Public,
+                    "internal" | "metrics" => Routes::Internal,
+                    _ => return None,
+                };
+                (target.trim(), routes)
+            }
+            None => (x, Routes::All),
+        };
+        let target = match target.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => BindTarget::Unix(PathBuf::from(path)),
+            Some(_) => return None,
+            None => BindTarget::Tcp(target.parse().ok()?),
+        };
+        Some(Self { target, routes })
+    }
+}
+
+///everywhere to listen, from `LISTEN`. Empty means the one TCP port from `PORT`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Listeners(Arc<[Listen]>);
+
+impl Listeners {
+    ///`Err` with every entry that isn't an address or `unix:` path, with an optional tag
+    pub fn new(list: &str) -> Result<Self, Vec<String>> {
+        let mut listens = vec![];
+        let mut invalid = vec![];
+        for x in list.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match Listen::parse(x) {
+                Some(listen) => listens.push(listen),
+                None => invalid.push(x.to_string()),
+            }
+        }
+
+        if invalid.is_empty() {
+            Ok(Self(listens.into()))
+        } else {
+            Err(invalid)
+        }
+    }
+
+    pub fn read(env: &mut EnvReader) -> Self {
+        let Some(list) = env.optional("LISTEN") else {
+            return Self::default();
+        };
+        Self::new(&list).unwrap_or_else(|invalid| {
+            env.problem(format!(
+                "LISTEN has entries that aren't addresses or unix:paths, optionally followed by =public or =internal: {}",
+                invalid.join(", ")
+            ));
+            Self::default()
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Listen> {
+        self.0.iter()
+    }
+}
+
+///a bound [`BindTarget`]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+///a connection, and who it's from
+pub enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    ///anything already at a Unix socket's path is removed first if it's a socket, as that'll be
+    ///left over from the last time we ran
+    pub async fn bind(target: &BindTarget) -> io::Result<Self> {
+        match target {
+            BindTarget::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            BindTarget::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if tokio::fs::metadata(path)
+                    .await
+                    .is_ok_and(|x| x.file_type().is_socket())
+                {
+                    tokio::fs::remove_file(path).await?;
+                }
+                Ok(Self::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            BindTarget::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are only supported on Unix",
+            )),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok(Accepted::Tcp(stream, addr))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Accepted::Unix(stream))
+            }
+        }
+    }
+
+    ///where it ended up, for the logs - a TCP port of `0` gets filled in
+    pub fn target(&self) -> io::Result<BindTarget> {
+        match self {
+            Self::Tcp(listener) => Ok(BindTarget::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Self::Unix(listener) => Ok(BindTarget::Unix(
+                listener
+                    .local_addr()?
+                    .as_pathname()
+                    .map(PathBuf::from)
+                    .unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listeners() {
+        let listeners = Listeners::new(
+            "0.0.0.0:8080, unix:/run/shove.sock,127.0.0.1:9100=metrics,[::1]:80=public",
+        )
+        .unwrap();
+        assert_eq!(
+            listeners.iter().cloned().collect::<Vec<_>>(),
+            vec![
+                Listen {
+                    target: BindTarget::Tcp("0.0.0.0:8080".parse().unwrap()),
+                    routes: Routes::All,
+                },
+                Listen {
+                    target: BindTarget::Unix(PathBuf::from("/run/shove.sock")),
+                    routes: Routes::All,
+                },
+                Listen {
+                    target: BindTarget::Tcp("127.0.0.1:9100".parse().unwrap()),
+                    routes: Routes::Internal,
+                },
+                Listen {
+                    target: BindTarget::Tcp("[::1]:80".parse().unwrap()),
+                    routes: Routes::Public,
+                },
+            ]
+        );
+
+        assert_eq!(
+            Listeners::new("8080,unix:,0.0.0.0:80=secret,127.0.0.1:81").unwrap_err(),
+            vec!["8080", "unix:", "0.0.0.0:80=secret"]
+        );
+    }
+
+    #[test]
+    fn test_routes() {
+        for path in ["/reload", "/__shove/purge", "/metrics"] {
+            assert!(Routes::All.serves(path));
+            assert!(!Routes::Public.serves(path));
+            assert!(Routes::Internal.serves(path));
+        }
+        assert!(Routes::Public.serves("/index.html"));
+        assert!(!Routes::Internal.serves("/index.html"));
+        assert!(Routes::Public.serves("/healthcheck"));
+        assert!(Routes::Internal.serves("/readycheck"));
+    }
+}
@@ -1,213 +1,562 @@
-use color_eyre::eyre::bail;
-use futures::{
-    io::{BufReader, BufWriter},
-    stream::FuturesUnordered,
-    StreamExt,
-};
+use futures::io::{BufReader, BufWriter};
 use hyper::{body::Incoming, upgrade::Upgraded, Request};
 use hyper_util::rt::TokioIo;
+use serde::Serialize;
 use soketto::{
     connection::Error as SokettoError, data::ByteSlice125, handshake::http::Server,
     Incoming as WsIncoming,
 };
-use std::{sync::Arc, time::Duration};
-use tokio::sync::{
-    mpsc::{channel, Sender},
-    Mutex,
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+    task::JoinHandle,
+    time::Instant,
 };
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 
-type WsSender = soketto::Sender<BufReader<BufWriter<Compat<TokioIo<Upgraded>>>>>;
-type WsReceiver = soketto::Receiver<BufReader<BufWriter<Compat<TokioIo<Upgraded>>>>>;
+pub const DEFAULT_WS_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_LIVERELOAD_CLIENTS: usize = 1024;
 
-#[derive(Clone, Debug)]
-pub struct LiveReloader {
-    senders: Arc<Mutex<Vec<(WsSender, WsReceiver)>>>,
-    stop_dead_check: Sender<()>,
+///how often each client gets pinged, so dead connections get noticed
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+const PING: &[u8] = "get pinged, loser".as_bytes();
+///a client that's sent nothing for this long, not even a pong, is treated as gone
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(PING_INTERVAL.as_secs() + 15);
+///how many reloads a slow client can fall behind by before it's just told to reload
+const MESSAGE_BACKLOG: usize = 16;
+///on top of the close timeout, for every socket to be given back when stopping
+const STOP_SLACK: Duration = Duration::from_secs(1);
+
+///past this many changed paths, clients just get told to reload
+const MAX_SCOPED_CHANGES: usize = 100;
+
+///connects back to wherever the page came from. Reloads if it or anything it uses has changed,
+///apart from stylesheets which get swapped in place
+///`data-base` is the `BASE_PATH`, which the server's paths don't include
+const LIVERELOAD_SCRIPT: &str = r#"(() => {
+    const base = document.currentScript.dataset.base;
+    const ws = new WebSocket((location.protocol === "https:" ? "wss://" : "ws://") + location.host + base + "/");
+    ws.onmessage = (msg) => {
+        if (msg.data === "reload") return location.reload();
+        const message = JSON.parse(msg.data);
+        const [changed, removed] = [message.changed, message.removed].map((paths) => paths.map((x) => base + x));
+        const page = location.pathname.endsWith("/") ? location.pathname + "index.html" : location.pathname;
+        const all = changed.concat(removed);
+        if (all.includes(page)) return location.reload();
+
+        for (const link of document.querySelectorAll('link[rel="stylesheet"]')) {
+            const url = new URL(link.href);
+            if (changed.includes(url.pathname)) {
+                url.searchParams.set("livereload", Date.now());
+                link.href = url;
+            }
+        }
+        const used = [...document.querySelectorAll("[src], link[href]")].map((x) => new URL(x.src || x.href).pathname);
+        if (all.some((x) => !x.endsWith(".css") && used.includes(x))) location.reload();
+    };
+})();"#;
+
+///the paths (not keys) that changed in a reload
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChangedPaths {
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
 }
 
-impl LiveReloader {
-    pub fn new() -> Self {
-        let senders: Arc<Mutex<Vec<(WsSender, WsReceiver)>>> = Arc::new(Mutex::new(vec![]));
-        let dead_check_senders = senders.clone();
-        let (stop_dead_check, mut stop_rx) = channel(1);
-        tokio::task::spawn(async move {
-            loop {
-                tokio::select! {
-                    _ = tokio::time::sleep(Duration::from_secs(60)) => {}
-                    _ = stop_rx.recv() => {
-                        break;
-                    }
-                }
+impl ChangedPaths {
+    ///JSON for clients that can work out whether they care, or a plain `reload` if it's too big
+    fn message(&self) -> String {
+        if self.changed.len() + self.removed.len() > MAX_SCOPED_CHANGES {
+            return "reload".to_string();
+        }
+        serde_json::to_string(self).unwrap_or_else(|_| "reload".to_string())
+    }
+}
 
-                async fn handle_tx_and_rx(tx: &mut WsSender, rx: &mut WsReceiver) -> bool {
-                    //can't use b"" as that gives a const byte array, not a slice :(
-                    const PING: &[u8] = "get pinged, loser".as_bytes();
+///`base_path` is only ever URL-safe characters, so it doesn't need escaping
+fn livereload_script(base_path: &str) -> String {
+    format!(r#"<script data-base="{base_path}">{LIVERELOAD_SCRIPT}</script>"#)
+}
 
-                    //stupid struct doesn't implement copy OR clone
-                    //https://github.com/paritytech/soketto/issues/118 ?
-                    //(got merged, but not yet released smh)
-                    let ping_msg = || ByteSlice125::try_from(PING).unwrap();
-
-                    let mut needs_to_be_removed = false;
-
-                    if let Err(e) = tx.send_ping(ping_msg()).await {
-                        match e {
-                            SokettoError::Closed => {
-                                needs_to_be_removed = true;
-                            }
-                            other => {
-                                warn!(?other, "Error sending ping to WS");
-                            }
-                        }
-                    }
-                    if let Err(e) = tx.flush().await {
-                        match e {
-                            SokettoError::Closed => {
-                                needs_to_be_removed = true;
-                            }
-                            other => {
-                                warn!(?other, "Error flushing WS");
-                            }
-                        }
-                    }
+///adds the live-reload script just before the last `</body>`, or at the end if there isn't one.
+///
+///this only ever happens on the way out, so the hashes used to spot changes never see the script
+pub fn inject_livereload_script(html: &[u8], base_path: &str) -> Vec<u8> {
+    const BODY_END: &[u8] = b"</body>";
+    let script = livereload_script(base_path);
 
-                    let mut output = vec![];
-                    match rx.receive(&mut output).await {
-                        Ok(incoming) => match incoming {
-                            WsIncoming::Data(data) => {
-                                trace!(?data, "Received data from WS???");
-                            }
-                            WsIncoming::Pong(pong) => {
-                                if pong != PING {
-                                    warn!(found=?pong, expected=?PING, "different ping/pong as expected");
-                                }
-                            }
-                            WsIncoming::Closed(_reason) => needs_to_be_removed = true,
-                        },
-                        Err(e) => {
-                            warn!(?e, "Error reading from WS");
-                        }
-                    }
+    let insert_at = html
+        .windows(BODY_END.len())
+        .rposition(|x| x.eq_ignore_ascii_case(BODY_END))
+        .unwrap_or(html.len());
 
-                    needs_to_be_removed
-                }
+    let mut injected = Vec::with_capacity(html.len() + script.len());
+    injected.extend_from_slice(&html[..insert_at]);
+    injected.extend_from_slice(script.as_bytes());
+    injected.extend_from_slice(&html[insert_at..]);
+    injected
+}
 
-                let mut senders_and_receivers = dead_check_senders.lock().await;
-                let mut needs_to_be_removed: FuturesUnordered<_> = senders_and_receivers
-                    .iter_mut()
-                    .enumerate()
-                    .map(|(i, (tx, rx))| async move {
-                        if handle_tx_and_rx(tx, rx).await {
-                            Some(i)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-
-                let mut tbr = vec![];
-                //there's a better way to do this, but i can't find it :(
-                #[allow(clippy::manual_flatten, for_loops_over_fallibles)]
-                for res in needs_to_be_removed.next().await {
-                    if let Some(res) = res {
-                        tbr.push(res);
-                    }
-                }
-                drop(needs_to_be_removed);
-
-                let senders_left = senders_and_receivers.len() - tbr.len();
-                info!(removed=%tbr.len(), %senders_left, "removing dead senders");
-                
-                //yes, there's probably a performance penalty, but really?
-                //like this is so easy to read
-                tbr.sort();
-                tbr.reverse();
-                for i in tbr {
-                    senders_and_receivers.remove(i);
+type WsStream = BufReader<BufWriter<Compat<GoingAway<TokioIo<Upgraded>>>>>;
+type WsSender = soketto::Sender<WsStream>;
+type WsReceiver = soketto::Receiver<WsStream>;
+
+///a close frame with 1001 (going away), which is what a server shutting down should send
+const GOING_AWAY_FRAME: [u8; 4] = [0x88, 0x02, 0x03, 0xE9];
+
+///soketto can only close with 1000, so this sits underneath it to write a 1001 close frame on
+///the next flush once `requested` is set. Anything written after that gets dropped, as nothing
+///is meant to follow a close frame (including soketto echoing back the peer's close).
+#[derive(Debug)]
+struct GoingAway<T> {
+    inner: T,
+    requested: Arc<AtomicBool>,
+    written: usize,
+}
+
+impl<T> GoingAway<T> {
+    fn new(inner: T) -> (Self, Arc<AtomicBool>) {
+        let requested = Arc::new(AtomicBool::new(false));
+        (
+            Self {
+                inner,
+                requested: requested.clone(),
+                written: 0,
+            },
+            requested,
+        )
+    }
+
+    fn is_sent(&self) -> bool {
+        self.written == GOING_AWAY_FRAME.len()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for GoingAway<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for GoingAway<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.is_sent() {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        //only on flush, so anything already buffered above us goes out first
+        if this.requested.load(Ordering::SeqCst) {
+            while !this.is_sent() {
+                let n = std::task::ready!(
+                    Pin::new(&mut this.inner).poll_write(cx, &GOING_AWAY_FRAME[this.written..])
+                )?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
                 }
+                this.written += n;
             }
-        });
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
 
+///what gets sent to every client's task
+#[derive(Clone, Debug)]
+enum Message {
+    Reload(Arc<str>),
+    ///close with a 1001
+    Stop,
+}
+
+///room for one more client, given back when it's dropped. Subscribed from the start, so a reload or
+///stop sent while the socket's still being upgraded isn't missed
+#[derive(Debug)]
+pub struct ClientSlot {
+    clients: Arc<watch::Sender<usize>>,
+    messages: broadcast::Receiver<Message>,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.clients.send_modify(|count| *count -= 1);
+    }
+}
+
+///each client has its own task, so a slow one only holds itself up
+#[derive(Clone, Debug)]
+pub struct LiveReloader {
+    messages: broadcast::Sender<Message>,
+    ///how many sockets are open, or about to be
+    clients: Arc<watch::Sender<usize>>,
+    max_clients: usize,
+    ///how long to wait for a client to reply to our close when shutting down
+    close_timeout: Duration,
+}
+
+impl LiveReloader {
+    pub fn new(close_timeout: Duration, max_clients: usize) -> Self {
+        let (messages, _) = broadcast::channel(MESSAGE_BACKLOG);
         Self {
-            senders,
-            stop_dead_check,
+            messages,
+            clients: Arc::new(watch::Sender::new(0)),
+            max_clients,
+            close_timeout,
         }
     }
 
+    ///`None` if there are already as many clients as allowed
+    pub fn admit(&self) -> Option<ClientSlot> {
+        let admitted = self.clients.send_if_modified(|count| {
+            if *count >= self.max_clients {
+                return false;
+            }
+            *count += 1;
+            true
+        });
+        admitted.then(|| ClientSlot {
+            clients: self.clients.clone(),
+            messages: self.messages.subscribe(),
+        })
+    }
+
+    ///how many sockets are open
+    pub fn clients(&self) -> usize {
+        *self.clients.borrow()
+    }
+
+    pub fn max_clients(&self) -> usize {
+        self.max_clients
+    }
+
+    ///runs until the client goes away or we stop, so `slot` is only given back then
     pub async fn handle_livereload(
         &self,
         req: Request<Incoming>,
         server: Server,
+        mut slot: ClientSlot,
     ) -> color_eyre::Result<()> {
         let stream = hyper::upgrade::on(req).await?;
-        let io = TokioIo::new(stream);
+        let (io, going_away) = GoingAway::new(TokioIo::new(stream));
         let stream = BufReader::new(BufWriter::new(io.compat()));
 
-        let ws = server.into_builder(stream).finish();
+        let (tx, rx) = server.into_builder(stream).finish();
+        debug!(clients=%self.clients(), "Live-reload client connected");
 
-        self.senders.lock().await.push(ws);
+        //reading has its own task, as soketto's receive can't be cancelled part way through a frame
+        let mut reading = tokio::task::spawn(read_until_closed(rx));
+        let res = self
+            .run_socket(tx, going_away, &mut slot.messages, &mut reading)
+            .await;
+        reading.abort();
 
-        Ok(())
+        drop(slot);
+        debug!(clients=%self.clients(), "Live-reload client gone");
+        res
     }
 
-    pub async fn send_reload(&self) -> color_eyre::Result<()> {
-        async fn reload(mut sender: WsSender) -> color_eyre::Result<()> {
-            fn handle(res: Result<(), SokettoError>) -> color_eyre::Result<()> {
-                match res {
-                    Ok(()) | Err(SokettoError::Closed) => Ok(()),
-                    Err(e) => Err(e.into()),
+    async fn run_socket(
+        &self,
+        mut tx: WsSender,
+        going_away: Arc<AtomicBool>,
+        messages: &mut broadcast::Receiver<Message>,
+        reading: &mut JoinHandle<()>,
+    ) -> color_eyre::Result<()> {
+        let mut ping = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+
+        loop {
+            let sent = tokio::select! {
+                message = messages.recv() => match message {
+                    Ok(Message::Reload(text)) => send_text(&mut tx, &text).await,
+                    //missed some, so there's no telling what's changed
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!(%missed, "Live-reload client fell behind");
+                        send_text(&mut tx, "reload").await
+                    }
+                    Ok(Message::Stop) | Err(RecvError::Closed) => {
+                        return stop(tx, going_away, reading, self.close_timeout).await;
+                    }
+                },
+                _ = ping.tick() => {
+                    //can't use b"" as that gives a const byte array, not a slice :(
+                    //and ByteSlice125 doesn't implement copy OR clone
+                    //https://github.com/paritytech/soketto/issues/118 ?
+                    match tx.send_ping(ByteSlice125::try_from(PING).unwrap()).await {
+                        Ok(()) => tx.flush().await,
+                        Err(e) => Err(e),
+                    }
                 }
+                _ = &mut *reading => return Ok(()),
+            };
+
+            match sent {
+                Ok(()) => {}
+                Err(SokettoError::Closed) => return Ok(()),
+                Err(e) => return Err(e.into()),
             }
+        }
+    }
+
+    ///sockets are kept open, as not every client will need to reload
+    pub async fn send_reload(&self, changes: &ChangedPaths) -> color_eyre::Result<()> {
+        //only fails if there's nobody to send it to
+        let _ = self
+            .messages
+            .send(Message::Reload(changes.message().into()));
+        Ok(())
+    }
 
-            handle(sender.send_text("reload").await)?;
-            handle(sender.flush().await)?;
-            handle(sender.close().await)?;
+    ///sends every socket a 1001 close, and gives them `close_timeout` to reply before they get dropped
+    pub async fn send_stop(&self) -> color_eyre::Result<()> {
+        let _ = self.messages.send(Message::Stop);
 
-            Ok(())
+        let mut clients = self.clients.subscribe();
+        //each socket has its own timeout, this is only in case one gets stuck somewhere else
+        let all_closed = clients.wait_for(|count| *count == 0);
+        if tokio::time::timeout(self.close_timeout + STOP_SLACK, all_closed)
+            .await
+            .is_err()
+        {
+            warn!(clients=%self.clients(), "Live-reload sockets still open after stopping");
         }
 
-        let Ok(mut senders) = self.senders.try_lock() else {
-            bail!("Already reloading");
-        };
+        Ok(())
+    }
+}
 
-        let senders = std::mem::take::<Vec<_>>(senders.as_mut());
-        let mut fo: FuturesUnordered<_> = senders
-            .into_iter()
-            .map(|(sender, _)| reload(sender))
-            .collect();
+///whether the socket is still usable
+async fn send_text(tx: &mut WsSender, message: &str) -> Result<(), SokettoError> {
+    tx.send_text(message).await?;
+    tx.flush().await
+}
 
-        while let Some(res) = fo.next().await {
-            if let Err(e) = res {
-                error!(?e, "Error sending reload message");
+///returns once the client's closed the socket, or stopped answering pings
+async fn read_until_closed(mut rx: WsReceiver) {
+    let mut output = vec![];
+    loop {
+        let Ok(received) = tokio::time::timeout(RECEIVE_TIMEOUT, rx.receive(&mut output)).await
+        else {
+            debug!(?RECEIVE_TIMEOUT, "Live-reload client stopped responding");
+            return;
+        };
+        match received {
+            Ok(WsIncoming::Data(data)) => trace!(?data, "Received data from WS???"),
+            Ok(WsIncoming::Pong(pong)) => {
+                if pong != PING {
+                    warn!(found=?pong, expected=?PING, "different ping/pong as expected");
+                }
+            }
+            Ok(WsIncoming::Closed(_)) | Err(SokettoError::Closed) => return,
+            Err(e) => {
+                warn!(?e, "Error reading from WS");
+                return;
             }
         }
+        output.clear();
+    }
+}
 
-        Ok(())
+///sends a 1001 close, then waits up to `close_timeout` for the client to reply
+async fn stop(
+    mut tx: WsSender,
+    going_away: Arc<AtomicBool>,
+    reading: &mut JoinHandle<()>,
+    close_timeout: Duration,
+) -> color_eyre::Result<()> {
+    going_away.store(true, Ordering::SeqCst);
+    match tx.flush().await {
+        Ok(()) => {}
+        Err(SokettoError::Closed) => return Ok(()),
+        Err(e) => return Err(e.into()),
     }
 
-    pub async fn send_stop(&self) -> color_eyre::Result<()> {
-        async fn stop(mut sender: WsSender) -> color_eyre::Result<()> {
-            match sender.close().await {
-                Ok(()) | Err(SokettoError::Closed) => Ok(()),
-                Err(e) => Err(e.into()),
+    if tokio::time::timeout(close_timeout, reading).await.is_err() {
+        debug!(?close_timeout, "WS didn't reply to close in time");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serve::{empty_body, ServeBody};
+    use hyper::{server::conn::http1, service::service_fn, Response};
+    use soketto::handshake::{Client, ServerResponse};
+    use std::{convert::Infallible, net::SocketAddr};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn start_server(reloader: LiveReloader) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let reloader = reloader.clone();
+                let svc = service_fn(move |req: Request<Incoming>| {
+                    let reloader = reloader.clone();
+                    async move {
+                        let slot = reloader.admit().unwrap();
+                        let mut server = Server::new();
+                        let rsp = server.receive_request(&req).unwrap();
+                        tokio::task::spawn(async move {
+                            reloader.handle_livereload(req, server, slot).await.unwrap();
+                        });
+                        Ok::<Response<ServeBody>, Infallible>(rsp.map(|()| empty_body()))
+                    }
+                });
+                tokio::task::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), svc)
+                        .with_upgrades()
+                        .await;
+                });
             }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_large_changes_fall_back_to_reload() {
+        let changes = ChangedPaths {
+            changed: vec!["/style.css".to_string()],
+            removed: vec![],
+        };
+        assert_eq!(
+            changes.message(),
+            r#"{"changed":["/style.css"],"removed":[]}"#
+        );
+
+        let changes = ChangedPaths {
+            changed: (0..=MAX_SCOPED_CHANGES)
+                .map(|i| format!("/{i}.html"))
+                .collect(),
+            removed: vec![],
+        };
+        assert_eq!(changes.message(), "reload");
+    }
+
+    #[test]
+    fn test_script_goes_before_body_end() {
+        let injected = inject_livereload_script(b"<html><BODY><p>hi</p></BODY></html>", "");
+        assert_eq!(
+            String::from_utf8(injected).unwrap(),
+            format!(
+                "<html><BODY><p>hi</p>{}</BODY></html>",
+                livereload_script("")
+            )
+        );
+    }
+
+    #[test]
+    fn test_script_is_appended_without_body_end() {
+        let injected = inject_livereload_script(b"<p>hi</p>", "/docs");
+        assert_eq!(
+            String::from_utf8(injected).unwrap(),
+            format!(r#"<p>hi</p><script data-base="/docs">{LIVERELOAD_SCRIPT}</script>"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stop_sends_going_away() {
+        let reloader = LiveReloader::new(DEFAULT_WS_CLOSE_TIMEOUT, DEFAULT_MAX_LIVERELOAD_CLIENTS);
+        let addr = start_server(reloader.clone()).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut client = Client::new(stream.compat(), "localhost", "/");
+        assert!(matches!(
+            client.handshake().await.unwrap(),
+            ServerResponse::Accepted { .. }
+        ));
+        let (_tx, mut rx) = client.into_builder().finish();
+
+        assert_eq!(reloader.clients(), 1);
+
+        let stop = tokio::task::spawn({
+            let reloader = reloader.clone();
+            async move { reloader.send_stop().await }
+        });
+
+        let mut output = vec![];
+        match rx.receive(&mut output).await.unwrap() {
+            WsIncoming::Closed(reason) => assert_eq!(reason.code, 1001),
+            other => panic!("expected a close, got {other:?}"),
         }
 
-        let _ = self.stop_dead_check.send(()).await;
+        //we replied to the close, so the server shouldn't need to wait out the timeout
+        tokio::time::timeout(Duration::from_secs(1), stop)
+            .await
+            .expect("server waited for the close timeout")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reloader.clients(), 0);
+    }
 
-        let senders = std::mem::take::<Vec<_>>(self.senders.lock().await.as_mut());
-        let mut fo: FuturesUnordered<_> = senders
-            .into_iter()
-            .map(|(sender, _)| stop(sender))
-            .collect();
+    #[tokio::test]
+    async fn test_closed_clients_are_all_removed() {
+        let reloader = LiveReloader::new(DEFAULT_WS_CLOSE_TIMEOUT, DEFAULT_MAX_LIVERELOAD_CLIENTS);
+        let addr = start_server(reloader.clone()).await;
 
-        while let Some(res) = fo.next().await {
-            if let Err(e) = res {
-                error!(?e, "Error closing sender");
-            }
+        let mut clients = vec![];
+        for _ in 0..3 {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut client = Client::new(stream.compat(), "localhost", "/");
+            assert!(matches!(
+                client.handshake().await.unwrap(),
+                ServerResponse::Accepted { .. }
+            ));
+            clients.push(client.into_builder().finish());
         }
+        assert_eq!(reloader.clients(), 3);
 
-        Ok(())
+        //dropped without a close, like a tab that's been killed
+        drop(clients);
+        let mut count = reloader.clients.subscribe();
+        tokio::time::timeout(Duration::from_secs(1), count.wait_for(|x| *x == 0))
+            .await
+            .expect("closed clients weren't removed")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_clients_past_the_max_are_turned_away() {
+        let reloader = LiveReloader::new(DEFAULT_WS_CLOSE_TIMEOUT, 2);
+        let first = reloader.admit().unwrap();
+        let _second = reloader.admit().unwrap();
+        assert!(reloader.admit().is_none());
+        assert_eq!(reloader.clients(), 2);
+
+        drop(first);
+        assert_eq!(reloader.clients(), 1);
+        assert!(reloader.admit().is_some());
     }
 }
@@ -0,0 +1,113 @@
+use crate::config::EnvReader;
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd};
+use std::path::Path;
+
+///from `RENDER_MARKDOWN`, for buckets of plain Markdown docs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderMarkdown {
+    ///linked from every rendered page, from `MARKDOWN_STYLESHEET`
+    pub stylesheet: Option<String>,
+}
+
+impl RenderMarkdown {
+    ///`None` unless `RENDER_MARKDOWN` is on
+    pub fn read(env: &mut EnvReader) -> Option<Self> {
+        let stylesheet = env.optional("MARKDOWN_STYLESHEET");
+        env.flag("RENDER_MARKDOWN").then_some(Self { stylesheet })
+    }
+}
+
+///whether `path` is a Markdown file, going by its extension
+pub fn is_markdown(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("md"))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+///the text of the first heading, however deep
+fn first_heading(markdown: &str, options: Options) -> Option<String> {
+    let mut title: Option<String> = None;
+    for event in Parser::new_ext(markdown, options) {
+        match (event, &mut title) {
+            (Event::Start(Tag::Heading { .. }), None) => title = Some(String::new()),
+            (Event::End(TagEnd::Heading(_)), Some(title)) => return Some(title.trim().to_string()),
+            (Event::Text(text) | Event::Code(text), Some(title)) => title.push_str(&text),
+            _ => {}
+        }
+    }
+    None
+}
+
+///`markdown` as a whole HTML page, titled by its first heading or `fallback_title` without one
+pub fn render(markdown: &str, fallback_title: &str, settings: &RenderMarkdown) -> Vec<u8> {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+    let title = first_heading(markdown, options)
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| fallback_title.to_string());
+
+    let mut page = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">",
+    );
+    page.push_str(&format!("<title>{}</title>", escape_html(&title)));
+    if let Some(stylesheet) = &settings.stylesheet {
+        page.push_str(&format!(
+            "<link rel=\"stylesheet\" href=\"{}\">",
+            escape_html(stylesheet)
+        ));
+    }
+    page.push_str("</head><body>\n");
+    html::push_html(&mut page, Parser::new_ext(markdown, options));
+    page.push_str("</body></html>\n");
+    page.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_titled_by_first_heading() {
+        let settings = RenderMarkdown {
+            stylesheet: Some("/docs.css".to_string()),
+        };
+        let page = render(
+            "intro\n\n## Getting `shove` & more\n\n# Later\n\n| a |\n|---|\n| b |\n",
+            "fallback",
+            &settings,
+        );
+        let page = String::from_utf8(page).unwrap();
+        assert!(
+            page.contains("<title>Getting shove &amp; more</title>"),
+            "{page}"
+        );
+        assert!(
+            page.contains("<link rel=\"stylesheet\" href=\"/docs.css\">"),
+            "{page}"
+        );
+        assert!(page.contains("<td>b</td>"), "{page}");
+
+        let page = render("no headings here", "notes.md", &RenderMarkdown::default());
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.contains("<title>notes.md</title>"), "{page}");
+        assert!(!page.contains("stylesheet"), "{page}");
+        assert!(page.contains("<p>no headings here</p>"), "{page}");
+    }
+
+    #[test]
+    fn test_is_markdown() {
+        assert!(is_markdown("/docs/intro.md"));
+        assert!(is_markdown("/README.MD"));
+        assert!(!is_markdown("/docs/intro.html"));
+        assert!(!is_markdown("/docs/md"));
+    }
+}
@@ -0,0 +1,197 @@
+use crate::serve::{full_body, ServeBody};
+use hyper::{header, header::HeaderValue, HeaderMap, Method, Response, StatusCode};
+use serde::Serialize;
+
+///the errors that get a JSON body for clients that would rather have one
+const NEGOTIATED: [StatusCode; 5] = [
+    StatusCode::UNAUTHORIZED,
+    StatusCode::FORBIDDEN,
+    StatusCode::NOT_FOUND,
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::INTERNAL_SERVER_ERROR,
+];
+
+///headers that describe the body being replaced, so go with it
+const BODY_HEADERS: [header::HeaderName; 7] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::CONTENT_RANGE,
+    header::ACCEPT_RANGES,
+    header::ETAG,
+    header::LAST_MODIFIED,
+];
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+    error: String,
+    path: &'a str,
+}
+
+///how much `accept` likes `essence`, from the most specific range that matches it. `0` if none do
+fn quality(accept: &str, essence: &str) -> f32 {
+    let (type_, _) = essence.split_once('/').unwrap_or((essence, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let range = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|x| x.trim().strip_prefix("q="))
+            .find_map(|x| x.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let specificity = if range == essence {
+            2
+        } else if range.strip_suffix("/*") == Some(type_) {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(x, _)| specificity > x) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+///whether errors should be JSON rather than a page - for paths to JSON files, or when `Accept`
+///likes JSON more than HTML. Browsers always like HTML at least as much, so still get the pages
+pub fn wants_json(headers: &HeaderMap, path: &str) -> bool {
+    if path
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| extension.eq_ignore_ascii_case("json"))
+    {
+        return true;
+    }
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|x| x.to_str().ok()) else {
+        return false;
+    };
+    quality(accept, mime::APPLICATION_JSON.essence_str())
+        > quality(accept, mime::TEXT_HTML.essence_str())
+}
+
+///swaps the body of an error for `{"error":"not_found","path":...}` if the client `wants_json`,
+///keeping the status & anything else like `WWW-Authenticate` or `Retry-After`. Either way it's
+///marked as depending on `Accept`, so caches don't hand one to the other
+pub fn negotiate_error(
+    rsp: Response<ServeBody>,
+    wants_json: bool,
+    req_method: &Method,
+    path: &str,
+) -> Response<ServeBody> {
+    if !NEGOTIATED.contains(&rsp.status()) {
+        return rsp;
+    }
+    let (mut parts, body) = rsp.into_parts();
+    parts.headers.append(header::VARY, header::ACCEPT.into());
+    if !wants_json {
+        return Response::from_parts(parts, body);
+    }
+
+    let error = JsonError {
+        error: parts
+            .status
+            .canonical_reason()
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+            .replace(' ', "_"),
+        path,
+    };
+    let Ok(bytes) = serde_json::to_vec(&error) else {
+        return Response::from_parts(parts, body);
+    };
+    for name in BODY_HEADERS {
+        parts.headers.remove(name);
+    }
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(mime::APPLICATION_JSON.as_ref()),
+    );
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, bytes.len().into());
+
+    let body = if req_method == Method::HEAD {
+        full_body(vec![])
+    } else {
+        full_body(bytes)
+    };
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serve::{empty_with_code, empty_with_headers};
+    use http_body_util::BodyExt;
+
+    fn accepting(accept: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, accept.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_wants_json() {
+        for (accept, path, expected) in [
+            (None, "/data.json", true),
+            (None, "/data.JSON", true),
+            (None, "/index.html", false),
+            (Some("application/json"), "/data", true),
+            (Some("application/*"), "/data", true),
+            (Some("application/json, text/html;q=0.9"), "/", true),
+            (Some("text/html, application/json;q=0.9"), "/", false),
+            (Some("*/*"), "/data", false),
+            (Some("application/json;q=0, */*"), "/data", false),
+            //what browsers send for pages
+            (
+                Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                "/data",
+                false,
+            ),
+            //what fetch sends by default
+            (Some("*/*"), "/api/items.json", true),
+        ] {
+            let headers = accept.map(accepting).unwrap_or_default();
+            assert_eq!(wants_json(&headers, path), expected, "{accept:?} {path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_negotiated() {
+        let rsp = empty_with_headers(StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "3")])
+            .unwrap();
+        let rsp = negotiate_error(rsp, true, &Method::GET, "/data.json");
+        assert_eq!(rsp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(rsp.headers()[header::RETRY_AFTER], "3");
+        assert_eq!(rsp.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(rsp.headers()[header::VARY], "accept");
+        let body = rsp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            body,
+            r#"{"error":"too_many_requests","path":"/data.json"}"#.as_bytes()
+        );
+
+        let rsp = empty_with_code(StatusCode::NOT_FOUND).unwrap();
+        let rsp = negotiate_error(rsp, true, &Method::HEAD, "/missing");
+        assert_eq!(rsp.headers()[header::CONTENT_LENGTH], "39");
+        assert!(rsp
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .is_empty());
+
+        //pages are left as they are, other than saying they could've been JSON
+        let rsp = empty_with_code(StatusCode::NOT_FOUND).unwrap();
+        let rsp = negotiate_error(rsp, false, &Method::GET, "/missing");
+        assert_eq!(rsp.headers()[header::CONTENT_LENGTH], "0");
+        assert_eq!(rsp.headers()[header::VARY], "accept");
+
+        let rsp = empty_with_code(StatusCode::BAD_GATEWAY).unwrap();
+        let rsp = negotiate_error(rsp, true, &Method::GET, "/data.json");
+        assert!(!rsp.headers().contains_key(header::VARY));
+    }
+}
@@ -1,92 +1,853 @@
 use crate::{
     cache_control::manager::{CacheControlManager, Directive},
+    config::EnvReader,
+    entry_key, entry_path,
+    error::{parse_json, ShoveError},
     hash_raw_bytes,
+    headers::manager::ExtraHeader,
     non_empty_list::NonEmptyList,
-    s3::UPLOAD_DATA_LOCATION,
-    serve::livereload::LiveReloader,
-    UploadData,
+    normalise_unicode,
+    s3::{get_bytes_if_changed, is_reserved_key, site_location, LastFetched, UPLOAD_DATA_LOCATION},
+    serve::{
+        background::Background,
+        empty_with_code, encoding, full_body,
+        journal::CacheStatus,
+        livereload::{inject_livereload_script, ChangedPaths, LiveReloader},
+        markdown::{self, is_markdown, RenderMarkdown},
+        range::{ByteRange, Validators},
+        redirects::REDIRECTS_PATH,
+        resize::{self, Dimensions},
+        sites::SiteSettings,
+        stats::{stats_key, HitStats},
+        ServeBody,
+    },
+    store::{guess_content_type, Fetched, ObjectStore, Store, StoreFailure},
+    to_hex, UploadData,
 };
 use color_eyre::eyre::bail;
 use futures::{stream::FuturesUnordered, StreamExt};
-use http_body_util::Full;
-use hyper::{body::Bytes, header, http, Method, Response, StatusCode};
-use moka::future::{Cache, CacheBuilder};
-use s3::{error::S3Error, Bucket};
-use serde_json::from_slice;
-use std::{collections::HashSet, sync::Arc};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{
+    body::{Bytes, Frame},
+    header, http, HeaderMap, Method, Response, StatusCode,
+};
+use moka::{
+    future::{Cache, CacheBuilder},
+    notification::RemovalCause,
+    policy::EvictionPolicy,
+};
+use path_clean::PathClean;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Serialize;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    future::Future,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock as SyncRwLock,
+    },
+    time::{Duration, SystemTime},
+};
 use tokio::sync::{Mutex, RwLock};
 
+///percent-decodes each segment on its own, so an encoded `/` can't split one in two, then collapses
+///repeated slashes & cleans out `.` and `..`. This is the one form of a path that auth, the cache and
+///everything else matches on, so nothing can be written to look like one thing to one and another
+///to another.
+///
+///A 400 for anything that decodes to something that couldn't have been written plainly - a `/`,
+///`.` or `..` - or isn't UTF-8, and for backslashes & control characters, which some other server
+///or filesystem along the way could read as something else
+pub fn normalise_request_path(path: &str) -> Result<String, StatusCode> {
+    let mut segments = vec![];
+    for raw in path.split('/').filter(|x| !x.is_empty()) {
+        let Ok(segment) = percent_decode_str(raw).decode_utf8() else {
+            return Err(StatusCode::BAD_REQUEST);
+        };
+        let smuggled = segment.contains(['/', '\\'])
+            || segment.chars().any(char::is_control)
+            || (segment != raw && matches!(&*segment, "." | ".."));
+        if smuggled {
+            debug!(?path, "Rejecting path");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        segments.push(segment);
+    }
+
+    let decoded = normalise_unicode(&format!("/{}", segments.join("/")));
+    let cleaned = Path::new(&decoded).clean();
+    match cleaned.to_str() {
+        Some(st) => Ok(st.to_owned()),
+        None => {
+            warn!(?cleaned, "Couldn't convert path to string");
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+///[normalises](normalise_request_path) a request path and resolves directories to their
+///`index.html`, so that `/blog`, `/blog/` and `/blog/index.html` all end up as the same path.
+///Returns `None` if the path can't be normalised
+pub fn resolve_request_path(path: &str) -> Option<String> {
+    let mut path = normalise_request_path(path).ok()?;
+
+    if Path::new(&path).extension().is_none_or(|x| x.is_empty()) {
+        //ensure that we don't miss zero-index fun
+        if path.chars().last().is_none_or(|ch| ch != '/') {
+            path.push('/');
+        }
+        path.push_str("index.html");
+    }
+
+    Some(path)
+}
+
+///what to do when a request's trailing slash doesn't match what's actually there
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    ///308 to the canonical form, so each page only lives at one URL
+    #[default]
+    Redirect,
+    ///serve the content at either form
+    Ignore,
+}
+
+impl TrailingSlash {
+    pub fn read(env: &mut EnvReader) -> Self {
+        match env.optional("TRAILING_SLASH").as_deref() {
+            Some("ignore") => Self::Ignore,
+            Some("redirect") | None => Self::Redirect,
+            Some(other) => {
+                env.problem(format!(
+                    "TRAILING_SLASH is {other:?}, but should be `redirect` or `ignore`"
+                ));
+                Self::Redirect
+            }
+        }
+    }
+}
+
+///what to do when a request only matches an uploaded path ignoring case, for sites written somewhere
+///that doesn't care about it
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CaseInsensitivePaths {
+    ///only exact matches
+    #[default]
+    Off,
+    ///301 to how it was uploaded, so crawlers & links converge on the one casing
+    Redirect,
+    ///serve it as if the request was exact
+    Serve,
+}
+
+impl CaseInsensitivePaths {
+    pub fn read(env: &mut EnvReader) -> Self {
+        match env.optional("CASE_INSENSITIVE_PATHS").as_deref() {
+            Some("false") | None => Self::Off,
+            Some("true" | "redirect") => Self::Redirect,
+            Some("serve") => Self::Serve,
+            Some(other) => {
+                env.problem(format!(
+                    "CASE_INSENSITIVE_PATHS is {other:?}, but should be `true`, `redirect`, `serve` or `false`"
+                ));
+                Self::Off
+            }
+        }
+    }
+}
+
+///the files a directory is served from, first uploaded one wins, from `INDEX_FILES`. A directory
+///with none of them still gets its `index.html` if it has one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexFiles(Arc<[String]>);
+
+impl Default for IndexFiles {
+    fn default() -> Self {
+        Self(["index.html".to_string()].into())
+    }
+}
+
+impl IndexFiles {
+    ///`Err` with every entry that isn't just a file name
+    pub fn new(list: &str) -> Result<Self, Vec<String>> {
+        let mut names = vec![];
+        let mut invalid = vec![];
+        for x in list.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            if x.contains('/') || x == "." || x == ".." {
+                invalid.push(x.to_string());
+            } else if !names.iter().any(|name| name == x) {
+                names.push(x.to_string());
+            }
+        }
+
+        if !invalid.is_empty() {
+            Err(invalid)
+        } else if names.is_empty() {
+            Ok(Self::default())
+        } else {
+            Ok(Self(names.into()))
+        }
+    }
+
+    pub fn read(env: &mut EnvReader) -> Self {
+        let Some(list) = env.optional("INDEX_FILES") else {
+            return Self::default();
+        };
+        Self::new(&list).unwrap_or_else(|invalid| {
+            env.problem(format!(
+                "INDEX_FILES has entries that aren't file names: {}",
+                invalid.join(", ")
+            ));
+            Self::default()
+        })
+    }
+
+    ///nothing to look for beyond what [`resolve_request_path`] already gives
+    fn only_index_html(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+///each directory's `index.html` key to the key of the first of `index_files` uploaded in it, for
+///the directories where that's something else
+fn index_fallbacks<'a>(
+    index_files: &IndexFiles,
+    keys: impl IntoIterator<Item = &'a String>,
+) -> HashMap<String, String> {
+    let mut best: HashMap<&str, (usize, &String)> = HashMap::new();
+    for key in keys {
+        let Some((directory, name)) = key.rsplit_once('/') else {
+            continue;
+        };
+        let Some(rank) = index_files.0.iter().position(|x| x == name) else {
+            continue;
+        };
+        match best.entry(directory) {
+            Entry::Occupied(mut found) if found.get().0 > rank => {
+                found.insert((rank, key));
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(vacant) => {
+                vacant.insert((rank, key));
+            }
+        }
+    }
+
+    best.into_iter()
+        .filter_map(|(directory, (_, key))| {
+            let index_html = format!("{directory}/index.html");
+            (*key != index_html).then(|| (index_html, key.clone()))
+        })
+        .collect()
+}
+
+///lowercased keys to how they were uploaded. When two only differ by case, the first alphabetically
+///is the one that gets found - the other can still be reached exactly
+fn index_casings<'a>(keys: impl IntoIterator<Item = &'a String>) -> HashMap<String, String> {
+    let mut keys: Vec<&String> = keys.into_iter().collect();
+    keys.sort_unstable();
+
+    let mut casings = HashMap::with_capacity(keys.len());
+    for key in keys {
+        match casings.entry(key.to_lowercase()) {
+            Entry::Occupied(found) => {
+                warn!(?key, found=?found.get(), "Keys only differ by case, so only an exact match reaches the second");
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(key.clone());
+            }
+        }
+    }
+    casings
+}
+
+///percent-encodes what [`normalise_request_path`] decoded, for sending back in a `Location`
+pub fn encode_request_path(path: &str) -> String {
+    //the WHATWG path percent-encode set, plus `%` so it decodes back to the same thing
+    const PATH: &AsciiSet = &CONTROLS
+        .add(b' ')
+        .add(b'"')
+        .add(b'#')
+        .add(b'%')
+        .add(b'<')
+        .add(b'>')
+        .add(b'?')
+        .add(b'`')
+        .add(b'{')
+        .add(b'}');
+    utf8_percent_encode(path, PATH).to_string()
+}
+
+///sorted, for the live-reload clients
+fn keys_to_paths<'a>(root: &str, keys: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    let mut paths: Vec<String> = keys
+        .into_iter()
+        .filter_map(|key| entry_path(root, key))
+        .collect();
+    paths.sort();
+    paths
+}
+
+pub const DEFAULT_CACHE_MAX_BYTES: u64 = 256 * 1024 * 1024;
+///how many files get read at once when warming the cache, so the important ones get in first
+const WARM_CONCURRENCY: usize = 16;
+///how long a path the store wouldn't give us goes without asking again, so a bucket that's down
+///isn't hammered by every request for it
+const FAILURE_BACKOFF: Duration = Duration::from_secs(10);
+///only the most recent failures are remembered, so made-up paths can't use up the memory
+const MAX_FAILING_PATHS: u64 = 10_000;
+
+///the order to warm the cache in - `index.html` files and the 404 page first, then the most
+///requested last time, then the shallowest paths, as those are what visitors are most likely to land on
+fn warm_order<'a>(
+    root: &str,
+    keys: impl IntoIterator<Item = &'a String>,
+    requests: impl Fn(&str) -> u64,
+) -> Vec<String> {
+    let priority = |key: &str| {
+        let path = entry_path(root, key).unwrap_or_else(|| key.to_string());
+        let important = path.ends_with("/index.html") || path == "/404.html";
+        (
+            !important,
+            std::cmp::Reverse(requests(&path)),
+            path.matches('/').count(),
+        )
+    };
+
+    let mut keys: Vec<String> = keys.into_iter().cloned().collect();
+    keys.sort_by_cached_key(|key| (priority(key), key.clone()));
+    keys
+}
+
+///how far the first pass of reading every file in has got
+#[derive(Debug, Default)]
+struct WarmProgress {
+    cached: AtomicUsize,
+    total: AtomicUsize,
+    finished: AtomicBool,
+}
+
+impl WarmProgress {
+    fn snapshot(&self) -> Warmth {
+        Warmth {
+            cached: self.cached.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            finished: self.finished.load(Ordering::Relaxed),
+        }
+    }
+}
+
+///what purging a path did
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Purged {
+    Purged,
+    NotCached,
+}
+
+///how warm the cache is, for `/readycheck` and logging
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct Warmth {
+    pub cached: usize,
+    pub total: usize,
+    pub finished: bool,
+}
+
+///nothing to warm, so nothing to wait for
+impl Default for Warmth {
+    fn default() -> Self {
+        Self {
+            cached: 0,
+            total: 0,
+            finished: true,
+        }
+    }
+}
+
+impl Warmth {
+    ///for several sites at once
+    pub fn combine(self, other: Self) -> Self {
+        Self {
+            cached: self.cached + other.cached,
+            total: self.total + other.total,
+            finished: self.finished && other.finished,
+        }
+    }
+
+    ///either the first pass has finished, or at least `fraction` of the files are cached
+    pub fn is_ready(self, fraction: Option<f64>) -> bool {
+        if self.finished || self.total == 0 {
+            return true;
+        }
+        fraction.is_some_and(|fraction| self.cached as f64 / self.total as f64 >= fraction)
+    }
+}
+
+///what's happened to files read in on a miss
+#[derive(Debug, Default)]
+struct AdmissionCounters {
+    admitted: AtomicU64,
+    bypassed: AtomicU64,
+    ///pushed out to make room, rather than purged or replaced
+    evicted: AtomicU64,
+}
+
+impl AdmissionCounters {
+    fn snapshot(&self) -> Admission {
+        Admission {
+            admitted: self.admitted.load(Ordering::Relaxed),
+            bypassed: self.bypassed.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+///how many files read in on a miss went into the cache, how many were served without being kept
+///(for being too big, or for a crawler), & how many cached files were pushed out to make room
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Admission {
+    pub admitted: u64,
+    pub bypassed: u64,
+    pub evicted: u64,
+}
+
+impl Admission {
+    ///for several sites at once
+    pub fn combine(self, other: Self) -> Self {
+        Self {
+            admitted: self.admitted + other.admitted,
+            bypassed: self.bypassed + other.bypassed,
+            evicted: self.evicted + other.evicted,
+        }
+    }
+}
+
+///where a page that was stored compressed gets kept once it's been decoded, next to what it was
+///decoded from so a new version drops both
+struct DecodedCache {
+    cache: Cache<CacheKey, CacheEntry>,
+    ///with an `identity` encoding, so it's apart from the bytes as they are in the bucket
+    key: CacheKey,
+    ///crawlers can use what's there, but don't add to it
+    admit: bool,
+    cache_admit_max_bytes: Option<u64>,
+}
+
+///what's cached for a key. The validators are for the version of the file these bytes are, which
+///can be older than the upload data while a reload's still reading the new ones in
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    content: Vec<u8>,
+    content_type: String,
+    ///what the bytes were stored compressed with, if anything
+    content_encoding: Option<String>,
+    validators: Validators,
+}
+
+///for `key` as it is in `upload_data`, which was read in at `loaded_at`
+fn validators_for(upload_data: &UploadData, key: &str, loaded_at: SystemTime) -> Validators {
+    Validators::new(upload_data.entries.get(key).map(String::as_str), loaded_at)
+}
+
+///what gets cached - every variant made from the same object shares its `path`, so they can all be
+///dropped together. Each site has its own [`Pages`], so the host doesn't need to be part of it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    ///the key in the bucket
+    pub path: String,
+    ///the `Content-Encoding` of the cached bytes, `None` for exactly what's in the bucket
+    pub encoding: Option<String>,
+    ///what's been made from the object, `None` for exactly what's in the bucket
+    pub variant: Option<Variant>,
+}
+
+///something made from an object at serve time, rather than served as it was uploaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Variant {
+    ///scaled down to fit, if it's an image that can be
+    Resized(Dimensions),
+    ///Markdown rendered to HTML, with [`RenderMarkdown`] on
+    Rendered,
+}
+
+impl Variant {
+    ///what's added to the uploaded file's ETag, so each variant has its own
+    fn tag(&self) -> String {
+        match self {
+            Self::Resized(dimensions) => dimensions.tag(),
+            Self::Rendered => "html".to_string(),
+        }
+    }
+}
+
+impl From<String> for CacheKey {
+    ///the object as it is in the bucket
+    fn from(path: String) -> Self {
+        Self {
+            path,
+            encoding: None,
+            variant: None,
+        }
+    }
+}
+
+impl From<&str> for CacheKey {
+    fn from(path: &str) -> Self {
+        path.to_string().into()
+    }
+}
+
+///a cache that's bounded by how many bytes it holds rather than how many files. Every variant is
+///weighed separately. TinyLFU only lets a new file in over ones that have been asked for more, so
+///a scan of things nobody asks for twice doesn't push out what's popular
+fn build_cache(max_bytes: u64, counters: Arc<AdmissionCounters>) -> Cache<CacheKey, CacheEntry> {
+    CacheBuilder::new(max_bytes)
+        .weigher(|key: &CacheKey, entry: &CacheEntry| {
+            (key.path.len() + entry.content.len() + entry.content_type.len())
+                .try_into()
+                .unwrap_or(u32::MAX)
+        })
+        .eviction_policy(EvictionPolicy::tiny_lfu())
+        .eviction_listener(move |_, _, cause| {
+            if cause == RemovalCause::Size {
+                counters.evicted.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .support_invalidation_closures()
+        .build()
+}
+
+///whether `entry` is small enough to be worth keeping
+fn fits(entry: &CacheEntry, cache_admit_max_bytes: Option<u64>) -> bool {
+    cache_admit_max_bytes.is_none_or(|max| entry.content.len() as u64 <= max)
+}
+
+fn build_failing() -> Cache<String, StoreFailure> {
+    CacheBuilder::new(MAX_FAILING_PATHS)
+        .time_to_live(FAILURE_BACKOFF)
+        .build()
+}
+
+///drops every variant of the object at `path`, returning whether there were any. This goes
+///through the whole cache, so is for one-offs like a purge - anything dropping several paths
+///should use [`Stale`]
+async fn invalidate_path(cache: &Cache<CacheKey, CacheEntry>, path: &str) -> bool {
+    let keys: Vec<Arc<CacheKey>> = cache
+        .iter()
+        .filter(|(key, _)| key.path == path)
+        .map(|(key, _)| key)
+        .collect();
+    for key in &keys {
+        cache.invalidate(&**key).await;
+    }
+    !keys.is_empty()
+}
+
+///what's cached that's no longer the current version, gathered up so it can all be dropped at once
+///rather than going through the cache for every path
+#[derive(Debug, Default)]
+struct Stale {
+    ///gone, or too big to cache, so nothing for them should be left
+    gone: HashSet<String>,
+    ///swapped for new bytes, so only what was made from the old ones goes
+    swapped: HashSet<String>,
+}
+
+impl Stale {
+    ///puts the new bytes for `path` in place of the old ones in one go, so nothing asking for it in
+    ///between misses. The entry's validators go in with them, so a response never has the old
+    ///bytes with the new version's ETag or the other way round. Any other variants were made from
+    ///the old bytes, so go with [`Self::invalidate`]
+    async fn swap(&mut self, cache: &Cache<CacheKey, CacheEntry>, path: String, entry: CacheEntry) {
+        cache.insert(path.clone().into(), entry).await;
+        self.swapped.insert(path);
+    }
+
+    fn remove(&mut self, path: String) {
+        self.gone.insert(path);
+    }
+
+    ///the one pass over the cache, however many paths there are
+    fn invalidate(self, cache: &Cache<CacheKey, CacheEntry>) {
+        if self.gone.is_empty() && self.swapped.is_empty() {
+            return;
+        }
+        let Self { gone, swapped } = self;
+        if let Err(e) = cache.invalidate_entries_if(move |key, _| {
+            gone.contains(&key.path)
+                || ((key.encoding.is_some() || key.variant.is_some())
+                    && swapped.contains(&key.path))
+        }) {
+            warn!(?e, "Error invalidating old entries");
+        }
+    }
+}
+
+///some stores don't keep the content type of objects put there by other tools, so it's guessed from
+///the extension instead, as uploads would've
+fn content_type_or_guess(content_type: Option<String>, path: &str) -> String {
+    content_type.unwrap_or_else(|| {
+        let guess = guess_content_type(path);
+        warn!(?path, ?guess, "No content type from the store, guessing");
+        guess
+    })
+}
+
+///`content_type` with `; charset=utf-8` added if it's text-like and doesn't already say, `None`
+///otherwise
+fn with_default_charset(content_type: &str) -> Option<String> {
+    let mime: mime::Mime = content_type.parse().ok()?;
+    if mime.get_param(mime::CHARSET).is_some() {
+        return None;
+    }
+    let text_like = mime.type_() == mime::TEXT
+        || (mime.type_() == mime::APPLICATION
+            && ["json", "javascript"].contains(&mime.subtype().as_str()));
+    text_like.then(|| format!("{content_type}; charset=utf-8"))
+}
+
+///what we got back when asking S3 for a file
+enum S3File {
+    Read(CacheEntry),
+    ///bigger than `MAX_CACHEABLE_BYTES`, so it wasn't read and needs streaming instead
+    TooLarge {
+        content_type: String,
+        content_encoding: Option<String>,
+        content_length: u64,
+    },
+}
+
+///which upload is being served, for monitors to check a deploy went out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeployedVersion {
+    ///of `upload_data.json`, in hex. Quoted, it's the `ETag` to send back in an `If-Match`
+    pub upload_data_sha256: String,
+    pub entries: usize,
+    pub root: String,
+}
+
+impl DeployedVersion {
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.upload_data_sha256)
+    }
+
+    ///whether an `If-Match` lets something go ahead on this upload. Only strong comparisons count,
+    ///so a weak ETag never matches
+    pub fn matches(&self, if_match: &str) -> bool {
+        let etag = self.etag();
+        if_match.trim() == "*" || if_match.split(',').any(|x| x.trim() == etag)
+    }
+}
+
+///another reload got there first, so whatever this one would've found, that one will
+#[derive(Debug)]
+pub struct AlreadyReloading;
+
+impl std::fmt::Display for AlreadyReloading {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "already reloading")
+    }
+}
+
+impl std::error::Error for AlreadyReloading {}
+
+///what checking one site's upload data for changes found
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SiteReload {
+    ///`None` for the site at the top of the bucket
+    pub host: Option<String>,
+    pub manifest_changed: bool,
+    ///files dropped from the cache as they're no longer uploaded
+    pub invalidated: usize,
+    ///files that are new or changed, and are being read in again
+    pub updated: usize,
+    ///hex SHA-256 of the upload data now being served
+    pub manifest_hash: String,
+}
+
 #[derive(Clone)]
 pub struct Pages {
     upload_data: Arc<RwLock<UploadData>>,
-    last_upload_hash: Arc<Mutex<Vec<u8>>>,
-    cache: Cache<String, (Vec<u8>, String)>,
+    ///of the upload data's bytes, only ever written while holding `upload_data`'s lock
+    upload_hash: Arc<RwLock<Vec<u8>>>,
+    ///from [`index_casings`], and only there if [`CaseInsensitivePaths`] is on. Written alongside
+    ///`upload_hash`
+    casings: Arc<RwLock<HashMap<String, String>>>,
+    ///from [`index_fallbacks`], and only there if [`IndexFiles`] has more than `index.html`.
+    ///Written alongside `upload_hash`
+    indexes: Arc<RwLock<HashMap<String, String>>>,
+    ///when the upload data was last read in, which is as close as we get to when each file last
+    ///changed. Written alongside `upload_hash`
+    loaded_at: Arc<RwLock<SystemTime>>,
+    last_upload_fetched: Arc<Mutex<LastFetched>>,
+    ///swapped for a bigger or smaller one by [`Self::resize_cache`], so get it with [`Self::cache`]
+    cache: Arc<SyncRwLock<Cache<CacheKey, CacheEntry>>>,
+    ///keys the store recently wouldn't give us, and why, for [`FAILURE_BACKOFF`]
+    failing: Cache<String, StoreFailure>,
+    ///files made up at serve time rather than uploaded, kept until the upload data changes
+    generated: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    warm_progress: Arc<WarmProgress>,
+    admission: Arc<AdmissionCounters>,
+    stats: HitStats,
+    ///what warming & reloading run in, so shutting down can stop or wait for them
+    background: Background,
+    ///the host this site was uploaded for, `None` for the one at the top of the bucket
+    site: Option<String>,
+    max_cacheable_bytes: Option<u64>,
+    cache_admit_max_bytes: Option<u64>,
+    case_insensitive_paths: CaseInsensitivePaths,
+    index_files: IndexFiles,
+    ///`Some` if `.md` files can be rendered to HTML
+    render_markdown: Option<RenderMarkdown>,
+    upload_data_location: String,
 }
 
 impl Pages {
-    #[instrument(skip(bucket))]
+    ///if there's a maximum size, this checks it with a HEAD before reading anything. `validators`
+    ///are for the version of the file that's expected to be there
+    #[instrument(skip(bucket, validators))]
     async fn read_file_from_s3(
         path: String,
-        bucket: &Bucket,
-    ) -> color_eyre::Result<(Vec<u8>, String, String)> {
-        let contents = bucket.get_object(&path).await?;
-        let headers = contents.headers();
+        validators: Validators,
+        bucket: &dyn ObjectStore,
+        max_cacheable_bytes: Option<u64>,
+    ) -> Result<(S3File, String), ShoveError> {
+        if let Some(max_cacheable_bytes) = max_cacheable_bytes {
+            let Some(head) = bucket.head(&path).await? else {
+                return Err(ShoveError::Missing { key: path });
+            };
+            let content_length = head.content_length;
+
+            if content_length > max_cacheable_bytes {
+                let content_type = content_type_or_guess(head.content_type, &path);
+                trace!(?path, ?content_length, "File too large to cache");
+                return Ok((
+                    S3File::TooLarge {
+                        content_type,
+                        content_encoding: head.content_encoding,
+                        content_length,
+                    },
+                    path,
+                ));
+            }
+        }
 
-        let Some(content_type) = headers.get("content-type") else {
-            bail!("unable to get CONTENT_TYPE");
+        let Fetched::Found(contents) = bucket.get(&path, None).await? else {
+            return Err(ShoveError::Missing { key: path });
         };
-        let bytes = contents.to_vec();
-        trace!(?path, len=?bytes.len(), ?content_type, "Read in file from S3");
 
-        Ok((bytes, content_type.to_owned(), path))
+        let content_type = content_type_or_guess(contents.content_type, &path);
+        let bytes = contents.bytes;
+        let content_encoding = contents.content_encoding;
+        trace!(?path, len=?bytes.len(), ?content_type, ?content_encoding, "Read in file from S3");
+
+        Ok((
+            S3File::Read(CacheEntry {
+                content: bytes,
+                content_type,
+                content_encoding,
+                validators,
+            }),
+            path,
+        ))
     }
 
-    pub async fn new(bucket: &Bucket) -> color_eyre::Result<Option<Self>> {
-        let (upload_data, hash) = {
-            let data = bucket.get_object(UPLOAD_DATA_LOCATION).await;
-            match data {
-                Ok(data) => {
-                    let bytes = data.bytes();
-                    let ud: UploadData = from_slice(bytes)?;
-                    let hash = hash_raw_bytes(bytes);
-                    (ud, hash)
-                }
-                Err(e) => {
-                    return match e {
-                        S3Error::HttpFailWithBody(404, _) => Ok(None),
-                        _ => Err(e.into()),
-                    }
+    ///`site` is the host the site was uploaded for, or `None` for the one at the top of the bucket
+    pub async fn new(
+        bucket: &Store,
+        site: Option<&str>,
+        settings: &SiteSettings,
+        stats: HitStats,
+        background: Background,
+    ) -> color_eyre::Result<Option<Self>> {
+        let upload_data_location = site_location(site, UPLOAD_DATA_LOCATION);
+        let (upload_data, upload_hash, last_upload_fetched) = {
+            match bucket.get(&upload_data_location, None).await? {
+                Fetched::Found(data) => {
+                    let ud: UploadData = parse_json(&upload_data_location, &data.bytes)?;
+                    (
+                        ud,
+                        hash_raw_bytes(&data.bytes),
+                        LastFetched::new(&data.bytes),
+                    )
                 }
+                Fetched::NotModified | Fetched::Missing => return Ok(None),
             }
         };
 
-        let cache = CacheBuilder::new(256)
-            .support_invalidation_closures()
-            .build();
+        let admission = Arc::new(AdmissionCounters::default());
+        let cache = build_cache(settings.cache_max_bytes, admission.clone());
+        let max_cacheable_bytes = settings.max_cacheable_bytes;
+        let cache_admit_max_bytes = settings.cache_admit_max_bytes;
+        let case_insensitive_paths = settings.case_insensitive_paths;
+        let casings = if case_insensitive_paths == CaseInsensitivePaths::Off {
+            HashMap::new()
+        } else {
+            index_casings(upload_data.entries.keys())
+        };
+        let index_files = settings.index_files.clone();
+        let indexes = if index_files.only_index_html() {
+            HashMap::new()
+        } else {
+            index_fallbacks(&index_files, upload_data.entries.keys())
+        };
 
-        match Self::read_file_from_s3(format!("{}/404.html", &upload_data.root), bucket).await {
-            Ok((contents, content_type, path)) => {
+        let loaded_at = SystemTime::now();
+        match Self::read_file_from_s3(
+            entry_key(&upload_data.root, "/404.html"),
+            Validators::default(),
+            bucket,
+            max_cacheable_bytes,
+        )
+        .await
+        {
+            Ok((S3File::Read(entry), path)) => {
                 info!("Adding 404 path to cache");
-                cache.insert(path, (contents, content_type)).await;
+                cache.insert(path.into(), entry).await;
             }
+            Ok((S3File::TooLarge { .. }, path)) => warn!(?path, "404 page too large to cache"),
             Err(e) => error!(?e, "Error getting 404 page from S3"),
         }
 
+        let redirects_key = entry_key(&upload_data.root, REDIRECTS_PATH);
+        let to_warm: Vec<(String, Validators)> = warm_order(
+            &upload_data.root,
+            upload_data.entries.keys().filter(|x| **x != redirects_key),
+            |path| stats.requests(&stats_key(site, path)),
+        )
+        .into_iter()
+        .map(|key| {
+            let validators = validators_for(&upload_data, &key, loaded_at);
+            (key, validators)
+        })
+        .collect();
+        let warm_progress = Arc::new(WarmProgress::default());
+        warm_progress.total.store(to_warm.len(), Ordering::Relaxed);
+
         let task_cache = cache.clone();
         let task_bucket = bucket.clone();
-        let task_upload_data = upload_data.clone();
-        tokio::task::spawn(async move {
-            let mut read_files: FuturesUnordered<_> = task_upload_data
-                .entries
-                .keys()
-                .map(|pb| Self::read_file_from_s3(pb.clone(), &task_bucket))
-                .collect();
+        let task_progress = warm_progress.clone();
+        //not worth holding up shutting down for, as it'd only be warming a cache that's about to go
+        let stopping = background.stopping();
+        background.spawn("warm", async move {
+            let mut read_files = std::pin::pin!(futures::stream::iter(to_warm)
+                .map(|(pb, validators)| Self::read_file_from_s3(pb, validators, &*task_bucket, max_cacheable_bytes))
+                .buffer_unordered(WARM_CONCURRENCY)
+                .take_until(stopping));
 
             while let Some(res) = read_files.next().await {
                 match res {
-                    Ok((contents, content_type, path)) => {
-                        trace!(?path, "initial load adding to cache");
-                        task_cache.insert(path, (contents, content_type)).await;
+                    Ok((S3File::Read(entry), path)) if !fits(&entry, cache_admit_max_bytes) => {
+                        trace!(?path, "initial load skipping file too big to admit");
+                    }
+                    Ok((S3File::Read(entry), path)) => {
+                        task_cache.insert(path.clone().into(), entry).await;
+                        let cached = task_progress.cached.fetch_add(1, Ordering::Relaxed) + 1;
+                        trace!(?path, %cached, "initial load adding to cache");
+                    }
+                    Ok((S3File::TooLarge { .. }, path)) => {
+                        trace!(?path, "initial load skipping large file");
                     }
                     Err(e) => {
                         warn!(?e, "Error reading file from S3")
@@ -94,45 +855,86 @@ impl Pages {
                 }
             }
 
-            info!("Read files from S3");
+            task_cache.run_pending_tasks().await;
+            task_progress.finished.store(true, Ordering::Relaxed);
+            let warmth = task_progress.snapshot();
+            info!(weighted_size=%task_cache.weighted_size(), cached=%warmth.cached, total=%warmth.total, "Read files from S3");
         });
 
         Ok(Some(Self {
             upload_data: Arc::new(RwLock::new(upload_data)),
-            last_upload_hash: Arc::new(Mutex::new(hash)),
-            cache,
+            upload_hash: Arc::new(RwLock::new(upload_hash)),
+            casings: Arc::new(RwLock::new(casings)),
+            indexes: Arc::new(RwLock::new(indexes)),
+            loaded_at: Arc::new(RwLock::new(loaded_at)),
+            last_upload_fetched: Arc::new(Mutex::new(last_upload_fetched)),
+            cache: Arc::new(SyncRwLock::new(cache)),
+            failing: build_failing(),
+            generated: Arc::default(),
+            warm_progress,
+            admission,
+            stats,
+            background,
+            site: site.map(ToString::to_string),
+            max_cacheable_bytes,
+            cache_admit_max_bytes,
+            case_insensitive_paths,
+            index_files,
+            render_markdown: settings.render_markdown.clone(),
+            upload_data_location,
         }))
     }
 
     pub async fn check_and_reload(
         &self,
-        bucket: &Bucket,
+        bucket: &Store,
         reloader: LiveReloader,
-    ) -> color_eyre::Result<()> {
-        let Ok(last_upload_hash) = self.last_upload_hash.try_lock() else {
-            bail!("Already reloading");
+    ) -> color_eyre::Result<SiteReload> {
+        let Ok(mut last_upload_fetched) = self.last_upload_fetched.try_lock() else {
+            return Err(AlreadyReloading.into());
         };
 
-        let (bytes, hash) = {
-            let rsp = bucket.get_object(UPLOAD_DATA_LOCATION).await?;
-            let bytes = rsp.to_vec();
-            let hash = hash_raw_bytes(&bytes);
-            (bytes, hash)
+        let Some(bytes) =
+            get_bytes_if_changed(bucket, &self.upload_data_location, &mut last_upload_fetched)
+                .await?
+        else {
+            return Ok(SiteReload {
+                host: self.site.clone(),
+                manifest_changed: false,
+                invalidated: 0,
+                updated: 0,
+                manifest_hash: to_hex(&self.upload_hash.read().await),
+            });
         };
-
-        if *last_upload_hash == hash {
-            return Ok(());
+        if bytes.is_empty() {
+            bail!("Upload data has gone missing");
         }
 
         let old_upload_data = self.upload_data.read().await.clone();
-        let new_upload_data: UploadData = from_slice(&bytes)?;
+        let new_upload_data: UploadData = parse_json(&self.upload_data_location, &bytes)?;
 
         info!("Reloading cache");
+        //for what's read in of the new version. Whatever's still cached keeps its own validators
+        //until `Stale::swap` puts the new bytes in with these
+        let loaded_at = SystemTime::now();
 
-        *self.upload_data.write().await = new_upload_data.clone();
+        {
+            let mut upload_data = self.upload_data.write().await;
+            *upload_data = new_upload_data.clone();
+            *self.upload_hash.write().await = hash_raw_bytes(&bytes);
+            if self.case_insensitive_paths != CaseInsensitivePaths::Off {
+                *self.casings.write().await = index_casings(new_upload_data.entries.keys());
+            }
+            if !self.index_files.only_index_html() {
+                *self.indexes.write().await =
+                    index_fallbacks(&self.index_files, new_upload_data.entries.keys());
+            }
+            *self.loaded_at.write().await = loaded_at;
+        }
+        self.forget_generated().await;
 
         let mut to_be_updated: HashSet<String> = new_upload_data.entries.keys().cloned().collect();
-        let mut to_be_removed: Vec<String> = vec![];
+        let mut to_be_removed: HashSet<String> = HashSet::new();
 
         for (old_entry, old_hash) in old_upload_data.entries {
             match new_upload_data.entries.get(&old_entry) {
@@ -141,128 +943,2458 @@ impl Pages {
                         to_be_updated.remove(&old_entry);
                     }
                 }
-                None => to_be_removed.push(old_entry),
+                None => {
+                    to_be_removed.insert(old_entry);
+                }
             }
         }
+        //picked up separately by the redirects, and never served
+        to_be_updated.remove(&entry_key(&new_upload_data.root, REDIRECTS_PATH));
 
-        if let Err(e) = self
-            .cache
-            .invalidate_entries_if(move |entry, _| to_be_removed.contains(entry))
-        {
-            warn!(?e, "Error invalidating old entries")
+        let changes = ChangedPaths {
+            changed: keys_to_paths(&new_upload_data.root, &to_be_updated),
+            removed: keys_to_paths(&new_upload_data.root, &to_be_removed),
+        };
+        let report = SiteReload {
+            host: self.site.clone(),
+            manifest_changed: true,
+            invalidated: to_be_removed.len(),
+            updated: to_be_updated.len(),
+            manifest_hash: to_hex(&hash_raw_bytes(&bytes)),
+        };
+
+        Stale {
+            gone: to_be_removed,
+            swapped: HashSet::new(),
         }
+        .invalidate(&self.cache());
 
-        let task_cache = self.cache.clone();
+        let task_cache = self.cache();
         let task_bucket = bucket.clone();
-        tokio::task::spawn(async move {
+        let max_cacheable_bytes = self.max_cacheable_bytes;
+        //whatever's cached is evidently being asked for, so gets asked for first. Until the new
+        //bytes are in, the old ones keep being served rather than everyone waiting on the store
+        let mut to_be_updated: Vec<(String, Validators)> = to_be_updated
+            .into_iter()
+            .map(|key| {
+                let validators = validators_for(&new_upload_data, &key, loaded_at);
+                (key, validators)
+            })
+            .collect();
+        to_be_updated.sort_by_cached_key(|(path, _)| {
+            !task_cache.contains_key(&CacheKey::from(path.as_str()))
+        });
+        let reloading = self.background.spawn("reload", async move {
             let mut read_files: FuturesUnordered<_> = to_be_updated
                 .into_iter()
-                .map(|pb| Self::read_file_from_s3(pb.clone(), &task_bucket))
+                .map(|(pb, validators)| {
+                    Self::read_file_from_s3(pb, validators, &*task_bucket, max_cacheable_bytes)
+                })
                 .collect();
 
+            //what was made from the old bytes is still the old version, so can be served with its
+            //own validators until it's all dropped at the end
+            let mut stale = Stale::default();
             while let Some(res) = read_files.next().await {
                 match res {
-                    Ok((contents, content_type, path)) => {
+                    Ok((S3File::Read(entry), path)) => {
                         info!(?path, "file changed, updating");
-                        task_cache.insert(path, (contents, content_type)).await;
+                        stale.swap(&task_cache, path, entry).await;
+                    }
+                    Ok((S3File::TooLarge { .. }, path)) => {
+                        info!(?path, "large file changed, removing from cache");
+                        stale.remove(path);
                     }
                     Err(e) => {
-                        warn!(?e, "Error updating file from S3")
+                        warn!(?e, failure=%StoreFailure::of(&e), "Error updating file from S3");
+                        //anything other than it having gone keeps the old version until it can be read
+                        if let ShoveError::Missing { key } = e {
+                            stale.remove(key);
+                        }
                     }
                 }
             }
+            stale.invalidate(&task_cache);
 
-            info!("Updated cache from S3");
-            if let Err(e) = reloader.send_reload().await {
+            task_cache.run_pending_tasks().await;
+            info!(weighted_size=%task_cache.weighted_size(), "Updated cache from S3");
+            if let Err(e) = reloader.send_reload(&changes).await {
                 error!(?e, "Error reloading tasks");
             }
         });
+        if reloading.is_none() {
+            warn!("Shutting down, so not fetching the files that changed");
+        }
+
+        Ok(report)
+    }
+
+    ///re-fetches one file that's changed in the bucket. `false` if it isn't one of ours
+    pub async fn refresh_key(
+        &self,
+        bucket: &Store,
+        key: &str,
+        reloader: LiveReloader,
+    ) -> color_eyre::Result<bool> {
+        let (root, validators) = {
+            let upload_data = self.upload_data.read().await;
+            if !upload_data.entries.contains_key(key) {
+                return Ok(false);
+            }
+            let validators = validators_for(&upload_data, key, *self.loaded_at.read().await);
+            (upload_data.root.clone(), validators)
+        };
+
+        let cache = self.cache();
+        let mut stale = Stale::default();
+        match Self::read_file_from_s3(
+            key.to_string(),
+            validators,
+            bucket,
+            self.max_cacheable_bytes,
+        )
+        .await?
+        {
+            (S3File::Read(entry), path) => {
+                info!(?path, "file changed, updating");
+                stale.swap(&cache, path, entry).await;
+            }
+            (S3File::TooLarge { .. }, path) => {
+                info!(?path, "large file changed, removing from cache");
+                stale.remove(path);
+            }
+        }
+        stale.invalidate(&cache);
 
-        Ok(())
+        let changes = ChangedPaths {
+            changed: keys_to_paths(&root, [&key.to_string()]),
+            removed: vec![],
+        };
+        if let Err(e) = reloader.send_reload(&changes).await {
+            error!(?e, "Error reloading tasks");
+        }
+
+        Ok(true)
     }
 
+    ///`path` must already have been through [`resolve_request_path`]
+    ///counted towards the hit stats. Without `admit`, a miss is served but not cached
     pub async fn get(
         &self,
-        bucket: &Bucket,
+        bucket: &Store,
         path: &str,
         ccm: &CacheControlManager,
+        admit: bool,
+        variant: Option<Variant>,
     ) -> Option<PageOutput> {
-        let root = self.upload_data.read().await.clone().root;
-        let cache_path = format!("{root}{path}");
-
-        let not_found = || async {
-            let (content, content_type) = self.cache.get(&format!("{root}/404.html")).await?;
-            Some(PageOutput {
-                content,
-                cache_control: vec![Directive::MaxAge(604800)],
-                content_type,
-                status: StatusCode::NOT_FOUND,
-            })
-        };
+        let output = self.find(bucket, path, ccm, admit, variant).await;
+        if let Some(output) = &output {
+            self.stats.record(
+                &stats_key(self.site.as_deref(), path),
+                output.cache_status,
+                output.content_length(),
+            );
+        }
+        output
+    }
 
-        if let Some((content, content_type)) = self.cache.get(&cache_path).await {
-            let cache_control = ccm.get_directives(path).await;
-            return Some(PageOutput {
-                content,
-                content_type,
-                cache_control,
-                status: StatusCode::OK,
-            });
+    ///swaps `output`'s content for it scaled down to fit in `dimensions`, if it's an image that can
+    ///be. If it couldn't be resized, that's the original, which is then what's cached & served
+    async fn resize(
+        &self,
+        output: &mut PageOutput,
+        path: &str,
+        dimensions: Dimensions,
+        admit: bool,
+    ) {
+        let resizable = output.status == StatusCode::OK
+            && output.content_encoding.is_none()
+            && resize::can_resize(&output.content_type);
+        if !resizable {
+            return;
         }
 
-        match self.upload_data.read().await.entries.get(&cache_path) {
-            Some(_hash) => match Self::read_file_from_s3(cache_path.clone(), bucket).await {
-                Ok((content, content_type, cache_path)) => {
-                    info!(?cache_path, "Adding to cache");
-                    self.cache
-                        .insert(cache_path.clone(), (content.clone(), content_type.clone()))
-                        .await;
-                    let cache_control = ccm.get_directives(path).await;
-                    Some(PageOutput {
-                        content,
-                        content_type,
-                        cache_control,
-                        status: StatusCode::OK,
-                    })
-                }
-                Err(e) => {
-                    warn!(
-                        ?e,
-                        "Error getting file from S3, removing from local upload data"
-                    );
-                    self.upload_data.write().await.entries.remove(&cache_path);
-
-                    not_found().await
-                }
+        self.make_variant(
+            output,
+            path,
+            Variant::Resized(dimensions),
+            admit,
+            move |entry| CacheEntry {
+                content: resize::resize(&entry.content, &entry.content_type, dimensions)
+                    .unwrap_or(entry.content),
+                ..entry
             },
-            None => not_found().await,
+        )
+        .await;
+    }
+
+    ///swaps `output`'s content for it rendered to HTML, if it's a Markdown file & that's on. Anything
+    ///that can't be decompressed to be rendered is served as it was uploaded
+    async fn render_markdown(
+        &self,
+        output: &mut PageOutput,
+        path: &str,
+        ccm: &CacheControlManager,
+        admit: bool,
+    ) {
+        let Some(settings) = self.render_markdown.clone() else {
+            return;
+        };
+        if output.status != StatusCode::OK || !is_markdown(path) {
+            return;
         }
+
+        let fallback_title = Path::new(path)
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.make_variant(output, path, Variant::Rendered, admit, move |entry| {
+            let markdown = match &entry.content_encoding {
+                Some(encoding) => match encoding::decode(encoding, &entry.content) {
+                    Some(decoded) => decoded,
+                    None => return entry,
+                },
+                None => entry.content,
+            };
+            let page = markdown::render(
+                &String::from_utf8_lossy(&markdown),
+                &fallback_title,
+                &settings,
+            );
+            CacheEntry {
+                content: page,
+                content_type: "text/html; charset=utf-8".to_string(),
+                content_encoding: None,
+                validators: entry.validators,
+            }
+        })
+        .await;
+        output.cache_control = ccm.get_directives(path, &output.content_type).await;
     }
-}
 
-pub struct PageOutput {
-    content: Vec<u8>,
+    ///swaps `output`'s content for `variant` of it, which `make` turns what was uploaded into off
+    ///the async threads. Whatever comes of it is cached, so each variant only gets made once.
+    ///Streamed files are too big to be worth holding in memory to make anything of, so are left
+    async fn make_variant(
+        &self,
+        output: &mut PageOutput,
+        path: &str,
+        variant: Variant,
+        admit: bool,
+        make: impl FnOnce(CacheEntry) -> CacheEntry + Send + 'static,
+    ) {
+        let PageContent::Buffered(content) = &output.content else {
+            return;
+        };
+        let key = CacheKey {
+            path: entry_key(&self.root().await, path),
+            encoding: None,
+            variant: Some(variant),
+        };
+
+        let entry = match self.cache().get(&key).await {
+            Some(entry) => {
+                output.cache_status = CacheStatus::Hit;
+                entry
+            }
+            None => {
+                let original = CacheEntry {
+                    content: content.clone(),
+                    content_type: output.content_type.clone(),
+                    content_encoding: output.content_encoding.clone(),
+                    validators: output.validators.clone(),
+                };
+                let entry = match tokio::task::spawn_blocking(move || make(original)).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(
+                            ?e,
+                            ?path,
+                            ?variant,
+                            "Making variant panicked, serving the original"
+                        );
+                        return;
+                    }
+                };
+                output.cache_status = if admit && fits(&entry, self.cache_admit_max_bytes) {
+                    trace!(?key, "Adding variant to cache");
+                    self.cache().insert(key, entry.clone()).await;
+                    CacheStatus::Miss
+                } else {
+                    CacheStatus::Bypass
+                };
+                entry
+            }
+        };
+
+        output.content = PageContent::Buffered(entry.content);
+        output.content_type = entry.content_type;
+        output.content_encoding = entry.content_encoding;
+        //the variant's own, as it could've been made from an older version than `output` is
+        output.validators = entry.validators;
+        output.validators.for_variant(&variant.tag());
+        if let Some(decoded) = &mut output.decoded {
+            decoded.key.variant = Some(variant);
+        }
+    }
+
+    ///where a copy of the object at `path` decoded for clients that can't take it compressed goes
+    fn decoded_cache(&self, path: String, admit: bool) -> DecodedCache {
+        DecodedCache {
+            cache: self.cache(),
+            key: CacheKey {
+                path,
+                encoding: Some("identity".to_string()),
+                variant: None,
+            },
+            admit,
+            cache_admit_max_bytes: self.cache_admit_max_bytes,
+        }
+    }
+
+    ///the site's own page for `status`, eg. `/404.html`
+    async fn error_page(
+        &self,
+        bucket: &Store,
+        root: &str,
+        status: StatusCode,
+    ) -> Option<(PageContent, String, Option<String>)> {
+        let error_path = entry_key(root, &format!("/{}.html", status.as_u16()));
+        match self.cache().get(&CacheKey::from(error_path.as_str())).await {
+            Some(entry) => Some((
+                PageContent::Buffered(entry.content),
+                entry.content_type,
+                entry.content_encoding,
+            )),
+            //it can get evicted like anything else, so fetch it again if needs be
+            None => {
+                match Self::read_file_from_s3(
+                    error_path,
+                    Validators::default(),
+                    bucket,
+                    self.max_cacheable_bytes,
+                )
+                .await
+                {
+                    Ok((S3File::Read(entry), path)) => {
+                        info!(?path, "Re-adding error page to cache");
+                        self.cache().insert(path.into(), entry.clone()).await;
+                        Some((
+                            PageContent::Buffered(entry.content),
+                            entry.content_type,
+                            entry.content_encoding,
+                        ))
+                    }
+                    Ok((
+                        S3File::TooLarge {
+                            content_type,
+                            content_encoding,
+                            content_length,
+                        },
+                        path,
+                    )) => Some((
+                        PageContent::Streamed {
+                            bucket: bucket.clone(),
+                            path,
+                            content_length,
+                        },
+                        content_type,
+                        content_encoding,
+                    )),
+                    Err(e) => {
+                        trace!(?e, %status, "Unable to get error page from S3");
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    ///what was uploaded at `path`, made into `variant` of it if that's asked for & it can be
+    async fn find(
+        &self,
+        bucket: &Store,
+        path: &str,
+        ccm: &CacheControlManager,
+        admit: bool,
+        variant: Option<Variant>,
+    ) -> Option<PageOutput> {
+        let mut output = self.find_uploaded(bucket, path, ccm, admit).await?;
+        match variant {
+            Some(Variant::Resized(dimensions)) => {
+                self.resize(&mut output, path, dimensions, admit).await;
+            }
+            Some(Variant::Rendered) => {
+                self.render_markdown(&mut output, path, ccm, admit).await;
+            }
+            None => {}
+        }
+        Some(output)
+    }
+
+    async fn find_uploaded(
+        &self,
+        bucket: &Store,
+        path: &str,
+        ccm: &CacheControlManager,
+        admit: bool,
+    ) -> Option<PageOutput> {
+        //both from the same upload, so a switch to a new root part way through can't mix the two.
+        //Anything cached has validators of its own, so these are only for what gets read in
+        let (root, known, validators) = {
+            let upload_data = self.upload_data.read().await;
+            let key = entry_key(&upload_data.root, path);
+            let validators = validators_for(&upload_data, &key, *self.loaded_at.read().await);
+            (
+                upload_data.root.clone(),
+                upload_data.entries.contains_key(&key),
+                validators,
+            )
+        };
+        let cache_path = entry_key(&root, path);
+        //shove's own files are under the root too, when that's the top of the bucket or a site's directory
+        let hidden = cache_path == entry_key(&root, REDIRECTS_PATH) || is_reserved_key(&cache_path);
+
+        let not_found = || async {
+            let (content, content_type, content_encoding) = self
+                .error_page(bucket, &root, StatusCode::NOT_FOUND)
+                .await?;
+            Some(PageOutput {
+                content,
+                cache_control: vec![Directive::MaxAge(604800)],
+                content_type,
+                content_encoding,
+                status: StatusCode::NOT_FOUND,
+                cache_status: CacheStatus::NotFound,
+                validators: Validators::default(),
+                range: ByteRange::Full,
+                vary_encoding: false,
+                decoded: None,
+            })
+        };
+        //always something, as otherwise it'd look like a 404
+        let root = &root;
+        let failed = |failure: StoreFailure| async move {
+            let status = failure.status_code();
+            let (content, content_type, content_encoding) = self
+                .error_page(bucket, root, status)
+                .await
+                .unwrap_or_else(|| {
+                    (
+                        PageContent::Buffered(vec![]),
+                        mime::TEXT_PLAIN.to_string(),
+                        None,
+                    )
+                });
+            Some(PageOutput {
+                content,
+                cache_control: vec![Directive::NoStore],
+                content_type,
+                content_encoding,
+                status,
+                cache_status: CacheStatus::Unavailable,
+                validators: Validators::default(),
+                range: ByteRange::Full,
+                vary_encoding: false,
+                decoded: None,
+            })
+        };
+
+        if hidden {
+            return not_found().await;
+        }
+
+        if let Some(entry) = self.cache().get(&CacheKey::from(cache_path.as_str())).await {
+            let cache_control = ccm.get_directives(path, &entry.content_type).await;
+            return Some(PageOutput {
+                content: PageContent::Buffered(entry.content),
+                content_type: entry.content_type,
+                content_encoding: entry.content_encoding,
+                cache_control,
+                status: StatusCode::OK,
+                cache_status: CacheStatus::Hit,
+                validators: entry.validators,
+                range: ByteRange::Full,
+                vary_encoding: false,
+                decoded: Some(self.decoded_cache(cache_path, admit)),
+            });
+        }
+
+        if known {
+            if let Some(failure) = self.failing.get(&cache_path).await {
+                trace!(?cache_path, %failure, "Backing off from the store");
+                return failed(failure).await;
+            }
+
+            match Self::read_file_from_s3(
+                cache_path.clone(),
+                validators.clone(),
+                bucket,
+                self.max_cacheable_bytes,
+            )
+            .await
+            {
+                Ok((S3File::Read(entry), cache_path)) => {
+                    let cache_status = if admit && fits(&entry, self.cache_admit_max_bytes) {
+                        info!(?cache_path, "Adding to cache");
+                        self.admission.admitted.fetch_add(1, Ordering::Relaxed);
+                        self.cache()
+                            .insert(cache_path.clone().into(), entry.clone())
+                            .await;
+                        CacheStatus::Miss
+                    } else {
+                        debug!(?cache_path, %admit, "Serving without adding to cache");
+                        self.admission.bypassed.fetch_add(1, Ordering::Relaxed);
+                        CacheStatus::Bypass
+                    };
+                    let cache_control = ccm.get_directives(path, &entry.content_type).await;
+                    Some(PageOutput {
+                        content: PageContent::Buffered(entry.content),
+                        content_type: entry.content_type,
+                        content_encoding: entry.content_encoding,
+                        cache_control,
+                        status: StatusCode::OK,
+                        cache_status,
+                        validators: entry.validators,
+                        range: ByteRange::Full,
+                        vary_encoding: false,
+                        decoded: Some(self.decoded_cache(cache_path, admit)),
+                    })
+                }
+                Ok((
+                    S3File::TooLarge {
+                        content_type,
+                        content_encoding,
+                        content_length,
+                    },
+                    cache_path,
+                )) => {
+                    debug!(?cache_path, "Streaming large file");
+                    self.admission.bypassed.fetch_add(1, Ordering::Relaxed);
+                    let cache_control = ccm.get_directives(path, &content_type).await;
+                    Some(PageOutput {
+                        content: PageContent::Streamed {
+                            bucket: bucket.clone(),
+                            path: cache_path,
+                            content_length,
+                        },
+                        content_type,
+                        content_encoding,
+                        cache_control,
+                        status: StatusCode::OK,
+                        cache_status: CacheStatus::Bypass,
+                        validators,
+                        range: ByteRange::Full,
+                        vary_encoding: false,
+                        decoded: None,
+                    })
+                }
+                Err(e) => match StoreFailure::of(&e) {
+                    StoreFailure::Missing => {
+                        warn!(?e, "File missing from S3, removing from local upload data");
+                        self.upload_data.write().await.entries.remove(&cache_path);
+
+                        not_found().await
+                    }
+                    //it's still meant to be there, so it's kept for once the store's back
+                    failure => {
+                        warn!(?e, %failure, "Error getting file from S3, backing off");
+                        self.failing.insert(cache_path, failure).await;
+
+                        failed(failure).await
+                    }
+                },
+            }
+        } else {
+            not_found().await
+        }
+    }
+
+    ///if `path` is a directory missing its trailing slash, or a file with one, where it should be
+    pub async fn canonical_path(&self, path: &str) -> Option<String> {
+        let upload_data = self.upload_data.read().await;
+        let indexes = self.indexes.read().await;
+        let exists = |resolved: &str| {
+            let key = entry_key(&upload_data.root, resolved);
+            upload_data.entries.contains_key(&key) || indexes.contains_key(&key)
+        };
+
+        let clean = |path: &str| Path::new(path).clean().to_str().map(ToString::to_string);
+        //looked up decoded, but redirected to still encoded
+        let cleaned = normalise_request_path(path).ok()?;
+        let location = clean(path)?;
+        let is_file = Path::new(&cleaned)
+            .extension()
+            .is_some_and(|x| !x.is_empty());
+
+        if path.ends_with('/') {
+            (path != "/" && is_file && exists(&cleaned)).then_some(location)
+        } else {
+            (!is_file && exists(&format!("{cleaned}/index.html"))).then(|| format!("{location}/"))
+        }
+    }
+
+    ///how `path` was uploaded, if it wasn't exactly but was with a different case. `path` must
+    ///already have been through [`resolve_request_path`]
+    pub async fn actual_casing(&self, path: &str) -> Option<String> {
+        let upload_data = self.upload_data.read().await;
+        let key = entry_key(&upload_data.root, path);
+        if upload_data.entries.contains_key(&key) {
+            return None;
+        }
+        let casings = self.casings.read().await;
+        let actual = casings.get(&key.to_lowercase())?;
+        entry_path(&upload_data.root, actual)
+    }
+
+    ///which of the [`IndexFiles`] to serve for a directory, if it isn't its `index.html`. `path`
+    ///must already have been through [`resolve_request_path`]
+    pub async fn index_for(&self, path: &str) -> Option<String> {
+        let upload_data = self.upload_data.read().await;
+        let indexes = self.indexes.read().await;
+        let actual = indexes.get(&entry_key(&upload_data.root, path))?;
+        entry_path(&upload_data.root, actual)
+    }
+
+    ///the `.md` file to serve for a directory that has no `index.html`, so `/docs/intro` can be
+    ///`/docs/intro.md`. `path` must already have been through [`resolve_request_path`]
+    pub async fn markdown_for(&self, path: &str) -> Option<String> {
+        let directory = path.strip_suffix("/index.html")?;
+        if directory.is_empty() || self.contains(path).await {
+            return None;
+        }
+        let markdown = format!("{directory}.md");
+        self.contains(&markdown).await.then_some(markdown)
+    }
+
+    pub async fn root(&self) -> String {
+        self.upload_data.read().await.root.clone()
+    }
+
+    ///whether `path` was uploaded
+    pub async fn contains(&self, path: &str) -> bool {
+        let upload_data = self.upload_data.read().await;
+        upload_data
+            .entries
+            .contains_key(&entry_key(&upload_data.root, path))
+    }
+
+    ///every uploaded path, from the site root
+    pub async fn paths(&self) -> Vec<String> {
+        let upload_data = self.upload_data.read().await;
+        upload_data
+            .entries
+            .keys()
+            .filter_map(|key| entry_path(&upload_data.root, key))
+            .collect()
+    }
+
+    ///a file made up at serve time, only calling `generate` if it hasn't been since the upload data
+    ///last changed
+    pub async fn get_generated<F: Future<Output = Vec<u8>>>(
+        &self,
+        path: &str,
+        content_type: &str,
+        ccm: &CacheControlManager,
+        generate: impl FnOnce() -> F,
+    ) -> PageOutput {
+        let cached = self.generated.read().await.get(path).cloned();
+        let (content, cache_status) = match cached {
+            Some(content) => (content, CacheStatus::Hit),
+            None => {
+                let content = generate().await;
+                self.generated
+                    .write()
+                    .await
+                    .insert(path.to_string(), content.clone());
+                (content, CacheStatus::Miss)
+            }
+        };
+
+        PageOutput {
+            content: PageContent::Buffered(content),
+            cache_control: ccm.get_directives(path, content_type).await,
+            content_type: content_type.to_string(),
+            content_encoding: None,
+            status: StatusCode::OK,
+            cache_status,
+            validators: Validators::default(),
+            range: ByteRange::Full,
+            vary_encoding: false,
+            decoded: None,
+        }
+    }
+
+    ///for when something other than the upload data changes what would be generated
+    pub async fn forget_generated(&self) {
+        self.generated.write().await.clear();
+    }
+
+    ///drops `path` from the cache so the next request fetches it from the bucket again. `path`
+    ///must already have been through [`resolve_request_path`]
+    pub async fn purge(&self, path: &str) -> Purged {
+        let cache_path = entry_key(&self.upload_data.read().await.root, path);
+        let cached = invalidate_path(&self.cache(), &cache_path).await;
+        //generated files get made again on the next request too
+        let generated = self.generated.write().await.remove(path).is_some();
+
+        if cached || generated {
+            debug!(?cache_path, "Purged from cache");
+            Purged::Purged
+        } else {
+            Purged::NotCached
+        }
+    }
+
+    ///what `path` is counted under in the stats
+    pub fn stats_key(&self, path: &str) -> String {
+        stats_key(self.site.as_deref(), path)
+    }
+
+    ///only what's already in memory, so it never asks the bucket
+    pub async fn deployed_version(&self) -> DeployedVersion {
+        let upload_data = self.upload_data.read().await;
+        DeployedVersion {
+            upload_data_sha256: to_hex(&self.upload_hash.read().await),
+            entries: upload_data.entries.len(),
+            root: upload_data.root.clone(),
+        }
+    }
+
+    pub fn warmth(&self) -> Warmth {
+        self.warm_progress.snapshot()
+    }
+
+    pub fn admission(&self) -> Admission {
+        self.admission.snapshot()
+    }
+
+    fn cache(&self) -> Cache<CacheKey, CacheEntry> {
+        self.cache.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    ///swaps the cache for one holding roughly `max_bytes`, keeping as much of what's in it as fits
+    pub async fn resize_cache(&self, max_bytes: u64) {
+        let old = self.cache();
+        if old.policy().max_capacity() == Some(max_bytes) {
+            return;
+        }
+
+        let new = build_cache(max_bytes, self.admission.clone());
+        for (key, value) in &old {
+            new.insert(Arc::unwrap_or_clone(key), value).await;
+        }
+        new.run_pending_tasks().await;
+        *self.cache.write().unwrap_or_else(|e| e.into_inner()) = new;
+    }
+
+    ///roughly how many bytes are currently cached
+    pub fn cache_weighted_size(&self) -> u64 {
+        self.cache().weighted_size()
+    }
+}
+
+#[cfg(test)]
+impl Pages {
+    pub(super) fn from_upload_data(upload_data: UploadData) -> Self {
+        Self::from_upload_data_with_max_bytes(upload_data, DEFAULT_CACHE_MAX_BYTES)
+    }
+
+    fn from_upload_data_with_max_bytes(upload_data: UploadData, cache_max_bytes: u64) -> Self {
+        let admission = Arc::new(AdmissionCounters::default());
+        Self {
+            upload_hash: Arc::new(RwLock::new(hash_raw_bytes(
+                serde_json::to_vec(&upload_data).unwrap_or_default(),
+            ))),
+            upload_data: Arc::new(RwLock::new(upload_data)),
+            casings: Arc::default(),
+            indexes: Arc::default(),
+            loaded_at: Arc::new(RwLock::new(SystemTime::now())),
+            last_upload_fetched: Arc::new(Mutex::new(LastFetched::default())),
+            cache: Arc::new(SyncRwLock::new(build_cache(
+                cache_max_bytes,
+                admission.clone(),
+            ))),
+            failing: build_failing(),
+            generated: Arc::default(),
+            warm_progress: Arc::new(WarmProgress {
+                finished: AtomicBool::new(true),
+                ..Default::default()
+            }),
+            admission,
+            stats: HitStats::default(),
+            background: Background::default(),
+            site: None,
+            max_cacheable_bytes: None,
+            cache_admit_max_bytes: None,
+            case_insensitive_paths: CaseInsensitivePaths::Off,
+            index_files: IndexFiles::default(),
+            render_markdown: None,
+            upload_data_location: UPLOAD_DATA_LOCATION.to_string(),
+        }
+    }
+
+    pub async fn cached_entry_count(&self) -> u64 {
+        let cache = self.cache();
+        cache.run_pending_tasks().await;
+        cache.entry_count()
+    }
+}
+
+enum PageContent {
+    Buffered(Vec<u8>),
+    ///only gets read from S3 once we know the body is actually wanted
+    Streamed {
+        bucket: Store,
+        path: String,
+        content_length: u64,
+    },
+}
+
+pub struct PageOutput {
+    content: PageContent,
     cache_control: Vec<Directive>,
     content_type: String,
+    ///what the bucket has the bytes compressed with, until [`Self::select_encoding`] decides
+    ///whether the client can have them like that
+    content_encoding: Option<String>,
     status: StatusCode,
+    cache_status: CacheStatus,
+    validators: Validators,
+    ///only ever picked for a buffered `200`
+    range: ByteRange,
+    ///set once the bytes were stored compressed, as then what's sent depends on `Accept-Encoding`
+    vary_encoding: bool,
+    ///for anything that's cached, so it only gets decoded once a version
+    decoded: Option<DecodedCache>,
 }
 
 impl PageOutput {
-    pub fn into_response(self, req_method: &Method) -> http::Result<Response<Full<Bytes>>> {
-        let mut builder = Response::builder()
-            .status(self.status)
-            .header(header::CONTENT_TYPE, self.content_type)
-            .header(header::CONTENT_LENGTH, self.content.len());
+    fn content_length(&self) -> u64 {
+        match &self.content {
+            PageContent::Buffered(content) => content.len() as u64,
+            PageContent::Streamed { content_length, .. } => *content_length,
+        }
+    }
+
+    ///turns a page that was found into an error page, so a site can have eg. its own `/403.html`.
+    ///`None` if it wasn't found, as then this would be the 404 page
+    pub fn into_error_page(mut self, status: StatusCode) -> Option<Self> {
+        if self.status != StatusCode::OK {
+            return None;
+        }
+        self.status = status;
+        self.cache_control = vec![Directive::NoStore];
+        self.validators = Validators::default();
+        Some(self)
+    }
+
+    ///so browsers don't have to guess how text is encoded
+    pub fn add_default_charset(&mut self) {
+        if let Some(content_type) = with_default_charset(&self.content_type) {
+            self.content_type = content_type;
+        }
+    }
+
+    ///sends pages that were uploaded compressed as they are if the client can take that, and
+    ///decompressed if not. Anything that can't be decompressed, like `br` or anything too big to
+    ///be held in memory, is a `406` instead. Needs to be before anything that reads the content,
+    ///like [`Self::inject_livereload`]
+    pub async fn select_encoding(&mut self, headers: &HeaderMap) {
+        let Some(encoding) = self.content_encoding.take() else {
+            return;
+        };
+        if encoding.eq_ignore_ascii_case("identity") {
+            return;
+        }
+        self.vary_encoding = true;
+        if encoding::accepts(headers, &encoding) {
+            self.content_encoding = Some(encoding);
+            return;
+        }
+
+        let decoded = match &mut self.content {
+            PageContent::Buffered(content) => {
+                let content = std::mem::take(content);
+                self.decode(encoding.clone(), content).await
+            }
+            PageContent::Streamed { .. } => None,
+        };
+        match decoded {
+            Some(decoded) => {
+                trace!(%encoding, len=%decoded.len(), "Decompressed for a client that can't take it compressed");
+                self.content = PageContent::Buffered(decoded);
+                //the same page, but not the same bytes as anyone that got it compressed
+                if let Some(etag) = &mut self.validators.etag
+                    && !etag.starts_with("W/")
+                {
+                    *etag = format!("W/{etag}");
+                }
+            }
+            None => {
+                debug!(%encoding, "Unable to decompress for a client that can't take it compressed");
+                self.content = PageContent::Buffered(vec![]);
+                self.content_type = mime::TEXT_PLAIN.to_string();
+                self.status = StatusCode::NOT_ACCEPTABLE;
+                self.cache_control = vec![Directive::NoStore];
+                self.validators = Validators::default();
+            }
+        }
+    }
+
+    ///`content` decoded, or what it was decoded to last time if that's still cached. Otherwise
+    ///it's done off the async threads, as it can come to [`encoding::MAX_DECODED_BYTES`]
+    async fn decode(&mut self, encoding: String, content: Vec<u8>) -> Option<Vec<u8>> {
+        if let Some(decoded) = &self.decoded
+            && let Some(entry) = decoded.cache.get(&decoded.key).await
+        {
+            //what it was decoded from, which a reload could've swapped since
+            self.validators = entry.validators;
+            return Some(entry.content);
+        }
+
+        let decoded = match tokio::task::spawn_blocking(move || {
+            encoding::decode(&encoding, &content)
+        })
+        .await
+        {
+            Ok(decoded) => decoded?,
+            Err(e) => {
+                error!(?e, "Decoding panicked");
+                return None;
+            }
+        };
+        if let Some(cache) = &self.decoded
+            && cache.admit
+        {
+            let entry = CacheEntry {
+                content: decoded.clone(),
+                content_type: self.content_type.clone(),
+                content_encoding: None,
+                validators: self.validators.clone(),
+            };
+            if fits(&entry, cache.cache_admit_max_bytes) {
+                trace!(key=?cache.key, "Adding decoded copy to cache");
+                cache.cache.insert(cache.key.clone(), entry).await;
+            }
+        }
+        Some(decoded)
+    }
+
+    ///only touches HTML we've already got in memory, that isn't still compressed - streamed files
+    ///are left alone
+    pub fn inject_livereload(&mut self, base_path: &str) {
+        if self.content_encoding.is_some() {
+            return;
+        }
+        let is_html = self
+            .content_type
+            .parse::<mime::Mime>()
+            .is_ok_and(|x| x.essence_str() == mime::TEXT_HTML.essence_str());
+        if !is_html {
+            return;
+        }
+
+        if let PageContent::Buffered(content) = &mut self.content {
+            *content = inject_livereload_script(content, base_path);
+        }
+    }
+
+    ///a `304` if the client's copy is still good, so none of the content needs sending. Before
+    ///[`Self::select_range`], as there's no point picking out bytes that won't be sent
+    pub fn select_not_modified(&mut self, headers: &HeaderMap) {
+        if self.status == StatusCode::OK && self.validators.not_modified(headers) {
+            self.status = StatusCode::NOT_MODIFIED;
+            self.content = PageContent::Buffered(vec![]);
+        }
+    }
+
+    ///picks out the bytes a `Range` asks for. Only pages we've got in memory get cut up - anything
+    ///streamed is sent whole, which is always allowed. Needs to be after anything that changes the
+    ///content, like [`Self::inject_livereload`]
+    pub fn select_range(&mut self, headers: &HeaderMap) {
+        if self.status != StatusCode::OK {
+            return;
+        }
+        if let PageContent::Buffered(content) = &self.content {
+            self.range = ByteRange::requested(headers, content.len() as u64, &self.validators);
+        }
+    }
+
+    ///`extra_headers` replace any of the same name that would otherwise be set
+    pub async fn into_response(
+        self,
+        req_method: &Method,
+        extra_headers: Vec<ExtraHeader>,
+    ) -> http::Result<Response<ServeBody>> {
+        let content_length = match &self.content {
+            PageContent::Buffered(content) => content.len() as u64,
+            PageContent::Streamed { content_length, .. } => *content_length,
+        };
+
+        let mut builder = Response::builder();
+        builder = match self.range {
+            //there's no body, so nothing to describe
+            _ if self.status == StatusCode::NOT_MODIFIED => builder.status(self.status),
+            ByteRange::Full => builder
+                .header(header::CONTENT_TYPE, self.content_type)
+                .status(self.status)
+                .header(header::CONTENT_LENGTH, content_length),
+            ByteRange::Partial { start, end } => builder
+                .header(header::CONTENT_TYPE, self.content_type)
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {start}-{end}/{content_length}"),
+                )
+                .header(header::CONTENT_LENGTH, end - start + 1),
+            ByteRange::Unsatisfiable => builder
+                .header(header::CONTENT_TYPE, self.content_type)
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{content_length}"))
+                .header(header::CONTENT_LENGTH, 0),
+        };
+
+        if let Some(etag) = self.validators.etag {
+            builder = builder.header(header::ETAG, etag);
+        }
+        if let Some(last_modified) = self.validators.last_modified {
+            builder = builder.header(
+                header::LAST_MODIFIED,
+                httpdate::fmt_http_date(last_modified),
+            );
+        }
+        if self.status == StatusCode::OK && matches!(self.content, PageContent::Buffered(_)) {
+            builder = builder.header(header::ACCEPT_RANGES, "bytes");
+        }
+        if let Some(content_encoding) = self.content_encoding
+            && self.status != StatusCode::NOT_MODIFIED
+        {
+            builder = builder.header(header::CONTENT_ENCODING, content_encoding);
+        }
+        if self.vary_encoding {
+            builder = builder.header(header::VARY, header::ACCEPT_ENCODING.as_str());
+        }
 
         if let Some(cc) = NonEmptyList::new(self.cache_control).map(Directive::directives_to_header)
         {
             builder = builder.header(header::CACHE_CONTROL, cc);
         }
 
+        builder = builder.extension(self.cache_status);
+
+        if let Some(headers) = builder.headers_mut() {
+            for extra in extra_headers {
+                match extra.to_header() {
+                    Some((name, value)) => {
+                        headers.insert(name, value);
+                    }
+                    None => warn!(?extra, "Skipping invalid extra header"),
+                }
+            }
+        }
+
         if req_method == Method::HEAD {
-            Ok(builder.body(Full::default())?)
-        } else {
-            Ok(builder.body(Full::new(Bytes::from(self.content)))?)
+            return builder.body(full_body(Bytes::new()));
+        }
+
+        match self.content {
+            PageContent::Buffered(content) => match self.range {
+                ByteRange::Full => builder.body(full_body(content)),
+                ByteRange::Partial { start, end } => builder.body(full_body(
+                    Bytes::from(content).slice(start as usize..=end as usize),
+                )),
+                ByteRange::Unsatisfiable => builder.body(full_body(Bytes::new())),
+            },
+            PageContent::Streamed { bucket, path, .. } => {
+                let stream = match bucket.stream(&path).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(?e, ?path, "Error starting stream from S3");
+                        return empty_with_code(StatusCode::BAD_GATEWAY);
+                    }
+                };
+
+                let body = StreamBody::new(stream.map(|chunk| chunk.map(Frame::data)));
+                builder.body(body.boxed_unsync())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        serve::livereload::DEFAULT_MAX_LIVERELOAD_CLIENTS,
+        store::memory::{Fault, InMemoryBucket},
+    };
+    use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request};
+    use hyper_util::rt::TokioIo;
+    use s3::{creds::Credentials, Bucket, Region};
+    use std::{collections::HashMap, convert::Infallible, time::Duration};
+    use tokio::net::TcpListener;
+
+    fn test_bucket() -> Store {
+        let region = Region::Custom {
+            region: "auto".to_owned(),
+            endpoint: "http://127.0.0.1:1".to_owned(),
+        };
+        Arc::new(Bucket::new_public("test", region).unwrap())
+    }
+
+    ///something to put straight into the cache, for no version in particular
+    fn entry(content: impl Into<Vec<u8>>, content_type: &str) -> CacheEntry {
+        CacheEntry {
+            content: content.into(),
+            content_type: content_type.to_string(),
+            content_encoding: None,
+            validators: Validators::default(),
+        }
+    }
+
+    #[test]
+    fn test_all_forms_resolve_to_index() {
+        for path in ["/blog", "/blog/", "/blog/index.html", "/blog/./", "/blog//"] {
+            assert_eq!(
+                resolve_request_path(path).as_deref(),
+                Some("/blog/index.html"),
+                "{path}"
+            );
+        }
+        assert_eq!(resolve_request_path("/").as_deref(), Some("/index.html"));
+        assert_eq!(
+            resolve_request_path("/style.css").as_deref(),
+            Some("/style.css")
+        );
+    }
+
+    #[test]
+    fn test_paths_are_decoded() {
+        assert_eq!(
+            resolve_request_path("/My%20File.pdf").as_deref(),
+            Some("/My File.pdf")
+        );
+        //NFD in the request, NFC out
+        assert_eq!(
+            resolve_request_path("/docs/cafe%CC%81.html").as_deref(),
+            Some("/docs/caf\u{e9}.html")
+        );
+        assert_eq!(
+            resolve_request_path("/docs/caf%C3%A9.html").as_deref(),
+            Some("/docs/caf\u{e9}.html")
+        );
+
+        //an encoded slash would otherwise reach a different file
+        assert_eq!(resolve_request_path("/admin%2Fsecret.html"), None);
+        assert_eq!(resolve_request_path("/a/%2e%2e/admin/"), None);
+        assert_eq!(resolve_request_path("/a/%2E/b.html"), None);
+        assert_eq!(resolve_request_path("/index.html%00.png"), None);
+        assert_eq!(resolve_request_path("/%FF.html"), None);
+        //`.` in a name is fine
+        assert_eq!(
+            resolve_request_path("/a%2e%2eb.html").as_deref(),
+            Some("/a..b.html")
+        );
+    }
+
+    #[test]
+    fn test_normalise_request_path() {
+        for (path, normalised) in [
+            ("/", "/"),
+            ("", "/"),
+            ("//", "/"),
+            ("/a//b///c.html", "/a/b/c.html"),
+            ("//admin/", "/admin"),
+            ("/./admin/./", "/admin"),
+            ("/a/../admin", "/admin"),
+            ("/a/../../admin", "/admin"),
+            ("/../../../etc/passwd", "/etc/passwd"),
+            ("/admin/..", "/"),
+            ("/admin%20/", "/admin "),
+            //trailing dots are part of the name, not dropped like some filesystems do
+            ("/admin./", "/admin."),
+            ("/secret.html.", "/secret.html."),
+            ("/admin/...", "/admin/..."),
+            ("/%61dmin/", "/admin"),
+        ] {
+            assert_eq!(
+                normalise_request_path(path).as_deref(),
+                Ok(normalised),
+                "{path}"
+            );
+        }
+
+        for path in [
+            "/..%2f",
+            "/..%2fadmin",
+            "/a/..%2F..%2Fadmin",
+            "/%2e%2e/admin",
+            "/%2E%2E%2Fadmin",
+            "/a/%2e/b",
+            "/admin%2fsecret.html",
+            "/admin\\secret.html",
+            "/admin%5csecret.html",
+            "/..%5c..%5cadmin",
+            "/index.html%00",
+            "/index.html%00.png",
+            "/a%0d%0aSet-Cookie:%20x",
+            "/a%09b",
+            "/a%7fb",
+            "/%c2%85",
+            "/%FF",
+            "/%c0%ae%c0%ae/admin",
+        ] {
+            assert_eq!(
+                normalise_request_path(path),
+                Err(StatusCode::BAD_REQUEST),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_entry_path_undoes_entry_key() {
+        for (root, path) in [
+            ("public", "/a.html"),
+            ("", "/docs/a.html"),
+            ("sites/x/", "/b.css"),
+        ] {
+            assert_eq!(
+                entry_path(root, &entry_key(root, path)).as_deref(),
+                Some(path)
+            );
+        }
+        assert_eq!(entry_path("public", "publicity/a.html"), None);
+        assert_eq!(entry_path("public", "other/a.html"), None);
+    }
+
+    #[test]
+    fn test_entry_key_ignores_trailing_slash_on_root() {
+        assert_eq!(entry_key("public", "/a.html"), "public/a.html");
+        assert_eq!(entry_key("public/", "/a.html"), "public/a.html");
+    }
+
+    #[tokio::test]
+    async fn test_both_url_forms_share_one_cache_entry() {
+        let bucket = InMemoryBucket::new();
+        let upload_data = |hash: &str| UploadData {
+            entries: [("public/blog/index.html".to_string(), hash.to_string())].into(),
+            root: "public/".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        let upload = async |hash: &str, content: &[u8]| {
+            bucket
+                .put(
+                    UPLOAD_DATA_LOCATION,
+                    &serde_json::to_vec(&upload_data(hash)).unwrap(),
+                    "application/json",
+                )
+                .await
+                .unwrap();
+            bucket
+                .put("public/blog/index.html", content, "text/html")
+                .await
+                .unwrap();
+        };
+        ///whether every form gets `expected`
+        async fn serves(pages: &Pages, store: &Store, expected: &[u8]) -> bool {
+            let ccm = CacheControlManager::default();
+            for form in ["/blog", "/blog/", "/blog/index.html"] {
+                let path = resolve_request_path(form).unwrap();
+                let output = pages.get(store, &path, &ccm, true, None).await.unwrap();
+                let PageContent::Buffered(content) = output.content else {
+                    panic!("should be buffered");
+                };
+                if content != expected {
+                    return false;
+                }
+            }
+            true
+        }
+
+        upload("1", b"old").await;
+        let store: Store = Arc::new(bucket.clone());
+        let pages = Pages::from_upload_data(upload_data("1"));
+        assert!(serves(&pages, &store, b"old").await);
+        assert_eq!(pages.cached_entry_count().await, 1);
+
+        //a reload updates by manifest key, which must be what every form reads from
+        upload("2", b"new").await;
+        let reloader = LiveReloader::new(Duration::from_secs(1), DEFAULT_MAX_LIVERELOAD_CLIENTS);
+        let report = pages.check_and_reload(&store, reloader).await.unwrap();
+        assert_eq!(report.updated, 1);
+        for tries in 0.. {
+            if serves(&pages, &store, b"new").await {
+                break;
+            }
+            assert!(tries < 100, "never swapped");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(pages.cached_entry_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let pages = Pages::from_upload_data(UploadData {
+            entries: [("public/blog/index.html".to_string(), "hash".to_string())].into(),
+            root: "public/".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        pages
+            .cache()
+            .insert("public/blog/index.html".into(), entry(b"old", "text/html"))
+            .await;
+
+        pages
+            .cache()
+            .insert(
+                CacheKey {
+                    path: "public/blog/index.html".to_string(),
+                    encoding: Some("gzip".to_string()),
+                    variant: None,
+                },
+                entry(b"gzipped", "text/html"),
+            )
+            .await;
+        pages
+            .cache()
+            .insert("public/other.html".into(), entry(b"other", "text/html"))
+            .await;
+        assert_eq!(pages.cached_entry_count().await, 3);
+
+        //every variant goes, and nothing else
+        let path = resolve_request_path("/blog").unwrap();
+        assert_eq!(pages.purge(&path).await, Purged::Purged);
+        assert_eq!(pages.cached_entry_count().await, 1);
+        assert_eq!(pages.purge(&path).await, Purged::NotCached);
+        assert_eq!(
+            serde_json::to_string(&Purged::NotCached).unwrap(),
+            r#""not-cached""#
+        );
+    }
+
+    ///a bucket that serves `objects` as HTML
+    async fn mock_bucket(objects: HashMap<String, Vec<u8>>) -> Store {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let objects = Arc::new(objects);
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let objects = objects.clone();
+                tokio::task::spawn(async move {
+                    let svc = service_fn(move |req: Request<Incoming>| {
+                        let object = req
+                            .uri()
+                            .path()
+                            .strip_prefix("/test/")
+                            .and_then(|key| objects.get(key))
+                            .cloned();
+                        async move {
+                            Ok::<_, Infallible>(match object {
+                                Some(bytes) => Response::builder()
+                                    .header(header::CONTENT_TYPE, "text/html")
+                                    .body(full_body(bytes))
+                                    .unwrap(),
+                                None => empty_with_code(StatusCode::NOT_FOUND).unwrap(),
+                            })
+                        }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), svc)
+                        .await;
+                });
+            }
+        });
+
+        let region = Region::Custom {
+            region: "auto".to_owned(),
+            endpoint: format!("http://{addr}"),
+        };
+        let creds = Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap();
+        Arc::new(
+            Bucket::new("test", region, creds)
+                .unwrap()
+                .with_path_style(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_switching_root_replaces_the_whole_tree() {
+        let upload_data = |root: &str| UploadData {
+            entries: ["/index.html", "/style.css"]
+                .iter()
+                .map(|path| (entry_key(root, path), "hash".to_string()))
+                .collect(),
+            root: root.to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        let pages = Pages::from_upload_data(upload_data("releases/1"));
+        for key in ["releases/1/index.html", "releases/1/style.css"] {
+            pages
+                .cache()
+                .insert(key.into(), entry(b"old", "text/html"))
+                .await;
+        }
+        pages
+            .cache()
+            .insert(
+                CacheKey {
+                    path: "releases/1/index.html".to_string(),
+                    encoding: Some("br".to_string()),
+                    variant: None,
+                },
+                entry(b"old", "text/html"),
+            )
+            .await;
+
+        let new = upload_data("releases/2");
+        let bucket = mock_bucket(HashMap::from([
+            (
+                UPLOAD_DATA_LOCATION.to_string(),
+                serde_json::to_vec(&new).unwrap(),
+            ),
+            ("releases/2/index.html".to_string(), b"new".to_vec()),
+            ("releases/2/style.css".to_string(), b"new".to_vec()),
+        ]))
+        .await;
+        let reloader = LiveReloader::new(Duration::from_secs(1), DEFAULT_MAX_LIVERELOAD_CLIENTS);
+        {
+            let _reloading = pages.last_upload_fetched.lock().await;
+            let e = pages
+                .check_and_reload(&bucket, reloader.clone())
+                .await
+                .unwrap_err();
+            assert!(e.is::<AlreadyReloading>());
+        }
+        let report = pages.check_and_reload(&bucket, reloader).await.unwrap();
+        assert!(report.manifest_changed);
+        assert_eq!(
+            report.manifest_hash,
+            to_hex(&pages.upload_hash.read().await)
+        );
+        assert_eq!(pages.root().await, "releases/2");
+
+        //repopulating happens in the background
+        for _ in 0..100 {
+            if pages
+                .cache()
+                .contains_key(&CacheKey::from("releases/2/style.css"))
+                && pages
+                    .cache()
+                    .contains_key(&CacheKey::from("releases/2/index.html"))
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        pages.cache().run_pending_tasks().await;
+
+        let mut cached: Vec<String> = pages.cache().iter().map(|(k, _)| k.path.clone()).collect();
+        cached.sort();
+        assert_eq!(
+            cached,
+            vec!["releases/2/index.html", "releases/2/style.css"]
+        );
+
+        let ccm = CacheControlManager::default();
+        for path in ["/index.html", "/style.css"] {
+            let output = pages.get(&bucket, path, &ccm, true, None).await.unwrap();
+            assert_eq!(output.cache_status, CacheStatus::Hit, "{path}");
+            assert!(
+                matches!(output.content, PageContent::Buffered(x) if x == b"new"),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_warm_order() {
+        let keys = [
+            "public/docs/api/types.html",
+            "public/style.css",
+            "public/docs/index.html",
+            "public/404.html",
+            "public/index.html",
+            "public/docs/intro.html",
+        ]
+        .map(ToString::to_string);
+        assert_eq!(
+            warm_order("public", &keys, |_| 0),
+            vec![
+                "public/404.html",
+                "public/index.html",
+                "public/docs/index.html",
+                "public/style.css",
+                "public/docs/intro.html",
+                "public/docs/api/types.html",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deployed_version() {
+        let upload_data = UploadData {
+            entries: [("public/index.html".to_string(), "hash".to_string())].into(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        let bytes = serde_json::to_vec(&upload_data).unwrap();
+        let version = Pages::from_upload_data(upload_data)
+            .deployed_version()
+            .await;
+
+        assert_eq!(
+            serde_json::to_value(&version).unwrap(),
+            serde_json::json!({
+                "upload_data_sha256": to_hex(&hash_raw_bytes(bytes)),
+                "entries": 1,
+                "root": "public",
+            })
+        );
+        assert_eq!(version.upload_data_sha256.len(), 64);
+    }
+
+    #[test]
+    fn test_warm_order_follows_requests() {
+        let keys = ["/a.html", "/b.html", "/c/d.html", "/index.html"].map(ToString::to_string);
+        let requests = |path: &str| match path {
+            "/c/d.html" => 10,
+            "/b.html" => 3,
+            _ => 0,
+        };
+        assert_eq!(
+            warm_order("", &keys, requests),
+            vec!["/index.html", "/c/d.html", "/b.html", "/a.html"]
+        );
+    }
+
+    #[test]
+    fn test_readiness() {
+        let warming = Warmth {
+            cached: 3,
+            total: 4,
+            finished: false,
+        };
+        assert!(!warming.is_ready(None));
+        assert!(warming.is_ready(Some(0.75)));
+        assert!(!warming.is_ready(Some(0.8)));
+
+        let finished = Warmth {
+            cached: 1,
+            total: 4,
+            finished: true,
+        };
+        assert!(finished.is_ready(None));
+        assert!(!warming.combine(finished).is_ready(Some(0.75)));
+        assert!(Warmth::default().is_ready(None));
+    }
+
+    #[tokio::test]
+    async fn test_generated_files_are_made_once() {
+        let pages = Pages::from_upload_data(UploadData {
+            entries: [("public/robots.txt".to_string(), "hash".to_string())].into(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        let ccm = CacheControlManager::default();
+        assert!(pages.contains("/robots.txt").await);
+        assert!(!pages.contains("/sitemap.xml").await);
+        assert_eq!(pages.paths().await, vec!["/robots.txt"]);
+
+        let generate = |content: &'static str| async move { content.as_bytes().to_vec() };
+        let output = pages
+            .get_generated("/sitemap.xml", "application/xml", &ccm, || generate("a"))
+            .await;
+        assert_eq!(output.cache_status, CacheStatus::Miss);
+        let output = pages
+            .get_generated("/sitemap.xml", "application/xml", &ccm, || generate("b"))
+            .await;
+        assert_eq!(output.cache_status, CacheStatus::Hit);
+        assert!(matches!(output.content, PageContent::Buffered(x) if x == b"a"));
+
+        pages.forget_generated().await;
+        let output = pages
+            .get_generated("/sitemap.xml", "application/xml", &ccm, || generate("b"))
+            .await;
+        assert!(matches!(output.content, PageContent::Buffered(x) if x == b"b"));
+    }
+
+    #[tokio::test]
+    async fn test_serves_pages_from_several_mappings() {
+        //as uploaded by `shove upload site:/ docs/build:/docs storybook-static:/components`
+        let keys = [
+            "/index.html",
+            "/docs/index.html",
+            "/components/button/index.html",
+        ];
+        let pages = Pages::from_upload_data(UploadData {
+            entries: keys
+                .iter()
+                .map(|x| (x.to_string(), "hash".to_string()))
+                .collect(),
+            root: String::new(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        let bucket = test_bucket();
+        let ccm = CacheControlManager::default();
+
+        for key in keys {
+            pages
+                .cache()
+                .insert(key.into(), entry(key, "text/html"))
+                .await;
+        }
+
+        for (request, key) in [
+            ("/", "/index.html"),
+            ("/docs", "/docs/index.html"),
+            ("/components/button/", "/components/button/index.html"),
+        ] {
+            let path = resolve_request_path(request).unwrap();
+            let output = pages.get(&bucket, &path, &ccm, true, None).await.unwrap();
+            assert_eq!(output.cache_status, CacheStatus::Hit, "{request}");
+            assert!(
+                matches!(output.content, PageContent::Buffered(x) if x == key.as_bytes()),
+                "{request}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_head_of_streamed_page_has_no_body() {
+        //the bucket is unreachable, so this only passes if nothing gets fetched
+        let output = PageOutput {
+            content: PageContent::Streamed {
+                bucket: test_bucket(),
+                path: "public/big.bin".to_string(),
+                content_length: 1_000_000,
+            },
+            cache_control: vec![],
+            content_type: "application/octet-stream".to_string(),
+            content_encoding: None,
+            status: StatusCode::OK,
+            cache_status: CacheStatus::Bypass,
+            validators: Validators::default(),
+            range: ByteRange::Full,
+            vary_encoding: false,
+            decoded: None,
+        };
+
+        let rsp = output.into_response(&Method::HEAD, vec![]).await.unwrap();
+
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert_eq!(rsp.headers()[header::CONTENT_LENGTH], "1000000");
+        assert_eq!(
+            rsp.extensions().get::<CacheStatus>(),
+            Some(&CacheStatus::Bypass)
+        );
+        let body = rsp.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_bounded_by_bytes() {
+        let pages = Pages::from_upload_data_with_max_bytes(UploadData::default(), 10_000);
+
+        for i in 0..100 {
+            pages
+                .cache()
+                .insert(
+                    format!("public/{i}.bin").into(),
+                    entry(vec![0; 1_000], "a/b"),
+                )
+                .await;
+        }
+        pages.cache().run_pending_tasks().await;
+
+        assert!(pages.cache_weighted_size() <= 10_000);
+        assert!(pages.cache_weighted_size() > 0);
+        assert!(pages.cached_entry_count().await < 10);
+    }
+
+    #[tokio::test]
+    async fn test_resizing_the_cache_keeps_what_fits() {
+        let pages = Pages::from_upload_data(UploadData::default());
+        for i in 0..20 {
+            pages
+                .cache()
+                .insert(
+                    format!("public/{i}.bin").into(),
+                    entry(vec![0; 1_000], "a/b"),
+                )
+                .await;
         }
+        assert_eq!(pages.cached_entry_count().await, 20);
+
+        pages.resize_cache(10_000).await;
+        assert!(pages.cache_weighted_size() <= 10_000);
+        let kept = pages.cached_entry_count().await;
+        assert!(kept > 0 && kept < 10, "{kept}");
+
+        pages.resize_cache(DEFAULT_CACHE_MAX_BYTES).await;
+        assert_eq!(pages.cached_entry_count().await, kept);
+        pages
+            .cache()
+            .insert("public/new.bin".into(), entry(vec![0; 1_000], "a/b"))
+            .await;
+        assert_eq!(pages.cached_entry_count().await, kept + 1);
+    }
+
+    #[tokio::test]
+    async fn test_canonical_paths() {
+        let pages = Pages::from_upload_data(UploadData {
+            entries: [
+                ("public/index.html".to_string(), "hash".to_string()),
+                ("public/blog/index.html".to_string(), "hash".to_string()),
+                ("public/style.css".to_string(), "hash".to_string()),
+            ]
+            .into(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+
+        assert_eq!(
+            pages.canonical_path("/blog").await.as_deref(),
+            Some("/blog/")
+        );
+        assert_eq!(pages.canonical_path("/blog/").await, None);
+        assert_eq!(
+            pages.canonical_path("/style.css/").await.as_deref(),
+            Some("/style.css")
+        );
+        assert_eq!(pages.canonical_path("/style.css").await, None);
+        assert_eq!(pages.canonical_path("/").await, None);
+        assert_eq!(pages.canonical_path("/missing").await, None);
+        assert_eq!(pages.canonical_path("/missing.css/").await, None);
+    }
+
+    fn buffered(content: &str, content_type: &str) -> PageOutput {
+        PageOutput {
+            content: PageContent::Buffered(content.as_bytes().to_vec()),
+            cache_control: vec![],
+            content_type: content_type.to_string(),
+            content_encoding: None,
+            status: StatusCode::OK,
+            cache_status: CacheStatus::Hit,
+            validators: Validators::default(),
+            range: ByteRange::Full,
+            vary_encoding: false,
+            decoded: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_other_casings_are_found() {
+        let upload_data = UploadData {
+            entries: [
+                ("public/Photos/Photo.JPG".to_string(), "hash".to_string()),
+                ("public/blog/index.html".to_string(), "hash".to_string()),
+                ("public/README.md".to_string(), "hash".to_string()),
+                ("public/readme.md".to_string(), "hash".to_string()),
+            ]
+            .into(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        let casings = index_casings(upload_data.entries.keys());
+        let pages = Pages::from_upload_data(upload_data);
+        *pages.casings.write().await = casings;
+
+        assert_eq!(
+            pages.actual_casing("/photos/photo.jpg").await.as_deref(),
+            Some("/Photos/Photo.JPG")
+        );
+        assert_eq!(
+            pages.actual_casing("/BLOG/index.html").await.as_deref(),
+            Some("/blog/index.html")
+        );
+        assert_eq!(pages.actual_casing("/Photos/Photo.JPG").await, None);
+        assert_eq!(pages.actual_casing("/missing.jpg").await, None);
+
+        //exact matches win, and otherwise the first alphabetically
+        assert_eq!(pages.actual_casing("/readme.md").await, None);
+        assert_eq!(pages.actual_casing("/README.md").await, None);
+        assert_eq!(
+            pages.actual_casing("/ReadMe.md").await.as_deref(),
+            Some("/README.md")
+        );
+
+        assert_eq!(
+            encode_request_path("/My Photos/caf\u{e9} 100%.jpg"),
+            "/My%20Photos/caf%C3%A9%20100%25.jpg"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ranges_only_come_from_the_current_version() {
+        let pages = Pages::from_upload_data(UploadData {
+            entries: [("public/a.txt".to_string(), "v2".to_string())].into(),
+            root: "public/".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        let cached = |content: &[u8], hash| CacheEntry {
+            validators: Validators::new(Some(hash), SystemTime::now()),
+            ..entry(content, "text/plain")
+        };
+        pages
+            .cache()
+            .insert("public/a.txt".into(), cached(b"0123456789", "v2"))
+            .await;
+        let bucket = test_bucket();
+        let ccm = CacheControlManager::default();
+
+        let respond = |if_range: Option<&'static str>| {
+            let pages = pages.clone();
+            let bucket = bucket.clone();
+            let ccm = ccm.clone();
+            async move {
+                let mut headers = HeaderMap::new();
+                headers.insert(header::RANGE, "bytes=2-4".parse().unwrap());
+                if let Some(if_range) = if_range {
+                    headers.insert(header::IF_RANGE, if_range.parse().unwrap());
+                }
+                let mut output = pages
+                    .get(&bucket, "/a.txt", &ccm, true, None)
+                    .await
+                    .unwrap();
+                output.select_range(&headers);
+                let rsp = output.into_response(&Method::GET, vec![]).await.unwrap();
+                let status = rsp.status();
+                let content_range = rsp.headers().get(header::CONTENT_RANGE).cloned();
+                let body = rsp.into_body().collect().await.unwrap().to_bytes();
+                (status, content_range, body)
+            }
+        };
+
+        let (status, content_range, body) = respond(None).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(content_range.unwrap(), "bytes 2-4/10");
+        assert_eq!(&body[..], b"234");
+
+        let (status, _, body) = respond(Some("\"v2\"")).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&body[..], b"234");
+
+        //the client's bytes are from before a deploy, so splicing these on would corrupt the file
+        let (status, content_range, body) = respond(Some("\"v1\"")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(content_range.is_none());
+        assert_eq!(&body[..], b"0123456789");
+
+        let (status, _, _) = respond(Some("Tue, 15 Nov 1994 08:12:31 GMT")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        //a reload that's not got the new bytes in yet, so what's cached is still the old version
+        pages
+            .cache()
+            .insert("public/a.txt".into(), cached(b"abcdefghij", "v1"))
+            .await;
+        let (status, content_range, body) = respond(Some("\"v2\"")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(content_range.is_none());
+        assert_eq!(&body[..], b"abcdefghij");
+    }
+
+    #[tokio::test]
+    async fn test_only_missing_files_are_forgotten() {
+        let bucket = InMemoryBucket::new();
+        for (key, contents) in [
+            ("public/a.html", "a"),
+            ("public/b.html", "b"),
+            ("public/503.html", "down"),
+        ] {
+            bucket
+                .put(key, contents.as_bytes(), "text/html")
+                .await
+                .unwrap();
+        }
+        let store: Store = Arc::new(bucket.clone());
+        let pages = Pages::from_upload_data(UploadData {
+            entries: ["public/a.html", "public/b.html", "public/503.html"]
+                .into_iter()
+                .map(|key| (key.to_string(), "hash".to_string()))
+                .collect(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        let ccm = CacheControlManager::default();
+
+        bucket.fail("public/a.html", Fault::Forbidden).await;
+        let output = pages
+            .get(&store, "/a.html", &ccm, true, None)
+            .await
+            .unwrap();
+        assert_eq!(output.status, StatusCode::BAD_GATEWAY);
+        assert!(pages.contains("/a.html").await);
+
+        bucket.fail("public/b.html", Fault::Unavailable).await;
+        let output = pages
+            .get(&store, "/b.html", &ccm, true, None)
+            .await
+            .unwrap();
+        assert_eq!(output.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(output.cache_status, CacheStatus::Unavailable);
+        let PageContent::Buffered(content) = &output.content else {
+            panic!("error page should be buffered");
+        };
+        assert_eq!(content, b"down");
+
+        //backing off, so it isn't asked again straight away even though it's back
+        bucket.heal("public/b.html").await;
+        let output = pages
+            .get(&store, "/b.html", &ccm, true, None)
+            .await
+            .unwrap();
+        assert_eq!(output.status, StatusCode::SERVICE_UNAVAILABLE);
+        pages.failing.invalidate_all();
+        let output = pages
+            .get(&store, "/b.html", &ccm, true, None)
+            .await
+            .unwrap();
+        assert_eq!(output.status, StatusCode::OK);
+
+        bucket.fail("public/a.html", Fault::Missing).await;
+        let output = pages.get(&store, "/a.html", &ccm, true, None).await;
+        assert!(output.is_none_or(|x| x.status == StatusCode::NOT_FOUND));
+        assert!(!pages.contains("/a.html").await);
+    }
+
+    #[tokio::test]
+    async fn test_updated_pages_are_served_until_the_new_version_is_in() {
+        let upload_data = |entries: &[(&str, &str)]| UploadData {
+            entries: entries
+                .iter()
+                .map(|(key, hash)| (key.to_string(), hash.to_string()))
+                .collect(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        let pages = Pages::from_upload_data(upload_data(&[
+            ("public/hot.html", "1"),
+            ("public/gone.html", "1"),
+        ]));
+        let old = CacheEntry {
+            validators: Validators::new(Some("1"), SystemTime::now()),
+            ..entry(b"old", "text/html")
+        };
+        for key in ["public/hot.html", "public/gone.html"] {
+            pages.cache().insert(key.into(), old.clone()).await;
+        }
+        pages
+            .cache()
+            .insert(
+                CacheKey {
+                    path: "public/hot.html".to_string(),
+                    encoding: Some("br".to_string()),
+                    variant: None,
+                },
+                old,
+            )
+            .await;
+
+        let bucket = InMemoryBucket::new();
+        let new = upload_data(&[("public/hot.html", "2")]);
+        bucket
+            .put(
+                UPLOAD_DATA_LOCATION,
+                &serde_json::to_vec(&new).unwrap(),
+                "application/json",
+            )
+            .await
+            .unwrap();
+        bucket
+            .put("public/hot.html", b"new", "text/html")
+            .await
+            .unwrap();
+        bucket
+            .fail("public/hot.html", Fault::Slow(Duration::from_millis(300)))
+            .await;
+        let store: Store = Arc::new(bucket.clone());
+        let ccm = CacheControlManager::default();
+
+        let reloader = LiveReloader::new(Duration::from_secs(1), DEFAULT_MAX_LIVERELOAD_CLIENTS);
+        let report = pages.check_and_reload(&store, reloader).await.unwrap();
+        assert_eq!((report.updated, report.invalidated), (1, 1));
+        //gone straight away, rather than once the new version is in
+        assert!(!pages.contains("/gone.html").await);
+        assert!(!pages
+            .cache()
+            .contains_key(&CacheKey::from("public/gone.html")));
+
+        //never a miss, which would mean waiting on the slow read
+        let mut served = vec![];
+        loop {
+            let output = tokio::time::timeout(
+                Duration::from_millis(100),
+                pages.get(&store, "/hot.html", &ccm, true, None),
+            )
+            .await
+            .expect("waited on the store")
+            .unwrap();
+            assert_eq!(output.cache_status, CacheStatus::Hit);
+            let rsp = output.into_response(&Method::GET, vec![]).await.unwrap();
+            let etag = rsp.headers().get(header::ETAG).cloned().unwrap();
+            let content = rsp.into_body().collect().await.unwrap().to_bytes().to_vec();
+            //each body with the validators of the version it is, whichever side of the swap
+            match &content[..] {
+                b"old" => assert_eq!(etag, "\"1\""),
+                b"new" => assert_eq!(etag, "\"2\""),
+                other => panic!("served {other:?}"),
+            }
+            served.push(content.clone());
+            if content == b"new" {
+                break;
+            }
+            assert!(served.len() < 100, "never swapped");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(served.first().map(Vec::as_slice), Some(&b"old"[..]));
+
+        //the compressed one was made from the old bytes
+        pages.cache().run_pending_tasks().await;
+        let cached: Vec<CacheKey> = pages.cache().iter().map(|(k, _)| (*k).clone()).collect();
+        assert_eq!(cached, vec![CacheKey::from("public/hot.html")]);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_keys_are_not_served() {
+        let bucket = InMemoryBucket::new();
+        for key in ["/index.html", "authdata", "cache_control.json"] {
+            bucket.put(key, b"secret", "text/html").await.unwrap();
+        }
+        let store: Store = Arc::new(bucket);
+        //as if an older upload had put them in
+        let pages = Pages::from_upload_data(UploadData {
+            entries: ["/index.html", "/authdata", "/cache_control.json"]
+                .into_iter()
+                .map(|key| (key.to_string(), "hash".to_string()))
+                .collect(),
+            root: String::new(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        let ccm = CacheControlManager::default();
+
+        let output = pages
+            .get(&store, "/index.html", &ccm, true, None)
+            .await
+            .unwrap();
+        assert_eq!(output.status, StatusCode::OK);
+        for path in ["/authdata", "/cache_control.json"] {
+            let output = pages.get(&store, path, &ccm, true, None).await;
+            assert!(
+                output.is_none_or(|x| x.status == StatusCode::NOT_FOUND),
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_content_types_are_guessed() {
+        let bucket = InMemoryBucket::new();
+        for key in ["/index.html", "/blob", "/big.css"] {
+            //as if another tool had put them there
+            bucket.put(key, b"<h1>hi</h1>", "").await.unwrap();
+        }
+        let store: Store = Arc::new(bucket);
+        let mut pages = Pages::from_upload_data(UploadData {
+            entries: ["/index.html", "/blob", "/big.css"]
+                .into_iter()
+                .map(|key| (key.to_string(), "hash".to_string()))
+                .collect(),
+            root: String::new(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        let ccm = CacheControlManager::default();
+
+        let mut output = pages
+            .get(&store, "/index.html", &ccm, true, None)
+            .await
+            .unwrap();
+        assert_eq!(output.status, StatusCode::OK);
+        assert_eq!(output.content_type, "text/html");
+        //so it still renders, rather than being downloaded
+        output.add_default_charset();
+        let rsp = output.into_response(&Method::GET, vec![]).await.unwrap();
+        assert_eq!(
+            rsp.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+
+        let output = pages.get(&store, "/blob", &ccm, true, None).await.unwrap();
+        assert_eq!(output.content_type, "application/octet-stream");
+
+        pages.max_cacheable_bytes = Some(1);
+        let output = pages
+            .get(&store, "/big.css", &ccm, true, None)
+            .await
+            .unwrap();
+        assert!(matches!(output.content, PageContent::Streamed { .. }));
+        assert_eq!(output.content_type, "text/css");
+    }
+
+    #[tokio::test]
+    async fn test_crawlers_and_big_files_are_not_admitted() {
+        let bucket = InMemoryBucket::new();
+        for (key, contents) in [
+            ("/a.html", "a"),
+            ("/b.html", "b"),
+            ("/big.html", "far too big"),
+        ] {
+            bucket
+                .put(key, contents.as_bytes(), "text/html")
+                .await
+                .unwrap();
+        }
+        let store: Store = Arc::new(bucket);
+        let mut pages = Pages::from_upload_data(UploadData {
+            entries: ["/a.html", "/b.html", "/big.html"]
+                .into_iter()
+                .map(|key| (key.to_string(), "hash".to_string()))
+                .collect(),
+            root: String::new(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        pages.cache_admit_max_bytes = Some(4);
+        let ccm = CacheControlManager::default();
+
+        //a crawler still gets the page
+        let output = pages
+            .get(&store, "/a.html", &ccm, false, None)
+            .await
+            .unwrap();
+        assert_eq!(output.cache_status, CacheStatus::Bypass);
+        let body = output.into_response(&Method::GET, vec![]).await.unwrap();
+        let body = body.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "a".as_bytes());
+        assert_eq!(pages.cached_entry_count().await, 0);
+
+        let output = pages
+            .get(&store, "/big.html", &ccm, true, None)
+            .await
+            .unwrap();
+        assert_eq!(output.cache_status, CacheStatus::Bypass);
+        assert!(matches!(output.content, PageContent::Buffered(_)));
+        assert_eq!(pages.cached_entry_count().await, 0);
+
+        let output = pages
+            .get(&store, "/b.html", &ccm, true, None)
+            .await
+            .unwrap();
+        assert_eq!(output.cache_status, CacheStatus::Miss);
+        let output = pages
+            .get(&store, "/b.html", &ccm, false, None)
+            .await
+            .unwrap();
+        assert_eq!(output.cache_status, CacheStatus::Hit);
+        assert_eq!(pages.cached_entry_count().await, 1);
+
+        assert_eq!(
+            pages.admission(),
+            Admission {
+                admitted: 1,
+                bypassed: 2,
+                evicted: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_default_charset() {
+        for (content_type, expected) in [
+            ("text/html", Some("text/html; charset=utf-8")),
+            ("text/css", Some("text/css; charset=utf-8")),
+            ("application/json", Some("application/json; charset=utf-8")),
+            (
+                "application/javascript",
+                Some("application/javascript; charset=utf-8"),
+            ),
+            ("text/html; charset=iso-8859-1", None),
+            ("text/plain; CHARSET=utf-8", None),
+            ("application/octet-stream", None),
+            ("image/svg+xml", None),
+            ("not a content type", None),
+        ] {
+            assert_eq!(
+                with_default_charset(content_type).as_deref(),
+                expected,
+                "{content_type}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_livereload_only_touches_html() {
+        let mut css = buffered("</body>", "text/css");
+        css.inject_livereload("");
+        let rsp = css.into_response(&Method::GET, vec![]).await.unwrap();
+        assert_eq!(rsp.headers()[header::CONTENT_LENGTH], "7");
+
+        let mut html = buffered("<body></body>", "text/html; charset=utf-8");
+        html.inject_livereload("");
+        let rsp = html.into_response(&Method::GET, vec![]).await.unwrap();
+        let content_length: usize = rsp.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = rsp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body.len(), content_length);
+        assert!(body.ends_with(b"</script></body>"));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_uploads_are_sent_as_the_client_can_take_them() {
+        let page = b"<html><body>hello hello hello</body></html>".repeat(20);
+        let bucket = InMemoryBucket::new();
+        bucket
+            .put_encoded("/index.html", &encoding::gzip(&page), "text/html", "gzip")
+            .await
+            .unwrap();
+        let script = b"console.log('hello hello hello');".repeat(20);
+        bucket
+            .put_encoded(
+                "/app.js",
+                &encoding::brotli(&script),
+                "text/javascript",
+                "br",
+            )
+            .await
+            .unwrap();
+        bucket
+            .put_encoded("/broken.js", b"not really brotli", "text/javascript", "br")
+            .await
+            .unwrap();
+        let store: Store = Arc::new(bucket);
+        let pages = Pages::from_upload_data(UploadData {
+            entries: ["/index.html", "/app.js", "/broken.js"]
+                .into_iter()
+                .map(|key| (key.to_string(), "hash".to_string()))
+                .collect(),
+            root: String::new(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        let ccm = CacheControlManager::default();
+        let accepting = |accept_encoding: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, accept_encoding.parse().unwrap());
+            headers
+        };
+
+        for method in [Method::GET, Method::HEAD] {
+            //passed through as it is
+            let mut output = pages
+                .get(&store, "/index.html", &ccm, true, None)
+                .await
+                .unwrap();
+            output.select_encoding(&accepting("gzip, br")).await;
+            let rsp = output.into_response(&method, vec![]).await.unwrap();
+            assert_eq!(rsp.status(), StatusCode::OK);
+            assert_eq!(rsp.headers()[header::CONTENT_ENCODING], "gzip");
+            assert_eq!(rsp.headers()[header::VARY], "accept-encoding");
+            assert_eq!(rsp.headers()[header::ETAG], "\"hash\"");
+            assert_eq!(
+                rsp.headers()[header::CONTENT_LENGTH],
+                encoding::gzip(&page).len().to_string()
+            );
+            let body = rsp.into_body().collect().await.unwrap().to_bytes();
+            if method == Method::GET {
+                assert_eq!(encoding::decode("gzip", &body).unwrap(), page);
+            } else {
+                assert!(body.is_empty());
+            }
+
+            //decompressed for a client that can't take gzip
+            let mut output = pages
+                .get(&store, "/index.html", &ccm, true, None)
+                .await
+                .unwrap();
+            output.select_encoding(&accepting("br")).await;
+            let rsp = output.into_response(&method, vec![]).await.unwrap();
+            assert_eq!(rsp.status(), StatusCode::OK);
+            assert!(!rsp.headers().contains_key(header::CONTENT_ENCODING));
+            assert_eq!(rsp.headers()[header::VARY], "accept-encoding");
+            assert_eq!(rsp.headers()[header::ETAG], "W/\"hash\"");
+            assert_eq!(
+                rsp.headers()[header::CONTENT_LENGTH],
+                page.len().to_string()
+            );
+            let body = rsp.into_body().collect().await.unwrap().to_bytes();
+            if method == Method::GET {
+                assert_eq!(body, page);
+            } else {
+                assert!(body.is_empty());
+            }
+
+            //brotli too, for a client that only takes gzip
+            let mut output = pages
+                .get(&store, "/app.js", &ccm, true, None)
+                .await
+                .unwrap();
+            output.select_encoding(&accepting("gzip")).await;
+            let rsp = output.into_response(&method, vec![]).await.unwrap();
+            assert_eq!(rsp.status(), StatusCode::OK);
+            assert!(!rsp.headers().contains_key(header::CONTENT_ENCODING));
+            assert_eq!(rsp.headers()[header::VARY], "accept-encoding");
+            assert_eq!(
+                rsp.headers()[header::CONTENT_LENGTH],
+                script.len().to_string()
+            );
+            let body = rsp.into_body().collect().await.unwrap().to_bytes();
+            if method == Method::GET {
+                assert_eq!(body, script);
+            } else {
+                assert!(body.is_empty());
+            }
+
+            //not what it says it is, so there's nothing to send
+            let mut output = pages
+                .get(&store, "/broken.js", &ccm, true, None)
+                .await
+                .unwrap();
+            output.select_encoding(&HeaderMap::new()).await;
+            let rsp = output.into_response(&method, vec![]).await.unwrap();
+            assert_eq!(rsp.status(), StatusCode::NOT_ACCEPTABLE);
+            assert!(!rsp.headers().contains_key(header::CONTENT_ENCODING));
+            assert_eq!(rsp.headers()[header::VARY], "accept-encoding");
+        }
+
+        //and it's never touched while still compressed
+        let mut output = pages
+            .get(&store, "/index.html", &ccm, true, None)
+            .await
+            .unwrap();
+        output.select_encoding(&accepting("gzip")).await;
+        output.inject_livereload("");
+        let PageContent::Buffered(content) = output.content else {
+            panic!("not buffered");
+        };
+        assert_eq!(content, encoding::gzip(&page));
+
+        //decoded the once, then kept next to the compressed bytes for whoever's next
+        let decoded = CacheKey {
+            path: "/index.html".to_string(),
+            encoding: Some("identity".to_string()),
+            variant: None,
+        };
+        assert_eq!(pages.cache().get(&decoded).await.unwrap().content, page);
+        pages
+            .cache()
+            .insert(decoded, entry(b"from the cache", "text/html"))
+            .await;
+        let mut output = pages
+            .get(&store, "/index.html", &ccm, true, None)
+            .await
+            .unwrap();
+        output.select_encoding(&accepting("br")).await;
+        let PageContent::Buffered(content) = output.content else {
+            panic!("not buffered");
+        };
+        assert_eq!(content, b"from the cache");
+    }
+
+    #[cfg(feature = "resize")]
+    #[tokio::test]
+    async fn test_resized_images_are_cached_by_size() {
+        use image::ImageFormat;
+
+        let bucket = InMemoryBucket::new();
+        let photo = resize::image(200, 100, ImageFormat::Png);
+        bucket
+            .put("public/cat.png", &photo, "image/png")
+            .await
+            .unwrap();
+        bucket
+            .put("public/notes.txt", b"notes", "text/plain")
+            .await
+            .unwrap();
+        bucket
+            .put("public/broken.png", b"not a png", "image/png")
+            .await
+            .unwrap();
+        let store: Store = Arc::new(bucket.clone());
+        let pages = Pages::from_upload_data(UploadData {
+            entries: ["public/cat.png", "public/notes.txt", "public/broken.png"]
+                .into_iter()
+                .map(|key| (key.to_string(), "hash".to_string()))
+                .collect(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        });
+        let ccm = CacheControlManager::default();
+        let width = |width| {
+            Some(Variant::Resized(Dimensions {
+                width: Some(width),
+                height: None,
+            }))
+        };
+        let content = |output: PageOutput| {
+            let PageContent::Buffered(content) = output.content else {
+                panic!("should be buffered");
+            };
+            content
+        };
+
+        let output = pages
+            .get(&store, "/cat.png", &ccm, true, width(40))
+            .await
+            .unwrap();
+        assert_eq!(output.cache_status, CacheStatus::Miss);
+        assert_eq!(output.validators.etag.as_deref(), Some("\"hash-w40\""));
+        let resized = image::load_from_memory(&content(output)).unwrap();
+        assert_eq!((resized.width(), resized.height()), (40, 20));
+
+        //only worked out the once, and kept alongside the original
+        let output = pages
+            .get(&store, "/cat.png", &ccm, true, width(40))
+            .await
+            .unwrap();
+        assert_eq!(output.cache_status, CacheStatus::Hit);
+        assert_eq!(pages.cached_entry_count().await, 2);
+        let output = pages
+            .get(&store, "/cat.png", &ccm, true, None)
+            .await
+            .unwrap();
+        assert_eq!(output.validators.etag.as_deref(), Some("\"hash\""));
+        assert_eq!(content(output), photo);
+
+        //anything that isn't an image that can be resized is served as it is
+        let output = pages
+            .get(&store, "/notes.txt", &ccm, true, width(40))
+            .await
+            .unwrap();
+        assert_eq!(output.validators.etag.as_deref(), Some("\"hash\""));
+        assert_eq!(content(output), b"notes");
+        let output = pages
+            .get(&store, "/broken.png", &ccm, true, width(40))
+            .await
+            .unwrap();
+        assert_eq!(output.status, StatusCode::OK);
+        assert_eq!(content(output), b"not a png");
+
+        //every size goes with the original
+        assert_eq!(pages.purge("/cat.png").await, Purged::Purged);
+        let output = pages
+            .get(&store, "/cat.png", &ccm, true, width(40))
+            .await
+            .unwrap();
+        assert_eq!(output.cache_status, CacheStatus::Miss);
     }
 }
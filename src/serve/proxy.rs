@@ -0,0 +1,200 @@
+use crate::{config::EnvReader, ip_filter::parse_net};
+use hyper::{header, HeaderMap};
+use ipnet::IpNet;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+///the proxies allowed to tell us who the client really is, from `TRUSTED_PROXIES`
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Arc<[IpNet]>);
+
+impl TrustedProxies {
+    ///`Err` with every entry that isn't an address or CIDR
+    pub fn new(list: &str) -> Result<Self, Vec<String>> {
+        let mut nets = vec![];
+        let mut invalid = vec![];
+        for x in list.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match parse_net(x) {
+                Some(net) => nets.push(net),
+                None => invalid.push(x.to_string()),
+            }
+        }
+
+        if invalid.is_empty() {
+            Ok(Self(nets.into()))
+        } else {
+            Err(invalid)
+        }
+    }
+
+    pub fn read(env: &mut EnvReader) -> Self {
+        let Some(list) = env.optional("TRUSTED_PROXIES") else {
+            return Self::default();
+        };
+        Self::new(&list).unwrap_or_else(|invalid| {
+            env.problem(format!(
+                "TRUSTED_PROXIES has entries that aren't addresses or CIDRs: {}",
+                invalid.join(", ")
+            ));
+            Self::default()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|x| x.contains(&ip))
+    }
+
+    ///who sent the request. the forwarding headers only count when `peer` is one of our proxies, and
+    ///are read from the right so a client can't just prepend whatever address it likes
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(peer) {
+            return peer;
+        }
+
+        let mut client = peer;
+        for hop in forwarded_chain(headers).iter().rev() {
+            //anything we can't read could be anyone, so stop at whoever told us about it
+            let Some(ip) = hop.as_deref().and_then(parse_hop) else {
+                break;
+            };
+            client = ip;
+            if !self.trusts(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+///every hop in `X-Forwarded-For`, or `Forwarded` if there's none of that, leftmost first
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<String>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .flat_map(|x| match x.to_str() {
+                Ok(x) => x.split(',').map(|x| Some(x.trim().to_string())).collect(),
+                Err(_) => vec![None],
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let xff = values(header::HeaderName::from_static("x-forwarded-for"));
+    if !xff.is_empty() {
+        return xff;
+    }
+
+    values(header::FORWARDED)
+        .into_iter()
+        .map(|element| {
+            element?.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect()
+}
+
+///one address from a forwarding header, which might have a port and/or IPv6 brackets
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|x| x.ip()))
+        .or_else(|| {
+            hop.strip_prefix('[')
+                .and_then(|x| x.strip_suffix(']'))
+                .and_then(|x| x.parse().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    #[test]
+    fn test_untrusted_peer_is_the_client() {
+        let proxies = TrustedProxies::new("10.0.0.0/8").unwrap();
+        let peer = "192.0.2.1".parse().unwrap();
+        let spoofed = headers(&[("x-forwarded-for", "198.51.100.7")]);
+
+        assert_eq!(proxies.client_ip(peer, &spoofed), peer);
+        assert_eq!(TrustedProxies::default().client_ip(peer, &spoofed), peer);
+    }
+
+    #[test]
+    fn test_trusted_peer_forwards_client() {
+        let proxies = TrustedProxies::new("10.0.0.1").unwrap();
+        let peer = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(
+            proxies.client_ip(peer, &headers(&[("x-forwarded-for", "192.0.2.1")])),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            proxies.client_ip(
+                peer,
+                &headers(&[("forwarded", r#"for="[2001:db8::1]:4711";proto=https"#)])
+            ),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(proxies.client_ip(peer, &HeaderMap::new()), peer);
+    }
+
+    #[test]
+    fn test_multi_hop_chains() {
+        let proxies = TrustedProxies::new("10.0.0.0/8, 2001:db8:ffff::/48").unwrap();
+        let peer = "10.0.0.1".parse().unwrap();
+        let client = "192.0.2.1".parse::<IpAddr>().unwrap();
+
+        //client -> cdn -> load balancer -> us, with the client trying to blame someone else
+        let chain = headers(&[
+            ("x-forwarded-for", "198.51.100.7, 192.0.2.1"),
+            ("x-forwarded-for", "10.1.2.3"),
+        ]);
+        assert_eq!(proxies.client_ip(peer, &chain), client);
+
+        let chain = headers(&[(
+            "forwarded",
+            r#"for=198.51.100.7, for=192.0.2.1:1234;proto=https, for="[2001:db8:ffff::1]""#,
+        )]);
+        assert_eq!(proxies.client_ip(peer, &chain), client);
+
+        //X-Forwarded-For wins if both are there
+        let both = headers(&[
+            ("forwarded", "for=198.51.100.7"),
+            ("x-forwarded-for", "192.0.2.1"),
+        ]);
+        assert_eq!(proxies.client_ip(peer, &both), client);
+
+        //every hop trusted means the furthest one is all we know
+        let internal = headers(&[("x-forwarded-for", "10.9.9.9, 10.1.2.3")]);
+        assert_eq!(
+            proxies.client_ip(peer, &internal),
+            "10.9.9.9".parse::<IpAddr>().unwrap()
+        );
+
+        //garbage stops the walk at the proxy that passed it on
+        let garbage = headers(&[("x-forwarded-for", "192.0.2.1, unknown, 10.1.2.3")]);
+        assert_eq!(
+            proxies.client_ip(peer, &garbage),
+            "10.1.2.3".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use hyper::{header, HeaderMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+///what a page can be checked against with `If-Range`, `If-None-Match` & `If-Modified-Since`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    ///already quoted, ready for the `ETag` header
+    pub etag: Option<String>,
+    ///to the second, as that's all an HTTP date can hold
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    ///`hash` is the entry's hash from the upload data, which changes whenever its contents do
+    pub fn new(hash: Option<&str>, last_modified: SystemTime) -> Self {
+        Self {
+            etag: hash.map(|x| format!("\"{x}\"")),
+            last_modified: Some(to_the_second(last_modified)),
+        }
+    }
+
+    ///for bytes made from the entry's rather than exactly what was uploaded, which can't share its
+    ///ETag. `variant` tells them apart from each other
+    pub fn for_variant(&mut self, variant: &str) {
+        if let Some(etag) = &mut self.etag
+            && let Some(unquoted) = etag.strip_suffix('"')
+        {
+            *etag = format!("{unquoted}-{variant}\"");
+        }
+    }
+
+    ///only strong comparisons count - a weak ETag could be for different bytes entirely
+    fn matches(&self, if_range: &str) -> bool {
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            return self.etag.as_deref() == Some(if_range);
+        }
+        match (httpdate::parse_http_date(if_range), self.last_modified) {
+            (Ok(date), Some(last_modified)) => date == last_modified,
+            _ => false,
+        }
+    }
+
+    ///whether the client's copy is still good, so it can have a `304`. `If-Modified-Since` is only
+    ///looked at without an `If-None-Match`, as ETags are the more precise of the two
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+            let Some(etag) = self.etag.as_deref() else {
+                return false;
+            };
+            let Ok(if_none_match) = if_none_match.to_str() else {
+                return false;
+            };
+            //weak comparison, as the bytes don't need to be the same to not send them again
+            let opaque = |x: &str| x.trim().trim_start_matches("W/").to_string();
+            return if_none_match.trim() == "*"
+                || if_none_match.split(',').any(|x| opaque(x) == opaque(etag));
+        }
+
+        let if_modified_since = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| httpdate::parse_http_date(x).ok());
+        match (if_modified_since, self.last_modified) {
+            (Some(since), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    }
+}
+
+fn to_the_second(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+///which bytes of a page to send
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteRange {
+    #[default]
+    Full,
+    ///from `start` to `end`, both inclusive
+    Partial { start: u64, end: u64 },
+    ///asked for a range, but none of it is there
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    ///what the request asks for from a page of `len` bytes. `If-Range` not matching `validators`
+    ///means the page has changed since the client got the rest of it, so they get all of it again
+    pub fn requested(headers: &HeaderMap, len: u64, validators: &Validators) -> Self {
+        let Some(range) = headers.get(header::RANGE).and_then(|x| x.to_str().ok()) else {
+            return Self::Full;
+        };
+        if let Some(if_range) = headers.get(header::IF_RANGE) {
+            let matches = if_range.to_str().is_ok_and(|x| validators.matches(x));
+            if !matches {
+                debug!(?if_range, "If-Range doesn't match, sending the whole page");
+                return Self::Full;
+            }
+        }
+
+        Self::parse(range, len)
+    }
+
+    ///only single ranges are done - anything else gets the whole page, which is always allowed
+    fn parse(range: &str, len: u64) -> Self {
+        let Some(spec) = range.trim().strip_prefix("bytes=") else {
+            return Self::Full;
+        };
+        if spec.contains(',') {
+            return Self::Full;
+        }
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Self::Full;
+        };
+
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", "") => return Self::Full,
+            //the last `suffix` bytes
+            ("", suffix) => {
+                let Ok(suffix) = suffix.parse::<u64>() else {
+                    return Self::Full;
+                };
+                if suffix == 0 || len == 0 {
+                    return Self::Unsatisfiable;
+                }
+                (len.saturating_sub(suffix), len - 1)
+            }
+            (start, end) => {
+                let Ok(start) = start.parse::<u64>() else {
+                    return Self::Full;
+                };
+                let end = match end {
+                    "" => u64::MAX,
+                    end => match end.parse::<u64>() {
+                        Ok(end) if end >= start => end,
+                        _ => return Self::Full,
+                    },
+                };
+                if start >= len {
+                    return Self::Unsatisfiable;
+                }
+                (start, end.min(len - 1))
+            }
+        };
+
+        Self::Partial { start, end }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let last_modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let validators = Validators::new(Some("abc"), last_modified);
+        let date = |secs| httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs));
+
+        for (pairs, expected) in [
+            (vec![(header::IF_NONE_MATCH, "\"abc\"")], true),
+            (vec![(header::IF_NONE_MATCH, "W/\"abc\"")], true),
+            (vec![(header::IF_NONE_MATCH, "\"x\", \"abc\"")], true),
+            (vec![(header::IF_NONE_MATCH, "*")], true),
+            (vec![(header::IF_NONE_MATCH, "\"x\"")], false),
+            (
+                vec![(header::IF_MODIFIED_SINCE, date(1_700_000_000).as_str())],
+                true,
+            ),
+            (
+                vec![(header::IF_MODIFIED_SINCE, date(1_800_000_000).as_str())],
+                true,
+            ),
+            (
+                vec![(header::IF_MODIFIED_SINCE, date(1_600_000_000).as_str())],
+                false,
+            ),
+            (vec![(header::IF_MODIFIED_SINCE, "yesterday")], false),
+            //the ETag wins
+            (
+                vec![
+                    (header::IF_NONE_MATCH, "\"x\""),
+                    (header::IF_MODIFIED_SINCE, date(1_800_000_000).as_str()),
+                ],
+                false,
+            ),
+            (vec![], false),
+        ] {
+            assert_eq!(
+                validators.not_modified(&headers(&pairs)),
+                expected,
+                "{pairs:?}"
+            );
+        }
+        assert!(!Validators::default().not_modified(&headers(&[(header::IF_NONE_MATCH, "*")])));
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-4", 10),
+            ByteRange::Partial { start: 0, end: 4 }
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=5-", 10),
+            ByteRange::Partial { start: 5, end: 9 }
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=5-100", 10),
+            ByteRange::Partial { start: 5, end: 9 }
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-3", 10),
+            ByteRange::Partial { start: 7, end: 9 }
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-30", 10),
+            ByteRange::Partial { start: 0, end: 9 }
+        );
+        assert_eq!(ByteRange::parse("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=-0", 10), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=0-1,4-5", 10), ByteRange::Full);
+        assert_eq!(ByteRange::parse("bytes=4-1", 10), ByteRange::Full);
+        assert_eq!(ByteRange::parse("lines=0-1", 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let deployed = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let validators = Validators::new(Some("abc"), deployed);
+        let partial = ByteRange::Partial { start: 0, end: 4 };
+        let requested = |pairs: &[(header::HeaderName, &str)]| {
+            ByteRange::requested(&headers(pairs), 10, &validators)
+        };
+
+        //no If-Range means the range is served whatever the client has
+        assert_eq!(requested(&[(header::RANGE, "bytes=0-4")]), partial);
+
+        assert_eq!(
+            requested(&[(header::RANGE, "bytes=0-4"), (header::IF_RANGE, "\"abc\"")]),
+            partial
+        );
+        assert_eq!(
+            requested(&[(header::RANGE, "bytes=0-4"), (header::IF_RANGE, "\"old\"")]),
+            ByteRange::Full
+        );
+        assert_eq!(
+            requested(&[
+                (header::RANGE, "bytes=0-4"),
+                (header::IF_RANGE, "W/\"abc\"")
+            ]),
+            ByteRange::Full
+        );
+
+        let date = httpdate::fmt_http_date(deployed);
+        assert_eq!(
+            requested(&[(header::RANGE, "bytes=0-4"), (header::IF_RANGE, &date)]),
+            partial
+        );
+        let earlier = httpdate::fmt_http_date(deployed - Duration::from_secs(60));
+        assert_eq!(
+            requested(&[(header::RANGE, "bytes=0-4"), (header::IF_RANGE, &earlier)]),
+            ByteRange::Full
+        );
+
+        assert_eq!(requested(&[(header::IF_RANGE, "\"abc\"")]), ByteRange::Full);
+    }
+}
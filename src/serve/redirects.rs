@@ -0,0 +1,262 @@
+use crate::{
+    entry_key,
+    s3::{get_bytes_if_changed, get_bytes_or_default, LastFetched},
+    store::ObjectStore,
+};
+use color_eyre::eyre::bail;
+use hyper::StatusCode;
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+///where the rules live, relative to the site root. Never served to clients
+pub const REDIRECTS_PATH: &str = "/_redirects";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    from: String,
+    ///whether `from` ended in `/*`, in which case `from` is everything before the `*`
+    splat: bool,
+    to: String,
+    status: StatusCode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirect {
+    ///a 3xx with a `Location`
+    Moved {
+        location: String,
+        status: StatusCode,
+    },
+    ///serve the content at this path instead
+    Rewrite(String),
+}
+
+///the start of a path without any trailing `/`, so `/old` and `/old/` match the same rules
+fn trim_path(path: &str) -> &str {
+    match path.trim_end_matches('/') {
+        "" => "/",
+        x => x,
+    }
+}
+
+///parses Netlify-style `_redirects` lines of `from to [status]`. Malformed lines are skipped
+fn parse_rules(contents: &str) -> Vec<Rule> {
+    let mut rules = vec![];
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (from, to, status) = match parts.as_slice() {
+            [from, to] => (*from, *to, StatusCode::MOVED_PERMANENTLY),
+            [from, to, status] => match status.parse::<u16>() {
+                Ok(301) => (*from, *to, StatusCode::MOVED_PERMANENTLY),
+                Ok(302) => (*from, *to, StatusCode::FOUND),
+                Ok(200) => (*from, *to, StatusCode::OK),
+                _ => {
+                    warn!(line=%i + 1, ?status, "Unsupported status in _redirects, skipping");
+                    continue;
+                }
+            },
+            _ => {
+                warn!(line=%i + 1, ?line, "Malformed line in _redirects, skipping");
+                continue;
+            }
+        };
+
+        if !from.starts_with('/') {
+            warn!(line=%i + 1, ?from, "Redirect source must start with a `/`, skipping");
+            continue;
+        }
+        if status == StatusCode::OK && !to.starts_with('/') {
+            warn!(line=%i + 1, ?to, "Rewrites must point at a path on this site, skipping");
+            continue;
+        }
+
+        let (from, splat) = match from.strip_suffix("/*") {
+            Some(prefix) => (format!("{prefix}/"), true),
+            None => (trim_path(from).to_string(), false),
+        };
+
+        rules.push(Rule {
+            from,
+            splat,
+            to: to.to_string(),
+            status,
+        });
+    }
+
+    rules
+}
+
+///finds the first rule matching `path`, and where it goes
+fn resolve(rules: &[Rule], path: &str, query: Option<&str>) -> Option<Redirect> {
+    rules.iter().find_map(|rule| {
+        let to = if rule.splat {
+            let rest = if trim_path(path) == trim_path(&rule.from) {
+                ""
+            } else {
+                path.strip_prefix(&rule.from)?
+            };
+            rule.to.replace(":splat", rest)
+        } else if trim_path(path) == rule.from {
+            rule.to.clone()
+        } else {
+            return None;
+        };
+
+        Some(if rule.status == StatusCode::OK {
+            Redirect::Rewrite(to)
+        } else {
+            let location = match query {
+                Some(query) if !to.contains('?') => format!("{to}?{query}"),
+                _ => to,
+            };
+            Redirect::Moved {
+                location,
+                status: rule.status,
+            }
+        })
+    })
+}
+
+#[derive(Clone, Default)]
+pub struct Redirects {
+    last_fetched: Arc<Mutex<LastFetched>>,
+    rules: Arc<RwLock<Vec<Rule>>>,
+}
+
+impl Redirects {
+    pub async fn new(bucket: &dyn ObjectStore, root: &str) -> color_eyre::Result<Self> {
+        let raw_bytes = get_bytes_or_default(bucket, entry_key(root, REDIRECTS_PATH)).await?;
+        let rules = parse_rules(&String::from_utf8_lossy(&raw_bytes));
+        info!(len=%rules.len(), "Loaded redirects");
+
+        Ok(Self {
+            last_fetched: Arc::new(Mutex::new(LastFetched::new(&raw_bytes))),
+            rules: Arc::new(RwLock::new(rules)),
+        })
+    }
+
+    pub async fn check_and_reload(
+        &self,
+        bucket: &dyn ObjectStore,
+        root: &str,
+    ) -> color_eyre::Result<()> {
+        let Ok(mut last_fetched) = self.last_fetched.try_lock() else {
+            bail!("already reloading redirects")
+        };
+
+        let location = entry_key(root, REDIRECTS_PATH);
+        let Some(raw_bytes) = get_bytes_if_changed(bucket, location, &mut last_fetched).await?
+        else {
+            return Ok(());
+        };
+
+        let rules = parse_rules(&String::from_utf8_lossy(&raw_bytes));
+        info!(len=%rules.len(), "Reloaded redirects");
+        *self.rules.write().await = rules;
+
+        Ok(())
+    }
+
+    pub async fn resolve(&self, path: &str, query: Option<&str>) -> Option<Redirect> {
+        resolve(&self.rules.read().await, path, query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = "
+# comment
+/old /new 301
+/temp /elsewhere 302
+/blog/* /news/:splat 301
+/app/* /app/index.html 200
+/default /somewhere
+/external https://example.com 302
+this is not valid at all
+/bad-status /x 418
+relative /x 301
+/rewrite-out https://example.com 200
+";
+
+    fn moved(location: &str, status: StatusCode) -> Option<Redirect> {
+        Some(Redirect::Moved {
+            location: location.to_string(),
+            status,
+        })
+    }
+
+    #[test]
+    fn test_malformed_lines_are_skipped() {
+        let rules = parse_rules(RULES);
+        assert_eq!(rules.len(), 6);
+        assert_eq!(
+            rules[2],
+            Rule {
+                from: "/blog/".to_string(),
+                splat: true,
+                to: "/news/:splat".to_string(),
+                status: StatusCode::MOVED_PERMANENTLY
+            }
+        );
+    }
+
+    #[test]
+    fn test_exact_redirects() {
+        let rules = parse_rules(RULES);
+
+        assert_eq!(
+            resolve(&rules, "/old", None),
+            moved("/new", StatusCode::MOVED_PERMANENTLY)
+        );
+        assert_eq!(
+            resolve(&rules, "/old/", None),
+            moved("/new", StatusCode::MOVED_PERMANENTLY)
+        );
+        assert_eq!(
+            resolve(&rules, "/temp", Some("a=b")),
+            moved("/elsewhere?a=b", StatusCode::FOUND)
+        );
+        assert_eq!(
+            resolve(&rules, "/default", None),
+            moved("/somewhere", StatusCode::MOVED_PERMANENTLY)
+        );
+        assert_eq!(
+            resolve(&rules, "/external", None),
+            moved("https://example.com", StatusCode::FOUND)
+        );
+        assert_eq!(resolve(&rules, "/older", None), None);
+    }
+
+    #[test]
+    fn test_splats() {
+        let rules = parse_rules(RULES);
+
+        assert_eq!(
+            resolve(&rules, "/blog/2024/post.html", None),
+            moved("/news/2024/post.html", StatusCode::MOVED_PERMANENTLY)
+        );
+        assert_eq!(
+            resolve(&rules, "/blog", None),
+            moved("/news/", StatusCode::MOVED_PERMANENTLY)
+        );
+        assert_eq!(resolve(&rules, "/blogs/post.html", None), None);
+    }
+
+    #[test]
+    fn test_rewrites() {
+        let rules = parse_rules(RULES);
+
+        assert_eq!(
+            resolve(&rules, "/app/settings/profile", Some("x=1")),
+            Some(Redirect::Rewrite("/app/index.html".to_string()))
+        );
+    }
+}
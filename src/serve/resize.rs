@@ -0,0 +1,238 @@
+use crate::{config::EnvReader, serve::query_param};
+use std::num::NonZeroU32;
+
+const DEFAULT_RESIZE_MAX_DIMENSION: u32 = 2048;
+
+///the box an image gets scaled down to fit in, from `?w=` & `?h=`. Either can be left out, to
+///only fit the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Dimensions {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl Dimensions {
+    ///for telling the variants apart in ETags, eg. `w400` or `w400h300`
+    pub fn tag(&self) -> String {
+        let mut tag = String::new();
+        if let Some(width) = self.width {
+            tag.push_str(&format!("w{width}"));
+        }
+        if let Some(height) = self.height {
+            tag.push_str(&format!("h{height}"));
+        }
+        tag
+    }
+}
+
+///from `RESIZE_IMAGES`, which needs shove built with the `resize` feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resizing {
+    ///the most either side can be asked for, from `RESIZE_MAX_DIMENSION`. Anything over is
+    ///brought down to it, so there's only so many variants of each image
+    pub max_dimension: u32,
+}
+
+impl Resizing {
+    ///`None` unless `RESIZE_IMAGES` is on
+    pub fn read(env: &mut EnvReader) -> Option<Self> {
+        let max_dimension = env
+            .parsed("RESIZE_MAX_DIMENSION")
+            .map_or(DEFAULT_RESIZE_MAX_DIMENSION, NonZeroU32::get);
+        if !env.flag("RESIZE_IMAGES") {
+            return None;
+        }
+        if !cfg!(feature = "resize") {
+            env.problem("RESIZE_IMAGES is set, but shove was built without the `resize` feature");
+            return None;
+        }
+        Some(Self { max_dimension })
+    }
+
+    ///what `query` asks for, capped to [`Self::max_dimension`]. `None` if it doesn't ask for
+    ///either, or not as a positive whole number
+    pub fn requested(&self, query: Option<&str>) -> Option<Dimensions> {
+        let side = |name| {
+            let side: NonZeroU32 = query_param(query, name)?.parse().ok()?;
+            Some(side.get().min(self.max_dimension))
+        };
+        let dimensions = Dimensions {
+            width: side("w"),
+            height: side("h"),
+        };
+        (dimensions.width.is_some() || dimensions.height.is_some()).then_some(dimensions)
+    }
+}
+
+#[cfg(feature = "resize")]
+fn format_of(content_type: &str) -> Option<image::ImageFormat> {
+    use image::ImageFormat;
+
+    let mime: mime::Mime = content_type.parse().ok()?;
+    ImageFormat::from_mime_type(mime.essence_str())
+        .filter(|x| matches!(x, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP))
+}
+
+///whether anything of `content_type` can be resized - everything else ignores `?w=` & `?h=`
+pub fn can_resize(content_type: &str) -> bool {
+    #[cfg(feature = "resize")]
+    {
+        format_of(content_type).is_some()
+    }
+    #[cfg(not(feature = "resize"))]
+    {
+        let _ = content_type;
+        false
+    }
+}
+
+///`bytes` scaled down to fit in `dimensions`, in the format they came in. `None` if they can't be
+///read, or already fit, as then the original is served instead. Slow for big images, so wants
+///[`spawn_blocking`](tokio::task::spawn_blocking)
+#[cfg(feature = "resize")]
+pub fn resize(bytes: &[u8], content_type: &str, dimensions: Dimensions) -> Option<Vec<u8>> {
+    use image::{imageops::FilterType, DynamicImage, ImageFormat, ImageReader};
+    use std::io::Cursor;
+
+    let format = format_of(content_type)?;
+    let image = match ImageReader::with_format(Cursor::new(bytes), format).decode() {
+        Ok(x) => x,
+        Err(e) => {
+            debug!(?e, %content_type, "Unable to read image to resize");
+            return None;
+        }
+    };
+
+    let width = dimensions.width.unwrap_or(u32::MAX);
+    let height = dimensions.height.unwrap_or(u32::MAX);
+    //never made any bigger
+    if image.width() <= width && image.height() <= height {
+        return None;
+    }
+    let resized = image.resize(width, height, FilterType::Lanczos3);
+    //JPEGs can't hold transparency, or more than 8 bits a channel
+    let resized = match format {
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(resized.to_rgb8()),
+        _ => resized,
+    };
+
+    let mut encoded = Cursor::new(vec![]);
+    if let Err(e) = resized.write_to(&mut encoded, format) {
+        warn!(?e, %content_type, "Unable to write resized image");
+        return None;
+    }
+    Some(encoded.into_inner())
+}
+
+#[cfg(not(feature = "resize"))]
+pub fn resize(_bytes: &[u8], _content_type: &str, _dimensions: Dimensions) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(all(test, feature = "resize"))]
+pub fn image(width: u32, height: u32, format: image::ImageFormat) -> Vec<u8> {
+    let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([x as u8, y as u8, 128])
+    }));
+    let mut encoded = std::io::Cursor::new(vec![]);
+    image.write_to(&mut encoded, format).unwrap();
+    encoded.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESIZING: Resizing = Resizing { max_dimension: 500 };
+
+    #[test]
+    fn test_requested_dimensions() {
+        assert_eq!(
+            RESIZING.requested(Some("w=400")),
+            Some(Dimensions {
+                width: Some(400),
+                height: None
+            })
+        );
+        assert_eq!(
+            RESIZING.requested(Some("v=2&h=300&w=100")),
+            Some(Dimensions {
+                width: Some(100),
+                height: Some(300)
+            })
+        );
+        //capped, rather than refused
+        assert_eq!(
+            RESIZING.requested(Some("w=10000")),
+            Some(Dimensions {
+                width: Some(500),
+                height: None
+            })
+        );
+
+        for query in [
+            None,
+            Some(""),
+            Some("v=2"),
+            Some("w=0"),
+            Some("w=-4"),
+            Some("w=big"),
+        ] {
+            assert_eq!(RESIZING.requested(query), None, "{query:?}");
+        }
+    }
+
+    #[test]
+    fn test_only_on_when_asked() {
+        let mut env = EnvReader::new(|_| None);
+        assert_eq!(Resizing::read(&mut env), None);
+
+        let mut env = EnvReader::new(|name| match name {
+            "RESIZE_IMAGES" => Some("true".to_string()),
+            "RESIZE_MAX_DIMENSION" => Some("800".to_string()),
+            _ => None,
+        });
+        let resizing = Resizing::read(&mut env);
+        if cfg!(feature = "resize") {
+            assert_eq!(resizing, Some(Resizing { max_dimension: 800 }));
+            assert!(env.finish(()).is_ok());
+        } else {
+            assert_eq!(resizing, None);
+            assert!(env.finish(()).is_err());
+        }
+    }
+
+    #[cfg(feature = "resize")]
+    #[test]
+    fn test_resize() {
+        use image::ImageFormat;
+
+        let fit = Dimensions {
+            width: Some(40),
+            height: None,
+        };
+        for (format, content_type) in [
+            (ImageFormat::Jpeg, "image/jpeg"),
+            (ImageFormat::Png, "image/png"),
+            (ImageFormat::WebP, "image/webp"),
+        ] {
+            let resized = resize(&image(200, 100, format), content_type, fit).unwrap();
+            let resized = image::load_from_memory_with_format(&resized, format).unwrap();
+            assert_eq!(
+                (resized.width(), resized.height()),
+                (40, 20),
+                "{content_type}"
+            );
+        }
+
+        //already small enough
+        assert_eq!(
+            resize(&image(20, 10, ImageFormat::Png), "image/png", fit),
+            None
+        );
+        //not what it says it is
+        assert_eq!(resize(b"not a png", "image/png", fit), None);
+        assert!(!can_resize("image/svg+xml"));
+        assert!(can_resize("image/jpeg; charset=binary"));
+    }
+}
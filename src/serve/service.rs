@@ -1,171 +1,1032 @@
 use crate::{
+    cors::manager::{preflight_headers, response_headers, CorsHeaders},
+    normalise_host,
     protect::auth::AuthReturn,
-    serve::{empty_with_code, state::State},
+    serve::{
+        body::{discard_body, expectation_is_supported, read_capped_body},
+        concurrency::{ConcurrencyReport, Rejected},
+        empty_body, empty_with_code, empty_with_headers, full_body,
+        journal::{CacheStatus, MatchedRealm, RequestContext, ResponseError},
+        json_with_code,
+        listen::Routes,
+        markdown::is_markdown,
+        negotiate::{negotiate_error, wants_json},
+        pages::{
+            encode_request_path, normalise_request_path, resolve_request_path, Admission,
+            AlreadyReloading, CaseInsensitivePaths, DeployedVersion, Purged, TrailingSlash,
+            Variant, Warmth,
+        },
+        query_param,
+        redirects::Redirect,
+        sites::Site,
+        state::State,
+        telemetry::{spawn_tagged, RequestTelemetry},
+        tls::{https_redirect, OverTls},
+        webhook::changed_keys,
+        ServeBody,
+    },
 };
-use http_body_util::Full;
+use futures::FutureExt;
 use hyper::{
-    body::{Bytes, Incoming},
-    http,
-    service::Service,
-    Method, Request, Response, StatusCode,
+    body::Incoming, header, header::HeaderValue, http, service::Service, HeaderMap, Method,
+    Request, Response, StatusCode, Uri, Version,
 };
-use path_clean::PathClean;
+use serde::{Deserialize, Serialize};
 use soketto::handshake::http::{is_upgrade_request, Server};
-use std::{future::Future, net::SocketAddr, path::Path, pin::Pin, sync::Arc};
-use tokio::sync::{Semaphore, TryAcquireError};
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    panic::AssertUnwindSafe,
+    pin::Pin,
+};
+use subtle::ConstantTimeEq;
+use tokio::sync::OwnedSemaphorePermit;
 
 pub struct ServeService {
     state: State,
     remote_ip: SocketAddr,
-    semaphore: Arc<Semaphore>,
+    ///what the listener this came in on answers
+    routes: Routes,
+    over_tls: bool,
 }
 
 impl ServeService {
-    pub fn new(state: State, remote_ip: SocketAddr, semaphore: Arc<Semaphore>) -> Self {
+    pub fn new(state: State, remote_ip: SocketAddr, routes: Routes, over_tls: bool) -> Self {
         Self {
             state,
             remote_ip,
-            semaphore,
+            routes,
+            over_tls,
         }
     }
 }
 
 impl Service<Request<Incoming>> for ServeService {
-    type Response = Response<Full<Bytes>>;
+    type Response = Response<ServeBody>;
     type Error = http::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, req: Request<Incoming>) -> Self::Future {
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
+        req.extensions_mut().insert(self.routes);
+        if self.over_tls {
+            req.extensions_mut().insert(OverTls);
+        }
         let state = self.state.clone();
         let remote_addr = self.remote_ip;
-        let livereload = state.live_reloader();
-        let semaphore = self.semaphore.clone();
+        //everything after this point only cares who the client is, not which proxy they came through
+        let client_ip = state
+            .trusted_proxies
+            .client_ip(remote_addr.ip(), req.headers());
+
+        //worked out now, as whatever goes wrong could happen after the request's gone
+        let wants_json = wants_json(req.headers(), req.uri().path());
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let negotiate = move |rsp: Result<Response<ServeBody>, http::Error>| {
+            rsp.map(|rsp| negotiate_error(rsp, wants_json, &method, &path))
+        };
 
         Box::pin(async move {
-            let permit = match semaphore.try_acquire_owned() {
-                Ok(p) => p,
-                Err(TryAcquireError::NoPermits) => {
-                    return empty_with_code(StatusCode::TOO_MANY_REQUESTS);
-                }
-                Err(TryAcquireError::Closed) => {
-                    return empty_with_code(StatusCode::SERVICE_UNAVAILABLE);
-                }
+            let Some(telemetry) = RequestTelemetry::start(&req, client_ip) else {
+                return negotiate(respond(req, state, remote_addr, client_ip).await);
             };
+            if let Some(site) = state.site(request_host(&req).as_deref()).await {
+                telemetry.set_deployed(&site.deployed_version().await.upload_data_sha256);
+            }
+            let rsp = negotiate(
+                telemetry
+                    .run(respond(req, state, remote_addr, client_ip))
+                    .await,
+            );
+            telemetry.finish(&rsp);
+            rsp
+        })
+    }
+}
 
-            //thx https://github.com/paritytech/soketto/blob/master/examples/hyper_server.rs
-            if is_upgrade_request(&req) {
-                let mut handshake_server = Server::new();
-
-                match handshake_server.receive_request(&req) {
-                    Ok(rsp) => {
-                        tokio::task::spawn(async move {
-                            if let Err(e) =
-                                livereload.handle_livereload(req, handshake_server).await
-                            {
-                                error!(?e, "Error with websockets");
-                            }
-                            //ensure permit is moved into the new thread
-                            drop(permit);
-                        });
-                        Ok(rsp.map(|()| Full::default()))
-                    }
-                    Err(e) => {
-                        error!(?e, "Couldn't upgrade connection");
-                        empty_with_code(StatusCode::INTERNAL_SERVER_ERROR)
-                    }
+///handles the request, keeping any that fail in the journal
+async fn respond(
+    req: Request<Incoming>,
+    state: State,
+    remote_addr: SocketAddr,
+    client_ip: IpAddr,
+) -> Result<Response<ServeBody>, http::Error> {
+    let Some(journal) = state.journal() else {
+        return handle_with_timeout(req, state, client_ip).await;
+    };
+
+    let context = RequestContext::new(
+        req.method(),
+        req.uri(),
+        req.version(),
+        req.headers(),
+        remote_addr,
+        client_ip,
+    );
+
+    match AssertUnwindSafe(handle_with_timeout(req, state, client_ip))
+        .catch_unwind()
+        .await
+    {
+        Ok(Ok(rsp)) => {
+            if rsp.status().is_server_error() {
+                let extensions = rsp.extensions();
+                journal.record(context.finish(
+                    Some(rsp.status().as_u16()),
+                    extensions.get::<MatchedRealm>().map(|x| x.0.clone()),
+                    extensions.get::<CacheStatus>().copied(),
+                    extensions.get::<ResponseError>().map(|x| x.0.clone()),
+                ));
+            }
+            Ok(rsp)
+        }
+        Ok(Err(e)) => {
+            journal.record(context.finish(None, None, None, Some(format!("{e:?}"))));
+            Err(e)
+        }
+        Err(panic) => {
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|x| x.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            error!(?msg, "Panicked whilst handling request");
+            journal.record(context.finish(None, None, None, Some(msg)));
+            empty_with_code(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+///WebSocket upgrades are exempt, as they're meant to stick around - dead ones get found by the pings
+async fn handle_with_timeout(
+    req: Request<Incoming>,
+    state: State,
+    client_ip: IpAddr,
+) -> Result<Response<ServeBody>, http::Error> {
+    if is_upgrade_request(&req) {
+        return handle(req, state, client_ip).await;
+    }
+
+    let request_timeout = state.limits.request_timeout;
+    let timeouts = state.timeouts.clone();
+    match tokio::time::timeout(request_timeout, handle(req, state, client_ip)).await {
+        Ok(rsp) => rsp,
+        Err(_) => {
+            timeouts.request();
+            empty_with_code(StatusCode::REQUEST_TIMEOUT)
+        }
+    }
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    state: State,
+    client_ip: IpAddr,
+) -> Result<Response<ServeBody>, http::Error> {
+    let permit = match state.concurrency.acquire().await {
+        Ok(p) => p,
+        //unlike a rate limit, this is about everyone rather than the client, so it's worth a retry soon
+        Err(Rejected::Full) => {
+            const BODY: &str = "too many requests at once, try again shortly";
+            return Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(header::RETRY_AFTER, 1)
+                .header(header::CONTENT_TYPE, mime::TEXT_PLAIN.as_ref())
+                .header(header::CONTENT_LENGTH, BODY.len())
+                .body(full_body(BODY));
+        }
+        Err(Rejected::Closed) => {
+            return empty_with_code(StatusCode::SERVICE_UNAVAILABLE);
+        }
+    };
+
+    let base_path = state.base_path.clone();
+    let req = match base_path.as_deref() {
+        Some(base_path) => match strip_base_path(req, base_path) {
+            Some(req) => req,
+            None => return empty_with_code(StatusCode::NOT_FOUND),
+        },
+        None => req,
+    };
+
+    let mut rsp = dispatch(req, state, client_ip, permit).await?;
+    if let Some(base_path) = base_path.as_deref() {
+        prefix_location(&mut rsp, base_path);
+    }
+    Ok(rsp)
+}
+
+async fn dispatch(
+    req: Request<Incoming>,
+    state: State,
+    client_ip: IpAddr,
+    permit: OwnedSemaphorePermit,
+) -> Result<Response<ServeBody>, http::Error> {
+    //looked at after the base path's gone, so the internal routes are where they'd usually be
+    let routes = req
+        .extensions()
+        .get::<Routes>()
+        .copied()
+        .unwrap_or_default();
+    if !routes.serves(req.uri().path()) {
+        debug!(?routes, "Not served on this listener");
+        return empty_with_code(StatusCode::NOT_FOUND);
+    }
+
+    //an internal listener isn't exposed, so has no need for it
+    if let Some(tls) = &state.tls
+        && routes != Routes::Internal
+        && req.extensions().get::<OverTls>().is_none()
+        && let Some(rsp) = https_redirect(
+            tls,
+            request_host(&req).as_deref(),
+            state.base_path.as_deref(),
+            req.uri(),
+        )
+    {
+        return rsp;
+    }
+
+    if expectation_is_supported(req.headers()) == Some(false) {
+        debug!("Unsupported expectation");
+        return empty_with_code(StatusCode::EXPECTATION_FAILED);
+    }
+
+    //pages are checked again by where they end up, and only limited once they have
+    let is_page = !is_upgrade_request(&req) && matches!(*req.method(), Method::GET | Method::HEAD);
+    if let Some(path) = resolve_request_path(req.uri().path()) {
+        if let Some(rsp) = filter_ip(&req, &state, client_ip, &path).await {
+            return rsp;
+        }
+        if !is_page && let Some(rsp) = limit_rate(&req, &state, client_ip, &path).await {
+            return rsp;
+        }
+    }
+
+    if is_upgrade_request(&req) {
+        upgrade(req, state, client_ip, permit).await
+    } else {
+        match *req.method() {
+            Method::POST => serve_post(req, state, client_ip).await,
+            Method::GET | Method::HEAD => serve_get_head(req, state, client_ip).await,
+            Method::OPTIONS => serve_options(req, state).await,
+            Method::PUT | Method::DELETE | Method::PATCH | Method::TRACE | Method::CONNECT => {
+                method_not_allowed(req.uri().path(), &state)
+            }
+            //not something we know how to turn down properly
+            _ => empty_with_code(StatusCode::NOT_IMPLEMENTED),
+        }
+    }
+}
+
+///takes `base_path` off the front of the request's path, or `None` if it's somewhere else
+fn strip_base_path<B>(mut req: Request<B>, base_path: &str) -> Option<Request<B>> {
+    let rest = req.uri().path().strip_prefix(base_path)?;
+    let path = match rest {
+        "" => "/",
+        rest if rest.starts_with('/') => rest,
+        //eg. `/docsearch` for `/docs`
+        _ => return None,
+    };
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    *req.uri_mut() = Uri::from_parts(parts).ok()?;
+    Some(req)
+}
+
+///puts `base_path` back on redirects within the site, so the client doesn't leave it
+fn prefix_location(rsp: &mut Response<ServeBody>, base_path: &str) {
+    let Some(location) = rsp
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|x| x.to_str().ok())
+    else {
+        return;
+    };
+    //`//host/path` is another site entirely
+    if !location.starts_with('/') || location.starts_with("//") {
+        return;
+    }
+    if let Ok(prefixed) = HeaderValue::from_str(&format!("{base_path}{location}")) {
+        rsp.headers_mut().insert(header::LOCATION, prefixed);
+    }
+}
+
+///hands the connection over to the live-reloader, once it's passed the same auth as a page at
+///that path would. `permit` is held for as long as the socket's open
+async fn upgrade(
+    req: Request<Incoming>,
+    state: State,
+    client_ip: IpAddr,
+    permit: OwnedSemaphorePermit,
+) -> Result<Response<ServeBody>, http::Error> {
+    let Some(path) = resolve_request_path(req.uri().path()) else {
+        return empty_with_code(StatusCode::BAD_REQUEST);
+    };
+    let req = match state.check_auth(&path, req, client_ip).await {
+        AuthReturn::AuthConfirmed(req) => req,
+        AuthReturn::ResponseFromAuth(rsp) => {
+            debug!(?path, status=?rsp.status(), "Refusing websocket upgrade from auth");
+            return Ok(rsp);
+        }
+        AuthReturn::Error(e) => return Err(e),
+    };
+
+    //thx https://github.com/paritytech/soketto/blob/master/examples/hyper_server.rs
+    //only HTTP/1.1 connections can be handed over to soketto
+    if req.version() != Version::HTTP_11 {
+        debug!(version=?req.version(), "Rejecting websocket upgrade over non-HTTP/1.1");
+        return empty_with_code(StatusCode::BAD_REQUEST);
+    }
+    if state.drainer().is_draining() {
+        debug!("Rejecting websocket upgrade whilst draining");
+        return empty_with_code(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let livereload = state.live_reloader();
+    let Some(slot) = livereload.admit() else {
+        warn!(max=%livereload.max_clients(), "Too many live-reload clients, rejecting upgrade");
+        return empty_with_code(StatusCode::SERVICE_UNAVAILABLE);
+    };
+    let mut handshake_server = Server::new();
+
+    match handshake_server.receive_request(&req) {
+        Ok(rsp) => {
+            spawn_tagged("livereload", async move {
+                if let Err(e) = livereload
+                    .handle_livereload(req, handshake_server, slot)
+                    .await
+                {
+                    error!(?e, "Error with websockets");
                 }
-            } else {
-                let rsp = match *req.method() {
-                    Method::POST => serve_post(req, state).await,
-                    Method::GET | Method::HEAD => serve_get_head(req, state, remote_addr).await,
-                    _ => empty_with_code(StatusCode::METHOD_NOT_ALLOWED),
-                };
-                rsp
+                //ensure permit is moved into the new thread
+                drop(permit);
+            });
+            Ok(rsp.map(|()| empty_body()))
+        }
+        Err(e) => {
+            error!(?e, "Couldn't upgrade connection");
+            empty_with_code(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+///turns the request away if the site's IP filter doesn't let the client in, using the site's
+///`/403.html` if it has one
+async fn filter_ip(
+    req: &Request<Incoming>,
+    state: &State,
+    client_ip: IpAddr,
+    path: &str,
+) -> Option<Result<Response<ServeBody>, http::Error>> {
+    let host = request_host(req);
+    let site = state.site(host.as_deref()).await?;
+
+    if site.ip_allowed(path, client_ip).await {
+        return None;
+    }
+
+    warn!(?client_ip, ?path, "Denying request by IP");
+    Some(
+        match state
+            .get(&site, "/403.html", None, user_agent(req), None)
+            .await
+            .and_then(|x| x.into_error_page(StatusCode::FORBIDDEN))
+        {
+            Some(mut page) => {
+                page.select_encoding(req.headers()).await;
+                page.into_response(req.method(), vec![]).await
             }
-        })
+            None => empty_with_code(StatusCode::FORBIDDEN),
+        },
+    )
+}
+
+///turns the request away with a 429 if the client has been requesting a rate-limited path too often
+async fn limit_rate(
+    req: &Request<Incoming>,
+    state: &State,
+    client_ip: IpAddr,
+    path: &str,
+) -> Option<Result<Response<ServeBody>, http::Error>> {
+    let host = request_host(req);
+    let site = state.site(host.as_deref()).await?;
+
+    let retry_after = site.check_rate_limit(path, client_ip).await.err()?;
+    debug!(?client_ip, ?path, ?retry_after, "Rate limited request");
+    //round up so clients don't come back just too early
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    Some(empty_with_headers(
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.to_string().as_str())],
+    ))
+}
+
+///only pages that are actually there, with anything not found counted as the 404 page
+fn record_bandwidth(state: &State, site: &Site, path: &str, rsp: &Response<ServeBody>) {
+    let path = match rsp.status() {
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT => path,
+        StatusCode::NOT_FOUND => "/404.html",
+        _ => return,
+    };
+    let bytes = rsp
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok()?.parse().ok())
+        .unwrap_or_default();
+    state.record_bandwidth(site, path, bytes);
+}
+
+///the normalised host the request was for, if it says
+fn request_host(req: &Request<Incoming>) -> Option<String> {
+    req.uri()
+        .host()
+        .or_else(|| req.headers().get(header::HOST)?.to_str().ok())
+        .map(normalise_host)
+}
+
+fn user_agent(req: &Request<Incoming>) -> Option<&str> {
+    req.headers().get(header::USER_AGENT)?.to_str().ok()
+}
+
+fn add_cors_headers(rsp: &mut Response<ServeBody>, cors_headers: CorsHeaders) {
+    let headers = rsp.headers_mut();
+    for (name, value) in cors_headers {
+        //don't clobber anything else the response varies by
+        if name == header::VARY {
+            headers.append(name, value);
+        } else {
+            headers.insert(name, value);
+        }
     }
 }
 
+///what can be done at `path`, for `Allow`
+fn allowed_methods(path: &str, state: &State) -> &'static str {
+    let post = match path {
+        "/reload" | "/__shove/purge" => state.tigris_tokens.is_some(),
+        //404s without the admin token instead
+        "/__shove/drain" => true,
+        _ => false,
+    };
+    if post {
+        "GET, HEAD, OPTIONS, POST"
+    } else {
+        "GET, HEAD, OPTIONS"
+    }
+}
+
+fn method_not_allowed(path: &str, state: &State) -> Result<Response<ServeBody>, http::Error> {
+    empty_with_headers(
+        StatusCode::METHOD_NOT_ALLOWED,
+        [(header::ALLOW, allowed_methods(path, state))],
+    )
+}
+
+///CORS preflights never carry credentials, so this happens without auth. Anything else is just
+///told what it can do
+async fn serve_options(
+    req: Request<Incoming>,
+    state: State,
+) -> Result<Response<ServeBody>, http::Error> {
+    let header_str = |name| req.headers().get(name).and_then(|x| x.to_str().ok());
+    let (Some(origin), Some(requested_method)) = (
+        header_str(header::ORIGIN),
+        header_str(header::ACCESS_CONTROL_REQUEST_METHOD),
+    ) else {
+        return empty_with_headers(
+            StatusCode::NO_CONTENT,
+            [(header::ALLOW, allowed_methods(req.uri().path(), &state))],
+        );
+    };
+
+    let host = request_host(&req);
+    let Some(site) = state.site(host.as_deref()).await else {
+        debug!(?host, "No site for host");
+        return empty_with_code(StatusCode::NOT_FOUND);
+    };
+    let Some(path) = resolve_request_path(req.uri().path()) else {
+        return empty_with_code(StatusCode::BAD_REQUEST);
+    };
+
+    let rule = site.get_cors_rule(&path).await;
+    let (code, cors_headers) = match preflight_headers(
+        rule.as_ref(),
+        origin,
+        requested_method,
+        req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS),
+    ) {
+        Ok(cors_headers) => (StatusCode::NO_CONTENT, cors_headers),
+        Err((code, cors_headers)) => {
+            debug!(?path, ?origin, ?requested_method, "Rejecting preflight");
+            (code, cors_headers)
+        }
+    };
+
+    let mut rsp = empty_with_code(code)?;
+    add_cors_headers(&mut rsp, cors_headers);
+    Ok(rsp)
+}
+
+///returns the token from an `Authorization: Bearer <token>` header, or the status to respond with
+fn get_bearer_token(req: &Request<Incoming>) -> Result<&str, StatusCode> {
+    match req.headers().get("Authorization") {
+        Some(x) => match x.to_str() {
+            Ok(x) => match x.strip_prefix("Bearer ") {
+                Some(x) => Ok(x),
+                None => {
+                    warn!("Unable to find Bearer part");
+                    Err(StatusCode::BAD_REQUEST)
+                }
+            },
+            Err(e) => {
+                warn!(?e, "Error converting auth token to string");
+                Err(StatusCode::BAD_REQUEST)
+            }
+        },
+        None => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+///checks the request carries the `ADMIN_TOKEN`. The admin endpoints don't exist if it isn't set
+fn check_admin_token(req: &Request<Incoming>, state: &State) -> Result<(), StatusCode> {
+    let Some(actual_admin_token) = state.admin_token.as_deref() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let provided_auth_token = get_bearer_token(req)?;
+
+    if !bool::from(
+        actual_admin_token
+            .as_bytes()
+            .ct_eq(provided_auth_token.as_bytes()),
+    ) {
+        warn!("Tried to use admin endpoint with incorrect token");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(())
+}
+
 #[instrument(skip(state, req))]
 async fn serve_post(
     req: Request<Incoming>,
     state: State,
-) -> Result<Response<Full<Bytes>>, http::Error> {
-    let Some(actual_tigris_token) = state.tigris_token.clone() else {
-        return empty_with_code(StatusCode::METHOD_NOT_ALLOWED);
-    };
-    
-    match req.uri().path() {
-        "/reload" => {
-            let headers = req.headers();
-            let provided_auth_token = match headers.get("Authorization").cloned() {
-                Some(x) => match x.to_str() {
-                    Ok(x) => match x.strip_prefix("Bearer ") {
-                        Some(x) => Arc::<str>::from(x),
-                        None => {
-                            warn!("Unable to find Bearer part");
-                            return empty_with_code(StatusCode::BAD_REQUEST);
-                        }
-                    },
-                    Err(e) => {
-                        warn!(?e, "Error converting auth token to string");
-                        return empty_with_code(StatusCode::BAD_REQUEST);
-                    }
-                },
-                None => return empty_with_code(StatusCode::BAD_REQUEST),
-            };
+    client_ip: IpAddr,
+) -> Result<Response<ServeBody>, http::Error> {
+    let path = req.uri().path();
+    //the same 405 & `Allow` as any other method a path doesn't take
+    if !allowed_methods(path, &state).ends_with("POST") {
+        return method_not_allowed(path, &state);
+    }
 
-            if actual_tigris_token != provided_auth_token {
-                warn!("Tried to reload with incorrect token");
-                return empty_with_code(StatusCode::FORBIDDEN);
-            }
+    match path {
+        "/reload" => serve_reload(req, state, client_ip).await,
+        "/__shove/drain" => serve_drain(req, state).await,
+        "/__shove/purge" => serve_purge(req, state, client_ip).await,
+        _ => method_not_allowed(path, &state),
+    }
+}
 
-            info!("Reloading from webhook");
-            if let Err(e) = state.check_and_reload().await {
-                error!(?e, "Error reloading state");
-                empty_with_code(StatusCode::INTERNAL_SERVER_ERROR)
-            } else {
-                empty_with_code(StatusCode::OK)
+///checks the request carries one of the reload tokens. Reloading (and purging) doesn't exist if
+///there aren't any
+fn check_reload_token(
+    req: &Request<Incoming>,
+    state: &State,
+    client_ip: IpAddr,
+) -> Result<(), StatusCode> {
+    let Some(tigris_tokens) = state.tigris_tokens.as_ref() else {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    };
+
+    //before checking the token, so a leaked one can't be used to hammer the bucket either
+    if state.reload_rate_limiter.check_key(&client_ip).is_err() {
+        warn!(?client_ip, "Rate limited reload");
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let provided_auth_token = get_bearer_token(req)?;
+
+    let Some(token_index) = tigris_tokens.matching(provided_auth_token) else {
+        warn!("Tried to reload with incorrect token");
+        return Err(StatusCode::FORBIDDEN);
+    };
+    info!(%token_index, "Reload token matched");
+    Ok(())
+}
+
+///`Err(412)` if there's an `If-Match` that doesn't name the upload being served for `host`, so
+///deploy tooling can make sure it isn't racing another deploy. Without one, anything goes
+async fn check_if_match(
+    headers: &HeaderMap,
+    host: Option<&str>,
+    state: &State,
+) -> Result<(), StatusCode> {
+    let mut if_matches = headers.get_all(header::IF_MATCH).iter().peekable();
+    if if_matches.peek().is_none() {
+        return Ok(());
+    }
+
+    let deployed = match state.site(host).await {
+        Some(site) => Some(site.deployed_version().await),
+        None => None,
+    };
+    let matched = deployed
+        .as_ref()
+        .is_some_and(|deployed| if_matches.any(|x| x.to_str().is_ok_and(|x| deployed.matches(x))));
+    if matched {
+        Ok(())
+    } else {
+        info!(
+            ?host,
+            deployed = deployed.map(|x| x.upload_data_sha256),
+            "If-Match doesn't match the deployed upload"
+        );
+        Err(StatusCode::PRECONDITION_FAILED)
+    }
+}
+
+///the body of a 202 from `/reload`
+#[derive(Serialize)]
+struct ReloadPending {
+    already_reloading: bool,
+}
+
+///turns down a request with `code`. Without all of its body having been read, the connection gets
+///closed, as otherwise the next request on it would be read from part way through the body. HTTP/2
+///gives each request its own stream, so doesn't have that problem
+fn refuse_body(
+    code: StatusCode,
+    version: Version,
+    read_all: bool,
+) -> Result<Response<ServeBody>, http::Error> {
+    let mut rsp = empty_with_code(code)?;
+    if !read_all && version < Version::HTTP_2 {
+        rsp.headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    Ok(rsp)
+}
+
+///[`refuse_body`] before the body's been touched, reading it anyway if it's small enough so the
+///connection can be kept
+async fn refuse_unread(
+    req: Request<Incoming>,
+    code: StatusCode,
+    max_body_bytes: usize,
+) -> Result<Response<ServeBody>, http::Error> {
+    let (parts, body) = req.into_parts();
+    match discard_body(body, &parts.headers, max_body_bytes).await {
+        Some(refusal) => Ok(refuse_body(code, parts.version, true)?.map(|_| refusal)),
+        None => refuse_body(code, parts.version, false),
+    }
+}
+
+async fn serve_reload(
+    req: Request<Incoming>,
+    state: State,
+    client_ip: IpAddr,
+) -> Result<Response<ServeBody>, http::Error> {
+    if let Err(code) = check_reload_token(&req, &state, client_ip) {
+        return refuse_unread(req, code, state.limits.max_body_bytes).await;
+    }
+
+    //only read once we know who's asking, so `Expect: 100-continue` clients can be turned away early
+    let host = request_host(&req);
+    let (parts, body) = req.into_parts();
+    let body = match read_capped_body(body, &parts.headers, state.limits.max_body_bytes).await {
+        Ok(body) => body,
+        Err(code) => return refuse_body(code, parts.version, false),
+    };
+    if let Err(code) = check_if_match(&parts.headers, host.as_deref(), &state).await {
+        return empty_with_code(code);
+    }
+
+    //in the background, so shutting down waits for it even if the webhook's connection goes
+    let reload_state = state.clone();
+    let reloading = state.background().spawn("webhook reload", async move {
+        match changed_keys(&body) {
+            Some(keys) => {
+                info!(?keys, "Reloading from webhook");
+                reload_state.reload_keys(&keys).await
+            }
+            None => {
+                info!("Reloading everything from webhook");
+                reload_state.check_and_reload().await
             }
         }
-        _ => empty_with_code(StatusCode::NOT_FOUND),
+    });
+    let Some(reloading) = reloading else {
+        info!("Not reloading from webhook, as we're shutting down");
+        return empty_with_code(StatusCode::SERVICE_UNAVAILABLE);
+    };
+    let res = reloading.await.unwrap_or_else(|e| Err(e.into()));
+    match res {
+        Ok(report) => {
+            info!(?report, "Reloaded from webhook");
+            json_with_code(StatusCode::OK, &report)
+        }
+        //whatever changed, the reload that's already going will pick up
+        Err(e) if e.is::<AlreadyReloading>() => {
+            info!("Already reloading, so leaving it to that");
+            json_with_code(
+                StatusCode::ACCEPTED,
+                &ReloadPending {
+                    already_reloading: true,
+                },
+            )
+        }
+        Err(e) => {
+            error!(?e, "Error reloading state");
+            let mut rsp = empty_with_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+            rsp.extensions_mut().insert(ResponseError(format!("{e:?}")));
+            Ok(rsp)
+        }
     }
 }
 
+#[derive(Deserialize)]
+struct PurgeRequest {
+    paths: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct PurgeResult {
+    path: String,
+    result: Purged,
+}
+
+///drops individual paths from the cache, so they get fetched from the bucket on the next request
+async fn serve_purge(
+    req: Request<Incoming>,
+    state: State,
+    client_ip: IpAddr,
+) -> Result<Response<ServeBody>, http::Error> {
+    if let Err(code) = check_reload_token(&req, &state, client_ip) {
+        return refuse_unread(req, code, state.limits.max_body_bytes).await;
+    }
+
+    let host = request_host(&req);
+    let Some(site) = state.site(host.as_deref()).await else {
+        debug!(?host, "No site for host");
+        return refuse_unread(req, StatusCode::NOT_FOUND, state.limits.max_body_bytes).await;
+    };
+
+    let (parts, body) = req.into_parts();
+    let body = match read_capped_body(body, &parts.headers, state.limits.max_body_bytes).await {
+        Ok(body) => body,
+        Err(code) => return refuse_body(code, parts.version, false),
+    };
+    if let Err(code) = check_if_match(&parts.headers, host.as_deref(), &state).await {
+        return empty_with_code(code);
+    }
+    let Ok(PurgeRequest { paths }) = serde_json::from_slice(&body) else {
+        return empty_with_code(StatusCode::BAD_REQUEST);
+    };
+
+    let mut results = Vec::with_capacity(paths.len());
+    for path in paths {
+        let result = match resolve_request_path(&path) {
+            Some(resolved) => site.purge(&resolved).await,
+            None => Purged::NotCached,
+        };
+        results.push(PurgeResult { path, result });
+    }
+    info!(?results, "Purged paths from cache");
+
+    json_with_code(StatusCode::OK, &results)
+}
+
+async fn serve_drain(
+    req: Request<Incoming>,
+    state: State,
+) -> Result<Response<ServeBody>, http::Error> {
+    if let Err(code) = check_admin_token(&req, &state) {
+        return empty_with_code(code);
+    }
+
+    let drainer = state.drainer();
+    if drainer.start_drain() {
+        info!("Draining from admin endpoint");
+        if let Some(after) = state.drain_exit_after {
+            info!(?after, "Scheduling exit after drain");
+            drainer.schedule_exit(after);
+        }
+    } else {
+        info!("Already draining");
+    }
+
+    json_with_code(StatusCode::OK, &Status::new(&state).await)
+}
+
+#[derive(Serialize)]
+struct Status {
+    draining: bool,
+    cache_bytes: u64,
+    warmth: Warmth,
+    admission: Admission,
+    livereload_clients: usize,
+    concurrency: ConcurrencyReport,
+}
+
+impl Status {
+    async fn new(state: &State) -> Self {
+        Self {
+            draining: state.drainer().is_draining(),
+            cache_bytes: state.cache_weighted_size().await,
+            warmth: state.warmth().await,
+            admission: state.admission().await,
+            livereload_clients: state.live_reloader().clients(),
+            concurrency: state.concurrency.report(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VersionReport {
+    #[serde(flatten)]
+    deployed: DeployedVersion,
+    server_version: &'static str,
+}
+
+impl VersionReport {
+    async fn new(site: &Site) -> Self {
+        Self {
+            deployed: site.deployed_version().await,
+            server_version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+///what `/` gets before anything's been uploaded. Never cached, so it's gone as soon as a site is
+fn nothing_deployed_page() -> Result<Response<ServeBody>, http::Error> {
+    const BODY: &str = "<!DOCTYPE html>\n<html><head><title>Nothing here yet</title></head><body><h1>No site deployed yet</h1><p>This server is up, and will start serving the site as soon as one is uploaded.</p></body></html>\n";
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(header::CONTENT_LENGTH, BODY.len())
+        .body(full_body(BODY))
+}
+
 #[instrument(skip(req, state))]
 async fn serve_get_head(
     req: Request<Incoming>,
     state: State,
-    remote_addr: SocketAddr,
-) -> Result<Response<Full<Bytes>>, http::Error> {
+    client_ip: IpAddr,
+) -> Result<Response<ServeBody>, http::Error> {
     let path = req.uri().path();
-    if path == "/healthcheck" {
-        return empty_with_code(StatusCode::OK);
+    match path {
+        "/healthcheck" => {
+            if state.drainer().is_draining() {
+                return empty_with_code(StatusCode::SERVICE_UNAVAILABLE);
+            }
+            let report = state.health().await;
+            return json_with_code(report.status_code(), &report);
+        }
+        "/readycheck" => {
+            return if state.drainer().is_draining() || !state.is_ready().await {
+                empty_with_code(StatusCode::SERVICE_UNAVAILABLE)
+            } else {
+                empty_with_code(StatusCode::OK)
+            };
+        }
+        "/__shove/status" => {
+            //says more about the server than anyone outside needs to know, like `/__shove/journal`
+            if let Err(code) = check_admin_token(&req, &state) {
+                return empty_with_code(code);
+            }
+            return json_with_code(StatusCode::OK, &Status::new(&state).await);
+        }
+        "/__shove/version" => {
+            if state.protect_version
+                && let Err(code) = check_admin_token(&req, &state)
+            {
+                return empty_with_code(code);
+            }
+            let Some(site) = state.site(request_host(&req).as_deref()).await else {
+                return empty_with_code(StatusCode::NOT_FOUND);
+            };
+            //so a deploy can read it, then send it back as `If-Match` to `/reload` or a purge
+            let report = VersionReport::new(&site).await;
+            let mut rsp = json_with_code(StatusCode::OK, &report)?;
+            if let Ok(etag) = HeaderValue::from_str(&report.deployed.etag()) {
+                rsp.headers_mut().insert(header::ETAG, etag);
+            }
+            return Ok(rsp);
+        }
+        "/__shove/journal" => {
+            if let Err(code) = check_admin_token(&req, &state) {
+                return empty_with_code(code);
+            }
+            return match state.journal() {
+                Some(journal) => json_with_code(StatusCode::OK, &journal.snapshot().await),
+                None => empty_with_code(StatusCode::NOT_FOUND),
+            };
+        }
+        _ => {}
     }
 
-    let cleaned = Path::new(path).clean();
-    let mut path = match cleaned.to_str() {
-        Some(st) => st.to_owned(),
-        None => {
-            warn!(?cleaned, "Couldn't convert path to string");
-            return empty_with_code(StatusCode::BAD_REQUEST);
+    //before redirects too, so a rule can't be reached by a path that'd be refused anywhere else
+    if let Err(code) = normalise_request_path(path) {
+        return empty_with_code(code);
+    }
+
+    let host = request_host(&req);
+    let Some(site) = state.site(host.as_deref()).await else {
+        if path == "/" && state.nothing_deployed().await {
+            return nothing_deployed_page();
         }
+        debug!(?host, "No site for host");
+        return empty_with_code(StatusCode::NOT_FOUND);
     };
-    
-    debug!(?path, "maybe serving");    
-    
-    if cleaned.extension().is_none_or(|x| x.is_empty()) {
-        //ensure that we don't miss zero-index fun
-        #[allow(clippy::if_same_then_else)]
-        if path.chars().last().is_none_or(|ch| ch != '/') {
-            path.push('/');
+
+    let path = match site.redirect(path, req.uri().query()).await {
+        Some(Redirect::Moved { location, status }) => {
+            debug!(?path, ?location, "Redirecting");
+            return empty_with_headers(status, [(header::LOCATION, location.as_str())]);
+        }
+        Some(Redirect::Rewrite(to)) => {
+            debug!(?path, ?to, "Rewriting");
+            to
         }
-        path.push_str("index.html");
+        None => path.to_string(),
+    };
+
+    if state.trailing_slash == TrailingSlash::Redirect
+        && let Some(canonical) = site.canonical_path(&path).await
+    {
+        let location = match req.uri().query() {
+            Some(query) => format!("{canonical}?{query}"),
+            None => canonical,
+        };
+        debug!(?path, ?location, "Redirecting to canonical path");
+        return empty_with_headers(
+            StatusCode::PERMANENT_REDIRECT,
+            [(header::LOCATION, location.as_str())],
+        );
     }
-    
+
+    let Some(requested) = resolve_request_path(&path) else {
+        return empty_with_code(StatusCode::BAD_REQUEST);
+    };
+    let asked_for_directory = normalise_request_path(&path).is_ok_and(|x| x != requested);
+    //only for directories, so asking for `/blog/index.html` by name never gets anything else
+    let requested = if asked_for_directory && let Some(index) = site.index_for(&requested).await {
+        index
+    } else if state.render_markdown
+        && asked_for_directory
+        && let Some(markdown) = site.markdown_for(&requested).await
+    {
+        markdown
+    } else {
+        requested
+    };
+
+    //auth goes by how it was uploaded, not how it was asked for
+    let path = match state.case_insensitive_paths {
+        CaseInsensitivePaths::Off => requested,
+        mode => match site.actual_casing(&requested).await {
+            Some(actual) if mode == CaseInsensitivePaths::Redirect => {
+                //back to how it was asked for, so `/Blog/` goes to `/blog/` not `/blog/index.html`
+                let actual = match actual.strip_suffix("/index.html") {
+                    Some(directory) if asked_for_directory => format!("{directory}/"),
+                    _ => actual,
+                };
+                let location = match req.uri().query() {
+                    Some(query) => format!("{}?{query}", encode_request_path(&actual)),
+                    None => encode_request_path(&actual),
+                };
+                debug!(?path, ?location, "Redirecting to uploaded casing");
+                return empty_with_headers(
+                    StatusCode::MOVED_PERMANENTLY,
+                    [(header::LOCATION, location.as_str())],
+                );
+            }
+            Some(actual) => {
+                trace!(?requested, ?actual, "Serving with uploaded casing");
+                actual
+            }
+            None => requested,
+        },
+    };
+
     debug!(?path, "yeppers serving");
-    
-    let req = match state.check_auth(&path, req, remote_addr).await {
+
+    //by the same path as auth, so a rewrite or another casing can't get round them
+    if let Some(rsp) = filter_ip(&req, &state, client_ip, &path).await {
+        return rsp;
+    }
+    if let Some(rsp) = limit_rate(&req, &state, client_ip, &path).await {
+        return rsp;
+    }
+
+    let req = match state.check_auth(&path, req, client_ip).await {
         AuthReturn::AuthConfirmed(req) => req,
         AuthReturn::ResponseFromAuth(rsp) => return Ok(rsp),
         AuthReturn::Error(e) => return Err(e),
@@ -173,10 +1034,1159 @@ async fn serve_get_head(
 
     trace!(?path, "Serving");
 
-    match state.get(&path).await {
-        Some(page_output) => page_output.into_response(req.method()),
-        None => {
-            empty_with_code(StatusCode::NOT_FOUND)
+    let variant = if state.render_markdown && is_markdown(&path) {
+        //the Markdown as it was uploaded, for anything that wants to render it itself
+        (query_param(req.uri().query(), "raw") != Some("1")).then_some(Variant::Rendered)
+    } else {
+        state
+            .resizing
+            .and_then(|x| x.requested(req.uri().query()))
+            .map(Variant::Resized)
+    };
+    let mut rsp = match state
+        .get(&site, &path, host.as_deref(), user_agent(&req), variant)
+        .await
+    {
+        Some(mut page_output) => {
+            page_output.select_encoding(req.headers()).await;
+            if state.default_charset {
+                page_output.add_default_charset();
+            }
+            if state.livereload_inject {
+                page_output.inject_livereload(state.base_path.as_deref().unwrap_or_default());
+            }
+            page_output.select_not_modified(req.headers());
+            if req.method() == Method::GET {
+                page_output.select_range(req.headers());
+            }
+            page_output
+                .into_response(req.method(), site.get_headers(&path).await)
+                .await?
+        }
+        None => empty_with_code(StatusCode::NOT_FOUND)?,
+    };
+    if req.method() == Method::GET {
+        record_bandwidth(&state, &site, &path, &rsp);
+    }
+    if let Some(realm) = req.extensions().get::<MatchedRealm>() {
+        rsp.extensions_mut().insert(realm.clone());
+    }
+
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|x| x.to_str().ok());
+    let cors_headers = response_headers(site.get_cors_rule(&path).await.as_ref(), origin);
+    add_cors_headers(&mut rsp, cors_headers);
+
+    Ok(rsp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::EnvReader,
+        ip_filter::manager::IP_FILTER_LOCATION,
+        non_empty_list::NonEmptyList,
+        protect::{
+            auth::AUTH_DATA_LOCATION,
+            auth_storer::{AuthStorer, PasswordPolicy},
+        },
+        s3::UPLOAD_DATA_LOCATION,
+        serve::config::{Config, Storage},
+        store::{
+            memory::{Fault, InMemoryBucket},
+            ObjectStore,
+        },
+        Realm, UploadData,
+    };
+    use hyper::server::conn::http1;
+    use hyper_util::rt::TokioIo;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    ///serves a site with `/admin` protected, returning where
+    async fn serve_protected() -> SocketAddr {
+        serve_protected_with(&[]).await
+    }
+
+    ///`vars` on top of the bucket's
+    async fn serve_protected_with(vars: &[(&str, &str)]) -> SocketAddr {
+        serve_protected_in(vars, InMemoryBucket::new()).await.0
+    }
+
+    ///for a bucket, with `vars` on top
+    fn config_with(vars: &[(&str, &str)]) -> Config {
+        let vars: HashMap<String, String> = [
+            ("AWS_ACCESS_KEY_ID", "key"),
+            ("AWS_SECRET_ACCESS_KEY", "secret"),
+            ("BUCKET_NAME", "bucket"),
+            ("AWS_ENDPOINT_URL_S3", "http://127.0.0.1:1"),
+            ("AUTH_ENCRYPTION_KEY", "hunter2"),
+        ]
+        .iter()
+        .chain(vars)
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let mut env = EnvReader::new(move |name| vars.get(name).cloned());
+        let config = Config::read(&mut env, None);
+        env.finish(config).unwrap()
+    }
+
+    ///whatever's in `bucket`, along with the state to look into
+    async fn serve_bucket(config: Config, bucket: InMemoryBucket) -> (SocketAddr, State) {
+        let state = State::with_store(config, Arc::new(bucket), None)
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = state.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, remote_addr) = listener.accept().await.unwrap();
+                let svc = ServeService::new(serving.clone(), remote_addr, Routes::All, false);
+                tokio::task::spawn(
+                    http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), svc)
+                        .with_upgrades(),
+                );
+            }
+        });
+        (addr, state)
+    }
+
+    ///from `bucket`, which can be kept hold of to break, along with the state to look into
+    async fn serve_protected_in(
+        vars: &[(&str, &str)],
+        bucket: InMemoryBucket,
+    ) -> (SocketAddr, State) {
+        let config = config_with(vars);
+        let Storage::Bucket { auth, .. } = &config.storage else {
+            unreachable!("no local directory was given");
+        };
+
+        let upload_data = UploadData {
+            entries: [("public/index.html".to_string(), "hash".to_string())].into(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        bucket
+            .put(
+                UPLOAD_DATA_LOCATION,
+                &serde_json::to_vec(&upload_data).unwrap(),
+                "application/json",
+            )
+            .await
+            .unwrap();
+        bucket
+            .put("public/index.html", b"<h1>hi</h1>", "text/html")
+            .await
+            .unwrap();
+        let mut auth_storer = AuthStorer::default();
+        let admin = auth_storer
+            .add_user(
+                "admin".to_string(),
+                "correct horse",
+                &PasswordPolicy::default(),
+            )
+            .unwrap();
+        auth_storer.protect(
+            Realm::StartsWith("/admin".to_string()),
+            NonEmptyList::new(vec![admin]).unwrap(),
+        );
+        auth_storer.save(&bucket, &auth.key).await.unwrap();
+        assert!(bucket.bytes(AUTH_DATA_LOCATION).await.is_some());
+
+        serve_bucket(config, bucket).await
+    }
+
+    ///the response head to a WebSocket upgrade at `path`
+    async fn upgrade_head(addr: SocketAddr, path: &str) -> String {
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        );
+        response_head(addr, &request).await
+    }
+
+    ///lowercased, so headers can be looked for without worrying about case
+    async fn response_head(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut head = vec![];
+        let mut buf = [0; 1024];
+        while !head.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0, "connection closed before the response head");
+            head.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(head).unwrap().to_ascii_lowercase()
+    }
+
+    ///the status, headers (without `Date`, which can tick over between requests) & body of the
+    ///response to `method path`, asked on a connection of its own
+    async fn exchange(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> (u16, Vec<(String, String)>, Vec<u8>) {
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}\r\n"))
+            .collect();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{headers}\r\n"
+        );
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut rsp = vec![];
+        stream.read_to_end(&mut rsp).await.unwrap();
+
+        let split = rsp.windows(4).position(|x| x == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(rsp[..split].to_vec()).unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap()[9..12].parse().unwrap();
+        let mut headers: Vec<_> = lines
+            .map(|x| x.split_once(':').unwrap())
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+            .filter(|(name, _)| name != "date")
+            .collect();
+        headers.sort();
+        (status, headers, rsp[split + 4..].to_vec())
+    }
+
+    ///a path, the headers to ask for it with, & the status it should get
+    type Case<'a> = (&'a str, Vec<(&'a str, &'a str)>, u16);
+
+    ///every response has an accurate `Content-Length` (and none where there can't be a body),
+    ///a `Content-Type` for any body, and the same headers for a `HEAD` as for a `GET`
+    async fn check_responses(addr: SocketAddr, cases: &[Case<'_>]) {
+        for (path, headers, expected) in cases {
+            let (status, get_headers, body) = exchange(addr, "GET", path, headers).await;
+            assert_eq!(status, *expected, "{path} {headers:?}");
+            let header = |name: &str| {
+                get_headers
+                    .iter()
+                    .find(|(x, _)| x == name)
+                    .map(|(_, value)| value.as_str())
+            };
+            if status == 204 || status == 304 {
+                assert_eq!(header("content-length"), None, "{path} {headers:?}");
+                assert!(body.is_empty(), "{path} {headers:?}");
+            } else {
+                assert_eq!(
+                    header("content-length"),
+                    Some(body.len().to_string().as_str()),
+                    "{path} {headers:?}"
+                );
+            }
+            if !body.is_empty() {
+                assert!(header("content-type").is_some(), "{path} {headers:?}");
+            }
+
+            //a range only means anything to a GET, so a HEAD gets the whole page's headers
+            if headers.iter().any(|(name, _)| *name == "Range") {
+                continue;
+            }
+            let (head_status, head_headers, head_body) =
+                exchange(addr, "HEAD", path, headers).await;
+            assert_eq!(head_status, status, "{path} {headers:?}");
+            assert_eq!(head_headers, get_headers, "{path} {headers:?}");
+            assert!(head_body.is_empty(), "{path} {headers:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_responses_describe_their_bodies() {
+        let addr = serve_protected_with(&[("TRAILING_SLASH", "redirect")]).await;
+        let (_, headers, _) = exchange(addr, "GET", "/", &[]).await;
+        let etag = headers
+            .iter()
+            .find(|(name, _)| name == "etag")
+            .map(|(_, value)| value.clone())
+            .unwrap();
+
+        check_responses(
+            addr,
+            &[
+                ("/", vec![], 200),
+                ("/index.html", vec![], 200),
+                ("/index.html", vec![("Range", "bytes=0-3")], 206),
+                ("/index.html", vec![("Range", "bytes=100-")], 416),
+                ("/index.html", vec![("If-None-Match", etag.as_str())], 304),
+                ("/index.html", vec![("If-None-Match", "\"other\"")], 200),
+                ("/index.html/", vec![], 308),
+                ("/missing.html", vec![], 404),
+                ("/admin/", vec![], 401),
+                ("/missing.json", vec![], 404),
+                ("/missing", vec![("Accept", "application/json")], 404),
+                ("/admin/", vec![("Accept", "application/json")], 401),
+                ("/%00", vec![], 400),
+                ("/readycheck", vec![], 200),
+            ],
+        )
+        .await;
+
+        let bucket = InMemoryBucket::new();
+        bucket.fail("public/index.html", Fault::Hang).await;
+        let (addr, _) = serve_protected_in(&[("S3_TIMEOUT_SECS", "1")], bucket).await;
+        check_responses(addr, &[("/", vec![], 504)]).await;
+    }
+
+    #[tokio::test]
+    async fn test_json_clients_get_json_errors() {
+        let addr = serve_protected().await;
+
+        let (status, headers, body) = exchange(addr, "GET", "/data/missing.json", &[]).await;
+        assert_eq!(status, 404);
+        assert!(headers.contains(&("content-type".to_string(), "application/json".to_string())));
+        assert!(headers.contains(&("vary".to_string(), "accept".to_string())));
+        assert_eq!(
+            body,
+            br#"{"error":"not_found","path":"/data/missing.json"}"#
+        );
+
+        let json = [("Accept", "application/json")];
+        let (status, headers, body) = exchange(addr, "GET", "/admin/", &json).await;
+        assert_eq!(status, 401);
+        assert!(headers.iter().any(|(name, _)| name == "www-authenticate"));
+        assert_eq!(body, br#"{"error":"unauthorized","path":"/admin/"}"#);
+
+        //browsers still get the page
+        let html = [("Accept", "text/html,*/*;q=0.8")];
+        let (status, headers, _) = exchange(addr, "GET", "/missing", &html).await;
+        assert_eq!(status, 404);
+        assert!(!headers.contains(&("content-type".to_string(), "application/json".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_protected_upgrades_need_auth() {
+        let addr = serve_protected().await;
+
+        let head = upgrade_head(addr, "/admin/live").await;
+        assert!(head.starts_with("http/1.1 401"), "{head}");
+        assert!(
+            head.contains("www-authenticate: basic realm=\"/admin\", charset=\"utf-8\""),
+            "{head}"
+        );
+
+        let head = upgrade_head(addr, "/").await;
+        assert!(head.starts_with("http/1.1 101"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_odd_paths_still_need_auth() {
+        let addr = serve_protected().await;
+        let get = |path: &str| format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        for path in [
+            "//admin/",
+            "/admin//index.html",
+            "/./admin/",
+            "/a/../admin/",
+            "/a/../../admin/",
+            "/%61dmin/",
+        ] {
+            let head = response_head(addr, &get(path)).await;
+            assert!(head.starts_with("http/1.1 401"), "{path}: {head}");
+        }
+        for path in [
+            "/admin%5Cindex.html",
+            "/%5Cadmin/",
+            "/admin%2F",
+            "/admin%00/",
+        ] {
+            let head = response_head(addr, &get(path)).await;
+            assert!(head.starts_with("http/1.1 400"), "{path}: {head}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_other_casings_redirect_or_serve() {
+        let get = |path: &str| format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        let addr = serve_protected().await;
+        let head = response_head(addr, &get("/INDEX.html")).await;
+        assert!(head.starts_with("http/1.1 404"), "{head}");
+
+        let addr = serve_protected_with(&[("CASE_INSENSITIVE_PATHS", "true")]).await;
+        let head = response_head(addr, &get("/INDEX.html?a=b")).await;
+        assert!(head.starts_with("http/1.1 301"), "{head}");
+        assert!(head.contains("location: /index.html?a=b\r\n"), "{head}");
+
+        let addr = serve_protected_with(&[("CASE_INSENSITIVE_PATHS", "serve")]).await;
+        let head =
+            response_head(addr, "HEAD /INDEX.html HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(head.starts_with("http/1.1 200"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_ip_rules_apply_to_other_casings() {
+        let bucket = InMemoryBucket::new();
+        let rules = r#"{"default":{"allow":[],"deny":[]},"rules":[[{"StartsWith":"/index"},{"allow":[],"deny":["127.0.0.0/8"]}]]}"#;
+        bucket
+            .put(IP_FILTER_LOCATION, rules.as_bytes(), "application/json")
+            .await
+            .unwrap();
+        let (addr, _) = serve_protected_in(&[("CASE_INSENSITIVE_PATHS", "serve")], bucket).await;
+
+        for path in ["/index.html", "/INDEX.html", "/Index.HTML"] {
+            let (status, _, _) = exchange(addr, "GET", path, &[]).await;
+            assert_eq!(status, 403, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_directories_fall_back_to_other_index_files() {
+        async fn upload(bucket: &InMemoryBucket, files: &[&str]) {
+            let upload_data = UploadData {
+                entries: files
+                    .iter()
+                    .map(|x| (format!("public{x}"), "hash".to_string()))
+                    .collect(),
+                root: "public".to_string(),
+                cache_control: HashMap::new(),
+                sizes: HashMap::new(),
+            };
+            bucket
+                .put(
+                    UPLOAD_DATA_LOCATION,
+                    &serde_json::to_vec(&upload_data).unwrap(),
+                    "application/json",
+                )
+                .await
+                .unwrap();
+            for file in files {
+                bucket
+                    .put(&format!("public{file}"), file.as_bytes(), "text/html")
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let bucket = InMemoryBucket::new();
+        upload(
+            &bucket,
+            &[
+                "/index.html",
+                "/docs/index.htm",
+                "/docs/default.html",
+                "/old/default.html",
+                "/both/index.html",
+                "/both/index.htm",
+                "/none/page.html",
+            ],
+        )
+        .await;
+        let config = config_with(&[
+            ("INDEX_FILES", "index.html, index.htm, default.html"),
+            ("TIGRIS_TOKEN", "token"),
+        ]);
+        let (addr, _) = serve_bucket(config, bucket.clone()).await;
+        let get = |path: &'static str| async move {
+            let (status, headers, body) = exchange(addr, "GET", path, &[]).await;
+            let location = headers
+                .into_iter()
+                .find(|(name, _)| name == "location")
+                .map(|(_, value)| value);
+            (status, location, String::from_utf8(body).unwrap())
+        };
+
+        assert_eq!(get("/").await, (200, None, "/index.html".to_string()));
+        assert_eq!(
+            get("/docs/").await,
+            (200, None, "/docs/index.htm".to_string())
+        );
+        assert_eq!(
+            get("/old/").await,
+            (200, None, "/old/default.html".to_string())
+        );
+        assert_eq!(
+            get("/both/").await,
+            (200, None, "/both/index.html".to_string())
+        );
+        assert_eq!(get("/none/").await.0, 404);
+        //only directories get a fallback, not their `index.html` asked for by name
+        assert_eq!(get("/docs/index.html").await.0, 404);
+        assert_eq!(
+            get("/docs/default.html").await,
+            (200, None, "/docs/default.html".to_string())
+        );
+        //missing the trailing slash still counts as a directory, so gets one
+        assert_eq!(get("/old").await.1.as_deref(), Some("/old/"));
+        assert_eq!(get("/old?a=b").await.1.as_deref(), Some("/old/?a=b"));
+        assert_eq!(get("/none").await.0, 404);
+
+        upload(&bucket, &["/index.html", "/docs/default.html"]).await;
+        let (status, _, _) = exchange(
+            addr,
+            "POST",
+            "/reload",
+            &[("Authorization", "Bearer token"), ("Content-Length", "0")],
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            get("/docs/").await,
+            (200, None, "/docs/default.html".to_string())
+        );
+        assert_eq!(get("/old/").await.0, 404);
+    }
+
+    #[tokio::test]
+    async fn test_markdown_is_rendered_unless_asked_for_raw() {
+        async fn upload(bucket: &InMemoryBucket, version: &str, files: &[(&str, &str, &str)]) {
+            let upload_data = UploadData {
+                entries: files
+                    .iter()
+                    .map(|(path, _, _)| (format!("public{path}"), version.to_string()))
+                    .collect(),
+                root: "public".to_string(),
+                cache_control: HashMap::new(),
+                sizes: HashMap::new(),
+            };
+            bucket
+                .put(
+                    UPLOAD_DATA_LOCATION,
+                    &serde_json::to_vec(&upload_data).unwrap(),
+                    "application/json",
+                )
+                .await
+                .unwrap();
+            for (path, contents, content_type) in files {
+                bucket
+                    .put(&format!("public{path}"), contents.as_bytes(), content_type)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let bucket = InMemoryBucket::new();
+        let guide = ("/guide/index.html", "<h1>guide</h1>", "text/html");
+        let guide_markdown = ("/guide.md", "# Not this one", "text/markdown");
+        upload(
+            &bucket,
+            "1",
+            &[
+                ("/intro.md", "# Intro\n\nhello", "text/markdown"),
+                guide,
+                guide_markdown,
+            ],
+        )
+        .await;
+        let config = config_with(&[
+            ("RENDER_MARKDOWN", "true"),
+            ("MARKDOWN_STYLESHEET", "/docs.css"),
+            ("TIGRIS_TOKEN", "token"),
+        ]);
+        let (addr, _) = serve_bucket(config, bucket.clone()).await;
+        let get = |path: &'static str| async move {
+            let (status, headers, body) = exchange(addr, "GET", path, &[]).await;
+            let content_type = headers
+                .into_iter()
+                .find(|(name, _)| name == "content-type")
+                .map(|(_, value)| value)
+                .unwrap_or_default();
+            (status, content_type, String::from_utf8(body).unwrap())
+        };
+
+        for path in ["/intro.md", "/intro", "/intro/"] {
+            let (status, content_type, body) = get(path).await;
+            assert_eq!(status, 200, "{path}");
+            assert_eq!(content_type, "text/html; charset=utf-8", "{path}");
+            assert!(body.contains("<title>Intro</title>"), "{path}: {body}");
+            assert!(body.contains("<p>hello</p>"), "{path}: {body}");
+            assert!(body.contains("href=\"/docs.css\""), "{path}: {body}");
         }
+        let (status, content_type, body) = get("/intro.md?raw=1").await;
+        assert_eq!(status, 200);
+        assert!(content_type.starts_with("text/markdown"), "{content_type}");
+        assert_eq!(body, "# Intro\n\nhello");
+        //a directory's own index comes first, and keeps its trailing slash
+        assert_eq!(get("/guide").await.0, 308);
+        assert_eq!(get("/guide/").await.2, "<h1>guide</h1>");
+
+        //what was rendered goes when what it was rendered from changes
+        upload(
+            &bucket,
+            "2",
+            &[
+                ("/intro.md", "# Welcome\n\nhello again", "text/markdown"),
+                guide,
+                guide_markdown,
+            ],
+        )
+        .await;
+        let (status, _, _) = exchange(
+            addr,
+            "POST",
+            "/reload",
+            &[("Authorization", "Bearer token"), ("Content-Length", "0")],
+        )
+        .await;
+        assert_eq!(status, 200);
+        for tries in 0.. {
+            if get("/intro").await.2.contains("<title>Welcome</title>") {
+                break;
+            }
+            assert!(tries < 100, "never re-rendered");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(get("/intro.md?raw=1").await.2, "# Welcome\n\nhello again");
+    }
+
+    #[tokio::test]
+    async fn test_hung_store_is_a_gateway_timeout() {
+        //from the start, so it never makes it into the cache
+        let bucket = InMemoryBucket::new();
+        bucket.fail("public/index.html", Fault::Hang).await;
+        let (addr, _) = serve_protected_in(&[("S3_TIMEOUT_SECS", "1")], bucket).await;
+
+        let head = response_head(addr, "HEAD / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(head.starts_with("http/1.1 504"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_disconnecting_gives_the_permit_back() {
+        let bucket = InMemoryBucket::new();
+        bucket.fail("public/index.html", Fault::Hang).await;
+        let (addr, state) = serve_protected_in(&[("MAX_CONCURRENT_REQUESTS", "16")], bucket).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let permits = |expected| {
+            let concurrency = state.concurrency.clone();
+            async move {
+                for _ in 0..100 {
+                    if concurrency.report().available == expected {
+                        return true;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                false
+            }
+        };
+        assert!(permits(15).await, "the request never took a permit");
+
+        drop(stream);
+        assert!(
+            permits(16).await,
+            "the permit was kept after the client went"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_too_many_at_once_is_told_to_retry() {
+        let bucket = InMemoryBucket::new();
+        bucket.fail("public/index.html", Fault::Hang).await;
+        let (addr, state) = serve_protected_in(
+            &[("MAX_CONCURRENT_REQUESTS", "1"), ("REQUEST_QUEUE_MS", "0")],
+            bucket,
+        )
+        .await;
+
+        let mut hung = TcpStream::connect(addr).await.unwrap();
+        hung.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        while state.concurrency.report().available != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let head = response_head(addr, "HEAD / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(head.starts_with("http/1.1 429"), "{head}");
+        assert!(head.contains("retry-after: 1\r\n"), "{head}");
+        assert_eq!(state.concurrency.report().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_methods_are_advertised() {
+        let addr = serve_protected_with(&[("TIGRIS_TOKEN", "token")]).await;
+        let request = |method: &str, path: &str| {
+            format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n")
+        };
+
+        let head = response_head(addr, &request("OPTIONS", "/index.html")).await;
+        assert!(head.starts_with("http/1.1 204"), "{head}");
+        assert!(head.contains("allow: get, head, options\r\n"), "{head}");
+
+        let head = response_head(addr, &request("OPTIONS", "/reload")).await;
+        assert!(head.starts_with("http/1.1 204"), "{head}");
+        assert!(
+            head.contains("allow: get, head, options, post\r\n"),
+            "{head}"
+        );
+
+        let head = response_head(addr, &request("TRACE", "/index.html")).await;
+        assert!(head.starts_with("http/1.1 405"), "{head}");
+        assert!(head.contains("allow: get, head, options\r\n"), "{head}");
+
+        let head = response_head(addr, &request("POST", "/index.html")).await;
+        assert!(head.starts_with("http/1.1 405"), "{head}");
+        assert!(head.contains("allow: get, head, options\r\n"), "{head}");
+
+        let head = response_head(addr, &request("BREW", "/index.html")).await;
+        assert!(head.starts_with("http/1.1 501"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_goes_to_https_with_tls_on() {
+        let addr = serve_protected_with(&[
+            ("TLS_DOMAIN", "example.com"),
+            ("ACME_EMAIL", "admin@example.com"),
+        ])
+        .await;
+        let request = |path: &str| {
+            format!("GET {path} HTTP/1.1\r\nHost: example.com:80\r\nContent-Length: 0\r\n\r\n")
+        };
+
+        //before auth, so credentials never go over plain HTTP
+        //certificates are got with TLS-ALPN-01, so nothing needs answering over plain HTTP
+        for (path, location) in [
+            ("/index.html?v=2", "https://example.com/index.html?v=2"),
+            ("/admin", "https://example.com/admin"),
+            (
+                "/.well-known/acme-challenge/token",
+                "https://example.com/.well-known/acme-challenge/token",
+            ),
+        ] {
+            let head = response_head(addr, &request(path)).await;
+            assert!(head.starts_with("http/1.1 301"), "{head}");
+            assert!(
+                head.contains(&format!("location: {location}\r\n")),
+                "{head}"
+            );
+        }
+
+        assert_eq!(exchange(addr, "GET", "/healthcheck", &[]).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn test_draining_fails_health_but_keeps_serving() {
+        let addr = serve_protected_with(&[("ADMIN_TOKEN", "admin")]).await;
+        let admin = [("Authorization", "Bearer admin")];
+        let drain = || async {
+            let request = "POST /__shove/drain HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer admin\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut rsp = vec![];
+            stream.read_to_end(&mut rsp).await.unwrap();
+            String::from_utf8(rsp).unwrap()
+        };
+
+        assert_eq!(exchange(addr, "GET", "/healthcheck", &[]).await.0, 200);
+        assert_eq!(exchange(addr, "GET", "/readycheck", &[]).await.0, 200);
+        let (_, _, status) = exchange(addr, "GET", "/__shove/status", &admin).await;
+        assert!(status.starts_with(br#"{"draining":false,"#));
+        assert!(upgrade_head(addr, "/").await.starts_with("http/1.1 101"));
+
+        //and again, which changes nothing
+        for _ in 0..2 {
+            let rsp = drain().await;
+            assert!(rsp.starts_with("HTTP/1.1 200"), "{rsp}");
+            assert!(rsp.contains(r#"{"draining":true,"#), "{rsp}");
+        }
+
+        assert_eq!(exchange(addr, "GET", "/healthcheck", &[]).await.0, 503);
+        assert_eq!(exchange(addr, "GET", "/readycheck", &[]).await.0, 503);
+        let (status, _, body) = exchange(addr, "GET", "/index.html", &[]).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, b"<h1>hi</h1>");
+        let (_, _, status) = exchange(addr, "GET", "/__shove/status", &admin).await;
+        assert!(status.starts_with(br#"{"draining":true,"#));
+
+        let head = upgrade_head(addr, "/").await;
+        assert!(head.starts_with("http/1.1 503"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_status_needs_the_admin_token() {
+        let addr = serve_protected().await;
+        assert_eq!(exchange(addr, "GET", "/__shove/status", &[]).await.0, 404);
+
+        let addr = serve_protected_with(&[("ADMIN_TOKEN", "admin")]).await;
+        let status = |token: &'static str| async move {
+            let auth = format!("Bearer {token}");
+            exchange(addr, "GET", "/__shove/status", &[("Authorization", &auth)])
+                .await
+                .0
+        };
+        assert_eq!(exchange(addr, "GET", "/__shove/status", &[]).await.0, 400);
+        assert_eq!(status("wrong").await, 403);
+        assert_eq!(status("admin").await, 200);
+    }
+
+    #[tokio::test]
+    async fn test_reload_without_tokens_is_not_allowed() {
+        let addr = serve_protected().await;
+        let head = response_head(
+            addr,
+            "POST /reload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 405"), "{head}");
+        assert!(head.contains("allow: get, head, options\r\n"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_reload_reports_what_changed() {
+        let bucket = InMemoryBucket::new();
+        let (addr, _) = serve_protected_in(&[("TIGRIS_TOKEN", "token")], bucket.clone()).await;
+        let reload = || async {
+            let (status, _, body) = exchange(
+                addr,
+                "POST",
+                "/reload",
+                &[("Authorization", "Bearer token"), ("Content-Length", "0")],
+            )
+            .await;
+            assert_eq!(status, 200);
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let report = reload().await;
+        assert_eq!(report["manifest_changed"], false, "{report}");
+        assert_eq!(report["updated"], 0, "{report}");
+
+        let upload_data = UploadData {
+            entries: [
+                ("public/index.html".to_string(), "hash".to_string()),
+                ("public/new.html".to_string(), "new".to_string()),
+            ]
+            .into(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        bucket
+            .put(
+                UPLOAD_DATA_LOCATION,
+                &serde_json::to_vec(&upload_data).unwrap(),
+                "application/json",
+            )
+            .await
+            .unwrap();
+
+        let report = reload().await;
+        assert_eq!(report["manifest_changed"], true, "{report}");
+        assert_eq!(report["updated"], 1, "{report}");
+        assert!(report["elapsed_ms"].is_u64(), "{report}");
+    }
+
+    #[tokio::test]
+    async fn test_turned_down_reload_bodies_leave_the_connection_usable() {
+        let (addr, _) =
+            serve_protected_in(&[("TIGRIS_TOKEN", "token")], InMemoryBucket::new()).await;
+        let pipelined = |token: &str, body_len: usize| {
+            let mut request = format!(
+                "POST /reload HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\nContent-Length: {body_len}\r\n\r\n"
+            )
+            .into_bytes();
+            request.extend(vec![b'a'; body_len]);
+            request.extend(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(&request).await.unwrap();
+                let mut rsp = vec![];
+                stream.read_to_end(&mut rsp).await.unwrap();
+                String::from_utf8_lossy(&rsp).into_owned()
+            }
+        };
+
+        //the whole body gets read, so the GET after it is still understood
+        let rsp = pipelined("wrong", 60 * 1024).await;
+        assert!(rsp.starts_with("HTTP/1.1 403"), "{rsp}");
+        assert!(rsp.contains("HTTP/1.1 200"), "{rsp}");
+        assert!(rsp.ends_with("<h1>hi</h1>"), "{rsp}");
+
+        let rsp = pipelined("token", 60 * 1024).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{rsp}");
+        assert!(rsp.ends_with("<h1>hi</h1>"), "{rsp}");
+
+        //too big to be worth reading, so the connection's closed rather than guessed at. None of the
+        //body's sent, as closing with it unread would reset the connection before the 413 is seen
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "POST /reload HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer token\r\nContent-Length: {}\r\n\r\n",
+                    65 * 1024
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut rsp = vec![];
+        stream.read_to_end(&mut rsp).await.unwrap();
+        let rsp = String::from_utf8(rsp).unwrap();
+        assert!(rsp.starts_with("HTTP/1.1 413"), "{rsp}");
+        assert!(rsp.contains("connection: close\r\n"), "{rsp}");
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_reloads_are_turned_down_before_the_body() {
+        let (addr, _) =
+            serve_protected_in(&[("TIGRIS_TOKEN", "token")], InMemoryBucket::new()).await;
+        ///one byte at a time, so nothing after the head gets read
+        async fn read_head(stream: &mut TcpStream) -> String {
+            let mut head = vec![];
+            let mut byte = [0];
+            while !head.ends_with(b"\r\n\r\n") {
+                let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut byte))
+                    .await
+                    .expect("waited on the body")
+                    .unwrap();
+                assert_ne!(n, 0, "connection closed before the response head");
+                head.push(byte[0]);
+            }
+            String::from_utf8(head).unwrap().to_ascii_lowercase()
+        }
+        let send = async |token: &str, body_len: usize| {
+            let request = format!(
+                "POST /reload HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\nExpect: 100-continue\r\nContent-Length: {body_len}\r\n\r\n"
+            );
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let head = read_head(&mut stream).await;
+            (stream, head)
+        };
+
+        //refused without the body, which can still be sent after to keep using the connection
+        let (mut stream, head) = send("wrong", 1024).await;
+        assert!(head.starts_with("http/1.1 403"), "{head}");
+        assert!(!head.contains("100 continue"), "{head}");
+        assert!(!head.contains("connection: close"), "{head}");
+        stream.write_all(&[b'a'; 1024]).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut rsp = vec![];
+        stream.read_to_end(&mut rsp).await.unwrap();
+        let rsp = String::from_utf8(rsp).unwrap();
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{rsp}");
+        assert!(rsp.ends_with("<h1>hi</h1>"), "{rsp}");
+
+        //too big to ever read, so the connection can't be kept
+        let (mut stream, head) = send("token", 65 * 1024).await;
+        assert!(head.starts_with("http/1.1 413"), "{head}");
+        assert!(head.contains("connection: close"), "{head}");
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        //and with the right token and a body that fits, it's asked for
+        let (mut stream, head) = send("token", 5).await;
+        assert!(head.starts_with("http/1.1 100"), "{head}");
+        stream.write_all(b"hello").await.unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("http/1.1 20"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn test_if_match_on_reload_and_purge() {
+        let (addr, _) =
+            serve_protected_in(&[("TIGRIS_TOKEN", "token")], InMemoryBucket::new()).await;
+        let (status, headers, body) = exchange(addr, "GET", "/__shove/version", &[]).await;
+        assert_eq!(status, 200);
+        let etag = headers
+            .into_iter()
+            .find(|(name, _)| name == "etag")
+            .map(|(_, value)| value)
+            .unwrap();
+        let version: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            etag,
+            format!("\"{}\"", version["upload_data_sha256"].as_str().unwrap())
+        );
+
+        let reload = |if_match: Option<&str>| {
+            let if_match = if_match.map(str::to_string);
+            async move {
+                let mut headers = vec![("Authorization", "Bearer token"), ("Content-Length", "0")];
+                headers.extend(if_match.as_deref().map(|x| ("If-Match", x)));
+                exchange(addr, "POST", "/reload", &headers).await.0
+            }
+        };
+        assert_eq!(reload(Some("\"someone-else\"")).await, 412);
+        assert_eq!(reload(Some(&format!("W/{etag}"))).await, 412);
+        assert_eq!(reload(Some(&format!("\"x\", {etag}"))).await, 200);
+        assert_eq!(reload(Some("*")).await, 200);
+        assert_eq!(reload(None).await, 200);
+
+        let purge = |if_match: Option<&str>| {
+            let body = r#"{"paths":["/"]}"#;
+            let if_match = if_match
+                .map(|x| format!("If-Match: {x}\r\n"))
+                .unwrap_or_default();
+            let request = format!(
+                "POST /__shove/purge HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nAuthorization: Bearer token\r\n{if_match}Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut rsp = vec![];
+                stream.read_to_end(&mut rsp).await.unwrap();
+                String::from_utf8(rsp).unwrap()
+            }
+        };
+        let rsp = purge(Some("\"someone-else\"")).await;
+        assert!(rsp.starts_with("HTTP/1.1 412"), "{rsp}");
+        let rsp = purge(Some(&etag)).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{rsp}");
+        assert!(
+            rsp.ends_with(r#"[{"path":"/","result":"purged"}]"#),
+            "{rsp}"
+        );
+        let rsp = purge(None).await;
+        assert!(rsp.starts_with("HTTP/1.1 200"), "{rsp}");
+    }
+
+    #[tokio::test]
+    async fn test_shutting_down_finishes_webhook_reloads() {
+        let bucket = InMemoryBucket::new();
+        let (addr, state) = serve_protected_in(&[("TIGRIS_TOKEN", "token")], bucket.clone()).await;
+        let reload = || async {
+            exchange(
+                addr,
+                "POST",
+                "/reload",
+                &[("Authorization", "Bearer token"), ("Content-Length", "0")],
+            )
+            .await
+            .0
+        };
+
+        let upload_data = UploadData {
+            entries: [("public/index.html".to_string(), "changed".to_string())].into(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        bucket
+            .put(
+                UPLOAD_DATA_LOCATION,
+                &serde_json::to_vec(&upload_data).unwrap(),
+                "application/json",
+            )
+            .await
+            .unwrap();
+        bucket
+            .put("public/index.html", b"<h1>new</h1>", "text/html")
+            .await
+            .unwrap();
+        bucket
+            .fail("public/index.html", Fault::Slow(Duration::from_millis(300)))
+            .await;
+
+        //the new bytes are still being fetched once it's answered
+        assert_eq!(reload().await, 200);
+        assert!(state.background().finish(Duration::from_secs(5)).await);
+
+        //so they must be in the cache
+        bucket.fail("public/index.html", Fault::Hang).await;
+        let (status, _, body) = exchange(addr, "GET", "/", &[]).await;
+        assert_eq!((status, body.as_slice()), (200, &b"<h1>new</h1>"[..]));
+        assert_eq!(reload().await, 503);
+    }
+
+    #[tokio::test]
+    async fn test_empty_bucket_serves_a_placeholder_until_the_first_upload() {
+        let bucket = InMemoryBucket::new();
+        let config = config_with(&[("TIGRIS_TOKEN", "token")]);
+        let (addr, _) = serve_bucket(config, bucket.clone()).await;
+
+        check_responses(
+            addr,
+            &[
+                ("/", vec![], 200),
+                ("/index.html", vec![], 404),
+                ("/healthcheck", vec![], 200),
+                ("/readycheck", vec![], 200),
+            ],
+        )
+        .await;
+        let (_, headers, body) = exchange(addr, "GET", "/", &[]).await;
+        assert!(String::from_utf8(body)
+            .unwrap()
+            .contains("No site deployed yet"));
+        assert!(headers.contains(&("cache-control".to_string(), "no-store".to_string())));
+
+        let upload_data = UploadData {
+            entries: [("public/index.html".to_string(), "hash".to_string())].into(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        };
+        bucket
+            .put(
+                UPLOAD_DATA_LOCATION,
+                &serde_json::to_vec(&upload_data).unwrap(),
+                "application/json",
+            )
+            .await
+            .unwrap();
+        bucket
+            .put("public/index.html", b"<h1>hi</h1>", "text/html")
+            .await
+            .unwrap();
+        let (status, _, _) = exchange(
+            addr,
+            "POST",
+            "/reload",
+            &[("Authorization", "Bearer token"), ("Content-Length", "0")],
+        )
+        .await;
+        assert_eq!(status, 200);
+
+        for path in ["/", "/index.html"] {
+            let (status, _, body) = exchange(addr, "GET", path, &[]).await;
+            assert_eq!(
+                (status, body.as_slice()),
+                (200, &b"<h1>hi</h1>"[..]),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_base_path_is_stripped_and_put_back() {
+        let stripped = |uri: &str| {
+            let req = Request::builder().uri(uri).body(()).unwrap();
+            strip_base_path(req, "/docs").map(|x| x.uri().to_string())
+        };
+        assert_eq!(stripped("/docs/a/b.html").as_deref(), Some("/a/b.html"));
+        assert_eq!(stripped("/docs?q=1").as_deref(), Some("/?q=1"));
+        assert_eq!(
+            stripped("http://example.com/docs/x?y").as_deref(),
+            Some("http://example.com/x?y")
+        );
+        assert_eq!(stripped("/docsearch"), None);
+        assert_eq!(stripped("/other/docs/"), None);
+
+        let location = |location: &str| {
+            let mut rsp = Response::builder()
+                .header(header::LOCATION, location)
+                .body(empty_body())
+                .unwrap();
+            prefix_location(&mut rsp, "/docs");
+            rsp.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(location("/a/"), "/docs/a/");
+        assert_eq!(location("https://example.com/a"), "https://example.com/a");
+        assert_eq!(location("//example.com/a"), "//example.com/a");
     }
 }
@@ -0,0 +1,162 @@
+use std::fmt::Write;
+
+pub const SITEMAP_PATH: &str = "/sitemap.xml";
+pub const ROBOTS_PATH: &str = "/robots.txt";
+
+///the files that can be made up from the upload data if a site doesn't have its own
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Generated {
+    Sitemap,
+    Robots,
+}
+
+impl Generated {
+    pub fn for_path(path: &str) -> Option<Self> {
+        match path {
+            SITEMAP_PATH => Some(Self::Sitemap),
+            ROBOTS_PATH => Some(Self::Robots),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Sitemap => "application/xml",
+            Self::Robots => "text/plain",
+        }
+    }
+}
+
+///where URLs in the sitemap start, from `CANONICAL_HOST` if it's set or otherwise the request's host.
+///hosts without a scheme are assumed to be HTTPS
+pub fn base_url(canonical_host: Option<&str>, request_host: Option<&str>) -> Option<String> {
+    let host = canonical_host.or(request_host)?.trim_end_matches('/');
+    if host.contains("://") {
+        Some(host.to_string())
+    } else {
+        Some(format!("https://{host}"))
+    }
+}
+
+///the URL an HTML file is served at, with `index.html` being its directory. `None` for anything
+///else, and for error pages like `/404.html`
+fn page_url(path: &str) -> Option<&str> {
+    let stem = path.strip_suffix(".html")?;
+    let is_error_page = stem
+        .strip_prefix('/')
+        .is_some_and(|x| x.len() == 3 && x.bytes().all(|b| b.is_ascii_digit()));
+    if is_error_page {
+        return None;
+    }
+    Some(path.strip_suffix("index.html").unwrap_or(path))
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+///every HTML page out of `paths`, which shouldn't include anything protected
+pub fn sitemap(base_url: &str, paths: impl IntoIterator<Item = String>) -> String {
+    let mut urls: Vec<String> = paths
+        .into_iter()
+        .filter_map(|path| page_url(&path).map(ToString::to_string))
+        .collect();
+    urls.sort_unstable();
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for url in urls {
+        let _ = writeln!(
+            xml,
+            "  <url><loc>{}</loc></url>",
+            escape_xml(&format!("{base_url}{url}"))
+        );
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+///keeps crawlers out of the `disallowed` prefixes, and points them at the sitemap if there is one
+pub fn robots(disallowed: &[String], sitemap_url: Option<&str>) -> String {
+    let mut txt = String::from("User-agent: *\n");
+    if disallowed.is_empty() {
+        txt.push_str("Disallow:\n");
+    }
+    for prefix in disallowed {
+        let _ = writeln!(txt, "Disallow: {prefix}");
+    }
+    if let Some(sitemap_url) = sitemap_url {
+        let _ = writeln!(txt, "\nSitemap: {sitemap_url}");
+    }
+    txt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sitemap_only_has_pages() {
+        let paths = [
+            "/index.html",
+            "/blog/index.html",
+            "/blog/a&b.html",
+            "/style.css",
+            "/404.html",
+        ]
+        .map(ToString::to_string);
+        let xml = sitemap("https://example.com", paths);
+
+        assert_eq!(
+            xml,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">
+  <url><loc>https://example.com/</loc></url>
+  <url><loc>https://example.com/blog/</loc></url>
+  <url><loc>https://example.com/blog/a&amp;b.html</loc></url>
+</urlset>
+"
+        );
+    }
+
+    #[test]
+    fn test_robots() {
+        assert_eq!(robots(&[], None), "User-agent: *\nDisallow:\n");
+        assert_eq!(
+            robots(
+                &["/admin".to_string(), "/drafts/".to_string()],
+                Some("https://example.com/sitemap.xml")
+            ),
+            "User-agent: *\nDisallow: /admin\nDisallow: /drafts/\n\nSitemap: https://example.com/sitemap.xml\n"
+        );
+    }
+
+    #[test]
+    fn test_base_url() {
+        assert_eq!(
+            base_url(Some("example.com"), Some("other.com")).as_deref(),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            base_url(Some("http://localhost:8080/"), None).as_deref(),
+            Some("http://localhost:8080")
+        );
+        assert_eq!(
+            base_url(None, Some("other.com")).as_deref(),
+            Some("https://other.com")
+        );
+        assert_eq!(base_url(None, None), None);
+    }
+}
@@ -0,0 +1,911 @@
+use crate::{
+    cache_control::manager::{CacheControlManager, CC_LOCATION},
+    cors::manager::{CorsManager, CorsRule, CORS_LOCATION},
+    crawlers::manager::{CrawlerManager, CRAWLERS_LOCATION},
+    entry_key, hash_raw_bytes,
+    headers::manager::{ExtraHeader, HeaderManager, HEADERS_LOCATION},
+    ip_filter::manager::{IpFilterManager, IP_FILTER_LOCATION},
+    rate_limit::manager::{RateLimitManager, RATE_LIMIT_LOCATION},
+    s3::{get_bytes_or_default, StaleConfig, SITES_LOCATION},
+    serve::{
+        background::Background,
+        livereload::LiveReloader,
+        markdown::RenderMarkdown,
+        pages::{
+            Admission, AlreadyReloading, CaseInsensitivePaths, DeployedVersion, IndexFiles,
+            PageOutput, Pages, Purged, SiteReload, Variant, Warmth,
+        },
+        redirects::{Redirect, Redirects, REDIRECTS_PATH},
+        stats::HitStats,
+        webhook::ChangedKey,
+    },
+    store::Store,
+    SitesManifest,
+};
+use serde_json::from_slice;
+use std::{collections::HashMap, future::Future, net::IpAddr, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, RwLock};
+
+///what every site gets served with
+#[derive(Clone, Debug)]
+pub struct SiteSettings {
+    ///roughly how many bytes of files to keep in memory
+    pub cache_max_bytes: u64,
+    ///anything bigger gets streamed rather than cached
+    pub max_cacheable_bytes: Option<u64>,
+    pub smart_cache_defaults: bool,
+    pub case_insensitive_paths: CaseInsensitivePaths,
+    pub index_files: IndexFiles,
+    ///anything bigger is still read in to be served, but not kept
+    pub cache_admit_max_bytes: Option<u64>,
+    pub render_markdown: Option<RenderMarkdown>,
+}
+
+///everything needed to serve one site
+#[derive(Clone)]
+pub struct Site {
+    pages: Pages,
+    redirects: Redirects,
+    cache_control_manager: CacheControlManager,
+    header_manager: HeaderManager,
+    cors_manager: CorsManager,
+    ip_filter_manager: IpFilterManager,
+    rate_limit_manager: RateLimitManager,
+    crawler_manager: CrawlerManager,
+}
+
+impl Site {
+    ///`None` if nothing has been uploaded for this site
+    pub async fn new(
+        bucket: &Store,
+        site: Option<&str>,
+        settings: &SiteSettings,
+        stats: HitStats,
+        background: Background,
+    ) -> color_eyre::Result<Option<Self>> {
+        let Some(pages) = Pages::new(bucket, site, settings, stats, background).await? else {
+            return Ok(None);
+        };
+        let redirects = Redirects::new(bucket, &pages.root().await).await?;
+        let cache_control_manager =
+            CacheControlManager::new(bucket, site, settings.smart_cache_defaults).await?;
+        let header_manager = HeaderManager::new(bucket, site).await?;
+        let cors_manager = CorsManager::new(bucket, site).await?;
+        let ip_filter_manager = IpFilterManager::new(bucket, site).await?;
+        let rate_limit_manager = RateLimitManager::new(bucket, site).await?;
+        let crawler_manager = CrawlerManager::new(bucket, site).await?;
+
+        Ok(Some(Self {
+            pages,
+            redirects,
+            cache_control_manager,
+            header_manager,
+            cors_manager,
+            ip_filter_manager,
+            rate_limit_manager,
+            crawler_manager,
+        }))
+    }
+
+    ///`None` if the upload data couldn't be read, which gets logged. Anything else that can't be read
+    ///is only logged
+    pub async fn check_and_reload(
+        &self,
+        bucket: &Store,
+        reloader: LiveReloader,
+    ) -> Result<Option<SiteReload>, AlreadyReloading> {
+        trace!("Checking for pages reload");
+        let report = match self.pages.check_and_reload(bucket, reloader).await {
+            Ok(report) => Some(report),
+            Err(e) if e.is::<AlreadyReloading>() => return Err(AlreadyReloading),
+            Err(e) => {
+                error!(?e, "Error reloading pages");
+                None
+            }
+        };
+        trace!("Checking for redirects reload");
+        if let Err(e) = self
+            .redirects
+            .check_and_reload(bucket, &self.pages.root().await)
+            .await
+        {
+            error!(?e, "Error reloading redirects");
+        }
+        trace!("Checking for Cache Control reload");
+        if let Err(e) = self.cache_control_manager.check_and_reload(bucket).await {
+            error!(?e, "Error reloading cache control manager");
+        }
+        trace!("Checking for headers reload");
+        if let Err(e) = self.header_manager.check_and_reload(bucket).await {
+            error!(?e, "Error reloading header manager");
+        }
+        trace!("Checking for CORS reload");
+        if let Err(e) = self.cors_manager.check_and_reload(bucket).await {
+            error!(?e, "Error reloading CORS manager");
+        }
+        trace!("Checking for IP filter reload");
+        if let Err(e) = self.ip_filter_manager.check_and_reload(bucket).await {
+            error!(?e, "Error reloading IP filter manager");
+        }
+        trace!("Checking for rate limit reload");
+        if let Err(e) = self.rate_limit_manager.check_and_reload(bucket).await {
+            error!(?e, "Error reloading rate limit manager");
+        }
+        trace!("Checking for crawlers reload");
+        if let Err(e) = self.crawler_manager.check_and_reload(bucket).await {
+            error!(?e, "Error reloading crawler manager");
+        }
+        Ok(report)
+    }
+
+    ///reloads the manager stored in `file`, `false` if there isn't one
+    async fn reload_config(&self, bucket: &Store, file: &str) -> color_eyre::Result<bool> {
+        match file {
+            CC_LOCATION => self.cache_control_manager.check_and_reload(bucket).await?,
+            HEADERS_LOCATION => self.header_manager.check_and_reload(bucket).await?,
+            CORS_LOCATION => self.cors_manager.check_and_reload(bucket).await?,
+            IP_FILTER_LOCATION => self.ip_filter_manager.check_and_reload(bucket).await?,
+            RATE_LIMIT_LOCATION => self.rate_limit_manager.check_and_reload(bucket).await?,
+            CRAWLERS_LOCATION => self.crawler_manager.check_and_reload(bucket).await?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    ///reloads whatever `key` is in this site, `false` if it isn't part of this site
+    async fn reload_content(
+        &self,
+        bucket: &Store,
+        key: &str,
+        reloader: LiveReloader,
+    ) -> color_eyre::Result<bool> {
+        let root = self.pages.root().await;
+        if key == entry_key(&root, REDIRECTS_PATH) {
+            self.redirects.check_and_reload(bucket, &root).await?;
+            return Ok(true);
+        }
+        self.pages.refresh_key(bucket, key, reloader).await
+    }
+
+    ///crawlers get served like anyone else, but what they ask for doesn't go in the cache
+    pub async fn get(
+        &self,
+        bucket: &Store,
+        path: &str,
+        user_agent: Option<&str>,
+        variant: Option<Variant>,
+    ) -> Option<PageOutput> {
+        let admit = !self.crawler_manager.is_crawler(user_agent).await;
+        self.pages
+            .get(bucket, path, &self.cache_control_manager, admit, variant)
+            .await
+    }
+
+    pub async fn contains(&self, path: &str) -> bool {
+        self.pages.contains(path).await
+    }
+
+    pub async fn paths(&self) -> Vec<String> {
+        self.pages.paths().await
+    }
+
+    pub async fn get_generated<F: Future<Output = Vec<u8>>>(
+        &self,
+        path: &str,
+        content_type: &str,
+        generate: impl FnOnce() -> F,
+    ) -> PageOutput {
+        self.pages
+            .get_generated(path, content_type, &self.cache_control_manager, generate)
+            .await
+    }
+
+    pub async fn get_headers(&self, path: &str) -> Vec<ExtraHeader> {
+        self.header_manager.get_headers(path).await
+    }
+
+    pub async fn get_cors_rule(&self, path: &str) -> Option<CorsRule> {
+        self.cors_manager.get_rule(path).await
+    }
+
+    pub async fn ip_allowed(&self, path: &str, ip: IpAddr) -> bool {
+        self.ip_filter_manager.allows(path, ip).await
+    }
+
+    ///`Err` with how long until `ip` can try again, if it's been requesting `path` too often
+    pub async fn check_rate_limit(&self, path: &str, ip: IpAddr) -> Result<(), Duration> {
+        self.rate_limit_manager.check(path, ip).await
+    }
+
+    pub async fn redirect(&self, path: &str, query: Option<&str>) -> Option<Redirect> {
+        self.redirects.resolve(path, query).await
+    }
+
+    pub async fn canonical_path(&self, path: &str) -> Option<String> {
+        self.pages.canonical_path(path).await
+    }
+
+    pub async fn actual_casing(&self, path: &str) -> Option<String> {
+        self.pages.actual_casing(path).await
+    }
+
+    pub async fn index_for(&self, path: &str) -> Option<String> {
+        self.pages.index_for(path).await
+    }
+
+    pub async fn markdown_for(&self, path: &str) -> Option<String> {
+        self.pages.markdown_for(path).await
+    }
+
+    pub fn cache_weighted_size(&self) -> u64 {
+        self.pages.cache_weighted_size()
+    }
+
+    pub fn warmth(&self) -> Warmth {
+        self.pages.warmth()
+    }
+
+    pub fn admission(&self) -> Admission {
+        self.pages.admission()
+    }
+
+    pub async fn purge(&self, path: &str) -> Purged {
+        self.pages.purge(path).await
+    }
+
+    pub fn stats_key(&self, path: &str) -> String {
+        self.pages.stats_key(path)
+    }
+
+    pub async fn deployed_version(&self) -> DeployedVersion {
+        self.pages.deployed_version().await
+    }
+
+    ///config in the bucket that couldn't be read, so an older version's still being used
+    pub fn stale_config(&self) -> Vec<StaleConfig> {
+        self.cache_control_manager.stale().into_iter().collect()
+    }
+}
+
+#[derive(Clone, Default)]
+struct SiteMap {
+    ///the site uploaded without `--site`, if there is one
+    top_level: Option<Site>,
+    by_host: HashMap<String, Site>,
+    default_host: Option<String>,
+}
+
+impl SiteMap {
+    fn get(&self, host: Option<&str>) -> Option<Site> {
+        host.and_then(|host| self.by_host.get(host))
+            .or_else(|| {
+                self.default_host
+                    .as_ref()
+                    .and_then(|host| self.by_host.get(host))
+            })
+            .or(self.top_level.as_ref())
+            .cloned()
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Site> {
+        self.top_level.iter().chain(self.by_host.values())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.top_level.is_none() && self.by_host.is_empty()
+    }
+}
+
+///every site in the bucket, picked between by the request's `Host`
+#[derive(Clone)]
+pub struct Sites {
+    ///can change with [`Self::resize_caches`]
+    settings: Arc<Mutex<SiteSettings>>,
+    stats: HitStats,
+    background: Background,
+    last_manifest_hash: Arc<Mutex<Vec<u8>>>,
+    sites: Arc<RwLock<SiteMap>>,
+}
+
+impl Sites {
+    ///still works if nothing has been uploaded yet, so [`Self::check_and_reload`] can pick up the
+    ///first upload
+    pub async fn new(
+        bucket: &Store,
+        settings: SiteSettings,
+        stats: HitStats,
+        background: Background,
+    ) -> color_eyre::Result<Self> {
+        let raw_manifest = get_bytes_or_default(bucket, SITES_LOCATION).await?;
+        let sites = Self {
+            settings: Arc::new(Mutex::new(settings)),
+            stats,
+            background,
+            last_manifest_hash: Arc::new(Mutex::new(hash_raw_bytes(&raw_manifest))),
+            sites: Arc::new(RwLock::new(SiteMap::default())),
+        };
+
+        let map = sites
+            .build_map(bucket, &raw_manifest, &SiteMap::default())
+            .await?;
+        if map.is_empty() {
+            warn!("Nothing has been uploaded yet, serving a placeholder until there is");
+        } else {
+            info!(hosts=?map.by_host.keys().collect::<Vec<_>>(), top_level=%map.top_level.is_some(), "Got sites");
+        }
+
+        *sites.sites.write().await = map;
+        Ok(sites)
+    }
+
+    ///keeps any sites that are still around from `existing` so their caches survive
+    async fn build_map(
+        &self,
+        bucket: &Store,
+        raw_manifest: &[u8],
+        existing: &SiteMap,
+    ) -> color_eyre::Result<SiteMap> {
+        let settings = self.settings.lock().await.clone();
+        let manifest: SitesManifest = if raw_manifest.is_empty() {
+            SitesManifest::default()
+        } else {
+            from_slice(raw_manifest)?
+        };
+
+        let top_level = match &existing.top_level {
+            Some(site) => Some(site.clone()),
+            None => {
+                Site::new(
+                    bucket,
+                    None,
+                    &settings,
+                    self.stats.clone(),
+                    self.background.clone(),
+                )
+                .await?
+            }
+        };
+
+        let mut by_host = HashMap::new();
+        for host in manifest.hosts {
+            let site = match existing.by_host.get(&host) {
+                Some(site) => Some(site.clone()),
+                None => {
+                    Site::new(
+                        bucket,
+                        Some(&host),
+                        &settings,
+                        self.stats.clone(),
+                        self.background.clone(),
+                    )
+                    .await?
+                }
+            };
+            match site {
+                Some(site) => {
+                    by_host.insert(host, site);
+                }
+                None => warn!(?host, "Site in manifest has nothing uploaded"),
+            }
+        }
+
+        Ok(SiteMap {
+            top_level,
+            by_host,
+            default_host: manifest.default,
+        })
+    }
+
+    pub async fn get(&self, host: Option<&str>) -> Option<Site> {
+        self.sites.read().await.get(host)
+    }
+
+    ///`true` until something's been uploaded to any site
+    pub async fn is_empty(&self) -> bool {
+        self.sites.read().await.is_empty()
+    }
+
+    ///each site only gets reloaded if its own files have changed. Returns what was found for every
+    ///site whose upload data could be read
+    pub async fn check_and_reload(
+        &self,
+        bucket: &Store,
+        reloader: LiveReloader,
+    ) -> color_eyre::Result<Vec<SiteReload>> {
+        {
+            let Ok(mut last_manifest_hash) = self.last_manifest_hash.try_lock() else {
+                return Err(AlreadyReloading.into());
+            };
+
+            let raw_manifest = get_bytes_or_default(bucket, SITES_LOCATION).await?;
+            let new_hash = hash_raw_bytes(&raw_manifest);
+            let top_level_missing = self.sites.read().await.top_level.is_none();
+
+            if *last_manifest_hash != new_hash || top_level_missing {
+                let existing = self.sites.read().await.clone();
+                let map = self.build_map(bucket, &raw_manifest, &existing).await?;
+                info!(hosts=?map.by_host.keys().collect::<Vec<_>>(), "Reloaded sites");
+                *self.sites.write().await = map;
+                *last_manifest_hash = new_hash;
+            }
+        }
+
+        let sites: Vec<Site> = self.sites.read().await.all().cloned().collect();
+        let mut reports = vec![];
+        for site in sites {
+            reports.extend(site.check_and_reload(bucket, reloader.clone()).await?);
+        }
+
+        Ok(reports)
+    }
+
+    ///reloads just what `key` affects. `false` if that couldn't be worked out, so everything should be
+    ///reloaded instead
+    pub async fn reload_key(
+        &self,
+        bucket: &Store,
+        key: &str,
+        reloader: LiveReloader,
+    ) -> color_eyre::Result<bool> {
+        match ChangedKey::classify(key) {
+            ChangedKey::Everything | ChangedKey::Auth => Ok(false),
+            ChangedKey::Ignored => Ok(true),
+            ChangedKey::SiteConfig { site, file } => {
+                let site = {
+                    let map = self.sites.read().await;
+                    match site {
+                        Some(host) => map.by_host.get(host).cloned(),
+                        None => map.top_level.clone(),
+                    }
+                };
+                match site {
+                    Some(site) => site.reload_config(bucket, file).await,
+                    None => Ok(false),
+                }
+            }
+            ChangedKey::Content(key) => {
+                let sites: Vec<Site> = self.sites.read().await.all().cloned().collect();
+                let mut found = false;
+                for site in sites {
+                    found |= site.reload_content(bucket, key, reloader.clone()).await?;
+                }
+                Ok(found)
+            }
+        }
+    }
+
+    pub async fn forget_generated(&self) {
+        let sites: Vec<Site> = self.sites.read().await.all().cloned().collect();
+        for site in sites {
+            site.pages.forget_generated().await;
+        }
+    }
+
+    ///gives every site's cache roughly `max_bytes`, as well as any added later, returning what they
+    ///had before
+    pub async fn resize_caches(&self, max_bytes: u64) -> u64 {
+        let old = std::mem::replace(&mut self.settings.lock().await.cache_max_bytes, max_bytes);
+        let sites: Vec<Site> = self.sites.read().await.all().cloned().collect();
+        for site in sites {
+            site.pages.resize_cache(max_bytes).await;
+        }
+        old
+    }
+
+    pub async fn cache_weighted_size(&self) -> u64 {
+        self.sites
+            .read()
+            .await
+            .all()
+            .map(Site::cache_weighted_size)
+            .sum()
+    }
+
+    ///forgets clients that can't be rate limited anymore, returning how many are still remembered
+    pub async fn sweep_rate_limits(&self) -> usize {
+        let sites: Vec<Site> = self.sites.read().await.all().cloned().collect();
+        let mut remembered = 0;
+        for site in sites {
+            remembered += site.rate_limit_manager.sweep().await;
+        }
+        remembered
+    }
+
+    pub async fn stale_config(&self) -> Vec<StaleConfig> {
+        self.sites
+            .read()
+            .await
+            .all()
+            .flat_map(Site::stale_config)
+            .collect()
+    }
+
+    ///of every site together
+    pub async fn warmth(&self) -> Warmth {
+        self.sites
+            .read()
+            .await
+            .all()
+            .map(Site::warmth)
+            .fold(Warmth::default(), Warmth::combine)
+    }
+
+    ///of every site together
+    pub async fn admission(&self) -> Admission {
+        self.sites
+            .read()
+            .await
+            .all()
+            .map(Site::admission)
+            .fold(Admission::default(), Admission::combine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        normalise_host,
+        s3::UPLOAD_DATA_LOCATION,
+        serve::{
+            livereload::DEFAULT_MAX_LIVERELOAD_CLIENTS,
+            pages::{resolve_request_path, DEFAULT_CACHE_MAX_BYTES},
+        },
+        store::memory::{Fault, InMemoryBucket},
+        upload::machinery::{upload_dirs_to_bucket, Mapping, UploadOptions},
+        UploadData,
+    };
+    use http_body_util::BodyExt;
+    use hyper::{Method, StatusCode};
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    fn settings() -> SiteSettings {
+        SiteSettings {
+            cache_max_bytes: DEFAULT_CACHE_MAX_BYTES,
+            max_cacheable_bytes: None,
+            smart_cache_defaults: false,
+            case_insensitive_paths: CaseInsensitivePaths::Off,
+            index_files: IndexFiles::default(),
+            cache_admit_max_bytes: None,
+            render_markdown: None,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "shove-sites-test-{name}-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn upload(bucket: &InMemoryBucket, dir: &Path, site: Option<&str>) {
+        let mappings = [Mapping::parse(dir.to_str().unwrap()).unwrap()];
+        let options = UploadOptions {
+            no_manifest: true,
+            quiet: true,
+            ..Default::default()
+        };
+        upload_dirs_to_bucket(&mappings, site, bucket, options)
+            .await
+            .unwrap();
+    }
+
+    ///what serving `path` to `host` comes back with
+    async fn fetch(
+        sites: &Sites,
+        bucket: &Store,
+        host: Option<&str>,
+        path: &str,
+    ) -> (StatusCode, String) {
+        let site = sites.get(host).await.expect("no site");
+        let Some(output) = site.get(bucket, path, None, None).await else {
+            return (StatusCode::NOT_FOUND, String::new());
+        };
+        let rsp = output.into_response(&Method::GET, vec![]).await.unwrap();
+        let status = rsp.status();
+        let body = rsp.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    ///files are read into the cache in the background at first, which could otherwise race with a
+    ///reload
+    async fn wait_for_initial_load(sites: &Sites, host: Option<&str>, files: u64) {
+        let site = sites.get(host).await.expect("no site");
+        for _ in 0..100 {
+            if site.pages.cached_entry_count().await == files {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("never read in {files} files");
+    }
+
+    ///changed files are fetched in the background after a reload, so waits for `path` to catch up
+    async fn fetch_after_reload(
+        sites: &Sites,
+        bucket: &Store,
+        path: &str,
+        expected: (StatusCode, &str),
+    ) {
+        for _ in 0..100 {
+            let (status, body) = fetch(sites, bucket, None, path).await;
+            if (status, body.as_str()) == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{path} never became {expected:?}");
+    }
+
+    fn site(root: &str) -> Site {
+        Site {
+            pages: Pages::from_upload_data(UploadData {
+                entries: HashMap::new(),
+                root: root.to_string(),
+                cache_control: HashMap::new(),
+                sizes: HashMap::new(),
+            }),
+            redirects: Redirects::default(),
+            cache_control_manager: CacheControlManager::default(),
+            header_manager: HeaderManager::default(),
+            cors_manager: CorsManager::default(),
+            ip_filter_manager: IpFilterManager::default(),
+            rate_limit_manager: RateLimitManager::default(),
+            crawler_manager: CrawlerManager::default(),
+        }
+    }
+
+    async fn root_of(site: Option<Site>) -> Option<String> {
+        Some(site?.pages.root().await)
+    }
+
+    #[test]
+    fn test_normalise_host() {
+        assert_eq!(normalise_host("Example.com"), "example.com");
+        assert_eq!(normalise_host("example.com:8080"), "example.com");
+        assert_eq!(normalise_host("example.com."), "example.com");
+        assert_eq!(normalise_host("[::1]:8080"), "::1");
+    }
+
+    #[tokio::test]
+    async fn test_hosts_pick_their_own_site() {
+        let map = SiteMap {
+            top_level: Some(site("public")),
+            by_host: [
+                ("example.com".to_string(), site("sites/example.com")),
+                (
+                    "blog.example.com".to_string(),
+                    site("sites/blog.example.com"),
+                ),
+            ]
+            .into(),
+            default_host: None,
+        };
+
+        assert_eq!(
+            root_of(map.get(Some("example.com"))).await.as_deref(),
+            Some("sites/example.com")
+        );
+        assert_eq!(
+            root_of(map.get(Some("blog.example.com"))).await.as_deref(),
+            Some("sites/blog.example.com")
+        );
+        assert_eq!(
+            root_of(map.get(Some("unknown.com"))).await.as_deref(),
+            Some("public")
+        );
+        assert_eq!(root_of(map.get(None)).await.as_deref(), Some("public"));
+    }
+
+    #[tokio::test]
+    async fn test_default_host_is_used_for_unknown_hosts() {
+        let mut map = SiteMap {
+            top_level: None,
+            by_host: [("example.com".to_string(), site("sites/example.com"))].into(),
+            default_host: Some("example.com".to_string()),
+        };
+
+        assert_eq!(
+            root_of(map.get(Some("unknown.com"))).await.as_deref(),
+            Some("sites/example.com")
+        );
+
+        map.default_host = None;
+        assert!(map.get(Some("unknown.com")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unicode_names_round_trip() {
+        let bucket = InMemoryBucket::new();
+        let store: Store = Arc::new(bucket.clone());
+        let dir = temp_dir("unicode");
+        fs::write(dir.join("\u{1f980} crab.html"), "<p>crab</p>").unwrap();
+        //decomposed, like macOS writes them
+        fs::write(dir.join("cafe\u{301}.html"), "<p>cafe</p>").unwrap();
+        upload(&bucket, &dir, None).await;
+
+        let sites = Sites::new(
+            &store,
+            settings(),
+            HitStats::default(),
+            Background::default(),
+        )
+        .await
+        .unwrap();
+        wait_for_initial_load(&sites, None, 2).await;
+        for (request, expected) in [
+            ("/%F0%9F%A6%80%20crab.html", "<p>crab</p>"),
+            ("/caf%C3%A9.html", "<p>cafe</p>"),
+            ("/cafe%CC%81.html", "<p>cafe</p>"),
+        ] {
+            let path = resolve_request_path(request).unwrap();
+            assert_eq!(
+                fetch(&sites, &store, None, &path).await,
+                (StatusCode::OK, expected.to_string()),
+                "{request}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_serve_reload_round_trip() {
+        let bucket = InMemoryBucket::new();
+        let store: Store = Arc::new(bucket.clone());
+        assert!(
+            Sites::new(
+                &store,
+                settings(),
+                HitStats::default(),
+                Background::default()
+            )
+            .await
+            .unwrap()
+            .is_empty()
+            .await
+        );
+
+        let dir = temp_dir("round-trip");
+        fs::write(dir.join("index.html"), "<p>one</p>").unwrap();
+        fs::write(dir.join("style.css"), "p {}").unwrap();
+        upload(&bucket, &dir, None).await;
+
+        let sites = Sites::new(
+            &store,
+            settings(),
+            HitStats::default(),
+            Background::default(),
+        )
+        .await
+        .unwrap();
+        wait_for_initial_load(&sites, None, 2).await;
+        assert_eq!(
+            fetch(&sites, &store, None, "/index.html").await,
+            (StatusCode::OK, "<p>one</p>".to_string())
+        );
+        assert_eq!(
+            fetch(&sites, &store, None, "/style.css").await,
+            (StatusCode::OK, "p {}".to_string())
+        );
+
+        fs::write(dir.join("index.html"), "<p>two</p>").unwrap();
+        fs::remove_file(dir.join("style.css")).unwrap();
+        upload(&bucket, &dir, None).await;
+        assert!(bucket.bytes("style.css").await.is_none());
+
+        //still what was there before, until it's reloaded
+        assert_eq!(
+            fetch(&sites, &store, None, "/index.html").await.1,
+            "<p>one</p>"
+        );
+        sites
+            .check_and_reload(
+                &store,
+                LiveReloader::new(Duration::from_secs(1), DEFAULT_MAX_LIVERELOAD_CLIENTS),
+            )
+            .await
+            .unwrap();
+        fetch_after_reload(
+            &sites,
+            &store,
+            "/index.html",
+            (StatusCode::OK, "<p>two</p>"),
+        )
+        .await;
+        //removed files can take a moment to drop out of the cache too
+        fetch_after_reload(&sites, &store, "/style.css", (StatusCode::NOT_FOUND, "")).await;
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_bucket_keeps_serving_the_old_upload() {
+        let bucket = InMemoryBucket::new();
+        let store: Store = Arc::new(bucket.clone());
+
+        let dir = temp_dir("unavailable");
+        fs::write(dir.join("index.html"), "<p>one</p>").unwrap();
+        upload(&bucket, &dir, None).await;
+        let sites = Sites::new(
+            &store,
+            settings(),
+            HitStats::default(),
+            Background::default(),
+        )
+        .await
+        .unwrap();
+        wait_for_initial_load(&sites, None, 1).await;
+
+        fs::write(dir.join("index.html"), "<p>two</p>").unwrap();
+        upload(&bucket, &dir, None).await;
+
+        bucket.fail(UPLOAD_DATA_LOCATION, Fault::Unavailable).await;
+        let reloader = LiveReloader::new(Duration::from_secs(1), DEFAULT_MAX_LIVERELOAD_CLIENTS);
+        //the error's only logged, as the other sites might still be fine
+        sites
+            .check_and_reload(&store, reloader.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            fetch(&sites, &store, None, "/index.html").await,
+            (StatusCode::OK, "<p>one</p>".to_string())
+        );
+
+        bucket.heal(UPLOAD_DATA_LOCATION).await;
+        sites.check_and_reload(&store, reloader).await.unwrap();
+        fetch_after_reload(
+            &sites,
+            &store,
+            "/index.html",
+            (StatusCode::OK, "<p>two</p>"),
+        )
+        .await;
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_uploaded_sites_are_served_to_their_hosts() {
+        let bucket = InMemoryBucket::new();
+        let store: Store = Arc::new(bucket.clone());
+
+        let top = temp_dir("top");
+        fs::write(top.join("index.html"), "top").unwrap();
+        upload(&bucket, &top, None).await;
+        let blog = temp_dir("blog");
+        fs::write(blog.join("index.html"), "blog").unwrap();
+        upload(&bucket, &blog, Some("blog.example.com")).await;
+
+        let sites = Sites::new(
+            &store,
+            settings(),
+            HitStats::default(),
+            Background::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            fetch(&sites, &store, Some("blog.example.com"), "/index.html")
+                .await
+                .1,
+            "blog"
+        );
+        assert_eq!(
+            fetch(&sites, &store, Some("example.com"), "/index.html")
+                .await
+                .1,
+            "top"
+        );
+
+        fs::remove_dir_all(&top).unwrap();
+        fs::remove_dir_all(&blog).unwrap();
+    }
+}
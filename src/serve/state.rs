@@ -1,101 +1,560 @@
 use crate::{
-    cache_control::manager::CacheControlManager,
+    audit::AuditLog,
     protect::auth::{AuthChecker, AuthReturn},
-    s3::get_bucket,
     serve::{
+        background::Background,
+        bandwidth::Bandwidth,
+        concurrency::Concurrency,
+        config::{Config, Storage},
+        drain::Drainer,
+        health::{HealthReport, StoreHealth},
+        journal::Journal,
+        limits::{Limits, TimeoutCounts},
         livereload::LiveReloader,
-        pages::{PageOutput, Pages},
+        pages::{
+            Admission, AlreadyReloading, CaseInsensitivePaths, PageOutput, SiteReload,
+            TrailingSlash, Variant, Warmth,
+        },
+        proxy::TrustedProxies,
+        resize::Resizing,
+        sitemap::{base_url, robots, sitemap, Generated, SITEMAP_PATH},
+        sites::{Site, Sites},
+        stats::HitStats,
+        tls::TlsConfig,
+        webhook::{ChangedKey, WebhookTokens},
     },
+    set_log_filter,
+    store::{local::LocalDir, prefixed::normalise_prefix, timeout::TimeLimited, Store},
 };
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
 use hyper::{body::Incoming, Request};
-use s3::Bucket;
-use std::{env, net::SocketAddr, sync::Arc};
+use serde::Serialize;
+use std::{
+    net::IpAddr,
+    num::NonZeroU32,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+///how often clients that are back to a full allowance get forgotten
+const RATE_LIMIT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+///what a reload did, for whoever asked for it
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReloadReport {
+    ///whether any site's upload data had changed
+    pub manifest_changed: bool,
+    pub auth_changed: bool,
+    ///files dropped from the cache across every site, as they're no longer uploaded
+    pub invalidated: usize,
+    ///files that are new or changed across every site, and are being read in again
+    pub updated: usize,
+    ///changed keys a webhook listed that were reloaded on their own
+    pub keys_reloaded: usize,
+    ///each site that got checked, which is none if only `keys_reloaded` were
+    pub sites: Vec<SiteReload>,
+    pub elapsed_ms: u64,
+}
+
+impl ReloadReport {
+    fn add_sites(&mut self, sites: Vec<SiteReload>) {
+        for site in &sites {
+            self.manifest_changed |= site.manifest_changed;
+            self.invalidated += site.invalidated;
+            self.updated += site.updated;
+        }
+        self.sites.extend(sites);
+    }
+}
 
 #[derive(Clone)]
 pub struct State {
-    bucket: Box<Bucket>,
-    pub tigris_token: Option<Arc<str>>,
-    pages: Pages,
+    store: Store,
+    ///what webhooks' keys start with, as they know nothing of [`S3_PREFIX`](crate::store::prefixed::Prefixed)
+    key_prefix: String,
+    ///`Some` with `serve --local`
+    local: Option<LocalDir>,
+    pub tigris_tokens: Option<WebhookTokens>,
+    pub reload_rate_limiter: Arc<DefaultKeyedRateLimiter<IpAddr>>,
+    pub admin_token: Option<Arc<str>>,
+    pub protect_version: bool,
+    pub drain_exit_after: Option<Duration>,
+    pub trailing_slash: TrailingSlash,
+    pub case_insensitive_paths: CaseInsensitivePaths,
+    ///whether `.md` files get rendered to HTML
+    pub render_markdown: bool,
+    ready_fraction: Option<f64>,
+    health: StoreHealth,
+    healthcheck_probe: bool,
+    pub livereload_inject: bool,
+    pub default_charset: bool,
+    ///`None` unless images can be resized with `?w=` & `?h=`
+    pub resizing: Option<Resizing>,
+    generate_sitemap: bool,
+    canonical_host: Option<String>,
+    ///what every request path starts with, which is taken off before anything else sees it
+    pub base_path: Option<String>,
+    ///`Some` if plain HTTP should be sent to https
+    pub tls: Option<Arc<TlsConfig>>,
+    pub limits: Limits,
+    pub timeouts: TimeoutCounts,
+    pub concurrency: Concurrency,
+    pub trusted_proxies: TrustedProxies,
+    drainer: Drainer,
+    journal: Option<Journal>,
+    audit: Option<AuditLog>,
+    bandwidth: Bandwidth,
+    ///the reloads, cache warming & flushing that shutting down waits for
+    background: Background,
+    sites: Sites,
     live_reloader: LiveReloader,
-    auth: AuthChecker,
-    cache_control_manager: CacheControlManager,
+    ///`None` when serving a local directory, which has nothing to protect
+    auth: Option<AuthChecker>,
 }
 
 impl State {
-    #[instrument]
-    pub async fn new() -> color_eyre::Result<Option<Self>> {
-        let bucket = get_bucket();
-        let Some(pages) = Pages::new(&bucket).await? else {
-            return Ok(None);
+    #[instrument(skip(config))]
+    pub async fn new(config: Config) -> color_eyre::Result<Self> {
+        let (store, local): (Store, _) = match &config.storage {
+            Storage::Bucket { bucket, .. } => (Arc::new(bucket.bucket()?), None),
+            Storage::Local(dir) => {
+                let local = LocalDir::new(dir.clone())?;
+                (Arc::new(local.clone()), Some(local))
+            }
+        };
+        Self::with_store(config, store, local).await
+    }
+
+    ///serves from `store` rather than whatever `config.storage` says, though the auth key & the
+    ///prefix webhooks' keys are under still come from there
+    pub(crate) async fn with_store(
+        config: Config,
+        store: Store,
+        local: Option<LocalDir>,
+    ) -> color_eyre::Result<Self> {
+        let (auth_key, key_prefix) = match config.storage {
+            Storage::Bucket { auth, bucket } => (Some(auth.key), normalise_prefix(&bucket.prefix)),
+            Storage::Local(_) => (None, String::new()),
         };
-        info!("Got bucket & upload data");
+        let health = StoreHealth::new(config.healthcheck_failures);
+        //inside the monitor, so a store that's stopped answering counts as failing
+        let store = health.monitor(Arc::new(TimeLimited::new(store, config.store_timeout)));
+        //a local directory is never written to
+        let writable = local.is_none();
+        let stats = HitStats::load(&*store).await;
+        let background = Background::default();
+        let sites = Sites::new(
+            &store,
+            config.site_settings.clone(),
+            stats.clone(),
+            background.clone(),
+        )
+        .await?;
+        let bandwidth = Bandwidth::load(&*store, config.bandwidth_max_paths).await;
+        //the hit stats & bandwidth, which aren't kept when serving a local directory
+        if writable {
+            stats.start_flushing(store.clone(), &background);
+            bandwidth.start_flushing(store.clone(), &background);
+        }
 
-        let live_reloader = LiveReloader::new();
-        let auth = AuthChecker::new(&bucket).await?;
-        let cache_control_manager = CacheControlManager::new(&bucket).await?;
+        let sweep_sites = sites.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(RATE_LIMIT_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let remembered = sweep_sites.sweep_rate_limits().await;
+                trace!(%remembered, "Swept rate limited clients");
+            }
+        });
+        info!("Got store & upload data");
 
-        let tigris_token = env::var("TIGRIS_TOKEN").ok().map(|x| x.into());
-        if tigris_token.is_some() {
-            info!("Waiting on Tigris Webhook for reloads");
+        let live_reloader =
+            LiveReloader::new(config.ws_close_timeout, config.max_livereload_clients);
+        let audit = if config.audit_log && writable {
+            info!("Recording logins to the audit log");
+            Some(AuditLog::new(store.clone()))
+        } else {
+            None
+        };
+        let auth = match auth_key {
+            Some(key) => Some(AuthChecker::new(&store, key, config.backoff, audit.clone()).await?),
+            None => None,
+        };
+
+        let tigris_tokens = config.tigris_tokens;
+        if let Some(local) = &local {
+            info!(dir=?local.dir(), "Watching the local directory for reloads");
+        } else if let Some(tokens) = &tigris_tokens {
+            info!(tokens=%tokens.len(), "Waiting on Tigris Webhook for reloads");
         } else {
             info!("Checking every 60s for reloads");
         }
 
-        Ok(Some(Self {
-            bucket,
-            pages,
-            tigris_token,
+        let admin_token = config.admin_token.map(|x| x.into());
+        let protect_version = config.protect_version;
+        let drain_exit_after = config.drain_exit_after;
+
+        let reload_rate_limiter = Arc::new(RateLimiter::keyed(Quota::per_minute(
+            NonZeroU32::new(10).unwrap(),
+        )));
+
+        let trailing_slash = config.trailing_slash;
+        let case_insensitive_paths = config.site_settings.case_insensitive_paths;
+        let render_markdown = config.site_settings.render_markdown.is_some();
+        if let Some(markdown) = &config.site_settings.render_markdown {
+            info!(?markdown, "Rendering Markdown to HTML");
+        }
+        let ready_fraction = config.ready_fraction;
+        let healthcheck_probe = config.healthcheck_probe;
+        let livereload_inject = config.livereload_inject;
+        if livereload_inject {
+            info!("Injecting the live-reload script into HTML");
+        }
+        let default_charset = config.default_charset;
+        let resizing = config.resizing;
+        if let Some(resizing) = resizing {
+            info!(?resizing, "Resizing images on request");
+        }
+        let generate_sitemap = config.generate_sitemap;
+        let canonical_host = config.canonical_host;
+        if generate_sitemap {
+            info!(
+                ?canonical_host,
+                "Generating sitemaps & robots.txt for sites without them"
+            );
+        }
+        let base_path = config.base_path;
+        if let Some(base_path) = &base_path {
+            info!(?base_path, "Serving under a base path");
+        }
+        let tls = config.tls.map(Arc::new);
+        let limits = config.limits;
+        info!(?limits, "Got connection limits");
+        let trusted_proxies = config.trusted_proxies;
+        if trusted_proxies.len() > 0 {
+            info!(proxies=%trusted_proxies.len(), "Trusting forwarding headers from proxies");
+        }
+
+        let journal = match config.journal_size {
+            Some(size) if writable => {
+                info!(?size, "Recording server errors to the request journal");
+                Some(Journal::new(store.clone(), size).await?)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            store,
+            key_prefix,
+            local,
+            sites,
+            tigris_tokens,
+            reload_rate_limiter,
+            admin_token,
+            protect_version,
+            drain_exit_after,
+            trailing_slash,
+            case_insensitive_paths,
+            render_markdown,
+            ready_fraction,
+            health,
+            healthcheck_probe,
+            livereload_inject,
+            default_charset,
+            resizing,
+            generate_sitemap,
+            canonical_host,
+            base_path,
+            tls,
+            limits,
+            timeouts: TimeoutCounts::default(),
+            concurrency: Concurrency::new(limits.max_concurrent_requests, limits.queue_wait),
+            trusted_proxies,
+            drainer: Drainer::default(),
+            journal,
+            audit,
+            bandwidth,
+            background,
             live_reloader,
             auth,
-            cache_control_manager,
-        }))
+        })
     }
 
     pub fn live_reloader(&self) -> LiveReloader {
         self.live_reloader.clone()
     }
 
+    pub fn drainer(&self) -> Drainer {
+        self.drainer.clone()
+    }
+
+    ///for anything else that keeps what it needs in the bucket, like TLS certificates
+    pub fn store(&self) -> Store {
+        self.store.clone()
+    }
+
+    pub fn local(&self) -> Option<&LocalDir> {
+        self.local.as_ref()
+    }
+
+    pub fn journal(&self) -> Option<Journal> {
+        self.journal.clone()
+    }
+
+    pub fn audit(&self) -> Option<AuditLog> {
+        self.audit.clone()
+    }
+
+    pub fn background(&self) -> Background {
+        self.background.clone()
+    }
+
+    ///counts a response body towards `path`'s bandwidth for today
+    pub fn record_bandwidth(&self, site: &Site, path: &str, bytes: u64) {
+        self.bandwidth.record(&site.stats_key(path), bytes);
+    }
+
+    ///what SIGHUP does - everything [`Self::check_and_reload`] does, after re-reading the settings
+    ///that can change without a restart
+    #[instrument(skip(self))]
+    pub async fn reload_everything(&self) {
+        match Config::reread(self.local.as_ref().map(|x| x.dir().to_path_buf())) {
+            Ok(config) => self.apply_settings(&config).await,
+            Err(e) => error!(%e, "Not changing any settings"),
+        }
+        if let Err(e) = self.check_and_reload().await {
+            error!(?e, "Error reloading state");
+        }
+    }
+
+    ///the log filter, how many requests can be handled at once, and how big the caches are. Anything
+    ///else in `config` needs a restart
+    pub async fn apply_settings(&self, config: &Config) {
+        let log_filter = config.log_filter.as_deref().unwrap_or_default();
+        match set_log_filter(log_filter) {
+            Ok(Some(old)) => info!(%old, new=%log_filter, "Changed the log filter"),
+            Ok(None) => {}
+            Err(e) => error!(?e, "Error changing the log filter, keeping the old one"),
+        }
+
+        let max = config.limits.max_concurrent_requests;
+        let old = self.concurrency.resize(max);
+        if old != max {
+            info!(%old, new=%max, "Changed how many requests can be handled at once");
+        }
+
+        let max_bytes = config.site_settings.cache_max_bytes;
+        let old = self.sites.resize_caches(max_bytes).await;
+        if old != max_bytes {
+            info!(%old, new=%max_bytes, "Changed the size of the caches");
+        }
+    }
+
+    ///an [`AlreadyReloading`] error if another reload is still going
     #[instrument(skip(self))]
-    pub async fn check_and_reload(&self) -> color_eyre::Result<()> {
+    pub async fn check_and_reload(&self) -> color_eyre::Result<ReloadReport> {
         trace!("Checking for reload");
+        let started = Instant::now();
+        let mut report = ReloadReport::default();
 
-        trace!("Checking for auth reload");
-        if let Err(e) = self.auth.check_and_reload(&self.bucket).await {
-            error!(?e, "Error reloading auth checker");
+        if let Some(auth) = &self.auth {
+            trace!("Checking for auth reload");
+            match auth.check_and_reload(&self.store).await {
+                //what's protected decides what goes in them
+                Ok(true) => {
+                    report.auth_changed = true;
+                    self.sites.forget_generated().await;
+                }
+                Ok(false) => {}
+                Err(e) => error!(?e, "Error reloading auth checker"),
+            }
         }
-        trace!("Checking for pages reload");
-        if let Err(e) = self
-            .pages
-            .check_and_reload(&self.bucket, self.live_reloader.clone())
+        trace!("Checking for sites reload");
+        match self
+            .sites
+            .check_and_reload(&self.store, self.live_reloader.clone())
             .await
         {
-            error!(?e, "Error reloading pages")
+            Ok(sites) => report.add_sites(sites),
+            Err(e) if e.is::<AlreadyReloading>() => return Err(e),
+            Err(e) => error!(?e, "Error reloading sites"),
         }
-        trace!("Checking for Cache Control reload");
-        if let Err(e) = self
-            .cache_control_manager
-            .check_and_reload(&self.bucket)
-            .await
+
+        report.elapsed_ms = started.elapsed().as_millis().try_into().unwrap_or(u64::MAX);
+        Ok(report)
+    }
+
+    ///reloads only what the changed `keys` affect, falling back to [`Self::check_and_reload`] for
+    ///anything we can't pin down
+    #[instrument(skip(self))]
+    ///reloads just what `keys` affect, or everything if that can't be worked out
+    pub async fn reload_keys(&self, keys: &[String]) -> color_eyre::Result<ReloadReport> {
+        let started = Instant::now();
+        let mut report = ReloadReport::default();
+        let mut reload_everything = false;
+
+        for key in keys {
+            //webhooks are for the whole bucket
+            let Some(key) = key.strip_prefix(&self.key_prefix) else {
+                trace!(?key, "Changed key isn't under S3_PREFIX");
+                continue;
+            };
+            if ChangedKey::classify(key) == ChangedKey::Auth {
+                if let Some(auth) = &self.auth {
+                    trace!("Reloading auth");
+                    if auth.check_and_reload(&self.store).await? {
+                        report.auth_changed = true;
+                        self.sites.forget_generated().await;
+                    }
+                }
+                continue;
+            }
+
+            match self
+                .sites
+                .reload_key(&self.store, key, self.live_reloader.clone())
+                .await
+            {
+                Ok(true) => {
+                    trace!(?key, "Reloaded key");
+                    report.keys_reloaded += 1;
+                }
+                Ok(false) => reload_everything = true,
+                Err(e) => {
+                    warn!(
+                        ?e,
+                        ?key,
+                        "Error reloading key, reloading everything instead"
+                    );
+                    reload_everything = true;
+                }
+            }
+        }
+
+        if reload_everything {
+            let everything = self.check_and_reload().await?;
+            report.auth_changed |= everything.auth_changed;
+            report.add_sites(everything.sites);
+        }
+
+        report.elapsed_ms = started.elapsed().as_millis().try_into().unwrap_or(u64::MAX);
+        Ok(report)
+    }
+
+    ///the site for a request's `Host`, or the default one
+    pub async fn site(&self, host: Option<&str>) -> Option<Site> {
+        self.sites.get(host).await
+    }
+
+    ///`true` until the first upload, to any site
+    pub async fn nothing_deployed(&self) -> bool {
+        self.sites.is_empty().await
+    }
+
+    ///`host` is what the request was for, for generated sitemaps without a `CANONICAL_HOST`
+    #[instrument(skip(self, site))]
+    pub async fn get(
+        &self,
+        site: &Site,
+        path: &str,
+        host: Option<&str>,
+        user_agent: Option<&str>,
+        variant: Option<Variant>,
+    ) -> Option<PageOutput> {
+        if self.generate_sitemap
+            && let Some(generated) = Generated::for_path(path)
+            && !site.contains(path).await
         {
-            error!(?e, "Error reloading cache control manager");
+            return self.get_generated(site, path, generated, host).await;
+        }
+        site.get(&self.store, path, user_agent, variant).await
+    }
+
+    ///a sitemap or robots.txt for a site that didn't upload its own
+    async fn get_generated(
+        &self,
+        site: &Site,
+        path: &str,
+        generated: Generated,
+        host: Option<&str>,
+    ) -> Option<PageOutput> {
+        let base_url = base_url(self.canonical_host.as_deref(), host)
+            .map(|x| format!("{x}{}", self.base_path.as_deref().unwrap_or_default()));
+        if generated == Generated::Sitemap && base_url.is_none() {
+            debug!("No host to put in the sitemap");
+            return None;
         }
 
-        Ok(())
+        let output = site
+            .get_generated(path, generated.content_type(), || async {
+                match generated {
+                    Generated::Sitemap => {
+                        let paths = match &self.auth {
+                            Some(auth) => auth.public_paths(site.paths().await).await,
+                            None => site.paths().await,
+                        };
+                        sitemap(base_url.as_deref().unwrap_or_default(), paths)
+                    }
+                    Generated::Robots => {
+                        let disallowed = match &self.auth {
+                            Some(auth) => auth.protected_prefixes().await,
+                            None => vec![],
+                        };
+                        let sitemap_url = base_url.map(|x| format!("{x}{SITEMAP_PATH}"));
+                        robots(&disallowed, sitemap_url.as_deref())
+                    }
+                }
+                .into_bytes()
+            })
+            .await;
+        Some(output)
     }
 
-    #[instrument(skip(self))]
-    pub async fn get(&self, path: &str) -> Option<PageOutput> {
-        self.pages
-            .get(&self.bucket, path, &self.cache_control_manager)
-            .await
+    pub async fn cache_weighted_size(&self) -> u64 {
+        self.sites.cache_weighted_size().await
+    }
+
+    pub async fn warmth(&self) -> Warmth {
+        self.sites.warmth().await
+    }
+
+    pub async fn admission(&self) -> Admission {
+        self.sites.admission().await
+    }
+
+    ///whether enough of the cache is warm to take traffic, for `/readycheck`
+    pub async fn is_ready(&self) -> bool {
+        self.warmth().await.is_ready(self.ready_fraction)
+    }
+
+    ///how the store's been doing, and any config from it that couldn't be used, for `/healthcheck`
+    pub async fn health(&self) -> HealthReport {
+        if self.healthcheck_probe {
+            self.health.probe(&*self.store).await;
+        }
+        let mut report = self.health.report();
+
+        //still serving fine with what was there before, so it isn't worth failing over
+        report
+            .stale_config
+            .extend(self.auth.as_ref().and_then(AuthChecker::stale));
+        report.stale_config.extend(self.sites.stale_config().await);
+        if !report.stale_config.is_empty() && report.warning.is_none() {
+            report.warning = Some("some config couldn't be read, so older versions are being used");
+        }
+
+        report
     }
 
     pub async fn check_auth(
         &self,
         path: &str,
         req: Request<Incoming>,
-        remote_addr: SocketAddr,
+        client_ip: IpAddr,
     ) -> AuthReturn {
-        self.auth.check_auth(path, req, remote_addr).await
+        match &self.auth {
+            Some(auth) => auth.check_auth(path, req, client_ip).await,
+            None => AuthReturn::AuthConfirmed(req),
+        }
     }
 }
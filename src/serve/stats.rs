@@ -0,0 +1,230 @@
+use crate::{
+    audit::utc_datetime,
+    config::BucketConfig,
+    s3::get_bytes_or_default,
+    serve::{background::Background, flush::start_flushing, journal::CacheStatus},
+    store::{ObjectStore, Store},
+};
+use comfy_table::Table;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const STATS_LOCATION: &str = "stats.json";
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+///only the most hit paths get written, so sites with huge numbers of files don't make huge objects
+const PERSISTED_PATHS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub last_access_ms: u128,
+    ///of the body last served, in bytes
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct StoredStats {
+    pub paths: HashMap<String, PathStats>,
+}
+
+impl StoredStats {
+    ///most hit first, then by path so it's stable
+    fn top(mut self, count: usize) -> Vec<(String, PathStats)> {
+        let mut paths: Vec<_> = self.paths.drain().collect();
+        paths.sort_unstable_by(|(a_path, a), (b_path, b)| {
+            b.hits.cmp(&a.hits).then_with(|| a_path.cmp(b_path))
+        });
+        paths.truncate(count);
+        paths
+    }
+}
+
+///what a path is counted under - the request path, after the host for sites uploaded with `--site`.
+///not the key in the bucket, as that changes with every `--atomic` release
+pub fn stats_key(site: Option<&str>, path: &str) -> String {
+    match site {
+        Some(site) => format!("{site}{path}"),
+        None => path.to_string(),
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+///hits & misses for every path, carried on from the last run's `stats.json`. Clones share counts
+#[derive(Clone, Default)]
+pub struct HitStats {
+    paths: Arc<Mutex<HashMap<String, PathStats>>>,
+}
+
+impl HitStats {
+    ///starts from whatever was last persisted - if that can't be read, from nothing
+    pub async fn load(bucket: &dyn ObjectStore) -> Self {
+        let stored = match get_bytes_or_default(bucket, STATS_LOCATION).await {
+            Ok(bytes) if bytes.is_empty() => StoredStats::default(),
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!(?e, "Error parsing hit stats, starting afresh");
+                StoredStats::default()
+            }),
+            Err(e) => {
+                warn!(?e, "Error reading hit stats, starting afresh");
+                StoredStats::default()
+            }
+        };
+        info!(paths=%stored.paths.len(), "Loaded hit stats");
+
+        Self {
+            paths: Arc::new(Mutex::new(stored.paths)),
+        }
+    }
+
+    pub fn record(&self, key: &str, cache_status: CacheStatus, size: u64) {
+        //only files that are actually there, else anyone could fill it up with made-up paths
+        if matches!(
+            cache_status,
+            CacheStatus::NotFound | CacheStatus::Unavailable
+        ) {
+            return;
+        }
+        let Ok(mut paths) = self.paths.lock() else {
+            return;
+        };
+        let stats = paths.entry(key.to_string()).or_default();
+        if cache_status == CacheStatus::Hit {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+        stats.last_access_ms = now_ms();
+        stats.size = size;
+    }
+
+    ///every request for `key` so far, whether or not it was cached
+    pub fn requests(&self, key: &str) -> u64 {
+        self.paths
+            .lock()
+            .ok()
+            .and_then(|paths| paths.get(key).map(|x| x.hits + x.misses))
+            .unwrap_or_default()
+    }
+
+    fn snapshot(&self) -> StoredStats {
+        StoredStats {
+            paths: self.paths.lock().map(|x| x.clone()).unwrap_or_default(),
+        }
+    }
+
+    async fn flush(&self, bucket: &dyn ObjectStore) -> color_eyre::Result<()> {
+        let top = self.snapshot().top(PERSISTED_PATHS);
+        let stored = StoredStats {
+            paths: top.into_iter().collect(),
+        };
+        let bytes = serde_json::to_vec(&stored)?;
+
+        bucket
+            .put(STATS_LOCATION, &bytes, mime::JSON.as_str())
+            .await?;
+
+        trace!(len=%stored.paths.len(), "Flushed hit stats");
+
+        Ok(())
+    }
+
+    ///writes to the bucket every so often, and once more when shutting down
+    pub fn start_flushing(&self, bucket: Store, background: &Background) {
+        let stats = self.clone();
+        start_flushing("hit stats", FLUSH_INTERVAL, background, move || {
+            let stats = stats.clone();
+            let bucket = bucket.clone();
+            async move { stats.flush(&*bucket).await }
+        })
+    }
+}
+
+pub async fn stats(config: &BucketConfig) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let bytes = get_bytes_or_default(&bucket, STATS_LOCATION).await?;
+    if bytes.is_empty() {
+        println!("No hit stats yet.");
+        return Ok(());
+    }
+
+    let stored: StoredStats = serde_json::from_slice(&bytes)?;
+
+    let mut table = Table::new();
+    table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+    table.set_header(vec!["Path", "Hits", "Misses", "Last Access (UTC)", "Size"]);
+    for (path, stats) in stored.top(usize::MAX) {
+        let (year, month, day, hour, minute, second) = utc_datetime(stats.last_access_ms);
+        table.add_row(vec![
+            path,
+            stats.hits.to_string(),
+            stats.misses.to_string(),
+            format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}"),
+            format!("{}B", stats.size),
+        ]);
+    }
+    println!("{table}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::InMemoryBucket;
+
+    #[tokio::test]
+    async fn test_stats_survive_a_restart() {
+        let bucket: Store = Arc::new(InMemoryBucket::new());
+        let stats = HitStats::load(&*bucket).await;
+        stats.record("/index.html", CacheStatus::Miss, 10);
+        stats.record("/index.html", CacheStatus::Hit, 10);
+        stats.record("/index.html", CacheStatus::Hit, 12);
+        stats.record("/missing.html", CacheStatus::NotFound, 0);
+        stats.record("blog.example.com/index.html", CacheStatus::Bypass, 2048);
+
+        let background = Background::default();
+        stats.start_flushing(bucket.clone(), &background);
+        assert!(background.finish(Duration::from_secs(5)).await);
+
+        let restored = HitStats::load(&*bucket).await;
+        assert_eq!(restored.requests("/index.html"), 3);
+        assert_eq!(restored.requests("blog.example.com/index.html"), 1);
+        assert_eq!(restored.requests("/missing.html"), 0);
+
+        let top = restored.snapshot().top(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0, "/index.html");
+        assert_eq!((top[0].1.hits, top[0].1.misses, top[0].1.size), (2, 1, 12));
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_stats_start_afresh() {
+        let bucket = InMemoryBucket::new();
+        bucket
+            .put(STATS_LOCATION, b"not json", "application/json")
+            .await
+            .unwrap();
+        assert_eq!(HitStats::load(&bucket).await.requests("/index.html"), 0);
+    }
+
+    #[test]
+    fn test_stats_key() {
+        assert_eq!(stats_key(None, "/index.html"), "/index.html");
+        assert_eq!(
+            stats_key(Some("blog.example.com"), "/index.html"),
+            "blog.example.com/index.html"
+        );
+    }
+}
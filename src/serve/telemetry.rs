@@ -0,0 +1,300 @@
+use crate::{hash_raw_bytes, serve::journal::CacheStatus, to_hex};
+use hyper::{Method, Request, Response, StatusCode};
+use sentry::{protocol::SpanStatus, Hub, SentryFutureExt, TransactionContext};
+use soketto::handshake::http::is_upgrade_request;
+use std::{
+    future::Future,
+    net::IpAddr,
+    sync::{Arc, LazyLock},
+};
+use tokio::task::JoinHandle;
+
+///so client IPs can be told apart in Sentry without being sent there. Fresh each run, so the
+///hashes can't be looked up in a table of every IPv4 address
+static IP_SALT: LazyLock<[u8; 16]> = LazyLock::new(|| {
+    let mut salt = [0; 16];
+    if let Err(e) = getrandom::getrandom(&mut salt) {
+        warn!(?e, "Couldn't get a random salt for hashing client IPs");
+    }
+    salt
+});
+
+fn hash_ip(ip: IpAddr) -> String {
+    let mut salted = IP_SALT.to_vec();
+    salted.extend_from_slice(ip.to_string().as_bytes());
+    to_hex(&hash_raw_bytes(salted)[..8])
+}
+
+///what sort of request something was, for grouping timings in Sentry
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RouteClass {
+    ///anything not one of the others, which is usually a page
+    Page,
+    StaticHit,
+    StaticMiss,
+    NotFound,
+    ///the store couldn't be read
+    Unavailable,
+    ///asked for a password
+    AuthChallenge,
+    Webhook,
+    ///health & ready checks, and everything under `/__shove/`
+    Admin,
+    LiveReload,
+}
+
+impl RouteClass {
+    ///what can be told before handling it
+    fn of_request<B>(req: &Request<B>) -> Self {
+        let path = req.uri().path();
+        if is_upgrade_request(req) {
+            Self::LiveReload
+        } else if req.method() == Method::POST && matches!(path, "/reload" | "/__shove/purge") {
+            Self::Webhook
+        } else if path.starts_with("/__shove/") || matches!(path, "/healthcheck" | "/readycheck") {
+            Self::Admin
+        } else {
+            Self::Page
+        }
+    }
+
+    ///pages get narrowed down by how they were answered
+    fn with_response<B>(self, rsp: &Response<B>) -> Self {
+        if self != Self::Page {
+            return self;
+        }
+        if rsp.status() == StatusCode::UNAUTHORIZED {
+            return Self::AuthChallenge;
+        }
+        match rsp.extensions().get::<CacheStatus>() {
+            Some(CacheStatus::Hit) => Self::StaticHit,
+            Some(CacheStatus::Miss | CacheStatus::Bypass) => Self::StaticMiss,
+            Some(CacheStatus::NotFound) => Self::NotFound,
+            Some(CacheStatus::Unavailable) => Self::Unavailable,
+            None => self,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Page => "page",
+            Self::StaticHit => "static_hit",
+            Self::StaticMiss => "static_miss",
+            Self::NotFound => "not_found",
+            Self::Unavailable => "unavailable",
+            Self::AuthChallenge => "auth_challenge",
+            Self::Webhook => "webhook",
+            Self::Admin => "admin",
+            Self::LiveReload => "livereload",
+        }
+    }
+}
+
+fn span_status(status: StatusCode) -> SpanStatus {
+    match status {
+        StatusCode::UNAUTHORIZED => SpanStatus::Unauthenticated,
+        StatusCode::FORBIDDEN => SpanStatus::PermissionDenied,
+        StatusCode::NOT_FOUND => SpanStatus::NotFound,
+        StatusCode::TOO_MANY_REQUESTS => SpanStatus::ResourceExhausted,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => SpanStatus::DeadlineExceeded,
+        StatusCode::SERVICE_UNAVAILABLE => SpanStatus::Unavailable,
+        StatusCode::NOT_IMPLEMENTED => SpanStatus::Unimplemented,
+        x if x.is_client_error() => SpanStatus::InvalidArgument,
+        x if x.is_server_error() => SpanStatus::InternalError,
+        _ => SpanStatus::Ok,
+    }
+}
+
+///a hub & transaction of a request's own, so anything sent to Sentry while handling it (errors,
+///panics) says which request it was, and how long it took gets recorded.
+///
+///the transaction's named by what could be told up front, with the `route` tag saying how it
+///turned out - eg. a `page` transaction that was a `static_hit`
+pub struct RequestTelemetry {
+    hub: Arc<Hub>,
+    transaction: sentry::Transaction,
+    class: RouteClass,
+}
+
+impl RequestTelemetry {
+    ///`None` without a Sentry DSN, so nothing at all gets done for it
+    pub fn start<B>(req: &Request<B>, client_ip: IpAddr) -> Option<Self> {
+        let current = Hub::current();
+        if !current.client().is_some_and(|x| x.is_enabled()) {
+            return None;
+        }
+
+        let class = RouteClass::of_request(req);
+        let hub = Arc::new(Hub::new_from_top(current));
+        let transaction = hub.start_transaction(TransactionContext::new(
+            &format!("{} {}", req.method(), class.as_str()),
+            "http.server",
+        ));
+        hub.configure_scope(|scope| {
+            scope.set_tag("method", req.method());
+            scope.set_tag("path", req.uri().path());
+            scope.set_tag("client", hash_ip(client_ip));
+            scope.set_span(Some(transaction.clone().into()));
+        });
+
+        Some(Self {
+            hub,
+            transaction,
+            class,
+        })
+    }
+
+    ///which upload was being served, to tell events from before & after a deploy apart
+    pub fn set_deployed(&self, upload_data_sha256: &str) {
+        self.hub
+            .configure_scope(|scope| scope.set_tag("deployed", upload_data_sha256));
+    }
+
+    pub fn run<F: Future>(&self, fut: F) -> impl Future<Output = F::Output> {
+        fut.bind_hub(self.hub.clone())
+    }
+
+    pub fn finish<B, E>(self, rsp: &Result<Response<B>, E>) {
+        let class = match rsp {
+            Ok(rsp) => {
+                self.transaction.set_status(span_status(rsp.status()));
+                self.hub
+                    .configure_scope(|scope| scope.set_tag("status", rsp.status().as_u16()));
+                self.class.with_response(rsp)
+            }
+            Err(_) => {
+                self.transaction.set_status(SpanStatus::InternalError);
+                self.class
+            }
+        };
+        self.hub
+            .configure_scope(|scope| scope.set_tag("route", class.as_str()));
+
+        //finishing picks the tags up from whichever hub is current
+        let transaction = self.transaction;
+        Hub::run(self.hub, || transaction.finish());
+    }
+}
+
+///[`tokio::task::spawn`], with anything sent to Sentry from the task - like it panicking - saying
+///which task it was
+pub fn spawn_tagged<F>(task: &'static str, fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| scope.set_tag("task", task));
+    tokio::task::spawn(fut.bind_hub(hub))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::{
+        protocol::{Envelope, EnvelopeItem},
+        ClientOptions, Level, Scope, Transport,
+    };
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Captured(Mutex<Vec<Envelope>>);
+
+    impl Transport for Captured {
+        fn send_envelope(&self, envelope: Envelope) {
+            self.0.lock().unwrap().push(envelope);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nothing_is_done_without_a_dsn() {
+        let req = Request::get("/").body(()).unwrap();
+        let no_dsn = Arc::new(Hub::new(None, Arc::new(Scope::default())));
+        let started = Hub::run(no_dsn, || {
+            RequestTelemetry::start(&req, "127.0.0.1".parse().unwrap()).is_some()
+        });
+        assert!(!started);
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_described() {
+        let captured = Arc::new(Captured::default());
+        let client = sentry::Client::from(ClientOptions {
+            dsn: Some("https://public@example.com/1".parse().unwrap()),
+            transport: Some(Arc::new(captured.clone())),
+            traces_sample_rate: 1.0,
+            ..Default::default()
+        });
+        let hub = Arc::new(Hub::new(Some(Arc::new(client)), Arc::new(Scope::default())));
+
+        let req = Request::get("/blog/").body(()).unwrap();
+        let client_ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let telemetry = Hub::run(hub, || RequestTelemetry::start(&req, client_ip)).unwrap();
+        telemetry.set_deployed("abc123");
+        telemetry
+            .run(async { sentry::capture_message("went wrong", Level::Error) })
+            .await;
+        let mut rsp = Response::new(());
+        rsp.extensions_mut().insert(CacheStatus::Hit);
+        telemetry.finish(&Ok::<_, ()>(rsp));
+
+        let envelopes = captured.0.lock().unwrap();
+        let event = envelopes.iter().find_map(|x| x.event()).unwrap();
+        assert_eq!(event.tags["method"], "GET");
+        assert_eq!(event.tags["path"], "/blog/");
+        assert_eq!(event.tags["deployed"], "abc123");
+        assert_eq!(event.tags["client"], hash_ip(client_ip));
+        assert!(!event.tags["client"].contains("203.0.113.7"));
+
+        let transaction = envelopes
+            .iter()
+            .flat_map(|x| x.items())
+            .find_map(|x| match x {
+                EnvelopeItem::Transaction(x) => Some(x),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(transaction.name.as_deref(), Some("GET page"));
+        assert_eq!(transaction.tags["route"], "static_hit");
+        assert_eq!(transaction.tags["status"], "200");
+    }
+
+    #[test]
+    fn test_route_classes() {
+        let class = |method: Method, path: &str| {
+            RouteClass::of_request(
+                &Request::builder()
+                    .method(method)
+                    .uri(path)
+                    .body(())
+                    .unwrap(),
+            )
+        };
+        assert_eq!(class(Method::POST, "/reload"), RouteClass::Webhook);
+        assert_eq!(class(Method::GET, "/reload"), RouteClass::Page);
+        assert_eq!(class(Method::GET, "/__shove/status"), RouteClass::Admin);
+        assert_eq!(class(Method::GET, "/healthcheck"), RouteClass::Admin);
+        assert_eq!(class(Method::GET, "/index.html"), RouteClass::Page);
+
+        let answered = |status: StatusCode, cache_status: Option<CacheStatus>| {
+            let mut rsp = Response::builder().status(status).body(()).unwrap();
+            if let Some(cache_status) = cache_status {
+                rsp.extensions_mut().insert(cache_status);
+            }
+            RouteClass::Page.with_response(&rsp)
+        };
+        assert_eq!(
+            answered(StatusCode::UNAUTHORIZED, None),
+            RouteClass::AuthChallenge
+        );
+        assert_eq!(
+            answered(StatusCode::OK, Some(CacheStatus::Bypass)),
+            RouteClass::StaticMiss
+        );
+        assert_eq!(
+            answered(StatusCode::NOT_FOUND, Some(CacheStatus::NotFound)),
+            RouteClass::NotFound
+        );
+        assert_eq!(answered(StatusCode::BAD_REQUEST, None), RouteClass::Page);
+    }
+}
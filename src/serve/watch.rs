@@ -0,0 +1,56 @@
+use crate::serve::state::State;
+use color_eyre::eyre::bail;
+use notify::{Event, RecursiveMode, Watcher};
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::{channel, Receiver},
+    task::JoinHandle,
+};
+
+///how long to wait for things to settle after a change, as editors and build tools tend to write
+///several files in quick succession
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+///reloads `state` whenever a file that's served from the local directory changes, until something
+///is sent on `stop`
+pub fn watch_local(state: State, mut stop: Receiver<()>) -> color_eyre::Result<JoinHandle<()>> {
+    let Some(local) = state.local().cloned() else {
+        bail!("not serving a local directory");
+    };
+
+    let (send_event, mut recv_event) = channel(64);
+    let watch_local = local.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                if event.paths.iter().any(|x| watch_local.is_served(x)) {
+                    //a full channel means a reload is already coming
+                    let _ = send_event.try_send(());
+                }
+            }
+            Err(e) => error!(?e, "Error watching local directory"),
+        })?;
+    watcher.watch(local.dir(), RecursiveMode::Recursive)?;
+
+    Ok(tokio::task::spawn(async move {
+        //dropping the watcher stops it
+        let _watcher = watcher;
+        loop {
+            tokio::select! {
+                _ = stop.recv() => {
+                    info!("Stop signal received for watcher");
+                    break;
+                },
+                Some(()) = recv_event.recv() => {
+                    tokio::time::sleep(DEBOUNCE).await;
+                    while recv_event.try_recv().is_ok() {}
+
+                    info!("Reloading from local change");
+                    if let Err(e) = state.check_and_reload().await {
+                        error!(?e, "Error reloading state");
+                    }
+                }
+            }
+        }
+    }))
+}
@@ -0,0 +1,225 @@
+use crate::{
+    cache_control::manager::CC_LOCATION,
+    cors::manager::CORS_LOCATION,
+    crawlers::manager::CRAWLERS_LOCATION,
+    headers::manager::HEADERS_LOCATION,
+    ip_filter::manager::IP_FILTER_LOCATION,
+    protect::auth::AUTH_DATA_LOCATION,
+    rate_limit::manager::RATE_LIMIT_LOCATION,
+    s3::{is_reserved_key, SITES_LOCATION, UPLOAD_DATA_LOCATION},
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+///every token a webhook can use to reload. more than one so old and new can overlap while rotating
+#[derive(Clone, Debug)]
+pub struct WebhookTokens(Arc<[Box<str>]>);
+
+impl WebhookTokens {
+    ///`None` if there aren't any tokens in the comma-separated `list`
+    pub fn new(list: &str) -> Option<Self> {
+        let tokens: Arc<[Box<str>]> = list
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(Box::from)
+            .collect();
+
+        if tokens.is_empty() {
+            None
+        } else {
+            Some(Self(tokens))
+        }
+    }
+
+    ///always at least one
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    ///the index of the token that matches `provided`. every token gets compared in constant time,
+    ///so how long this takes doesn't say which (if any) matched
+    pub fn matching(&self, provided: &str) -> Option<usize> {
+        let mut found = None;
+        for (i, token) in self.0.iter().enumerate() {
+            if bool::from(token.as_bytes().ct_eq(provided.as_bytes())) {
+                found = Some(i);
+            }
+        }
+        found
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangedObject {
+    key: String,
+}
+
+///what Tigris sends
+#[derive(Deserialize)]
+struct TigrisEvent {
+    object: ChangedObject,
+}
+
+///what S3 event notifications send
+#[derive(Deserialize)]
+struct S3Record {
+    s3: S3Entity,
+}
+
+#[derive(Deserialize)]
+struct S3Entity {
+    object: ChangedObject,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Tigris {
+        events: Vec<TigrisEvent>,
+    },
+    S3 {
+        #[serde(rename = "Records")]
+        records: Vec<S3Record>,
+    },
+}
+
+///the keys a webhook says have changed, or `None` if we can't tell (so everything should get reloaded)
+pub fn changed_keys(body: &[u8]) -> Option<Vec<String>> {
+    let keys: Vec<String> = match serde_json::from_slice(body).ok()? {
+        Payload::Tigris { events } => events.into_iter().map(|x| x.object.key).collect(),
+        Payload::S3 { records } => records.into_iter().map(|x| x.s3.object.key).collect(),
+    };
+
+    if keys.is_empty() {
+        None
+    } else {
+        Some(keys)
+    }
+}
+
+///what needs reloading because of one changed key
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChangedKey<'a> {
+    Everything,
+    Auth,
+    ///one of a site's managers. `site` is `None` for the site at the top of the bucket
+    SiteConfig {
+        site: Option<&'a str>,
+        file: &'a str,
+    },
+    ///shove's own bookkeeping, which nothing being served comes from
+    Ignored,
+    ///anything else, which might be a file in one of the sites
+    Content(&'a str),
+}
+
+impl<'a> ChangedKey<'a> {
+    pub fn classify(key: &'a str) -> Self {
+        if key == AUTH_DATA_LOCATION {
+            return Self::Auth;
+        }
+        if key == SITES_LOCATION {
+            return Self::Everything;
+        }
+
+        let (site, file) = match key.strip_prefix("sites/").and_then(|x| x.split_once('/')) {
+            Some((site, file)) => (Some(site), file),
+            None => (None, key),
+        };
+        match file {
+            UPLOAD_DATA_LOCATION => Self::Everything,
+            CC_LOCATION | HEADERS_LOCATION | CORS_LOCATION | IP_FILTER_LOCATION
+            | RATE_LIMIT_LOCATION | CRAWLERS_LOCATION => Self::SiteConfig { site, file },
+            _ if is_reserved_key(key) => Self::Ignored,
+            _ => Self::Content(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webhook_tokens() {
+        assert!(WebhookTokens::new("").is_none());
+        assert!(WebhookTokens::new(" , ").is_none());
+
+        let tokens = WebhookTokens::new("old, new").unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens.matching("old"), Some(0));
+        assert_eq!(tokens.matching("new"), Some(1));
+        assert_eq!(tokens.matching("ne"), None);
+        assert_eq!(tokens.matching("old, new"), None);
+    }
+
+    #[test]
+    fn test_tigris_and_s3_payloads() {
+        let tigris = br#"{"events":[{"eventVersion":"1","eventSource":"tigris","eventName":"OBJECT_CREATED_PUT","bucket":"site","object":{"key":"public/index.html","size":10,"eTag":"x"}}]}"#;
+        assert_eq!(
+            changed_keys(tigris),
+            Some(vec!["public/index.html".to_string()])
+        );
+
+        let s3 = br#"{"Records":[{"eventName":"ObjectCreated:Put","s3":{"bucket":{"name":"site"},"object":{"key":"authdata"}}}]}"#;
+        assert_eq!(changed_keys(s3), Some(vec!["authdata".to_string()]));
+
+        assert_eq!(changed_keys(b""), None);
+        assert_eq!(changed_keys(br#"{"events":[]}"#), None);
+        assert_eq!(changed_keys(br#"{"something":"else"}"#), None);
+    }
+
+    #[test]
+    fn test_classify_keys() {
+        assert_eq!(ChangedKey::classify("authdata"), ChangedKey::Auth);
+        assert_eq!(ChangedKey::classify("sites.json"), ChangedKey::Everything);
+        assert_eq!(
+            ChangedKey::classify("upload_data.json"),
+            ChangedKey::Everything
+        );
+        assert_eq!(
+            ChangedKey::classify("sites/example.com/upload_data.json"),
+            ChangedKey::Everything
+        );
+        assert_eq!(
+            ChangedKey::classify("cache_control.json"),
+            ChangedKey::SiteConfig {
+                site: None,
+                file: "cache_control.json"
+            }
+        );
+        assert_eq!(
+            ChangedKey::classify("sites/example.com/cors.json"),
+            ChangedKey::SiteConfig {
+                site: Some("example.com"),
+                file: "cors.json"
+            }
+        );
+        assert_eq!(
+            ChangedKey::classify("rate_limit.json"),
+            ChangedKey::SiteConfig {
+                site: None,
+                file: "rate_limit.json"
+            }
+        );
+        assert_eq!(
+            ChangedKey::classify("public/style.css"),
+            ChangedKey::Content("public/style.css")
+        );
+        assert_eq!(
+            ChangedKey::classify("sites/example.com/a.html"),
+            ChangedKey::Content("sites/example.com/a.html")
+        );
+        assert_eq!(
+            ChangedKey::classify("sites/example.com/pending_deletes.json"),
+            ChangedKey::Ignored
+        );
+        assert_eq!(
+            ChangedKey::classify("snapshots/upload_data-1.json"),
+            ChangedKey::Ignored
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use crate::error::ShoveError;
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use hyper::{body::Bytes, StatusCode};
+use s3::{error::S3Error, Bucket};
+use std::{
+    fmt::{Display, Formatter},
+    path::Path,
+    sync::Arc,
+};
+
+pub mod local;
+#[cfg(test)]
+pub mod memory;
+pub mod prefixed;
+pub mod timeout;
+
+///what's served from - usually the bucket, but a local directory with `serve --local`
+pub type Store = Arc<dyn ObjectStore>;
+///a file's contents, a chunk at a time
+pub type ByteStream = BoxStream<'static, Result<Bytes, crate::serve::BoxError>>;
+
+///what an object at `path` probably is, from its extension, like uploads decide
+pub fn guess_content_type(path: impl AsRef<Path>) -> String {
+    new_mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string()
+}
+
+///an object, and what the store said about it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub bytes: Vec<u8>,
+    pub content_type: Option<String>,
+    ///how the bytes are stored, like `gzip` for pre-compressed uploads
+    pub content_encoding: Option<String>,
+    ///`None` if the store doesn't do them
+    pub etag: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fetched {
+    Found(Object),
+    ///it still has the ETag we asked with
+    NotModified,
+    Missing,
+}
+
+///what a HEAD says about an object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Head {
+    pub content_length: u64,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+}
+
+///why something couldn't be read, as only it not being there means it should be forgotten about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreFailure {
+    Missing,
+    ///the credentials aren't allowed to read it
+    Forbidden,
+    ///5xxs, and anything else that could well work next time
+    Unavailable,
+    ///it didn't answer in time
+    TimedOut,
+}
+
+impl StoreFailure {
+    pub fn of(e: &ShoveError) -> Self {
+        match e {
+            ShoveError::Missing { .. } => Self::Missing,
+            ShoveError::TimedOut { .. } => Self::TimedOut,
+            _ => match e.status() {
+                Some(404) => Self::Missing,
+                Some(401 | 403) => Self::Forbidden,
+                _ => Self::Unavailable,
+            },
+        }
+    }
+
+    ///what to tell a client whose request needed it
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            Self::Missing => StatusCode::NOT_FOUND,
+            Self::Forbidden => StatusCode::BAD_GATEWAY,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+impl Display for StoreFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "missing"),
+            Self::Forbidden => write!(f, "forbidden - check the credentials can read it"),
+            Self::Unavailable => write!(f, "unavailable"),
+            Self::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+///somewhere objects can be read from and written to by key
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    ///with `if_none_match`, [`Fetched::NotModified`] if the object still has that ETag
+    async fn get(&self, key: &str, if_none_match: Option<&str>) -> Result<Fetched, ShoveError>;
+
+    ///`None` if there's nothing at `key`
+    async fn head(&self, key: &str) -> Result<Option<Head>, ShoveError>;
+
+    ///for anything too large to read in all at once
+    async fn stream(&self, key: &str) -> Result<ByteStream, ShoveError>;
+
+    async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<(), ShoveError>;
+
+    ///[`Self::put`], with a `Cache-Control` for anything serving the object straight from the store
+    async fn put_with_cache_control(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        _cache_control: Option<&str>,
+    ) -> Result<(), ShoveError> {
+        self.put(key, bytes, content_type).await
+    }
+
+    ///only writes if `key` still has `etag`, or still doesn't exist for `None`, so read-modify-writes
+    ///from different places can't clobber each other. `false` if someone else got there first
+    async fn put_if_unchanged(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        etag: Option<&str>,
+    ) -> Result<bool, ShoveError>;
+
+    ///doesn't mind if there's nothing there
+    async fn delete(&self, key: &str) -> Result<(), ShoveError>;
+
+    ///copies `from` to `to`, which stores that can should do without the bytes leaving them
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ShoveError> {
+        match self.get(from, None).await? {
+            Fetched::Found(object) => {
+                let content_type = object
+                    .content_type
+                    .unwrap_or_else(|| mime::APPLICATION_OCTET_STREAM.to_string());
+                self.put(to, &object.bytes, &content_type).await
+            }
+            Fetched::NotModified | Fetched::Missing => Err(ShoveError::Missing {
+                key: from.to_string(),
+            }),
+        }
+    }
+
+    ///every key starting with `prefix`
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ShoveError>;
+}
+
+#[async_trait]
+impl ObjectStore for Bucket {
+    async fn get(&self, key: &str, if_none_match: Option<&str>) -> Result<Fetched, ShoveError> {
+        let rsp = match if_none_match {
+            Some(etag) => {
+                let mut conditional = self.clone();
+                conditional.add_header("If-None-Match", etag);
+                conditional.get_object(key).await
+            }
+            None => self.get_object(key).await,
+        };
+
+        match rsp {
+            Ok(rsp) => {
+                let headers = rsp.headers();
+                Ok(Fetched::Found(Object {
+                    content_type: headers.get("content-type").cloned(),
+                    content_encoding: headers.get("content-encoding").cloned(),
+                    etag: headers.get("etag").cloned(),
+                    bytes: rsp.to_vec(),
+                }))
+            }
+            Err(S3Error::HttpFailWithBody(304, _)) => Ok(Fetched::NotModified),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(Fetched::Missing),
+            Err(e) => Err(ShoveError::store(key, e)),
+        }
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<Head>, ShoveError> {
+        match self.head_object(key).await {
+            Ok((_, 404)) | Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Ok((head, _)) => Ok(Some(Head {
+                content_length: head
+                    .content_length
+                    .and_then(|x| u64::try_from(x).ok())
+                    .unwrap_or_default(),
+                content_type: head.content_type,
+                content_encoding: head.content_encoding,
+            })),
+            Err(e) => Err(ShoveError::store(key, e)),
+        }
+    }
+
+    async fn stream(&self, key: &str) -> Result<ByteStream, ShoveError> {
+        let stream = self
+            .get_object_stream(key)
+            .await
+            .map_err(|e| ShoveError::store(key, e))?;
+        Ok(stream.bytes.map_err(crate::serve::BoxError::from).boxed())
+    }
+
+    async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<(), ShoveError> {
+        self.put_object_with_content_type(key, bytes, content_type)
+            .await
+            .map_err(|e| ShoveError::store(key, e))?;
+        Ok(())
+    }
+
+    async fn put_with_cache_control(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        cache_control: Option<&str>,
+    ) -> Result<(), ShoveError> {
+        let Some(cache_control) = cache_control else {
+            return self.put(key, bytes, content_type).await;
+        };
+        let mut with_metadata = self.clone();
+        with_metadata.add_header("Cache-Control", cache_control);
+        with_metadata
+            .put_object_with_content_type(key, bytes, content_type)
+            .await
+            .map_err(|e| ShoveError::store(key, e))?;
+        Ok(())
+    }
+
+    async fn put_if_unchanged(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        etag: Option<&str>,
+    ) -> Result<bool, ShoveError> {
+        let mut conditional = self.clone();
+        match etag {
+            Some(etag) => conditional.add_header("If-Match", etag),
+            None => conditional.add_header("If-None-Match", "*"),
+        }
+
+        match conditional
+            .put_object_with_content_type(key, bytes, content_type)
+            .await
+        {
+            Ok(_) => Ok(true),
+            //409 is what S3 sends if another conditional write is still in flight
+            Err(S3Error::HttpFailWithBody(412 | 409, _)) => Ok(false),
+            Err(e) => Err(ShoveError::store(key, e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ShoveError> {
+        self.delete_object(key)
+            .await
+            .map_err(|e| ShoveError::store(key, e))?;
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ShoveError> {
+        self.copy_object_internal(from, to)
+            .await
+            .map_err(|e| ShoveError::store(from, e))?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ShoveError> {
+        Ok(Bucket::list(self, prefix.to_string(), None)
+            .await
+            .map_err(|e| ShoveError::store(prefix, e))?
+            .into_iter()
+            .flat_map(|x| x.contents)
+            .map(|x| x.key)
+            .collect())
+    }
+}
+
+///so a [`Store`] or a `Box<Bucket>` can be passed wherever a `&dyn ObjectStore` is wanted
+macro_rules! forward_store {
+    ($wrapper:ident) => {
+        #[async_trait]
+        impl<T: ObjectStore + ?Sized> ObjectStore for $wrapper<T> {
+            async fn get(
+                &self,
+                key: &str,
+                if_none_match: Option<&str>,
+            ) -> Result<Fetched, ShoveError> {
+                (**self).get(key, if_none_match).await
+            }
+
+            async fn head(&self, key: &str) -> Result<Option<Head>, ShoveError> {
+                (**self).head(key).await
+            }
+
+            async fn stream(&self, key: &str) -> Result<ByteStream, ShoveError> {
+                (**self).stream(key).await
+            }
+
+            async fn put(
+                &self,
+                key: &str,
+                bytes: &[u8],
+                content_type: &str,
+            ) -> Result<(), ShoveError> {
+                (**self).put(key, bytes, content_type).await
+            }
+
+            async fn put_with_cache_control(
+                &self,
+                key: &str,
+                bytes: &[u8],
+                content_type: &str,
+                cache_control: Option<&str>,
+            ) -> Result<(), ShoveError> {
+                (**self)
+                    .put_with_cache_control(key, bytes, content_type, cache_control)
+                    .await
+            }
+
+            async fn put_if_unchanged(
+                &self,
+                key: &str,
+                bytes: &[u8],
+                content_type: &str,
+                etag: Option<&str>,
+            ) -> Result<bool, ShoveError> {
+                (**self)
+                    .put_if_unchanged(key, bytes, content_type, etag)
+                    .await
+            }
+
+            async fn delete(&self, key: &str) -> Result<(), ShoveError> {
+                (**self).delete(key).await
+            }
+
+            async fn copy(&self, from: &str, to: &str) -> Result<(), ShoveError> {
+                (**self).copy(from, to).await
+            }
+
+            async fn list(&self, prefix: &str) -> Result<Vec<String>, ShoveError> {
+                (**self).list(prefix).await
+            }
+        }
+    };
+}
+
+forward_store!(Box);
+forward_store!(Arc);
@@ -0,0 +1,287 @@
+use crate::{
+    entry_key,
+    error::ShoveError,
+    s3::UPLOAD_DATA_LOCATION,
+    store::{guess_content_type, ByteStream, Fetched, Head, Object, ObjectStore},
+    upload::ignore::IgnoreRules,
+    UploadData,
+};
+use async_trait::async_trait;
+use color_eyre::eyre::bail;
+use futures::{StreamExt, TryStreamExt};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio_util::io::ReaderStream;
+use walkdir::WalkDir;
+
+///the root every file in the directory looks like it was uploaded under
+pub const LOCAL_ROOT: &str = "local";
+
+///a directory on disk, looking like it's just been uploaded. Anything other than the files in it and
+///the upload data is missing, so the managers all fall back to their defaults
+#[derive(Debug, Clone)]
+pub struct LocalDir {
+    dir: PathBuf,
+    ignore: IgnoreRules,
+}
+
+impl LocalDir {
+    ///respects the directory's `.shoveignore`, same as uploading it would
+    pub fn new(dir: impl Into<PathBuf>) -> color_eyre::Result<Self> {
+        let dir = dir.into();
+        if !dir.is_dir() {
+            bail!("{dir:?} isn't a directory");
+        }
+        let Some(dir_str) = dir.to_str() else {
+            bail!("unable to get UTF-8 path");
+        };
+        let ignore = IgnoreRules::for_dir(dir_str, &[])?;
+        Ok(Self { dir, ignore })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    ///`/`-separated from the directory, or `None` if it's the directory itself or isn't UTF-8
+    fn relative(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.dir).ok()?;
+        let parts: Option<Vec<&str>> = relative
+            .components()
+            .map(|x| x.as_os_str().to_str())
+            .collect();
+        let relative = parts?.join("/");
+        (!relative.is_empty()).then_some(relative)
+    }
+
+    ///whether a change to `path` could change what's served
+    pub fn is_served(&self, path: &Path) -> bool {
+        self.relative(path)
+            .is_some_and(|relative| !self.ignore.is_ignored(&relative, path.is_dir()))
+    }
+
+    ///where `key` is on disk, if it's one of the files and doesn't try to escape the directory
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        let relative = key.strip_prefix(LOCAL_ROOT)?.strip_prefix('/')?;
+        let relative = Path::new(relative);
+        if !relative
+            .components()
+            .all(|x| matches!(x, Component::Normal(_)))
+        {
+            return None;
+        }
+        let relative_str = relative.to_str()?;
+        if self.ignore.is_ignored(relative_str, false) {
+            return None;
+        }
+        Some(self.dir.join(relative))
+    }
+
+    ///every file, as its key and something that changes whenever the file does. Reading in everything
+    ///to hash it would make each reload slower the bigger the site is
+    fn entries(&self) -> HashMap<String, String> {
+        WalkDir::new(&self.dir)
+            .into_iter()
+            .filter_entry(|x| match self.relative(x.path()) {
+                Some(relative) => !self.ignore.is_ignored(&relative, x.file_type().is_dir()),
+                None => true,
+            })
+            .filter_map(Result::ok)
+            .filter(|x| x.file_type().is_file())
+            .filter_map(|x| {
+                let relative = self.relative(x.path())?;
+                let metadata = x.metadata().ok()?;
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |x| x.as_nanos());
+                Some((
+                    entry_key(LOCAL_ROOT, &relative),
+                    format!("{}-{modified}", metadata.len()),
+                ))
+            })
+            .collect()
+    }
+
+    fn upload_data(&self) -> UploadData {
+        UploadData {
+            entries: self.entries(),
+            root: LOCAL_ROOT.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalDir {
+    async fn get(&self, key: &str, _if_none_match: Option<&str>) -> Result<Fetched, ShoveError> {
+        if key == UPLOAD_DATA_LOCATION {
+            let this = self.clone();
+            let upload_data = tokio::task::spawn_blocking(move || this.upload_data())
+                .await
+                .map_err(|e| ShoveError::io(&self.dir, e.into()))?;
+            return Ok(Fetched::Found(Object {
+                bytes: serde_json::to_vec(&upload_data)
+                    .map_err(|e| ShoveError::serde(UPLOAD_DATA_LOCATION, e))?,
+                content_type: Some(mime::APPLICATION_JSON.to_string()),
+                content_encoding: None,
+                etag: None,
+            }));
+        }
+
+        let Some(path) = self.path(key) else {
+            return Ok(Fetched::Missing);
+        };
+        match tokio::fs::read(&path).await {
+            Ok(bytes) => Ok(Fetched::Found(Object {
+                bytes,
+                content_type: Some(guess_content_type(&path)),
+                content_encoding: None,
+                etag: None,
+            })),
+            //directories look missing too, as they would in a bucket
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::IsADirectory) => {
+                Ok(Fetched::Missing)
+            }
+            Err(e) => Err(ShoveError::io(path, e)),
+        }
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<Head>, ShoveError> {
+        let Some(path) = self.path(key) else {
+            return Ok(None);
+        };
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(Head {
+                content_length: metadata.len(),
+                content_type: Some(guess_content_type(&path)),
+                content_encoding: None,
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(ShoveError::io(path, e)),
+        }
+    }
+
+    async fn stream(&self, key: &str) -> Result<ByteStream, ShoveError> {
+        let Some(path) = self.path(key) else {
+            return Err(ShoveError::Missing {
+                key: key.to_string(),
+            });
+        };
+        let file = tokio::fs::File::open(&path)
+            .await
+            .map_err(|e| ShoveError::io(path, e))?;
+        Ok(ReaderStream::new(file)
+            .map_err(crate::serve::BoxError::from)
+            .boxed())
+    }
+
+    async fn put(&self, key: &str, _bytes: &[u8], _content_type: &str) -> Result<(), ShoveError> {
+        Err(ShoveError::ReadOnly {
+            key: key.to_string(),
+        })
+    }
+
+    async fn put_if_unchanged(
+        &self,
+        key: &str,
+        _bytes: &[u8],
+        _content_type: &str,
+        _etag: Option<&str>,
+    ) -> Result<bool, ShoveError> {
+        Err(ShoveError::ReadOnly {
+            key: key.to_string(),
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ShoveError> {
+        Err(ShoveError::ReadOnly {
+            key: key.to_string(),
+        })
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ShoveError> {
+        let this = self.clone();
+        let mut keys: Vec<String> = tokio::task::spawn_blocking(move || this.entries())
+            .await
+            .map_err(|e| ShoveError::io(&self.dir, e.into()))?
+            .into_keys()
+            .filter(|x| x.starts_with(prefix))
+            .collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, time::SystemTime};
+
+    #[tokio::test]
+    async fn test_directory_looks_uploaded() {
+        let dir = std::env::temp_dir().join(format!(
+            "shove-local-test-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(dir.join("blog")).unwrap();
+        fs::write(dir.join("index.html"), "<p>hi</p>").unwrap();
+        fs::write(dir.join("blog/post.css"), "p {}").unwrap();
+        fs::write(dir.join("notes.md"), "secret").unwrap();
+        fs::write(dir.join(".shoveignore"), "*.md").unwrap();
+        let local = LocalDir::new(&dir).unwrap();
+
+        let Fetched::Found(upload_data) = local.get(UPLOAD_DATA_LOCATION, None).await.unwrap()
+        else {
+            panic!("no upload data");
+        };
+        let upload_data: UploadData = serde_json::from_slice(&upload_data.bytes).unwrap();
+        let mut keys: Vec<&String> = upload_data.entries.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["local/blog/post.css", "local/index.html"]);
+
+        let Fetched::Found(page) = local.get("local/blog/post.css", None).await.unwrap() else {
+            panic!("no page");
+        };
+        assert_eq!(page.bytes, b"p {}");
+        assert_eq!(page.content_type.as_deref(), Some("text/css"));
+        assert_eq!(
+            local
+                .head("local/index.html")
+                .await
+                .unwrap()
+                .unwrap()
+                .content_length,
+            9
+        );
+
+        for missing in [
+            "local/notes.md",
+            "local/blog",
+            "local/../outside.txt",
+            "local/nope.html",
+            "authdata",
+        ] {
+            assert_eq!(
+                local.get(missing, None).await.unwrap(),
+                Fetched::Missing,
+                "{missing}"
+            );
+        }
+        assert!(matches!(
+            local.put("authdata", b"", "text/plain").await,
+            Err(ShoveError::ReadOnly { key }) if key == "authdata"
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
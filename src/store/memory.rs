@@ -0,0 +1,342 @@
+use crate::{
+    error::ShoveError,
+    hash_raw_bytes,
+    store::{ByteStream, Fetched, Head, Object, ObjectStore},
+    to_hex,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use hyper::body::Bytes;
+use s3::error::S3Error;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+///what an [`InMemoryBucket`] does instead of the usual for a key
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    ///reads act like there's nothing there, like an S3 404
+    Missing,
+    ///everything fails, like an S3 503
+    Unavailable,
+    ///reads fail like an S3 403, as if the credentials can't see it
+    Forbidden,
+    ///reads never come back, like an S3 that's stopped answering
+    Hang,
+    ///reads take this long to come back, like a far away or overloaded S3
+    Slow(Duration),
+}
+
+#[derive(Debug, Clone)]
+struct Stored {
+    bytes: Vec<u8>,
+    ///`None` when put with an empty one, like stores that don't keep them for some uploads
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    cache_control: Option<String>,
+    etag: String,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    objects: HashMap<String, Stored>,
+    faults: HashMap<String, Fault>,
+}
+
+///a bucket in a `HashMap`, so everything that takes an [`ObjectStore`] can be tested without S3.
+///Clones share the same objects
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryBucket {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl InMemoryBucket {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///makes every request for `key` go wrong, until it's [healed](Self::heal)
+    pub async fn fail(&self, key: &str, fault: Fault) {
+        self.inner
+            .lock()
+            .await
+            .faults
+            .insert(key.to_string(), fault);
+    }
+
+    pub async fn heal(&self, key: &str) {
+        self.inner.lock().await.faults.remove(key);
+    }
+
+    ///every key, sorted
+    pub async fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.inner.lock().await.objects.keys().cloned().collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    pub async fn bytes(&self, key: &str) -> Option<Vec<u8>> {
+        self.inner
+            .lock()
+            .await
+            .objects
+            .get(key)
+            .map(|x| x.bytes.clone())
+    }
+
+    pub async fn cache_control(&self, key: &str) -> Option<String> {
+        self.inner
+            .lock()
+            .await
+            .objects
+            .get(key)
+            .and_then(|x| x.cache_control.clone())
+    }
+
+    ///puts `bytes` as already compressed with `content_encoding`, like an upload of pre-compressed
+    ///files with that metadata set
+    pub async fn put_encoded(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        content_encoding: &str,
+    ) -> Result<(), ShoveError> {
+        let stored = Stored {
+            bytes: bytes.to_vec(),
+            content_type: Some(content_type.to_string()).filter(|x| !x.is_empty()),
+            content_encoding: Some(content_encoding.to_string()),
+            cache_control: None,
+            etag: etag(bytes),
+        };
+        self.write(key, Some(stored)).await
+    }
+
+    ///the object at `key`, or `None` if there isn't one or it's meant to look missing
+    async fn read(&self, key: &str) -> Result<Option<Stored>, ShoveError> {
+        let inner = self.inner.lock().await;
+        match inner.faults.get(key) {
+            Some(Fault::Missing) => Ok(None),
+            Some(Fault::Hang) => {
+                drop(inner);
+                std::future::pending().await
+            }
+            Some(Fault::Slow(delay)) => {
+                let delay = *delay;
+                drop(inner);
+                tokio::time::sleep(delay).await;
+                Ok(self.inner.lock().await.objects.get(key).cloned())
+            }
+            Some(Fault::Unavailable) => Err(unavailable(key)),
+            Some(Fault::Forbidden) => Err(ShoveError::store(
+                key,
+                S3Error::HttpFailWithBody(403, "AccessDenied".to_string()),
+            )),
+            None => Ok(inner.objects.get(key).cloned()),
+        }
+    }
+
+    async fn write(&self, key: &str, stored: Option<Stored>) -> Result<(), ShoveError> {
+        let mut inner = self.inner.lock().await;
+        if inner.faults.get(key) == Some(&Fault::Unavailable) {
+            return Err(unavailable(key));
+        }
+        match stored {
+            Some(stored) => inner.objects.insert(key.to_string(), stored),
+            None => inner.objects.remove(key),
+        };
+        Ok(())
+    }
+}
+
+fn unavailable(key: &str) -> ShoveError {
+    ShoveError::store(key, S3Error::HttpFailWithBody(503, "SlowDown".to_string()))
+}
+
+fn etag(bytes: &[u8]) -> String {
+    format!("\"{}\"", to_hex(&hash_raw_bytes(bytes)[..8]))
+}
+
+#[async_trait]
+impl ObjectStore for InMemoryBucket {
+    async fn get(&self, key: &str, if_none_match: Option<&str>) -> Result<Fetched, ShoveError> {
+        Ok(match self.read(key).await? {
+            Some(stored) if if_none_match == Some(stored.etag.as_str()) => Fetched::NotModified,
+            Some(stored) => Fetched::Found(Object {
+                bytes: stored.bytes,
+                content_type: stored.content_type,
+                content_encoding: stored.content_encoding,
+                etag: Some(stored.etag),
+            }),
+            None => Fetched::Missing,
+        })
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<Head>, ShoveError> {
+        Ok(self.read(key).await?.map(|stored| Head {
+            content_length: stored.bytes.len() as u64,
+            content_type: stored.content_type,
+            content_encoding: stored.content_encoding,
+        }))
+    }
+
+    async fn stream(&self, key: &str) -> Result<ByteStream, ShoveError> {
+        let Some(stored) = self.read(key).await? else {
+            return Err(ShoveError::Missing {
+                key: key.to_string(),
+            });
+        };
+        let chunks: Vec<_> = stored
+            .bytes
+            .chunks(1024)
+            .map(|x| Ok(Bytes::copy_from_slice(x)))
+            .collect();
+        Ok(futures::stream::iter(chunks).boxed())
+    }
+
+    async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<(), ShoveError> {
+        self.put_with_cache_control(key, bytes, content_type, None)
+            .await
+    }
+
+    async fn put_with_cache_control(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        cache_control: Option<&str>,
+    ) -> Result<(), ShoveError> {
+        let stored = Stored {
+            bytes: bytes.to_vec(),
+            content_type: Some(content_type.to_string()).filter(|x| !x.is_empty()),
+            content_encoding: None,
+            cache_control: cache_control.map(ToString::to_string),
+            etag: etag(bytes),
+        };
+        self.write(key, Some(stored)).await
+    }
+
+    async fn put_if_unchanged(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        etag: Option<&str>,
+    ) -> Result<bool, ShoveError> {
+        //all under the one lock, so nothing can sneak in between checking and writing
+        let mut inner = self.inner.lock().await;
+        if inner.faults.get(key) == Some(&Fault::Unavailable) {
+            return Err(unavailable(key));
+        }
+        let current = inner.objects.get(key).map(|x| x.etag.as_str());
+        if current != etag {
+            return Ok(false);
+        }
+        let stored = Stored {
+            bytes: bytes.to_vec(),
+            content_type: Some(content_type.to_string()).filter(|x| !x.is_empty()),
+            content_encoding: None,
+            cache_control: None,
+            etag: self::etag(bytes),
+        };
+        inner.objects.insert(key.to_string(), stored);
+        Ok(true)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ShoveError> {
+        self.write(key, None).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ShoveError> {
+        let Some(stored) = self.read(from).await? else {
+            return Err(ShoveError::Missing {
+                key: from.to_string(),
+            });
+        };
+        self.write(to, Some(stored)).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ShoveError> {
+        let mut keys: Vec<String> = self
+            .inner
+            .lock()
+            .await
+            .objects
+            .keys()
+            .filter(|x| x.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        s3::{get_bytes_if_changed, LastFetched},
+        store::StoreFailure,
+    };
+
+    #[tokio::test]
+    async fn test_conditional_gets() {
+        let bucket = InMemoryBucket::new();
+        bucket.put("a", b"one", "text/plain").await.unwrap();
+
+        let mut last = LastFetched::default();
+        assert_eq!(
+            get_bytes_if_changed(&bucket, "a", &mut last).await.unwrap(),
+            Some(b"one".to_vec())
+        );
+        assert_eq!(
+            get_bytes_if_changed(&bucket, "a", &mut last).await.unwrap(),
+            None
+        );
+
+        bucket.put("a", b"two", "text/plain").await.unwrap();
+        assert_eq!(
+            get_bytes_if_changed(&bucket, "a", &mut last).await.unwrap(),
+            Some(b"two".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_faults() {
+        let bucket = InMemoryBucket::new();
+        bucket.put("a", b"one", "text/plain").await.unwrap();
+
+        bucket.fail("a", Fault::Missing).await;
+        assert_eq!(bucket.get("a", None).await.unwrap(), Fetched::Missing);
+        assert_eq!(bucket.head("a").await.unwrap(), None);
+
+        bucket.fail("a", Fault::Unavailable).await;
+        let e = bucket.get("a", None).await.unwrap_err();
+        assert_eq!(e.status(), Some(503));
+        assert_eq!(StoreFailure::of(&e), StoreFailure::Unavailable);
+        assert!(bucket.put("a", b"two", "text/plain").await.is_err());
+
+        bucket.fail("a", Fault::Forbidden).await;
+        let e = bucket.head("a").await.unwrap_err();
+        assert!(
+            matches!(&e, ShoveError::Store { key, .. } if key == "a"),
+            "{e}"
+        );
+        assert_eq!(StoreFailure::of(&e), StoreFailure::Forbidden);
+        assert!(matches!(
+            bucket.stream("missing").await,
+            Err(ShoveError::Missing { .. })
+        ));
+        assert_eq!(
+            StoreFailure::of(&ShoveError::Missing {
+                key: "a".to_string()
+            }),
+            StoreFailure::Missing
+        );
+
+        bucket.heal("a").await;
+        assert_eq!(bucket.bytes("a").await.as_deref(), Some(&b"one"[..]));
+        bucket.copy("a", "b").await.unwrap();
+        bucket.delete("a").await.unwrap();
+        assert_eq!(bucket.keys().await, vec!["b"]);
+    }
+}
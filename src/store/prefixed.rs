@@ -0,0 +1,160 @@
+use crate::{
+    error::ShoveError,
+    store::{ByteStream, Fetched, Head, ObjectStore},
+};
+use async_trait::async_trait;
+
+///another store with every key under `prefix`, so a site can share a bucket with other things.
+///Keys given to & listed from it never include the prefix
+#[derive(Debug, Clone)]
+pub struct Prefixed<S> {
+    inner: S,
+    ///empty, or ends with a `/`
+    prefix: String,
+}
+
+///slashes either side of `prefix` are ignored, so `docs`, `/docs` & `docs/` are all `docs/`
+pub fn normalise_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{prefix}/")
+    }
+}
+
+impl<S> Prefixed<S> {
+    pub fn new(inner: S, prefix: &str) -> Self {
+        Self {
+            inner,
+            prefix: normalise_prefix(prefix),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+}
+
+#[async_trait]
+impl<S: ObjectStore> ObjectStore for Prefixed<S> {
+    async fn get(&self, key: &str, if_none_match: Option<&str>) -> Result<Fetched, ShoveError> {
+        self.inner.get(&self.key(key), if_none_match).await
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<Head>, ShoveError> {
+        self.inner.head(&self.key(key)).await
+    }
+
+    async fn stream(&self, key: &str) -> Result<ByteStream, ShoveError> {
+        self.inner.stream(&self.key(key)).await
+    }
+
+    async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<(), ShoveError> {
+        self.inner.put(&self.key(key), bytes, content_type).await
+    }
+
+    async fn put_with_cache_control(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        cache_control: Option<&str>,
+    ) -> Result<(), ShoveError> {
+        self.inner
+            .put_with_cache_control(&self.key(key), bytes, content_type, cache_control)
+            .await
+    }
+
+    async fn put_if_unchanged(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        etag: Option<&str>,
+    ) -> Result<bool, ShoveError> {
+        self.inner
+            .put_if_unchanged(&self.key(key), bytes, content_type, etag)
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ShoveError> {
+        self.inner.delete(&self.key(key)).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ShoveError> {
+        self.inner.copy(&self.key(from), &self.key(to)).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ShoveError> {
+        Ok(self
+            .inner
+            .list(&self.key(prefix))
+            .await?
+            .into_iter()
+            .filter_map(|x| x.strip_prefix(&self.prefix).map(ToString::to_string))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{s3::UPLOAD_DATA_LOCATION, store::memory::InMemoryBucket};
+
+    #[tokio::test]
+    async fn test_keys_are_under_the_prefix() {
+        let shared = InMemoryBucket::new();
+        let prefixed = Prefixed::new(shared.clone(), "/blog/");
+
+        prefixed
+            .put(UPLOAD_DATA_LOCATION, b"{}", "application/json")
+            .await
+            .unwrap();
+        prefixed
+            .put("public/index.html", b"<p>hi</p>", "text/html")
+            .await
+            .unwrap();
+        prefixed
+            .copy("public/index.html", "public/404.html")
+            .await
+            .unwrap();
+        shared
+            .put("other/index.html", b"not ours", "text/html")
+            .await
+            .unwrap();
+
+        let mut keys = shared.keys().await;
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            vec![
+                "blog/public/404.html",
+                "blog/public/index.html",
+                "blog/upload_data.json",
+                "other/index.html"
+            ]
+        );
+        assert_eq!(
+            prefixed.list("public/").await.unwrap().len(),
+            2,
+            "listed keys should be without the prefix"
+        );
+        assert!(prefixed
+            .list("")
+            .await
+            .unwrap()
+            .iter()
+            .all(|x| !x.starts_with("blog/")));
+
+        let unprefixed = Prefixed::new(shared.clone(), "");
+        assert!(matches!(
+            unprefixed.get("other/index.html", None).await.unwrap(),
+            Fetched::Found(_)
+        ));
+    }
+}
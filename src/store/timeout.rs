@@ -0,0 +1,134 @@
+use crate::{
+    error::ShoveError,
+    store::{ByteStream, Fetched, Head, ObjectStore},
+};
+use async_trait::async_trait;
+use std::{future::Future, time::Duration};
+
+///how long anything asked of the store gets by default
+pub const DEFAULT_STORE_TIMEOUT: Duration = Duration::from_secs(10);
+
+///another store that gives up on anything taking longer than `limit`, so a store that's stopped
+///answering can't hold requests (and their permits) forever. Streams only have to start in time
+#[derive(Debug, Clone)]
+pub struct TimeLimited<S> {
+    inner: S,
+    limit: Duration,
+}
+
+impl<S> TimeLimited<S> {
+    pub fn new(inner: S, limit: Duration) -> Self {
+        Self { inner, limit }
+    }
+
+    async fn limited<T>(
+        &self,
+        key: &str,
+        fut: impl Future<Output = Result<T, ShoveError>>,
+    ) -> Result<T, ShoveError> {
+        match tokio::time::timeout(self.limit, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(ShoveError::TimedOut {
+                key: key.to_string(),
+                after: self.limit,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: ObjectStore> ObjectStore for TimeLimited<S> {
+    async fn get(&self, key: &str, if_none_match: Option<&str>) -> Result<Fetched, ShoveError> {
+        self.limited(key, self.inner.get(key, if_none_match)).await
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<Head>, ShoveError> {
+        self.limited(key, self.inner.head(key)).await
+    }
+
+    async fn stream(&self, key: &str) -> Result<ByteStream, ShoveError> {
+        self.limited(key, self.inner.stream(key)).await
+    }
+
+    async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<(), ShoveError> {
+        self.limited(key, self.inner.put(key, bytes, content_type))
+            .await
+    }
+
+    async fn put_with_cache_control(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        cache_control: Option<&str>,
+    ) -> Result<(), ShoveError> {
+        self.limited(
+            key,
+            self.inner
+                .put_with_cache_control(key, bytes, content_type, cache_control),
+        )
+        .await
+    }
+
+    async fn put_if_unchanged(
+        &self,
+        key: &str,
+        bytes: &[u8],
+        content_type: &str,
+        etag: Option<&str>,
+    ) -> Result<bool, ShoveError> {
+        self.limited(
+            key,
+            self.inner.put_if_unchanged(key, bytes, content_type, etag),
+        )
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ShoveError> {
+        self.limited(key, self.inner.delete(key)).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), ShoveError> {
+        self.limited(from, self.inner.copy(from, to)).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ShoveError> {
+        self.limited(prefix, self.inner.list(prefix)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{
+        memory::{Fault, InMemoryBucket},
+        StoreFailure,
+    };
+    use hyper::StatusCode;
+
+    #[tokio::test]
+    async fn test_hung_reads_time_out() {
+        let bucket = InMemoryBucket::new();
+        bucket.put("a", b"a", "text/plain").await.unwrap();
+        bucket.put("b", b"b", "text/plain").await.unwrap();
+        bucket.fail("b", Fault::Hang).await;
+        let store = TimeLimited::new(bucket, Duration::from_millis(50));
+
+        assert!(matches!(
+            store.get("a", None).await.unwrap(),
+            Fetched::Found(_)
+        ));
+
+        let e = store.get("b", None).await.unwrap_err();
+        assert!(
+            matches!(&e, ShoveError::TimedOut { key, .. } if key == "b"),
+            "{e}"
+        );
+        assert_eq!(StoreFailure::of(&e), StoreFailure::TimedOut);
+        assert_eq!(
+            StoreFailure::of(&e).status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert!(store.stream("b").await.is_err());
+    }
+}
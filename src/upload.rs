@@ -1,47 +1,136 @@
-use crate::{s3::get_bucket, upload::machinery::upload_dir_to_bucket};
+use crate::{
+    config::BucketConfig,
+    releases::request_reload,
+    store::ObjectStore,
+    upload::{
+        machinery::{upload_dirs_to_bucket, Mapping},
+        watch::{watch_dirs, Notify},
+    },
+};
 use color_eyre::{eyre::bail, owo_colors::OwoColorize};
 use std::{env::current_dir, path::PathBuf};
+use tokio_util::sync::CancellationToken;
 
-mod machinery;
+pub mod diff;
+pub mod ignore;
+pub mod machinery;
+mod manifest;
+pub mod pending;
+pub mod progress;
+pub mod watch;
 
-pub async fn upload(dir: &str) -> color_eyre::Result<()> {
-    let mut failed = false;
+pub use machinery::UploadOptions;
 
-    let Ok(dir_path_buffer) = PathBuf::from(&dir).canonicalize() else {
-        bail!("unable to canonicalise {}", "[DIR]".blue());
+///uploads everything in `dir` as the site, or the top of the bucket without a `site`. Returns
+///whether there were any changes to make
+pub async fn upload_dir_to_bucket(
+    bucket: &dyn ObjectStore,
+    dir: impl Into<String>,
+    site: Option<&str>,
+    options: UploadOptions,
+) -> color_eyre::Result<bool> {
+    let mapping = Mapping {
+        dir: dir.into(),
+        prefix: String::new(),
     };
-    if !dir_path_buffer.exists() {
-        eprintln!("unable to find provided {}", "[DIR]".blue());
-        failed = true;
-    }
-    if !dir_path_buffer.is_dir() {
-        eprintln!("provided {} must be a directory", "[DIR]".blue());
-        failed = true;
-    }
-    match current_dir() {
-        Ok(cd) => {
-            if dir_path_buffer.eq(&cd) {
-                eprintln!(
-                    "provided {} must be a different from current directory",
-                    "[DIR]".blue()
-                );
-                failed = true;
-            }
+    upload_dirs_to_bucket(&[mapping], site, bucket, options).await
+}
+
+///with `watch`, keeps uploading whatever changes until Ctrl+C, asking the server to reload each
+///time if there's somewhere to `notify`
+pub async fn upload(
+    config: &BucketConfig,
+    mappings: &[String],
+    site: Option<&str>,
+    options: UploadOptions,
+    watch: bool,
+    notify: Option<Notify>,
+) -> color_eyre::Result<()> {
+    let dry_run = options.dry_run;
+    let mut failed = false;
+
+    let mappings = mappings
+        .iter()
+        .map(|x| Mapping::parse(x))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for Mapping { dir, .. } in &mappings {
+        let Ok(dir_path_buffer) = PathBuf::from(&dir).canonicalize() else {
+            bail!("unable to canonicalise {} {dir:?}", "[DIR]".blue());
+        };
+        if !dir_path_buffer.exists() {
+            eprintln!("unable to find provided {} {dir:?}", "[DIR]".blue());
+            failed = true;
         }
-        Err(e) => {
-            eprintln!("unable to access current directory: {e:?}");
+        if !dir_path_buffer.is_dir() {
+            eprintln!("provided {} {dir:?} must be a directory", "[DIR]".blue());
             failed = true;
         }
+        match current_dir() {
+            Ok(cd) => {
+                if dir_path_buffer.eq(&cd) {
+                    eprintln!(
+                        "provided {} {dir:?} must be a different from current directory",
+                        "[DIR]".blue()
+                    );
+                    failed = true;
+                }
+            }
+            Err(e) => {
+                eprintln!("unable to access current directory: {e:?}");
+                failed = true;
+            }
+        }
+    }
+
+    //ignored files stay where they were, which an atomic upload's new prefix doesn't include
+    if options.atomic && options.keep_ignored {
+        eprintln!(
+            "{} can't be used with {}",
+            "--keep-ignored".blue(),
+            "--atomic".blue()
+        );
+        failed = true;
+    }
+    if watch && dry_run {
+        eprintln!(
+            "{} can't be used with {}",
+            "--dry-run".blue(),
+            "--watch".blue()
+        );
+        failed = true;
     }
 
     if failed {
         std::process::exit(1);
     }
 
-    info!(?dir, "Reading files");
+    info!(?mappings, ?site, "Reading files");
+
+    let bucket = config.bucket()?;
+    if watch {
+        let stop = CancellationToken::new();
+        let ctrl_c = stop.clone();
+        tokio::spawn(async move {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                error!(?e, "Unable to listen for Ctrl+C");
+                return;
+            }
+            info!("Stopping once any upload that's going has finished");
+            ctrl_c.cancel();
+        });
+        return watch_dirs(&mappings, site, &bucket, options, notify, stop).await;
+    }
 
-    let bucket = get_bucket();
-    upload_dir_to_bucket(dir, &bucket).await?;
+    let any_changes = upload_dirs_to_bucket(&mappings, site, &bucket, options).await?;
+
+    if dry_run && any_changes {
+        //non-zero so this can be used as a drift check in CI
+        std::process::exit(2);
+    }
+    if any_changes && let Some(Notify { url, token }) = notify {
+        request_reload(&url, &token, site).await?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,99 @@
+use crate::{
+    config::BucketConfig,
+    upload::{
+        machinery::{diff_dirs, Mapping},
+        UploadOptions,
+    },
+};
+use comfy_table::Table;
+use serde::Serialize;
+
+///one path that's different locally
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    ///where it's served from, eg. `/docs/intro.html`
+    pub path: String,
+    ///the local file's, or for anything removed what was uploaded. `None` if it was uploaded before
+    ///sizes were recorded
+    pub size: Option<u64>,
+    ///what was uploaded, for anything changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploaded_size: Option<u64>,
+}
+
+///how the local directories differ from what was last uploaded, each sorted by path
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Diff {
+    pub added: Vec<Difference>,
+    pub changed: Vec<Difference>,
+    pub removed: Vec<Difference>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    pub(super) fn sort(&mut self) {
+        for differences in [&mut self.added, &mut self.changed, &mut self.removed] {
+            differences.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+    }
+
+    fn print(&self) {
+        let size = |x: Option<u64>| x.map(|x| x.to_string()).unwrap_or_default();
+
+        let mut table = Table::new();
+        table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+        table.set_header(vec!["Change", "Path", "Bytes"]);
+        for (change, differences) in [
+            ("Added", &self.added),
+            ("Changed", &self.changed),
+            ("Removed", &self.removed),
+        ] {
+            for difference in differences {
+                let bytes = match difference.uploaded_size {
+                    Some(uploaded) => format!("{uploaded} -> {}", size(difference.size)),
+                    None => size(difference.size),
+                };
+                table.add_row(vec![change.to_string(), difference.path.clone(), bytes]);
+            }
+        }
+        println!("{table}");
+
+        println!(
+            "{} added, {} changed, {} removed",
+            self.added.len(),
+            self.changed.len(),
+            self.removed.len()
+        );
+    }
+}
+
+///compares the `mappings` to what was last uploaded, printing what's different. Returns whether
+///anything is
+pub async fn diff(
+    config: &BucketConfig,
+    mappings: &[String],
+    site: Option<&str>,
+    options: UploadOptions,
+    json: bool,
+) -> color_eyre::Result<bool> {
+    let mappings = mappings
+        .iter()
+        .map(|x| Mapping::parse(x))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let bucket = config.bucket()?;
+    let diff = diff_dirs(&mappings, site, &bucket, options).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else if diff.is_empty() {
+        println!("Nothing has changed since the last upload.");
+    } else {
+        diff.print();
+    }
+
+    Ok(!diff.is_empty())
+}
@@ -0,0 +1,234 @@
+use crate::upload::manifest::MANIFEST_FILE;
+use regex::Regex;
+use std::path::Path;
+
+///the file in the root of each uploaded directory listing what to leave out
+pub const IGNORE_FILE: &str = ".shoveignore";
+
+///one line of a `.shoveignore`, or one `--exclude`
+#[derive(Debug, Clone)]
+struct Pattern {
+    regex: Regex,
+    ///starts with a `!`, so it un-ignores anything matched
+    negated: bool,
+    ///ends with a `/`, so only matches directories
+    dir_only: bool,
+}
+
+impl Pattern {
+    ///`None` for blank lines & comments
+    fn parse(line: &str) -> color_eyre::Result<Option<Self>> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        //with a slash anywhere but the end, it's relative to the root - otherwise it can match at any depth
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+
+        let mut regex = String::from("^");
+        if !anchored {
+            regex.push_str("(?:.*/)?");
+        }
+        regex.push_str(&glob_to_regex(line));
+        regex.push('$');
+
+        Ok(Some(Self {
+            regex: Regex::new(&regex)?,
+            negated,
+            dir_only,
+        }))
+    }
+}
+
+///gitignore-flavoured globs - `*` and `?` stay within one directory, `**` crosses them
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let mut class = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        closed = true;
+                        break;
+                    }
+                    class.push(c);
+                }
+                if closed && !class.is_empty() {
+                    regex.push('[');
+                    if let Some(rest) = class.strip_prefix('!') {
+                        regex.push('^');
+                        regex.push_str(&rest.replace('\\', "\\\\"));
+                    } else {
+                        regex.push_str(&class.replace('\\', "\\\\"));
+                    }
+                    regex.push(']');
+                } else {
+                    regex.push_str(&regex::escape(&format!("[{class}")));
+                }
+            }
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    regex.push_str(&regex::escape(&escaped.to_string()));
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+///what to leave out of one uploaded directory, from its `.shoveignore` and any `--exclude`s. Hidden
+///files are kept unless something says otherwise, as plenty of sites need `.well-known/`
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreRules {
+    ///later lines win, so `excludes` win over the file
+    pub fn new<'a>(
+        lines: impl IntoIterator<Item = &'a str>,
+        excludes: &[String],
+    ) -> color_eyre::Result<Self> {
+        let mut patterns = vec![];
+        for line in lines {
+            if let Some(pattern) = Pattern::parse(line)? {
+                patterns.push(pattern);
+            }
+        }
+        for exclude in excludes {
+            if let Some(pattern) = Pattern::parse(exclude)? {
+                patterns.push(pattern);
+            }
+        }
+        Ok(Self { patterns })
+    }
+
+    ///reads the `.shoveignore` in `dir`, if there is one
+    pub fn for_dir(dir: &str, excludes: &[String]) -> color_eyre::Result<Self> {
+        let contents = match std::fs::read_to_string(Path::new(dir).join(IGNORE_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Self::new(contents.lines(), excludes)
+    }
+
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            if (is_dir || !pattern.dir_only) && pattern.regex.is_match(relative) {
+                ignored = !pattern.negated;
+            }
+        }
+        ignored
+    }
+
+    ///`relative` is `/`-separated from the uploaded directory, eg. `blog/index.html`. Like git, nothing
+    ///inside an ignored directory can be brought back
+    pub fn is_ignored(&self, relative: &str, is_dir: bool) -> bool {
+        let relative = relative.trim_matches('/');
+        if relative == IGNORE_FILE || relative == MANIFEST_FILE {
+            return true;
+        }
+
+        let mut parent_end = 0;
+        while let Some(slash) = relative[parent_end..].find('/') {
+            let parent = &relative[..(parent_end + slash)];
+            if self.matches(parent, true) {
+                return true;
+            }
+            parent_end += slash + 1;
+        }
+        self.matches(relative, is_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &str) -> IgnoreRules {
+        IgnoreRules::new(lines.lines(), &[]).unwrap()
+    }
+
+    #[test]
+    fn test_gitignore_style_patterns() {
+        let rules = rules(
+            "# editor & OS junk
+.DS_Store
+*.swp
+node_modules/
+/drafts
+docs/**/*.tmp
+build-?.log
+[Tt]humbs.db
+",
+        );
+
+        assert!(rules.is_ignored(".DS_Store", false));
+        assert!(rules.is_ignored("blog/.DS_Store", false));
+        assert!(rules.is_ignored("blog/.index.html.swp", false));
+        assert!(rules.is_ignored("node_modules", true));
+        assert!(rules.is_ignored("node_modules/react/index.js", false));
+        assert!(rules.is_ignored("app/node_modules/x.js", false));
+        assert!(rules.is_ignored("drafts/post.html", false));
+        assert!(rules.is_ignored("docs/a/b/c.tmp", false));
+        assert!(rules.is_ignored("docs/c.tmp", false));
+        assert!(rules.is_ignored("build-1.log", false));
+        assert!(rules.is_ignored("Thumbs.db", false));
+        assert!(rules.is_ignored(IGNORE_FILE, false));
+
+        assert!(!rules.is_ignored("index.html", false));
+        //only directories called node_modules
+        assert!(!rules.is_ignored("node_modules", false));
+        //anchored to the root
+        assert!(!rules.is_ignored("blog/drafts/post.html", false));
+        assert!(!rules.is_ignored("build-10.log", false));
+        assert!(!rules.is_ignored("other/c.tmp", false));
+        //hidden files are fine by default
+        assert!(!rules.is_ignored(".well-known/security.txt", false));
+    }
+
+    #[test]
+    fn test_negation_and_excludes() {
+        assert!(!rules("*.map\n!keep.map").is_ignored("keep.map", false));
+
+        let ignore = IgnoreRules::new(
+            "*.map\n!keep.map\nsecret/\n!secret/but-not-this.html".lines(),
+            &["*.psd".to_string(), "keep.map".to_string()],
+        )
+        .unwrap();
+
+        assert!(ignore.is_ignored("app.js.map", false));
+        assert!(ignore.is_ignored("design.psd", false));
+        //the exclude comes after the negation, so wins
+        assert!(ignore.is_ignored("keep.map", false));
+        //can't un-ignore something in an ignored directory
+        assert!(ignore.is_ignored("secret/but-not-this.html", false));
+    }
+}
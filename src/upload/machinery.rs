@@ -1,167 +1,1475 @@
-use crate::{hash_raw_bytes, s3::UPLOAD_DATA_LOCATION, UploadData};
+use crate::{
+    cache_control::manager::{Caching, Directive},
+    entry_key, entry_path,
+    error::{parse_json, ShoveError},
+    hash_raw_bytes,
+    non_empty_list::NonEmptyList,
+    normalise_unicode,
+    releases::{now_ms, release_root, Releases},
+    s3::{
+        get_bytes_and_etag, get_bytes_or_default, is_reserved_key, site_location, SITES_LOCATION,
+        UPLOAD_DATA_LOCATION,
+    },
+    store::ObjectStore,
+    upload::{
+        diff::{Diff, Difference},
+        ignore::IgnoreRules,
+        manifest::{FileRecord, LocalManifest},
+        pending::PendingDeletes,
+        progress::{fancy, Phase, Summary},
+    },
+    SitesManifest, UploadData,
+};
 use color_eyre::eyre::bail;
-use futures::{stream::FuturesUnordered, StreamExt};
+use comfy_table::Table;
+use futures::{stream, StreamExt};
 use new_mime_guess::MimeGuess;
-use s3::Bucket;
-use serde_json::from_slice;
 use std::{
     collections::{HashMap, HashSet},
     fmt::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 use tokio::{fs::File, io::AsyncReadExt};
 use walkdir::WalkDir;
 
+///how many files get read at once, so big sites don't run out of file descriptors
+const MAX_CONCURRENT_READS: usize = 64;
+///how many files get uploaded at once
+const MAX_CONCURRENT_UPLOADS: usize = 32;
+///how many times a file gets uploaded before giving up on it arriving intact
+const UPLOAD_ATTEMPTS: usize = 3;
+
+///what's recorded in the upload data for `contents`. Not zero-padded, as it never has been, and
+///changing it would mean re-uploading everything
+pub fn entry_hash(contents: &[u8]) -> String {
+    hash_raw_bytes(contents)
+        .into_iter()
+        .fold(String::new(), |mut acc, x| {
+            let _ = write!(acc, "{x:x}");
+            acc
+        })
+}
+
+///a local directory, and where it ends up in the site
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub dir: String,
+    ///either empty for the site root, or starts with a `/` and has no trailing `/`
+    pub prefix: String,
+}
+
+impl Mapping {
+    ///parses `dir` or `dir:/prefix`
+    pub fn parse(arg: &str) -> Result<Self, ShoveError> {
+        let (dir, prefix) = arg.split_once(':').unwrap_or((arg, "/"));
+        if dir.is_empty() {
+            return Err(ShoveError::Config(format!(
+                "missing directory in mapping {arg:?}"
+            )));
+        }
+        if !prefix.starts_with('/') {
+            return Err(ShoveError::Config(format!(
+                "prefix in mapping {arg:?} must start with a `/`"
+            )));
+        }
+
+        Ok(Self {
+            dir: dir.to_string(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+        })
+    }
+
+    ///where a file from this mapping lives in the site, eg. `/docs/intro.html`
+    fn site_path(&self, file: &Path) -> Option<String> {
+        let site_path = format!("{}/{}", self.prefix, self.relative_path(file)?);
+        Some(normalise_unicode(&site_path))
+    }
+
+    ///`/`-separated from the mapped directory, eg. `intro.html`
+    fn relative_path(&self, file: &Path) -> Option<String> {
+        let relative = file.strip_prefix(&self.dir).ok()?;
+        let components = relative
+            .components()
+            .map(|x| x.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()?;
+        Some(components.join("/"))
+    }
+}
+
+///a lone directory mounted at the root keeps its directory as the root, so that sites uploaded
+///before mappings existed don't get moved around. Anything else is rooted at the site root
+pub fn storage_root(mappings: &[Mapping]) -> String {
+    match mappings {
+        [only] if only.prefix.is_empty() => only.dir.clone(),
+        _ => String::new(),
+    }
+}
+
+///puts a site's root under its directory in the bucket, if it's got one
+pub fn site_root(site: Option<&str>, storage_root: &str) -> String {
+    match site {
+        Some(_) => site_location(site, storage_root)
+            .trim_end_matches('/')
+            .to_string(),
+        None => storage_root.to_string(),
+    }
+}
+
 struct Entry {
+    ///the key in the bucket
     path: String,
-    contents: Vec<u8>,
+    ///where it's served from, eg. `/docs/intro.html`
+    site_path: String,
+    ///the local file this came from
+    source: String,
+    size: u64,
+    ///`None` if the local manifest showed it hadn't changed, in which case it gets read if it's needed
+    contents: Option<Vec<u8>>,
     hash: String,
     mime_guess: MimeGuess,
+    ///set on the object, if there's any cache-control to set and we're not skipping metadata
+    cache_control: Option<String>,
 }
 
-pub async fn upload_dir_to_bucket(dir: &str, bucket: &Bucket) -> color_eyre::Result<()> {
-    async fn read_fs_file(pb: PathBuf) -> color_eyre::Result<Entry> {
-        let Some(path) = pb.to_str().map(|x| x.to_string()) else {
-            bail!("unable to get UTF-8 path")
-        };
-
-        trace!(?pb, "Reading file");
-
-        let contents: Vec<u8> = {
-            let mut file = File::open(&pb).await?;
-            let mut contents = vec![];
-            let mut tmp = [0_u8; 1024];
-            loop {
-                match file.read(&mut tmp).await? {
-                    0 => break,
-                    n => {
-                        contents.extend(&tmp[0..n]);
-                    }
-                }
-            }
+///a file found while walking the mapped directories
+struct FoundFile {
+    pb: PathBuf,
+    ///the key in the bucket
+    path: String,
+    site_path: String,
+    ///which mapping it came from
+    mapping: usize,
+    ///from the mapped directory
+    relative: String,
+}
 
-            contents
-        };
+///what to write to a mapping's local manifest once the upload's done
+struct ManifestUpdate {
+    mapping: usize,
+    relative: String,
+    record: FileRecord,
+}
 
-        let mime_guess = new_mime_guess::from_path(&pb);
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct UploadOptions {
+    ///print the changes rather than making them
+    pub dry_run: bool,
+    ///don't set `Cache-Control` on the objects
+    pub skip_metadata: bool,
+    ///extra patterns to ignore, on top of each directory's `.shoveignore`
+    pub excludes: Vec<String>,
+    ///leave previously uploaded files that are now ignored in the bucket
+    pub keep_ignored: bool,
+    ///hash every file rather than trusting the local manifests, and don't write them
+    pub no_manifest: bool,
+    ///upload everything under a new prefix, so the site only changes once it's all there
+    pub atomic: bool,
+    ///no progress bars or summary, only logs
+    pub quiet: bool,
+    ///how long files that are no longer part of the site stay in the bucket, for servers that haven't
+    ///seen the new upload data yet. `None` deletes them straight away
+    pub delete_after: Option<Duration>,
+}
 
-        let hash = hash_raw_bytes(&contents)
-            .into_iter()
-            .try_fold(String::new(), |mut acc, x| {
-                write!(acc, "{x:x}").map(|()| acc)
-            })?;
+async fn read_contents(pb: &Path) -> Result<Vec<u8>, ShoveError> {
+    let mut file = File::open(pb).await.map_err(|e| ShoveError::io(pb, e))?;
+    let mut contents = vec![];
+    let mut tmp = [0_u8; 1024];
+    loop {
+        match file
+            .read(&mut tmp)
+            .await
+            .map_err(|e| ShoveError::io(pb, e))?
+        {
+            0 => break,
+            n => {
+                contents.extend(&tmp[0..n]);
+            }
+        }
+    }
 
-        trace!(len=?contents.len(), ?pb, "Read file");
+    Ok(contents)
+}
 
-        Ok(Entry {
-            path,
-            contents,
-            hash,
-            mime_guess,
-        })
-    }
+///uploads the union of all the `mappings` as the site. Returns whether there were any changes to make.
+///See [`UploadOptions`] for the rest
+pub async fn upload_dirs_to_bucket(
+    mappings: &[Mapping],
+    site: Option<&str>,
+    bucket: &dyn ObjectStore,
+    options: UploadOptions,
+) -> color_eyre::Result<bool> {
     async fn write_file_to_bucket(
-        bucket: &Bucket,
+        bucket: &dyn ObjectStore,
         Entry {
             path,
+            site_path: _,
+            source,
+            size: _,
             contents,
             hash: _,
             mime_guess,
+            cache_control,
         }: Entry,
-    ) -> color_eyre::Result<()> {
+    ) -> Result<u64, ShoveError> {
+        let contents = match contents {
+            Some(contents) => contents,
+            None => read_contents(Path::new(&source)).await?,
+        };
         let content_type = mime_guess.first_or_octet_stream();
-        let rsp = bucket
-            .put_object_with_content_type(&path, &contents, content_type.essence_str())
-            .await?;
+        let expected_length = contents.len() as u64;
 
-        info!(?path, ?content_type, code=%rsp.status_code(), "Uploaded to S3");
+        //a proxy in the way can cut the body short and still say it all went fine
+        for attempt in 1..=UPLOAD_ATTEMPTS {
+            bucket
+                .put_with_cache_control(
+                    &path,
+                    &contents,
+                    content_type.essence_str(),
+                    cache_control.as_deref(),
+                )
+                .await?;
 
-        Ok(())
+            let uploaded_length = bucket.head(&path).await?.map(|x| x.content_length);
+            if uploaded_length == Some(expected_length) {
+                debug!(?path, ?content_type, ?cache_control, "Uploaded to S3");
+                return Ok(expected_length);
+            }
+            warn!(?path, %attempt, ?uploaded_length, %expected_length, "Uploaded object is the wrong size");
+        }
+
+        Err(ShoveError::Invalid(format!(
+            "{path:?} kept arriving in the bucket the wrong size after {UPLOAD_ATTEMPTS} attempts"
+        )))
     }
+    ///copies `from` within the bucket rather than uploading the bytes again, as long as it's the same
+    ///size. Returns how many bytes had to be uploaded instead, if it didn't get copied
+    async fn copy_file_in_bucket(
+        bucket: &dyn ObjectStore,
+        entry: Entry,
+        from: String,
+    ) -> Result<Option<u64>, ShoveError> {
+        let existing_length = bucket.head(&from).await?.map(|x| x.content_length);
+        if existing_length != Some(entry.size) {
+            warn!(?from, to=?entry.path, ?existing_length, "Sizes didn't match, so uploading instead of copying");
+            let uploaded = write_file_to_bucket(bucket, entry).await?;
+            return Ok(Some(uploaded));
+        }
 
-    async fn get_upload_data(bucket: &Bucket) -> color_eyre::Result<Option<UploadData>> {
-        let Ok(data) = bucket.get_object(UPLOAD_DATA_LOCATION).await else {
-            return Ok(None);
-        };
-        let bytes = data.bytes();
-        Ok(from_slice(bytes)?)
+        bucket.copy(&from, &entry.path).await?;
+        debug!(?from, to=?entry.path, "Copied within S3");
+
+        Ok(None)
     }
 
-    let UploadData {
-        root,
-        entries: existing_entries,
-    } = get_upload_data(bucket).await?.unwrap_or_default();
+    ///makes sure the server knows to look for this site
+    async fn register_site(bucket: &dyn ObjectStore, site: &str) -> Result<(), ShoveError> {
+        let bytes = get_bytes_or_default(bucket, SITES_LOCATION).await?;
+        let mut manifest: SitesManifest = if bytes.is_empty() {
+            SitesManifest::default()
+        } else {
+            parse_json(SITES_LOCATION, &bytes)?
+        };
 
-    info!("Reading files");
-    let mut futures: FuturesUnordered<_> = WalkDir::new(dir)
-        .into_iter()
-        .filter_map(|x| x.ok().filter(|x| x.path().is_file()))
-        .map(|item| read_fs_file(item.path().to_path_buf()))
-        .collect();
+        if manifest.hosts.insert(site.to_string()) {
+            let bytes =
+                serde_json::to_vec(&manifest).map_err(|e| ShoveError::serde(SITES_LOCATION, e))?;
+            bucket
+                .put(SITES_LOCATION, &bytes, mime::JSON.as_str())
+                .await?;
+            info!(?site, "Registered new site");
+        }
 
-    let mut to_write = vec![];
-    let mut to_delete: HashSet<_> = existing_entries.keys().collect();
-    let mut entries = HashMap::new();
+        Ok(())
+    }
 
-    if dir == root {
-        while let Some(entry) = futures.next().await {
-            let entry = entry?;
+    let UploadOptions {
+        dry_run,
+        no_manifest,
+        atomic,
+        quiet,
+        delete_after,
+        ..
+    } = options;
+    let started = Instant::now();
+    let fancy = fancy(quiet);
 
-            to_delete.remove(&entry.path);
+    let upload_data_location = site_location(site, UPLOAD_DATA_LOCATION);
+    let existing = get_upload_data(bucket, &upload_data_location)
+        .await?
+        .unwrap_or_default();
+    let root = if atomic {
+        release_root(site, now_ms())
+    } else {
+        site_root(site, &storage_root(mappings))
+    };
 
-            match existing_entries.get(&entry.path) {
-                None => {
-                    entries.insert(entry.path.clone(), entry.hash.clone());
-                    to_write.push(entry);
-                }
-                Some(x) => {
-                    entries.insert(entry.path.clone(), entry.hash.clone());
-                    if x != &entry.hash {
-                        to_write.push(entry);
-                    } else {
-                        trace!(pb=?entry.path, "Skipping upload");
-                    }
-                }
-            }
+    let (plan, manifest_updates) =
+        plan_dirs(mappings, site, bucket, &existing, &root, &options).await?;
+    let any_changes = plan.has_changes();
+    let UploadPlan {
+        new,
+        changed,
+        metadata_changed,
+        copied,
+        unchanged,
+        deleted,
+    } = plan;
+
+    if dry_run {
+        let mut table = Table::new();
+        table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+        table.set_header(vec!["Change", "Path", "Source", "Bytes"]);
+
+        for entry in &new {
+            table.add_row(vec![
+                "New".to_string(),
+                entry.path.clone(),
+                entry.source.clone(),
+                entry.size.to_string(),
+            ]);
         }
-    } else {
-        while let Some(entry) = futures.next().await {
-            let entry = entry?;
+        for entry in &changed {
+            table.add_row(vec![
+                "Changed".to_string(),
+                entry.path.clone(),
+                entry.source.clone(),
+                entry.size.to_string(),
+            ]);
+        }
+        for entry in &metadata_changed {
+            table.add_row(vec![
+                "Cache-Control".to_string(),
+                entry.path.clone(),
+                entry.source.clone(),
+                entry.size.to_string(),
+            ]);
+        }
+        for (entry, from) in &copied {
+            table.add_row(vec![
+                "Copied".to_string(),
+                entry.path.clone(),
+                format!("{} (already at {from})", entry.source),
+                entry.size.to_string(),
+            ]);
+        }
+        for path in &deleted {
+            table.add_row(vec![
+                "Deleted".to_string(),
+                path.clone(),
+                String::new(),
+                String::new(),
+            ]);
+        }
+
+        println!("{table}");
+
+        let new_bytes: u64 = new.iter().map(|x| x.size).sum();
+        let changed_bytes: u64 = changed.iter().map(|x| x.size).sum();
+        println!(
+            "{} new ({new_bytes} bytes), {} changed ({changed_bytes} bytes), {} with new cache-control, {} copied instead of uploaded, {} deleted, {} unchanged",
+            new.len(),
+            changed.len(),
+            metadata_changed.len(),
+            copied.len(),
+            deleted.len(),
+            unchanged.len()
+        );
+
+        return Ok(any_changes);
+    }
 
-            entries.insert(entry.path.clone(), entry.hash.clone());
-            to_write.push(entry);
+    //objects that aren't re-uploaded keep whatever they had
+    let mut cache_control: HashMap<String, String> = unchanged
+        .iter()
+        .filter_map(|(path, _)| {
+            existing
+                .cache_control
+                .get(path)
+                .map(|cc| (path.clone(), cc.clone()))
+        })
+        .collect();
+    let skipped = unchanged.len();
+    let mut sizes: HashMap<String, u64> = unchanged
+        .iter()
+        .filter_map(|(path, _)| existing.sizes.get(path).map(|size| (path.clone(), *size)))
+        .collect();
+    let mut entries: HashMap<_, _> = unchanged.into_iter().collect();
+    for entry in new
+        .iter()
+        .chain(&changed)
+        .chain(&metadata_changed)
+        .chain(copied.iter().map(|(entry, _)| entry))
+    {
+        entries.insert(entry.path.clone(), entry.hash.clone());
+        sizes.insert(entry.path.clone(), entry.size);
+        if let Some(cc) = &entry.cache_control {
+            cache_control.insert(entry.path.clone(), cc.clone());
         }
     }
 
-    info!("Read all files");
+    let mut releases = Releases::load(bucket, site).await?;
+    releases
+        .preserve(
+            bucket,
+            &existing,
+            new.iter()
+                .chain(&changed)
+                .chain(copied.iter().map(|(entry, _)| entry))
+                .map(|entry| (entry.path.as_str(), entry.hash.as_str())),
+        )
+        .await?;
 
-    let mut futures: FuturesUnordered<_> = to_write
+    let mut summary = Summary {
+        skipped,
+        ..Default::default()
+    };
+
+    let to_upload: Vec<Entry> = new
         .into_iter()
-        .map(|e| write_file_to_bucket(bucket, e))
+        .chain(changed)
+        .chain(metadata_changed)
         .collect();
+    let uploading = Phase::uploading(to_upload.iter().map(|x| x.size).sum(), fancy);
+    let mut futures = stream::iter(to_upload)
+        .map(|e| write_file_to_bucket(bucket, e))
+        .buffer_unordered(MAX_CONCURRENT_UPLOADS);
     while let Some(res) = futures.next().await {
-        res?;
+        let bytes = res?;
+        uploading.uploaded(bytes);
+        summary.uploaded += 1;
+        summary.bytes += bytes;
     }
+    uploading.finish();
 
-    info!("Uploaded files to S3");
+    let mut futures = stream::iter(copied)
+        .map(|(e, from)| copy_file_in_bucket(bucket, e, from))
+        .buffer_unordered(MAX_CONCURRENT_UPLOADS);
+    while let Some(res) = futures.next().await {
+        match res? {
+            Some(bytes) => {
+                summary.uploaded += 1;
+                summary.bytes += bytes;
+            }
+            None => summary.copied += 1,
+        }
+    }
+
+    info!(uploads_avoided=%summary.copied, "Uploaded files to S3, copying any that were already there");
 
     let upload_data = UploadData {
         entries,
-        root: dir.to_string(),
+        root,
+        cache_control,
+        sizes,
     };
     let json_upload_data = serde_json::to_vec(&upload_data)?;
     bucket
-        .put_object_with_content_type(UPLOAD_DATA_LOCATION, &json_upload_data, mime::JSON.as_str())
+        .put(
+            &upload_data_location,
+            &json_upload_data,
+            mime::JSON.as_str(),
+        )
         .await?;
 
     info!("Uploaded object data to S3");
 
-    for path in to_delete {
-        info!(?path, "Deleting old file");
-        bucket.delete_object(path).await?;
+    if let Some(site) = site {
+        register_site(bucket, site).await?;
+    }
+
+    //anything a release we can roll back to uses has to stay
+    let needed: HashSet<String> = releases.needed(&upload_data).into_iter().cloned().collect();
+    let garbage = releases.record(bucket, &upload_data, deleted).await?;
+    let mut pending = PendingDeletes::load(bucket, site).await?;
+    let before = pending.clone();
+    match delete_after {
+        Some(delete_after) => {
+            summary.pending = garbage.len();
+            pending.add(garbage, now_ms(), delete_after);
+        }
+        None => {
+            for path in garbage {
+                info!(?path, "Deleting old file");
+                bucket.delete(&path).await?;
+                summary.deleted += 1;
+            }
+        }
+    }
+    //whatever earlier uploads left behind that's now due
+    summary.deleted += pending
+        .collect(bucket, &needed.iter().collect(), false)
+        .await?;
+    if pending != before {
+        pending.save(bucket, site).await?;
+    }
+
+    info!(deleted=%summary.deleted, pending=%pending.len(), "Deleted old files from S3");
+
+    if !no_manifest {
+        let mut updated = vec![LocalManifest::default(); mappings.len()];
+        for ManifestUpdate {
+            mapping,
+            relative,
+            record,
+        } in manifest_updates
+        {
+            updated[mapping].insert(relative, record);
+        }
+        for (mapping, manifest) in mappings.iter().zip(updated) {
+            //only ever a shortcut, so not worth failing the upload over
+            if let Err(e) = manifest.save(&mapping.dir) {
+                warn!(dir=?mapping.dir, ?e, "Unable to save local manifest");
+            }
+        }
+    }
+
+    summary.elapsed = started.elapsed();
+    summary.report(quiet);
+
+    Ok(any_changes)
+}
+
+async fn read_fs_file(
+    file: FoundFile,
+    manifest: Option<&LocalManifest>,
+    existing_hash: Option<&str>,
+) -> Result<(Entry, Option<ManifestUpdate>), ShoveError> {
+    let FoundFile {
+        pb,
+        path,
+        site_path,
+        mapping,
+        relative,
+    } = file;
+    let Some(source) = pb.to_str().map(|x| x.to_string()) else {
+        return Err(ShoveError::Config(format!(
+            "unable to get UTF-8 path for {pb:?}"
+        )));
+    };
+    let metadata = tokio::fs::metadata(&pb)
+        .await
+        .map_err(|e| ShoveError::io(&pb, e))?;
+    let mime_guess = new_mime_guess::from_path(&pb);
+
+    //only trusted if the bucket agrees, otherwise it's worth hashing to find out what's changed
+    let trusted_hash = manifest
+        .and_then(|x| x.unchanged_hash(&relative, &metadata))
+        .filter(|hash| Some(*hash) == existing_hash)
+        .map(ToString::to_string);
+
+    let (contents, hash) = match trusted_hash {
+        Some(hash) => {
+            trace!(?pb, "Skipping hashing unchanged file");
+            (None, hash)
+        }
+        None => {
+            trace!(?pb, "Reading file");
+            let contents = read_contents(&pb).await?;
+            let hash = entry_hash(&contents);
+            trace!(len=?contents.len(), ?pb, "Read file");
+            (Some(contents), hash)
+        }
+    };
+
+    let update = FileRecord::new(&metadata, hash.clone()).map(|record| ManifestUpdate {
+        mapping,
+        relative,
+        record,
+    });
+
+    Ok((
+        Entry {
+            path,
+            site_path,
+            source,
+            size: metadata.len(),
+            contents,
+            hash,
+            mime_guess,
+            cache_control: None,
+        },
+        update,
+    ))
+}
+
+async fn get_upload_data(
+    bucket: &dyn ObjectStore,
+    location: &str,
+) -> Result<Option<UploadData>, ShoveError> {
+    let (bytes, _) = get_bytes_and_etag(bucket, location).await?;
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    parse_json(location, &bytes)
+}
+
+///how the `mappings` differ from what was last uploaded for the site, going by contents alone
+pub async fn diff_dirs(
+    mappings: &[Mapping],
+    site: Option<&str>,
+    bucket: &dyn ObjectStore,
+    options: UploadOptions,
+) -> color_eyre::Result<Diff> {
+    let existing = get_upload_data(bucket, &site_location(site, UPLOAD_DATA_LOCATION))
+        .await?
+        .unwrap_or_default();
+    //compared where it was uploaded, so an atomic upload's moved root isn't everything changing
+    let root = if existing.entries.is_empty() {
+        site_root(site, &storage_root(mappings))
+    } else {
+        existing.root.clone()
+    };
+    let options = UploadOptions {
+        skip_metadata: true,
+        ..options
+    };
+
+    let (plan, _) = plan_dirs(mappings, site, bucket, &existing, &root, &options).await?;
+    let added = |entry: &Entry| Difference {
+        path: entry.site_path.clone(),
+        size: Some(entry.size),
+        uploaded_size: None,
+    };
+    let mut diff = Diff {
+        added: plan
+            .new
+            .iter()
+            .chain(plan.copied.iter().map(|(entry, _)| entry))
+            .map(added)
+            .collect(),
+        changed: plan
+            .changed
+            .iter()
+            .chain(&plan.metadata_changed)
+            .map(|entry| Difference {
+                uploaded_size: existing.sizes.get(&entry.path).copied(),
+                ..added(entry)
+            })
+            .collect(),
+        removed: plan
+            .deleted
+            .iter()
+            .map(|key| Difference {
+                path: entry_path(&existing.root, key).unwrap_or_else(|| key.clone()),
+                size: existing.sizes.get(key).copied(),
+                uploaded_size: None,
+            })
+            .collect(),
+    };
+    diff.sort();
+    Ok(diff)
+}
+
+///walks & hashes the `mappings` as they'd be uploaded at `root`, and works out what it'd take to get
+///there from `existing`. Uploads, dry runs & diffs all start here, so they can't disagree
+async fn plan_dirs(
+    mappings: &[Mapping],
+    site: Option<&str>,
+    bucket: &dyn ObjectStore,
+    existing: &UploadData,
+    root: &str,
+    UploadOptions {
+        skip_metadata,
+        excludes,
+        keep_ignored,
+        no_manifest,
+        quiet,
+        ..
+    }: &UploadOptions,
+) -> color_eyre::Result<(UploadPlan, Vec<ManifestUpdate>)> {
+    let ignores = mappings
+        .iter()
+        .map(|mapping| IgnoreRules::for_dir(&mapping.dir, excludes))
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+    let manifests: Vec<Option<LocalManifest>> = mappings
+        .iter()
+        .map(|mapping| (!no_manifest).then(|| LocalManifest::load(&mapping.dir)))
+        .collect();
+
+    info!("Reading files");
+    let mut found = vec![];
+    for (index, (mapping, ignore)) in mappings.iter().zip(&ignores).enumerate() {
+        for item in WalkDir::new(&mapping.dir)
+            .into_iter()
+            //skipping whole directories means not even walking eg. `node_modules`
+            .filter_entry(|x| match mapping.relative_path(x.path()) {
+                Some(relative) if !relative.is_empty() => {
+                    let ignored = ignore.is_ignored(&relative, x.file_type().is_dir());
+                    if ignored {
+                        trace!(?relative, "Ignoring");
+                    }
+                    !ignored
+                }
+                _ => true,
+            })
+            .filter_map(|x| x.ok().filter(|x| x.path().is_file()))
+        {
+            let pb = item.path().to_path_buf();
+            let (Some(site_path), Some(relative)) =
+                (mapping.site_path(&pb), mapping.relative_path(&pb))
+            else {
+                bail!("unable to work out where {pb:?} goes in the site");
+            };
+            found.push(FoundFile {
+                path: entry_key(root, &site_path),
+                pb,
+                site_path,
+                mapping: index,
+                relative,
+            });
+        }
+    }
+
+    let hashing = Phase::hashing(found.len() as u64, fancy(*quiet));
+    let mut reads = stream::iter(found)
+        .map(|file| {
+            let manifest = manifests[file.mapping].as_ref();
+            //wherever it was last time, so moving the root doesn't mean hashing everything again
+            let existing_hash = existing
+                .entries
+                .get(&entry_key(&existing.root, &file.site_path))
+                .map(String::as_str);
+            read_fs_file(file, manifest, existing_hash)
+        })
+        .buffer_unordered(MAX_CONCURRENT_READS);
+
+    let mut local = vec![];
+    let mut manifest_updates = vec![];
+    while let Some(res) = reads.next().await {
+        let (entry, update) = res?;
+        hashing.hashed(entry.contents.as_ref().map_or(0, |x| x.len() as u64));
+        local.push(entry);
+        manifest_updates.extend(update);
     }
+    drop(reads);
+    hashing.finish();
 
-    info!("Deleted old files from S3");
+    let hashed = local.iter().filter(|x| x.contents.is_some()).count();
+    info!(files=%local.len(), %hashed, "Read all files");
 
-    Ok(())
+    if !skip_metadata {
+        let (caching, _) = Caching::new(bucket, site).await?;
+        for entry in &mut local {
+            entry.cache_control = cache_control_for(&caching, &entry.site_path);
+        }
+    }
+
+    let mut plan = plan_upload(existing, root, local, !skip_metadata)?;
+    if *keep_ignored {
+        let ignored_mappings: Vec<(&Mapping, &IgnoreRules)> =
+            mappings.iter().zip(&ignores).collect();
+        keep_ignored_files(&mut plan, existing, root, &ignored_mappings);
+    }
+
+    Ok((plan, manifest_updates))
+}
+
+///stops files that are only missing because they're ignored from getting deleted
+fn keep_ignored_files(
+    plan: &mut UploadPlan,
+    existing: &UploadData,
+    root: &str,
+    mappings: &[(&Mapping, &IgnoreRules)],
+) {
+    //if the root moved, the old keys aren't where this upload would put them anyway
+    if existing.root != root {
+        return;
+    }
+
+    plan.deleted.retain(|key| {
+        let Some(site_path) = entry_path(root, key) else {
+            return true;
+        };
+        //the most specific mapping is the one the file would've come from
+        let from = mappings
+            .iter()
+            .filter_map(|(mapping, ignore)| {
+                let relative = site_path.strip_prefix(&mapping.prefix)?.strip_prefix('/')?;
+                Some((mapping.prefix.len(), relative, ignore))
+            })
+            .max_by_key(|(prefix_len, _, _)| *prefix_len);
+
+        match from {
+            Some((_, relative, ignore)) if ignore.is_ignored(relative, false) => {
+                if let Some(hash) = existing.entries.get(key) {
+                    trace!(?key, "Keeping ignored file");
+                    plan.unchanged.push((key.clone(), hash.clone()));
+                }
+                false
+            }
+            _ => true,
+        }
+    });
+}
+
+///the same `Cache-Control` the server would send for `site_path`, going by the stored rules
+fn cache_control_for(caching: &Caching, site_path: &str) -> Option<String> {
+    NonEmptyList::new(caching.get_cache_control_directives(site_path))
+        .map(Directive::directives_to_header)
+}
+
+struct UploadPlan {
+    new: Vec<Entry>,
+    changed: Vec<Entry>,
+    ///the same contents, but they need different `Cache-Control` metadata
+    metadata_changed: Vec<Entry>,
+    ///new paths whose contents are already in the bucket at another key, which they can be copied from
+    copied: Vec<(Entry, String)>,
+    ///path and hash of entries that are already in the bucket
+    unchanged: Vec<(String, String)>,
+    deleted: Vec<String>,
+}
+
+impl UploadPlan {
+    fn has_changes(&self) -> bool {
+        !(self.new.is_empty()
+            && self.changed.is_empty()
+            && self.metadata_changed.is_empty()
+            && self.copied.is_empty()
+            && self.deleted.is_empty())
+    }
+}
+
+///works out what needs doing to get the bucket from `existing` to `local`. Fails if two local files
+///would end up at the same key. With `check_metadata`, unchanged files whose `Cache-Control` would be
+///different from last time get uploaded again. New files that are already in the bucket under another
+///key, eg. from a rename, get copied from there instead. Nothing gets uploaded to or deleted from a
+///[reserved key](is_reserved_key)
+fn plan_upload(
+    existing: &UploadData,
+    root: &str,
+    mut local: Vec<Entry>,
+    check_metadata: bool,
+) -> Result<UploadPlan, ShoveError> {
+    local.retain(|entry| {
+        let reserved = is_reserved_key(&entry.path);
+        if reserved {
+            warn!(
+                source=?entry.source,
+                key=?entry.path,
+                "Skipping file that would overwrite shove's own data"
+            );
+        }
+        !reserved
+    });
+
+    let mut sources: HashMap<&str, Vec<&str>> = HashMap::new();
+    for entry in &local {
+        sources
+            .entry(entry.path.as_str())
+            .or_default()
+            .push(entry.source.as_str());
+    }
+    let mut collisions: Vec<String> = sources
+        .into_iter()
+        .filter(|(_, sources)| sources.len() > 1)
+        .map(|(path, mut sources)| {
+            sources.sort();
+            format!("{path} is provided by {}", sources.join(" and "))
+        })
+        .collect();
+    if !collisions.is_empty() {
+        collisions.sort();
+        return Err(ShoveError::Config(format!(
+            "mappings overlap:\n{}",
+            collisions.join("\n")
+        )));
+    }
+
+    let mut new = vec![];
+    let mut changed = vec![];
+    let mut metadata_changed = vec![];
+    let mut unchanged = vec![];
+    let mut to_delete: HashSet<&String> = existing
+        .entries
+        .keys()
+        .filter(|key| !is_reserved_key(key))
+        .collect();
+
+    for entry in local {
+        to_delete.remove(&entry.path);
+
+        //if the root has moved, then nothing we've got stored is comparable
+        let existing_hash = if root == existing.root {
+            existing.entries.get(&entry.path)
+        } else {
+            None
+        };
+
+        match existing_hash {
+            None => new.push(entry),
+            Some(x) if x != &entry.hash => changed.push(entry),
+            Some(_)
+                if check_metadata
+                    && entry.cache_control.as_ref() != existing.cache_control.get(&entry.path) =>
+            {
+                metadata_changed.push(entry)
+            }
+            Some(_) => {
+                trace!(pb=?entry.path, "Skipping upload");
+                unchanged.push((entry.path, entry.hash));
+            }
+        }
+    }
+
+    let mut deleted: Vec<String> = to_delete.into_iter().cloned().collect();
+    deleted.sort();
+
+    //copies keep the object's metadata, so the content type & cache-control have to match too
+    let mut by_hash: HashMap<&str, Vec<&String>> = HashMap::new();
+    for (path, hash) in &existing.entries {
+        by_hash.entry(hash.as_str()).or_default().push(path);
+    }
+    let mut copied = vec![];
+    let mut still_new = vec![];
+    for entry in new {
+        let content_type = entry.mime_guess.first_or_octet_stream();
+        let from = by_hash
+            .get(entry.hash.as_str())
+            .into_iter()
+            .flatten()
+            .filter(|path| {
+                **path != &entry.path
+                    && new_mime_guess::from_path(path.as_str()).first_or_octet_stream()
+                        == content_type
+                    && existing.cache_control.get(path.as_str()) == entry.cache_control.as_ref()
+            })
+            .min();
+
+        match from {
+            Some(from) => {
+                let from = from.to_string();
+                copied.push((entry, from));
+            }
+            None => still_new.push(entry),
+        }
+    }
+    let new = still_new;
+
+    Ok(UploadPlan {
+        new,
+        changed,
+        metadata_changed,
+        copied,
+        unchanged,
+        deleted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, hash: &str) -> Entry {
+        sourced_entry(path, path, hash)
+    }
+
+    fn sourced_entry(path: &str, source: &str, hash: &str) -> Entry {
+        Entry {
+            path: path.to_string(),
+            site_path: path.to_string(),
+            source: source.to_string(),
+            size: hash.len() as u64,
+            contents: Some(hash.as_bytes().to_vec()),
+            hash: hash.to_string(),
+            mime_guess: new_mime_guess::from_path(path),
+            cache_control: None,
+        }
+    }
+
+    fn existing(root: &str, entries: &[(&str, &str)]) -> UploadData {
+        UploadData {
+            entries: entries
+                .iter()
+                .map(|(p, h)| (p.to_string(), h.to_string()))
+                .collect(),
+            root: root.to_string(),
+            cache_control: HashMap::new(),
+            sizes: HashMap::new(),
+        }
+    }
+
+    fn paths(entries: &[Entry]) -> Vec<&str> {
+        let mut paths: Vec<&str> = entries.iter().map(|x| x.path.as_str()).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_plan_classifies_everything() {
+        let existing = existing(
+            "public",
+            &[
+                ("public/same.html", "a"),
+                ("public/changed.html", "b"),
+                ("public/gone.html", "c"),
+            ],
+        );
+        let local = vec![
+            entry("public/same.html", "a"),
+            entry("public/changed.html", "different"),
+            entry("public/brand_new.html", "d"),
+        ];
+
+        let plan = plan_upload(&existing, "public", local, true).unwrap();
+
+        assert_eq!(paths(&plan.new), vec!["public/brand_new.html"]);
+        assert_eq!(paths(&plan.changed), vec!["public/changed.html"]);
+        assert_eq!(
+            plan.unchanged,
+            vec![("public/same.html".to_string(), "a".to_string())]
+        );
+        assert_eq!(plan.deleted, vec!["public/gone.html".to_string()]);
+    }
+
+    #[test]
+    fn test_plan_never_touches_reserved_keys() {
+        //several mappings are rooted at the top of the bucket, or the site's directory
+        let existing = existing(
+            "",
+            &[("/index.html", "a"), ("/authdata", "b"), ("/old.html", "c")],
+        );
+        let local = vec![
+            entry("/index.html", "a"),
+            entry("/upload_data.json", "d"),
+            entry("/cache_control.json", "e"),
+            entry("/audit/2024.json", "f"),
+        ];
+
+        let plan = plan_upload(&existing, "", local, true).unwrap();
+
+        assert!(plan.new.is_empty());
+        assert_eq!(
+            plan.unchanged,
+            vec![("/index.html".to_string(), "a".to_string())]
+        );
+        assert_eq!(plan.deleted, vec!["/old.html".to_string()]);
+
+        let local = vec![entry("sites/example.com/headers.json", "g")];
+        let plan = plan_upload(&UploadData::default(), "sites/example.com", local, true).unwrap();
+        assert!(plan.new.is_empty());
+    }
+
+    #[test]
+    fn test_plan_with_no_existing_data() {
+        let local = vec![entry("public/a.html", "a"), entry("public/b.html", "b")];
+
+        let plan = plan_upload(&UploadData::default(), "public", local, true).unwrap();
+
+        assert_eq!(paths(&plan.new), vec!["public/a.html", "public/b.html"]);
+        assert!(plan.changed.is_empty());
+        assert!(plan.unchanged.is_empty());
+        assert!(plan.deleted.is_empty());
+    }
+
+    #[test]
+    fn test_plan_with_nothing_changed() {
+        let existing = existing("public", &[("public/a.html", "a")]);
+
+        let plan =
+            plan_upload(&existing, "public", vec![entry("public/a.html", "a")], true).unwrap();
+
+        assert!(!plan.has_changes());
+        assert_eq!(plan.unchanged.len(), 1);
+    }
+
+    #[test]
+    fn test_plan_with_different_root_reuploads_everything() {
+        let existing = existing("old", &[("old/a.html", "a"), ("new/a.html", "a")]);
+
+        let plan = plan_upload(&existing, "new", vec![entry("new/a.html", "a")], true).unwrap();
+
+        //the old key still has the same bytes, so those get copied over rather than uploaded
+        assert!(plan.new.is_empty());
+        assert_eq!(plan.copied.len(), 1);
+        assert_eq!(plan.copied[0].1, "old/a.html");
+        assert!(plan.unchanged.is_empty());
+        assert_eq!(plan.deleted, vec!["old/a.html".to_string()]);
+    }
+
+    #[test]
+    fn test_plan_reuploads_for_new_cache_control() {
+        let mut existing = existing(
+            "public",
+            &[
+                ("public/a.js", "a"),
+                ("public/b.js", "b"),
+                ("public/c.js", "c"),
+            ],
+        );
+        existing
+            .cache_control
+            .insert("public/a.js".to_string(), "max-age=60".to_string());
+        existing
+            .cache_control
+            .insert("public/b.js".to_string(), "max-age=60".to_string());
+
+        let with_cc = |path, hash, cc: Option<&str>| Entry {
+            cache_control: cc.map(ToString::to_string),
+            ..entry(path, hash)
+        };
+        let local = || {
+            vec![
+                with_cc("public/a.js", "a", Some("max-age=60")),
+                with_cc("public/b.js", "b", Some("no-store")),
+                with_cc("public/c.js", "c", Some("max-age=60")),
+            ]
+        };
+
+        let plan = plan_upload(&existing, "public", local(), true).unwrap();
+        assert!(plan.new.is_empty());
+        assert!(plan.changed.is_empty());
+        assert_eq!(
+            paths(&plan.metadata_changed),
+            vec!["public/b.js", "public/c.js"]
+        );
+        assert_eq!(
+            plan.unchanged,
+            vec![("public/a.js".to_string(), "a".to_string())]
+        );
+
+        //--skip-metadata is just like before
+        let plan = plan_upload(&existing, "public", local(), false).unwrap();
+        assert!(!plan.has_changes());
+
+        //rules being removed means the metadata has to go too
+        let plan = plan_upload(&existing, "public", vec![entry("public/a.js", "a")], true).unwrap();
+        assert_eq!(paths(&plan.metadata_changed), vec!["public/a.js"]);
+    }
+
+    #[test]
+    fn test_cache_control_for_matches_serving() {
+        let mut caching = Caching::default();
+        caching.set_directives(
+            crate::Realm::StartsWith("/assets".to_string()),
+            NonEmptyList::new(vec![Directive::MaxAge(60), Directive::Immutable]).unwrap(),
+        );
+
+        assert_eq!(
+            cache_control_for(&caching, "/assets/app.js").as_deref(),
+            Some("max-age=60, immutable")
+        );
+        assert_eq!(cache_control_for(&caching, "/index.html"), None);
+    }
+
+    #[test]
+    fn test_plan_copies_renamed_files() {
+        let existing = existing(
+            "",
+            &[
+                ("/old/a.html", "a"),
+                ("/old/b.html", "b"),
+                ("/old/c.txt", "c"),
+            ],
+        );
+        let local = vec![
+            entry("/new/a.html", "a"),
+            entry("/new/b.html", "changed"),
+            //same bytes, but it'd be served with a different content type
+            entry("/new/c.html", "c"),
+        ];
+
+        let plan = plan_upload(&existing, "", local, true).unwrap();
+
+        let copied: Vec<(&str, &str)> = plan
+            .copied
+            .iter()
+            .map(|(entry, from)| (entry.path.as_str(), from.as_str()))
+            .collect();
+        assert_eq!(copied, vec![("/new/a.html", "/old/a.html")]);
+        assert_eq!(paths(&plan.new), vec!["/new/b.html", "/new/c.html"]);
+        assert_eq!(
+            plan.deleted,
+            vec![
+                "/old/a.html".to_string(),
+                "/old/b.html".to_string(),
+                "/old/c.txt".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_only_copies_matching_cache_control() {
+        let mut existing = existing("", &[("/old/a.js", "a")]);
+        existing
+            .cache_control
+            .insert("/old/a.js".to_string(), "max-age=60".to_string());
+
+        let moved = |cc: Option<&str>| Entry {
+            cache_control: cc.map(ToString::to_string),
+            ..entry("/new/a.js", "a")
+        };
+
+        let plan = plan_upload(&existing, "", vec![moved(Some("max-age=60"))], true).unwrap();
+        assert_eq!(plan.copied.len(), 1);
+
+        let plan = plan_upload(&existing, "", vec![moved(Some("no-store"))], true).unwrap();
+        assert!(plan.copied.is_empty());
+        assert_eq!(paths(&plan.new), vec!["/new/a.js"]);
+
+        let plan = plan_upload(&existing, "", vec![moved(None)], false).unwrap();
+        assert!(plan.copied.is_empty());
+    }
+
+    #[test]
+    fn test_ignored_files_are_deleted_unless_kept() {
+        let mappings = mappings(&["site:/", "docs/build:/docs"]);
+        let ignores = [
+            IgnoreRules::new(["*.map"], &[]).unwrap(),
+            IgnoreRules::new(["drafts/"], &[]).unwrap(),
+        ];
+        let existing = existing(
+            "",
+            &[
+                ("/index.html", "a"),
+                ("/app.js.map", "b"),
+                ("/docs/drafts/post.html", "c"),
+                ("/docs/gone.html", "d"),
+            ],
+        );
+        let local = || vec![mapped_entry(&mappings, 0, "index.html", "a")];
+
+        let plan = plan_upload(&existing, "", local(), true).unwrap();
+        assert_eq!(plan.deleted.len(), 3);
+
+        let mut plan = plan_upload(&existing, "", local(), true).unwrap();
+        let with_ignores: Vec<(&Mapping, &IgnoreRules)> = mappings.iter().zip(&ignores).collect();
+        keep_ignored_files(&mut plan, &existing, "", &with_ignores);
+
+        assert_eq!(plan.deleted, vec!["/docs/gone.html".to_string()]);
+        let mut unchanged: Vec<&str> = plan.unchanged.iter().map(|(k, _)| k.as_str()).collect();
+        unchanged.sort();
+        assert_eq!(
+            unchanged,
+            vec!["/app.js.map", "/docs/drafts/post.html", "/index.html"]
+        );
+    }
+
+    fn mappings(args: &[&str]) -> Vec<Mapping> {
+        args.iter().map(|x| Mapping::parse(x).unwrap()).collect()
+    }
+
+    ///what [`upload_dirs_to_bucket`] would make the key for each file
+    fn mapped_entry(mappings: &[Mapping], dir: usize, file: &str, hash: &str) -> Entry {
+        let mapping = &mappings[dir];
+        let source = format!("{}/{file}", mapping.dir);
+        let site_path = mapping.site_path(Path::new(&source)).unwrap();
+        sourced_entry(
+            &entry_key(&storage_root(mappings), &site_path),
+            &source,
+            hash,
+        )
+    }
+
+    #[test]
+    fn test_mapping_parse() {
+        assert_eq!(
+            Mapping::parse("site").unwrap(),
+            Mapping {
+                dir: "site".to_string(),
+                prefix: String::new()
+            }
+        );
+        assert_eq!(
+            Mapping::parse("site:/").unwrap(),
+            Mapping::parse("site").unwrap()
+        );
+        assert_eq!(
+            Mapping::parse("docs/build:/docs/").unwrap(),
+            Mapping {
+                dir: "docs/build".to_string(),
+                prefix: "/docs".to_string()
+            }
+        );
+        assert!(matches!(
+            Mapping::parse("docs:docs"),
+            Err(ShoveError::Config(_))
+        ));
+        assert!(matches!(
+            Mapping::parse(":/docs"),
+            Err(ShoveError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_single_root_mapping_keeps_legacy_keys() {
+        let mappings = mappings(&["public"]);
+        assert_eq!(storage_root(&mappings), "public");
+
+        let entry = mapped_entry(&mappings, 0, "blog/index.html", "a");
+        assert_eq!(entry.path, "public/blog/index.html");
+    }
+
+    #[test]
+    fn test_plan_unions_mappings() {
+        let mappings = mappings(&["site:/", "docs/build:/docs", "storybook-static:/components"]);
+        let existing = existing(
+            "",
+            &[
+                ("/index.html", "a"),
+                ("/docs/index.html", "b"),
+                ("/components/old.html", "c"),
+            ],
+        );
+        let local = vec![
+            mapped_entry(&mappings, 0, "index.html", "a"),
+            mapped_entry(&mappings, 1, "index.html", "changed"),
+            mapped_entry(&mappings, 2, "button/index.html", "d"),
+        ];
+
+        let plan = plan_upload(&existing, &storage_root(&mappings), local, true).unwrap();
+
+        assert_eq!(paths(&plan.new), vec!["/components/button/index.html"]);
+        assert_eq!(paths(&plan.changed), vec!["/docs/index.html"]);
+        assert_eq!(
+            plan.unchanged,
+            vec![("/index.html".to_string(), "a".to_string())]
+        );
+        assert_eq!(plan.deleted, vec!["/components/old.html".to_string()]);
+    }
+
+    #[test]
+    fn test_plan_rejects_colliding_mappings() {
+        let mappings = mappings(&["site:/", "docs/build:/docs"]);
+        let local = vec![
+            mapped_entry(&mappings, 0, "docs/index.html", "a"),
+            mapped_entry(&mappings, 1, "index.html", "b"),
+            mapped_entry(&mappings, 1, "other.html", "c"),
+        ];
+
+        let Err(e @ ShoveError::Config(_)) = plan_upload(&UploadData::default(), "", local, true)
+        else {
+            panic!("collision wasn't detected");
+        };
+        let msg = e.to_string();
+        assert!(msg.contains("/docs/index.html"), "{msg}");
+        assert!(msg.contains("site/docs/index.html"), "{msg}");
+        assert!(msg.contains("docs/build/index.html"), "{msg}");
+        assert!(!msg.contains("other.html"), "{msg}");
+    }
+
+    #[test]
+    fn test_plan_deletes_removed_mapping() {
+        let existing = existing(
+            "",
+            &[
+                ("/index.html", "a"),
+                ("/docs/index.html", "b"),
+                ("/docs/intro.html", "c"),
+                ("/components/index.html", "d"),
+            ],
+        );
+        let mappings = mappings(&["site:/", "storybook-static:/components"]);
+        let local = vec![
+            mapped_entry(&mappings, 0, "index.html", "a"),
+            mapped_entry(&mappings, 1, "index.html", "d"),
+        ];
+
+        let plan = plan_upload(&existing, &storage_root(&mappings), local, true).unwrap();
+
+        assert!(plan.new.is_empty());
+        assert!(plan.changed.is_empty());
+        assert_eq!(plan.unchanged.len(), 2);
+        assert_eq!(
+            plan.deleted,
+            vec![
+                "/docs/index.html".to_string(),
+                "/docs/intro.html".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_site_roots() {
+        assert_eq!(site_root(None, "public/"), "public/");
+        assert_eq!(site_root(Some("example.com"), ""), "sites/example.com");
+        assert_eq!(
+            site_root(Some("example.com"), "public"),
+            "sites/example.com/public"
+        );
+        assert_eq!(
+            entry_key(&site_root(Some("example.com"), ""), "/docs/index.html"),
+            "sites/example.com/docs/index.html"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diff_matches_what_upload_does() {
+        use crate::store::memory::InMemoryBucket;
+        use std::{
+            fs,
+            time::{SystemTime, UNIX_EPOCH},
+        };
+
+        let dir = std::env::temp_dir().join(format!(
+            "shove-diff-test-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("index.html"), "<p>one</p>").unwrap();
+        fs::write(dir.join("style.css"), "p {}").unwrap();
+        let mappings = [Mapping {
+            dir: dir.to_str().unwrap().to_string(),
+            prefix: String::new(),
+        }];
+        let options = UploadOptions {
+            no_manifest: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let bucket = InMemoryBucket::new();
+        let diff = || diff_dirs(&mappings, None, &bucket, options.clone());
+
+        let before = diff().await.unwrap();
+        assert_eq!(
+            before
+                .added
+                .iter()
+                .map(|x| (x.path.as_str(), x.size))
+                .collect::<Vec<_>>(),
+            vec![("/index.html", Some(10)), ("/style.css", Some(4))]
+        );
+
+        let atomic = UploadOptions {
+            atomic: true,
+            ..options.clone()
+        };
+        assert!(upload_dirs_to_bucket(&mappings, None, &bucket, atomic)
+            .await
+            .unwrap());
+        assert!(diff().await.unwrap().is_empty());
+
+        fs::write(dir.join("index.html"), "<p>two!</p>").unwrap();
+        fs::remove_file(dir.join("style.css")).unwrap();
+        fs::write(dir.join("new.js"), "1").unwrap();
+        let after = diff().await.unwrap();
+        assert_eq!(
+            after,
+            Diff {
+                added: vec![Difference {
+                    path: "/new.js".to_string(),
+                    size: Some(1),
+                    uploaded_size: None
+                }],
+                changed: vec![Difference {
+                    path: "/index.html".to_string(),
+                    size: Some(11),
+                    uploaded_size: Some(10)
+                }],
+                removed: vec![Difference {
+                    path: "/style.css".to_string(),
+                    size: Some(4),
+                    uploaded_size: None
+                }],
+            }
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs::Metadata, path::Path, time::UNIX_EPOCH};
+
+///kept in the root of each uploaded directory so unchanged files don't need hashing again. Only ever
+///a shortcut - the bucket's upload data is what actually says what's been uploaded
+pub const MANIFEST_FILE: &str = ".shove-manifest.json";
+
+///what a file looked like last time it was hashed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileRecord {
+    pub size: u64,
+    ///nanoseconds since the epoch
+    pub modified_ns: u64,
+    pub hash: String,
+}
+
+impl FileRecord {
+    ///`None` if the platform can't say when the file was modified
+    pub fn new(metadata: &Metadata, hash: String) -> Option<Self> {
+        let modified_ns = metadata
+            .modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_nanos()
+            .try_into()
+            .ok()?;
+        Some(Self {
+            size: metadata.len(),
+            modified_ns,
+            hash,
+        })
+    }
+}
+
+///every file in one uploaded directory, by its `/`-separated path relative to that directory
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LocalManifest {
+    files: HashMap<String, FileRecord>,
+}
+
+impl LocalManifest {
+    ///anything unreadable just means starting from scratch
+    pub fn load(dir: &str) -> Self {
+        match std::fs::read(Path::new(dir).join(MANIFEST_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!(
+                    ?dir,
+                    ?e,
+                    "Unable to read local manifest, hashing everything"
+                );
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self, dir: &str) -> color_eyre::Result<()> {
+        std::fs::write(
+            Path::new(dir).join(MANIFEST_FILE),
+            serde_json::to_vec(self)?,
+        )?;
+        Ok(())
+    }
+
+    ///the hash from last time, as long as the file doesn't look like it's changed since
+    pub fn unchanged_hash(&self, relative: &str, metadata: &Metadata) -> Option<&str> {
+        let record = self.files.get(relative)?;
+        let current = FileRecord::new(metadata, String::new())?;
+        (record.size == current.size && record.modified_ns == current.modified_ns)
+            .then_some(record.hash.as_str())
+    }
+
+    pub fn insert(&mut self, relative: String, record: FileRecord) {
+        self.files.insert(relative, record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, time::SystemTime};
+
+    #[test]
+    fn test_only_trusts_untouched_files() {
+        let dir = std::env::temp_dir().join(format!(
+            "shove-manifest-test-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let file = dir.join("a.html");
+        fs::write(&file, "hello").unwrap();
+
+        let mut manifest = LocalManifest::load(dir_str);
+        let metadata = fs::metadata(&file).unwrap();
+        assert_eq!(manifest.unchanged_hash("a.html", &metadata), None);
+
+        manifest.insert(
+            "a.html".to_string(),
+            FileRecord::new(&metadata, "hash".to_string()).unwrap(),
+        );
+        manifest.save(dir_str).unwrap();
+
+        let manifest = LocalManifest::load(dir_str);
+        assert_eq!(manifest.unchanged_hash("a.html", &metadata), Some("hash"));
+        assert_eq!(manifest.unchanged_hash("b.html", &metadata), None);
+
+        fs::write(&file, "hello, world").unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+        assert_eq!(manifest.unchanged_hash("a.html", &metadata), None);
+
+        fs::write(dir.join(MANIFEST_FILE), "not json").unwrap();
+        assert_eq!(
+            LocalManifest::load(dir_str).unchanged_hash("a.html", &metadata),
+            None
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
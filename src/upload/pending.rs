@@ -0,0 +1,230 @@
+use crate::{
+    config::BucketConfig,
+    releases::{now_ms, still_needed},
+    s3::{get_bytes_or_default, site_location},
+    store::ObjectStore,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
+
+///the keys an upload stopped needing, and when they can go
+pub const PENDING_DELETES_LOCATION: &str = "pending_deletes.json";
+
+///how long removed files stay in the bucket without `--delete-after`. Servers check for new upload
+///data every 60s, so this leaves plenty of time for them all to have moved on
+pub const DEFAULT_DELETE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+///parses `--delete-after`, either plain seconds or a number followed by `s`, `m`, `h` or `d`
+pub fn parse_delete_after(arg: &str) -> Option<Duration> {
+    let (number, unit) = match arg.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => arg.split_at(i),
+        None => (arg, "s"),
+    };
+    let number: u64 = number.parse().ok()?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(secs)?))
+}
+
+///files that are no longer part of the site, but that servers still on the old upload data could
+///be serving, so are left in the bucket for a while
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct PendingDeletes {
+    ///each key, and the milliseconds since the epoch after which it can be deleted
+    keys: BTreeMap<String, u64>,
+}
+
+impl PendingDeletes {
+    pub async fn load(bucket: &dyn ObjectStore, site: Option<&str>) -> color_eyre::Result<Self> {
+        let bytes =
+            get_bytes_or_default(bucket, site_location(site, PENDING_DELETES_LOCATION)).await?;
+        if bytes.is_empty() {
+            Ok(Self::default())
+        } else {
+            Ok(serde_json::from_slice(&bytes)?)
+        }
+    }
+
+    ///gets rid of the object once there's nothing left in it
+    pub async fn save(
+        &self,
+        bucket: &dyn ObjectStore,
+        site: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        let location = site_location(site, PENDING_DELETES_LOCATION);
+        if self.keys.is_empty() {
+            bucket.delete(&location).await?;
+        } else {
+            bucket
+                .put(&location, &serde_json::to_vec(self)?, mime::JSON.as_str())
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    ///`keys` can go once `delete_after` has passed from `now`. Anything already waiting keeps its
+    ///own time
+    pub fn add(
+        &mut self,
+        keys: impl IntoIterator<Item = String>,
+        now: u64,
+        delete_after: Duration,
+    ) {
+        let at = now.saturating_add(delete_after.as_millis().try_into().unwrap_or(u64::MAX));
+        for key in keys {
+            self.keys.entry(key).or_insert(at);
+        }
+    }
+
+    ///takes out every key that's due by `now`, or all of them with `everything`. Anything `needed`
+    ///again, by a newer upload or a rollback, gets forgotten rather than deleted
+    fn take_due(&mut self, needed: &HashSet<&String>, now: u64, everything: bool) -> Vec<String> {
+        self.keys.retain(|key, _| !needed.contains(key));
+        let due: Vec<String> = self
+            .keys
+            .iter()
+            .filter(|(_, at)| everything || **at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &due {
+            self.keys.remove(key);
+        }
+        due
+    }
+
+    ///deletes what's due, returning how many were. Anything that fails to be deleted stays, to be
+    ///tried again next time
+    pub async fn collect(
+        &mut self,
+        bucket: &dyn ObjectStore,
+        needed: &HashSet<&String>,
+        everything: bool,
+    ) -> color_eyre::Result<usize> {
+        let now = now_ms();
+        let due = self.take_due(needed, now, everything);
+        let mut deleted = 0;
+        let mut failed = vec![];
+        for key in due {
+            info!(?key, "Deleting old file");
+            match bucket.delete(&key).await {
+                Ok(()) => deleted += 1,
+                Err(e) => {
+                    warn!(
+                        ?key,
+                        ?e,
+                        "Unable to delete old file, leaving it for next time"
+                    );
+                    failed.push(key);
+                }
+            }
+        }
+        self.keys.extend(failed.into_iter().map(|key| (key, now)));
+        Ok(deleted)
+    }
+}
+
+///deletes the files earlier uploads removed once they're due, or all of them `now`
+pub async fn gc(config: &BucketConfig, site: Option<&str>, now: bool) -> color_eyre::Result<()> {
+    let bucket = config.bucket()?;
+    let mut pending = PendingDeletes::load(&bucket, site).await?;
+    if pending.is_empty() {
+        println!("Nothing waiting to be deleted.");
+        return Ok(());
+    }
+
+    let needed = still_needed(&bucket, site).await?;
+    let deleted = pending
+        .collect(&bucket, &needed.iter().collect(), now)
+        .await?;
+    pending.save(&bucket, site).await?;
+    println!(
+        "{deleted} deleted, {} still waiting for their grace period.",
+        pending.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::InMemoryBucket;
+
+    #[test]
+    fn test_parse_delete_after() {
+        assert_eq!(parse_delete_after("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_delete_after("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_delete_after("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_delete_after("2h"), Some(Duration::from_secs(7_200)));
+        assert_eq!(parse_delete_after("1d"), Some(Duration::from_secs(86_400)));
+        assert_eq!(parse_delete_after("0"), Some(Duration::ZERO));
+        for invalid in ["", "m", "10 m", "10w", "-1", "1.5h"] {
+            assert_eq!(parse_delete_after(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_only_due_and_unneeded_keys_are_taken() {
+        let mut pending = PendingDeletes::default();
+        pending.add(["/a.html".to_string()], 1_000, Duration::from_secs(1));
+        pending.add(
+            ["/b.html".to_string(), "/c.html".to_string()],
+            5_000,
+            Duration::from_secs(1),
+        );
+        //already waiting, so it isn't pushed back
+        pending.add(["/a.html".to_string()], 5_000, Duration::from_secs(1));
+
+        let back = "/c.html".to_string();
+        let needed = HashSet::from([&back]);
+        assert_eq!(pending.take_due(&needed, 2_000, false), vec!["/a.html"]);
+        assert_eq!(pending.len(), 1);
+        assert!(pending.take_due(&needed, 5_999, false).is_empty());
+        assert_eq!(pending.take_due(&needed, 6_000, false), vec!["/b.html"]);
+        assert!(pending.is_empty());
+
+        pending.add(["/d.html".to_string()], 1_000, DEFAULT_DELETE_AFTER);
+        assert_eq!(pending.take_due(&needed, 1_000, true), vec!["/d.html"]);
+    }
+
+    #[tokio::test]
+    async fn test_saving_nothing_removes_the_object() {
+        let bucket = InMemoryBucket::new();
+        let site = Some("example.com");
+        let location = site_location(site, PENDING_DELETES_LOCATION);
+
+        let mut pending = PendingDeletes::default();
+        pending.add(["/a.html".to_string()], now_ms(), Duration::ZERO);
+        pending.save(&bucket, site).await.unwrap();
+        assert!(bucket.bytes(&location).await.is_some());
+        assert_eq!(PendingDeletes::load(&bucket, site).await.unwrap(), pending);
+
+        bucket.put("/a.html", b"old", "text/html").await.unwrap();
+        assert_eq!(
+            pending
+                .collect(&bucket, &HashSet::new(), false)
+                .await
+                .unwrap(),
+            1
+        );
+        pending.save(&bucket, site).await.unwrap();
+        assert!(bucket.bytes("/a.html").await.is_none());
+        assert!(bucket.bytes(&location).await.is_none());
+    }
+}
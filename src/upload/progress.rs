@@ -0,0 +1,206 @@
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use std::{
+    io::IsTerminal,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+///how often progress gets logged when there's no bar to draw it on
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+///whether to draw bars, or only log every so often
+pub fn fancy(quiet: bool) -> bool {
+    !quiet && std::io::stderr().is_terminal()
+}
+
+///one step of an upload, either drawn as a bar or logged every [`LOG_INTERVAL`]. Safe to update
+///from any task
+pub struct Phase {
+    what: &'static str,
+    bar: Option<ProgressBar>,
+    ///files when hashing, bytes when uploading
+    done: AtomicU64,
+    total: u64,
+    ///read while hashing, for the rate
+    bytes: AtomicU64,
+    started: Instant,
+    last_logged: Mutex<Instant>,
+}
+
+impl Phase {
+    fn new(what: &'static str, total: u64, template: &str, fancy: bool) -> Self {
+        let bar = fancy.then(|| {
+            let style = ProgressStyle::with_template(template)
+                .unwrap_or_else(|_| ProgressStyle::default_bar())
+                .progress_chars("=> ");
+            ProgressBar::new(total).with_style(style).with_prefix(what)
+        });
+        let now = Instant::now();
+        Self {
+            what,
+            bar,
+            done: AtomicU64::new(0),
+            total,
+            bytes: AtomicU64::new(0),
+            started: now,
+            last_logged: Mutex::new(now),
+        }
+    }
+
+    ///counts files, showing how quickly they're being read
+    pub fn hashing(files: u64, fancy: bool) -> Self {
+        Self::new(
+            "Hashing",
+            files,
+            "{prefix:>10} [{bar:30}] {pos}/{len} files {msg}",
+            fancy,
+        )
+    }
+
+    ///counts bytes
+    pub fn uploading(bytes: u64, fancy: bool) -> Self {
+        Self::new(
+            "Uploading",
+            bytes,
+            "{prefix:>10} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec}",
+            fancy,
+        )
+    }
+
+    ///counts files, showing how quickly they're coming in
+    pub fn downloading(files: u64, fancy: bool) -> Self {
+        Self::new(
+            "Fetching",
+            files,
+            "{prefix:>10} [{bar:30}] {pos}/{len} files {msg}",
+            fancy,
+        )
+    }
+
+    ///another file downloaded (or found already there), which was `bytes` long
+    pub fn downloaded(&self, bytes: u64) {
+        self.hashed(bytes);
+    }
+
+    ///another file hashed, which was `bytes` long
+    pub fn hashed(&self, bytes: u64) {
+        let bytes = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(bar) = &self.bar {
+            bar.set_message(format!("{}/s", HumanBytes(self.rate(bytes))));
+        }
+        self.inc(1);
+    }
+
+    pub fn uploaded(&self, bytes: u64) {
+        self.inc(bytes);
+    }
+
+    fn inc(&self, n: u64) {
+        let done = self.done.fetch_add(n, Ordering::Relaxed) + n;
+        if let Some(bar) = &self.bar {
+            bar.inc(n);
+            return;
+        }
+
+        let Ok(mut last_logged) = self.last_logged.try_lock() else {
+            return;
+        };
+        if last_logged.elapsed() >= LOG_INTERVAL {
+            *last_logged = Instant::now();
+            info!(what=%self.what, %done, total=%self.total, "Progress");
+        }
+    }
+
+    fn rate(&self, bytes: u64) -> u64 {
+        let secs = self.started.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            (bytes as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+///what an upload did, for once it's all over
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub uploaded: usize,
+    ///already in the bucket somewhere else, so copied there instead
+    pub copied: usize,
+    pub skipped: usize,
+    pub deleted: usize,
+    ///no longer part of the site, but left in the bucket until their grace period is up
+    pub pending: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Summary {
+    pub fn throughput(&self) -> u64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            (self.bytes as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+
+    ///printed unless `quiet`, when it's only logged
+    pub fn report(&self, quiet: bool) {
+        if quiet {
+            info!(summary=?self, "Uploaded");
+            return;
+        }
+        println!(
+            "{} uploaded, {} copied, {} unchanged, {} deleted, {} to delete later. {} transferred in {} ({}/s)",
+            self.uploaded,
+            self.copied,
+            self.skipped,
+            self.deleted,
+            self.pending,
+            HumanBytes(self.bytes),
+            HumanDuration(self.elapsed),
+            HumanBytes(self.throughput())
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phases_count_without_a_bar() {
+        let hashing = Phase::hashing(3, false);
+        hashing.hashed(10);
+        hashing.hashed(20);
+        assert_eq!(hashing.done.load(Ordering::Relaxed), 2);
+        assert_eq!(hashing.bytes.load(Ordering::Relaxed), 30);
+
+        let uploading = Phase::uploading(100, false);
+        uploading.uploaded(60);
+        uploading.uploaded(40);
+        assert_eq!(uploading.done.load(Ordering::Relaxed), 100);
+        uploading.finish();
+    }
+
+    #[test]
+    fn test_throughput() {
+        let summary = Summary {
+            bytes: 10_000,
+            elapsed: Duration::from_secs(4),
+            ..Default::default()
+        };
+        assert_eq!(summary.throughput(), 2_500);
+        assert_eq!(Summary::default().throughput(), 0);
+    }
+}
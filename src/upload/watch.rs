@@ -0,0 +1,239 @@
+use crate::{
+    releases::request_reload,
+    store::ObjectStore,
+    upload::{
+        ignore::IgnoreRules,
+        machinery::{upload_dirs_to_bucket, Mapping},
+        UploadOptions,
+    },
+};
+use notify::{Event, RecursiveMode, Watcher};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio_util::sync::CancellationToken;
+
+///how long nothing needs to change for before uploading, so several saves in a row (or an editor
+///writing a temp file & renaming it over the real one) only get uploaded once they've finished
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+///what editors leave next to a file while it's open or being saved. Left out on top of any
+///`--exclude`s, so a change settling while one's there doesn't upload it
+const EDITOR_FILES: [&str; 6] = ["*.swp", "*.swx", "*~", ".#*", "4913", "*.tmp"];
+
+///a server to ask to reload after each upload that changed anything
+#[derive(Debug, Clone)]
+pub struct Notify {
+    ///where the server's being served, without the `/reload`
+    pub url: String,
+    ///one of the server's `TIGRIS_TOKEN`s
+    pub token: String,
+}
+
+///a mapping, and where its directory really is, to match up the paths the watcher gives
+struct Watched<'a> {
+    mapping: &'a Mapping,
+    canonical: PathBuf,
+}
+
+impl Watched<'_> {
+    ///whether a change at `path` could change what gets uploaded
+    fn is_relevant(&self, path: &Path, rules: &IgnoreRules) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.canonical) else {
+            return false;
+        };
+        let Some(relative) = relative
+            .components()
+            .map(|x| x.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()
+        else {
+            return false;
+        };
+        !rules.is_ignored(&relative.join("/"), path.is_dir())
+    }
+}
+
+///uploads the `mappings`, then uploads them again each time anything in them changes until
+///`stop` is cancelled. An upload that's going when it is gets finished first
+pub async fn watch_dirs(
+    mappings: &[Mapping],
+    site: Option<&str>,
+    bucket: &dyn ObjectStore,
+    mut options: UploadOptions,
+    notify: Option<Notify>,
+    stop: CancellationToken,
+) -> color_eyre::Result<()> {
+    options
+        .excludes
+        .extend(EDITOR_FILES.iter().map(ToString::to_string));
+
+    let (send_changed, mut recv_changed) = channel(1024);
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                for path in event.paths {
+                    //a full channel means an upload is already coming
+                    let _ = send_changed.try_send(path);
+                }
+            }
+            Err(e) => error!(?e, "Error watching directory"),
+        })?;
+    let mut watched = Vec::with_capacity(mappings.len());
+    for mapping in mappings {
+        let canonical = Path::new(&mapping.dir).canonicalize()?;
+        watcher.watch(&canonical, RecursiveMode::Recursive)?;
+        watched.push(Watched { mapping, canonical });
+    }
+
+    sync(mappings, site, bucket, &options, notify.as_ref()).await;
+    info!(?mappings, "Watching for changes");
+
+    while !stop.is_cancelled() {
+        tokio::select! {
+            () = stop.cancelled() => break,
+            Some(path) = recv_changed.recv() => {
+                let mut changed = vec![path];
+                if !settle(&mut recv_changed, &mut changed, &stop).await {
+                    break;
+                }
+
+                let relevant = watched.iter().any(|watched| {
+                    //read again each time, as it could've been changed too
+                    match IgnoreRules::for_dir(&watched.mapping.dir, &options.excludes) {
+                        Ok(rules) => changed.iter().any(|x| watched.is_relevant(x, &rules)),
+                        Err(e) => {
+                            warn!(?e, dir=?watched.mapping.dir, "Unable to read ignore rules");
+                            true
+                        }
+                    }
+                });
+                if relevant {
+                    sync(mappings, site, bucket, &options, notify.as_ref()).await;
+                } else {
+                    trace!(?changed, "Only ignored files changed");
+                }
+            }
+        }
+    }
+
+    info!("Stopped watching");
+    Ok(())
+}
+
+///waits until nothing's changed for [`DEBOUNCE`], gathering what did into `changed`. `false` if
+///`stop` was cancelled first
+async fn settle(
+    recv_changed: &mut Receiver<PathBuf>,
+    changed: &mut Vec<PathBuf>,
+    stop: &CancellationToken,
+) -> bool {
+    loop {
+        tokio::select! {
+            () = stop.cancelled() => return false,
+            () = tokio::time::sleep(DEBOUNCE) => return true,
+            Some(path) = recv_changed.recv() => changed.push(path),
+        }
+    }
+}
+
+///errors are only logged, as the next change will try again
+async fn sync(
+    mappings: &[Mapping],
+    site: Option<&str>,
+    bucket: &dyn ObjectStore,
+    options: &UploadOptions,
+    notify: Option<&Notify>,
+) {
+    match upload_dirs_to_bucket(mappings, site, bucket, options.clone()).await {
+        Ok(true) => {
+            if let Some(Notify { url, token }) = notify
+                && let Err(e) = request_reload(url, token, site).await
+            {
+                warn!(?e, ?url, "Unable to ask the server to reload");
+            }
+        }
+        Ok(false) => debug!("Nothing to upload"),
+        Err(e) => error!(?e, "Error uploading"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{entry_key, s3::UPLOAD_DATA_LOCATION, store::memory::InMemoryBucket, UploadData};
+    use std::{
+        fs,
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    #[tokio::test]
+    async fn test_watching_uploads_changes() {
+        let dir = std::env::temp_dir().join(format!(
+            "shove-watch-test-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("index.html"), "<p>one</p>").unwrap();
+        fs::write(dir.join("style.css"), "p {}").unwrap();
+        let mappings = vec![Mapping {
+            dir: dir.to_str().unwrap().to_string(),
+            prefix: String::new(),
+        }];
+        let options = UploadOptions {
+            no_manifest: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let bucket = Arc::new(InMemoryBucket::new());
+        let stop = CancellationToken::new();
+        let watching = tokio::spawn({
+            let (mappings, bucket, stop) = (mappings.clone(), bucket.clone(), stop.clone());
+            async move { watch_dirs(&mappings, None, &*bucket, options, None, stop).await }
+        });
+
+        let key = |path: &str| entry_key(&mappings[0].dir, path);
+        let entries = || async {
+            let Some(bytes) = bucket.bytes(UPLOAD_DATA_LOCATION).await else {
+                return vec![];
+            };
+            let upload_data: UploadData = serde_json::from_slice(&bytes).unwrap();
+            let mut entries: Vec<String> = upload_data.entries.into_keys().collect();
+            entries.sort();
+            entries
+        };
+        let eventually = |expected: Vec<String>| async move {
+            for _ in 0..100 {
+                if entries().await == expected {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            assert_eq!(entries().await, expected);
+        };
+
+        eventually(vec![key("index.html"), key("style.css")]).await;
+
+        //several saves in a row, and an editor's swap file that's still there once they settle
+        fs::write(dir.join(".index.html.swp"), "half written").unwrap();
+        for n in 0..5 {
+            fs::write(dir.join("index.html"), format!("<p>two {n}</p>")).unwrap();
+        }
+        fs::remove_file(dir.join("style.css")).unwrap();
+        fs::write(dir.join("new.js"), "1").unwrap();
+        eventually(vec![key("index.html"), key("new.js")]).await;
+        assert_eq!(
+            bucket.bytes(&key("index.html")).await.unwrap(),
+            b"<p>two 4</p>"
+        );
+
+        stop.cancel();
+        watching.await.unwrap().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    config::BucketConfig,
+    s3::{get_bytes_or_default, site_location, UPLOAD_DATA_LOCATION},
+    store::{Fetched, ObjectStore},
+    upload::machinery::entry_hash,
+    UploadData,
+};
+use color_eyre::{eyre::bail, owo_colors::OwoColorize};
+use comfy_table::Table;
+use futures::{stream, StreamExt};
+use std::fmt::{Display, Formatter};
+
+///how many objects get checked at once
+const MAX_CONCURRENT_CHECKS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Missing,
+    WrongSize {
+        expected: u64,
+        found: u64,
+    },
+    ///only found with `--deep`
+    WrongHash {
+        expected: String,
+        found: String,
+    },
+    ///the bucket wouldn't say either way
+    Unreadable(String),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "missing"),
+            Self::WrongSize { expected, found } => {
+                write!(f, "{found} bytes, but {expected} were uploaded")
+            }
+            Self::WrongHash { expected, found } => {
+                write!(f, "hashes to {found}, but {expected} was uploaded")
+            }
+            Self::Unreadable(e) => write!(f, "unable to check ({e})"),
+        }
+    }
+}
+
+///checks one entry, HEADing it or with `deep` downloading & re-hashing it
+async fn check(
+    bucket: &dyn ObjectStore,
+    upload_data: &UploadData,
+    key: &str,
+    hash: &str,
+    deep: bool,
+) -> Option<Problem> {
+    let expected_size = upload_data.sizes.get(key).copied();
+
+    if deep {
+        let object = match bucket.get(key, None).await {
+            Ok(Fetched::Found(object)) => object,
+            Ok(Fetched::Missing | Fetched::NotModified) => return Some(Problem::Missing),
+            Err(e) => return Some(Problem::Unreadable(e.to_string())),
+        };
+        let found = object.bytes.len() as u64;
+        if let Some(expected) = expected_size.filter(|x| *x != found) {
+            return Some(Problem::WrongSize { expected, found });
+        }
+        let found = entry_hash(&object.bytes);
+        return (found != hash).then(|| Problem::WrongHash {
+            expected: hash.to_string(),
+            found,
+        });
+    }
+
+    match bucket.head(key).await {
+        Ok(Some(head)) => expected_size
+            .filter(|x| *x != head.content_length)
+            .map(|expected| Problem::WrongSize {
+                expected,
+                found: head.content_length,
+            }),
+        Ok(None) => Some(Problem::Missing),
+        Err(e) => Some(Problem::Unreadable(e.to_string())),
+    }
+}
+
+///everything in `upload_data` that isn't in the bucket as it was uploaded, sorted by key
+pub async fn find_problems(
+    bucket: &dyn ObjectStore,
+    upload_data: &UploadData,
+    deep: bool,
+) -> Vec<(String, Problem)> {
+    let mut problems: Vec<_> = stream::iter(&upload_data.entries)
+        .map(|(key, hash)| async move {
+            check(bucket, upload_data, key, hash, deep)
+                .await
+                .map(|problem| (key.clone(), problem))
+        })
+        .buffer_unordered(MAX_CONCURRENT_CHECKS)
+        .filter_map(|x| async move { x })
+        .collect()
+        .await;
+    problems.sort_by(|a, b| a.0.cmp(&b.0));
+    problems
+}
+
+///checks everything in the upload data is in the bucket, returning whether it all was
+pub async fn verify(
+    config: &BucketConfig,
+    site: Option<&str>,
+    deep: bool,
+) -> color_eyre::Result<bool> {
+    let bucket = config.bucket()?;
+    let upload_data =
+        get_bytes_or_default(&bucket, site_location(site, UPLOAD_DATA_LOCATION)).await?;
+    if upload_data.is_empty() {
+        bail!("nothing has been uploaded yet");
+    }
+    let upload_data: UploadData = serde_json::from_slice(&upload_data)?;
+
+    let problems = find_problems(&bucket, &upload_data, deep).await;
+    if problems.is_empty() {
+        println!(
+            "All {} objects are {}.",
+            upload_data.entries.len(),
+            "intact".green()
+        );
+        return Ok(true);
+    }
+
+    let mut table = Table::new();
+    table.apply_modifier(comfy_table::modifiers::UTF8_ROUND_CORNERS);
+    table.set_header(vec!["Key", "Problem"]);
+    for (key, problem) in &problems {
+        table.add_row(vec![key.clone(), problem.to_string()]);
+    }
+    println!("{table}");
+    println!(
+        "{} of {} objects are {}.",
+        problems.len(),
+        upload_data.entries.len(),
+        "not as uploaded".red()
+    );
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::InMemoryBucket;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_find_problems() {
+        let bucket = InMemoryBucket::new();
+        bucket
+            .put("public/index.html", b"<h1>hi</h1>", "text/plain")
+            .await
+            .unwrap();
+        bucket
+            .put("public/short.css", b"body", "text/plain")
+            .await
+            .unwrap();
+        bucket
+            .put("public/changed.js", b"let x = 2;", "text/plain")
+            .await
+            .unwrap();
+
+        let upload_data = UploadData {
+            entries: [
+                ("public/index.html", entry_hash(b"<h1>hi</h1>")),
+                ("public/short.css", entry_hash(b"body {}")),
+                ("public/changed.js", entry_hash(b"let x = 1;")),
+                ("public/gone.png", entry_hash(b"png")),
+            ]
+            .into_iter()
+            .map(|(key, hash)| (key.to_string(), hash))
+            .collect(),
+            root: "public".to_string(),
+            cache_control: HashMap::new(),
+            sizes: [
+                ("public/index.html".to_string(), 11),
+                ("public/short.css".to_string(), 7),
+            ]
+            .into(),
+        };
+
+        let shallow = find_problems(&bucket, &upload_data, false).await;
+        assert_eq!(
+            shallow,
+            vec![
+                ("public/gone.png".to_string(), Problem::Missing),
+                (
+                    "public/short.css".to_string(),
+                    Problem::WrongSize {
+                        expected: 7,
+                        found: 4
+                    }
+                ),
+            ]
+        );
+
+        //without a recorded size only re-hashing notices
+        let deep = find_problems(&bucket, &upload_data, true).await;
+        assert_eq!(deep.len(), 3);
+        assert_eq!(deep[0].0, "public/changed.js");
+        assert!(matches!(deep[0].1, Problem::WrongHash { .. }));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::error::{parse_json, ShoveError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+///turns the data from one version into the next, or says why it couldn't
+pub type Migration = fn(Value) -> Result<Value, String>;
+
+///what's written before there was a version, which is read as version 1
+pub const UNVERSIONED: u32 = 1;
+
+///something stored as JSON that can change shape, with how to bring old shapes up to date
+pub trait Versioned: Serialize + DeserializeOwned {
+    ///what gets written now
+    const VERSION: u32;
+    ///the first goes from version 1 to 2, the next from 2 to 3, and so on - so there's always
+    ///`VERSION - 1` of them
+    const MIGRATIONS: &'static [Migration];
+}
+
+#[derive(Serialize)]
+struct Wrapped<'a, T> {
+    version: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Unwrapped {
+    version: u32,
+    data: Value,
+}
+
+///the version and data of `value`, with anything not wrapped up being [`UNVERSIONED`]
+fn unwrap(value: Value) -> (u32, Value) {
+    let wrapped = value
+        .as_object()
+        .is_some_and(|x| x.len() == 2 && x.contains_key("version") && x.contains_key("data"));
+    if wrapped && let Ok(unwrapped) = serde_json::from_value::<Unwrapped>(value.clone()) {
+        return (unwrapped.version, unwrapped.data);
+    }
+    (UNVERSIONED, value)
+}
+
+///which version `json` was written as
+pub fn stored_version(json: &[u8]) -> Result<u32, ShoveError> {
+    Ok(unwrap(parse_json("versioned data", json)?).0)
+}
+
+///reads `json` written as any version up to `T::VERSION`, migrating it up to now
+pub fn from_versioned<T: Versioned>(json: &[u8]) -> Result<T, ShoveError> {
+    debug_assert_eq!(T::MIGRATIONS.len() + 1, T::VERSION as usize);
+
+    let (version, mut data) = unwrap(parse_json("versioned data", json)?);
+    if version > T::VERSION {
+        return Err(ShoveError::TooNew {
+            version,
+            latest: T::VERSION,
+        });
+    }
+    if version < UNVERSIONED {
+        return Err(ShoveError::Invalid(format!(
+            "written as version {version}, which never existed"
+        )));
+    }
+
+    for (from, migration) in (version..T::VERSION).zip(&T::MIGRATIONS[(version - 1) as usize..]) {
+        data = migration(data).map_err(|e| {
+            ShoveError::Invalid(format!(
+                "couldn't migrate from version {from} to {}: {e}",
+                from + 1
+            ))
+        })?;
+    }
+    serde_json::from_value(data).map_err(|e| {
+        if version == T::VERSION {
+            ShoveError::serde(format!("version {version}"), e)
+        } else {
+            ShoveError::serde(format!("version {version} after migrating it"), e)
+        }
+    })
+}
+
+///wraps `data` up as the latest version
+pub fn to_versioned<T: Versioned>(data: &T) -> Result<Vec<u8>, ShoveError> {
+    serde_json::to_vec(&Wrapped {
+        version: T::VERSION,
+        data,
+    })
+    .map_err(|e| ShoveError::serde(format!("version {}", T::VERSION), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Thing {
+        name: String,
+        count: u32,
+    }
+
+    impl Versioned for Thing {
+        const VERSION: u32 = 3;
+        const MIGRATIONS: &'static [Migration] = &[
+            //1 only had a name
+            |mut data| {
+                data["count"] = json!(0);
+                Ok(data)
+            },
+            //2 called it a title
+            |mut data| {
+                let Some(title) = data.as_object_mut().and_then(|x| x.remove("title")) else {
+                    return Err("no title".to_string());
+                };
+                data["name"] = title;
+                Ok(data)
+            },
+        ];
+    }
+
+    #[test]
+    fn test_every_version_is_read() {
+        let expected = |count| Thing {
+            name: "x".to_string(),
+            count,
+        };
+
+        assert_eq!(
+            from_versioned::<Thing>(br#"{"title":"x"}"#).unwrap(),
+            expected(0)
+        );
+        assert_eq!(
+            from_versioned::<Thing>(br#"{"version":2,"data":{"title":"x","count":5}}"#).unwrap(),
+            expected(5)
+        );
+        assert_eq!(
+            from_versioned::<Thing>(br#"{"version":3,"data":{"name":"x","count":5}}"#).unwrap(),
+            expected(5)
+        );
+
+        let written = to_versioned(&expected(5)).unwrap();
+        assert_eq!(stored_version(&written).unwrap(), 3);
+        assert_eq!(from_versioned::<Thing>(&written).unwrap(), expected(5));
+        assert_eq!(stored_version(br#"{"title":"x"}"#).unwrap(), UNVERSIONED);
+
+        let newer = from_versioned::<Thing>(br#"{"version":4,"data":{}}"#).unwrap_err();
+        assert!(
+            matches!(
+                newer,
+                ShoveError::TooNew {
+                    version: 4,
+                    latest: 3
+                }
+            ),
+            "{newer}"
+        );
+        assert!(newer.to_string().contains("update shove"), "{newer}");
+        let unmigratable =
+            from_versioned::<Thing>(br#"{"version":2,"data":{"count":1}}"#).unwrap_err();
+        assert!(
+            unmigratable.to_string().contains("no title"),
+            "{unmigratable}"
+        );
+        assert!(matches!(
+            from_versioned::<Thing>(br#"{"version":0,"data":{}}"#),
+            Err(ShoveError::Invalid(_))
+        ));
+        assert!(matches!(
+            from_versioned::<Thing>(b"{"),
+            Err(ShoveError::Serde { .. })
+        ));
+    }
+}